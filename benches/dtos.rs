@@ -0,0 +1,158 @@
+//!
+//! DTO BENCHMARK
+//! -------------
+//!
+//! Compares two ways of serializing a list of todos fetched from the
+//! database (see `Todo::to_dto` and `Todo::to_dto_ref` in
+//! `src/persistence.rs`):
+//!
+//! - `owned`: each todo becomes a `TodoDTO`, which clones its title,
+//!   description and metadata, and formats its `href` into a `String`;
+//! - `borrowed`: each todo becomes a `TodoDTORef`, which borrows them, and
+//!   writes its `href` straight into the output.
+//!
+//! Both write the same JSON. Before timing them, the benchmark counts the
+//! allocations each one makes, with a global allocator that counts calls to
+//! `alloc`, and prints them. The one allocation per todo that borrowing
+//! leaves is `time` formatting `created_at` into a `String`.
+//!
+//! Run with `cargo bench --bench dtos`.
+//!
+
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    fmt,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use time::{macros::datetime, OffsetDateTime};
+
+struct CountingAllocator;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+struct Todo {
+    id: i64,
+    title: String,
+    description: String,
+    created_at: OffsetDateTime,
+    metadata: serde_json::Value,
+}
+
+struct TodoById {
+    id: i64,
+}
+
+impl fmt::Display for TodoById {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "/todo/{}", self.id)
+    }
+}
+
+#[derive(serde::Serialize)]
+struct TodoDTO {
+    id: i64,
+    title: String,
+    description: String,
+    #[serde(with = "time::serde::rfc3339")]
+    created_at: OffsetDateTime,
+    metadata: serde_json::Value,
+    href: String,
+}
+
+#[derive(serde::Serialize)]
+struct TodoDTORef<'a> {
+    id: i64,
+    title: &'a str,
+    description: &'a str,
+    #[serde(with = "time::serde::rfc3339")]
+    created_at: OffsetDateTime,
+    metadata: &'a serde_json::Value,
+    #[serde(serialize_with = "serialize_display")]
+    href: TodoById,
+}
+
+fn serialize_display<T: fmt::Display, S: serde::Serializer>(value: &T, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.collect_str(value)
+}
+
+fn owned(todos: &[Todo]) -> Vec<u8> {
+    let dtos: Vec<TodoDTO> = todos
+        .iter()
+        .map(|todo| TodoDTO {
+            id: todo.id,
+            title: todo.title.clone(),
+            description: todo.description.clone(),
+            created_at: todo.created_at,
+            metadata: todo.metadata.clone(),
+            href: TodoById { id: todo.id }.to_string(),
+        })
+        .collect();
+    serde_json::to_vec(&dtos).unwrap()
+}
+
+fn borrowed(todos: &[Todo]) -> Vec<u8> {
+    let dtos: Vec<TodoDTORef> = todos
+        .iter()
+        .map(|todo| TodoDTORef {
+            id: todo.id,
+            title: &todo.title,
+            description: &todo.description,
+            created_at: todo.created_at,
+            metadata: &todo.metadata,
+            href: TodoById { id: todo.id },
+        })
+        .collect();
+    serde_json::to_vec(&dtos).unwrap()
+}
+
+fn allocations(f: impl FnOnce() -> Vec<u8>) -> usize {
+    let before = ALLOCATIONS.load(Ordering::Relaxed);
+    drop(f());
+    ALLOCATIONS.load(Ordering::Relaxed) - before
+}
+
+fn dtos(c: &mut Criterion) {
+    let mut group = c.benchmark_group("dtos");
+    for rows in [100, 10_000] {
+        let todos: Vec<Todo> = (0..rows)
+            .map(|id| Todo {
+                id,
+                title: format!("Todo {}", id),
+                description: "Borrow me, don't clone me".to_string(),
+                created_at: datetime!(2026-10-16 12:00 UTC),
+                metadata: serde_json::json!({ "tags": ["home", "urgent"] }),
+            })
+            .collect();
+        assert_eq!(owned(&todos), borrowed(&todos));
+
+        println!(
+            "{} todos: {} allocations owned, {} borrowed",
+            rows,
+            allocations(|| owned(&todos)),
+            allocations(|| borrowed(&todos))
+        );
+
+        group.bench_with_input(BenchmarkId::new("owned", rows), &todos, |b, todos| b.iter(|| owned(todos)));
+        group.bench_with_input(BenchmarkId::new("borrowed", rows), &todos, |b, todos| b.iter(|| borrowed(todos)));
+    }
+    group.finish();
+}
+
+criterion_group!(benches, dtos);
+criterion_main!(benches);
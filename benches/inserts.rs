@@ -0,0 +1,116 @@
+//!
+//! INSERT BENCHMARK
+//! ----------------
+//!
+//! Compares four ways of inserting many todos (see `EXERCISE 10` in
+//! `src/persistence.rs`):
+//!
+//! - one `sqlx::query!` per row, which Postgres parses and plans once, and
+//!   then only executes;
+//! - one `sqlx::query` per row, with statement caching turned off, so that
+//!   every row is parsed and planned again;
+//! - a single multi-row `INSERT ... VALUES (...), (...)`, built with
+//!   `QueryBuilder`;
+//! - a single `INSERT ... SELECT * FROM UNNEST(...)`, which binds one array
+//!   per column, whatever the number of rows.
+//!
+//! Every iteration runs in a transaction that is rolled back, so the
+//! benchmark leaves the database as it found it.
+//!
+//! Run with `cargo bench --bench inserts`.
+//!
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use sqlx::{postgres::PgPoolOptions, Pool, Postgres, QueryBuilder};
+
+async fn per_row_query_macro(pool: &Pool<Postgres>, titles: &[String]) {
+    let mut tx = pool.begin().await.unwrap();
+    for title in titles {
+        sqlx::query!("INSERT INTO todos (title, description) VALUES ($1, '')", title)
+            .execute(&mut *tx)
+            .await
+            .unwrap();
+    }
+    tx.rollback().await.unwrap();
+}
+
+async fn per_row_unprepared(pool: &Pool<Postgres>, titles: &[String]) {
+    let mut tx = pool.begin().await.unwrap();
+    for title in titles {
+        sqlx::query("INSERT INTO todos (title, description) VALUES ($1, '')")
+            .bind(title)
+            .persistent(false)
+            .execute(&mut *tx)
+            .await
+            .unwrap();
+    }
+    tx.rollback().await.unwrap();
+}
+
+async fn multi_row_values(pool: &Pool<Postgres>, titles: &[String]) {
+    let mut tx = pool.begin().await.unwrap();
+    let mut builder = QueryBuilder::<Postgres>::new("INSERT INTO todos (title, description) ");
+    builder.push_values(titles, |mut row, title| {
+        row.push_bind(title).push_bind("");
+    });
+    builder.build().execute(&mut *tx).await.unwrap();
+    tx.rollback().await.unwrap();
+}
+
+///
+/// The statement behind `TodoRepo::create_many`, without the columns that
+/// the benchmark leaves at their defaults.
+///
+async fn unnest(pool: &Pool<Postgres>, titles: &[String]) {
+    let descriptions = vec![""; titles.len()];
+    let priorities = vec![0; titles.len()];
+
+    let mut tx = pool.begin().await.unwrap();
+    sqlx::query!(
+        "INSERT INTO todos (title, description, priority)
+        SELECT title, description, priority
+        FROM UNNEST($1::TEXT[], $2::TEXT[], $3::INTEGER[]) WITH ORDINALITY AS t (title, description, priority, position)
+        ORDER BY position
+        RETURNING id",
+        titles,
+        &descriptions as &[&str],
+        &priorities
+    )
+    .fetch_all(&mut *tx)
+    .await
+    .unwrap();
+    tx.rollback().await.unwrap();
+}
+
+fn inserts(c: &mut Criterion) {
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let pool = runtime.block_on(async {
+        PgPoolOptions::new()
+            .max_connections(1)
+            .connect(&std::env::var("DATABASE_URL").unwrap())
+            .await
+            .unwrap()
+    });
+
+    let mut group = c.benchmark_group("inserts");
+    for rows in [10, 100, 1000] {
+        let titles: Vec<String> = (0..rows).map(|i| format!("Benchmark {}", i)).collect();
+
+        group.bench_with_input(BenchmarkId::new("per_row_query_macro", rows), &titles, |b, titles| {
+            b.to_async(&runtime).iter(|| per_row_query_macro(&pool, titles))
+        });
+        group.bench_with_input(BenchmarkId::new("per_row_unprepared", rows), &titles, |b, titles| {
+            b.to_async(&runtime).iter(|| per_row_unprepared(&pool, titles))
+        });
+        group.bench_with_input(BenchmarkId::new("multi_row_values", rows), &titles, |b, titles| {
+            b.to_async(&runtime).iter(|| multi_row_values(&pool, titles))
+        });
+        group.bench_with_input(BenchmarkId::new("unnest", rows), &titles, |b, titles| {
+            b.to_async(&runtime).iter(|| unnest(&pool, titles))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, inserts);
+criterion_main!(benches);
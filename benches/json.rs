@@ -0,0 +1,81 @@
+//!
+//! JSON BENCHMARK
+//! --------------
+//!
+//! Compares serde_json and simd-json at serializing todo lists of 1,000,
+//! 10,000 and 100,000 rows, as `to_json` does for the todo list responses
+//! (see `src/extract.rs`). The todos have the shape of a `TodoDTO`, dates,
+//! metadata and all, so that the numbers hold for real responses.
+//!
+//! Throughput is reported in bytes of JSON per second.
+//!
+//! Run with `cargo bench --bench json --features simd-json`.
+//!
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use time::{macros::datetime, OffsetDateTime};
+
+#[derive(serde::Serialize)]
+struct TodoDTO {
+    id: i64,
+    title: String,
+    description: String,
+    status: &'static str,
+    #[serde(with = "time::serde::rfc3339")]
+    created_at: OffsetDateTime,
+    #[serde(with = "time::serde::rfc3339::option")]
+    due_at: Option<OffsetDateTime>,
+    priority: i32,
+    parent_id: Option<i64>,
+    owner_id: Option<i64>,
+    list_id: Option<i64>,
+    #[serde(with = "time::serde::rfc3339::option")]
+    completed_at: Option<OffsetDateTime>,
+    metadata: serde_json::Value,
+    href: String,
+}
+
+fn todos(rows: i64) -> Vec<TodoDTO> {
+    (0..rows)
+        .map(|id| TodoDTO {
+            id,
+            title: format!("Todo {}", id),
+            description: "Serialize me, \"quickly\"".to_string(),
+            status: if id % 3 == 0 { "done" } else { "open" },
+            created_at: datetime!(2026-10-16 12:00 UTC),
+            due_at: (id % 2 == 0).then_some(datetime!(2026-10-25 09:30 +02:00)),
+            priority: (id % 5) as i32,
+            parent_id: None,
+            owner_id: Some(1),
+            list_id: None,
+            completed_at: None,
+            metadata: serde_json::json!({ "tags": ["home", "urgent"], "estimate": 1.5 }),
+            href: format!("/todo/{}", id),
+        })
+        .collect()
+}
+
+fn serialization(c: &mut Criterion) {
+    let mut group = c.benchmark_group("json");
+    group.sample_size(20);
+    for rows in [1_000, 10_000, 100_000] {
+        let todos = todos(rows);
+        let json = serde_json::to_vec(&todos).unwrap();
+        assert_eq!(
+            serde_json::from_slice::<serde_json::Value>(&simd_json::to_vec(&todos).unwrap()).unwrap(),
+            serde_json::from_slice::<serde_json::Value>(&json).unwrap()
+        );
+        group.throughput(Throughput::Bytes(json.len() as u64));
+
+        group.bench_with_input(BenchmarkId::new("serde_json", rows), &todos, |b, todos| {
+            b.iter(|| serde_json::to_vec(todos).unwrap())
+        });
+        group.bench_with_input(BenchmarkId::new("simd_json", rows), &todos, |b, todos| {
+            b.iter(|| simd_json::to_vec(todos).unwrap())
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, serialization);
+criterion_main!(benches);
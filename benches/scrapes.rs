@@ -0,0 +1,89 @@
+//!
+//! SCRAPE BENCHMARK
+//! ----------------
+//!
+//! Compares two ways of serving `/metrics` to a storm of 64 concurrent
+//! scrapes (see `src/exposition.rs`):
+//!
+//! - `render_per_scrape`: every scrape renders the registry into a fresh
+//!   `String`, as `PrometheusHandle::render` does;
+//! - `rendered_once`: the registry is rendered into a `Bytes` once, and every
+//!   scrape is served a clone of it, as `RenderedMetrics` does within its
+//!   `max_age`.
+//!
+//! And two ways of serving `/health`: a `&'static str`, and a small struct
+//! serialized with `Json` for every request.
+//!
+//! Every request goes through an axum router, so the numbers include routing
+//! and building the response, as a real scrape would.
+//!
+//! Run with `cargo bench --bench scrapes`.
+//!
+
+use axum::{
+    body::{Body, Bytes},
+    extract::Request,
+    routing::get,
+    Json, Router,
+};
+use axum_prometheus::metrics_exporter_prometheus::{PrometheusBuilder, PrometheusHandle};
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use metrics::{Key, Label, Recorder};
+use tower::util::ServiceExt;
+
+const CONCURRENT_SCRAPES: usize = 64;
+
+fn registry(series: usize) -> PrometheusHandle {
+    let recorder = PrometheusBuilder::new().build_recorder();
+    for i in 0..series {
+        let key = Key::from_parts("bench_gauge", vec![Label::new("series", i.to_string())]);
+        recorder.register_gauge(&key).set(i as f64);
+    }
+    recorder.handle()
+}
+
+async fn storm(app: &Router, uri: &'static str) {
+    let scrapes = (0..CONCURRENT_SCRAPES).map(|_| {
+        let app = app.clone();
+        tokio::spawn(async move { app.oneshot(Request::get(uri).body(Body::empty()).unwrap()).await.unwrap() })
+    });
+    for scrape in futures::future::join_all(scrapes).await {
+        scrape.unwrap();
+    }
+}
+
+#[derive(serde::Serialize)]
+struct Health {
+    status: &'static str,
+}
+
+fn scrapes(c: &mut Criterion) {
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+
+    let mut group = c.benchmark_group("metrics");
+    for series in [100, 1_000, 10_000] {
+        let handle = registry(series);
+        let rendered = Bytes::from(handle.render());
+
+        let per_scrape = Router::new().route("/metrics", get(move || async move { handle.render() }));
+        let once = Router::new().route("/metrics", get(move || async move { rendered.clone() }));
+
+        group.bench_with_input(BenchmarkId::new("render_per_scrape", series), &per_scrape, |b, app| {
+            b.to_async(&runtime).iter(|| storm(app, "/metrics"))
+        });
+        group.bench_with_input(BenchmarkId::new("rendered_once", series), &once, |b, app| {
+            b.to_async(&runtime).iter(|| storm(app, "/metrics"))
+        });
+    }
+    group.finish();
+
+    let mut group = c.benchmark_group("health");
+    let static_str = Router::new().route("/health", get(|| async { "OK" }));
+    let json = Router::new().route("/health", get(|| async { Json(Health { status: "OK" }) }));
+    group.bench_function("static_str", |b| b.to_async(&runtime).iter(|| storm(&static_str, "/health")));
+    group.bench_function("json", |b| b.to_async(&runtime).iter(|| storm(&json, "/health")));
+    group.finish();
+}
+
+criterion_group!(benches, scrapes);
+criterion_main!(benches);
@@ -0,0 +1,18 @@
+#![no_main]
+
+//!
+//! Cursors come back from clients in `?after=`. Decoding one must never
+//! panic, and whatever decodes must encode to a cursor that decodes the same.
+//!
+
+use libfuzzer_sys::fuzz_target;
+
+#[path = "../../src/cursor.rs"]
+mod cursor;
+
+fuzz_target!(|input: &str| {
+    if let Some((at, rest)) = cursor::decode(input) {
+        let again = cursor::encode(at, &rest);
+        assert_eq!(cursor::decode(&again), Some((at, rest)));
+    }
+});
@@ -0,0 +1,40 @@
+#![no_main]
+
+//!
+//! Attachments are uploaded as `multipart/form-data`, and `read_upload` takes
+//! the file out of whatever body the client sends. It must never panic, and
+//! an upload it accepts must have a name without a path, and a name and a
+//! content type that can go back out in response headers.
+//!
+
+use axum::{
+    body::Body,
+    extract::{FromRequest, Multipart},
+    http::{header, HeaderValue, Request},
+};
+use libfuzzer_sys::fuzz_target;
+
+#[path = "../../src/upload.rs"]
+mod upload;
+
+use upload::{content_disposition, read_upload};
+
+const BOUNDARY: &str = "fuzz-boundary";
+
+fuzz_target!(|body: &[u8]| {
+    let request = Request::post("/todo/1/attachments")
+        .header(header::CONTENT_TYPE, format!("multipart/form-data; boundary={}", BOUNDARY))
+        .body(Body::from(body.to_vec()))
+        .unwrap();
+    let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+
+    runtime.block_on(async {
+        let mut multipart = Multipart::from_request(request, &()).await.unwrap();
+        if let Ok(upload) = read_upload(&mut multipart).await {
+            assert!(!upload.filename.is_empty());
+            assert!(!upload.filename.contains(['/', '\\']));
+            assert!(HeaderValue::from_str(&upload.content_type).is_ok(), "{:?}", upload.content_type);
+            assert!(HeaderValue::from_str(&content_disposition(&upload.filename)).is_ok());
+        }
+    });
+});
@@ -0,0 +1,85 @@
+#![no_main]
+
+//!
+//! The filters of `GET /todo/` are parsed by `QsQuery` from a query string
+//! that the client writes, with arrays and nested keys. Whatever it is, the
+//! request is either parsed or rejected with a 400, and never panics.
+//!
+//! `TodoQuery` is private to the persistence module, which needs a database
+//! to build, so this is a copy of its shape.
+//!
+
+use axum::{
+    extract::FromRequestParts,
+    http::{Request, StatusCode, Uri},
+    response::IntoResponse,
+};
+use libfuzzer_sys::fuzz_target;
+use time::OffsetDateTime;
+
+#[path = "../../src/extract.rs"]
+mod extract;
+
+use extract::QsQuery;
+
+// Only parsed, never read.
+#[allow(dead_code)]
+#[derive(Debug, serde::Deserialize)]
+struct TodoQuery {
+    #[serde(default)]
+    sort: TodoSort,
+    after: Option<String>,
+    limit: Option<i64>,
+    metadata: Option<String>,
+    #[serde(default)]
+    tag: Vec<String>,
+    #[serde(default)]
+    status: Vec<TodoStatus>,
+    #[serde(default)]
+    due: DueRange,
+}
+
+#[derive(Debug, Default, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum TodoSort {
+    #[default]
+    Id,
+    Priority,
+    DueAt,
+}
+
+#[derive(Debug, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum TodoStatus {
+    Open,
+    InProgress,
+    Done,
+    Cancelled,
+}
+
+#[allow(dead_code)]
+#[derive(Debug, Default, serde::Deserialize)]
+struct DueRange {
+    #[serde(default, with = "time::serde::rfc3339::option")]
+    after: Option<OffsetDateTime>,
+    #[serde(default, with = "time::serde::rfc3339::option")]
+    before: Option<OffsetDateTime>,
+}
+
+fuzz_target!(|query: &str| {
+    // Axum never sees a request whose URI does not parse.
+    let Ok(uri) = format!("/todo/?{}", query).parse::<Uri>() else {
+        return;
+    };
+    let (mut parts, ()) = Request::get(uri).body(()).unwrap().into_parts();
+    let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+
+    match runtime.block_on(QsQuery::<TodoQuery>::from_request_parts(&mut parts, &())) {
+        Ok(QsQuery(query)) => {
+            if let Some(metadata) = query.metadata {
+                let _ = serde_json::from_str::<serde_json::Map<String, serde_json::Value>>(&metadata);
+            }
+        }
+        Err(error) => assert_eq!(error.into_response().status(), StatusCode::BAD_REQUEST),
+    }
+});
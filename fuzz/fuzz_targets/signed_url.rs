@@ -0,0 +1,34 @@
+#![no_main]
+
+//!
+//! Signed URLs are accepted without authentication, so `verify` sees whatever
+//! anyone sends. It must never panic, must never accept a URL that it did not
+//! sign, and must accept every URL that it did.
+//!
+
+use std::sync::Arc;
+
+use libfuzzer_sys::fuzz_target;
+use time::{macros::datetime, Duration};
+
+#[path = "../../src/clock.rs"]
+mod clock;
+#[path = "../../src/problem.rs"]
+mod problem;
+#[path = "../../src/signed_urls.rs"]
+mod signed_urls;
+
+use clock::FakeClock;
+use signed_urls::{SignedUrlError, UrlSigner};
+
+fuzz_target!(|input: &str| {
+    let signer = UrlSigner::from_secret(b"fuzz", Arc::new(FakeClock::new(datetime!(2024-01-01 0:00 UTC))));
+
+    // Anything made up, signature and all, is rejected.
+    assert!(signer.verify(input).is_err());
+
+    // Anything signed is accepted, whatever the path and query were.
+    let link = signer.sign(input, Duration::hours(1));
+    assert_eq!(signer.verify(&link.url), Ok(link.expires_at));
+    assert_eq!(signer.verify(&format!("{}x", link.url)), Err(SignedUrlError::Invalid));
+});
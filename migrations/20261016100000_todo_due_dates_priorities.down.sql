@@ -0,0 +1,4 @@
+DROP INDEX IF EXISTS todos_due_at_idx;
+
+ALTER TABLE todos DROP COLUMN IF EXISTS priority;
+ALTER TABLE todos DROP COLUMN IF EXISTS due_at;
@@ -0,0 +1,6 @@
+CREATE TABLE IF NOT EXISTS todo_recurrences
+(
+    todo_id     BIGINT PRIMARY KEY REFERENCES todos (id) ON DELETE CASCADE,
+    rule        TEXT NOT NULL,
+    created_at  TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
@@ -0,0 +1,9 @@
+CREATE TABLE IF NOT EXISTS comments
+(
+    id          BIGSERIAL PRIMARY KEY,
+    todo_id     BIGINT NOT NULL REFERENCES todos (id) ON DELETE CASCADE,
+    body        TEXT NOT NULL,
+    created_at  TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX IF NOT EXISTS comments_todo_id_idx ON comments (todo_id);
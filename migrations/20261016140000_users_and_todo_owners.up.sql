@@ -0,0 +1,21 @@
+CREATE TABLE IF NOT EXISTS users
+(
+    id            BIGSERIAL PRIMARY KEY,
+    name          TEXT NOT NULL,
+    email         TEXT NOT NULL UNIQUE,
+    password_hash TEXT NOT NULL,
+    is_admin      BOOLEAN NOT NULL DEFAULT FALSE,
+    created_at    TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+-- Todos created before users existed have no owner, and are only visible to admins.
+ALTER TABLE todos ADD COLUMN IF NOT EXISTS owner_id BIGINT REFERENCES users (id) ON DELETE CASCADE;
+
+CREATE INDEX IF NOT EXISTS todos_owner_id_idx ON todos (owner_id);
+
+-- Whether a user may see (and change) a todo: their own todos, or any todo if they are an admin.
+CREATE OR REPLACE FUNCTION todo_visible_to(todo_owner_id BIGINT, user_id BIGINT) RETURNS BOOLEAN
+    LANGUAGE SQL STABLE
+AS $$
+    SELECT todo_owner_id = user_id OR EXISTS (SELECT 1 FROM users WHERE id = user_id AND is_admin)
+$$;
@@ -0,0 +1,22 @@
+CREATE TABLE IF NOT EXISTS lists
+(
+    id          BIGSERIAL PRIMARY KEY,
+    name        TEXT NOT NULL,
+    owner_id    BIGINT NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+    created_at  TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE TABLE IF NOT EXISTS list_members
+(
+    list_id     BIGINT NOT NULL REFERENCES lists (id) ON DELETE CASCADE,
+    user_id     BIGINT NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+    role        TEXT NOT NULL CHECK (role IN ('owner', 'editor', 'viewer')),
+    created_at  TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (list_id, user_id)
+);
+
+CREATE INDEX IF NOT EXISTS list_members_user_id_idx ON list_members (user_id);
+
+ALTER TABLE todos ADD COLUMN IF NOT EXISTS list_id BIGINT REFERENCES lists (id) ON DELETE CASCADE;
+
+CREATE INDEX IF NOT EXISTS todos_list_id_idx ON todos (list_id);
@@ -0,0 +1,4 @@
+DROP TRIGGER IF EXISTS todos_completed_at ON todos;
+DROP FUNCTION IF EXISTS set_todo_completed_at();
+
+ALTER TABLE todos DROP COLUMN IF EXISTS completed_at;
@@ -0,0 +1,17 @@
+ALTER TABLE todos ADD COLUMN IF NOT EXISTS completed_at TIMESTAMP;
+
+-- Keeps completed_at in step with done, whichever query changes it.
+CREATE OR REPLACE FUNCTION set_todo_completed_at() RETURNS TRIGGER AS $$
+BEGIN
+    IF NOT NEW.done THEN
+        NEW.completed_at := NULL;
+    ELSIF TG_OP = 'INSERT' OR NOT OLD.done THEN
+        NEW.completed_at := CURRENT_TIMESTAMP;
+    END IF;
+    RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
+
+DROP TRIGGER IF EXISTS todos_completed_at ON todos;
+CREATE TRIGGER todos_completed_at BEFORE INSERT OR UPDATE OF done ON todos
+    FOR EACH ROW EXECUTE FUNCTION set_todo_completed_at();
@@ -0,0 +1,2 @@
+-- Supports keyset pagination over (created_at, id).
+CREATE INDEX IF NOT EXISTS todos_created_at_id_idx ON todos (created_at, id);
@@ -0,0 +1,13 @@
+-- A variant of the todos table keyed by UUIDv7 instead of BIGSERIAL. The ids
+-- are generated by the application, so there is no default.
+CREATE TABLE IF NOT EXISTS uuid_todos
+(
+    id          UUID PRIMARY KEY,
+    title       TEXT NOT NULL,
+    description TEXT NOT NULL,
+    done        BOOLEAN NOT NULL DEFAULT FALSE,
+    owner_id    BIGINT NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+    created_at  TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX IF NOT EXISTS uuid_todos_owner_id_idx ON uuid_todos (owner_id);
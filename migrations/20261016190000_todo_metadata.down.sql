@@ -0,0 +1,3 @@
+DROP INDEX IF EXISTS todos_metadata_idx;
+
+ALTER TABLE todos DROP COLUMN IF EXISTS metadata;
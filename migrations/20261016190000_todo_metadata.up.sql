@@ -0,0 +1,7 @@
+-- Free-form metadata, such as labels or the id of a todo in another system.
+-- It is always an object, so that a merge patch can add and remove its keys.
+ALTER TABLE todos ADD COLUMN IF NOT EXISTS metadata JSONB NOT NULL DEFAULT '{}'
+    CHECK (jsonb_typeof(metadata) = 'object');
+
+-- Serves containment filters (metadata @> '{"label": "home"}').
+CREATE INDEX IF NOT EXISTS todos_metadata_idx ON todos USING GIN (metadata jsonb_path_ops);
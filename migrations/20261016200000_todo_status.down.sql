@@ -0,0 +1,26 @@
+-- Back to the done flag. Todos in progress become open again, and cancelled
+-- todos count as done, as they did before they could be cancelled.
+ALTER TABLE todos ADD COLUMN IF NOT EXISTS done BOOLEAN NOT NULL DEFAULT FALSE;
+UPDATE todos SET done = status IN ('done', 'cancelled');
+
+DROP TRIGGER IF EXISTS todos_completed_at ON todos;
+DROP INDEX IF EXISTS todos_due_at_idx;
+
+ALTER TABLE todos DROP COLUMN IF EXISTS status;
+DROP TYPE IF EXISTS todo_status;
+
+CREATE INDEX IF NOT EXISTS todos_due_at_idx ON todos (due_at) WHERE NOT done;
+
+CREATE OR REPLACE FUNCTION set_todo_completed_at() RETURNS TRIGGER AS $$
+BEGIN
+    IF NOT NEW.done THEN
+        NEW.completed_at := NULL;
+    ELSIF TG_OP = 'INSERT' OR NOT OLD.done THEN
+        NEW.completed_at := CURRENT_TIMESTAMP;
+    END IF;
+    RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER todos_completed_at BEFORE INSERT OR UPDATE OF done ON todos
+    FOR EACH ROW EXECUTE FUNCTION set_todo_completed_at();
@@ -0,0 +1,37 @@
+-- Replaces the done flag with a status, so that a todo can also be in
+-- progress, or cancelled without pretending that it was done.
+DO $$ BEGIN
+    CREATE TYPE todo_status AS ENUM ('open', 'in_progress', 'done', 'cancelled');
+EXCEPTION
+    WHEN duplicate_object THEN NULL;
+END $$;
+
+ALTER TABLE todos ADD COLUMN IF NOT EXISTS status todo_status NOT NULL DEFAULT 'open';
+
+DROP TRIGGER IF EXISTS todos_completed_at ON todos;
+DROP INDEX IF EXISTS todos_due_at_idx;
+
+DO $$ BEGIN
+    UPDATE todos SET status = 'done' WHERE done;
+    ALTER TABLE todos DROP COLUMN done;
+EXCEPTION
+    WHEN undefined_column THEN NULL;
+END $$;
+
+-- Only todos that are still to do can be overdue.
+CREATE INDEX IF NOT EXISTS todos_due_at_idx ON todos (due_at) WHERE status IN ('open', 'in_progress');
+
+-- Keeps completed_at in step with the status, whichever query changes it.
+CREATE OR REPLACE FUNCTION set_todo_completed_at() RETURNS TRIGGER AS $$
+BEGIN
+    IF NEW.status <> 'done' THEN
+        NEW.completed_at := NULL;
+    ELSIF TG_OP = 'INSERT' OR OLD.status <> 'done' THEN
+        NEW.completed_at := CURRENT_TIMESTAMP;
+    END IF;
+    RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER todos_completed_at BEFORE INSERT OR UPDATE OF status ON todos
+    FOR EACH ROW EXECUTE FUNCTION set_todo_completed_at();
@@ -0,0 +1,9 @@
+-- Amounts of money are exact decimals, never floating point.
+CREATE TABLE IF NOT EXISTS prices
+(
+    id         BIGSERIAL PRIMARY KEY,
+    name       TEXT NOT NULL,
+    amount     NUMERIC(12, 2) NOT NULL,
+    currency   CHAR(3) NOT NULL,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
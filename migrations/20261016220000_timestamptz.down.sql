@@ -0,0 +1,7 @@
+ALTER TABLE todos
+    ALTER COLUMN created_at TYPE TIMESTAMP USING created_at AT TIME ZONE 'UTC',
+    ALTER COLUMN completed_at TYPE TIMESTAMP USING completed_at AT TIME ZONE 'UTC';
+
+ALTER TABLE comments ALTER COLUMN created_at TYPE TIMESTAMP USING created_at AT TIME ZONE 'UTC';
+ALTER TABLE users ALTER COLUMN created_at TYPE TIMESTAMP USING created_at AT TIME ZONE 'UTC';
+ALTER TABLE uuid_todos ALTER COLUMN created_at TYPE TIMESTAMP USING created_at AT TIME ZONE 'UTC';
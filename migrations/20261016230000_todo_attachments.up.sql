@@ -0,0 +1,13 @@
+CREATE TABLE IF NOT EXISTS attachments
+(
+    id            BIGSERIAL PRIMARY KEY,
+    todo_id       BIGINT NOT NULL REFERENCES todos (id) ON DELETE CASCADE,
+    filename      TEXT NOT NULL,
+    content_type  TEXT NOT NULL,
+    size          BIGINT NOT NULL,
+    sha256        TEXT NOT NULL,
+    object_key    TEXT NOT NULL UNIQUE,
+    created_at    TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX IF NOT EXISTS attachments_todo_id_idx ON attachments (todo_id);
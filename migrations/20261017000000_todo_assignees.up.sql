@@ -0,0 +1,4 @@
+ALTER TABLE todos ADD COLUMN IF NOT EXISTS assignee_id BIGINT REFERENCES users (id) ON DELETE SET NULL;
+
+-- The queue of todos waiting to be claimed, in the order they are claimed.
+CREATE INDEX IF NOT EXISTS todos_unassigned_idx ON todos (id) WHERE assignee_id IS NULL AND status = 'open';
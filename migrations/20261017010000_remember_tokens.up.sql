@@ -0,0 +1,14 @@
+-- Remember-me tokens, split into a selector, which finds the row, and a
+-- validator, which is only stored hashed. The selector names the series, and
+-- stays the same while the validator rotates on every use.
+CREATE TABLE IF NOT EXISTS remember_tokens
+(
+    id              BIGSERIAL PRIMARY KEY,
+    user_id         BIGINT NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+    selector        TEXT NOT NULL UNIQUE,
+    validator_hash  TEXT NOT NULL,
+    expires_at      TIMESTAMP NOT NULL,
+    created_at      TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX IF NOT EXISTS remember_tokens_user_id_idx ON remember_tokens (user_id);
@@ -0,0 +1,4 @@
+DROP TABLE IF EXISTS recovery_codes;
+DROP TABLE IF EXISTS totp_secrets;
+
+ALTER TABLE refresh_tokens DROP COLUMN IF EXISTS mfa;
@@ -0,0 +1,24 @@
+-- Sessions that started with a second factor, whose access tokens say so.
+ALTER TABLE refresh_tokens ADD COLUMN IF NOT EXISTS mfa BOOLEAN NOT NULL DEFAULT false;
+
+-- One TOTP secret per user, pending until a first code confirms it.
+-- `last_step` is the time step of the last code accepted, which cannot be
+-- used again.
+CREATE TABLE IF NOT EXISTS totp_secrets
+(
+    user_id     BIGINT PRIMARY KEY REFERENCES users (id) ON DELETE CASCADE,
+    secret      TEXT NOT NULL,
+    enabled_at  TIMESTAMP,
+    last_step   BIGINT
+);
+
+-- Single-use codes for users who lost their authenticator, only stored hashed.
+CREATE TABLE IF NOT EXISTS recovery_codes
+(
+    id          BIGSERIAL PRIMARY KEY,
+    user_id     BIGINT NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+    code_hash   TEXT NOT NULL,
+    used_at     TIMESTAMP
+);
+
+CREATE INDEX IF NOT EXISTS recovery_codes_user_id_idx ON recovery_codes (user_id);
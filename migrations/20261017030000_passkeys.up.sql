@@ -0,0 +1,26 @@
+-- The public keys of the passkeys users registered, by the id that the
+-- authenticator gave them. `sign_count` is the last signature counter seen,
+-- which only ever grows on an authenticator that was not cloned.
+CREATE TABLE IF NOT EXISTS webauthn_credentials
+(
+    id              BIGSERIAL PRIMARY KEY,
+    user_id         BIGINT NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+    credential_id   TEXT NOT NULL UNIQUE,
+    public_key      BYTEA NOT NULL,
+    sign_count      BIGINT NOT NULL,
+    name            TEXT NOT NULL,
+    created_at      TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    last_used_at    TIMESTAMP
+);
+
+CREATE INDEX IF NOT EXISTS webauthn_credentials_user_id_idx ON webauthn_credentials (user_id);
+
+-- The challenges of ceremonies in progress, each answered at most once.
+-- Those of logins have no user yet.
+CREATE TABLE IF NOT EXISTS webauthn_challenges
+(
+    challenge   TEXT PRIMARY KEY,
+    user_id     BIGINT REFERENCES users (id) ON DELETE CASCADE,
+    kind        TEXT NOT NULL,
+    expires_at  TIMESTAMP NOT NULL
+);
@@ -0,0 +1,17 @@
+-- Tokens that users create for scripts and integrations, only stored hashed,
+-- each limited to some scopes. `mfa` is whether the session that created the
+-- token started with a second factor.
+CREATE TABLE IF NOT EXISTS personal_access_tokens
+(
+    id              BIGSERIAL PRIMARY KEY,
+    user_id         BIGINT NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+    name            TEXT NOT NULL,
+    token_hash      TEXT NOT NULL UNIQUE,
+    scopes          TEXT[] NOT NULL,
+    mfa             BOOLEAN NOT NULL DEFAULT false,
+    created_at      TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    expires_at      TIMESTAMP,
+    last_used_at    TIMESTAMP
+);
+
+CREATE INDEX IF NOT EXISTS personal_access_tokens_user_id_idx ON personal_access_tokens (user_id);
@@ -0,0 +1,3 @@
+-- Drops the blind index only. Emails that `rotate-pii-keys` encrypted stay
+-- encrypted, and cannot be found by email until they are decrypted again.
+ALTER TABLE users DROP COLUMN IF EXISTS email_index;
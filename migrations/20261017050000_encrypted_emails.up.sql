@@ -0,0 +1,4 @@
+-- Emails are encrypted by the app (see `pii.rs`), and looked up by a keyed
+-- hash of them, their blind index. Rows without an index are from before,
+-- with their email in plaintext, until `rotate-pii-keys` encrypts them.
+ALTER TABLE users ADD COLUMN IF NOT EXISTS email_index TEXT UNIQUE;
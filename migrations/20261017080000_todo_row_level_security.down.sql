@@ -0,0 +1,15 @@
+DROP POLICY IF EXISTS comments_of_visible_todos ON comments;
+
+ALTER TABLE comments DISABLE ROW LEVEL SECURITY;
+
+DROP POLICY IF EXISTS todos_visible_to_user ON todos;
+
+ALTER TABLE todos DISABLE ROW LEVEL SECURITY;
+
+REVOKE SELECT (id, is_admin) ON users FROM todo_rls;
+REVOKE USAGE ON SEQUENCE todos_id_seq, comments_id_seq FROM todo_rls;
+REVOKE SELECT, INSERT, UPDATE, DELETE ON todos, comments FROM todo_rls;
+
+-- The role is left, with its members: it belongs to the whole server, and
+-- other databases on it, such as the scratch database of `check-migrations`,
+-- may still have these tables and grant it on them.
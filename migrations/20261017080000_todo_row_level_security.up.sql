@@ -0,0 +1,43 @@
+-- Row-level security on todos, for `TodoRepoRls` (see `EXERCISE 16`). Its
+-- transactions run as `todo_rls`, with the user in `app.user_id`, and the
+-- policies only let that role see the rows visible to that user, whatever
+-- its queries forget to filter.
+--
+-- The owner of a table, and superusers, bypass its policies, so the app and
+-- every other repo, which connect as the owner, see every row as before.
+-- Other roles that are not the owner see none, unless a policy is added for
+-- them.
+--
+-- Roles belong to the whole server rather than to one database, so it may
+-- already exist. Creating it needs CREATEROLE.
+DO $$
+BEGIN
+    IF NOT EXISTS (SELECT 1 FROM pg_roles WHERE rolname = 'todo_rls') THEN
+        CREATE ROLE todo_rls NOLOGIN;
+    END IF;
+END
+$$;
+
+-- Lets the app `SET ROLE todo_rls`, even if it does not connect as a superuser.
+GRANT todo_rls TO CURRENT_USER;
+
+GRANT SELECT, INSERT, UPDATE, DELETE ON todos, comments TO todo_rls;
+GRANT USAGE ON SEQUENCE todos_id_seq, comments_id_seq TO todo_rls;
+-- For `todo_visible_to`, which looks up whether the user is an admin.
+GRANT SELECT (id, is_admin) ON users TO todo_rls;
+
+ALTER TABLE todos ENABLE ROW LEVEL SECURITY;
+
+-- `current_setting` fails if `app.user_id` was never set, rather than letting
+-- a transaction that forgot it see anything. A policy for all commands checks
+-- new rows too, so a todo cannot be given to another user either.
+DROP POLICY IF EXISTS todos_visible_to_user ON todos;
+CREATE POLICY todos_visible_to_user ON todos TO todo_rls
+    USING (todo_visible_to(owner_id, current_setting('app.user_id')::BIGINT));
+
+ALTER TABLE comments ENABLE ROW LEVEL SECURITY;
+
+-- The subquery is itself filtered by the policy of todos.
+DROP POLICY IF EXISTS comments_of_visible_todos ON comments;
+CREATE POLICY comments_of_visible_todos ON comments TO todo_rls
+    USING (EXISTS (SELECT 1 FROM todos WHERE todos.id = comments.todo_id));
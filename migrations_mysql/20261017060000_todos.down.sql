@@ -0,0 +1,3 @@
+-- The triggers go with the table.
+DROP TABLE IF EXISTS comments;
+DROP TABLE IF EXISTS todos;
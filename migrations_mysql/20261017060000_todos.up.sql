@@ -0,0 +1,44 @@
+-- The todos and their comments, for TODO_REPO=mysql, on MySQL 8 or MariaDB
+-- 10.6 and later. Users stay in Postgres with the rest of the app, so
+-- owner_id and assignee_id reference nothing here.
+CREATE TABLE IF NOT EXISTS todos
+(
+    id           BIGINT AUTO_INCREMENT PRIMARY KEY,
+    title        TEXT NOT NULL,
+    description  TEXT NOT NULL,
+    status       ENUM ('open', 'in_progress', 'done', 'cancelled') NOT NULL DEFAULT 'open',
+    -- DATETIME rather than TIMESTAMP, which ends in 2038. Both hold UTC, as
+    -- sqlx sets the time zone of every connection to +00:00.
+    created_at   DATETIME(6) NOT NULL DEFAULT CURRENT_TIMESTAMP(6),
+    due_at       DATETIME(6) NULL,
+    priority     INT NOT NULL DEFAULT 0,
+    parent_id    BIGINT NULL,
+    owner_id     BIGINT NULL,
+    list_id      BIGINT NULL,
+    assignee_id  BIGINT NULL,
+    completed_at DATETIME(6) NULL,
+    metadata     JSON NOT NULL DEFAULT (JSON_OBJECT()),
+    CONSTRAINT todos_parent_id_fk FOREIGN KEY (parent_id) REFERENCES todos (id) ON DELETE CASCADE,
+    INDEX todos_owner_id_idx (owner_id),
+    INDEX todos_created_at_id_idx (created_at, id),
+    INDEX todos_due_at_idx (due_at)
+);
+
+CREATE TABLE IF NOT EXISTS comments
+(
+    id         BIGINT AUTO_INCREMENT PRIMARY KEY,
+    todo_id    BIGINT NOT NULL,
+    body       TEXT NOT NULL,
+    created_at DATETIME(6) NOT NULL DEFAULT CURRENT_TIMESTAMP(6),
+    CONSTRAINT comments_todo_id_fk FOREIGN KEY (todo_id) REFERENCES todos (id) ON DELETE CASCADE
+);
+
+-- Keeps completed_at in step with the status, whichever query changes it, as
+-- set_todo_completed_at does in Postgres. A trigger of a single statement
+-- needs no BEGIN ... END, and so no DELIMITER, which only the mysql client
+-- understands.
+CREATE TRIGGER todos_completed_at_insert BEFORE INSERT ON todos FOR EACH ROW
+    SET NEW.completed_at = IF(NEW.status = 'done', CURRENT_TIMESTAMP(6), NULL);
+
+CREATE TRIGGER todos_completed_at_update BEFORE UPDATE ON todos FOR EACH ROW
+    SET NEW.completed_at = IF(NEW.status <> 'done', NULL, IF(OLD.status <> 'done', CURRENT_TIMESTAMP(6), OLD.completed_at));
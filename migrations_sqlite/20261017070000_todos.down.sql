@@ -0,0 +1,3 @@
+-- The triggers and indexes go with the tables.
+DROP TABLE IF EXISTS comments;
+DROP TABLE IF EXISTS todos;
@@ -0,0 +1,55 @@
+-- The todos and their comments, for TODO_REPO=sqlite. Users stay in Postgres
+-- with the rest of the app, so owner_id and assignee_id reference nothing
+-- here. sqlx turns on foreign keys for every connection, which SQLite leaves
+-- off unless asked.
+CREATE TABLE IF NOT EXISTS todos
+(
+    -- AUTOINCREMENT never hands out the id of a deleted todo again, as a
+    -- Postgres sequence does not.
+    id           INTEGER PRIMARY KEY AUTOINCREMENT,
+    title        TEXT NOT NULL,
+    description  TEXT NOT NULL,
+    status       TEXT NOT NULL DEFAULT 'open' CHECK (status IN ('open', 'in_progress', 'done', 'cancelled')),
+    -- SQLite has no type for times. These are microseconds since the Unix
+    -- epoch, which sort and compare as numbers, unlike the text that sqlx
+    -- would otherwise store. Its clock only gives milliseconds.
+    created_at   INTEGER NOT NULL DEFAULT (CAST(unixepoch('subsec') * 1000000 AS INTEGER)),
+    due_at       INTEGER NULL,
+    priority     INTEGER NOT NULL DEFAULT 0,
+    parent_id    INTEGER NULL REFERENCES todos (id) ON DELETE CASCADE,
+    owner_id     INTEGER NULL,
+    list_id      INTEGER NULL,
+    assignee_id  INTEGER NULL,
+    completed_at INTEGER NULL,
+    metadata     TEXT NOT NULL DEFAULT '{}' CHECK (json_valid(metadata))
+);
+
+CREATE INDEX IF NOT EXISTS todos_owner_id_idx ON todos (owner_id);
+CREATE INDEX IF NOT EXISTS todos_created_at_id_idx ON todos (created_at, id);
+CREATE INDEX IF NOT EXISTS todos_due_at_idx ON todos (due_at);
+CREATE INDEX IF NOT EXISTS todos_parent_id_idx ON todos (parent_id);
+
+CREATE TABLE IF NOT EXISTS comments
+(
+    id         INTEGER PRIMARY KEY AUTOINCREMENT,
+    todo_id    INTEGER NOT NULL REFERENCES todos (id) ON DELETE CASCADE,
+    body       TEXT NOT NULL,
+    created_at INTEGER NOT NULL DEFAULT (CAST(unixepoch('subsec') * 1000000 AS INTEGER))
+);
+
+CREATE INDEX IF NOT EXISTS comments_todo_id_idx ON comments (todo_id);
+
+-- Keeps completed_at in step with the status, whichever query changes it, as
+-- set_todo_completed_at does in Postgres. A trigger of SQLite cannot assign
+-- to NEW, so these update the row after it is written.
+CREATE TRIGGER IF NOT EXISTS todos_completed_at_insert AFTER INSERT ON todos FOR EACH ROW WHEN NEW.status = 'done'
+BEGIN
+    UPDATE todos SET completed_at = CAST(unixepoch('subsec') * 1000000 AS INTEGER) WHERE id = NEW.id;
+END;
+
+CREATE TRIGGER IF NOT EXISTS todos_completed_at_update AFTER UPDATE OF status ON todos FOR EACH ROW
+    WHEN NEW.status IS NOT OLD.status
+BEGIN
+    UPDATE todos SET completed_at = IIF(NEW.status = 'done', CAST(unixepoch('subsec') * 1000000 AS INTEGER), NULL)
+    WHERE id = NEW.id;
+END;
@@ -0,0 +1,284 @@
+#![allow(dead_code)]
+
+//!
+//! ADMIN
+//! -----
+//!
+//! Production services usually expose a second, internal set of endpoints for
+//! operators: health checks for the load balancer, metrics for Prometheus, and
+//! knobs such as reloading configuration.
+//!
+//! These endpoints must never be reachable from the public internet, so rather
+//! than mounting them under a secret path, they are served by a separate router
+//! on a separate listener (and port), which can be firewalled independently.
+//!
+
+use std::{future::Future, net::SocketAddr, sync::Arc};
+
+use axum::{
+    extract::{FromRef, State},
+    http::StatusCode,
+    Json, Router,
+};
+use tokio::{net::TcpListener, sync::RwLock};
+
+use crate::app::Routes;
+use crate::client_ip::{serve_proxy_protocol, TrustedProxies};
+use crate::config::{AppConfig, RedactedConfig};
+use crate::exposition::RenderedMetrics;
+use crate::secrets::Secrets;
+use crate::systemd::{listen_fds, notify_or_log, ActivatedListener};
+use crate::unix_socket::{serve_unix, UnixSocket};
+
+///
+/// State shared between the admin router and the public router. Cloning it is
+/// cheap, and every clone refers to the same underlying configuration, so a
+/// reload through the admin router is visible to the public router as well.
+///
+#[derive(Clone)]
+pub struct AdminState {
+    pub config: Arc<RwLock<AppConfig>>,
+    pub metrics: RenderedMetrics,
+    pub secrets: Secrets,
+}
+
+pub fn admin_routes<S>() -> Routes<S>
+where
+    AdminState: FromRef<S>,
+    S: Clone + Send + Sync + 'static,
+{
+    Routes::new()
+        .get("/health", health)
+        .get("/metrics", metrics)
+        .get("/config", get_config)
+        .post("/config/reload", reload_config)
+}
+
+async fn health() -> &'static str {
+    "OK"
+}
+
+async fn metrics(State(state): State<AdminState>) -> RenderedMetrics {
+    state.metrics
+}
+
+async fn get_config(State(state): State<AdminState>) -> Json<RedactedConfig> {
+    Json(state.config.read().await.redacted())
+}
+
+///
+/// Re-reads the configuration from the environment, and its secrets from
+/// their provider, bypassing its cache. Addresses of listeners
+/// that are already bound are not affected until the next restart.
+///
+async fn reload_config(
+    State(state): State<AdminState>,
+) -> Result<Json<RedactedConfig>, (StatusCode, String)> {
+    let config = AppConfig::load(&state.secrets.uncached()).await.map_err(|e| (StatusCode::BAD_REQUEST, e))?;
+    let redacted = config.redacted();
+    *state.config.write().await = config;
+    Ok(Json(redacted))
+}
+
+///
+/// Serves the public and the admin routers on their own listeners, each with
+/// its own graceful shutdown signal, and returns once both have shut down.
+///
+pub async fn serve_with_admin(
+    public: (TcpListener, Router),
+    admin: (TcpListener, Router),
+    public_shutdown: impl Future<Output = ()> + Send + 'static,
+    admin_shutdown: impl Future<Output = ()> + Send + 'static,
+) -> std::io::Result<()> {
+    let (public_result, admin_result) = tokio::join!(
+        serve(public, public_shutdown),
+        serve(admin, admin_shutdown)
+    );
+    public_result.and(admin_result)
+}
+
+///
+/// Binds both listeners from the configuration, or takes them from systemd
+/// (see `systemd.rs`), tells systemd that the app is ready, and serves until
+/// `shutdown` completes (Ctrl+C, usually). The public router stops accepting connections
+/// first; the admin router keeps answering health checks and metrics scrapes
+/// until the public router has finished draining in-flight requests, and only
+/// then shuts down itself.
+///
+pub async fn serve_until(
+    config: &AppConfig,
+    public_router: Router,
+    admin_router: Router,
+    shutdown: impl Future<Output = ()> + Send + 'static,
+) -> std::io::Result<()> {
+    let mut activated = listen_fds()?.into_iter();
+    let public_listener = PublicListener::bind(config, activated.next()).await?;
+    let admin_listener = match activated.next() {
+        Some(ActivatedListener::Tcp(listener)) => TcpListener::from_std(listener)?,
+        Some(ActivatedListener::Unix(_)) => {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "The admin router only listens on TCP",
+            ))
+        }
+        None => TcpListener::bind(config.admin_bind_addr).await?,
+    };
+    notify_or_log("READY=1");
+
+    let (public_done_tx, public_done_rx) = tokio::sync::oneshot::channel::<()>();
+
+    let public = async move {
+        let result = public_listener.serve(public_router, shutdown).await;
+        let _ = public_done_tx.send(());
+        result
+    };
+    let admin = serve((admin_listener, admin_router), async move {
+        let _ = public_done_rx.await;
+    });
+
+    let (public_result, admin_result) = tokio::join!(public, admin);
+    public_result.and(admin_result)
+}
+
+///
+/// The listener for the public router: a TCP address, or a Unix socket when
+/// `BIND_SOCKET` is set (see `unix_socket.rs`), or whichever systemd passed.
+///
+pub enum PublicListener {
+    Tcp(TcpListener),
+    /// Behind a proxy that speaks the PROXY protocol (see `client_ip.rs`).
+    ProxyProtocol(TcpListener, TrustedProxies),
+    Unix(UnixSocket),
+}
+
+impl PublicListener {
+    pub async fn bind(config: &AppConfig, activated: Option<ActivatedListener>) -> std::io::Result<PublicListener> {
+        let listener = match (activated, &config.bind_socket) {
+            (Some(ActivatedListener::Tcp(listener)), _) => TcpListener::from_std(listener)?,
+            (Some(ActivatedListener::Unix(listener)), _) => return Ok(PublicListener::Unix(UnixSocket::from_std(listener)?)),
+            (None, Some(path)) => return Ok(PublicListener::Unix(UnixSocket::bind(path, config.bind_socket_mode)?)),
+            (None, None) => TcpListener::bind(config.bind_addr).await?,
+        };
+        if config.proxy_protocol {
+            Ok(PublicListener::ProxyProtocol(listener, config.trusted_proxies.clone()))
+        } else {
+            Ok(PublicListener::Tcp(listener))
+        }
+    }
+
+    pub async fn serve(
+        self,
+        router: Router,
+        shutdown: impl Future<Output = ()> + Send + 'static,
+    ) -> std::io::Result<()> {
+        match self {
+            PublicListener::Tcp(listener) => serve((listener, router), shutdown).await,
+            PublicListener::ProxyProtocol(listener, trusted) => {
+                serve_proxy_protocol((listener, router), trusted, shutdown).await
+            }
+            PublicListener::Unix(socket) => serve_unix((socket, router), shutdown).await,
+        }
+    }
+}
+
+pub async fn serve(
+    (listener, router): (TcpListener, Router),
+    shutdown: impl Future<Output = ()> + Send + 'static,
+) -> std::io::Result<()> {
+    println!("Listening on {}", listener.local_addr()?);
+
+    axum::serve(listener, router.into_make_service_with_connect_info::<SocketAddr>())
+        .with_graceful_shutdown(shutdown)
+        .await
+}
+
+pub async fn ctrl_c() {
+    tokio::signal::ctrl_c()
+        .await
+        .expect("failed to install Ctrl+C handler");
+}
+
+#[tokio::test]
+async fn admin_outlives_public_shutdown() {
+    use axum::routing::get;
+    use axum_prometheus::metrics_exporter_prometheus::PrometheusBuilder;
+
+    use crate::config::PoolConfig;
+
+    let config = AppConfig {
+        database_url: "postgres://localhost/unused".to_string(),
+        database_password: None,
+        jwt_secret: "secret".to_string(),
+        bind_addr: "127.0.0.1:0".parse().unwrap(),
+        bind_socket: None,
+        bind_socket_mode: Default::default(),
+        trusted_proxies: Default::default(),
+        proxy_protocol: false,
+        admin_bind_addr: "127.0.0.1:0".parse().unwrap(),
+        attachments_dir: "attachments".into(),
+        trailing_slash: Default::default(),
+        log_filter: "info".to_string(),
+        slow_query_threshold_ms: 200,
+        expensive_route_concurrency: 8,
+        admission_latency_target_ms: 250,
+        admission_max_concurrency: 512,
+        http_cache_max_age_secs: 5,
+        geoip_dir: None,
+        maintenance_windows: Default::default(),
+        shadow_upstream: None,
+        shadow_percent: 0.0,
+        faults: Default::default(),
+        webauthn_rp_id: "localhost".to_string(),
+        webauthn_origin: "http://localhost:3000".to_string(),
+        pii_keys: "1:AQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQE=".parse().unwrap(),
+        pii_index_key: "CQkJCQkJCQkJCQkJCQkJCQkJCQkJCQkJCQkJCQkJCQk=".parse().unwrap(),
+        pool: PoolConfig::default(),
+        todo_repo: Default::default(),
+        mysql_url: None,
+        sqlite_url: None,
+        dynamodb_table: "todos".to_string(),
+        mongodb_url: None,
+        todo_database_url: None,
+        session_store: Default::default(),
+        redis_url: None,
+    };
+    let state = AdminState {
+        config: Arc::new(RwLock::new(config)),
+        metrics: RenderedMetrics::new(PrometheusBuilder::new().build_recorder().handle()),
+        secrets: Secrets::env(),
+    };
+
+    let public_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let admin_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let public_addr = public_listener.local_addr().unwrap();
+    let admin_addr = admin_listener.local_addr().unwrap();
+
+    let (stop_public, public_stopped) = tokio::sync::oneshot::channel::<()>();
+    let (stop_admin, admin_stopped) = tokio::sync::oneshot::channel::<()>();
+
+    let server = tokio::spawn(serve_with_admin(
+        (public_listener, Router::new().route("/", get(|| async { "public" }))),
+        (admin_listener, admin_routes().into_router().with_state(state)),
+        async move {
+            let _ = public_stopped.await;
+        },
+        async move {
+            let _ = admin_stopped.await;
+        },
+    ));
+
+    let client = reqwest::Client::new();
+    let public_url = format!("http://{}/", public_addr);
+    let health_url = format!("http://{}/health", admin_addr);
+
+    assert_eq!(client.get(&public_url).send().await.unwrap().text().await.unwrap(), "public");
+
+    stop_public.send(()).unwrap();
+    tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+
+    assert!(client.get(&public_url).send().await.is_err());
+    assert_eq!(client.get(&health_url).send().await.unwrap().text().await.unwrap(), "OK");
+
+    stop_admin.send(()).unwrap();
+    server.await.unwrap().unwrap();
+}
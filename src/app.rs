@@ -0,0 +1,695 @@
+#![allow(dead_code)]
+
+//!
+//! APP
+//! ---
+//!
+//! Every graduation project needs the same plumbing: routers nested under
+//! their prefixes, shared state, middleware layers, background tasks that run
+//! alongside the server, and a listener with graceful shutdown.
+//!
+//! `AppBuilder` gathers all of these pieces in one place. Tests call
+//! `into_router` to get a fully-wired `Router` they can drive with `oneshot`,
+//! while production calls `serve` to bind listeners and run until Ctrl+C.
+//!
+//! Axum cannot list the routes of a `Router`, so routers built with `Routes`
+//! also record every method and path they register. The builder collects these
+//! into a `RouteTable`, which is served by the admin router at `/routes`.
+//!
+//! To Axum, `/todo` and `/todo/` are different paths, and a client that adds
+//! or forgets a trailing slash gets a 404. The builder can be told to be
+//! lenient about this, with `TrailingSlash`.
+//!
+//! Requests that match no route, or a route but not its method, get a
+//! `Problem` instead of Axum's empty 404 and 405 bodies, with the routes of
+//! the `RouteTable` that the client most likely meant as `hints`.
+//!
+
+use std::{convert::Infallible, fmt, future::Future, pin::Pin, str::FromStr, sync::Arc};
+
+use axum::{
+    extract::{MatchedPath, Request},
+    handler::Handler,
+    http::{Method, StatusCode, Uri},
+    response::IntoResponse,
+    routing::{self, MethodRouter, Route},
+    Json, Router,
+};
+use tower::{Layer, Service};
+use tower_http::normalize_path::NormalizePath;
+
+use crate::{
+    admin::{ctrl_c, serve_until, PublicListener},
+    config::AppConfig,
+    inflight::{report_draining, InFlight},
+    problem::Problem,
+    systemd::{listen_fds, notify_or_log},
+};
+
+type BackgroundTask = Pin<Box<dyn Future<Output = ()> + Send + 'static>>;
+
+#[derive(Clone, Debug, PartialEq, serde::Serialize)]
+pub struct RouteInfo {
+    pub method: String,
+    pub path: String,
+}
+
+///
+/// The method-path pairs mounted in an application. Displays as a table, and
+/// serializes to JSON as an array of `RouteInfo`.
+///
+#[derive(Clone, Debug, Default, PartialEq, serde::Serialize)]
+#[serde(transparent)]
+pub struct RouteTable(pub Vec<RouteInfo>);
+
+impl RouteTable {
+    fn push(&mut self, method: &str, path: &str) {
+        self.0.push(RouteInfo {
+            method: method.to_string(),
+            path: path.to_string(),
+        });
+    }
+
+    fn extend(&mut self, other: RouteTable) {
+        self.0.extend(other.0);
+    }
+
+    ///
+    /// Up to `limit` routes whose paths are close to `path`, closest first.
+    /// Parameters such as `:id` match any segment, and other segments count
+    /// the edits it takes to turn one into the other, so that `/todos/5`
+    /// suggests `/todo/:id`.
+    ///
+    pub fn nearby(&self, path: &str, limit: usize) -> Vec<RouteInfo> {
+        const MAX_DISTANCE: usize = 3;
+
+        let mut routes: Vec<(usize, &RouteInfo)> = self
+            .0
+            .iter()
+            .map(|route| (path_distance(path, &route.path), route))
+            .filter(|(distance, _)| *distance <= MAX_DISTANCE)
+            .collect();
+        routes.sort_by_key(|(distance, _)| *distance);
+        routes.into_iter().take(limit).map(|(_, route)| route.clone()).collect()
+    }
+
+    ///
+    /// The routes registered at exactly this path, one per method.
+    ///
+    pub fn at(&self, path: &str) -> Vec<RouteInfo> {
+        self.0.iter().filter(|route| route.path == path).cloned().collect()
+    }
+
+    ///
+    /// Prefixes every path the same way `Router::nest` does.
+    ///
+    fn nested(self, prefix: &str) -> RouteTable {
+        RouteTable(
+            self.0
+                .into_iter()
+                .map(|route| RouteInfo {
+                    path: nested_path(prefix, &route.path),
+                    method: route.method,
+                })
+                .collect(),
+        )
+    }
+}
+
+impl fmt::Display for RouteTable {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let width = self
+            .0
+            .iter()
+            .map(|route| route.method.len())
+            .chain(std::iter::once("METHOD".len()))
+            .max()
+            .unwrap_or_default();
+
+        writeln!(f, "{:width$}  PATH", "METHOD")?;
+        for route in &self.0 {
+            writeln!(f, "{:width$}  {}", route.method, route.path)?;
+        }
+        Ok(())
+    }
+}
+
+fn segments(path: &str) -> Vec<&str> {
+    path.split('/').filter(|segment| !segment.is_empty()).collect()
+}
+
+fn is_parameter(segment: &str) -> bool {
+    segment.starts_with(':') || segment.starts_with('*')
+}
+
+///
+/// The edit distance between a request path and a route path, where
+/// replacing a segment costs the edit distance between the two, and
+/// inserting or removing one costs more than its length (but only 1 for a
+/// parameter), so that a parameter does not match the wrong segment.
+///
+fn path_distance(path: &str, route: &str) -> usize {
+    let (path, route) = (segments(path), segments(route));
+    let cost = |segment: &str| if is_parameter(segment) { 1 } else { segment.len() + 2 };
+
+    let mut previous: Vec<usize> = std::iter::once(0)
+        .chain(route.iter().scan(0, |total, segment| {
+            *total += cost(segment);
+            Some(*total)
+        }))
+        .collect();
+    for segment in &path {
+        let mut current = vec![previous[0] + cost(segment)];
+        for (j, route_segment) in route.iter().enumerate() {
+            let replace = if is_parameter(route_segment) { 0 } else { edit_distance(segment, route_segment) };
+            current.push(
+                (previous[j] + replace)
+                    .min(previous[j + 1] + cost(segment))
+                    .min(current[j] + cost(route_segment)),
+            );
+        }
+        previous = current;
+    }
+    previous[route.len()]
+}
+
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for (i, a) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, b) in b.iter().enumerate() {
+            let replace = previous[j] + usize::from(a != *b);
+            current.push(replace.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
+
+///
+/// Answers requests that match no route.
+///
+async fn route_not_found(routes: Arc<RouteTable>, method: Method, uri: Uri) -> Problem {
+    Problem::new(StatusCode::NOT_FOUND)
+        .detail(format!("No route for {} {}", method, uri.path()))
+        .with("hints", routes.nearby(uri.path(), 5))
+}
+
+///
+/// Answers requests that match a route, but none of its methods. Axum still
+/// adds the `Allow` header.
+///
+async fn method_not_allowed(routes: Arc<RouteTable>, method: Method, path: MatchedPath) -> Problem {
+    Problem::new(StatusCode::METHOD_NOT_ALLOWED)
+        .detail(format!("{} is not allowed on {}", method, path.as_str()))
+        .with("hints", routes.at(path.as_str()))
+}
+
+fn nested_path(prefix: &str, path: &str) -> String {
+    if prefix.ends_with('/') {
+        format!("{}{}", prefix, path.trim_start_matches('/'))
+    } else if path == "/" {
+        prefix.to_string()
+    } else {
+        format!("{}{}", prefix, path)
+    }
+}
+
+///
+/// A `Router` that remembers the routes registered on it. Converting a plain
+/// `Router` into `Routes` is possible, but its routes will not be listed.
+///
+pub struct Routes<S> {
+    router: Router<S>,
+    table: RouteTable,
+}
+
+impl<S: Clone + Send + Sync + 'static> Routes<S> {
+    pub fn new() -> Self {
+        Routes {
+            router: Router::new(),
+            table: RouteTable::default(),
+        }
+    }
+
+    pub fn get<H: Handler<T, S>, T: 'static>(self, path: &str, handler: H) -> Self {
+        self.route("GET", path, routing::get(handler))
+    }
+
+    pub fn post<H: Handler<T, S>, T: 'static>(self, path: &str, handler: H) -> Self {
+        self.route("POST", path, routing::post(handler))
+    }
+
+    pub fn put<H: Handler<T, S>, T: 'static>(self, path: &str, handler: H) -> Self {
+        self.route("PUT", path, routing::put(handler))
+    }
+
+    pub fn patch<H: Handler<T, S>, T: 'static>(self, path: &str, handler: H) -> Self {
+        self.route("PATCH", path, routing::patch(handler))
+    }
+
+    pub fn delete<H: Handler<T, S>, T: 'static>(self, path: &str, handler: H) -> Self {
+        self.route("DELETE", path, routing::delete(handler))
+    }
+
+    fn route(mut self, method: &str, path: &str, method_router: MethodRouter<S>) -> Self {
+        self.router = self.router.route(path, method_router);
+        self.table.push(method, path);
+        self
+    }
+
+    ///
+    /// Applies a layer to the routes added so far, each route getting its own
+    /// instance of the service it makes, as with `Router::layer`.
+    ///
+    pub fn layer<L>(mut self, layer: L) -> Self
+    where
+        L: Layer<Route> + Clone + Send + 'static,
+        L::Service: Service<Request> + Clone + Send + 'static,
+        <L::Service as Service<Request>>::Response: IntoResponse + 'static,
+        <L::Service as Service<Request>>::Error: Into<Infallible> + 'static,
+        <L::Service as Service<Request>>::Future: Send + 'static,
+    {
+        self.router = self.router.layer(layer);
+        self
+    }
+
+    pub fn merge(mut self, other: Routes<S>) -> Self {
+        self.router = self.router.merge(other.router);
+        self.table.extend(other.table);
+        self
+    }
+
+    pub fn into_router(self) -> Router<S> {
+        self.router
+    }
+}
+
+impl<S: Clone + Send + Sync + 'static> Default for Routes<S> {
+    fn default() -> Self {
+        Routes::new()
+    }
+}
+
+impl<S> From<Router<S>> for Routes<S> {
+    fn from(router: Router<S>) -> Self {
+        Routes {
+            router,
+            table: RouteTable::default(),
+        }
+    }
+}
+
+///
+/// What the app does about trailing slashes.
+///
+/// - `Strict` does nothing, like Axum: `/todo/` only reaches a route
+///   registered as `/todo/`.
+/// - `Lenient` removes trailing slashes before routing, so that `/todo/`
+///   reaches the `/todo` route. Routes must then be registered without one,
+///   or they can never be reached.
+///
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TrailingSlash {
+    #[default]
+    Strict,
+    Lenient,
+}
+
+impl FromStr for TrailingSlash {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "strict" => Ok(TrailingSlash::Strict),
+            "lenient" => Ok(TrailingSlash::Lenient),
+            _ => Err(format!("Unknown trailing slash mode: {}", s)),
+        }
+    }
+}
+
+impl TrailingSlash {
+    ///
+    /// `Router::layer` wraps the routes, which run after the router has picked
+    /// one, so a layer cannot change the path that is routed. Instead, the
+    /// whole router is wrapped, and served as the fallback of an empty one.
+    ///
+    fn apply(self, router: Router) -> Router {
+        match self {
+            TrailingSlash::Strict => router,
+            TrailingSlash::Lenient => Router::new().fallback_service(NormalizePath::trim_trailing_slash(router)),
+        }
+    }
+}
+
+///
+/// Builds an application whose routers all share the state `S`. Individual
+/// routers only ask for the parts of `S` they need, through `FromRef`.
+///
+pub struct AppBuilder<S> {
+    state: S,
+    router: Router<S>,
+    admin_router: Option<Router<S>>,
+    routes: RouteTable,
+    tasks: Vec<BackgroundTask>,
+    trailing_slash: TrailingSlash,
+    draining: Option<InFlight>,
+}
+
+impl<S: Clone + Send + Sync + 'static> AppBuilder<S> {
+    pub fn new(state: S) -> Self {
+        AppBuilder {
+            state,
+            router: Router::new(),
+            admin_router: None,
+            routes: RouteTable::default(),
+            tasks: Vec::new(),
+            trailing_slash: TrailingSlash::default(),
+            draining: None,
+        }
+    }
+
+    pub fn nest(mut self, path: &str, routes: impl Into<Routes<S>>) -> Self {
+        let Routes { router, table } = routes.into();
+        self.router = self.router.nest(path, router);
+        self.routes.extend(table.nested(path));
+        self
+    }
+
+    pub fn merge(mut self, routes: impl Into<Routes<S>>) -> Self {
+        let Routes { router, table } = routes.into();
+        self.router = self.router.merge(router);
+        self.routes.extend(table);
+        self
+    }
+
+    ///
+    /// Adds routes to the admin router, which is served on its own listener
+    /// (see the `admin` module), but shares state with the public router.
+    ///
+    pub fn admin(mut self, routes: impl Into<Routes<S>>) -> Self {
+        let router = routes.into().into_router();
+        self.admin_router = Some(self.admin_router.unwrap_or_default().merge(router));
+        self
+    }
+
+    ///
+    /// Applies a layer to all public routes added so far. As with
+    /// `Router::layer`, routes added afterwards are not wrapped by the layer.
+    ///
+    pub fn layer<L>(mut self, layer: L) -> Self
+    where
+        L: Layer<Route> + Clone + Send + 'static,
+        L::Service: Service<Request> + Clone + Send + 'static,
+        <L::Service as Service<Request>>::Response: IntoResponse + 'static,
+        <L::Service as Service<Request>>::Error: Into<Infallible> + 'static,
+        <L::Service as Service<Request>>::Future: Send + 'static,
+    {
+        self.router = self.router.layer(layer);
+        self
+    }
+
+    ///
+    /// Sets how the public router treats trailing slashes. Strict by default.
+    ///
+    pub fn trailing_slash(mut self, trailing_slash: TrailingSlash) -> Self {
+        self.trailing_slash = trailing_slash;
+        self
+    }
+
+    ///
+    /// Reports the requests that `in_flight` counts while graceful shutdown
+    /// waits for them to finish. The requests must also be counted, with the
+    /// `track_in_flight` middleware.
+    ///
+    pub fn report_draining(mut self, in_flight: InFlight) -> Self {
+        self.draining = Some(in_flight);
+        self
+    }
+
+    ///
+    /// Registers a task that runs for as long as the server does. Tasks are
+    /// only started by `serve`, never by `into_router`.
+    ///
+    pub fn background_task(mut self, task: impl Future<Output = ()> + Send + 'static) -> Self {
+        self.tasks.push(Box::pin(task));
+        self
+    }
+
+    ///
+    /// The public routes registered so far.
+    ///
+    pub fn routes(&self) -> &RouteTable {
+        &self.routes
+    }
+
+    ///
+    /// The public router, with state applied, for use in tests.
+    ///
+    pub fn into_router(self) -> Router {
+        self.public_router()
+    }
+
+    ///
+    /// The fallbacks are added last, because `method_not_allowed_fallback`
+    /// only applies to the routes that the router already has.
+    ///
+    fn public_router(&self) -> Router {
+        let routes = Arc::new(self.routes.clone());
+        let not_found_routes = routes.clone();
+        let router = self
+            .router
+            .clone()
+            .fallback(move |method: Method, uri: Uri| route_not_found(not_found_routes, method, uri))
+            .method_not_allowed_fallback(move |method: Method, path: MatchedPath| {
+                method_not_allowed(routes, method, path)
+            })
+            .with_state(self.state.clone());
+        self.trailing_slash.apply(router)
+    }
+
+    ///
+    /// The admin router, with state applied, for use in tests.
+    ///
+    pub fn into_admin_router(self) -> Router {
+        self.finish_admin_router().unwrap_or_default()
+    }
+
+    fn finish_admin_router(self) -> Option<Router> {
+        let routes = self.routes;
+        self.admin_router.map(|admin_router| {
+            admin_router
+                .route("/routes", routing::get(move || async move { Json(routes) }))
+                .with_state(self.state)
+        })
+    }
+
+    ///
+    /// Starts the background tasks and serves the public router (and the
+    /// admin router, if any admin routes were added) until Ctrl+C. Systemd,
+    /// if it started the app, hears that it is ready once its listeners are
+    /// bound, and that it is stopping on Ctrl+C.
+    ///
+    pub async fn serve(mut self, config: &AppConfig) -> std::io::Result<()> {
+        let tasks = std::mem::take(&mut self.tasks)
+            .into_iter()
+            .map(tokio::spawn)
+            .collect::<Vec<_>>();
+
+        let router = self.public_router();
+        let draining = self.draining.take();
+        let shutdown = async move {
+            ctrl_c().await;
+            notify_or_log("STOPPING=1");
+            if let Some(in_flight) = draining {
+                tokio::spawn(report_draining(in_flight, std::time::Duration::from_secs(1)));
+            }
+        };
+
+        let result = match self.finish_admin_router() {
+            Some(admin_router) => serve_until(config, router, admin_router, shutdown).await,
+            None => {
+                let listener = PublicListener::bind(config, listen_fds()?.into_iter().next()).await?;
+                notify_or_log("READY=1");
+                listener.serve(router, shutdown).await
+            }
+        };
+
+        for task in tasks {
+            task.abort();
+        }
+
+        result
+    }
+}
+
+#[tokio::test]
+async fn builder_shares_state_between_routers() {
+    // for Body::collect
+    use http_body_util::BodyExt;
+    /// for ServiceExt::oneshot
+    use tower::util::ServiceExt;
+    use axum::{body::Body, extract::State, http::{Method, StatusCode}, routing::get};
+
+    let builder = || {
+        AppBuilder::new("shared".to_string())
+            .nest("/api", Router::new().route("/", get(|State(s): State<String>| async move { s })))
+            .admin(Router::new().route("/health", get(|| async { "OK" })))
+            .layer(tower_http::timeout::TimeoutLayer::new(std::time::Duration::from_secs(1)))
+    };
+
+    let get_request = |uri: &str| {
+        Request::builder()
+            .method(Method::GET)
+            .uri(uri)
+            .body(Body::empty())
+            .unwrap()
+    };
+
+    let response = builder().into_router().oneshot(get_request("/api")).await.unwrap();
+    let body = response.into_body().collect().await.unwrap().to_bytes();
+    assert_eq!(body, "shared");
+
+    let response = builder().into_router().oneshot(get_request("/health")).await.unwrap();
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+    let response = builder().into_admin_router().oneshot(get_request("/health")).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+}
+
+#[tokio::test]
+async fn builder_lists_nested_routes() {
+    // for Body::collect
+    use http_body_util::BodyExt;
+    /// for ServiceExt::oneshot
+    use tower::util::ServiceExt;
+    use axum::{body::Body, http::Method};
+
+    let todos = Routes::<()>::new()
+        .get("/", || async {})
+        .post("/", || async {})
+        .delete("/:id", || async {});
+    let auth = Routes::<()>::new().post("/refresh", || async {});
+
+    let builder = AppBuilder::new(())
+        .nest("/todo/", todos)
+        .nest("/auth", auth)
+        .admin(Router::new());
+
+    assert_eq!(
+        builder.routes().to_string(),
+        "METHOD  PATH\n\
+         GET     /todo/\n\
+         POST    /todo/\n\
+         DELETE  /todo/:id\n\
+         POST    /auth/refresh\n"
+    );
+
+    let response = builder
+        .into_admin_router()
+        .oneshot(
+            Request::builder()
+                .method(Method::GET)
+                .uri("/routes")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+    let body = response.into_body().collect().await.unwrap().to_bytes();
+    let routes: serde_json::Value = serde_json::from_slice(&body).unwrap();
+
+    assert_eq!(routes[3], serde_json::json!({ "method": "POST", "path": "/auth/refresh" }));
+}
+
+#[tokio::test]
+async fn lenient_builder_ignores_trailing_slashes() {
+    /// for ServiceExt::oneshot
+    use tower::util::ServiceExt;
+    use axum::{body::Body, http::StatusCode};
+
+    let builder = || {
+        AppBuilder::new(())
+            .nest("/todo", Routes::new().get("/", || async {}).get("/:id", || async {}))
+    };
+    let status = |app: Router, uri: &'static str| async move {
+        app.oneshot(Request::get(uri).body(Body::empty()).unwrap()).await.unwrap().status()
+    };
+
+    let strict = builder().into_router();
+    assert_eq!(status(strict.clone(), "/todo").await, StatusCode::OK);
+    assert_eq!(status(strict.clone(), "/todo/").await, StatusCode::NOT_FOUND);
+    assert_eq!(status(strict, "/todo/5/").await, StatusCode::NOT_FOUND);
+
+    let lenient = builder().trailing_slash(TrailingSlash::Lenient).into_router();
+    assert_eq!(status(lenient.clone(), "/todo").await, StatusCode::OK);
+    assert_eq!(status(lenient.clone(), "/todo/").await, StatusCode::OK);
+    assert_eq!(status(lenient.clone(), "/todo/5/").await, StatusCode::OK);
+    assert_eq!(status(lenient, "/todo/?sort=priority").await, StatusCode::OK);
+}
+
+#[tokio::test]
+async fn unmatched_requests_get_problems_with_hints() {
+    // for Body::collect
+    use http_body_util::BodyExt;
+    /// for ServiceExt::oneshot
+    use tower::util::ServiceExt;
+    use axum::{body::Body, http::header};
+
+    let app = AppBuilder::new(())
+        .nest(
+            "/todo",
+            Routes::new()
+                .get("/", || async {})
+                .get("/:id", || async {})
+                .delete("/:id", || async {})
+                .get("/:id/comments", || async {}),
+        )
+        .nest("/auth", Routes::new().post("/refresh", || async {}))
+        .into_router();
+
+    let send = |method: Method, uri: &'static str| {
+        let app = app.clone();
+        async move {
+            let response = app.oneshot(Request::builder().method(method).uri(uri).body(Body::empty()).unwrap()).await.unwrap();
+            let (parts, body) = response.into_parts();
+            let body = body.collect().await.unwrap().to_bytes();
+            (parts, serde_json::from_slice::<serde_json::Value>(&body).unwrap())
+        }
+    };
+    let hints = |problem: &serde_json::Value| {
+        problem["hints"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|hint| format!("{} {}", hint["method"].as_str().unwrap(), hint["path"].as_str().unwrap()))
+            .collect::<Vec<_>>()
+    };
+
+    let (parts, problem) = send(Method::GET, "/todos/5/coments").await;
+    assert_eq!(parts.status, StatusCode::NOT_FOUND);
+    assert_eq!(parts.headers[header::CONTENT_TYPE], "application/problem+json");
+    assert_eq!(problem["detail"], "No route for GET /todos/5/coments");
+    assert_eq!(hints(&problem), vec!["GET /todo/:id/comments"]);
+
+    let (_, problem) = send(Method::GET, "/nothing/like/it").await;
+    assert_eq!(hints(&problem), Vec::<String>::new());
+
+    let (parts, problem) = send(Method::PUT, "/todo/5").await;
+    assert_eq!(parts.status, StatusCode::METHOD_NOT_ALLOWED);
+    assert_eq!(parts.headers[header::ALLOW], "GET,HEAD,DELETE");
+    assert_eq!(problem["detail"], "PUT is not allowed on /todo/:id");
+    assert_eq!(hints(&problem), vec!["GET /todo/:id", "DELETE /todo/:id"]);
+}
+
+#[test]
+fn path_distance_treats_parameters_as_wildcards() {
+    assert_eq!(path_distance("/todo/5", "/todo/:id"), 0);
+    assert_eq!(path_distance("/todos/5", "/todo/:id"), 1);
+    assert_eq!(path_distance("/todo/comments", "/todo/:id/comments"), 1);
+    assert_eq!(path_distance("/todo/5/x", "/todo/:id"), 3);
+    assert!(path_distance("/todo", "/auth/refresh") > 3);
+}
@@ -0,0 +1,604 @@
+#![allow(dead_code)]
+
+//!
+//! ATTACHMENTS
+//! -----------
+//!
+//! Todos can carry files: a scanned receipt, a PDF of the form to fill in.
+//! The bytes do not belong in Postgres. They go to an object store (a
+//! directory on disk here, S3 or similar in a larger deployment), and the
+//! database keeps what the app needs to know about them: which todo they
+//! belong to, their name, type, size and hash.
+//!
+//! Uploads are `multipart/form-data`, as sent by an HTML
+//! `<input type="file">`, with the file in a part named `file`. Axum's
+//! default body limit of 2 MB applies to them.
+//!
+//! Downloads stream the object to the client without reading it into memory,
+//! with the headers that make a browser handle it properly:
+//!
+//! - `Content-Type`, which the server decided when the file was uploaded by
+//!   looking at its first bytes, rather than trusting the client that sent it.
+//!   `X-Content-Type-Options: nosniff` tells browsers not to second-guess it.
+//! - `Content-Disposition: attachment`, so that the browser saves the file
+//!   under its original name instead of rendering it.
+//! - `Content-Length`, when the store knows the size, so that the browser can
+//!   show progress.
+//! - `ETag`, the hash of the content. A client that already has the file sends
+//!   it back in `If-None-Match`, and gets a `304 Not Modified` without a body.
+//!
+//! A user who can download an attachment can also share it, with
+//! `POST /todo/:id/attachments/:attachment_id/share`, which returns a signed
+//! link that downloads it without authentication until it expires (see the
+//! `signed_urls` module).
+//!
+
+use std::{
+    collections::HashMap,
+    io,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
+
+use axum::{
+    async_trait,
+    body::{Body, Bytes},
+    extract::{FromRef, Multipart, Query, State},
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use axum_extra::{
+    headers::{ETag, IfNoneMatch},
+    routing::TypedPath,
+    TypedHeader,
+};
+use base64::Engine as _;
+use sha2::{Digest, Sha256};
+use sqlx::{types::time::OffsetDateTime, Pool, Postgres};
+use time::Duration;
+use tokio_util::io::ReaderStream;
+
+use crate::app::Routes;
+use crate::auth::{Claims, JwtKeys};
+use crate::signed_urls::{SignedLink, SignedUrl, UrlSigner};
+use crate::upload::{content_disposition, read_upload, InvalidUpload, Upload};
+
+///
+/// An object read from a store. `size` is `None` when the store cannot tell
+/// without reading the whole object.
+///
+pub struct Object {
+    pub size: Option<u64>,
+    pub body: Body,
+}
+
+#[async_trait]
+pub trait ObjectStore: Send + Sync {
+    async fn put(&self, key: &str, bytes: Bytes) -> io::Result<()>;
+    ///
+    /// The object stored under `key`, or `None` if there is none.
+    ///
+    async fn get(&self, key: &str) -> io::Result<Option<Object>>;
+    ///
+    /// Deletes the object stored under `key`, if there is one.
+    ///
+    async fn delete(&self, key: &str) -> io::Result<()>;
+}
+
+pub type SharedObjectStore = Arc<dyn ObjectStore>;
+
+///
+/// Stores each object as a file under `root`, at the path given by its key.
+/// Keys are made by the app, never by clients, so they cannot point outside
+/// of `root`.
+///
+#[derive(Clone, Debug)]
+pub struct ObjectStoreFs {
+    root: PathBuf,
+}
+
+impl ObjectStoreFs {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        ObjectStoreFs { root: root.into() }
+    }
+}
+
+#[async_trait]
+impl ObjectStore for ObjectStoreFs {
+    async fn put(&self, key: &str, bytes: Bytes) -> io::Result<()> {
+        let path = self.root.join(key);
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        tokio::fs::write(path, bytes).await
+    }
+    async fn get(&self, key: &str) -> io::Result<Option<Object>> {
+        let file = match tokio::fs::File::open(self.root.join(key)).await {
+            Ok(file) => file,
+            Err(error) if error.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(error) => return Err(error),
+        };
+        let size = file.metadata().await?.len();
+        Ok(Some(Object { size: Some(size), body: Body::from_stream(ReaderStream::new(file)) }))
+    }
+    async fn delete(&self, key: &str) -> io::Result<()> {
+        match tokio::fs::remove_file(self.root.join(key)).await {
+            Err(error) if error.kind() == io::ErrorKind::NotFound => Ok(()),
+            result => result,
+        }
+    }
+}
+
+///
+/// An in-memory `ObjectStore`, for tests.
+///
+#[derive(Clone, Default)]
+pub struct ObjectStoreInMemory {
+    objects: Arc<Mutex<HashMap<String, Bytes>>>,
+}
+
+#[async_trait]
+impl ObjectStore for ObjectStoreInMemory {
+    async fn put(&self, key: &str, bytes: Bytes) -> io::Result<()> {
+        self.objects.lock().unwrap().insert(key.to_string(), bytes);
+        Ok(())
+    }
+    async fn get(&self, key: &str) -> io::Result<Option<Object>> {
+        let objects = self.objects.lock().unwrap();
+        Ok(objects.get(key).map(|bytes| Object { size: Some(bytes.len() as u64), body: Body::from(bytes.clone()) }))
+    }
+    async fn delete(&self, key: &str) -> io::Result<()> {
+        self.objects.lock().unwrap().remove(key);
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Attachment {
+    pub id: i64,
+    pub todo_id: i64,
+    pub filename: String,
+    pub content_type: String,
+    pub size: i64,
+    pub sha256: String,
+    pub object_key: String,
+    pub created_at: OffsetDateTime,
+}
+
+impl Attachment {
+    pub fn to_dto(&self) -> AttachmentDTO {
+        AttachmentDTO {
+            id: self.id,
+            filename: self.filename.clone(),
+            content_type: self.content_type.clone(),
+            size: self.size,
+            created_at: self.created_at,
+            href: TodoAttachmentDownload { id: self.todo_id, attachment_id: self.id }.to_string(),
+        }
+    }
+
+    ///
+    /// A strong ETag: two attachments with the same hash have the same bytes.
+    ///
+    pub fn etag(&self) -> ETag {
+        format!("\"{}\"", self.sha256).parse().unwrap()
+    }
+}
+
+#[derive(Debug, PartialEq, serde::Deserialize, serde::Serialize)]
+pub struct AttachmentDTO {
+    pub id: i64,
+    pub filename: String,
+    pub content_type: String,
+    pub size: i64,
+    #[serde(with = "time::serde::rfc3339")]
+    pub created_at: OffsetDateTime,
+    pub href: String,
+}
+
+#[derive(Debug)]
+pub struct NewAttachment {
+    pub filename: String,
+    pub content_type: String,
+    pub size: i64,
+    pub sha256: String,
+    pub object_key: String,
+}
+
+#[derive(Debug)]
+pub enum AttachmentError {
+    NotFound,
+    InvalidUpload(String),
+    Storage(io::Error),
+}
+
+impl From<InvalidUpload> for AttachmentError {
+    fn from(InvalidUpload(message): InvalidUpload) -> Self {
+        AttachmentError::InvalidUpload(message)
+    }
+}
+
+impl IntoResponse for AttachmentError {
+    fn into_response(self) -> Response {
+        match self {
+            AttachmentError::NotFound => (StatusCode::NOT_FOUND, "Attachment not found".to_string()),
+            AttachmentError::InvalidUpload(message) => (StatusCode::BAD_REQUEST, message),
+            AttachmentError::Storage(_) => {
+                (StatusCode::INTERNAL_SERVER_ERROR, "Could not access the attachment".to_string())
+            }
+        }
+        .into_response()
+    }
+}
+
+///
+/// Like the todo repo, every method takes the id of the user making the
+/// request, and only sees attachments of todos visible to that user.
+///
+#[async_trait]
+pub trait AttachmentRepo: Send + Sync {
+    async fn todo_visible(&self, user_id: i64, todo_id: i64) -> bool;
+    async fn create_attachment(&self, todo_id: i64, attachment: &NewAttachment) -> Attachment;
+    async fn get_attachment(&self, user_id: i64, todo_id: i64, attachment_id: i64) -> Option<Attachment>;
+}
+
+#[derive(Clone)]
+pub struct AttachmentRepoPostgres {
+    pool: Pool<Postgres>,
+}
+
+impl AttachmentRepoPostgres {
+    pub fn new(pool: Pool<Postgres>) -> Self {
+        AttachmentRepoPostgres { pool }
+    }
+}
+
+#[async_trait]
+impl AttachmentRepo for AttachmentRepoPostgres {
+    async fn todo_visible(&self, user_id: i64, todo_id: i64) -> bool {
+        let query = sqlx::query!(
+            "SELECT id FROM todos WHERE id = $1 AND todo_visible_to(owner_id, $2)",
+            todo_id,
+            user_id
+        );
+        query.fetch_optional(&self.pool).await.unwrap().is_some()
+    }
+    async fn create_attachment(&self, todo_id: i64, attachment: &NewAttachment) -> Attachment {
+        let query = sqlx::query_as!(
+            Attachment,
+            "INSERT INTO attachments (todo_id, filename, content_type, size, sha256, object_key)
+             VALUES ($1, $2, $3, $4, $5, $6)
+             RETURNING id, todo_id, filename, content_type, size, sha256, object_key, created_at",
+            todo_id,
+            attachment.filename,
+            attachment.content_type,
+            attachment.size,
+            attachment.sha256,
+            attachment.object_key
+        );
+        query.fetch_one(&self.pool).await.unwrap()
+    }
+    async fn get_attachment(&self, user_id: i64, todo_id: i64, attachment_id: i64) -> Option<Attachment> {
+        let query = sqlx::query_as!(
+            Attachment,
+            "SELECT attachments.id, attachments.todo_id, filename, content_type, size, sha256, object_key, attachments.created_at
+             FROM attachments JOIN todos ON todos.id = attachments.todo_id
+             WHERE attachments.id = $1 AND attachments.todo_id = $2 AND todo_visible_to(todos.owner_id, $3)",
+            attachment_id,
+            todo_id,
+            user_id
+        );
+        query.fetch_optional(&self.pool).await.unwrap()
+    }
+}
+
+#[derive(Clone)]
+pub struct AttachmentState<R: AttachmentRepo> {
+    pub repo: R,
+    pub store: SharedObjectStore,
+}
+
+#[derive(Debug, TypedPath, serde::Deserialize)]
+#[typed_path("/todo/:id/attachments")]
+pub struct TodoAttachments {
+    pub id: i64,
+}
+
+#[derive(Debug, TypedPath, serde::Deserialize)]
+#[typed_path("/todo/:id/attachments/:attachment_id/download")]
+pub struct TodoAttachmentDownload {
+    pub id: i64,
+    pub attachment_id: i64,
+}
+
+#[derive(Debug, TypedPath, serde::Deserialize)]
+#[typed_path("/todo/:id/attachments/:attachment_id/share")]
+pub struct TodoAttachmentShare {
+    pub id: i64,
+    pub attachment_id: i64,
+}
+
+#[derive(Debug, TypedPath, serde::Deserialize)]
+#[typed_path("/shared/todo/:id/attachments/:attachment_id/download")]
+pub struct SharedAttachmentDownload {
+    pub id: i64,
+    pub attachment_id: i64,
+}
+
+///
+/// How long a shared link stays valid, in seconds, a day by default.
+///
+#[derive(Debug, serde::Deserialize)]
+pub struct ShareQuery {
+    pub expires_in: Option<i64>,
+}
+
+impl ShareQuery {
+    pub fn ttl(&self) -> Duration {
+        self.expires_in.map(Duration::seconds).unwrap_or(Duration::days(1))
+    }
+}
+
+///
+/// Whose view a signed link shows: the user who shared it. The link stops
+/// working when that user can no longer see what it points to.
+///
+#[derive(Debug, serde::Deserialize)]
+pub struct SharedBy {
+    pub user: i64,
+}
+
+pub fn attachment_routes<S, R>() -> Routes<S>
+where
+    R: AttachmentRepo + Clone + 'static,
+    AttachmentState<R>: FromRef<S>,
+    JwtKeys: FromRef<S>,
+    UrlSigner: FromRef<S>,
+    S: Clone + Send + Sync + 'static,
+{
+    Routes::new()
+        .post(TodoAttachments::PATH, upload_attachment::<R>)
+        .get(TodoAttachmentDownload::PATH, download_attachment::<R>)
+        .post(TodoAttachmentShare::PATH, share_attachment::<R>)
+        .get(SharedAttachmentDownload::PATH, download_shared_attachment::<R>)
+}
+
+async fn upload_attachment<R: AttachmentRepo>(
+    Claims { sub: user_id, .. }: Claims,
+    TodoAttachments { id }: TodoAttachments,
+    State(AttachmentState { repo, store }): State<AttachmentState<R>>,
+    mut multipart: Multipart,
+) -> Result<(StatusCode, Json<AttachmentDTO>), AttachmentError> {
+    if !repo.todo_visible(user_id, id).await {
+        return Err(AttachmentError::NotFound);
+    }
+
+    let Upload { filename, content_type, bytes } = read_upload(&mut multipart).await?;
+    let attachment = NewAttachment {
+        filename,
+        content_type,
+        size: bytes.len() as i64,
+        sha256: base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(Sha256::digest(&bytes)),
+        object_key: format!("todos/{}/{}", id, uuid::Uuid::new_v4()),
+    };
+    store.put(&attachment.object_key, bytes).await.map_err(AttachmentError::Storage)?;
+    let attachment = repo.create_attachment(id, &attachment).await;
+
+    Ok((StatusCode::CREATED, Json(attachment.to_dto())))
+}
+
+async fn download_attachment<R: AttachmentRepo>(
+    Claims { sub: user_id, .. }: Claims,
+    TodoAttachmentDownload { id, attachment_id }: TodoAttachmentDownload,
+    State(state): State<AttachmentState<R>>,
+    if_none_match: Option<TypedHeader<IfNoneMatch>>,
+) -> Result<Response, AttachmentError> {
+    send_attachment(state, user_id, id, attachment_id, if_none_match).await
+}
+
+async fn share_attachment<R: AttachmentRepo>(
+    Claims { sub: user_id, .. }: Claims,
+    TodoAttachmentShare { id, attachment_id }: TodoAttachmentShare,
+    State(AttachmentState { repo, .. }): State<AttachmentState<R>>,
+    State(signer): State<UrlSigner>,
+    Query(query): Query<ShareQuery>,
+) -> Result<Json<SignedLink>, AttachmentError> {
+    repo.get_attachment(user_id, id, attachment_id).await.ok_or(AttachmentError::NotFound)?;
+    let path = format!("{}?user={}", SharedAttachmentDownload { id, attachment_id }, user_id);
+    tracing::info!(target: "audit", user_id, todo_id = id, attachment_id, "Attachment shared");
+    Ok(Json(signer.sign(&path, query.ttl())))
+}
+
+async fn download_shared_attachment<R: AttachmentRepo>(
+    _: SignedUrl,
+    SharedAttachmentDownload { id, attachment_id }: SharedAttachmentDownload,
+    Query(SharedBy { user }): Query<SharedBy>,
+    State(state): State<AttachmentState<R>>,
+    if_none_match: Option<TypedHeader<IfNoneMatch>>,
+) -> Result<Response, AttachmentError> {
+    send_attachment(state, user, id, attachment_id, if_none_match).await
+}
+
+async fn send_attachment<R: AttachmentRepo>(
+    AttachmentState { repo, store }: AttachmentState<R>,
+    user_id: i64,
+    id: i64,
+    attachment_id: i64,
+    if_none_match: Option<TypedHeader<IfNoneMatch>>,
+) -> Result<Response, AttachmentError> {
+    let attachment = repo.get_attachment(user_id, id, attachment_id).await.ok_or(AttachmentError::NotFound)?;
+    let etag = attachment.etag();
+
+    if let Some(TypedHeader(if_none_match)) = if_none_match {
+        if !if_none_match.precondition_passes(&etag) {
+            return Ok((StatusCode::NOT_MODIFIED, TypedHeader(etag)).into_response());
+        }
+    }
+
+    let object = store
+        .get(&attachment.object_key)
+        .await
+        .map_err(AttachmentError::Storage)?
+        .ok_or(AttachmentError::NotFound)?;
+
+    let mut response = (
+        TypedHeader(etag),
+        [
+            (header::CONTENT_TYPE, attachment.content_type.clone()),
+            (header::CONTENT_DISPOSITION, content_disposition(&attachment.filename)),
+            (header::X_CONTENT_TYPE_OPTIONS, "nosniff".to_string()),
+            // Private to the user, and checked with the ETag before reuse.
+            (header::CACHE_CONTROL, "private, no-cache".to_string()),
+        ],
+        object.body,
+    )
+        .into_response();
+    if let Some(size) = object.size {
+        response.headers_mut().insert(header::CONTENT_LENGTH, size.into());
+    }
+    Ok(response)
+}
+
+#[tokio::test]
+async fn attachments_download_with_their_headers() {
+    // for Body::collect
+    use http_body_util::BodyExt;
+    /// for ServiceExt::oneshot
+    use tower::util::ServiceExt;
+    use axum::{http::Request, Router};
+    use sqlx::postgres::PgPoolOptions;
+
+    use crate::auth::{AuthState, RefreshTokenRepoInMemory};
+    use crate::users::create_test_user;
+
+    #[derive(Clone, FromRef)]
+    struct TestState {
+        attachments: AttachmentState<AttachmentRepoPostgres>,
+        keys: JwtKeys,
+        signer: UrlSigner,
+    }
+
+    let pool = PgPoolOptions::new()
+        .max_connections(1)
+        .connect(&crate::test_db::database_url().await)
+        .await
+        .unwrap();
+
+    let keys = JwtKeys::from_secret(b"secret");
+    let auth = AuthState { repo: RefreshTokenRepoInMemory::default(), keys: keys.clone() };
+    let state = TestState {
+        attachments: AttachmentState {
+            repo: AttachmentRepoPostgres::new(pool.clone()),
+            store: Arc::new(ObjectStoreInMemory::default()),
+        },
+        keys,
+        signer: UrlSigner::from_secret(b"secret", Arc::new(crate::clock::SystemClock)),
+    };
+    let app: Router = attachment_routes::<_, AttachmentRepoPostgres>().into_router().with_state(state);
+
+    let owner = create_test_user(&pool, false).await;
+    let stranger = create_test_user(&pool, false).await;
+    let todo_id = sqlx::query!(
+        "INSERT INTO todos (title, description, owner_id) VALUES ('Taxes', '', $1) RETURNING id",
+        owner
+    )
+    .fetch_one(&pool)
+    .await
+    .unwrap()
+    .id;
+
+    let bearer = |user_id: i64| {
+        let auth = auth.clone();
+        async move { format!("Bearer {}", auth.issue_tokens(user_id).await.access_token) }
+    };
+
+    let pdf = b"%PDF-1.7\n...".as_slice();
+    let boundary = "X-BOUNDARY";
+    let mut body = format!(
+        "--{boundary}\r\nContent-Disposition: form-data; name=\"file\"; filename=\"kvittering æ.pdf\"\r\nContent-Type: text/plain\r\n\r\n"
+    )
+    .into_bytes();
+    body.extend_from_slice(pdf);
+    body.extend_from_slice(format!("\r\n--{boundary}--\r\n").as_bytes());
+
+    let request = Request::post(TodoAttachments { id: todo_id }.to_string())
+        .header(header::AUTHORIZATION, bearer(owner).await)
+        .header(header::CONTENT_TYPE, format!("multipart/form-data; boundary={}", boundary))
+        .body(Body::from(body))
+        .unwrap();
+    let response = app.clone().oneshot(request).await.unwrap();
+    assert_eq!(response.status(), StatusCode::CREATED);
+    let body = response.into_body().collect().await.unwrap().to_bytes();
+    let attachment: AttachmentDTO = serde_json::from_slice(&body).unwrap();
+    assert_eq!(attachment.content_type, "application/pdf");
+    assert_eq!(attachment.size, pdf.len() as i64);
+
+    let download = |user_id: i64, if_none_match: Option<String>| {
+        let app = app.clone();
+        let href = attachment.href.clone();
+        let bearer = bearer(user_id);
+        async move {
+            let mut request = Request::get(href).header(header::AUTHORIZATION, bearer.await);
+            if let Some(etag) = if_none_match {
+                request = request.header(header::IF_NONE_MATCH, etag);
+            }
+            app.oneshot(request.body(Body::empty()).unwrap()).await.unwrap()
+        }
+    };
+
+    let response = download(owner, None).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let headers = response.headers().clone();
+    assert_eq!(headers[header::CONTENT_TYPE], "application/pdf");
+    assert_eq!(headers[header::CONTENT_LENGTH], pdf.len().to_string().as_str());
+    assert_eq!(headers[header::X_CONTENT_TYPE_OPTIONS], "nosniff");
+    assert_eq!(
+        headers[header::CONTENT_DISPOSITION],
+        r#"attachment; filename="kvittering _.pdf"; filename*=UTF-8''kvittering%20%C3%A6.pdf"#
+    );
+    let body = response.into_body().collect().await.unwrap().to_bytes();
+    assert_eq!(&body[..], pdf);
+
+    let etag = headers[header::ETAG].to_str().unwrap().to_string();
+    let response = download(owner, Some(etag.clone())).await;
+    assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+    assert_eq!(response.headers()[header::ETAG], etag.as_str());
+    let body = response.into_body().collect().await.unwrap().to_bytes();
+    assert!(body.is_empty());
+
+    let response = download(owner, Some("\"something-else\"".to_string())).await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let response = download(stranger, None).await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+    let share = |user_id: i64| {
+        let app = app.clone();
+        let bearer = bearer(user_id);
+        let uri = TodoAttachmentShare { id: todo_id, attachment_id: attachment.id }.to_string();
+        async move {
+            let request = Request::post(uri).header(header::AUTHORIZATION, bearer.await).body(Body::empty()).unwrap();
+            app.oneshot(request).await.unwrap()
+        }
+    };
+
+    let response = share(stranger).await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    let response = share(owner).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = response.into_body().collect().await.unwrap().to_bytes();
+    let link: SignedLink = serde_json::from_slice(&body).unwrap();
+
+    let get = |uri: String| app.clone().oneshot(Request::get(uri).body(Body::empty()).unwrap());
+    let response = get(link.url.clone()).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.headers()[header::CONTENT_TYPE], "application/pdf");
+    let body = response.into_body().collect().await.unwrap().to_bytes();
+    assert_eq!(&body[..], pdf);
+
+    let forged = link.url.replace(&format!("user={}", owner), &format!("user={}", stranger));
+    assert_eq!(get(forged).await.unwrap().status(), StatusCode::FORBIDDEN);
+    let unsigned = SharedAttachmentDownload { id: todo_id, attachment_id: attachment.id }.to_string();
+    assert_eq!(get(format!("{}?user={}", unsigned, owner)).await.unwrap().status(), StatusCode::FORBIDDEN);
+}
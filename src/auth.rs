@@ -0,0 +1,1249 @@
+#![allow(dead_code)]
+
+//!
+//! AUTH
+//! ----
+//!
+//! Most web applications need to know who is making a request. A popular way
+//! of answering that question for APIs is the JSON Web Token (JWT): a signed,
+//! self-contained token that the server can verify without a database lookup.
+//!
+//! Because JWTs cannot be revoked once issued, they are kept short-lived, and
+//! paired with a long-lived, opaque refresh token that *is* stored (hashed) in
+//! the database. Exchanging a refresh token for a new pair rotates it, and any
+//! attempt to reuse an already-rotated refresh token is treated as theft: the
+//! whole token family is revoked, logging out both the thief and the victim.
+//!
+//! Browsers can also keep the refresh token for the user: logging in with
+//! "remember me" stores it in a private (encrypted) cookie, scoped to `/auth`,
+//! which `/auth/refresh` reads when the request has no body.
+//!
+//! Each refresh token family is a session: one login, on one device. It
+//! slides: every refresh pushes its expiry `REFRESH_TOKEN_TTL` further, so a
+//! session only ends once it has gone unused that long, or when it is
+//! revoked. Users list their sessions at `GET /users/me/sessions`, and end
+//! the one on a lost phone with `DELETE /users/me/sessions/:id`. A user has
+//! at most `MAX_SESSIONS` at once: logging in again beyond that ends the
+//! sessions that were used the longest ago.
+//!
+//! Sessions are kept in Postgres, or, with `SESSION_STORE=redis`, in Redis,
+//! which drops those that went unused for `REFRESH_TOKEN_TTL` by itself (see
+//! `RefreshTokenRepoRedis`). Tests keep them in memory.
+//!
+//! Users who enabled two-factor authentication (see `totp.rs`) get their
+//! tokens only once they also gave a code. Those sessions are marked, and so
+//! are their access tokens, with the `mfa` claim.
+//!
+
+use axum::{
+    async_trait,
+    extract::{FromRef, FromRequestParts, State},
+    http::{request::Parts, StatusCode},
+    response::{IntoResponse, Response},
+    Extension, Json,
+};
+use axum_extra::extract::cookie::{Cookie, Key, PrivateCookieJar, SameSite};
+use base64::Engine as _;
+use jsonwebtoken::{DecodingKey, EncodingKey, Header, Validation};
+use rand::RngCore;
+use sha2::{Digest, Sha256, Sha512};
+use sqlx::{Pool, Postgres};
+#[cfg(feature = "redis")]
+use std::collections::HashMap;
+use std::sync::Arc;
+use time::{Duration, OffsetDateTime, PrimitiveDateTime};
+use tokio::sync::Mutex;
+
+use crate::app::Routes;
+use crate::clock::{SharedClock, SystemClock};
+use crate::ids::{IdGenerator, SequenceIds};
+use crate::remember_me::RestoredSession;
+
+const ACCESS_TOKEN_TTL: Duration = Duration::minutes(15);
+const REFRESH_TOKEN_TTL: Duration = Duration::days(30);
+const MAX_SESSIONS: usize = 10;
+///
+/// How long after `exp` an access token is still accepted, to allow for clock
+/// skew between servers.
+///
+const ACCESS_TOKEN_LEEWAY: Duration = Duration::minutes(1);
+
+const BASE64_URL: base64::engine::GeneralPurpose = base64::engine::general_purpose::URL_SAFE_NO_PAD;
+
+///
+/// The private cookie that holds the refresh token of a remembered login.
+///
+pub const REFRESH_TOKEN_COOKIE: &str = "refresh_token";
+
+///
+/// The claims carried inside every access token. `sub` is the id of the user
+/// the token was issued to; `exp` and `iat` are Unix timestamps. `mfa` is
+/// set when the session started with a second factor.
+///
+#[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct Claims {
+    pub sub: i64,
+    pub exp: i64,
+    pub iat: i64,
+    #[serde(default)]
+    pub mfa: bool,
+}
+
+#[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct TokenPair {
+    pub access_token: String,
+    pub refresh_token: String,
+    pub token_type: String,
+    pub expires_in: i64,
+}
+
+///
+/// The keys used to sign and verify access tokens. Handlers do not need the
+/// full `AuthState` to authenticate a request, only these keys, which is why
+/// the `Claims` extractor asks for `JwtKeys: FromRef<S>`.
+///
+/// The keys also carry the clock that tokens are issued and checked against,
+/// which is the system clock unless replaced with `with_clock`, and the key
+/// of private cookies, derived from the same secret.
+///
+#[derive(Clone)]
+pub struct JwtKeys {
+    encoding: EncodingKey,
+    decoding: DecodingKey,
+    cookie: Key,
+    clock: SharedClock,
+}
+
+impl JwtKeys {
+    pub fn from_secret(secret: &[u8]) -> Self {
+        JwtKeys {
+            encoding: EncodingKey::from_secret(secret),
+            decoding: DecodingKey::from_secret(secret),
+            // A cookie `Key` takes exactly 64 bytes, whatever the secret's length.
+            cookie: Key::from(&Sha512::digest(secret)),
+            clock: Arc::new(SystemClock),
+        }
+    }
+
+    pub fn with_clock(self, clock: SharedClock) -> Self {
+        JwtKeys { clock, ..self }
+    }
+
+    pub fn cookie_key(&self) -> Key {
+        self.cookie.clone()
+    }
+
+    pub fn now(&self) -> OffsetDateTime {
+        self.clock.now()
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct RefreshToken {
+    pub id: i64,
+    pub user_id: i64,
+    pub family_id: String,
+    pub token_hash: String,
+    pub expires_at: PrimitiveDateTime,
+    pub used_at: Option<PrimitiveDateTime>,
+    pub revoked_at: Option<PrimitiveDateTime>,
+    pub created_at: PrimitiveDateTime,
+    pub mfa: bool,
+}
+
+///
+/// A refresh token family that can still be refreshed, as its user sees it.
+///
+#[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct Session {
+    pub id: String,
+    #[serde(with = "time::serde::rfc3339")]
+    pub started_at: OffsetDateTime,
+    #[serde(with = "time::serde::rfc3339")]
+    pub last_used_at: OffsetDateTime,
+    #[serde(with = "time::serde::rfc3339")]
+    pub expires_at: OffsetDateTime,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum AuthError {
+    MissingToken,
+    InvalidToken,
+    ExpiredToken,
+    TokenReuse,
+    InvalidCredentials,
+    InvalidCode,
+}
+
+impl IntoResponse for AuthError {
+    fn into_response(self) -> Response {
+        let message = match self {
+            AuthError::MissingToken => "Missing bearer token",
+            AuthError::InvalidToken => "Invalid token",
+            AuthError::ExpiredToken => "Expired token",
+            AuthError::TokenReuse => "Refresh token reuse detected, please log in again",
+            AuthError::InvalidCredentials => "Invalid email or password",
+            AuthError::InvalidCode => "Invalid two-factor code",
+        };
+        (StatusCode::UNAUTHORIZED, message).into_response()
+    }
+}
+
+#[async_trait]
+pub trait RefreshTokenRepo: Send + Sync {
+    async fn insert(
+        &self,
+        user_id: i64,
+        family_id: &str,
+        token_hash: &str,
+        created_at: PrimitiveDateTime,
+        expires_at: PrimitiveDateTime,
+        mfa: bool,
+    ) -> i64;
+    /// Atomically marks an unused, unrevoked token as used, returning it.
+    /// Returns `None` if no such token exists, or if it was already used.
+    async fn consume(&self, token_hash: &str, now: PrimitiveDateTime) -> Option<RefreshToken>;
+    async fn find(&self, token_hash: &str) -> Option<RefreshToken>;
+    async fn revoke_family(&self, family_id: &str, now: PrimitiveDateTime) -> u64;
+    async fn revoke_user(&self, user_id: i64, now: PrimitiveDateTime) -> u64;
+    ///
+    /// The sessions of the user, oldest first: the families that still have
+    /// an unused, unrevoked and unexpired token.
+    ///
+    async fn sessions(&self, user_id: i64, now: PrimitiveDateTime) -> Vec<Session>;
+}
+
+#[derive(Clone)]
+pub struct RefreshTokenRepoPostgres {
+    pool: Pool<Postgres>,
+}
+
+impl RefreshTokenRepoPostgres {
+    pub fn new(pool: Pool<Postgres>) -> Self {
+        RefreshTokenRepoPostgres { pool }
+    }
+}
+
+#[async_trait]
+impl RefreshTokenRepo for RefreshTokenRepoPostgres {
+    async fn insert(
+        &self,
+        user_id: i64,
+        family_id: &str,
+        token_hash: &str,
+        created_at: PrimitiveDateTime,
+        expires_at: PrimitiveDateTime,
+        mfa: bool,
+    ) -> i64 {
+        let query = sqlx::query!(
+            "INSERT INTO refresh_tokens (user_id, family_id, token_hash, created_at, expires_at, mfa) VALUES ($1, $2, $3, $4, $5, $6) RETURNING id",
+            user_id,
+            family_id,
+            token_hash,
+            created_at,
+            expires_at,
+            mfa
+        );
+        query.fetch_one(&self.pool).await.unwrap().id
+    }
+    async fn consume(&self, token_hash: &str, now: PrimitiveDateTime) -> Option<RefreshToken> {
+        let query = sqlx::query_as!(
+            RefreshToken,
+            "UPDATE refresh_tokens SET used_at = $2 WHERE token_hash = $1 AND used_at IS NULL AND revoked_at IS NULL RETURNING id, user_id, family_id, token_hash, expires_at, used_at, revoked_at, created_at, mfa",
+            token_hash,
+            now
+        );
+        query.fetch_optional(&self.pool).await.unwrap()
+    }
+    async fn find(&self, token_hash: &str) -> Option<RefreshToken> {
+        let query = sqlx::query_as!(
+            RefreshToken,
+            "SELECT id, user_id, family_id, token_hash, expires_at, used_at, revoked_at, created_at, mfa FROM refresh_tokens WHERE token_hash = $1",
+            token_hash
+        );
+        query.fetch_optional(&self.pool).await.unwrap()
+    }
+    async fn revoke_family(&self, family_id: &str, now: PrimitiveDateTime) -> u64 {
+        let query = sqlx::query!(
+            "UPDATE refresh_tokens SET revoked_at = $2 WHERE family_id = $1 AND revoked_at IS NULL",
+            family_id,
+            now
+        );
+        query.execute(&self.pool).await.unwrap().rows_affected()
+    }
+    async fn revoke_user(&self, user_id: i64, now: PrimitiveDateTime) -> u64 {
+        let query = sqlx::query!(
+            "UPDATE refresh_tokens SET revoked_at = $2 WHERE user_id = $1 AND revoked_at IS NULL",
+            user_id,
+            now
+        );
+        query.execute(&self.pool).await.unwrap().rows_affected()
+    }
+    async fn sessions(&self, user_id: i64, now: PrimitiveDateTime) -> Vec<Session> {
+        let query = sqlx::query!(
+            r#"SELECT family_id, MIN(created_at) AS "started_at!", MAX(created_at) AS "last_used_at!", MAX(expires_at) AS "expires_at!"
+            FROM refresh_tokens WHERE user_id = $1
+            GROUP BY family_id
+            HAVING bool_or(used_at IS NULL AND revoked_at IS NULL AND expires_at > $2)
+            ORDER BY MIN(created_at), family_id"#,
+            user_id,
+            now
+        );
+        let rows = query.fetch_all(&self.pool).await.unwrap();
+        rows.into_iter()
+            .map(|row| Session {
+                id: row.family_id,
+                started_at: row.started_at.assume_utc(),
+                last_used_at: row.last_used_at.assume_utc(),
+                expires_at: row.expires_at.assume_utc(),
+            })
+            .collect()
+    }
+}
+
+///
+/// An in-memory implementation of `RefreshTokenRepo`, for testing the token
+/// rotation logic without a database.
+///
+#[derive(Clone, Default)]
+pub struct RefreshTokenRepoInMemory {
+    tokens: Arc<Mutex<Vec<RefreshToken>>>,
+    ids: SequenceIds,
+}
+
+#[async_trait]
+impl RefreshTokenRepo for RefreshTokenRepoInMemory {
+    async fn insert(
+        &self,
+        user_id: i64,
+        family_id: &str,
+        token_hash: &str,
+        created_at: PrimitiveDateTime,
+        expires_at: PrimitiveDateTime,
+        mfa: bool,
+    ) -> i64 {
+        let mut tokens = self.tokens.lock().await;
+        let id = self.ids.next_id();
+        tokens.push(RefreshToken {
+            id,
+            user_id,
+            family_id: family_id.to_string(),
+            token_hash: token_hash.to_string(),
+            expires_at,
+            used_at: None,
+            revoked_at: None,
+            created_at,
+            mfa,
+        });
+        id
+    }
+    async fn consume(&self, token_hash: &str, now: PrimitiveDateTime) -> Option<RefreshToken> {
+        let mut tokens = self.tokens.lock().await;
+        let token = tokens.iter_mut().find(|token| {
+            token.token_hash == token_hash && token.used_at.is_none() && token.revoked_at.is_none()
+        })?;
+        token.used_at = Some(now);
+        Some(token.clone())
+    }
+    async fn find(&self, token_hash: &str) -> Option<RefreshToken> {
+        let tokens = self.tokens.lock().await;
+        tokens.iter().find(|token| token.token_hash == token_hash).cloned()
+    }
+    async fn revoke_family(&self, family_id: &str, now: PrimitiveDateTime) -> u64 {
+        let mut tokens = self.tokens.lock().await;
+        let mut count = 0;
+        for token in tokens.iter_mut() {
+            if token.family_id == family_id && token.revoked_at.is_none() {
+                token.revoked_at = Some(now);
+                count += 1;
+            }
+        }
+        count
+    }
+    async fn revoke_user(&self, user_id: i64, now: PrimitiveDateTime) -> u64 {
+        let mut tokens = self.tokens.lock().await;
+        let mut count = 0;
+        for token in tokens.iter_mut() {
+            if token.user_id == user_id && token.revoked_at.is_none() {
+                token.revoked_at = Some(now);
+                count += 1;
+            }
+        }
+        count
+    }
+    async fn sessions(&self, user_id: i64, now: PrimitiveDateTime) -> Vec<Session> {
+        let tokens = self.tokens.lock().await;
+        sessions_of(tokens.iter().filter(|token| token.user_id == user_id), now)
+    }
+}
+
+///
+/// The sessions of some tokens, as the `GROUP BY` of the Postgres repo makes
+/// them, for the repos that have to gather them by hand.
+///
+fn sessions_of<'a>(tokens: impl Iterator<Item = &'a RefreshToken> + Clone, now: PrimitiveDateTime) -> Vec<Session> {
+    let mut sessions: Vec<Session> = Vec::new();
+    for token in tokens.clone() {
+        match sessions.iter_mut().find(|session| session.id == token.family_id) {
+            Some(session) => {
+                session.started_at = session.started_at.min(token.created_at.assume_utc());
+                session.last_used_at = session.last_used_at.max(token.created_at.assume_utc());
+                session.expires_at = session.expires_at.max(token.expires_at.assume_utc());
+            }
+            None => sessions.push(Session {
+                id: token.family_id.clone(),
+                started_at: token.created_at.assume_utc(),
+                last_used_at: token.created_at.assume_utc(),
+                expires_at: token.expires_at.assume_utc(),
+            }),
+        }
+    }
+    sessions.retain(|session| {
+        tokens.clone().any(|token| {
+            token.family_id == session.id
+                && token.used_at.is_none()
+                && token.revoked_at.is_none()
+                && token.expires_at > now
+        })
+    });
+    sessions.sort_by(|a, b| (a.started_at, &a.id).cmp(&(b.started_at, &b.id)));
+    sessions
+}
+
+///
+/// The repo that `SESSION_STORE` picks, when the app starts.
+///
+pub type SharedRefreshTokenRepo = Arc<dyn RefreshTokenRepo>;
+
+#[async_trait]
+impl RefreshTokenRepo for SharedRefreshTokenRepo {
+    async fn insert(
+        &self,
+        user_id: i64,
+        family_id: &str,
+        token_hash: &str,
+        created_at: PrimitiveDateTime,
+        expires_at: PrimitiveDateTime,
+        mfa: bool,
+    ) -> i64 {
+        self.as_ref().insert(user_id, family_id, token_hash, created_at, expires_at, mfa).await
+    }
+    async fn consume(&self, token_hash: &str, now: PrimitiveDateTime) -> Option<RefreshToken> {
+        self.as_ref().consume(token_hash, now).await
+    }
+    async fn find(&self, token_hash: &str) -> Option<RefreshToken> {
+        self.as_ref().find(token_hash).await
+    }
+    async fn revoke_family(&self, family_id: &str, now: PrimitiveDateTime) -> u64 {
+        self.as_ref().revoke_family(family_id, now).await
+    }
+    async fn revoke_user(&self, user_id: i64, now: PrimitiveDateTime) -> u64 {
+        self.as_ref().revoke_user(user_id, now).await
+    }
+    async fn sessions(&self, user_id: i64, now: PrimitiveDateTime) -> Vec<Session> {
+        self.as_ref().sessions(user_id, now).await
+    }
+}
+
+///
+/// A `RefreshTokenRepo` in Redis, where sessions expire by themselves.
+///
+/// Each token is a hash, at `refresh_token:<hash>`. The hashes of a family
+/// are in the set `refresh_family:<family>`, and the families of a user in
+/// the sorted set `refresh_sessions:<user>`, by the time they started. The
+/// keys of a family expire with its newest token, and every use of the
+/// session, which adds the next token, pushes the expiry of them all
+/// `REFRESH_TOKEN_TTL` further. A session that goes unused that long is
+/// dropped by Redis, with nothing to clean up, where Postgres keeps the rows
+/// of expired tokens, and the in-memory repo keeps them until the process
+/// ends. The used tokens of a family stay as long as it does, so that their
+/// reuse is still caught.
+///
+/// The scripts run each change as one command, as the `UPDATE`s of Postgres
+/// and the lock of the in-memory repo do, so that a token can only be
+/// consumed once, even by two instances of the app at the same time.
+///
+#[cfg(feature = "redis")]
+#[derive(Clone)]
+pub struct RefreshTokenRepoRedis {
+    connection: Arc<tokio::sync::OnceCell<redis::aio::ConnectionManager>>,
+    client: redis::Client,
+}
+
+#[cfg(feature = "redis")]
+impl RefreshTokenRepoRedis {
+    ///
+    /// The repo of the Redis at `url`, which it connects to when it is first
+    /// used, and reconnects to whenever the connection is lost.
+    ///
+    pub fn connect_lazy(url: &str) -> Self {
+        let client = redis::Client::open(url).expect("REDIS_URL must be a redis:// URL");
+        RefreshTokenRepoRedis { connection: Arc::default(), client }
+    }
+
+    async fn connection(&self) -> redis::aio::ConnectionManager {
+        let connect = || redis::aio::ConnectionManager::new(self.client.clone());
+        self.connection.get_or_try_init(connect).await.unwrap().clone()
+    }
+
+    async fn token(&self, token_hash: &str) -> Option<RefreshToken> {
+        use redis::AsyncCommands;
+
+        let fields: HashMap<String, String> = self.connection().await.hgetall(token_key(token_hash)).await.unwrap();
+        token_of(fields)
+    }
+}
+
+///
+/// Adds a token to its family and its family to the sessions of the user,
+/// and pushes the expiry of every key of the family to that of the token.
+///
+/// `KEYS`: the token, its family, and the sessions of its user.
+/// `ARGV`: the expiry in milliseconds, the start of the family, its id, the
+/// hash of the token, then the fields of the token.
+///
+#[cfg(feature = "redis")]
+const INSERT: &str = r"
+redis.call('HSET', KEYS[1], unpack(ARGV, 5))
+redis.call('SADD', KEYS[2], ARGV[4])
+redis.call('ZADD', KEYS[3], 'NX', ARGV[2], ARGV[3])
+for _, hash in ipairs(redis.call('SMEMBERS', KEYS[2])) do
+    redis.call('PEXPIREAT', 'refresh_token:' .. hash, ARGV[1])
+end
+redis.call('PEXPIREAT', KEYS[2], ARGV[1])
+redis.call('PEXPIREAT', KEYS[3], ARGV[1])
+";
+
+///
+/// Marks a token used, unless it is used or revoked already, or gone.
+///
+/// `KEYS`: the token. `ARGV`: the time.
+///
+#[cfg(feature = "redis")]
+const CONSUME: &str = r"
+if redis.call('EXISTS', KEYS[1]) == 0 then
+    return false
+end
+local marks = redis.call('HMGET', KEYS[1], 'used_at', 'revoked_at')
+if marks[1] or marks[2] then
+    return false
+end
+redis.call('HSET', KEYS[1], 'used_at', ARGV[1])
+return redis.call('HGETALL', KEYS[1])
+";
+
+///
+/// Revokes the tokens of some families that are not revoked yet, and counts
+/// them: those of the sessions of a user, or, without a key, those of the
+/// families of `ARGV`.
+///
+/// `KEYS`: the sessions of a user, or nothing. `ARGV`: the time, then the
+/// families.
+///
+#[cfg(feature = "redis")]
+const REVOKE: &str = r"
+local families = {unpack(ARGV, 2)}
+if KEYS[1] then
+    families = redis.call('ZRANGE', KEYS[1], 0, -1)
+end
+local count = 0
+for _, family in ipairs(families) do
+    for _, hash in ipairs(redis.call('SMEMBERS', 'refresh_family:' .. family)) do
+        local key = 'refresh_token:' .. hash
+        if redis.call('EXISTS', key) == 1 and redis.call('HEXISTS', key, 'revoked_at') == 0 then
+            redis.call('HSET', key, 'revoked_at', ARGV[1])
+            count = count + 1
+        end
+    end
+end
+return count
+";
+
+#[cfg(feature = "redis")]
+fn token_key(token_hash: &str) -> String {
+    format!("refresh_token:{}", token_hash)
+}
+
+#[cfg(feature = "redis")]
+fn family_key(family_id: &str) -> String {
+    format!("refresh_family:{}", family_id)
+}
+
+#[cfg(feature = "redis")]
+fn sessions_key(user_id: i64) -> String {
+    format!("refresh_sessions:{}", user_id)
+}
+
+///
+/// Times are kept in nanoseconds since the epoch, so that they come back
+/// exactly as they were given.
+///
+#[cfg(feature = "redis")]
+fn nanos(at: PrimitiveDateTime) -> i128 {
+    at.assume_utc().unix_timestamp_nanos()
+}
+
+#[cfg(feature = "redis")]
+fn at_nanos(nanos: &str) -> Option<PrimitiveDateTime> {
+    let at = OffsetDateTime::from_unix_timestamp_nanos(nanos.parse().ok()?).ok()?;
+    Some(PrimitiveDateTime::new(at.date(), at.time()))
+}
+
+///
+/// The token of the fields of its hash, or `None` if it has none, as a key
+/// that does not exist, or no longer does, has none.
+///
+#[cfg(feature = "redis")]
+fn token_of(fields: HashMap<String, String>) -> Option<RefreshToken> {
+    let field = |name: &str| fields.get(name).map(String::as_str);
+    Some(RefreshToken {
+        id: field("id")?.parse().ok()?,
+        user_id: field("user_id")?.parse().ok()?,
+        family_id: field("family_id")?.to_string(),
+        token_hash: field("token_hash")?.to_string(),
+        expires_at: at_nanos(field("expires_at")?)?,
+        used_at: field("used_at").and_then(at_nanos),
+        revoked_at: field("revoked_at").and_then(at_nanos),
+        created_at: at_nanos(field("created_at")?)?,
+        mfa: field("mfa")? == "1",
+    })
+}
+
+#[cfg(feature = "redis")]
+#[async_trait]
+impl RefreshTokenRepo for RefreshTokenRepoRedis {
+    async fn insert(
+        &self,
+        user_id: i64,
+        family_id: &str,
+        token_hash: &str,
+        created_at: PrimitiveDateTime,
+        expires_at: PrimitiveDateTime,
+        mfa: bool,
+    ) -> i64 {
+        use redis::AsyncCommands;
+
+        let mut connection = self.connection().await;
+        let id: i64 = connection.incr("refresh_token:ids", 1).await.unwrap();
+        let fields = [
+            ("id", id.to_string()),
+            ("user_id", user_id.to_string()),
+            ("family_id", family_id.to_string()),
+            ("token_hash", token_hash.to_string()),
+            ("created_at", nanos(created_at).to_string()),
+            ("expires_at", nanos(expires_at).to_string()),
+            ("mfa", if mfa { "1" } else { "0" }.to_string()),
+        ];
+        redis::Script::new(INSERT)
+            .key(token_key(token_hash))
+            .key(family_key(family_id))
+            .key(sessions_key(user_id))
+            .arg((nanos(expires_at) / 1_000_000) as i64)
+            .arg(created_at.assume_utc().unix_timestamp())
+            .arg(family_id)
+            .arg(token_hash)
+            .arg(&fields[..])
+            .invoke_async::<()>(&mut connection)
+            .await
+            .unwrap();
+        id
+    }
+    async fn consume(&self, token_hash: &str, now: PrimitiveDateTime) -> Option<RefreshToken> {
+        let fields: Option<HashMap<String, String>> = redis::Script::new(CONSUME)
+            .key(token_key(token_hash))
+            .arg(nanos(now).to_string())
+            .invoke_async(&mut self.connection().await)
+            .await
+            .unwrap();
+        token_of(fields?)
+    }
+    async fn find(&self, token_hash: &str) -> Option<RefreshToken> {
+        self.token(token_hash).await
+    }
+    async fn revoke_family(&self, family_id: &str, now: PrimitiveDateTime) -> u64 {
+        redis::Script::new(REVOKE)
+            .arg(nanos(now).to_string())
+            .arg(family_id)
+            .invoke_async(&mut self.connection().await)
+            .await
+            .unwrap()
+    }
+    async fn revoke_user(&self, user_id: i64, now: PrimitiveDateTime) -> u64 {
+        redis::Script::new(REVOKE)
+            .key(sessions_key(user_id))
+            .arg(nanos(now).to_string())
+            .invoke_async(&mut self.connection().await)
+            .await
+            .unwrap()
+    }
+    async fn sessions(&self, user_id: i64, now: PrimitiveDateTime) -> Vec<Session> {
+        use redis::AsyncCommands;
+
+        let mut connection = self.connection().await;
+        let families: Vec<String> = connection.zrange(sessions_key(user_id), 0, -1).await.unwrap();
+        let mut tokens = Vec::new();
+        for family_id in families {
+            let hashes: Vec<String> = connection.smembers(family_key(&family_id)).await.unwrap();
+            if hashes.is_empty() {
+                // The family expired, and can never be refreshed again.
+                let _: () = connection.zrem(sessions_key(user_id), &family_id).await.unwrap();
+            }
+            for hash in hashes {
+                tokens.extend(self.token(&hash).await);
+            }
+        }
+        sessions_of(tokens.iter(), now)
+    }
+}
+
+#[derive(Clone)]
+pub struct AuthState<R: RefreshTokenRepo> {
+    pub repo: R,
+    pub keys: JwtKeys,
+}
+
+impl<R: RefreshTokenRepo + Clone> FromRef<AuthState<R>> for JwtKeys {
+    fn from_ref(state: &AuthState<R>) -> Self {
+        state.keys.clone()
+    }
+}
+
+impl<R: RefreshTokenRepo + Clone> FromRef<AuthState<R>> for Key {
+    fn from_ref(state: &AuthState<R>) -> Self {
+        state.keys.cookie_key()
+    }
+}
+
+///
+/// Keeps the refresh token in a private cookie, which the browser only sends
+/// back to `/auth`, never to scripts (`HttpOnly`), never over plain HTTP
+/// (`Secure`), and never with requests from other sites (`SameSite=Strict`),
+/// so that no other site can make the browser refresh on its behalf.
+///
+pub fn remember_refresh_token(jar: PrivateCookieJar, refresh_token: &str) -> PrivateCookieJar {
+    jar.add(
+        Cookie::build((REFRESH_TOKEN_COOKIE, refresh_token.to_string()))
+            .path("/auth")
+            .http_only(true)
+            .secure(true)
+            .same_site(SameSite::Strict)
+            .max_age(REFRESH_TOKEN_TTL),
+    )
+}
+
+///
+/// Removes the refresh token cookie, if the request had one. The path must
+/// match the one it was set with, or the browser keeps it.
+///
+pub fn forget_refresh_token(jar: PrivateCookieJar) -> PrivateCookieJar {
+    jar.remove(Cookie::build(REFRESH_TOKEN_COOKIE).path("/auth"))
+}
+
+impl<R: RefreshTokenRepo> AuthState<R> {
+    ///
+    /// Issues a fresh token pair for a user who has just proven their identity,
+    /// starting a new refresh token family.
+    ///
+    pub async fn issue_tokens(&self, user_id: i64) -> TokenPair {
+        self.start_session(user_id, false).await
+    }
+
+    ///
+    /// Like `issue_tokens`, for a user who also gave a second factor.
+    ///
+    pub async fn issue_two_factor_tokens(&self, user_id: i64) -> TokenPair {
+        self.start_session(user_id, true).await
+    }
+
+    async fn start_session(&self, user_id: i64, mfa: bool) -> TokenPair {
+        let family_id = random_token();
+        let tokens = self.issue_in_family(user_id, &family_id, mfa).await;
+
+        let now = self.keys.clock.now_utc_primitive();
+        let mut others = self.repo.sessions(user_id, now).await;
+        others.retain(|session| session.id != family_id);
+        if others.len() >= MAX_SESSIONS {
+            others.sort_by_key(|session| session.last_used_at);
+            for session in &others[..others.len() + 1 - MAX_SESSIONS] {
+                self.repo.revoke_family(&session.id, now).await;
+            }
+        }
+        tokens
+    }
+
+    ///
+    /// Exchanges a refresh token for a new token pair in the same family. The
+    /// presented token can never be used again; presenting it a second time
+    /// revokes every token in its family.
+    ///
+    pub async fn refresh(&self, refresh_token: &str) -> Result<TokenPair, AuthError> {
+        let now = self.keys.clock.now_utc_primitive();
+        let token_hash = hash_token(refresh_token);
+
+        match self.repo.consume(&token_hash, now).await {
+            Some(token) if token.expires_at > now => {
+                Ok(self.issue_in_family(token.user_id, &token.family_id, token.mfa).await)
+            }
+            Some(_) => Err(AuthError::ExpiredToken),
+            None => match self.repo.find(&token_hash).await {
+                Some(token) => {
+                    self.repo.revoke_family(&token.family_id, now).await;
+                    Err(AuthError::TokenReuse)
+                }
+                None => Err(AuthError::InvalidToken),
+            },
+        }
+    }
+
+    ///
+    /// Revokes every refresh token of the user, on every device. Outstanding
+    /// access tokens remain valid until they expire, which is why they are
+    /// kept short-lived.
+    ///
+    pub async fn logout_everywhere(&self, user_id: i64) -> u64 {
+        self.repo.revoke_user(user_id, self.keys.clock.now_utc_primitive()).await
+    }
+
+    pub async fn sessions(&self, user_id: i64) -> Vec<Session> {
+        self.repo.sessions(user_id, self.keys.clock.now_utc_primitive()).await
+    }
+
+    ///
+    /// Ends one of the user's sessions. Returns `false` if the user has no
+    /// such session, including when it belongs to someone else.
+    ///
+    pub async fn revoke_session(&self, user_id: i64, session_id: &str) -> bool {
+        let now = self.keys.clock.now_utc_primitive();
+        let sessions = self.repo.sessions(user_id, now).await;
+        if !sessions.iter().any(|session| session.id == session_id) {
+            return false;
+        }
+        self.repo.revoke_family(session_id, now).await > 0
+    }
+
+    async fn issue_in_family(&self, user_id: i64, family_id: &str, mfa: bool) -> TokenPair {
+        let now = self.keys.clock.now_utc_primitive();
+        let refresh_token = random_token();
+        self.repo
+            .insert(user_id, family_id, &hash_token(&refresh_token), now, now + REFRESH_TOKEN_TTL, mfa)
+            .await;
+
+        TokenPair {
+            access_token: encode_access_token(&self.keys, user_id, now, mfa),
+            refresh_token,
+            token_type: "Bearer".to_string(),
+            expires_in: ACCESS_TOKEN_TTL.whole_seconds(),
+        }
+    }
+}
+
+fn encode_access_token(keys: &JwtKeys, user_id: i64, now: PrimitiveDateTime, mfa: bool) -> String {
+    let iat = now.assume_utc().unix_timestamp();
+    let claims = Claims {
+        sub: user_id,
+        iat,
+        exp: iat + ACCESS_TOKEN_TTL.whole_seconds(),
+        mfa,
+    };
+    sign(keys, &claims)
+}
+
+///
+/// Signs other claims than those of access tokens, such as the challenge of
+/// a login waiting for its second factor. Claims without `sub` can never
+/// pass for an access token.
+///
+pub(crate) fn sign<T: serde::Serialize>(keys: &JwtKeys, claims: &T) -> String {
+    jsonwebtoken::encode(&Header::default(), claims, &keys.encoding).unwrap()
+}
+
+///
+/// The claims of a token signed with `sign`, without checking `exp`, which
+/// is left to the caller, against the keys' clock.
+///
+pub(crate) fn verify<T: serde::de::DeserializeOwned>(keys: &JwtKeys, token: &str) -> Option<T> {
+    let mut validation = Validation::default();
+    validation.validate_exp = false;
+    jsonwebtoken::decode::<T>(token, &keys.decoding, &validation).map(|data| data.claims).ok()
+}
+
+pub fn decode_access_token(keys: &JwtKeys, token: &str) -> Result<Claims, AuthError> {
+    // `jsonwebtoken` checks `exp` against the system clock, so the check is
+    // done here instead, against the keys' clock.
+    let mut validation = Validation::default();
+    validation.validate_exp = false;
+
+    let claims = jsonwebtoken::decode::<Claims>(token, &keys.decoding, &validation)
+        .map(|data| data.claims)
+        .map_err(|_| AuthError::InvalidToken)?;
+
+    if claims.exp + ACCESS_TOKEN_LEEWAY.whole_seconds() < keys.clock.now().unix_timestamp() {
+        return Err(AuthError::ExpiredToken);
+    }
+    Ok(claims)
+}
+
+pub(crate) fn random_token() -> String {
+    let mut bytes = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    BASE64_URL.encode(bytes)
+}
+
+pub(crate) fn hash_token(token: &str) -> String {
+    BASE64_URL.encode(Sha256::digest(token.as_bytes()))
+}
+
+///
+/// Requiring `Claims` in a handler makes the route authenticated: the request
+/// must carry a valid `Authorization: Bearer <access token>` header, or a
+/// personal access token with the scope of the route, whose claims
+/// `require_scope` already left in the request (see `pat.rs`).
+///
+#[async_trait]
+impl<S> FromRequestParts<S> for Claims
+where
+    JwtKeys: FromRef<S>,
+    S: Send + Sync,
+{
+    type Rejection = AuthError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        if let Some(claims) = parts.extensions.get::<Claims>() {
+            return Ok(claims.clone());
+        }
+        let token = parts
+            .headers
+            .get("Authorization")
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .ok_or(AuthError::MissingToken)?;
+
+        decode_access_token(&JwtKeys::from_ref(state), token)
+    }
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct RefreshRequest {
+    refresh_token: String,
+}
+
+///
+/// Takes the refresh token from the body or, without one, from the cookie of
+/// a remembered login. A remembered login stays remembered, with the rotated
+/// token, and one whose token is rejected is forgotten. A session that
+/// `restore_session` just started is answered with as it is.
+///
+async fn refresh<R: RefreshTokenRepo>(
+    State(state): State<AuthState<R>>,
+    restored: Option<Extension<RestoredSession>>,
+    jar: PrivateCookieJar,
+    body: Option<Json<RefreshRequest>>,
+) -> Result<(PrivateCookieJar, Json<TokenPair>), (PrivateCookieJar, AuthError)> {
+    let Some(Json(RefreshRequest { refresh_token })) = body else {
+        if let Some(Extension(RestoredSession(tokens))) = restored {
+            return Ok((remember_refresh_token(jar, &tokens.refresh_token), Json(tokens)));
+        }
+        let Some(cookie) = jar.get(REFRESH_TOKEN_COOKIE) else {
+            return Err((jar, AuthError::MissingToken));
+        };
+        return match state.refresh(cookie.value()).await {
+            Ok(tokens) => Ok((remember_refresh_token(jar, &tokens.refresh_token), Json(tokens))),
+            Err(error) => Err((forget_refresh_token(jar), error)),
+        };
+    };
+
+    match state.refresh(&refresh_token).await {
+        Ok(tokens) => Ok((jar, Json(tokens))),
+        Err(error) => Err((jar, error)),
+    }
+}
+
+async fn logout_everywhere<R: RefreshTokenRepo>(
+    claims: Claims,
+    State(state): State<AuthState<R>>,
+    jar: PrivateCookieJar,
+) -> (PrivateCookieJar, StatusCode) {
+    state.logout_everywhere(claims.sub).await;
+    (forget_refresh_token(jar), StatusCode::NO_CONTENT)
+}
+
+///
+/// The routes of the auth module, ready to be nested under `/auth` of any
+/// router whose state contains an `AuthState`.
+///
+pub fn auth_routes<S, R>() -> Routes<S>
+where
+    R: RefreshTokenRepo + Clone + 'static,
+    AuthState<R>: FromRef<S>,
+    JwtKeys: FromRef<S>,
+    Key: FromRef<S>,
+    S: Clone + Send + Sync + 'static,
+{
+    Routes::new()
+        .post("/refresh", refresh::<R>)
+        .post("/logout-everywhere", logout_everywhere::<R>)
+}
+
+#[tokio::test]
+async fn refresh_rotates_tokens() {
+    let state = AuthState {
+        repo: RefreshTokenRepoInMemory::default(),
+        keys: JwtKeys::from_secret(b"secret"),
+    };
+
+    let first = state.issue_tokens(42).await;
+    let second = state.refresh(&first.refresh_token).await.unwrap();
+
+    assert_ne!(first.refresh_token, second.refresh_token);
+    assert_eq!(decode_access_token(&state.keys, &second.access_token).unwrap().sub, 42);
+    assert!(state.refresh(&second.refresh_token).await.is_ok());
+}
+
+#[tokio::test]
+async fn refresh_token_reuse_revokes_family() {
+    let state = AuthState {
+        repo: RefreshTokenRepoInMemory::default(),
+        keys: JwtKeys::from_secret(b"secret"),
+    };
+
+    let first = state.issue_tokens(42).await;
+    let second = state.refresh(&first.refresh_token).await.unwrap();
+
+    assert_eq!(state.refresh(&first.refresh_token).await, Err(AuthError::TokenReuse));
+    assert_eq!(state.refresh(&second.refresh_token).await, Err(AuthError::TokenReuse));
+}
+
+#[tokio::test]
+async fn logout_everywhere_revokes_all_families() {
+    let state = AuthState {
+        repo: RefreshTokenRepoInMemory::default(),
+        keys: JwtKeys::from_secret(b"secret"),
+    };
+
+    let laptop = state.issue_tokens(42).await;
+    let phone = state.issue_tokens(42).await;
+    let other_user = state.issue_tokens(7).await;
+
+    assert_eq!(state.logout_everywhere(42).await, 2);
+    assert!(state.refresh(&laptop.refresh_token).await.is_err());
+    assert!(state.refresh(&phone.refresh_token).await.is_err());
+    assert!(state.refresh(&other_user.refresh_token).await.is_ok());
+}
+
+#[tokio::test]
+async fn tokens_expire_on_the_clock() {
+    use time::macros::datetime;
+
+    use crate::clock::FakeClock;
+
+    let clock = FakeClock::new(datetime!(2026-10-16 12:00 UTC));
+    let state = AuthState {
+        repo: RefreshTokenRepoInMemory::default(),
+        keys: JwtKeys::from_secret(b"secret").with_clock(Arc::new(clock.clone())),
+    };
+
+    let tokens = state.issue_tokens(42).await;
+
+    clock.advance(ACCESS_TOKEN_TTL + ACCESS_TOKEN_LEEWAY);
+    assert!(decode_access_token(&state.keys, &tokens.access_token).is_ok());
+    clock.advance(Duration::seconds(1));
+    assert_eq!(decode_access_token(&state.keys, &tokens.access_token), Err(AuthError::ExpiredToken));
+
+    let tokens = state.issue_tokens(42).await;
+    clock.advance(REFRESH_TOKEN_TTL);
+    assert_eq!(state.refresh(&tokens.refresh_token).await, Err(AuthError::ExpiredToken));
+}
+
+#[tokio::test]
+async fn sessions_slide_and_are_limited_per_user() {
+    use time::macros::datetime;
+
+    use crate::clock::FakeClock;
+
+    let clock = FakeClock::new(datetime!(2026-10-16 12:00 UTC));
+    let state = AuthState {
+        repo: RefreshTokenRepoInMemory::default(),
+        keys: JwtKeys::from_secret(b"secret").with_clock(Arc::new(clock.clone())),
+    };
+
+    let laptop = state.issue_tokens(42).await;
+    clock.advance(Duration::days(1));
+    let phone = state.issue_tokens(42).await;
+    state.issue_tokens(7).await;
+
+    let sessions = state.sessions(42).await;
+    assert_eq!(sessions.len(), 2);
+    assert_eq!(sessions[0].started_at, datetime!(2026-10-16 12:00 UTC));
+
+    // Refreshing slides the expiry, without starting another session.
+    clock.advance(Duration::days(20));
+    let laptop = state.refresh(&laptop.refresh_token).await.unwrap();
+    let sessions = state.sessions(42).await;
+    assert_eq!(sessions.len(), 2);
+    assert_eq!(sessions[0].last_used_at, datetime!(2026-11-06 12:00 UTC));
+    assert_eq!(sessions[0].expires_at, datetime!(2026-12-06 12:00 UTC));
+
+    // Other users cannot end it, its user can.
+    let phone_session = sessions[1].id.clone();
+    assert!(!state.revoke_session(7, &phone_session).await);
+    assert!(state.revoke_session(42, &phone_session).await);
+    assert!(state.refresh(&phone.refresh_token).await.is_err());
+    assert_eq!(state.sessions(42).await.len(), 1);
+
+    // Beyond the limit, the session used the longest ago ends.
+    for _ in 1..MAX_SESSIONS {
+        clock.advance(Duration::minutes(1));
+        state.issue_tokens(42).await;
+    }
+    assert_eq!(state.sessions(42).await.len(), MAX_SESSIONS);
+    state.issue_tokens(42).await;
+    assert_eq!(state.sessions(42).await.len(), MAX_SESSIONS);
+    assert!(state.refresh(&laptop.refresh_token).await.is_err());
+}
+
+#[tokio::test]
+async fn refresh_endpoint_postgres() {
+    // for Body::collect
+    use http_body_util::BodyExt;
+    /// for ServiceExt::oneshot
+    use tower::util::ServiceExt;
+    use axum::{body::Body, http::{Method, Request}};
+    use sqlx::postgres::PgPoolOptions;
+
+    let pool = PgPoolOptions::new()
+        .max_connections(1)
+        .connect(&crate::test_db::database_url().await)
+        .await
+        .unwrap();
+
+    let state = AuthState {
+        repo: RefreshTokenRepoPostgres::new(pool),
+        keys: JwtKeys::from_secret(b"secret"),
+    };
+    let tokens = state.issue_tokens(42).await;
+    let sessions = state.sessions(42).await.len();
+    assert!(sessions >= 1);
+    let app = auth_routes().into_router().with_state(state.clone());
+
+    let refresh_request = || {
+        Request::builder()
+            .method(Method::POST)
+            .uri("/refresh")
+            .header("Content-Type", "application/json")
+            .body(Body::from(format!(r#"{{"refresh_token": "{}"}}"#, tokens.refresh_token)))
+            .unwrap()
+    };
+
+    let response = app.clone().oneshot(refresh_request()).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let body = response.into_body().collect().await.unwrap().to_bytes();
+    let rotated: TokenPair = serde_json::from_slice(&body).unwrap();
+    assert_ne!(rotated.refresh_token, tokens.refresh_token);
+
+    let response = app.oneshot(refresh_request()).await.unwrap();
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    // The reuse revoked the whole family, which was one of the sessions.
+    assert_eq!(state.sessions(42).await.len(), sessions - 1);
+}
+
+///
+/// Runs against the Redis of `REDIS_URL`:
+///
+/// ```bash
+/// docker run -d --name redis -p 6379:6379 redis:7
+/// REDIS_URL=redis://localhost:6379 cargo test --features redis redis
+/// ```
+///
+#[cfg(feature = "redis")]
+#[tokio::test]
+async fn sessions_slide_in_redis() {
+    use redis::AsyncCommands;
+
+    let url = std::env::var("REDIS_URL").expect("REDIS_URL must be set for the Redis tests");
+    let state = AuthState {
+        repo: RefreshTokenRepoRedis::connect_lazy(&url),
+        keys: JwtKeys::from_secret(b"secret"),
+    };
+    // Redis outlives the test, with the sessions of earlier runs.
+    let user_id = rand::random::<u32>() as i64;
+
+    let laptop = state.issue_tokens(user_id).await;
+    let phone = state.issue_tokens(user_id).await;
+    assert_eq!(state.sessions(user_id).await.len(), 2);
+
+    // Refreshing pushes the expiry of the whole session, the used token
+    // included, whose reuse is still caught.
+    let mut connection = state.repo.connection().await;
+    let used = token_key(&hash_token(&laptop.refresh_token));
+    let ttl: i64 = connection.pttl(&used).await.unwrap();
+    assert!(ttl > 0 && ttl <= REFRESH_TOKEN_TTL.whole_milliseconds() as i64);
+    tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+    let rotated = state.refresh(&laptop.refresh_token).await.unwrap();
+    assert!(connection.pttl::<_, i64>(&used).await.unwrap() > ttl);
+    assert_eq!(state.sessions(user_id).await.len(), 2);
+
+    assert_eq!(state.refresh(&laptop.refresh_token).await, Err(AuthError::TokenReuse));
+    assert!(state.refresh(&rotated.refresh_token).await.is_err());
+    let sessions = state.sessions(user_id).await;
+    assert_eq!(sessions.len(), 1);
+
+    assert!(!state.revoke_session(user_id + 1, &sessions[0].id).await);
+    assert!(state.revoke_session(user_id, &sessions[0].id).await);
+    assert!(state.refresh(&phone.refresh_token).await.is_err());
+    assert!(state.sessions(user_id).await.is_empty());
+
+    state.issue_tokens(user_id).await;
+    state.issue_tokens(user_id).await;
+    assert_eq!(state.logout_everywhere(user_id).await, 2);
+    assert!(state.sessions(user_id).await.is_empty());
+}
+
+#[tokio::test]
+async fn remembered_logins_refresh_from_the_cookie() {
+    // for Body::collect
+    use http_body_util::BodyExt;
+    /// for ServiceExt::oneshot
+    use tower::util::ServiceExt;
+    use axum::{body::Body, http::{header, Method, Request}};
+
+    use crate::cookies::{cookie_pair, set_cookies};
+
+    let state = AuthState {
+        repo: RefreshTokenRepoInMemory::default(),
+        keys: JwtKeys::from_secret(b"secret"),
+    };
+    let tokens = state.issue_tokens(42).await;
+    let jar = remember_refresh_token(PrivateCookieJar::new(state.keys.cookie_key()), &tokens.refresh_token);
+    let cookie = cookie_pair(&set_cookies(jar.into_response().headers())[0]);
+    let app = auth_routes().into_router().with_state(state);
+
+    let refresh_request = |cookie: &str| {
+        Request::builder()
+            .method(Method::POST)
+            .uri("/refresh")
+            .header(header::COOKIE, cookie)
+            .body(Body::empty())
+            .unwrap()
+    };
+
+    let response = app.clone().oneshot(refresh_request(&cookie)).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let rotated = set_cookies(response.headers());
+    assert!(rotated[0].contains("HttpOnly") && rotated[0].contains("Path=/auth"));
+    assert!(!rotated[0].contains(&tokens.refresh_token));
+    let body = response.into_body().collect().await.unwrap().to_bytes();
+    let refreshed: TokenPair = serde_json::from_slice(&body).unwrap();
+    assert_ne!(refreshed.refresh_token, tokens.refresh_token);
+
+    let response = app.clone().oneshot(refresh_request(&cookie_pair(&rotated[0]))).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    // Reusing the first cookie revokes the family, and forgets the login.
+    let response = app.clone().oneshot(refresh_request(&cookie)).await.unwrap();
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    assert!(set_cookies(response.headers())[0].starts_with("refresh_token=; "));
+
+    let response = app.oneshot(refresh_request("refresh_token=forged")).await.unwrap();
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+}
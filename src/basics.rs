@@ -207,3 +207,127 @@ async fn test_hello_world() {
 
     assert!(s.contains("Hello, World!"));
 }
+
+///
+/// EXERCISE 6
+///
+/// Axum matches paths exactly, and a trailing slash is part of the path. This
+/// is easy to trip over with `nest`: whether the prefix ends with a slash
+/// decides which of `/user` and `/user/` reaches the nested `/` route, and
+/// the other one is a 404.
+///
+/// Change the prefix in `nest_router` (EXERCISE 3) from `/user/` to `/user`,
+/// and change the assertions below to match what happens. Which of the two
+/// would a client expect to work?
+///
+#[tokio::test]
+async fn nest_trailing_slashes() {
+    /// for ServiceExt::oneshot
+    use tower::util::ServiceExt;
+    use axum::http::StatusCode;
+
+    let app = nest_router(Router::<()>::new());
+
+    let status = |uri: &'static str| {
+        let app = app.clone();
+        async move { app.oneshot(Request::get(uri).body(Body::empty()).unwrap()).await.unwrap().status() }
+    };
+
+    assert_eq!(status("/user/").await, StatusCode::OK);
+    assert_eq!(status("/user").await, StatusCode::NOT_FOUND);
+    assert_eq!(status("/user/42").await, StatusCode::OK);
+    assert_eq!(status("/user/42/").await, StatusCode::NOT_FOUND);
+}
+
+///
+/// EXERCISE 7
+///
+/// `NormalizePath` from `tower-http` removes trailing slashes from the path
+/// before the router sees it, so that `/user/` and `/user` reach the same
+/// route. It has to wrap the router: a layer added with `Router::layer` only
+/// runs once a route has been chosen, which is too late to change the path.
+///
+/// Register the routes with a trailing slash instead (`/user/`), and see
+/// what `NormalizePath` does to them. The graduation project offers both
+/// behaviors, as `TrailingSlash::Strict` and `TrailingSlash::Lenient`.
+///
+#[tokio::test]
+async fn normalize_trailing_slashes() {
+    /// for ServiceExt::oneshot
+    use tower::util::ServiceExt;
+    use axum::http::StatusCode;
+    use tower_http::normalize_path::NormalizePath;
+
+    let routes = Router::new().route("/user", get(identity_handler)).route("/user/:id", get(identity_handler));
+    let app = Router::new().fallback_service(NormalizePath::trim_trailing_slash(routes));
+
+    let status = |uri: &'static str| {
+        let app = app.clone();
+        async move { app.oneshot(Request::get(uri).body(Body::empty()).unwrap()).await.unwrap().status() }
+    };
+
+    assert_eq!(status("/user").await, StatusCode::OK);
+    assert_eq!(status("/user/").await, StatusCode::OK);
+    assert_eq!(status("/user/42/").await, StatusCode::OK);
+}
+
+///
+/// EXERCISE 8
+///
+/// A single listener can serve more than one site: the client says which one
+/// it wants in the `Host` header (`Host: api.example.test`), which is how
+/// several domains can point to the same server. The `Host` extractor reads
+/// it, and a fallback handler can then pass the request on to the router of
+/// that site. Here, the API is served on `api.example.test`, and the HTML UI
+/// on `www.example.test`:
+///
+/// ```ignore
+/// axum::serve(listener, host_router()).await.unwrap();
+/// ```
+///
+/// Add a third host, `admin.example.test`, with a router of its own. Then
+/// send a request for `www.example.test` with an `X-Forwarded-Host:
+/// api.example.test` header as well. Which router answers it, and why is
+/// that a problem when the app is not behind a proxy that sets the header?
+///
+pub fn host_router() -> Router {
+    /// for ServiceExt::oneshot
+    use tower::util::ServiceExt;
+    use axum::{extract::Host, http::StatusCode, response::IntoResponse};
+
+    let api = Router::new().route("/", get(|| async { Json(serde_json::json!({ "name": "todos" })) }));
+    let www = Router::new().route("/", get(|| async { Html("<h1>Todos</h1>") }));
+
+    Router::new().fallback(move |Host(host): Host, request: Request<Body>| {
+        let (api, www) = (api.clone(), www.clone());
+        async move {
+            // The port, if any, is part of the host: `api.example.test:3000`.
+            match host.split(':').next().unwrap_or_default() {
+                "api.example.test" => api.oneshot(request).await.unwrap(),
+                "www.example.test" => www.oneshot(request).await.unwrap(),
+                _ => (StatusCode::NOT_FOUND, format!("Unknown host: {}", host)).into_response(),
+            }
+        }
+    })
+}
+
+#[tokio::test]
+async fn routes_by_host() {
+    // for Body::collect
+    use http_body_util::BodyExt;
+    /// for ServiceExt::oneshot
+    use tower::util::ServiceExt;
+    use axum::http::{header, StatusCode};
+
+    let send = |host: &'static str| async move {
+        let request = Request::get("/").header(header::HOST, host).body(Body::empty()).unwrap();
+        let response = host_router().oneshot(request).await.unwrap();
+        let status = response.status();
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        (status, String::from_utf8(body.to_vec()).unwrap())
+    };
+
+    assert_eq!(send("api.example.test").await, (StatusCode::OK, r#"{"name":"todos"}"#.to_string()));
+    assert_eq!(send("www.example.test:3000").await, (StatusCode::OK, "<h1>Todos</h1>".to_string()));
+    assert_eq!(send("example.test").await.0, StatusCode::NOT_FOUND);
+}
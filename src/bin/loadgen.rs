@@ -0,0 +1,233 @@
+//!
+//! LOADGEN
+//! -------
+//!
+//! A small load generator for the graduation project, to get a feel for the
+//! performance exercises: start the server in one terminal, then the load in
+//! another:
+//!
+//! ```sh
+//! cargo run --release --bin rust-web -- serve
+//! cargo run --release --bin loadgen -- --url http://127.0.0.1:3000/todo \
+//!     --email ada@example.com --password hunter2 --concurrency 32 --duration 10
+//! ```
+//!
+//! Every worker sends requests back to back, for the given number of seconds,
+//! and the latencies of all of them are reported as percentiles. Requests
+//! that fail, or that get a response other than 2xx, count as errors.
+//!
+//! With `--http-version 2`, requests are sent over h2c, HTTP/2 without TLS,
+//! and every worker shares a single connection (see `src/http2.rs` for what
+//! to look for).
+//!
+
+use std::time::{Duration, Instant};
+
+#[derive(Debug)]
+struct Options {
+    url: String,
+    concurrency: usize,
+    duration: Duration,
+    token: Option<String>,
+    email: Option<String>,
+    password: Option<String>,
+    http2: bool,
+}
+
+impl Options {
+    fn from_args(args: impl Iterator<Item = String>) -> Result<Options, String> {
+        let mut options = Options {
+            url: "http://127.0.0.1:3000/todo".to_string(),
+            concurrency: 16,
+            duration: Duration::from_secs(10),
+            token: None,
+            email: None,
+            password: None,
+            http2: false,
+        };
+
+        let mut args = args;
+        while let Some(flag) = args.next() {
+            let value = args.next().ok_or(format!("{} needs a value", flag))?;
+            match flag.as_str() {
+                "--url" => options.url = value,
+                "--concurrency" => {
+                    options.concurrency = value.parse().map_err(|_| format!("Invalid concurrency: {}", value))?
+                }
+                "--duration" => {
+                    let secs = value.parse().map_err(|_| format!("Invalid duration: {}", value))?;
+                    options.duration = Duration::from_secs(secs);
+                }
+                "--token" => options.token = Some(value),
+                "--email" => options.email = Some(value),
+                "--password" => options.password = Some(value),
+                "--http-version" => {
+                    options.http2 = match value.as_str() {
+                        "1.1" => false,
+                        "2" => true,
+                        _ => return Err(format!("Invalid HTTP version: {}", value)),
+                    }
+                }
+                _ => return Err(format!("Unknown flag: {}", flag)),
+            }
+        }
+
+        Ok(options)
+    }
+}
+
+#[derive(Debug, Default)]
+struct Report {
+    latencies: Vec<Duration>,
+    errors: usize,
+}
+
+impl Report {
+    fn merge(&mut self, other: Report) {
+        self.latencies.extend(other.latencies);
+        self.errors += other.errors;
+    }
+
+    ///
+    /// The latency below which `p` percent of the requests completed, using
+    /// the nearest-rank method. `latencies` must be sorted.
+    ///
+    fn percentile(latencies: &[Duration], p: f64) -> Duration {
+        if latencies.is_empty() {
+            return Duration::ZERO;
+        }
+        let rank = ((p / 100.0) * latencies.len() as f64).ceil() as usize;
+        latencies[rank.clamp(1, latencies.len()) - 1]
+    }
+
+    fn print(mut self, elapsed: Duration) {
+        self.latencies.sort();
+        let total = self.latencies.len();
+
+        println!("requests:   {}", total);
+        println!("throughput: {:.1} req/s", total as f64 / elapsed.as_secs_f64());
+        println!(
+            "errors:     {} ({:.2}%)",
+            self.errors,
+            if total == 0 { 0.0 } else { 100.0 * self.errors as f64 / total as f64 }
+        );
+        for p in [50.0, 95.0, 99.0] {
+            println!("p{}:        {:?}", p, Report::percentile(&self.latencies, p));
+        }
+    }
+}
+
+#[derive(serde::Deserialize)]
+struct TokenPair {
+    access_token: String,
+}
+
+///
+/// Logs in through `/users/login` on the same server as `url`.
+///
+async fn login(client: &reqwest::Client, url: &str, email: &str, password: &str) -> Result<String, String> {
+    let url = reqwest::Url::parse(url).map_err(|e| e.to_string())?;
+    let login = url.join("/users/login").map_err(|e| e.to_string())?;
+
+    let response = client
+        .post(login)
+        .json(&serde_json::json!({ "email": email, "password": password }))
+        .send()
+        .await
+        .map_err(|e| e.to_string())?;
+    if !response.status().is_success() {
+        return Err(format!("Login failed: {}", response.status()));
+    }
+
+    let tokens: TokenPair = response.json().await.map_err(|e| e.to_string())?;
+    Ok(tokens.access_token)
+}
+
+async fn worker(client: reqwest::Client, url: String, token: Option<String>, deadline: Instant) -> Report {
+    let mut report = Report::default();
+
+    while Instant::now() < deadline {
+        let mut request = client.get(&url);
+        if let Some(token) = &token {
+            request = request.bearer_auth(token);
+        }
+
+        let start = Instant::now();
+        let ok = match request.send().await {
+            Ok(response) => response.status().is_success() && response.bytes().await.is_ok(),
+            Err(_) => false,
+        };
+        report.latencies.push(start.elapsed());
+        if !ok {
+            report.errors += 1;
+        }
+    }
+
+    report
+}
+
+#[tokio::main]
+async fn main() {
+    let options = match Options::from_args(std::env::args().skip(1)) {
+        Ok(options) => options,
+        Err(error) => {
+            eprintln!("{}", error);
+            eprintln!(
+                "Usage: loadgen [--url URL] [--concurrency N] [--duration SECS] [--http-version 1.1|2] [--token TOKEN | --email EMAIL --password PASSWORD]"
+            );
+            std::process::exit(2);
+        }
+    };
+
+    // Over HTTP/1.1, the client opens a connection per request in flight.
+    // Over HTTP/2, it multiplexes them all on one connection.
+    let client = if options.http2 {
+        reqwest::Client::builder().http2_prior_knowledge().build().unwrap()
+    } else {
+        reqwest::Client::new()
+    };
+
+    let token = match (&options.token, &options.email, &options.password) {
+        (Some(token), _, _) => Some(token.clone()),
+        (None, Some(email), Some(password)) => match login(&client, &options.url, email, password).await {
+            Ok(token) => Some(token),
+            Err(error) => {
+                eprintln!("{}", error);
+                std::process::exit(1);
+            }
+        },
+        _ => None,
+    };
+
+    println!(
+        "GET {} with {} workers for {:?}, over HTTP/{}",
+        options.url,
+        options.concurrency,
+        options.duration,
+        if options.http2 { "2" } else { "1.1" }
+    );
+
+    let start = Instant::now();
+    let deadline = start + options.duration;
+    let workers: Vec<_> = (0..options.concurrency)
+        .map(|_| tokio::spawn(worker(client.clone(), options.url.clone(), token.clone(), deadline)))
+        .collect();
+
+    let mut report = Report::default();
+    for worker in workers {
+        report.merge(worker.await.unwrap());
+    }
+
+    report.print(start.elapsed());
+}
+
+#[test]
+fn percentiles_use_nearest_rank() {
+    let latencies: Vec<Duration> = (1..=100).map(Duration::from_millis).collect();
+
+    assert_eq!(Report::percentile(&latencies, 50.0), Duration::from_millis(50));
+    assert_eq!(Report::percentile(&latencies, 95.0), Duration::from_millis(95));
+    assert_eq!(Report::percentile(&latencies, 99.0), Duration::from_millis(99));
+    assert_eq!(Report::percentile(&latencies[..1], 99.0), Duration::from_millis(1));
+    assert_eq!(Report::percentile(&[], 50.0), Duration::ZERO);
+}
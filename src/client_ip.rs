@@ -0,0 +1,378 @@
+#![allow(dead_code)]
+
+//!
+//! CLIENT IP
+//! ---------
+//!
+//! Behind a reverse proxy or a load balancer, every connection to the app
+//! comes from the proxy: the address of the socket peer is the proxy's, and
+//! rate limiting or auditing by it would treat all clients as one. Proxies
+//! pass the address of their client along instead, in one of three ways:
+//!
+//! - `X-Forwarded-For: 203.0.113.7, 10.0.0.2`, where each proxy appends the
+//!   address of the client it got the request from;
+//! - `Forwarded: for=203.0.113.7, for="[2001:db8::1]:4711"`, the standard
+//!   form of the same (RFC 7239);
+//! - the PROXY protocol, where a proxy that does not speak HTTP (HAProxy, or
+//!   nginx's `stream` module) sends a line such as
+//!   `PROXY TCP4 203.0.113.7 10.0.0.1 51234 443` before anything else.
+//!
+//! Anyone can send these headers, though, so they are only believed when
+//! they were added by a proxy that is trusted: the `TRUSTED_PROXIES`, a
+//! comma-separated list of addresses and networks such as
+//! `10.0.0.0/8,127.0.0.1`. `ClientIp` walks the list of hops from the socket
+//! peer backwards, and the client is the first hop that is not a trusted
+//! proxy: whatever it claims came before it is ignored.
+//!
+//! Connections over a Unix socket (see `unix_socket.rs`) have no address,
+//! and can only come from a proxy on the same machine, so their headers are
+//! always believed.
+//!
+//! With `PROXY_PROTOCOL=true`, every connection to the public TCP listener
+//! must start with a PROXY protocol line (version 1, the text one), and come
+//! from a trusted proxy: the others are closed.
+//!
+
+use std::{
+    fmt,
+    future::Future,
+    io,
+    net::{IpAddr, SocketAddr},
+    str::FromStr,
+    sync::Arc,
+    time::Duration,
+};
+
+use axum::{
+    async_trait,
+    extract::{ConnectInfo, FromRef, FromRequestParts},
+    http::{request::Parts, HeaderMap, Request, StatusCode},
+    Router,
+};
+use hyper::body::Incoming;
+use ipnet::IpNet;
+use tokio::{
+    io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, BufReader},
+    net::TcpListener,
+    sync::watch,
+};
+use tower::ServiceExt;
+
+use crate::unix_socket::serve_connection;
+
+/// The longest PROXY protocol line there is, `\r\n` included.
+const MAX_PROXY_HEADER: u64 = 107;
+
+/// How long a proxy may take to send its PROXY protocol line.
+const PROXY_HEADER_TIMEOUT: Duration = Duration::from_secs(5);
+
+///
+/// The addresses and networks of the proxies whose forwarding headers are
+/// believed, written as a comma-separated list: `10.0.0.0/8,127.0.0.1`.
+///
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct TrustedProxies(Arc<Vec<IpNet>>);
+
+impl TrustedProxies {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        let ip = ip.to_canonical();
+        self.0.iter().any(|network| network.contains(&ip))
+    }
+}
+
+impl FromStr for TrustedProxies {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.split(',')
+            .map(str::trim)
+            .filter(|proxy| !proxy.is_empty())
+            .map(|proxy| {
+                proxy
+                    .parse::<IpNet>()
+                    .or_else(|_| proxy.parse::<IpAddr>().map(IpNet::from))
+                    .map_err(|_| format!("Invalid trusted proxy: {}", proxy))
+            })
+            .collect::<Result<Vec<_>, _>>()
+            .map(|proxies| TrustedProxies(Arc::new(proxies)))
+    }
+}
+
+impl fmt::Display for TrustedProxies {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let proxies = self.0.iter().map(ToString::to_string).collect::<Vec<_>>();
+        write!(f, "{}", proxies.join(","))
+    }
+}
+
+impl serde::Serialize for TrustedProxies {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+///
+/// The address of the client that sent the request, past the trusted
+/// proxies. Requests whose client is unknown, such as those that a proxy
+/// forwarded as `for=unknown`, are rejected: extract an `Option<ClientIp>`
+/// to serve them anyway.
+///
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ClientIp(pub IpAddr);
+
+impl fmt::Display for ClientIp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+#[async_trait]
+impl<S> FromRequestParts<S> for ClientIp
+where
+    TrustedProxies: FromRef<S>,
+    S: Send + Sync,
+{
+    type Rejection = (StatusCode, &'static str);
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let peer = parts.extensions.get::<ConnectInfo<SocketAddr>>().map(|ConnectInfo(peer)| peer.ip());
+        resolve(peer, &parts.headers, &TrustedProxies::from_ref(state))
+            .map(ClientIp)
+            .ok_or((StatusCode::INTERNAL_SERVER_ERROR, "The address of the client is unknown"))
+    }
+}
+
+///
+/// The last hop that is not a trusted proxy, starting from `peer`, the
+/// socket peer, which is `None` over a Unix socket.
+///
+fn resolve(peer: Option<IpAddr>, headers: &HeaderMap, trusted: &TrustedProxies) -> Option<IpAddr> {
+    let mut client = peer.map(|peer| peer.to_canonical());
+    let mut hops = forwarded_for(headers);
+    loop {
+        if let Some(ip) = client {
+            if !trusted.contains(ip) {
+                return Some(ip);
+            }
+        }
+        match hops.pop() {
+            Some(Some(hop)) => client = Some(hop),
+            // A trusted proxy did not say who its client was.
+            Some(None) => return None,
+            None => return client,
+        }
+    }
+}
+
+///
+/// The hops listed by `Forwarded`, or else by `X-Forwarded-For`, from the
+/// original client to the last proxy. A hop that is not an address, such as
+/// `unknown` or an obfuscated identifier, is `None`.
+///
+fn forwarded_for(headers: &HeaderMap) -> Vec<Option<IpAddr>> {
+    let values = |name| {
+        headers
+            .get_all(name)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .collect::<Vec<_>>()
+    };
+
+    let forwarded = values("Forwarded");
+    if !forwarded.is_empty() {
+        return forwarded
+            .into_iter()
+            .map(|element| {
+                element
+                    .split(';')
+                    .filter_map(|pair| pair.trim().split_once('='))
+                    .find(|(name, _)| name.eq_ignore_ascii_case("for"))
+                    .and_then(|(_, node)| parse_node(node))
+            })
+            .collect();
+    }
+    values("X-Forwarded-For").into_iter().map(parse_node).collect()
+}
+
+///
+/// An address, with or without a port: `203.0.113.7`, `"203.0.113.7:4711"`,
+/// `"[2001:db8::1]"` or `"[2001:db8::1]:4711"`.
+///
+fn parse_node(node: &str) -> Option<IpAddr> {
+    let node = node.trim().trim_matches('"');
+    let ip = node
+        .parse::<IpAddr>()
+        .ok()
+        .or_else(|| node.parse::<SocketAddr>().ok().map(|address| address.ip()))
+        .or_else(|| node.strip_prefix('[')?.strip_suffix(']')?.parse().ok())?;
+    Some(ip.to_canonical())
+}
+
+///
+/// Reads a PROXY protocol (version 1) line, and returns the address of the
+/// client it announces, or `None` for `PROXY UNKNOWN`, which proxies send for
+/// their own connections, such as health checks.
+///
+pub async fn read_proxy_header<R: AsyncBufRead + Unpin>(reader: &mut R) -> io::Result<Option<SocketAddr>> {
+    let mut line = Vec::new();
+    reader.take(MAX_PROXY_HEADER).read_until(b'\n', &mut line).await?;
+    parse_proxy_header(&line)
+}
+
+fn parse_proxy_header(line: &[u8]) -> io::Result<Option<SocketAddr>> {
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, "Invalid PROXY protocol header");
+
+    let line = std::str::from_utf8(line).ok().and_then(|line| line.strip_suffix("\r\n")).ok_or_else(invalid)?;
+    let fields = line.split(' ').collect::<Vec<_>>();
+    match fields.as_slice() {
+        ["PROXY", "UNKNOWN", ..] => Ok(None),
+        ["PROXY", "TCP4" | "TCP6", source, _destination, source_port, _destination_port] => {
+            let ip = source.parse::<IpAddr>().map_err(|_| invalid())?;
+            let port = source_port.parse::<u16>().map_err(|_| invalid())?;
+            Ok(Some(SocketAddr::new(ip, port)))
+        }
+        _ => Err(invalid()),
+    }
+}
+
+///
+/// Serves `router` on connections that start with a PROXY protocol line, as
+/// `serve_unix` does on a Unix socket, with the client that the line
+/// announces as the `ConnectInfo` of their requests. Connections that are
+/// not from a trusted proxy, or whose line is missing, are closed.
+///
+pub async fn serve_proxy_protocol(
+    (listener, router): (TcpListener, Router),
+    trusted: TrustedProxies,
+    shutdown: impl Future<Output = ()> + Send + 'static,
+) -> io::Result<()> {
+    println!("Listening on {}, behind the PROXY protocol", listener.local_addr()?);
+
+    let (stop_tx, stop_rx) = watch::channel(());
+    let (done_tx, done_rx) = watch::channel(());
+    tokio::pin!(shutdown);
+
+    loop {
+        let (stream, peer) = tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    eprintln!("Failed to accept a connection: {}", e);
+                    tokio::time::sleep(Duration::from_secs(1)).await;
+                    continue;
+                }
+            },
+            _ = &mut shutdown => break,
+        };
+        if !trusted.contains(peer.ip()) {
+            continue;
+        }
+
+        let (router, stop, done) = (router.clone(), stop_rx.clone(), done_rx.clone());
+        tokio::spawn(async move {
+            let mut stream = BufReader::new(stream);
+            let client = match tokio::time::timeout(PROXY_HEADER_TIMEOUT, read_proxy_header(&mut stream)).await {
+                Ok(Ok(client)) => client.unwrap_or(peer),
+                _ => return,
+            };
+            let service = router.map_request(move |mut request: Request<Incoming>| {
+                request.extensions_mut().insert(ConnectInfo(client));
+                request
+            });
+            serve_connection(stream, service, stop).await;
+            drop(done);
+        });
+    }
+
+    drop(stop_tx);
+    drop(done_rx);
+    done_tx.closed().await;
+    Ok(())
+}
+
+#[test]
+fn the_client_is_the_last_untrusted_hop() {
+    use axum::http::HeaderValue;
+
+    let trusted: TrustedProxies = "10.0.0.0/8, 127.0.0.1".parse().unwrap();
+    let headers = |pairs: &[(&'static str, &'static str)]| {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.append(*name, HeaderValue::from_static(value));
+        }
+        headers
+    };
+    let ip = |ip: &str| Some(ip.parse::<IpAddr>().unwrap());
+    let proxy = ip("10.0.0.2");
+
+    // Not from a proxy: the headers are whatever the client made up.
+    let spoofed = headers(&[("X-Forwarded-For", "1.2.3.4")]);
+    assert_eq!(resolve(ip("203.0.113.7"), &spoofed, &trusted), ip("203.0.113.7"));
+
+    // Through two proxies, the first of which was sent a made-up header.
+    let forwarded = headers(&[("X-Forwarded-For", "1.2.3.4, 203.0.113.7"), ("X-Forwarded-For", "10.0.0.1")]);
+    assert_eq!(resolve(proxy, &forwarded, &trusted), ip("203.0.113.7"));
+
+    // `Forwarded` wins over `X-Forwarded-For`, and may carry ports.
+    let forwarded = headers(&[
+        ("X-Forwarded-For", "1.2.3.4"),
+        ("Forwarded", r#"for="[2001:db8::1]:4711";proto=https, For=10.0.0.1:80"#),
+    ]);
+    assert_eq!(resolve(proxy, &forwarded, &trusted), ip("2001:db8::1"));
+
+    let hidden = headers(&[("Forwarded", "for=unknown")]);
+    assert_eq!(resolve(proxy, &hidden, &trusted), None);
+
+    // Over a Unix socket, only a proxy can be on the other side.
+    assert_eq!(resolve(None, &headers(&[("X-Forwarded-For", "203.0.113.7")]), &trusted), ip("203.0.113.7"));
+    assert_eq!(resolve(None, &HeaderMap::new(), &trusted), None);
+
+    // IPv4 peers of a dual-stack listener are IPv4-mapped IPv6 addresses.
+    assert_eq!(resolve(ip("::ffff:10.0.0.2"), &forwarded, &trusted), ip("2001:db8::1"));
+}
+
+#[tokio::test]
+async fn proxy_protocol_announces_the_client() {
+    use axum::routing::get;
+    use tokio::io::AsyncWriteExt;
+
+    #[derive(Clone)]
+    struct State {
+        trusted: TrustedProxies,
+    }
+
+    impl FromRef<State> for TrustedProxies {
+        fn from_ref(state: &State) -> Self {
+            state.trusted.clone()
+        }
+    }
+
+    let trusted: TrustedProxies = "127.0.0.1".parse().unwrap();
+    let app = Router::new()
+        .route("/", get(|ClientIp(ip): ClientIp| async move { ip.to_string() }))
+        .with_state(State { trusted: trusted.clone() });
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    tokio::spawn(serve_proxy_protocol((listener, app), trusted, std::future::pending()));
+
+    let request = |header: &'static str| async move {
+        let mut stream = tokio::net::TcpStream::connect(address).await.unwrap();
+        stream.write_all(header.as_bytes()).await.unwrap();
+        stream.write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n").await.unwrap();
+        let mut response = String::new();
+        let _ = stream.read_to_string(&mut response).await;
+        response
+    };
+
+    let response = request("PROXY TCP4 203.0.113.7 127.0.0.1 51234 80\r\n").await;
+    assert!(response.starts_with("HTTP/1.1 200 OK"), "{}", response);
+    assert!(response.ends_with("\r\n\r\n203.0.113.7"), "{}", response);
+
+    // The proxy's own health checks.
+    let response = request("PROXY UNKNOWN\r\n").await;
+    assert!(response.ends_with("\r\n\r\n127.0.0.1"), "{}", response);
+
+    // Without the line, the request line is taken for a broken one.
+    assert_eq!(request("").await, "");
+}
@@ -0,0 +1,88 @@
+#![allow(dead_code)]
+
+//!
+//! CLOCK
+//! -----
+//!
+//! Anything that decides based on the current time, such as whether a token
+//! has expired or a todo is overdue, is hard to test against the real clock:
+//! the test either waits, or it cannot tell what "now" will be.
+//!
+//! So the application asks a `Clock` for the time instead. In production that
+//! is the `SystemClock`; tests use a `FakeClock`, which only moves when told
+//! to. The clock is shared through state as a `SharedClock`, and every piece
+//! of state that needs it holds the same one.
+//!
+//! Timestamps that Postgres fills in itself, such as `created_at`, still come
+//! from the database's clock.
+//!
+
+use std::sync::{Arc, Mutex};
+
+use time::{Duration, OffsetDateTime, PrimitiveDateTime};
+
+pub trait Clock: Send + Sync {
+    fn now(&self) -> OffsetDateTime;
+
+    ///
+    /// The current time in UTC, without an offset, as stored in `TIMESTAMP`
+    /// columns.
+    ///
+    fn now_utc_primitive(&self) -> PrimitiveDateTime {
+        let now = self.now().to_offset(time::UtcOffset::UTC);
+        PrimitiveDateTime::new(now.date(), now.time())
+    }
+}
+
+pub type SharedClock = Arc<dyn Clock>;
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> OffsetDateTime {
+        OffsetDateTime::now_utc()
+    }
+}
+
+///
+/// A clock that stands still until it is `set` or `advance`d. Clones share
+/// the same time, so a test can keep one and hand another to the app.
+///
+#[derive(Clone, Debug)]
+pub struct FakeClock {
+    now: Arc<Mutex<OffsetDateTime>>,
+}
+
+impl FakeClock {
+    pub fn new(now: OffsetDateTime) -> Self {
+        FakeClock { now: Arc::new(Mutex::new(now)) }
+    }
+
+    pub fn set(&self, now: OffsetDateTime) {
+        *self.now.lock().unwrap() = now;
+    }
+
+    pub fn advance(&self, by: Duration) {
+        *self.now.lock().unwrap() += by;
+    }
+}
+
+impl Clock for FakeClock {
+    fn now(&self) -> OffsetDateTime {
+        *self.now.lock().unwrap()
+    }
+}
+
+#[test]
+fn fake_clock_clones_share_the_time() {
+    use time::macros::datetime;
+
+    let clock = FakeClock::new(datetime!(2026-10-16 12:00 +02:00));
+    let shared: SharedClock = Arc::new(clock.clone());
+
+    clock.advance(Duration::minutes(30));
+
+    assert_eq!(shared.now(), datetime!(2026-10-16 12:30 +02:00));
+    assert_eq!(shared.now_utc_primitive(), datetime!(2026-10-16 10:30));
+}
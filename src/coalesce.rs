@@ -0,0 +1,70 @@
+#![allow(dead_code)]
+
+//!
+//! REQUEST COALESCING
+//! ------------------
+//!
+//! When a todo is popular, many requests for it arrive at the same time, and
+//! each one runs the same query against the database. A single-flight group
+//! lets the first request run the query, while the others wait for it and
+//! share its result.
+//!
+//! This is not a cache: once the query completes, the flight is over, and the
+//! next request runs the query again. Results are only ever shared between
+//! requests that were in flight together, so they are never staler than the
+//! slowest of those requests would have seen anyway.
+//!
+
+use std::{
+    collections::HashMap,
+    future::Future,
+    hash::Hash,
+    sync::{Arc, Mutex},
+};
+
+use tokio::sync::OnceCell;
+
+pub struct SingleFlight<K, V> {
+    flights: Arc<Mutex<HashMap<K, Arc<OnceCell<V>>>>>,
+}
+
+impl<K, V> Clone for SingleFlight<K, V> {
+    fn clone(&self) -> Self {
+        SingleFlight { flights: self.flights.clone() }
+    }
+}
+
+impl<K, V> Default for SingleFlight<K, V> {
+    fn default() -> Self {
+        SingleFlight { flights: Arc::new(Mutex::new(HashMap::new())) }
+    }
+}
+
+impl<K: Eq + Hash + Clone, V: Clone> SingleFlight<K, V> {
+    ///
+    /// Runs `load` for `key`, unless a call for the same key is already in
+    /// flight, in which case this waits for it and returns a copy of its
+    /// result.
+    ///
+    /// If the caller running `load` is cancelled, one of the waiters runs it
+    /// instead, so a dropped request never leaves the others hanging.
+    ///
+    pub async fn run<F, Fut>(&self, key: K, load: F) -> V
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = V>,
+    {
+        let flight = self.flights.lock().unwrap().entry(key.clone()).or_default().clone();
+
+        let value = flight.get_or_init(load).await.clone();
+
+        // Whoever gets here first ends the flight. Callers arriving after
+        // that start a new one, rather than reusing a finished result.
+        let mut flights = self.flights.lock().unwrap();
+        if flights.get(&key).is_some_and(|current| Arc::ptr_eq(current, &flight)) {
+            flights.remove(&key);
+        }
+
+        value
+    }
+}
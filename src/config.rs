@@ -0,0 +1,464 @@
+#![allow(dead_code)]
+
+//!
+//! CONFIG
+//! ------
+//!
+//! Real applications are configured by their environment: the same binary runs
+//! on a laptop, in CI, and in production, with only environment variables
+//! telling them apart.
+//!
+//! This module gathers all of the graduation project's configuration into a
+//! single `AppConfig`, read from the environment (see `.env` for examples).
+//!
+//! Secrets are read through a `SecretsProvider` instead (see `secrets.rs`), by
+//! `AppConfig::load`. `AppConfig::from_env` reads them from the environment,
+//! for tools and tests.
+//!
+
+use std::{net::SocketAddr, path::PathBuf, str::FromStr};
+
+use sqlx::postgres::PgConnectOptions;
+
+use crate::app::TrailingSlash;
+use crate::client_ip::TrustedProxies;
+use crate::faults::Faults;
+use crate::maintenance::MaintenanceWindows;
+use crate::pii::{DataKeys, IndexKey};
+use crate::secrets::Secrets;
+use crate::unix_socket::SocketMode;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct AppConfig {
+    pub database_url: String,
+    /// Overrides the password of `database_url`, if any.
+    pub database_password: Option<String>,
+    pub jwt_secret: String,
+    pub bind_addr: SocketAddr,
+    pub bind_socket: Option<PathBuf>,
+    pub bind_socket_mode: SocketMode,
+    pub trusted_proxies: TrustedProxies,
+    pub proxy_protocol: bool,
+    pub admin_bind_addr: SocketAddr,
+    pub attachments_dir: PathBuf,
+    pub trailing_slash: TrailingSlash,
+    pub log_filter: String,
+    pub slow_query_threshold_ms: u64,
+    pub expensive_route_concurrency: usize,
+    pub admission_latency_target_ms: u64,
+    pub admission_max_concurrency: usize,
+    pub http_cache_max_age_secs: u64,
+    pub geoip_dir: Option<PathBuf>,
+    pub maintenance_windows: MaintenanceWindows,
+    pub shadow_upstream: Option<String>,
+    pub shadow_percent: f64,
+    pub faults: Faults,
+    pub webauthn_rp_id: String,
+    pub webauthn_origin: String,
+    pub pii_keys: DataKeys,
+    pub pii_index_key: IndexKey,
+    pub pool: PoolConfig,
+    pub todo_repo: TodoRepoKind,
+    /// The database of the todos, with `TODO_REPO=mysql`.
+    pub mysql_url: Option<String>,
+    /// The database of the todos, with `TODO_REPO=sqlite`.
+    pub sqlite_url: Option<String>,
+    /// The table of the todos, with `TODO_REPO=dynamodb`.
+    pub dynamodb_table: String,
+    /// The database of the todos, with `TODO_REPO=mongodb`.
+    pub mongodb_url: Option<String>,
+    /// The database of the todos, with `TODO_REPO=any`, whose scheme picks
+    /// the driver.
+    pub todo_database_url: Option<String>,
+    pub session_store: SessionStoreKind,
+    /// The Redis of the sessions, with `SESSION_STORE=redis`.
+    pub redis_url: Option<String>,
+}
+
+///
+/// Where the todos of `/todo` are kept, from `TODO_REPO`. Everything else,
+/// the users included, is always kept in Postgres, and the routes that join
+/// it with the todos are left out when they are elsewhere (see `in_postgres`).
+///
+#[derive(Clone, Copy, Debug, Default, PartialEq, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TodoRepoKind {
+    #[default]
+    Postgres,
+    /// The same Postgres, with every query run as the `todo_rls` role, which
+    /// row-level security policies keep to the todos of the user.
+    Rls,
+    /// The same Postgres, through SeaORM rather than sqlx, with the `sea-orm`
+    /// feature.
+    #[cfg(feature = "sea-orm")]
+    SeaOrm,
+    /// MySQL or MariaDB, at `MYSQL_URL`, with the `mysql` feature.
+    #[cfg(feature = "mysql")]
+    MySql,
+    /// SQLite, in the file of `SQLITE_URL`, with the `sqlite` feature.
+    #[cfg(feature = "sqlite")]
+    Sqlite,
+    /// DynamoDB, in the table `DYNAMODB_TABLE`, with the `dynamodb` feature.
+    /// The client is configured by the usual `AWS_*` variables.
+    #[cfg(feature = "dynamodb")]
+    DynamoDb,
+    /// MongoDB, at `MONGODB_URL`, with the `mongodb` feature.
+    #[cfg(feature = "mongodb")]
+    MongoDb,
+    /// Postgres, MySQL or SQLite, at `TODO_DATABASE_URL`, through the `Any`
+    /// driver of sqlx, with the `any` feature.
+    #[cfg(feature = "any")]
+    Any,
+}
+
+impl TodoRepoKind {
+    ///
+    /// Whether the todos are in the tables of the app's own Postgres, where
+    /// the lists, attachments, recurrences and feed query them directly.
+    /// Those routes are only mounted when they are.
+    ///
+    pub fn in_postgres(self) -> bool {
+        match self {
+            TodoRepoKind::Postgres | TodoRepoKind::Rls => true,
+            #[cfg(feature = "sea-orm")]
+            TodoRepoKind::SeaOrm => true,
+            #[cfg(feature = "mysql")]
+            TodoRepoKind::MySql => false,
+            #[cfg(feature = "sqlite")]
+            TodoRepoKind::Sqlite => false,
+            #[cfg(feature = "dynamodb")]
+            TodoRepoKind::DynamoDb => false,
+            #[cfg(feature = "mongodb")]
+            TodoRepoKind::MongoDb => false,
+            #[cfg(feature = "any")]
+            TodoRepoKind::Any => false,
+        }
+    }
+}
+
+impl FromStr for TodoRepoKind {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "postgres" => Ok(TodoRepoKind::Postgres),
+            "rls" => Ok(TodoRepoKind::Rls),
+            #[cfg(feature = "sea-orm")]
+            "sea_orm" => Ok(TodoRepoKind::SeaOrm),
+            #[cfg(not(feature = "sea-orm"))]
+            "sea_orm" => Err("TODO_REPO=sea_orm needs the app to be built with the sea-orm feature".to_string()),
+            #[cfg(feature = "mysql")]
+            "mysql" => Ok(TodoRepoKind::MySql),
+            #[cfg(not(feature = "mysql"))]
+            "mysql" => Err("TODO_REPO=mysql needs the app to be built with the mysql feature".to_string()),
+            #[cfg(feature = "sqlite")]
+            "sqlite" => Ok(TodoRepoKind::Sqlite),
+            #[cfg(not(feature = "sqlite"))]
+            "sqlite" => Err("TODO_REPO=sqlite needs the app to be built with the sqlite feature".to_string()),
+            #[cfg(feature = "dynamodb")]
+            "dynamodb" => Ok(TodoRepoKind::DynamoDb),
+            #[cfg(not(feature = "dynamodb"))]
+            "dynamodb" => Err("TODO_REPO=dynamodb needs the app to be built with the dynamodb feature".to_string()),
+            #[cfg(feature = "mongodb")]
+            "mongodb" => Ok(TodoRepoKind::MongoDb),
+            #[cfg(not(feature = "mongodb"))]
+            "mongodb" => Err("TODO_REPO=mongodb needs the app to be built with the mongodb feature".to_string()),
+            #[cfg(feature = "any")]
+            "any" => Ok(TodoRepoKind::Any),
+            #[cfg(not(feature = "any"))]
+            "any" => Err("TODO_REPO=any needs the app to be built with the any feature".to_string()),
+            other => Err(format!("TODO_REPO has an invalid value: {}", other)),
+        }
+    }
+}
+
+///
+/// Where the sessions, that is the refresh tokens of `auth.rs`, are kept,
+/// from `SESSION_STORE`.
+///
+#[derive(Clone, Copy, Debug, Default, PartialEq, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SessionStoreKind {
+    #[default]
+    Postgres,
+    /// Redis, at `REDIS_URL`, with the `redis` feature.
+    #[cfg(feature = "redis")]
+    Redis,
+}
+
+impl FromStr for SessionStoreKind {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "postgres" => Ok(SessionStoreKind::Postgres),
+            #[cfg(feature = "redis")]
+            "redis" => Ok(SessionStoreKind::Redis),
+            #[cfg(not(feature = "redis"))]
+            "redis" => Err("SESSION_STORE=redis needs the app to be built with the redis feature".to_string()),
+            other => Err(format!("SESSION_STORE has an invalid value: {}", other)),
+        }
+    }
+}
+
+///
+/// Settings for the database connection pool. The defaults suit a single
+/// instance talking to a dedicated Postgres; see `EXERCISE 9` in
+/// `persistence.rs` for what each of them is for.
+///
+#[derive(Clone, Debug, PartialEq, serde::Serialize)]
+pub struct PoolConfig {
+    pub max_connections: u32,
+    pub min_connections: u32,
+    pub acquire_timeout_secs: u64,
+    pub idle_timeout_secs: u64,
+    pub test_before_acquire: bool,
+}
+
+impl Default for PoolConfig {
+    fn default() -> Self {
+        PoolConfig {
+            max_connections: 10,
+            min_connections: 0,
+            acquire_timeout_secs: 30,
+            idle_timeout_secs: 600,
+            test_before_acquire: true,
+        }
+    }
+}
+
+impl PoolConfig {
+    pub fn from_env() -> Result<PoolConfig, String> {
+        let defaults = PoolConfig::default();
+        Ok(PoolConfig {
+            max_connections: parsed_var("DATABASE_MAX_CONNECTIONS", &defaults.max_connections.to_string())?,
+            min_connections: parsed_var("DATABASE_MIN_CONNECTIONS", &defaults.min_connections.to_string())?,
+            acquire_timeout_secs: parsed_var("DATABASE_ACQUIRE_TIMEOUT_SECS", &defaults.acquire_timeout_secs.to_string())?,
+            idle_timeout_secs: parsed_var("DATABASE_IDLE_TIMEOUT_SECS", &defaults.idle_timeout_secs.to_string())?,
+            test_before_acquire: parsed_var("DATABASE_TEST_BEFORE_ACQUIRE", &defaults.test_before_acquire.to_string())?,
+        })
+    }
+}
+
+///
+/// The settings of `AppConfig` that are secrets.
+///
+struct AppSecrets {
+    database_password: Option<String>,
+    jwt_secret: String,
+    pii_keys: String,
+    pii_index_key: String,
+}
+
+impl AppSecrets {
+    fn from_env() -> Result<AppSecrets, String> {
+        Ok(AppSecrets {
+            database_password: optional_var("DATABASE_PASSWORD"),
+            jwt_secret: required_var("JWT_SECRET")?,
+            pii_keys: required_var("PII_KEYS")?,
+            pii_index_key: required_var("PII_INDEX_KEY")?,
+        })
+    }
+
+    async fn load(secrets: &Secrets) -> Result<AppSecrets, String> {
+        Ok(AppSecrets {
+            database_password: secrets.get("DATABASE_PASSWORD").await.map_err(|e| e.to_string())?,
+            jwt_secret: secrets.require("JWT_SECRET").await?,
+            pii_keys: secrets.require("PII_KEYS").await?,
+            pii_index_key: secrets.require("PII_INDEX_KEY").await?,
+        })
+    }
+}
+
+impl AppConfig {
+    pub fn from_env() -> Result<AppConfig, String> {
+        AppConfig::with_secrets(AppSecrets::from_env()?)
+    }
+
+    ///
+    /// The configuration from the environment, with its secrets from
+    /// `secrets`.
+    ///
+    pub async fn load(secrets: &Secrets) -> Result<AppConfig, String> {
+        AppConfig::with_secrets(AppSecrets::load(secrets).await?)
+    }
+
+    ///
+    /// The options to connect to the database with, which include
+    /// `database_password` rather than the password of `database_url`.
+    ///
+    pub fn connect_options(&self) -> Result<PgConnectOptions, String> {
+        let options: PgConnectOptions =
+            self.database_url.parse().map_err(|e| format!("DATABASE_URL has an invalid value: {}", e))?;
+        Ok(match &self.database_password {
+            Some(password) => options.password(password),
+            None => options,
+        })
+    }
+
+    fn with_secrets(secrets: AppSecrets) -> Result<AppConfig, String> {
+        let todo_repo: TodoRepoKind = optional_var("TODO_REPO").unwrap_or("postgres".to_string()).parse()?;
+        let mysql_url = optional_var("MYSQL_URL");
+        #[cfg(feature = "mysql")]
+        if todo_repo == TodoRepoKind::MySql && mysql_url.is_none() {
+            return Err("MYSQL_URL must be set when TODO_REPO is mysql".to_string());
+        }
+        let sqlite_url = optional_var("SQLITE_URL");
+        #[cfg(feature = "sqlite")]
+        if todo_repo == TodoRepoKind::Sqlite && sqlite_url.is_none() {
+            return Err("SQLITE_URL must be set when TODO_REPO is sqlite".to_string());
+        }
+        let mongodb_url = optional_var("MONGODB_URL");
+        #[cfg(feature = "mongodb")]
+        if todo_repo == TodoRepoKind::MongoDb && mongodb_url.is_none() {
+            return Err("MONGODB_URL must be set when TODO_REPO is mongodb".to_string());
+        }
+        let todo_database_url = optional_var("TODO_DATABASE_URL");
+        #[cfg(feature = "any")]
+        if todo_repo == TodoRepoKind::Any && todo_database_url.is_none() {
+            return Err("TODO_DATABASE_URL must be set when TODO_REPO is any".to_string());
+        }
+        let session_store: SessionStoreKind =
+            optional_var("SESSION_STORE").unwrap_or("postgres".to_string()).parse()?;
+        let redis_url = optional_var("REDIS_URL");
+        #[cfg(feature = "redis")]
+        if session_store == SessionStoreKind::Redis && redis_url.is_none() {
+            return Err("REDIS_URL must be set when SESSION_STORE is redis".to_string());
+        }
+        Ok(AppConfig {
+            database_url: required_var("DATABASE_URL")?,
+            database_password: secrets.database_password,
+            jwt_secret: secrets.jwt_secret,
+            bind_addr: parsed_var("BIND_ADDR", "127.0.0.1:3000")?,
+            bind_socket: optional_var("BIND_SOCKET").map(PathBuf::from),
+            bind_socket_mode: parsed_var("BIND_SOCKET_MODE", "660")?,
+            trusted_proxies: parsed_var("TRUSTED_PROXIES", "")?,
+            proxy_protocol: parsed_var("PROXY_PROTOCOL", "false")?,
+            admin_bind_addr: parsed_var("ADMIN_BIND_ADDR", "127.0.0.1:3001")?,
+            attachments_dir: parsed_var("ATTACHMENTS_DIR", "attachments")?,
+            trailing_slash: parsed_var("TRAILING_SLASH", "lenient")?,
+            log_filter: parsed_var("RUST_LOG", "info")?,
+            slow_query_threshold_ms: parsed_var("SLOW_QUERY_THRESHOLD_MS", "200")?,
+            expensive_route_concurrency: parsed_var("EXPENSIVE_ROUTE_CONCURRENCY", "8")?,
+            admission_latency_target_ms: parsed_var("ADMISSION_LATENCY_TARGET_MS", "250")?,
+            admission_max_concurrency: parsed_var("ADMISSION_MAX_CONCURRENCY", "512")?,
+            http_cache_max_age_secs: parsed_var("HTTP_CACHE_MAX_AGE_SECS", "5")?,
+            geoip_dir: optional_var("GEOIP_DIR").map(PathBuf::from),
+            maintenance_windows: parsed_var("MAINTENANCE_WINDOWS", "")?,
+            shadow_upstream: optional_var("SHADOW_UPSTREAM"),
+            shadow_percent: parsed_var("SHADOW_PERCENT", "0")?,
+            faults: parsed_var("FAULTS", "")?,
+            webauthn_rp_id: parsed_var("WEBAUTHN_RP_ID", "localhost")?,
+            webauthn_origin: parsed_var("WEBAUTHN_ORIGIN", "http://localhost:3000")?,
+            pii_keys: secrets.pii_keys.parse()?,
+            pii_index_key: secrets.pii_index_key.parse()?,
+            pool: PoolConfig::from_env()?,
+            todo_repo,
+            mysql_url,
+            sqlite_url,
+            dynamodb_table: parsed_var("DYNAMODB_TABLE", "todos")?,
+            mongodb_url,
+            todo_database_url,
+            session_store,
+            redis_url,
+        })
+    }
+
+    ///
+    /// A view of the configuration that is safe to show to operators, with
+    /// secrets masked out.
+    ///
+    pub fn redacted(&self) -> RedactedConfig {
+        RedactedConfig {
+            database_url: "<redacted>".to_string(),
+            database_password: self.database_password.as_ref().map(|_| "<redacted>".to_string()),
+            jwt_secret: "<redacted>".to_string(),
+            bind_addr: self.bind_addr.to_string(),
+            bind_socket: self.bind_socket.as_ref().map(|path| path.display().to_string()),
+            bind_socket_mode: self.bind_socket_mode,
+            trusted_proxies: self.trusted_proxies.clone(),
+            proxy_protocol: self.proxy_protocol,
+            admin_bind_addr: self.admin_bind_addr.to_string(),
+            attachments_dir: self.attachments_dir.display().to_string(),
+            trailing_slash: self.trailing_slash,
+            log_filter: self.log_filter.clone(),
+            slow_query_threshold_ms: self.slow_query_threshold_ms,
+            expensive_route_concurrency: self.expensive_route_concurrency,
+            admission_latency_target_ms: self.admission_latency_target_ms,
+            admission_max_concurrency: self.admission_max_concurrency,
+            http_cache_max_age_secs: self.http_cache_max_age_secs,
+            geoip_dir: self.geoip_dir.as_ref().map(|dir| dir.display().to_string()),
+            maintenance_windows: self.maintenance_windows.clone(),
+            shadow_upstream: self.shadow_upstream.clone(),
+            shadow_percent: self.shadow_percent,
+            faults: self.faults.clone(),
+            webauthn_rp_id: self.webauthn_rp_id.clone(),
+            webauthn_origin: self.webauthn_origin.clone(),
+            pii_keys: "<redacted>".to_string(),
+            pii_index_key: "<redacted>".to_string(),
+            pool: self.pool.clone(),
+            todo_repo: self.todo_repo,
+            mysql_url: self.mysql_url.as_ref().map(|_| "<redacted>".to_string()),
+            sqlite_url: self.sqlite_url.clone(),
+            dynamodb_table: self.dynamodb_table.clone(),
+            mongodb_url: self.mongodb_url.as_ref().map(|_| "<redacted>".to_string()),
+            todo_database_url: self.todo_database_url.as_ref().map(|_| "<redacted>".to_string()),
+            session_store: self.session_store,
+            redis_url: self.redis_url.as_ref().map(|_| "<redacted>".to_string()),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, serde::Serialize)]
+pub struct RedactedConfig {
+    pub database_url: String,
+    pub database_password: Option<String>,
+    pub jwt_secret: String,
+    pub bind_addr: String,
+    pub bind_socket: Option<String>,
+    pub bind_socket_mode: SocketMode,
+    pub trusted_proxies: TrustedProxies,
+    pub proxy_protocol: bool,
+    pub admin_bind_addr: String,
+    pub attachments_dir: String,
+    pub trailing_slash: TrailingSlash,
+    pub log_filter: String,
+    pub slow_query_threshold_ms: u64,
+    pub expensive_route_concurrency: usize,
+    pub admission_latency_target_ms: u64,
+    pub admission_max_concurrency: usize,
+    pub http_cache_max_age_secs: u64,
+    pub geoip_dir: Option<String>,
+    pub maintenance_windows: MaintenanceWindows,
+    pub shadow_upstream: Option<String>,
+    pub shadow_percent: f64,
+    pub faults: Faults,
+    pub webauthn_rp_id: String,
+    pub webauthn_origin: String,
+    pub pii_keys: String,
+    pub pii_index_key: String,
+    pub pool: PoolConfig,
+    pub todo_repo: TodoRepoKind,
+    pub mysql_url: Option<String>,
+    pub sqlite_url: Option<String>,
+    pub dynamodb_table: String,
+    pub mongodb_url: Option<String>,
+    pub todo_database_url: Option<String>,
+    pub session_store: SessionStoreKind,
+    pub redis_url: Option<String>,
+}
+
+fn required_var(name: &str) -> Result<String, String> {
+    std::env::var(name).map_err(|_| format!("{} must be set", name))
+}
+
+fn optional_var(name: &str) -> Option<String> {
+    std::env::var(name).ok().filter(|value| !value.is_empty())
+}
+
+fn parsed_var<T: std::str::FromStr>(name: &str, default: &str) -> Result<T, String> {
+    let value = std::env::var(name).unwrap_or_else(|_| default.to_string());
+    value
+        .parse()
+        .map_err(|_| format!("{} has an invalid value: {}", name, value))
+}
@@ -25,12 +25,21 @@ use std::sync::Arc;
 #[allow(unused_imports)]
 use axum::extract::State;
 use axum::extract::Path;
+use axum::Json;
 #[allow(unused_imports)]
 use axum::{body::Body, http::Method, routing::*};
 #[allow(unused_imports)]
 use hyper::Request;
+use rust_decimal::Decimal;
+#[allow(unused_imports)]
+use rust_decimal_macros::dec;
 use tokio::sync::Mutex;
 
+use crate::app::AppBuilder;
+use crate::config::AppConfig;
+use crate::ids::{IdGenerator, SequenceIds};
+use crate::money::Money;
+
 ///
 /// EXERCISE 1
 ///
@@ -47,7 +56,7 @@ async fn closure_shared_context() {
     /// for ServiceExt::oneshot
     use tower::util::ServiceExt;
 
-    let gbp_to_usd_rate = 1.3;
+    let gbp_to_usd_rate = dec!(1.3);
 
     let _app = Router::<()>::new()
         .route("/usd_to_gbp", get(move |usd: String| async move {convert_gbp_to_usd(usd, gbp_to_usd_rate)}))
@@ -68,20 +77,20 @@ async fn closure_shared_context() {
 
     let _body_as_string = String::from_utf8(body.to_vec()).unwrap();
 
-    assert_eq!(_body_as_string, "130");
+    assert_eq!(_body_as_string, "130.00");
 }
-fn convert_usd_to_gbp(usd: String, gbp_to_usd_rate: f64) -> String {
-    format!("{}", usd.parse::<f64>().unwrap() * gbp_to_usd_rate)
+fn convert_usd_to_gbp(usd: String, gbp_to_usd_rate: Decimal) -> String {
+    format!("{}", usd.parse::<Money>().unwrap() * gbp_to_usd_rate)
 }
-fn convert_gbp_to_usd(gbp: String, gbp_to_usd_rate: f64) -> String {
-    format!("{}", gbp.parse::<f64>().unwrap() / gbp_to_usd_rate)
+fn convert_gbp_to_usd(gbp: String, gbp_to_usd_rate: Decimal) -> String {
+    format!("{}", gbp.parse::<Money>().unwrap() / gbp_to_usd_rate)
 }
 
 ///
 /// EXERCISE 2
 ///
 /// The previous exercise was almost too easy, because the context was of type
-/// `f64`, which is `Copy`. This means that the context was copied into both
+/// `Decimal`, which is `Copy`. This means that the context was copied into both
 /// closures, rather than truly shared between them.
 ///
 /// Of course, for any data type that you do not wish to mutate, you can always
@@ -107,7 +116,7 @@ async fn shared_mutable_context() {
     use std::sync::Arc;
     use tokio::sync::Mutex;
 
-    let gbp_to_usd_rate = 1.3;
+    let gbp_to_usd_rate = dec!(1.3);
     let arc1 = Arc::new(Mutex::new(gbp_to_usd_rate));
     let arc2 = arc1.clone();
 
@@ -142,7 +151,7 @@ async fn shared_mutable_context() {
 
     let _body_as_string = String::from_utf8(body.to_vec()).unwrap();
 
-    assert_eq!(_body_as_string, "130");
+    assert_eq!(_body_as_string, "130.00");
 }
 
 ///
@@ -167,7 +176,7 @@ async fn state_shared_context() {
     /// for ServiceExt::oneshot
     use tower::util::ServiceExt;
 
-    let _gbp_to_usd_rate = 1.3;
+    let _gbp_to_usd_rate = dec!(1.3);
 
     let _app = Router::new()
         .route("/usd_to_gbp",  get(usd_to_gbp_handler))
@@ -189,13 +198,13 @@ async fn state_shared_context() {
 
     let _body_as_string = String::from_utf8(body.to_vec()).unwrap();
 
-    assert_eq!(_body_as_string, "130");
+    assert_eq!(_body_as_string, "130.00");
 }
-async fn usd_to_gbp_handler(State(gbp_to_usd_rate): axum::extract::State<f64>, usd: String) -> String {
-    format!("{}", usd.parse::<f64>().unwrap() * gbp_to_usd_rate)
+async fn usd_to_gbp_handler(State(gbp_to_usd_rate): axum::extract::State<Decimal>, usd: String) -> String {
+    format!("{}", usd.parse::<Money>().unwrap() * gbp_to_usd_rate)
 }
-async fn gbp_to_usd_handler(State(gbp_to_usd_rate): axum::extract::State<f64>, gbp: String) -> String {
-    format!("{}", gbp.parse::<f64>().unwrap() / gbp_to_usd_rate)
+async fn gbp_to_usd_handler(State(gbp_to_usd_rate): axum::extract::State<Decimal>, gbp: String) -> String {
+    format!("{}", gbp.parse::<Money>().unwrap() / gbp_to_usd_rate)
 }
 
 ///
@@ -214,7 +223,7 @@ async fn mutable_state_shared_context() {
     /// for ServiceExt::oneshot
     use tower::util::ServiceExt;
 
-    let gbp_to_usd_rate = Arc::new(Mutex::new(1.3));
+    let gbp_to_usd_rate = Arc::new(Mutex::new(dec!(1.3)));
 
     let app = Router::new()
         .route("/usd_to_gbp", get(mutable_usd_to_gbp_handler))
@@ -248,21 +257,21 @@ async fn mutable_state_shared_context() {
 
     let _body_as_string = String::from_utf8(body.to_vec()).unwrap();
 
-    assert_eq!(_body_as_string, "200");
+    assert_eq!(_body_as_string, "200.00");
 }
-async fn mutable_usd_to_gbp_handler(State(rate): State<Arc<Mutex<f64>>>, body: String) -> String {
+async fn mutable_usd_to_gbp_handler(State(rate): State<Arc<Mutex<Decimal>>>, body: String) -> String {
     let guard = rate.lock().await;
-    let usd = body.parse::<f64>().unwrap();
+    let usd = body.parse::<Money>().unwrap();
     format!("{}", usd * (*guard))
 }
-async fn mutable_gbp_to_usd_handler(State(rate): State<Arc<Mutex<f64>>>, body: String) -> String {
+async fn mutable_gbp_to_usd_handler(State(rate): State<Arc<Mutex<Decimal>>>, body: String) -> String {
     let guard = rate.lock().await;
-    let gpd = body.parse::<f64>().unwrap();
+    let gpd = body.parse::<Money>().unwrap();
     format!("{}", gpd / (*guard))
 }
 
-async fn set_exchange_rate_handler(State(rate): State<Arc<Mutex<f64>>>, body: String) -> () {
-    let new_rate = body.parse::<f64>().unwrap();
+async fn set_exchange_rate_handler(State(rate): State<Arc<Mutex<Decimal>>>, body: String) -> () {
+    let new_rate = body.parse::<Decimal>().unwrap();
     let mut guard = rate.lock().await;
     *guard = new_rate
 }
@@ -313,8 +322,8 @@ async fn generic_state_shared_context() {
         .route("/eur_to_usd", get(generic_eur_to_usd_handler))
         .route("/usd_to_eur", get(generic_usd_to_eur_handler))
         .with_state(AllExchangeRates {
-            gbp_to_usd: GBPtoUSD(1.3),
-            eur_to_usd: EURtoUSD(1.2),
+            gbp_to_usd: GBPtoUSD(dec!(1.3)),
+            eur_to_usd: EURtoUSD(dec!(1.2)),
         });
 
     let response = _app
@@ -332,7 +341,7 @@ async fn generic_state_shared_context() {
 
     let _body_as_string = String::from_utf8(body.to_vec()).unwrap();
 
-    assert_eq!(_body_as_string, "130");
+    assert_eq!(_body_as_string, "130.00");
 }
 async fn generic_usd_to_gbp_handler(_price: String) -> String {
     todo!("Use State to access the exchange rate")
@@ -352,9 +361,9 @@ struct AllExchangeRates {
     eur_to_usd: EURtoUSD,
 }
 #[derive(Clone, Copy, Debug, PartialEq)]
-struct GBPtoUSD(f64);
+struct GBPtoUSD(Decimal);
 #[derive(Clone, Copy, Debug, PartialEq)]
-struct EURtoUSD(f64);
+struct EURtoUSD(Decimal);
 
 ///
 /// EXERCISE 6
@@ -389,7 +398,7 @@ async fn extension_shared_context() {
     /// for ServiceExt::oneshot
     use tower::util::ServiceExt;
 
-    let _gbp_to_usd_rate = 1.3;
+    let _gbp_to_usd_rate = dec!(1.3);
 
     let _app = Router::new()
         .route("/usd_to_gbp", get(extension_usd_to_gbp_handler))
@@ -410,7 +419,7 @@ async fn extension_shared_context() {
 
     let _body_as_string = String::from_utf8(body.to_vec()).unwrap();
 
-    assert_eq!(_body_as_string, "130");
+    assert_eq!(_body_as_string, "130.00");
 }
 async fn extension_usd_to_gbp_handler() -> String {
     todo!("Use Extensions to access the exchange rate")
@@ -443,89 +452,189 @@ struct User {
 }
 #[derive(Clone, Debug, serde::Serialize)]
 struct UserState {
-    users: Vec<User>
+    users: Vec<User>,
+    #[serde(skip)]
+    ids: SequenceIds,
 }
 
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
 struct UserDTO {
     name: String,
     email: String,
 }
 
 async fn run_users_server() {
+    let config = AppConfig::from_env().unwrap();
+
     let state = Arc::new(Mutex::new(UserState {
-        users: vec![]
+        users: vec![],
+        ids: SequenceIds::default(),
     }));
-    
 
-    let user_routes = Router::new()
-        // .route("/", get(get_users))
-        // .route("/:id", get(get_user))
-        // .route("/", post(create_user))
-        // .route("/:id", put(update_user))
-        // .route("/:id", delete(delete_user))
-        .with_state(state);
-
-    let app = Router::new()
-        .nest("/user/", user_routes);
-
-    let listener = tokio::net::TcpListener::bind("127.0.0.1:3000")
+    AppBuilder::new(state)
+        .nest("/user/", user_routes())
+        .serve(&config)
         .await
         .unwrap();
+}
 
-    println!("Listening on {}", listener.local_addr().unwrap());
-
-    axum::serve(listener, app).await.unwrap();
+fn user_routes() -> Router<Arc<Mutex<UserState>>> {
+    Router::new()
+        .route("/", get(get_users))
+        .route("/:id", get(get_user))
+        .route("/", post(create_user))
+        .route("/:id", put(update_user))
+        .route("/:id", delete(delete_user))
 }
 
-async fn get_users(state: State<Arc<Mutex<UserState>>>) -> Vec<User> {
-    state.lock().await.users.clone()
+async fn get_users(state: State<Arc<Mutex<UserState>>>) -> Json<Vec<User>> {
+    Json(state.lock().await.users.clone())
 }
 
 async fn get_user(
     state: State<Arc<Mutex<UserState>>>,
     Path(id): Path<u64>
-) -> Option<User> {
+) -> Json<Option<User>> {
     let users = &state.lock().await.users;
-    users.iter().find(|user| user.id == id).cloned()
+    Json(users.iter().find(|user| user.id == id).cloned())
 }
 
 async fn create_user(
     state: State<Arc<Mutex<UserState>>>,
-    body: UserDTO
-) -> User {
+    Json(body): Json<UserDTO>
+) -> Json<User> {
     let mut guard = state.lock().await;
     let user = User {
-        id: 1,
+        id: guard.ids.next_id() as u64,
         name: body.name,
         email: body.email
     };
     guard.users.push(user.clone());
-    user
+    Json(user)
 }
 
 async fn update_user(
     state: State<Arc<Mutex<UserState>>>,
     Path(id): Path<u64>,
-    body: UserDTO
-) -> Option<User> {
+    Json(body): Json<UserDTO>
+) -> Json<Option<User>> {
     let mut guard = state.lock().await;
-    let idx = guard.users.iter().position(|user| user.id == id)?;
-    let user = guard.users.get(idx)?;
+    let Some(idx) = guard.users.iter().position(|user| user.id == id) else {
+        return Json(None);
+    };
     let new_user = User {
-        id: user.id,
+        id: guard.users[idx].id,
         name: body.name,
         email: body.email
     };
     guard.users[idx] = new_user.clone();
-    Some(new_user)
+    Json(Some(new_user))
 }
 
 async fn delete_user(
     state: State<Arc<Mutex<UserState>>>,
     Path(id): Path<u64>,
-) -> Option<()> {
+) -> Json<Option<()>> {
     let mut guard = state.lock().await;
-    let idx = guard.users.iter().position(|user| user.id == id)?;
+    let Some(idx) = guard.users.iter().position(|user| user.id == id) else {
+        return Json(None);
+    };
     guard.users.remove(idx);
-    Some(())
+    Json(Some(()))
+}
+
+#[cfg(test)]
+#[derive(Clone, Debug)]
+enum UserOp {
+    Create(UserDTO),
+    Update(usize, UserDTO),
+    Delete(usize),
+}
+
+#[cfg(test)]
+fn user_op() -> impl proptest::strategy::Strategy<Value = UserOp> {
+    use proptest::prelude::*;
+
+    let dto = ("[a-z]{1,8}", "[a-z]{1,8}@example\\.com").prop_map(|(name, email)| UserDTO { name, email });
+    prop_oneof![
+        dto.clone().prop_map(UserOp::Create),
+        (any::<usize>(), dto).prop_map(|(pick, dto)| UserOp::Update(pick, dto)),
+        any::<usize>().prop_map(UserOp::Delete),
+    ]
+}
+
+#[cfg(test)]
+proptest::proptest! {
+    ///
+    /// Applies random sequences of operations to the users API, and to a
+    /// model of it (a map from id to user), and checks that the API lists
+    /// exactly the users of the model after every step. Updates and deletes
+    /// sometimes target an id that does not exist.
+    ///
+    #[test]
+    fn users_api_matches_model(ops in proptest::collection::vec(user_op(), 1..20)) {
+        // for Body::collect
+        use http_body_util::BodyExt;
+        /// for ServiceExt::oneshot
+        use tower::util::ServiceExt;
+
+        async fn send<T: serde::de::DeserializeOwned>(app: &Router, method: Method, uri: String, body: Option<&UserDTO>) -> T {
+            let body = match body {
+                Some(dto) => Body::from(serde_json::to_string(dto).unwrap()),
+                None => Body::empty(),
+            };
+            let request = Request::builder()
+                .method(method)
+                .uri(uri)
+                .header("Content-Type", "application/json")
+                .body(body)
+                .unwrap();
+            let response = app.clone().oneshot(request).await.unwrap();
+            serde_json::from_slice(&response.into_body().collect().await.unwrap().to_bytes()).unwrap()
+        }
+
+        let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+        runtime.block_on(async {
+            let state = Arc::new(Mutex::new(UserState { users: vec![], ids: SequenceIds::default() }));
+            let app = user_routes().with_state(state);
+
+            let mut model = std::collections::BTreeMap::<u64, User>::new();
+            let pick = |model: &std::collections::BTreeMap<u64, User>, pick: usize| {
+                model.keys().nth(pick % (model.len() + 1)).copied().unwrap_or(u64::MAX)
+            };
+
+            for op in ops {
+                match op {
+                    UserOp::Create(dto) => {
+                        let user: User = send(&app, Method::POST, "/".to_string(), Some(&dto)).await;
+                        assert!(!model.contains_key(&user.id), "id {} was handed out twice", user.id);
+                        assert_eq!((&user.name, &user.email), (&dto.name, &dto.email));
+                        model.insert(user.id, user);
+                    }
+                    UserOp::Update(pick_id, dto) => {
+                        let id = pick(&model, pick_id);
+                        let user: Option<User> = send(&app, Method::PUT, format!("/{}", id), Some(&dto)).await;
+                        let expected = model.get_mut(&id).map(|user| {
+                            user.name = dto.name;
+                            user.email = dto.email;
+                            user.clone()
+                        });
+                        assert_eq!(user, expected);
+                    }
+                    UserOp::Delete(pick_id) => {
+                        let id = pick(&model, pick_id);
+                        let _: Option<()> = send(&app, Method::DELETE, format!("/{}", id), None).await;
+                        model.remove(&id);
+                    }
+                }
+
+                let users: Vec<User> = send(&app, Method::GET, "/".to_string(), None).await;
+                assert_eq!(users, model.values().cloned().collect::<Vec<_>>());
+                for (id, user) in &model {
+                    let found: Option<User> = send(&app, Method::GET, format!("/{}", id), None).await;
+                    assert_eq!(found.as_ref(), Some(user));
+                }
+            }
+        });
+    }
 }
@@ -0,0 +1,396 @@
+#![allow(dead_code)]
+
+//!
+//! CONTRACT TESTS
+//! --------------
+//!
+//! The tests of a provider check what its authors think clients need. A
+//! consumer-driven contract is what one client actually relies on, written
+//! down by that client: the requests it sends, and what it reads in the
+//! responses. The provider replays every contract it has been given, and a
+//! change that would break one of its clients fails its build, before it is
+//! deployed, rather than theirs after.
+//!
+//! Contracts live in `contracts/`, one JSON file per consumer, in the spirit
+//! of Pact:
+//!
+//! ```json
+//! {
+//!   "consumer": "todo-cli",
+//!   "provider": "todos",
+//!   "interactions": [{
+//!     "description": "fetch a todo",
+//!     "provider_state": "a todo exists",
+//!     "request": { "method": "GET", "path": "/todo/{id}" },
+//!     "response": { "status": 200, "body": { "id": 1, "title": "Buy milk" } }
+//!   }]
+//! }
+//! ```
+//!
+//! `ContractRecorder` writes them from ordinary `oneshot` tests: each request
+//! sent through it is recorded with the response it got. Values that differ
+//! from one run to the next, such as ids, are named with `param`, and are
+//! recorded as `{id}` in paths.
+//!
+//! `ContractVerifier` replays a contract against a router. Before it does,
+//! the test sets up each `provider_state` the contract mentions, and tells
+//! the verifier the params that it produced. Responses are compared by
+//! shape, not by value: the status must be the same, every member of the
+//! recorded body must be there, with a value of the same JSON type, and
+//! members that the consumer never saw may be added freely. That is what
+//! lets a provider evolve without breaking clients.
+//!
+//! Recording a contract that already exists does not overwrite it, so that
+//! a change in the provider cannot silently rewrite what its clients agreed
+//! to. Set `UPDATE_CONTRACTS=1` to write it anyway.
+//!
+
+use std::{collections::HashMap, fmt, path::PathBuf};
+
+use axum::{
+    body::{Body, Bytes},
+    extract::Request,
+    http::{header, HeaderValue, Method, StatusCode},
+    response::Response,
+    Router,
+};
+use http_body_util::BodyExt;
+use serde_json::Value;
+use tower::ServiceExt;
+
+#[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct Contract {
+    pub consumer: String,
+    pub provider: String,
+    pub interactions: Vec<Interaction>,
+}
+
+#[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct Interaction {
+    pub description: String,
+    /// What the provider must hold before the request, such as "a todo
+    /// exists".
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub provider_state: Option<String>,
+    pub request: RecordedRequest,
+    pub response: RecordedResponse,
+}
+
+#[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct RecordedRequest {
+    pub method: String,
+    /// The path and query, with `{name}` in place of the value of each param.
+    pub path: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content_type: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub body: Option<Value>,
+}
+
+#[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct RecordedResponse {
+    pub status: u16,
+    /// Absent when the body is empty, or not JSON.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub body: Option<Value>,
+}
+
+impl Contract {
+    pub fn dir() -> PathBuf {
+        PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("contracts")
+    }
+
+    ///
+    /// Every contract in `contracts/`.
+    ///
+    pub fn load_all() -> Vec<Contract> {
+        let Ok(entries) = std::fs::read_dir(Contract::dir()) else {
+            return Vec::new();
+        };
+        let mut paths = entries
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| path.extension().is_some_and(|extension| extension == "json"))
+            .collect::<Vec<_>>();
+        paths.sort();
+        paths
+            .iter()
+            .map(|path| {
+                let json = std::fs::read_to_string(path).unwrap();
+                serde_json::from_str(&json).unwrap_or_else(|e| panic!("Invalid contract {}: {}", path.display(), e))
+            })
+            .collect()
+    }
+}
+
+///
+/// Records the requests sent through it, and the responses they got, into
+/// the contract of `consumer`.
+///
+pub struct ContractRecorder {
+    contract: Contract,
+    state: Option<String>,
+    params: Vec<(String, String)>,
+}
+
+impl ContractRecorder {
+    pub fn new(consumer: &str, provider: &str) -> Self {
+        ContractRecorder {
+            contract: Contract { consumer: consumer.to_string(), provider: provider.to_string(), interactions: Vec::new() },
+            state: None,
+            params: Vec::new(),
+        }
+    }
+
+    ///
+    /// The provider state of the interactions recorded from now on.
+    ///
+    pub fn given(&mut self, state: &str) -> &mut Self {
+        self.state = Some(state.to_string());
+        self
+    }
+
+    ///
+    /// Records path segments equal to `value` as `{name}`.
+    ///
+    pub fn param(&mut self, name: &str, value: impl fmt::Display) -> &mut Self {
+        self.params.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    ///
+    /// Sends `request` to `app`, and records it as `description`. The
+    /// response is returned as it was, to be asserted on as usual.
+    ///
+    pub async fn send(&mut self, app: &Router, description: &str, request: Request) -> Response {
+        let (parts, body) = request.into_parts();
+        let body = body.collect().await.unwrap().to_bytes();
+
+        let path = parts.uri.path_and_query().map(|path| path.as_str()).unwrap_or("/");
+        let path = path
+            .split('/')
+            .map(|segment| match self.params.iter().find(|(_, value)| value == segment) {
+                Some((name, _)) => format!("{{{}}}", name),
+                None => segment.to_string(),
+            })
+            .collect::<Vec<_>>()
+            .join("/");
+        let request = RecordedRequest {
+            method: parts.method.to_string(),
+            path,
+            content_type: parts.headers.get(header::CONTENT_TYPE).and_then(|value| value.to_str().ok()).map(str::to_string),
+            body: serde_json::from_slice(&body).ok(),
+        };
+
+        let response = app.clone().oneshot(Request::from_parts(parts, Body::from(body))).await.unwrap();
+        let (parts, body) = response.into_parts();
+        let body = body.collect().await.unwrap().to_bytes();
+
+        self.contract.interactions.push(Interaction {
+            description: description.to_string(),
+            provider_state: self.state.clone(),
+            request,
+            response: RecordedResponse { status: parts.status.as_u16(), body: serde_json::from_slice(&body).ok() },
+        });
+        Response::from_parts(parts, Body::from(body))
+    }
+
+    ///
+    /// Writes the contract to `contracts/<consumer>.json`, unless it already
+    /// exists and `UPDATE_CONTRACTS` is not set. Returns the contract.
+    ///
+    pub fn finish(self) -> Contract {
+        let path = Contract::dir().join(format!("{}.json", self.contract.consumer));
+        if !path.exists() || std::env::var_os("UPDATE_CONTRACTS").is_some() {
+            std::fs::create_dir_all(Contract::dir()).unwrap();
+            std::fs::write(&path, serde_json::to_string_pretty(&self.contract).unwrap() + "\n").unwrap();
+        }
+        self.contract
+    }
+}
+
+///
+/// An interaction whose replay did not give what the consumer relies on.
+///
+#[derive(Clone, Debug, PartialEq)]
+pub struct Mismatch {
+    pub consumer: String,
+    pub description: String,
+    pub message: String,
+}
+
+impl fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}: {}", self.consumer, self.description, self.message)
+    }
+}
+
+///
+/// Replays contracts against a router, with the headers every request needs
+/// (such as `Authorization`), and the params of the provider states that the
+/// test has set up.
+///
+pub struct ContractVerifier {
+    app: Router,
+    headers: Vec<(header::HeaderName, HeaderValue)>,
+    states: HashMap<String, Vec<(String, String)>>,
+}
+
+impl ContractVerifier {
+    pub fn new(app: Router) -> Self {
+        ContractVerifier { app, headers: Vec::new(), states: HashMap::new() }
+    }
+
+    pub fn header(mut self, name: header::HeaderName, value: &str) -> Self {
+        self.headers.push((name, HeaderValue::from_str(value).unwrap()));
+        self
+    }
+
+    ///
+    /// Declares that `state` holds, with these params for the paths of the
+    /// interactions that need it.
+    ///
+    pub fn state(mut self, state: &str, params: &[(&str, &dyn fmt::Display)]) -> Self {
+        let params = params.iter().map(|(name, value)| (name.to_string(), value.to_string())).collect();
+        self.states.insert(state.to_string(), params);
+        self
+    }
+
+    ///
+    /// Replays every interaction of `contract`, in order, and returns those
+    /// that did not match.
+    ///
+    pub async fn verify(&self, contract: &Contract) -> Vec<Mismatch> {
+        let mut mismatches = Vec::new();
+        for interaction in &contract.interactions {
+            let mismatch = |message: String| Mismatch {
+                consumer: contract.consumer.clone(),
+                description: interaction.description.clone(),
+                message,
+            };
+
+            let params = match &interaction.provider_state {
+                Some(state) => match self.states.get(state) {
+                    Some(params) => params.as_slice(),
+                    None => {
+                        mismatches.push(mismatch(format!("provider state {:?} is not set up", state)));
+                        continue;
+                    }
+                },
+                None => &[],
+            };
+
+            let (status, body) = self.replay(&interaction.request, params).await;
+            let expected = &interaction.response;
+            if status.as_u16() != expected.status {
+                mismatches.push(mismatch(format!("expected status {}, got {}", expected.status, status.as_u16())));
+                continue;
+            }
+            if let Some(expected) = &expected.body {
+                match serde_json::from_slice::<Value>(&body) {
+                    Ok(actual) => {
+                        let mut differences = Vec::new();
+                        shape_differences(expected, &actual, "", &mut differences);
+                        mismatches.extend(differences.into_iter().map(mismatch));
+                    }
+                    Err(_) => mismatches.push(mismatch("expected a JSON body".to_string())),
+                }
+            }
+        }
+        mismatches
+    }
+
+    async fn replay(&self, recorded: &RecordedRequest, params: &[(String, String)]) -> (StatusCode, Bytes) {
+        let path = params
+            .iter()
+            .fold(recorded.path.clone(), |path, (name, value)| path.replace(&format!("{{{}}}", name), value));
+
+        let mut request = Request::builder().method(Method::from_bytes(recorded.method.as_bytes()).unwrap()).uri(path);
+        for (name, value) in &self.headers {
+            request = request.header(name, value);
+        }
+        if let Some(content_type) = &recorded.content_type {
+            request = request.header(header::CONTENT_TYPE, content_type);
+        }
+        let body = recorded.body.as_ref().map(Value::to_string).unwrap_or_default();
+
+        let response = self.app.clone().oneshot(request.body(Body::from(body)).unwrap()).await.unwrap();
+        let status = response.status();
+        (status, response.into_body().collect().await.unwrap().to_bytes())
+    }
+}
+
+///
+/// Where `actual` does not have the shape of `expected`: a missing member,
+/// or a value of another JSON type. The elements of an array must each have
+/// the shape of the first recorded one.
+///
+fn shape_differences(expected: &Value, actual: &Value, path: &str, differences: &mut Vec<String>) {
+    let at = |path: &str| if path.is_empty() { "the body".to_string() } else { format!("`{}`", path) };
+
+    match (expected, actual) {
+        (Value::Object(expected), Value::Object(actual)) => {
+            for (key, expected) in expected {
+                let path = if path.is_empty() { key.clone() } else { format!("{}.{}", path, key) };
+                match actual.get(key) {
+                    Some(actual) => shape_differences(expected, actual, &path, differences),
+                    None => differences.push(format!("{} is missing", at(&path))),
+                }
+            }
+        }
+        (Value::Array(expected), Value::Array(actual)) => {
+            if let Some(expected) = expected.first() {
+                for (index, actual) in actual.iter().enumerate() {
+                    shape_differences(expected, actual, &format!("{}[{}]", path, index), differences);
+                }
+            }
+        }
+        // A consumer that has seen a null there must be ready for one.
+        (Value::Null, _) => {}
+        (expected, actual) if type_name(expected) != type_name(actual) => differences.push(format!(
+            "expected {} to be {}, got {}",
+            at(path),
+            type_name(expected),
+            type_name(actual)
+        )),
+        _ => {}
+    }
+}
+
+fn type_name(value: &Value) -> &'static str {
+    match value {
+        Value::Null => "null",
+        Value::Bool(_) => "a boolean",
+        Value::Number(_) => "a number",
+        Value::String(_) => "a string",
+        Value::Array(_) => "an array",
+        Value::Object(_) => "an object",
+    }
+}
+
+#[test]
+fn responses_are_compared_by_shape() {
+    use serde_json::json;
+
+    let differences = |expected: Value, actual: Value| {
+        let mut differences = Vec::new();
+        shape_differences(&expected, &actual, "", &mut differences);
+        differences
+    };
+
+    let todo = json!({ "id": 1, "title": "Buy milk", "due_at": null, "tags": [{ "name": "home" }] });
+
+    // Other values, and members the consumer never saw, are fine.
+    assert_eq!(
+        differences(todo.clone(), json!({ "id": 7, "title": "Call", "due_at": "2026-10-16T09:00:00Z", "tags": [], "priority": 2 })),
+        Vec::<String>::new()
+    );
+    assert_eq!(
+        differences(todo, json!({ "id": "7", "due_at": null, "tags": [{ "name": "home" }, { "label": "work" }] })),
+        vec![
+            "expected `id` to be a number, got a string".to_string(),
+            "`tags[1].name` is missing".to_string(),
+            "`title` is missing".to_string(),
+        ]
+    );
+    assert_eq!(differences(json!([]), json!({})), vec!["expected the body to be an array, got an object".to_string()]);
+}
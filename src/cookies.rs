@@ -0,0 +1,205 @@
+#![allow(dead_code)]
+
+//!
+//! COOKIES
+//! -------
+//!
+//! APIs mostly authenticate with headers, but browsers only send what they
+//! were told to keep, and that is cookies: the server sets one with a
+//! `Set-Cookie` response header, and the browser sends it back with every
+//! matching request in a `Cookie` header.
+//!
+//! axum-extra has three extractors for them, which are also responses: a
+//! handler takes a jar, adds or removes cookies, and returns it, and the jar
+//! turns its changes into `Set-Cookie` headers.
+//!
+//! - `CookieJar` holds plain cookies, which the client can read and change.
+//! - `SignedCookieJar` signs its cookies, so the client can read them, but
+//!   any change makes the server ignore them.
+//! - `PrivateCookieJar` encrypts them, so the client can neither read nor
+//!   change them.
+//!
+//! Signed and private jars need a `Key`, which they find in state through
+//! `FromRef`, just like `Claims` finds the `JwtKeys`. Every server that reads
+//! a cookie must have the same key as the one that set it.
+//!
+
+#[allow(unused_imports)]
+use axum::{
+    body::Body,
+    extract::{FromRef, State},
+    http::{header, HeaderMap, Request, StatusCode},
+    response::IntoResponse,
+    routing::{get, post},
+    Router,
+};
+#[allow(unused_imports)]
+use axum_extra::extract::cookie::{Cookie, CookieJar, PrivateCookieJar, SameSite, SignedCookieJar};
+use axum_extra::extract::cookie::Key;
+
+///
+/// The `Set-Cookie` headers of a response, for tests to look at and send back.
+///
+#[cfg(test)]
+pub fn set_cookies(headers: &HeaderMap) -> Vec<String> {
+    headers
+        .get_all(header::SET_COOKIE)
+        .iter()
+        .map(|value| value.to_str().unwrap().to_string())
+        .collect()
+}
+
+///
+/// The `name=value` part of a `Set-Cookie` header, as a client would send it
+/// back in a `Cookie` header.
+///
+#[cfg(test)]
+pub fn cookie_pair(set_cookie: &str) -> String {
+    set_cookie.split(';').next().unwrap().to_string()
+}
+
+///
+/// EXERCISE 1
+///
+/// A plain `CookieJar` needs no state. `/theme` remembers the theme the client
+/// chose, and every other request can read it back.
+///
+/// Add a `DELETE /theme` route that removes the cookie again, with
+/// `jar.remove(Cookie::from("theme"))`. Which `Set-Cookie` header does it
+/// send, and why does that delete the cookie in the browser?
+///
+#[tokio::test]
+async fn plain_cookies() {
+    // for Body::collect
+    use http_body_util::BodyExt;
+    /// for ServiceExt::oneshot
+    use tower::util::ServiceExt;
+
+    async fn set_theme(jar: CookieJar, theme: String) -> CookieJar {
+        jar.add(Cookie::build(("theme", theme)).path("/").same_site(SameSite::Lax))
+    }
+
+    async fn get_theme(jar: CookieJar) -> String {
+        jar.get("theme").map(|cookie| cookie.value().to_string()).unwrap_or("light".to_string())
+    }
+
+    let app = Router::new().route("/theme", post(set_theme).get(get_theme));
+
+    let response = app
+        .clone()
+        .oneshot(Request::post("/theme").body(Body::from("dark")).unwrap())
+        .await
+        .unwrap();
+    let cookies = set_cookies(response.headers());
+    assert_eq!(cookies, vec!["theme=dark; SameSite=Lax; Path=/"]);
+
+    let request = Request::get("/theme").header(header::COOKIE, cookie_pair(&cookies[0])).body(Body::empty()).unwrap();
+    let response = app.oneshot(request).await.unwrap();
+    let body = response.into_body().collect().await.unwrap().to_bytes();
+    assert_eq!(&body[..], b"dark");
+}
+
+#[derive(Clone, FromRef)]
+struct CookieState {
+    key: Key,
+}
+
+///
+/// EXERCISE 2
+///
+/// A `SignedCookieJar` counts visits. The count is readable by anyone who
+/// looks at the cookie, but a client that changes it loses it: the signature
+/// no longer matches, and `get` returns `None`.
+///
+/// Change the state to hold a second, different key, and use it to read the
+/// cookie. What happens to the count?
+///
+#[tokio::test]
+async fn signed_cookies() {
+    // for Body::collect
+    use http_body_util::BodyExt;
+    /// for ServiceExt::oneshot
+    use tower::util::ServiceExt;
+
+    async fn visit(jar: SignedCookieJar) -> (SignedCookieJar, String) {
+        let visits = jar.get("visits").and_then(|cookie| cookie.value().parse::<u32>().ok()).unwrap_or(0) + 1;
+        (jar.add(Cookie::new("visits", visits.to_string())), visits.to_string())
+    }
+
+    let app = Router::new().route("/", get(visit)).with_state(CookieState { key: Key::generate() });
+
+    let send = |cookie: Option<String>| {
+        let app = app.clone();
+        async move {
+            let mut request = Request::get("/");
+            if let Some(cookie) = cookie {
+                request = request.header(header::COOKIE, cookie);
+            }
+            let response = app.oneshot(request.body(Body::empty()).unwrap()).await.unwrap();
+            let cookie = cookie_pair(&set_cookies(response.headers())[0]);
+            let body = response.into_body().collect().await.unwrap().to_bytes();
+            (String::from_utf8(body.to_vec()).unwrap(), cookie)
+        }
+    };
+
+    let (visits, cookie) = send(None).await;
+    assert_eq!(visits, "1");
+    // The signature comes first, then the value, in the clear.
+    assert!(cookie.starts_with("visits=") && cookie.ends_with('1'));
+
+    let (visits, cookie) = send(Some(cookie)).await;
+    assert_eq!(visits, "2");
+
+    let tampered = format!("{}9", cookie.trim_end_matches('2'));
+    let (visits, _) = send(Some(tampered)).await;
+    assert_eq!(visits, "1");
+}
+
+///
+/// EXERCISE 3
+///
+/// A `PrivateCookieJar` keeps a secret on the client: the cookie holds the
+/// encrypted value, which only the server can decrypt. The auth module keeps
+/// the refresh token of users who ask to be remembered in such a cookie.
+///
+/// Private cookies are also `HttpOnly` here, so that scripts in the page
+/// cannot read them either. What would a script that could read it do with
+/// an encrypted refresh token?
+///
+#[tokio::test]
+async fn private_cookies() {
+    // for Body::collect
+    use http_body_util::BodyExt;
+    /// for ServiceExt::oneshot
+    use tower::util::ServiceExt;
+
+    async fn set_secret(jar: PrivateCookieJar, secret: String) -> PrivateCookieJar {
+        jar.add(Cookie::build(("secret", secret)).http_only(true).secure(true))
+    }
+
+    async fn get_secret(jar: PrivateCookieJar) -> Result<String, StatusCode> {
+        jar.get("secret").map(|cookie| cookie.value().to_string()).ok_or(StatusCode::NOT_FOUND)
+    }
+
+    let app = Router::new()
+        .route("/secret", post(set_secret).get(get_secret))
+        .with_state(CookieState { key: Key::generate() });
+
+    let response = app
+        .clone()
+        .oneshot(Request::post("/secret").body(Body::from("open sesame")).unwrap())
+        .await
+        .unwrap();
+    let set_cookie = set_cookies(response.headers()).remove(0);
+    assert!(!set_cookie.contains("open sesame"));
+    assert!(set_cookie.contains("HttpOnly") && set_cookie.contains("Secure"));
+
+    let request = Request::get("/secret").header(header::COOKIE, cookie_pair(&set_cookie)).body(Body::empty()).unwrap();
+    let response = app.clone().oneshot(request).await.unwrap();
+    let body = response.into_body().collect().await.unwrap().to_bytes();
+    assert_eq!(&body[..], b"open sesame");
+
+    let request = Request::get("/secret").header(header::COOKIE, "secret=open sesame").body(Body::empty()).unwrap();
+    let response = app.oneshot(request).await.unwrap();
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+}
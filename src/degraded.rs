@@ -0,0 +1,209 @@
+#![allow(dead_code)]
+
+//!
+//! DEGRADED MODE
+//! -------------
+//!
+//! When Postgres goes down, or every connection in the pool is busy, each
+//! query fails. A handler that unwrapped its result would panic: the client
+//! would get its connection dropped, and the load balancer would keep sending
+//! more requests.
+//!
+//! Instead, the app keeps track of whether the database answers, and while it
+//! does not:
+//!
+//! - writes are turned away up front with `503 Service Unavailable` and a
+//!   `Retry-After` header, before they touch anything;
+//! - reads are served from a cache where there is one (todo lists are), even
+//!   if it has expired, since stale data beats no data;
+//! - anything else that still fails answers `503` too: the calls of a
+//!   `TodoRepo` return a `RepoError`, which handlers answer with `503`, and a
+//!   handler that panics anyway is caught by `catch_panics`, rather than
+//!   dropping the connection;
+//! - the readiness probe (`GET /ready` on the admin router) fails, so that the
+//!   load balancer sends traffic to healthier instances, if there are any.
+//!
+//! Liveness (`GET /health`) is not affected: restarting an instance does not
+//! bring its database back.
+//!
+
+use std::{
+    any::Any,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+#[allow(unused_imports)]
+use axum::{
+    body::Body,
+    extract::{FromRef, Request, State},
+    http::{header, Method, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    routing::get,
+    Router,
+};
+use sqlx::{Pool, Postgres};
+use tower_http::catch_panic::CatchPanicLayer;
+
+use crate::app::Routes;
+use crate::problem::Problem;
+
+///
+/// How long clients are told to wait before retrying, in seconds.
+///
+pub const RETRY_AFTER_SECS: u64 = 5;
+
+///
+/// Whether the database answered the last check. Clones share the same state.
+///
+#[derive(Clone, Debug)]
+pub struct DbHealth {
+    up: Arc<AtomicBool>,
+}
+
+impl Default for DbHealth {
+    fn default() -> Self {
+        DbHealth { up: Arc::new(AtomicBool::new(true)) }
+    }
+}
+
+impl DbHealth {
+    pub fn is_up(&self) -> bool {
+        self.up.load(Ordering::Relaxed)
+    }
+
+    pub fn set_up(&self, up: bool) {
+        if self.up.swap(up, Ordering::Relaxed) != up {
+            println!("The database is {}", if up { "back up" } else { "down" });
+        }
+    }
+}
+
+///
+/// Checks every `period` that the database answers within `period`. A pool
+/// with no connection to spare counts as down, just like a database that is.
+///
+pub async fn watch_database(pool: Pool<Postgres>, health: DbHealth, period: Duration) {
+    let mut interval = tokio::time::interval(period);
+    loop {
+        interval.tick().await;
+        let answered = tokio::time::timeout(period, sqlx::query("SELECT 1").execute(&pool)).await;
+        health.set_up(matches!(answered, Ok(Ok(_))));
+    }
+}
+
+///
+/// The answer to requests that need the database while it is down.
+///
+pub fn database_unavailable() -> Response {
+    (
+        [(header::RETRY_AFTER, RETRY_AFTER_SECS.to_string())],
+        Problem::new(StatusCode::SERVICE_UNAVAILABLE).detail("The database is unavailable, please try again later"),
+    )
+        .into_response()
+}
+
+///
+/// Turns away requests that may write (any method but `GET`, `HEAD`,
+/// `OPTIONS` and `TRACE`) while the database is down.
+///
+/// ```ignore
+/// router.layer(axum::middleware::from_fn_with_state(health, reject_writes_when_down))
+/// ```
+///
+pub async fn reject_writes_when_down(State(health): State<DbHealth>, request: Request, next: Next) -> Response {
+    if !health.is_up() && !request.method().is_safe() {
+        return database_unavailable();
+    }
+    next.run(request).await
+}
+
+///
+/// Answers requests whose handler panicked with `503` while the database is
+/// down, since that is the likely cause, and with `500` otherwise. Only a
+/// backstop: handlers answer the errors of the database themselves.
+///
+pub fn catch_panics(health: DbHealth) -> CatchPanicLayer<impl Fn(Box<dyn Any + Send>) -> Response + Clone> {
+    CatchPanicLayer::custom(move |_: Box<dyn Any + Send>| {
+        if health.is_up() {
+            Problem::new(StatusCode::INTERNAL_SERVER_ERROR).into_response()
+        } else {
+            database_unavailable()
+        }
+    })
+}
+
+pub fn readiness_routes<S>() -> Routes<S>
+where
+    DbHealth: FromRef<S>,
+    S: Clone + Send + Sync + 'static,
+{
+    Routes::new().get("/ready", ready)
+}
+
+async fn ready(State(health): State<DbHealth>) -> Result<&'static str, StatusCode> {
+    if health.is_up() {
+        Ok("OK")
+    } else {
+        Err(StatusCode::SERVICE_UNAVAILABLE)
+    }
+}
+
+#[tokio::test]
+async fn writes_are_rejected_while_the_database_is_down() {
+    /// for ServiceExt::oneshot
+    use tower::util::ServiceExt;
+
+    async fn panics() -> &'static str {
+        panic!("called `Result::unwrap()` on an `Err` value: PoolTimedOut")
+    }
+
+    let health = DbHealth::default();
+    let app = Router::new()
+        .route("/", get(|| async { "Read" }).post(|| async { "Written" }))
+        .route("/panic", get(panics))
+        .layer(axum::middleware::from_fn_with_state(health.clone(), reject_writes_when_down))
+        .layer(catch_panics(health.clone()))
+        .merge(readiness_routes().into_router().with_state(health.clone()));
+
+    let send = |method: Method, uri: &'static str| {
+        let app = app.clone();
+        async move { app.oneshot(Request::builder().method(method).uri(uri).body(Body::empty()).unwrap()).await.unwrap() }
+    };
+
+    assert_eq!(send(Method::POST, "/").await.status(), StatusCode::OK);
+    assert_eq!(send(Method::GET, "/panic").await.status(), StatusCode::INTERNAL_SERVER_ERROR);
+    assert_eq!(send(Method::GET, "/ready").await.status(), StatusCode::OK);
+
+    health.set_up(false);
+
+    let response = send(Method::POST, "/").await;
+    assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(response.headers()[header::RETRY_AFTER], "5");
+    assert_eq!(send(Method::GET, "/").await.status(), StatusCode::OK);
+    assert_eq!(send(Method::GET, "/panic").await.status(), StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(send(Method::GET, "/ready").await.status(), StatusCode::SERVICE_UNAVAILABLE);
+}
+
+#[tokio::test]
+async fn the_watcher_notices_an_unreachable_database() {
+    use sqlx::postgres::PgPoolOptions;
+
+    let health = DbHealth::default();
+    // Nothing listens on port 1.
+    let pool = PgPoolOptions::new().connect_lazy("postgres://postgres@127.0.0.1:1/postgres").unwrap();
+    let watcher = tokio::spawn(watch_database(pool, health.clone(), Duration::from_millis(100)));
+    tokio::time::sleep(Duration::from_millis(500)).await;
+    assert!(!health.is_up());
+    watcher.abort();
+
+    let pool = PgPoolOptions::new().connect_lazy(&crate::test_db::database_url().await).unwrap();
+    let watcher = tokio::spawn(watch_database(pool, health.clone(), Duration::from_millis(100)));
+    tokio::time::sleep(Duration::from_millis(500)).await;
+    assert!(health.is_up());
+    watcher.abort();
+}
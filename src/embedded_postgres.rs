@@ -0,0 +1,75 @@
+#![allow(dead_code)]
+
+//!
+//! EMBEDDED POSTGRES
+//! -----------------
+//!
+//! The hardest part of the persistence module, for someone new to it, is
+//! not Rust but getting a Postgres server running, with a user and password
+//! that match `DATABASE_URL`. With the `embedded-postgres` feature, the app
+//! skips that: when `DATABASE_URL` is not set, it starts a Postgres of its
+//! own, in a temporary directory, on a free port, and stops and deletes it
+//! when the app shuts down.
+//!
+//! ```sh
+//! cargo run --bin rust-web --features embedded-postgres -- serve
+//! ```
+//!
+//! The first run downloads Postgres (see `postgresql_embedded`) into
+//! `~/.theseus`, and later runs use that copy. The migrations run against it
+//! when the app starts, as they do against any other database. Nothing in it
+//! outlives the app, which suits a workshop, and nothing else.
+//!
+
+use postgresql_embedded::{PostgreSQL, Settings};
+
+const DATABASE: &str = "todos";
+
+pub struct EmbeddedPostgres {
+    postgresql: PostgreSQL,
+    url: String,
+}
+
+impl EmbeddedPostgres {
+    ///
+    /// Starts an empty server, with a `todos` database.
+    ///
+    pub async fn start() -> Result<Self, postgresql_embedded::Error> {
+        let mut postgresql = PostgreSQL::new(Settings { temporary: true, ..Settings::default() });
+        postgresql.setup().await?;
+        postgresql.start().await?;
+        postgresql.create_database(DATABASE).await?;
+        let url = postgresql.settings().url(DATABASE);
+        Ok(EmbeddedPostgres { postgresql, url })
+    }
+
+    ///
+    /// Starts a server unless `DATABASE_URL` is set, and sets it to the URL
+    /// of the server. This has to happen before anything else reads the
+    /// environment, the configuration in particular.
+    ///
+    pub async fn start_unless_configured() -> Result<Option<Self>, postgresql_embedded::Error> {
+        if std::env::var_os("DATABASE_URL").is_some() {
+            return Ok(None);
+        }
+        let embedded = EmbeddedPostgres::start().await?;
+        std::env::set_var("DATABASE_URL", &embedded.url);
+        Ok(Some(embedded))
+    }
+
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    pub fn port(&self) -> u16 {
+        self.postgresql.settings().port
+    }
+
+    ///
+    /// Stops the server, and deletes its data. Dropping it does the same,
+    /// but blocks the thread until the server has stopped.
+    ///
+    pub async fn stop(self) -> Result<(), postgresql_embedded::Error> {
+        self.postgresql.stop().await
+    }
+}
@@ -0,0 +1,421 @@
+#![allow(dead_code)]
+
+//!
+//! ACCOUNT DELETION
+//! ----------------
+//!
+//! `DELETE /users/:id` erases an account and everything that belongs to it,
+//! for users who leave, or ask for their data to be erased. Users can delete
+//! their own account, and admins anyone's.
+//!
+//! Most of the work is done by Postgres: every table that points at a user
+//! does so with `ON DELETE CASCADE`, or `SET NULL` for the todos assigned to
+//! them, and so does every table that points at a todo. Deleting the row in
+//! `users` deletes their todos, those in the lists they own, the subtasks of
+//! both (even those of other users), and the comments, attachments and
+//! recurrences of all of them, along with their lists, memberships, sessions,
+//! remember-me tokens, passkeys, second factor and personal access tokens.
+//! `refresh_tokens`, which is older than `users`, has no foreign key, and is
+//! cleared by hand. All of that happens in one transaction, so an account is
+//! either gone, or untouched.
+//!
+//! When the todos are kept elsewhere (see `TODO_REPO`), deleting the user
+//! cannot reach them. Their repo erases them first, with `erase_user`, and
+//! its counts are added to the report. If that fails, the account is left
+//! untouched; if Postgres fails after it, the account is left without its
+//! todos, and deleting it again finishes the job.
+//!
+//! The files of the attachments are not in Postgres. They are deleted from
+//! the object store once the transaction has committed: a failure leaves an
+//! orphaned file behind, which is logged, rather than a row whose file is
+//! gone.
+//!
+//! The audit log only ever records user ids, never names or emails, so once
+//! the row in `users` is gone, its entries no longer point to anyone. The
+//! erasure itself is audited with the same id, and the counts of what it
+//! deleted.
+//!
+//! With `?dry_run=true`, the response reports what would be deleted, in the
+//! same shape, and nothing is.
+//!
+
+use axum::{
+    async_trait,
+    extract::{FromRef, Path, Query, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use sqlx::{Pool, Postgres};
+
+use crate::app::Routes;
+use crate::attachments::SharedObjectStore;
+use crate::auth::{Claims, JwtKeys};
+use crate::persistence::{SharedTodoRepo, TodoErasure};
+use crate::users::{UserRepo, UserState};
+
+///
+/// What erasing an account deletes, or would delete. `todos` includes the
+/// todos of other users that are in the account's lists, or subtasks of its
+/// todos; `unassigned_todos` are those that stay, without their assignee.
+///
+#[derive(Clone, Debug, Default, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+pub struct ErasureReport {
+    pub dry_run: bool,
+    pub todos: i64,
+    pub comments: i64,
+    pub attachments: i64,
+    pub unassigned_todos: i64,
+    pub uuid_todos: i64,
+    pub lists: i64,
+    pub list_memberships: i64,
+    pub sessions: i64,
+    pub remember_tokens: i64,
+    pub passkeys: i64,
+    pub personal_access_tokens: i64,
+    pub two_factor: bool,
+}
+
+pub struct Erasure {
+    pub report: ErasureReport,
+    /// The keys of the attachments' objects, to delete from the store.
+    pub object_keys: Vec<String>,
+}
+
+#[async_trait]
+pub trait ErasureRepo: Send + Sync {
+    ///
+    /// Deletes the user and everything that belongs to them, unless
+    /// `dry_run`, and reports what that deleted. `None` if there is no such
+    /// user.
+    ///
+    async fn erase_user(&self, user_id: i64, dry_run: bool) -> Option<Erasure>;
+}
+
+#[derive(Clone)]
+pub struct ErasureRepoPostgres {
+    pool: Pool<Postgres>,
+}
+
+impl ErasureRepoPostgres {
+    pub fn new(pool: Pool<Postgres>) -> Self {
+        ErasureRepoPostgres { pool }
+    }
+}
+
+#[async_trait]
+impl ErasureRepo for ErasureRepoPostgres {
+    async fn erase_user(&self, user_id: i64, dry_run: bool) -> Option<Erasure> {
+        let mut tx = self.pool.begin().await.unwrap();
+        // Locked, so that nothing can be added to the account between the
+        // counts and the deletion.
+        sqlx::query!("SELECT id FROM users WHERE id = $1 FOR UPDATE", user_id)
+            .fetch_optional(&mut *tx)
+            .await
+            .unwrap()?;
+
+        let counts = sqlx::query!(
+            r#"
+            WITH RECURSIVE erased_todos AS (
+                SELECT id FROM todos
+                WHERE owner_id = $1 OR list_id IN (SELECT id FROM lists WHERE owner_id = $1)
+                UNION
+                SELECT todos.id FROM todos JOIN erased_todos ON todos.parent_id = erased_todos.id
+            )
+            SELECT
+                (SELECT COUNT(*) FROM erased_todos) AS "todos!",
+                (SELECT COUNT(*) FROM comments WHERE todo_id IN (SELECT id FROM erased_todos)) AS "comments!",
+                ARRAY(
+                    SELECT object_key FROM attachments WHERE todo_id IN (SELECT id FROM erased_todos)
+                ) AS "object_keys!",
+                (
+                    SELECT COUNT(*) FROM todos
+                    WHERE assignee_id = $1 AND id NOT IN (SELECT id FROM erased_todos)
+                ) AS "unassigned_todos!",
+                (SELECT COUNT(*) FROM uuid_todos WHERE owner_id = $1) AS "uuid_todos!",
+                (SELECT COUNT(*) FROM lists WHERE owner_id = $1) AS "lists!",
+                (
+                    SELECT COUNT(*) FROM list_members
+                    WHERE user_id = $1 AND list_id NOT IN (SELECT id FROM lists WHERE owner_id = $1)
+                ) AS "list_memberships!",
+                (SELECT COUNT(DISTINCT family_id) FROM refresh_tokens WHERE user_id = $1) AS "sessions!",
+                (SELECT COUNT(*) FROM remember_tokens WHERE user_id = $1) AS "remember_tokens!",
+                (SELECT COUNT(*) FROM webauthn_credentials WHERE user_id = $1) AS "passkeys!",
+                (SELECT COUNT(*) FROM personal_access_tokens WHERE user_id = $1) AS "personal_access_tokens!",
+                EXISTS (SELECT 1 FROM totp_secrets WHERE user_id = $1) AS "two_factor!"
+            "#,
+            user_id
+        )
+        .fetch_one(&mut *tx)
+        .await
+        .unwrap();
+
+        let report = ErasureReport {
+            dry_run,
+            todos: counts.todos,
+            comments: counts.comments,
+            attachments: counts.object_keys.len() as i64,
+            unassigned_todos: counts.unassigned_todos,
+            uuid_todos: counts.uuid_todos,
+            lists: counts.lists,
+            list_memberships: counts.list_memberships,
+            sessions: counts.sessions,
+            remember_tokens: counts.remember_tokens,
+            passkeys: counts.passkeys,
+            personal_access_tokens: counts.personal_access_tokens,
+            two_factor: counts.two_factor,
+        };
+        if dry_run {
+            return Some(Erasure { report, object_keys: Vec::new() });
+        }
+
+        sqlx::query!("DELETE FROM refresh_tokens WHERE user_id = $1", user_id)
+            .execute(&mut *tx)
+            .await
+            .unwrap();
+        sqlx::query!("DELETE FROM users WHERE id = $1", user_id)
+            .execute(&mut *tx)
+            .await
+            .unwrap();
+        tx.commit().await.unwrap();
+
+        Some(Erasure { report, object_keys: counts.object_keys })
+    }
+}
+
+#[derive(Clone)]
+pub struct ErasureState<E: ErasureRepo> {
+    pub repo: E,
+    pub store: SharedObjectStore,
+    /// The repo of the todos, if they are kept elsewhere than in Postgres.
+    pub todos: Option<SharedTodoRepo>,
+}
+
+pub fn erasure_routes<S, U, E>() -> Routes<S>
+where
+    U: UserRepo + Clone + 'static,
+    E: ErasureRepo + Clone + 'static,
+    UserState<U>: FromRef<S>,
+    ErasureState<E>: FromRef<S>,
+    JwtKeys: FromRef<S>,
+    S: Clone + Send + Sync + 'static,
+{
+    Routes::new().delete("/users/:id", delete_user::<U, E>)
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct DeleteQuery {
+    #[serde(default)]
+    dry_run: bool,
+}
+
+async fn delete_user<U: UserRepo, E: ErasureRepo>(
+    Claims { sub: caller, .. }: Claims,
+    Path(user_id): Path<i64>,
+    Query(DeleteQuery { dry_run }): Query<DeleteQuery>,
+    State(UserState { repo: users }): State<UserState<U>>,
+    State(ErasureState { repo, store, todos }): State<ErasureState<E>>,
+) -> Result<Json<ErasureReport>, Response> {
+    if caller != user_id && !users.get_user(caller).await.is_some_and(|user| user.is_admin) {
+        return Err(StatusCode::FORBIDDEN.into_response());
+    }
+    let erased_todos = match todos {
+        Some(todos) => {
+            users.get_user(user_id).await.ok_or(StatusCode::NOT_FOUND.into_response())?;
+            todos.erase_user(user_id, dry_run).await?
+        }
+        None => TodoErasure::default(),
+    };
+    let Erasure { mut report, object_keys } =
+        repo.erase_user(user_id, dry_run).await.ok_or(StatusCode::NOT_FOUND.into_response())?;
+    report.todos += erased_todos.todos;
+    report.comments += erased_todos.comments;
+    report.unassigned_todos += erased_todos.unassigned_todos;
+    if dry_run {
+        return Ok(Json(report));
+    }
+
+    tracing::warn!(target: "audit", user_id, erased_by = caller, ?report, "Account erased");
+    for key in object_keys {
+        if let Err(error) = store.delete(&key).await {
+            tracing::error!(user_id, key, %error, "Failed to delete the object of an erased attachment");
+        }
+    }
+    Ok(Json(report))
+}
+
+#[tokio::test]
+async fn accounts_are_erased_with_everything_they_own() {
+    // for Body::collect
+    use http_body_util::BodyExt;
+    /// for ServiceExt::oneshot
+    use tower::util::ServiceExt;
+    use axum::{body::Body, http::Request};
+    use sqlx::postgres::PgPoolOptions;
+    use std::sync::Arc;
+
+    use crate::attachments::{ObjectStore, ObjectStoreInMemory};
+    use crate::auth::{AuthState, RefreshTokenRepoPostgres};
+    use crate::users::{create_test_user, UserRepoPostgres};
+
+    #[derive(Clone, FromRef)]
+    struct TestState {
+        users: UserState<UserRepoPostgres>,
+        erasure: ErasureState<ErasureRepoPostgres>,
+        keys: JwtKeys,
+    }
+
+    let pool = PgPoolOptions::new()
+        .max_connections(1)
+        .connect(&crate::test_db::database_url().await)
+        .await
+        .unwrap();
+    let store = ObjectStoreInMemory::default();
+    let keys = JwtKeys::from_secret(b"secret");
+    let auth = AuthState { repo: RefreshTokenRepoPostgres::new(pool.clone()), keys: keys.clone() };
+    let app = erasure_routes::<_, UserRepoPostgres, ErasureRepoPostgres>().into_router().with_state(TestState {
+        users: UserState { repo: UserRepoPostgres::new(pool.clone(), crate::pii::test_cipher()) },
+        erasure: ErasureState {
+            repo: ErasureRepoPostgres::new(pool.clone()),
+            store: Arc::new(store.clone()),
+            todos: None,
+        },
+        keys,
+    });
+
+    let user = create_test_user(&pool, false).await;
+    let other = create_test_user(&pool, false).await;
+    let admin = create_test_user(&pool, true).await;
+    let todo = sqlx::query_scalar!("INSERT INTO todos (title, description, owner_id) VALUES ('Taxes', '', $1) RETURNING id", user)
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+    sqlx::query!("INSERT INTO comments (todo_id, body) VALUES ($1, 'Before May')", todo).execute(&pool).await.unwrap();
+    let object_key = format!("erasure-test/{}", todo);
+    sqlx::query!(
+        "INSERT INTO attachments (todo_id, filename, content_type, size, sha256, object_key)
+         VALUES ($1, 'receipt.pdf', 'application/pdf', 3, '', $2)",
+        todo,
+        object_key
+    )
+    .execute(&pool)
+    .await
+    .unwrap();
+    store.put(&object_key, "pdf".into()).await.unwrap();
+    sqlx::query!(
+        "INSERT INTO todos (title, description, owner_id, assignee_id) VALUES ('Review', '', $1, $2)",
+        other,
+        user
+    )
+    .execute(&pool)
+    .await
+    .unwrap();
+    let session = auth.issue_tokens(user).await.access_token;
+
+    let delete = |uri: String, token: String| {
+        let app = app.clone();
+        async move {
+            let request =
+                Request::delete(uri).header("Authorization", format!("Bearer {}", token)).body(Body::empty()).unwrap();
+            let response = app.oneshot(request).await.unwrap();
+            let status = response.status();
+            let body = response.into_body().collect().await.unwrap().to_bytes();
+            (status, serde_json::from_slice::<ErasureReport>(&body).ok())
+        }
+    };
+
+    let other_session = auth.issue_tokens(other).await.access_token;
+    assert_eq!(delete(format!("/users/{}", user), other_session).await.0, StatusCode::FORBIDDEN);
+
+    let expected = ErasureReport {
+        dry_run: true,
+        todos: 1,
+        comments: 1,
+        attachments: 1,
+        unassigned_todos: 1,
+        sessions: 1,
+        ..ErasureReport::default()
+    };
+    let (status, report) = delete(format!("/users/{}?dry_run=true", user), session.clone()).await;
+    assert_eq!((status, report), (StatusCode::OK, Some(expected.clone())));
+    assert!(store.get(&object_key).await.unwrap().is_some());
+
+    let admin_session = auth.issue_tokens(admin).await.access_token;
+    let (status, report) = delete(format!("/users/{}", user), admin_session.clone()).await;
+    assert_eq!((status, report), (StatusCode::OK, Some(ErasureReport { dry_run: false, ..expected })));
+    assert!(store.get(&object_key).await.unwrap().is_none());
+    let todos = sqlx::query_scalar!("SELECT COUNT(*) FROM todos WHERE owner_id = $1 OR assignee_id = $1", user)
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+    assert_eq!(todos, Some(0));
+    assert!(auth.sessions(user).await.is_empty());
+
+    assert_eq!(delete(format!("/users/{}", user), admin_session).await.0, StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn todos_kept_elsewhere_are_erased_with_the_account() {
+    // for Body::collect
+    use http_body_util::BodyExt;
+    /// for ServiceExt::oneshot
+    use tower::util::ServiceExt;
+    use axum::{body::Body, http::Request};
+    use sqlx::postgres::PgPoolOptions;
+    use std::sync::Arc;
+
+    use crate::attachments::ObjectStoreInMemory;
+    use crate::auth::{AuthState, RefreshTokenRepoInMemory};
+    use crate::persistence::{TodoRepo, TodoRepoInMemory, TodoSort};
+    use crate::users::{create_test_user, UserRepoPostgres};
+
+    #[derive(Clone, FromRef)]
+    struct TestState {
+        users: UserState<UserRepoPostgres>,
+        erasure: ErasureState<ErasureRepoPostgres>,
+        keys: JwtKeys,
+    }
+
+    let pool = PgPoolOptions::new()
+        .max_connections(1)
+        .connect(&crate::test_db::database_url().await)
+        .await
+        .unwrap();
+    let todos = TodoRepoInMemory::default();
+    let keys = JwtKeys::from_secret(b"secret");
+    let auth = AuthState { repo: RefreshTokenRepoInMemory::default(), keys: keys.clone() };
+    let app = erasure_routes::<_, UserRepoPostgres, ErasureRepoPostgres>().into_router().with_state(TestState {
+        users: UserState { repo: UserRepoPostgres::new(pool.clone(), crate::pii::test_cipher()) },
+        erasure: ErasureState {
+            repo: ErasureRepoPostgres::new(pool.clone()),
+            store: Arc::new(ObjectStoreInMemory::default()),
+            todos: Some(Arc::new(todos.clone())),
+        },
+        keys,
+    });
+
+    let user = create_test_user(&pool, false).await;
+    let todo = todos.create_todo(user, "Taxes", "", None, 0).await.unwrap();
+    todos.create_comment(user, todo, "Before May").await.unwrap();
+    let session = auth.issue_tokens(user).await.access_token;
+    let delete = |uri: String| {
+        let request =
+            Request::delete(uri).header("Authorization", format!("Bearer {}", session)).body(Body::empty()).unwrap();
+        let response = app.clone().oneshot(request);
+        async move {
+            let response = response.await.unwrap();
+            let status = response.status();
+            let body = response.into_body().collect().await.unwrap().to_bytes();
+            (status, serde_json::from_slice::<ErasureReport>(&body).ok())
+        }
+    };
+
+    let expected = ErasureReport { dry_run: true, todos: 1, comments: 1, ..ErasureReport::default() };
+    let (status, report) = delete(format!("/users/{}?dry_run=true", user)).await;
+    assert_eq!((status, report), (StatusCode::OK, Some(expected.clone())));
+    assert_eq!(todos.get_todos(user, TodoSort::Id).await.unwrap().len(), 1);
+
+    let (status, report) = delete(format!("/users/{}", user)).await;
+    assert_eq!((status, report), (StatusCode::OK, Some(ErasureReport { dry_run: false, ..expected })));
+    assert!(todos.get_todos(user, TodoSort::Id).await.unwrap().is_empty());
+}
@@ -0,0 +1,187 @@
+#![allow(dead_code)]
+
+//!
+//! METRICS EXPOSITION
+//! ------------------
+//!
+//! Prometheus scrapes `/metrics` every 15 seconds or so, which costs nothing.
+//! But a fleet of scrapers (one Prometheus per region, federation, a
+//! misconfigured agent scraping every 100ms, or a dashboard hitting the admin
+//! port directly) can turn that into hundreds of scrapes per second. Then the
+//! admin endpoints start competing with the app for CPU.
+//!
+//! `PrometheusHandle::render` walks every series and formats it into a fresh
+//! `String`, for every scrape. With a few thousand series (routes × methods ×
+//! status codes × histogram buckets), that is the whole cost of a scrape.
+//!
+//! `RenderedMetrics` renders at most once per `max_age` instead, straight
+//! into a `Bytes`, and every scrape in between is served the same buffer: a
+//! reference count increment, not a copy. While one scrape renders, the
+//! others are served the previous rendering rather than wait for the new
+//! one, so a storm of scrapes never renders more than once at a time either.
+//!
+//! `/health` and `/ready` were already cheap: a `&'static str` body is never
+//! allocated, nor serialized. The endpoints to keep that way are the ones
+//! that load balancers and scrapers call in a loop, so they go without JSON.
+//!
+//! `cargo bench --bench scrapes` compares the two under a storm of
+//! concurrent scrapes.
+//!
+
+use std::{
+    sync::{Arc, Mutex, RwLock},
+    time::{Duration, Instant},
+};
+
+use axum::{
+    body::Bytes,
+    http::header,
+    response::{IntoResponse, Response},
+};
+use axum_prometheus::metrics_exporter_prometheus::PrometheusHandle;
+
+///
+/// The content type of version 0.0.4 of the Prometheus text format.
+///
+pub const TEXT_FORMAT: &str = "text/plain; version=0.0.4; charset=utf-8";
+
+///
+/// The Prometheus exposition of the metrics, rendered at most once per
+/// `max_age`. Clones share the same rendering.
+///
+#[derive(Clone)]
+pub struct RenderedMetrics {
+    handle: PrometheusHandle,
+    max_age: Duration,
+    rendered: Arc<RwLock<Rendered>>,
+    rendering: Arc<Mutex<()>>,
+}
+
+struct Rendered {
+    at: Instant,
+    body: Bytes,
+}
+
+impl RenderedMetrics {
+    ///
+    /// A second is short next to any scrape interval, and long next to a
+    /// storm of scrapes.
+    ///
+    pub const MAX_AGE: Duration = Duration::from_secs(1);
+
+    pub fn new(handle: PrometheusHandle) -> Self {
+        let rendered = Rendered { at: Instant::now(), body: Bytes::from(handle.render()) };
+        RenderedMetrics {
+            handle,
+            max_age: Self::MAX_AGE,
+            rendered: Arc::new(RwLock::new(rendered)),
+            rendering: Arc::default(),
+        }
+    }
+
+    pub fn with_max_age(self, max_age: Duration) -> Self {
+        RenderedMetrics { max_age, ..self }
+    }
+
+    ///
+    /// The latest rendering, rendered again first if it is older than
+    /// `max_age`, and nobody else is already at it.
+    ///
+    pub fn get(&self) -> Bytes {
+        if let Some(body) = self.fresh() {
+            return body;
+        }
+        let Ok(_rendering) = self.rendering.try_lock() else {
+            return self.rendered.read().unwrap().body.clone();
+        };
+        // Someone else may have rendered between the check and the lock.
+        if let Some(body) = self.fresh() {
+            return body;
+        }
+
+        let body = Bytes::from(self.handle.render());
+        *self.rendered.write().unwrap() = Rendered { at: Instant::now(), body: body.clone() };
+        body
+    }
+
+    fn fresh(&self) -> Option<Bytes> {
+        let rendered = self.rendered.read().unwrap();
+        (rendered.at.elapsed() < self.max_age).then(|| rendered.body.clone())
+    }
+}
+
+impl IntoResponse for RenderedMetrics {
+    fn into_response(self) -> Response {
+        ([(header::CONTENT_TYPE, TEXT_FORMAT)], self.get()).into_response()
+    }
+}
+
+///
+/// EXERCISE 1
+///
+/// A registry of 2,000 series, and a storm of 200 scrapes at once, first
+/// rendered one by one, then through `RenderedMetrics`. Both serve the same
+/// series, but only one of them renders it 200 times.
+///
+/// Try lowering the number of series to 10: does the difference still
+/// matter? Then look at the `Bytes` that `get` returns: why is cloning it
+/// cheap, when cloning a `String` of the same length is not? What does a
+/// scraper lose by being served a rendering up to a second old?
+///
+#[tokio::test]
+async fn scrape_storms() {
+    use axum_prometheus::metrics_exporter_prometheus::PrometheusBuilder;
+    use metrics::{Key, Label, Recorder};
+
+    let recorder = PrometheusBuilder::new().build_recorder();
+    for series in 0..2_000 {
+        let key = Key::from_parts("storm_gauge", vec![Label::new("series", series.to_string())]);
+        recorder.register_gauge(&key).set(series as f64);
+    }
+    let handle = recorder.handle();
+
+    let scrape = |render: Arc<dyn Fn() -> Bytes + Send + Sync>| async move {
+        let start = Instant::now();
+        let scrapes = (0..200).map(|_| {
+            let render = render.clone();
+            tokio::spawn(async move { render() })
+        });
+        let bodies = futures::future::join_all(scrapes).await;
+        (start.elapsed(), bodies.into_iter().next().unwrap().unwrap())
+    };
+
+    let naive = {
+        let handle = handle.clone();
+        Arc::new(move || Bytes::from(handle.render()))
+    };
+    let rendered = {
+        let metrics = RenderedMetrics::new(handle.clone());
+        Arc::new(move || metrics.get())
+    };
+
+    let (naive_time, naive_body) = scrape(naive).await;
+    let (rendered_time, rendered_body) = scrape(rendered).await;
+
+    // The same series, though not necessarily in the same order.
+    assert_eq!(naive_body.len(), rendered_body.len());
+    assert!(rendered_time * 5 < naive_time, "{:?} vs {:?}", rendered_time, naive_time);
+}
+
+#[test]
+fn metrics_are_rendered_again_once_they_are_stale() {
+    use axum_prometheus::metrics_exporter_prometheus::PrometheusBuilder;
+    use metrics::{Key, Recorder};
+
+    let recorder = PrometheusBuilder::new().build_recorder();
+    let gauge = recorder.register_gauge(&Key::from_name("stale_gauge"));
+    gauge.set(1.0);
+    let metrics = RenderedMetrics::new(recorder.handle()).with_max_age(Duration::from_millis(50));
+
+    let first = metrics.get();
+    gauge.set(2.0);
+    assert_eq!(metrics.get(), first);
+
+    std::thread::sleep(Duration::from_millis(60));
+    let second = metrics.get();
+    assert!(String::from_utf8_lossy(&second).contains("stale_gauge 2"), "{:?}", second);
+}
@@ -0,0 +1,387 @@
+#![allow(dead_code)]
+
+//!
+//! EXTRACTORS
+//! ----------
+//!
+//! Axum's own extractors reject bad requests with a status code and a line of
+//! text meant for a developer reading logs. A client that sends a todo with
+//! `"priority": "high"` gets a 422 and has to guess which field was wrong.
+//!
+//! The extractors here turn their rejections into JSON that a client can act
+//! on:
+//!
+//! ```json
+//! { "message": "invalid type: string \"high\", expected i32", "path": "priority", "expected": "i32" }
+//! ```
+//!
+//! `path` is where in the document the error is, such as `operations[2].id`,
+//! and `expected` is what serde wanted to find there, when it says.
+//!
+//! - `AppJson<T>` wraps Axum's `Json<T>`.
+//! - `QsQuery<T>` replaces Axum's `Query<T>`, which is built on
+//!   `serde_urlencoded` and only knows flat `key=value` pairs. `QsQuery` is
+//!   built on `serde_qs` instead, which also reads arrays, `?tag[]=a&tag[]=b`,
+//!   and nested structs, `?due[before]=...`.
+//!
+//! `AppJson<T>` is also a response, serialized by `to_json`. That is where the
+//! time goes when listing thousands of todos, so with the `simd-json` feature
+//! it serializes with simd-json instead of serde_json:
+//!
+//! ```sh
+//! cargo run --release --bin rust-web --features simd-json -- serve
+//! cargo bench --bench json --features simd-json
+//! ```
+//!
+//! Request bodies are still parsed by serde_json, since the paths that the
+//! errors above report come from `serde_path_to_error`, which wraps it.
+//!
+
+use std::{error::Error, fmt};
+
+use axum::{
+    async_trait,
+    body::Bytes,
+    extract::{rejection::JsonRejection, FromRequest, FromRequestParts, Request},
+    http::{header, request::Parts, StatusCode},
+    response::{IntoResponse, Response},
+    BoxError, Json,
+};
+use serde::{de::DeserializeOwned, Serialize};
+
+///
+/// Like `Json<T>`, but rejects bodies that do not match `T` with an
+/// `ExtractError`.
+///
+#[derive(Clone, Copy, Debug, Default)]
+pub struct AppJson<T>(pub T);
+
+#[async_trait]
+impl<T, S> FromRequest<S> for AppJson<T>
+where
+    Json<T>: FromRequest<S, Rejection = JsonRejection>,
+    S: Send + Sync,
+{
+    type Rejection = ExtractError;
+
+    async fn from_request(req: Request, state: &S) -> Result<Self, Self::Rejection> {
+        match Json::<T>::from_request(req, state).await {
+            Ok(Json(value)) => Ok(AppJson(value)),
+            Err(rejection) => Err(ExtractError::from(rejection)),
+        }
+    }
+}
+
+impl<T: Serialize> IntoResponse for AppJson<T> {
+    fn into_response(self) -> Response {
+        match to_json(&self.0) {
+            Ok(json) => ([(header::CONTENT_TYPE, "application/json")], json).into_response(),
+            Err(error) => (StatusCode::INTERNAL_SERVER_ERROR, error.to_string()).into_response(),
+        }
+    }
+}
+
+///
+/// Serializes `value` as JSON, with serde_json, or with simd-json when the
+/// `simd-json` feature is enabled. Both write the same JSON.
+///
+#[cfg(not(feature = "simd-json"))]
+pub fn to_json<T: Serialize + ?Sized>(value: &T) -> Result<Bytes, BoxError> {
+    Ok(Bytes::from(serde_json::to_vec(value)?))
+}
+
+///
+/// Serializes `value` as JSON, with serde_json, or with simd-json when the
+/// `simd-json` feature is enabled. Both write the same JSON.
+///
+#[cfg(feature = "simd-json")]
+pub fn to_json<T: Serialize + ?Sized>(value: &T) -> Result<Bytes, BoxError> {
+    Ok(Bytes::from(simd_json::to_vec(value)?))
+}
+
+///
+/// Like `Query<T>`, but parsed with `serde_qs`. Brackets may be sent as they
+/// are or percent-encoded (`tag%5B%5D=a`), as browsers and HTTP clients tend
+/// to do. A missing query string is the same as an empty one.
+///
+#[derive(Clone, Copy, Debug, Default)]
+pub struct QsQuery<T>(pub T);
+
+///
+/// How deeply `?a[b][c]=...` may nest, which bounds the work a query string
+/// can cause.
+///
+const MAX_QUERY_DEPTH: usize = 5;
+
+#[async_trait]
+impl<T, S> FromRequestParts<S> for QsQuery<T>
+where
+    T: DeserializeOwned,
+    S: Send + Sync,
+{
+    type Rejection = ExtractError;
+
+    async fn from_request_parts(parts: &mut Parts, _: &S) -> Result<Self, Self::Rejection> {
+        let query = parts.uri.query().unwrap_or_default();
+        let config = serde_qs::Config::new(MAX_QUERY_DEPTH, false);
+        let deserializer = serde_qs::Deserializer::with_config(&config, query.as_bytes())
+            .map_err(|error| ExtractError::new(StatusCode::BAD_REQUEST, error.to_string()))?;
+        serde_path_to_error::deserialize(deserializer)
+            .map(QsQuery)
+            .map_err(|error| ExtractError::from_path_error(StatusCode::BAD_REQUEST, &error))
+    }
+}
+
+#[derive(Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct ErrorBody {
+    pub message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub path: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expected: Option<String>,
+}
+
+#[derive(Debug)]
+pub struct ExtractError {
+    pub status: StatusCode,
+    pub body: ErrorBody,
+}
+
+impl ExtractError {
+    fn new(status: StatusCode, message: impl Into<String>) -> Self {
+        ExtractError { status, body: ErrorBody { message: message.into(), path: None, expected: None } }
+    }
+
+    ///
+    /// An error for a document that is well-formed, but does not match the
+    /// type it was deserialized into with `serde_path_to_error`.
+    ///
+    pub fn from_path_error<E: fmt::Display>(status: StatusCode, error: &serde_path_to_error::Error<E>) -> Self {
+        let message = strip_location(&error.inner().to_string());
+        let mut path = match error.path().to_string().as_str() {
+            "." => None,
+            path => Some(path.to_string()),
+        };
+
+        // serde reports a missing field at the struct that lacks it, so the
+        // field's own name has to be read from the message.
+        if let Some(field) = between(&message, "missing field `", "`") {
+            path = Some(match path {
+                Some(path) => format!("{}.{}", path, field),
+                None => field.to_string(),
+            });
+        }
+        let expected = message.split_once(", expected ").map(|(_, expected)| expected.to_string());
+
+        ExtractError { status, body: ErrorBody { message, path, expected } }
+    }
+}
+
+impl From<JsonRejection> for ExtractError {
+    fn from(rejection: JsonRejection) -> Self {
+        match rejection {
+            // Valid JSON, but not a `T`: the only case with a path to report.
+            JsonRejection::JsonDataError(error) => {
+                match find_error_source::<serde_path_to_error::Error<serde_json::Error>>(&error) {
+                    Some(source) => ExtractError::from_path_error(StatusCode::UNPROCESSABLE_ENTITY, source),
+                    None => ExtractError::new(error.status(), error.body_text()),
+                }
+            }
+            JsonRejection::JsonSyntaxError(error) => {
+                match find_error_source::<serde_path_to_error::Error<serde_json::Error>>(&error) {
+                    Some(source) => ExtractError::new(error.status(), strip_location(&source.inner().to_string())),
+                    None => ExtractError::new(error.status(), error.body_text()),
+                }
+            }
+            rejection => ExtractError::new(rejection.status(), rejection.body_text()),
+        }
+    }
+}
+
+impl IntoResponse for ExtractError {
+    fn into_response(self) -> Response {
+        (self.status, Json(self.body)).into_response()
+    }
+}
+
+///
+/// serde_json appends " at line 1 column 42" to its messages, which means
+/// little once the body is parsed, and nothing to a client that sent it on a
+/// single line.
+///
+fn strip_location(message: &str) -> String {
+    match message.rfind(" at line ") {
+        Some(index) => message[..index].to_string(),
+        None => message.to_string(),
+    }
+}
+
+fn between<'a>(message: &'a str, start: &str, end: &str) -> Option<&'a str> {
+    let (_, rest) = message.split_once(start)?;
+    rest.split_once(end).map(|(inside, _)| inside)
+}
+
+///
+/// Axum boxes the errors it wraps, so the `serde_path_to_error` error is
+/// somewhere in the chain of sources, not the rejection itself.
+///
+fn find_error_source<'a, T: Error + 'static>(error: &'a (dyn Error + 'static)) -> Option<&'a T> {
+    match error.downcast_ref::<T>() {
+        Some(error) => Some(error),
+        None => error.source().and_then(find_error_source),
+    }
+}
+
+#[tokio::test]
+async fn json_errors_name_the_field_and_expected_type() {
+    // for Body::collect
+    use http_body_util::BodyExt;
+    /// for ServiceExt::oneshot
+    use tower::util::ServiceExt;
+    use axum::{body::Body, http::Method, routing::post, Router};
+
+    #[derive(Debug, serde::Deserialize)]
+    struct Todo {
+        title: String,
+        priority: i32,
+        #[serde(default)]
+        tags: Vec<Tag>,
+    }
+
+    #[derive(Debug, serde::Deserialize)]
+    struct Tag {
+        name: String,
+    }
+
+    let app = Router::new().route("/", post(|AppJson(todo): AppJson<Todo>| async move { todo.title }));
+
+    let send = |content_type: &'static str, body: &'static str| {
+        let app = app.clone();
+        async move {
+            let request = axum::http::Request::builder()
+                .method(Method::POST)
+                .uri("/")
+                .header("Content-Type", content_type)
+                .body(Body::from(body))
+                .unwrap();
+            let response = app.oneshot(request).await.unwrap();
+            let status = response.status();
+            let body = response.into_body().collect().await.unwrap().to_bytes();
+            (status, body)
+        }
+    };
+    let error = |body: &[u8]| serde_json::from_slice::<ErrorBody>(body).unwrap();
+
+    let (status, body) = send("application/json", r#"{ "title": "Write", "priority": 1 }"#).await;
+    assert_eq!((status, &body[..]), (StatusCode::OK, &b"Write"[..]));
+
+    let (status, body) = send("application/json", r#"{ "title": "Write", "priority": "high" }"#).await;
+    assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+    assert_eq!(
+        error(&body),
+        ErrorBody {
+            message: r#"invalid type: string "high", expected i32"#.to_string(),
+            path: Some("priority".to_string()),
+            expected: Some("i32".to_string()),
+        }
+    );
+
+    let (status, body) = send("application/json", r#"{ "title": "Write", "priority": 1, "tags": [{ "name": "a" }, { "name": 2 }] }"#).await;
+    assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+    assert_eq!(error(&body).path.as_deref(), Some("tags[1].name"));
+    assert_eq!(error(&body).expected.as_deref(), Some("a string"));
+
+    let (status, body) = send("application/json", r#"{ "priority": 1 }"#).await;
+    assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+    assert_eq!(error(&body).path.as_deref(), Some("title"));
+    assert_eq!(error(&body).expected, None);
+
+    let (status, body) = send("application/json", r#"{ "title": "Write", "#).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(error(&body).path, None);
+
+    let (status, _) = send("text/plain", r#"{ "title": "Write", "priority": 1 }"#).await;
+    assert_eq!(status, StatusCode::UNSUPPORTED_MEDIA_TYPE);
+}
+
+#[tokio::test]
+async fn query_strings_may_hold_arrays_and_nested_structs() {
+    // for Body::collect
+    use http_body_util::BodyExt;
+    /// for ServiceExt::oneshot
+    use tower::util::ServiceExt;
+    use axum::{body::Body, routing::get, Router};
+
+    #[derive(Debug, serde::Deserialize)]
+    struct Filter {
+        #[serde(default)]
+        tag: Vec<String>,
+        #[serde(default)]
+        priority: Range,
+    }
+
+    #[derive(Debug, Default, serde::Deserialize)]
+    struct Range {
+        min: Option<i32>,
+        max: Option<i32>,
+    }
+
+    let app = Router::new().route(
+        "/",
+        get(|QsQuery(filter): QsQuery<Filter>| async move {
+            format!("{:?} {:?} {:?}", filter.tag, filter.priority.min, filter.priority.max)
+        }),
+    );
+
+    let send = |uri: &'static str| {
+        let app = app.clone();
+        async move {
+            let response = app.oneshot(axum::http::Request::get(uri).body(Body::empty()).unwrap()).await.unwrap();
+            let status = response.status();
+            let body = response.into_body().collect().await.unwrap().to_bytes();
+            (status, String::from_utf8(body.to_vec()).unwrap())
+        }
+    };
+
+    assert_eq!(send("/").await, (StatusCode::OK, "[] None None".to_string()));
+    assert_eq!(
+        send("/?tag[]=home&tag[]=urgent&priority[min]=1").await,
+        (StatusCode::OK, r#"["home", "urgent"] Some(1) None"#.to_string())
+    );
+    assert_eq!(
+        send("/?tag%5B%5D=home&priority%5Bmax%5D=3").await,
+        (StatusCode::OK, r#"["home"] None Some(3)"#.to_string())
+    );
+
+    let (status, body) = send("/?priority[min]=high").await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    let error: ErrorBody = serde_json::from_str(&body).unwrap();
+    assert_eq!(error.path.as_deref(), Some("priority.min"));
+}
+
+#[test]
+fn responses_are_the_same_json_whichever_serializer_writes_them() {
+    #[derive(Clone, Serialize)]
+    struct Todo {
+        title: &'static str,
+        priority: i32,
+        done_ratio: f64,
+        due_at: Option<&'static str>,
+        metadata: serde_json::Value,
+    }
+
+    let todos = vec![
+        Todo {
+            title: "Quote \"this\",\tthen \u{1F600} and \u{0007}",
+            priority: -3,
+            done_ratio: 0.1,
+            due_at: None,
+            metadata: serde_json::json!({ "tags": ["a", "b"], "nested": { "x": 1.5e300 } }),
+        };
+        2
+    ];
+
+    let json = to_json(&todos).unwrap();
+    let parsed: serde_json::Value = serde_json::from_slice(&json).unwrap();
+    assert_eq!(parsed, serde_json::to_value(&todos).unwrap());
+}
@@ -0,0 +1,228 @@
+#![allow(dead_code)]
+
+//!
+//! FAULT INJECTION
+//! ---------------
+//!
+//! Retries, circuit breakers and timeouts are only as good as the failures
+//! they have been tried against, and a server on a laptop does not fail: its
+//! database answers in a millisecond, and its connections never drop. To see
+//! what a client does when things go wrong, make them go wrong on purpose.
+//!
+//! `inject_faults` does that, by route, with rules set in `FAULTS`: a path
+//! prefix, then the faults of the requests whose path starts with it. Rules
+//! are separated by semicolons, and the first one that matches applies.
+//!
+//! ```text
+//! FAULTS=/todo:latency=300ms@50%,error=10%;/auth:drop=5%
+//! ```
+//!
+//! - `latency=300ms@50%` delays half of the requests by 300ms, before they
+//!   run. Without `@`, it delays all of them.
+//! - `error=10%` answers a tenth of them with `500 Internal Server Error`,
+//!   without running them.
+//! - `drop=5%` cuts the connection of one in twenty, before the response is
+//!   complete, which clients see as a network error rather than an answer.
+//!
+//! Injected responses carry an `X-Injected-Fault` header, to tell them from
+//! real failures, and every fault is counted in `injected_faults_total`, by
+//! kind.
+//!
+//! Faults are for development only: release builds ignore `FAULTS`, so that
+//! a variable copied along with the rest of a `.env` cannot take production
+//! down.
+//!
+
+use std::{fmt, io, str::FromStr, sync::Arc, time::Duration};
+
+use axum::{
+    body::{Body, Bytes},
+    extract::{Request, State},
+    http::{HeaderValue, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+
+use crate::problem::Problem;
+
+///
+/// The faults of the requests whose path starts with `prefix`. Percentages
+/// go from 0 to 100.
+///
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct FaultRule {
+    pub prefix: String,
+    /// The delay, and the percentage of requests delayed.
+    pub latency: Option<(Duration, f64)>,
+    pub error_percent: f64,
+    pub drop_percent: f64,
+}
+
+///
+/// The rules of `FAULTS`. The default has none, and injects nothing.
+///
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Faults(pub Arc<Vec<FaultRule>>);
+
+impl Faults {
+    ///
+    /// These rules in development, and none in release builds.
+    ///
+    pub fn dev_only(self) -> Faults {
+        if cfg!(debug_assertions) || self.0.is_empty() {
+            return self;
+        }
+        tracing::warn!("FAULTS is ignored in release builds");
+        Faults::default()
+    }
+
+    fn rule_for(&self, path: &str) -> Option<&FaultRule> {
+        self.0.iter().find(|rule| path.starts_with(&rule.prefix))
+    }
+}
+
+impl FromStr for Faults {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.split(';')
+            .map(str::trim)
+            .filter(|rule| !rule.is_empty())
+            .map(|rule| {
+                let invalid = || format!("Invalid fault rule: {}", rule);
+                let (prefix, faults) = rule.split_once(':').ok_or_else(invalid)?;
+                let mut rule = FaultRule { prefix: prefix.trim().to_string(), ..FaultRule::default() };
+                for fault in faults.split(',').map(str::trim).filter(|fault| !fault.is_empty()) {
+                    match fault.split_once('=').ok_or_else(invalid)? {
+                        ("latency", value) => {
+                            let (delay, percent) = value.split_once('@').unwrap_or((value, "100%"));
+                            rule.latency = Some((duration(delay).ok_or_else(invalid)?, percentage(percent).ok_or_else(invalid)?));
+                        }
+                        ("error", value) => rule.error_percent = percentage(value).ok_or_else(invalid)?,
+                        ("drop", value) => rule.drop_percent = percentage(value).ok_or_else(invalid)?,
+                        _ => return Err(invalid()),
+                    }
+                }
+                Ok(rule)
+            })
+            .collect::<Result<Vec<_>, _>>()
+            .map(|rules| Faults(Arc::new(rules)))
+    }
+}
+
+/// `300ms` or `2s`.
+fn duration(s: &str) -> Option<Duration> {
+    match s.strip_suffix("ms") {
+        Some(millis) => millis.parse().ok().map(Duration::from_millis),
+        None => s.strip_suffix('s')?.parse().ok().map(Duration::from_secs),
+    }
+}
+
+/// `10%`, from 0 to 100.
+fn percentage(s: &str) -> Option<f64> {
+    let percent = s.strip_suffix('%')?.parse::<f64>().ok()?;
+    (0.0..=100.0).contains(&percent).then_some(percent)
+}
+
+impl fmt::Display for Faults {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, rule) in self.0.iter().enumerate() {
+            let mut faults = Vec::new();
+            if let Some((delay, percent)) = rule.latency {
+                faults.push(format!("latency={}ms@{}%", delay.as_millis(), percent));
+            }
+            if rule.error_percent > 0.0 {
+                faults.push(format!("error={}%", rule.error_percent));
+            }
+            if rule.drop_percent > 0.0 {
+                faults.push(format!("drop={}%", rule.drop_percent));
+            }
+            write!(f, "{}{}:{}", if i == 0 { "" } else { ";" }, rule.prefix, faults.join(","))?;
+        }
+        Ok(())
+    }
+}
+
+impl serde::Serialize for Faults {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+fn happens(percent: f64) -> bool {
+    percent > 0.0 && rand::random::<f64>() * 100.0 < percent
+}
+
+fn injected(kind: &'static str, mut response: Response) -> Response {
+    metrics::increment_counter!("injected_faults_total", "kind" => kind);
+    response.headers_mut().insert("X-Injected-Fault", HeaderValue::from_static(kind));
+    response
+}
+
+///
+/// Delays, fails, or cuts off the requests that the first matching rule
+/// picks, and runs the others as usual.
+///
+pub async fn inject_faults(State(faults): State<Faults>, request: Request, next: Next) -> Response {
+    let Some(rule) = faults.rule_for(request.uri().path()) else {
+        return next.run(request).await;
+    };
+
+    if let Some((delay, percent)) = rule.latency {
+        if happens(percent) {
+            metrics::increment_counter!("injected_faults_total", "kind" => "latency");
+            tokio::time::sleep(delay).await;
+        }
+    }
+    if happens(rule.error_percent) {
+        let problem = Problem::new(StatusCode::INTERNAL_SERVER_ERROR).detail("Injected fault");
+        return injected("error", problem.into_response());
+    }
+    if happens(rule.drop_percent) {
+        // A body that fails makes the server close the connection mid-response.
+        let body = futures::stream::once(async {
+            Err::<Bytes, _>(io::Error::new(io::ErrorKind::ConnectionAborted, "Injected fault"))
+        });
+        return injected("drop", Response::new(Body::from_stream(body)));
+    }
+    next.run(request).await
+}
+
+#[tokio::test(start_paused = true)]
+async fn faults_are_injected_by_route() {
+    // for Body::collect
+    use http_body_util::BodyExt;
+    /// for ServiceExt::oneshot
+    use tower::util::ServiceExt;
+    use axum::{routing::get, Router};
+    use tokio::time::Instant;
+
+    let faults: Faults = "/slow:latency=2s; /broken:error=100%; /dropped:drop=100%,latency=300ms@0%".parse().unwrap();
+    assert_eq!(faults.to_string(), "/slow:latency=2000ms@100%;/broken:error=100%;/dropped:latency=300ms@0%,drop=100%");
+    assert!("/todo:error=110%".parse::<Faults>().is_err());
+    assert!("/todo:timeout=1s".parse::<Faults>().is_err());
+
+    let app = ["/slow", "/broken", "/dropped", "/fine"]
+        .into_iter()
+        .fold(Router::new(), |app, path| app.route(path, get(|| async { "Fine" })))
+        .layer(axum::middleware::from_fn_with_state(faults, inject_faults));
+    let get = |uri: &str| app.clone().oneshot(Request::get(uri).body(Body::empty()).unwrap());
+
+    let start = Instant::now();
+    let response = get("/slow").await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert!(start.elapsed() >= Duration::from_secs(2));
+
+    let response = get("/broken").await.unwrap();
+    assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+    assert_eq!(response.headers()["X-Injected-Fault"], "error");
+
+    let response = get("/dropped").await.unwrap();
+    assert_eq!(response.headers()["X-Injected-Fault"], "drop");
+    assert!(response.into_body().collect().await.is_err());
+
+    let start = Instant::now();
+    let response = get("/fine").await.unwrap();
+    assert_eq!(response.into_body().collect().await.unwrap().to_bytes(), "Fine");
+    assert_eq!(start.elapsed(), Duration::ZERO);
+}
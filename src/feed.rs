@@ -0,0 +1,272 @@
+#![allow(dead_code)]
+
+//!
+//! ACTIVITY FEED
+//! -------------
+//!
+//! `GET /feed` shows what happened recently: todos created, comments added and
+//! users registered, newest first. There is no table of events; the feed is
+//! assembled at query time by a `UNION ALL` over the tables that already hold
+//! a `created_at`, so it can never drift from the data it describes.
+//!
+//! Users only see events about todos visible to them (see `todo_visible_to`),
+//! which for admins is everything. `?owner_id=` narrows the feed down to the
+//! events of one todo owner.
+//!
+//! The feed is paginated with a cursor rather than an offset: new events keep
+//! arriving at the top, and an offset would shift under the reader's feet.
+//! The cursor is the sort key of the last event on the page, `(at, kind, id)`,
+//! and the next page starts strictly after it.
+//!
+
+use axum::{
+    async_trait,
+    extract::{FromRef, Query, State},
+    http::StatusCode,
+    Json,
+};
+use sqlx::{Pool, Postgres};
+use time::OffsetDateTime;
+
+use crate::app::Routes;
+use crate::auth::{Claims, JwtKeys};
+use crate::cursor;
+
+const DEFAULT_LIMIT: i64 = 20;
+const MAX_LIMIT: i64 = 100;
+
+#[derive(Clone, Debug, PartialEq, serde::Deserialize, serde::Serialize)]
+pub struct FeedEvent {
+    ///
+    /// One of `todo_created`, `comment_added` or `user_registered`.
+    ///
+    pub kind: String,
+    ///
+    /// The id of the todo, comment or user, depending on the kind.
+    ///
+    pub id: i64,
+    pub todo_id: Option<i64>,
+    pub owner_id: Option<i64>,
+    #[serde(with = "time::serde::rfc3339")]
+    pub at: OffsetDateTime,
+    pub summary: String,
+}
+
+#[derive(Clone, Debug, PartialEq, serde::Deserialize, serde::Serialize)]
+pub struct FeedPage {
+    pub events: Vec<FeedEvent>,
+    pub next_cursor: Option<String>,
+}
+
+///
+/// The position of an event in the feed. Opaque to clients, who only ever
+/// pass back what they were given in `next_cursor`.
+///
+#[derive(Clone, Debug, PartialEq)]
+pub struct FeedCursor {
+    pub at: OffsetDateTime,
+    pub kind: String,
+    pub id: i64,
+}
+
+impl FeedCursor {
+    pub fn encode(&self) -> String {
+        cursor::encode(self.at, &format!("{}:{}", self.kind, self.id))
+    }
+
+    pub fn decode(cursor: &str) -> Option<Self> {
+        let (at, rest) = cursor::decode(cursor)?;
+        let (kind, id) = rest.split_once(':')?;
+        Some(FeedCursor { at, kind: kind.to_string(), id: id.parse().ok()? })
+    }
+}
+
+///
+/// A row of the feed, before its sort key is turned into a cursor.
+///
+struct FeedRow {
+    kind: String,
+    id: i64,
+    todo_id: Option<i64>,
+    owner_id: Option<i64>,
+    at: OffsetDateTime,
+    summary: String,
+}
+
+#[async_trait]
+pub trait FeedRepo: Send + Sync {
+    ///
+    /// Up to `limit` events visible to the user, strictly older than `after`.
+    ///
+    async fn get_feed(
+        &self,
+        user_id: i64,
+        owner_id: Option<i64>,
+        after: Option<FeedCursor>,
+        limit: i64,
+    ) -> FeedPage;
+}
+
+#[derive(Clone)]
+pub struct FeedRepoPostgres {
+    pool: Pool<Postgres>,
+}
+
+impl FeedRepoPostgres {
+    pub fn new(pool: Pool<Postgres>) -> Self {
+        FeedRepoPostgres { pool }
+    }
+}
+
+#[async_trait]
+impl FeedRepo for FeedRepoPostgres {
+    async fn get_feed(
+        &self,
+        user_id: i64,
+        owner_id: Option<i64>,
+        after: Option<FeedCursor>,
+        limit: i64,
+    ) -> FeedPage {
+        let (after_at, after_kind, after_id) = match after {
+            Some(FeedCursor { at, kind, id }) => (Some(at), Some(kind), Some(id)),
+            None => (None, None, None),
+        };
+
+        // One row more than asked for tells whether there is a next page.
+        let query = sqlx::query_as!(
+            FeedRow,
+            r#"SELECT kind AS "kind!", id AS "id!", todo_id, owner_id, at AS "at!", summary AS "summary!" FROM (
+                SELECT 'todo_created' AS kind, t.id, t.id AS todo_id, t.owner_id, t.created_at AS at, t.title AS summary
+                FROM todos t WHERE todo_visible_to(t.owner_id, $1)
+                UNION ALL
+                SELECT 'comment_added', c.id, c.todo_id, t.owner_id, c.created_at,
+                    'Comment #' || ROW_NUMBER() OVER (PARTITION BY c.todo_id ORDER BY c.id) || ' on ' || t.title
+                FROM comments c JOIN todos t ON t.id = c.todo_id WHERE todo_visible_to(t.owner_id, $1)
+                UNION ALL
+                SELECT 'user_registered', u.id, NULL, u.id, u.created_at, u.name
+                FROM users u WHERE todo_visible_to(u.id, $1)
+            ) events
+            WHERE ($2::BIGINT IS NULL OR owner_id = $2)
+            AND ($3::TIMESTAMPTZ IS NULL OR (at, kind, id) < ($3, $4, $5))
+            ORDER BY at DESC, kind DESC, id DESC
+            LIMIT $6"#,
+            user_id,
+            owner_id,
+            after_at,
+            after_kind,
+            after_id,
+            limit + 1
+        );
+        let mut rows = query.fetch_all(&self.pool).await.unwrap();
+
+        let next_cursor = if rows.len() as i64 > limit {
+            rows.truncate(limit as usize);
+            rows.last().map(|row| {
+                FeedCursor { at: row.at, kind: row.kind.clone(), id: row.id }.encode()
+            })
+        } else {
+            None
+        };
+
+        let events = rows
+            .into_iter()
+            .map(|row| FeedEvent {
+                kind: row.kind,
+                id: row.id,
+                todo_id: row.todo_id,
+                owner_id: row.owner_id,
+                at: row.at,
+                summary: row.summary,
+            })
+            .collect();
+
+        FeedPage { events, next_cursor }
+    }
+}
+
+#[derive(Clone)]
+pub struct FeedState<R: FeedRepo> {
+    pub repo: R,
+}
+
+pub fn feed_routes<S, R>() -> Routes<S>
+where
+    R: FeedRepo + Clone + 'static,
+    FeedState<R>: FromRef<S>,
+    JwtKeys: FromRef<S>,
+    S: Clone + Send + Sync + 'static,
+{
+    Routes::new().get("/feed", get_feed::<R>)
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct FeedQuery {
+    after: Option<String>,
+    limit: Option<i64>,
+    owner_id: Option<i64>,
+}
+
+async fn get_feed<R: FeedRepo>(
+    Claims { sub: user_id, .. }: Claims,
+    State(FeedState { repo }): State<FeedState<R>>,
+    Query(FeedQuery { after, limit, owner_id }): Query<FeedQuery>,
+) -> Result<Json<FeedPage>, (StatusCode, String)> {
+    let after = after
+        .map(|cursor| FeedCursor::decode(&cursor).ok_or((StatusCode::BAD_REQUEST, "Invalid cursor".to_string())))
+        .transpose()?;
+    let limit = limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT);
+
+    Ok(Json(repo.get_feed(user_id, owner_id, after, limit).await))
+}
+
+#[tokio::test]
+async fn feed_pages_through_visible_events() {
+    use sqlx::postgres::PgPoolOptions;
+
+    use crate::users::create_test_user;
+
+    let pool = PgPoolOptions::new()
+        .max_connections(1)
+        .connect(&crate::test_db::database_url().await)
+        .await
+        .unwrap();
+    let repo = FeedRepoPostgres::new(pool.clone());
+
+    let user_id = create_test_user(&pool, false).await;
+    let other_id = create_test_user(&pool, false).await;
+
+    let todo_id = sqlx::query!(
+        "INSERT INTO todos (title, description, owner_id) VALUES ('Feed', '', $1) RETURNING id",
+        user_id
+    )
+    .fetch_one(&pool)
+    .await
+    .unwrap()
+    .id;
+    for body in ["First", "Second"] {
+        sqlx::query!("INSERT INTO comments (todo_id, body) VALUES ($1, $2)", todo_id, body)
+            .execute(&pool)
+            .await
+            .unwrap();
+    }
+
+    let mut events = vec![];
+    let mut after = None;
+    loop {
+        let page = repo.get_feed(user_id, None, after, 1).await;
+        events.extend(page.events);
+        match page.next_cursor {
+            Some(cursor) => after = Some(FeedCursor::decode(&cursor).unwrap()),
+            None => break,
+        }
+    }
+
+    let kinds: Vec<&str> = events.iter().map(|event| event.kind.as_str()).collect();
+    assert_eq!(kinds.len(), 4);
+    assert_eq!(kinds.iter().filter(|kind| **kind == "comment_added").count(), 2);
+    assert!(events.iter().all(|event| event.owner_id == Some(user_id)));
+    assert!(events.iter().any(|event| event.summary == "Comment #2 on Feed"));
+
+    let page = repo.get_feed(other_id, None, None, MAX_LIMIT).await;
+    assert!(page.events.iter().all(|event| event.owner_id == Some(other_id)));
+}
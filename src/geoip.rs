@@ -0,0 +1,339 @@
+#![allow(dead_code)]
+
+//!
+//! GEOIP
+//! -----
+//!
+//! Knowing where requests come from helps with plenty: spotting that all the
+//! failed logins of an account come from an unusual country, rate limiting a
+//! whole hosting provider rather than each of its addresses, or just seeing
+//! which countries a tenant's users are in.
+//!
+//! MaxMind's free GeoLite2 databases map IP networks to countries, and to
+//! autonomous systems (ASN, the networks of ISPs and hosting providers).
+//! Download "GeoLite2 Country" and "GeoLite2 ASN" in their binary format,
+//! put the `.mmdb` files in one directory, and set `GEOIP_DIR` to it. Either
+//! of them can be left out.
+//!
+//! ```text
+//! GeoLite2-Country.mmdb
+//! GeoLite2-ASN.mmdb
+//! ```
+//!
+//! The databases are read into memory when the app starts, and looked up
+//! with `maxminddb`, which walks the search tree of the file, a bit of the
+//! address at a time. `enrich_with_geo` looks up the `ClientIp` of every
+//! request (see `client_ip.rs`), and attaches the result as a `Geo`
+//! extension, for handlers such as the login, which writes it to the audit
+//! log. It also counts requests by country, in `http_requests_by_country_total`:
+//! there are only so many countries, while labelling by ASN, or by address,
+//! would create a series for each of thousands of values.
+//!
+
+use std::{net::IpAddr, path::Path, sync::Arc};
+
+use axum::{
+    extract::{Request, State},
+    middleware::Next,
+    response::Response,
+};
+use maxminddb::{geoip2, Reader};
+
+use crate::client_ip::ClientIp;
+
+///
+/// What is known of the network of an address.
+///
+#[derive(Clone, Debug, Default, PartialEq, serde::Serialize)]
+pub struct Geo {
+    /// ISO 3166-1 code of the country, such as `DE`.
+    pub country: Option<Arc<str>>,
+    pub asn: Option<u32>,
+    pub as_organization: Option<Arc<str>>,
+}
+
+///
+/// The GeoLite2 databases, loaded. Clones share them. The default has no
+/// databases, and finds nothing.
+///
+#[derive(Clone, Default)]
+pub struct GeoIp {
+    countries: Option<Arc<Reader<Vec<u8>>>>,
+    asns: Option<Arc<Reader<Vec<u8>>>>,
+}
+
+impl GeoIp {
+    ///
+    /// Loads the databases of `dir` (see above), skipping those that are
+    /// missing.
+    ///
+    pub fn load_dir(dir: &Path) -> Result<GeoIp, String> {
+        let read = |name: &str| {
+            let path = dir.join(name);
+            match std::fs::read(&path) {
+                Ok(database) => Ok(Some(database)),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+                Err(e) => Err(format!("Failed to read {}: {}", path.display(), e)),
+            }
+        };
+
+        GeoIp::from_databases(read("GeoLite2-Country.mmdb")?, read("GeoLite2-ASN.mmdb")?)
+    }
+
+    ///
+    /// Opens the contents of the `.mmdb` files: the country database, and the
+    /// ASN database.
+    ///
+    pub fn from_databases(countries: Option<Vec<u8>>, asns: Option<Vec<u8>>) -> Result<GeoIp, String> {
+        let open = |database: Option<Vec<u8>>, name: &str| {
+            database
+                .map(|database| Reader::from_source(database).map(Arc::new))
+                .transpose()
+                .map_err(|e| format!("Invalid {} database: {}", name, e))
+        };
+
+        Ok(GeoIp { countries: open(countries, "country")?, asns: open(asns, "ASN")? })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.countries.is_none() && self.asns.is_none()
+    }
+
+    pub fn lookup(&self, ip: IpAddr) -> Geo {
+        // An IPv4 address mapped into IPv6 (`::ffff:203.0.113.7`) is looked
+        // up as the IPv4 address that it is.
+        let ip = ip.to_canonical();
+        let country = self.countries.as_ref().and_then(|reader| reader.lookup::<geoip2::Country>(ip).ok());
+        let asn = self.asns.as_ref().and_then(|reader| reader.lookup::<geoip2::Asn>(ip).ok());
+
+        // Without a country of its own, a network has the one it is
+        // registered in.
+        let country = country.and_then(|country| {
+            let own = country.country.and_then(|country| country.iso_code);
+            own.or(country.registered_country.and_then(|country| country.iso_code))
+        });
+        Geo {
+            country: country.map(Arc::from),
+            asn: asn.as_ref().and_then(|asn| asn.autonomous_system_number),
+            as_organization: asn.and_then(|asn| asn.autonomous_system_organization).map(Arc::from),
+        }
+    }
+}
+
+///
+/// Attaches the `Geo` of the client to the request, and counts the request
+/// under its country. Does nothing without a database, or a client address.
+///
+pub async fn enrich_with_geo(
+    State(geoip): State<GeoIp>,
+    client_ip: Option<ClientIp>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    if let (false, Some(ClientIp(ip))) = (geoip.is_empty(), client_ip) {
+        let geo = geoip.lookup(ip);
+        let country = geo.country.as_deref().unwrap_or("unknown").to_string();
+        metrics::increment_counter!("http_requests_by_country_total", "country" => country);
+        request.extensions_mut().insert(geo);
+    }
+    next.run(request).await
+}
+
+#[tokio::test]
+async fn requests_are_enriched_with_their_country_and_asn() {
+    // for Body::collect
+    use http_body_util::BodyExt;
+    /// for ServiceExt::oneshot
+    use tower::util::ServiceExt;
+    use axum::{body::Body, extract::FromRef, routing::get, Extension, Json, Router};
+
+    use crate::client_ip::TrustedProxies;
+
+    let countries = mmdb(
+        "GeoLite2-Country",
+        vec![
+            ("203.0.113.0/25", Mmdb::Map(vec![("country", Mmdb::Map(vec![("iso_code", Mmdb::Str("DE"))]))])),
+            ("203.0.113.128/25", Mmdb::Map(vec![("registered_country", Mmdb::Map(vec![("iso_code", Mmdb::Str("US"))]))])),
+            ("2001:db8::/32", Mmdb::Map(vec![("country", Mmdb::Map(vec![("iso_code", Mmdb::Str("US"))]))])),
+        ],
+    );
+    let asns = mmdb(
+        "GeoLite2-ASN",
+        vec![(
+            "203.0.113.0/24",
+            Mmdb::Map(vec![
+                ("autonomous_system_number", Mmdb::U32(64496)),
+                ("autonomous_system_organization", Mmdb::Str("Example, \"Documentation\" Networks")),
+            ]),
+        )],
+    );
+    let geoip = GeoIp::from_databases(Some(countries), Some(asns)).unwrap();
+
+    #[derive(Clone, FromRef)]
+    struct AppState {
+        geoip: GeoIp,
+        trusted_proxies: TrustedProxies,
+    }
+
+    let state = AppState { geoip, trusted_proxies: TrustedProxies::default() };
+    let app = Router::new()
+        .route("/", get(|geo: Option<Extension<Geo>>| async move { Json(geo.map(|Extension(geo)| geo)) }))
+        .layer(axum::middleware::from_fn_with_state(state.clone(), enrich_with_geo))
+        .with_state(state);
+
+    let geo_of = |ip: &'static str| {
+        let app = app.clone();
+        async move {
+            let request = Request::get("/").header("X-Forwarded-For", ip).body(Body::empty()).unwrap();
+            let body = app.oneshot(request).await.unwrap().into_body().collect().await.unwrap().to_bytes();
+            serde_json::from_slice::<serde_json::Value>(&body).unwrap()
+        }
+    };
+
+    assert_eq!(
+        geo_of("203.0.113.7").await,
+        serde_json::json!({ "country": "DE", "asn": 64496, "as_organization": "Example, \"Documentation\" Networks" })
+    );
+    // Without a country of its own, a network has the one it is registered in.
+    assert_eq!(geo_of("203.0.113.200").await["country"], "US");
+    assert_eq!(geo_of("2001:db8::1").await["country"], "US");
+    assert_eq!(geo_of("::ffff:203.0.113.7").await["country"], "DE");
+    assert_eq!(geo_of("198.51.100.1").await, serde_json::json!({ "country": null, "asn": null, "as_organization": null }));
+}
+
+///
+/// A value in the data section of a MaxMind DB, for the tests to write one.
+///
+#[cfg(test)]
+enum Mmdb<'a> {
+    Str(&'a str),
+    U16(u16),
+    U32(u32),
+    U64(u64),
+    Map(Vec<(&'a str, Mmdb<'a>)>),
+    Array(Vec<Mmdb<'a>>),
+}
+
+#[cfg(test)]
+impl Mmdb<'_> {
+    ///
+    /// A control byte holds the type in its top 3 bits, or 0 and then the
+    /// type less 7 in the next byte for the extended types, and the size in
+    /// its low 5 bits, or 29 and then the size less 29 in the next byte.
+    ///
+    fn encode(&self, out: &mut Vec<u8>) {
+        let header = |out: &mut Vec<u8>, type_num: u8, size: usize| {
+            assert!(size < 285);
+            let size_bits = size.min(29) as u8;
+            match type_num {
+                ..=7 => out.push(type_num << 5 | size_bits),
+                _ => out.extend([size_bits, type_num - 7]),
+            }
+            if size >= 29 {
+                out.push((size - 29) as u8);
+            }
+        };
+        let uint = |out: &mut Vec<u8>, type_num: u8, value: u64| {
+            let bytes = value.to_be_bytes();
+            let significant = &bytes[value.leading_zeros() as usize / 8..];
+            header(out, type_num, significant.len());
+            out.extend(significant);
+        };
+
+        match self {
+            Mmdb::Str(value) => {
+                header(out, 2, value.len());
+                out.extend(value.as_bytes());
+            }
+            Mmdb::U16(value) => uint(out, 5, u64::from(*value)),
+            Mmdb::U32(value) => uint(out, 6, u64::from(*value)),
+            Mmdb::U64(value) => uint(out, 9, *value),
+            Mmdb::Map(entries) => {
+                header(out, 7, entries.len());
+                for (key, value) in entries {
+                    Mmdb::Str(key).encode(out);
+                    value.encode(out);
+                }
+            }
+            Mmdb::Array(values) => {
+                header(out, 11, values.len());
+                for value in values {
+                    value.encode(out);
+                }
+            }
+        }
+    }
+}
+
+///
+/// A MaxMind DB of the networks, with their data, the way its writers lay it
+/// out: a binary tree of 24 bit records, where each bit of an address picks
+/// a record, which is either the next node, nothing, or the data of the
+/// network; then 16 zero bytes, the data, and the metadata after a marker.
+/// The tree is an IPv6 one, whose IPv4 addresses are under `::/96`.
+///
+#[cfg(test)]
+fn mmdb(database_type: &str, networks: Vec<(&str, Mmdb)>) -> Vec<u8> {
+    #[derive(Clone, Copy)]
+    enum Record {
+        Empty,
+        Node(usize),
+        Data(usize),
+    }
+
+    let mut nodes = vec![[Record::Empty; 2]];
+    let mut data = Vec::new();
+    for (network, value) in networks {
+        let network: ipnet::IpNet = network.parse().unwrap();
+        let (bits, prefix_len) = match network {
+            ipnet::IpNet::V4(network) => (u128::from(u32::from(network.network())), network.prefix_len() + 96),
+            ipnet::IpNet::V6(network) => (u128::from(network.network()), network.prefix_len()),
+        };
+
+        let mut node = 0;
+        for i in 0..prefix_len {
+            let bit = (bits >> (127 - i)) as usize & 1;
+            if i == prefix_len - 1 {
+                nodes[node][bit] = Record::Data(data.len());
+                value.encode(&mut data);
+                break;
+            }
+            node = match nodes[node][bit] {
+                Record::Node(next) => next,
+                Record::Empty => {
+                    nodes.push([Record::Empty; 2]);
+                    nodes[node][bit] = Record::Node(nodes.len() - 1);
+                    nodes.len() - 1
+                }
+                Record::Data(_) => panic!("{} is inside another network", network),
+            };
+        }
+    }
+
+    let node_count = nodes.len();
+    let mut out = Vec::new();
+    for record in nodes.iter().flatten() {
+        let value = match *record {
+            Record::Empty => node_count,
+            Record::Node(next) => next,
+            Record::Data(offset) => node_count + 16 + offset,
+        };
+        out.extend(&(value as u32).to_be_bytes()[1..]);
+    }
+    out.extend([0; 16]);
+    out.extend(data);
+    out.extend(b"\xab\xcd\xefMaxMind.com");
+    Mmdb::Map(vec![
+        ("binary_format_major_version", Mmdb::U16(2)),
+        ("binary_format_minor_version", Mmdb::U16(0)),
+        ("build_epoch", Mmdb::U64(0)),
+        ("database_type", Mmdb::Str(database_type)),
+        ("description", Mmdb::Map(vec![])),
+        ("ip_version", Mmdb::U16(6)),
+        ("languages", Mmdb::Array(vec![])),
+        ("node_count", Mmdb::U32(node_count as u32)),
+        ("record_size", Mmdb::U16(24)),
+    ])
+    .encode(&mut out);
+    out
+}
@@ -0,0 +1,253 @@
+#![allow(dead_code)]
+
+//!
+//! HEADERS
+//! -------
+//!
+//! Every request carries headers, and so far the todo app has only looked at
+//! one of them, `Authorization`, by hand. A `HeaderMap` hands out raw bytes:
+//! each handler has to find the header, check that it is valid UTF-8, and
+//! parse it, and each one does so a little differently.
+//!
+//! `TypedHeader<T>` from axum-extra is an extractor that does this once. `T`
+//! is a type from the `headers` crate, such as `UserAgent` or
+//! `Authorization<Bearer>`, which knows the header's name and how to parse
+//! it. A request whose header is missing or malformed is rejected with a 400
+//! before the handler runs.
+//!
+//! Headers that the `headers` crate does not know, such as the app's own
+//! `X-Client-Version`, can be typed just as well by implementing its `Header`
+//! trait.
+//!
+
+use std::{fmt, str::FromStr};
+
+use axum::{
+    extract::{Request, State},
+    http::{HeaderName, HeaderValue, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+#[allow(unused_imports)]
+use axum::{body::Body, routing::get, Router};
+use axum_extra::{
+    headers::{self, Header},
+    typed_header::TypedHeaderRejection,
+    TypedHeader,
+};
+
+///
+/// EXERCISE 1
+///
+/// `TypedHeader<UserAgent>` extracts the `User-Agent` header, and rejects
+/// requests without one.
+///
+/// Change the handler to take an `Option<TypedHeader<UserAgent>>` instead,
+/// and answer "unknown" when there is none. What does the second request
+/// receive now?
+///
+#[tokio::test]
+async fn user_agent_header() {
+    // for Body::collect
+    use http_body_util::BodyExt;
+    /// for ServiceExt::oneshot
+    use tower::util::ServiceExt;
+    use axum_extra::headers::UserAgent;
+
+    let app = Router::<()>::new().route(
+        "/",
+        get(|TypedHeader(agent): TypedHeader<UserAgent>| async move { agent.to_string() }),
+    );
+
+    let response = app
+        .clone()
+        .oneshot(axum::http::Request::get("/").header("User-Agent", "curl/8.4.0").body(Body::empty()).unwrap())
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = response.into_body().collect().await.unwrap().to_bytes();
+    assert_eq!(&body[..], b"curl/8.4.0");
+
+    let response = app.oneshot(axum::http::Request::get("/").body(Body::empty()).unwrap()).await.unwrap();
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+}
+
+///
+/// EXERCISE 2
+///
+/// Some headers are generic over their contents: `Authorization<Bearer>`
+/// only accepts `Authorization: Bearer <token>`, and hands out the token.
+///
+/// Send the same request with `Basic` credentials instead, and see how it is
+/// rejected. Then extract an `Authorization<Basic>` as well, with its
+/// `username` and `password`.
+///
+#[tokio::test]
+async fn bearer_token_header() {
+    // for Body::collect
+    use http_body_util::BodyExt;
+    /// for ServiceExt::oneshot
+    use tower::util::ServiceExt;
+    use axum_extra::headers::{authorization::Bearer, Authorization};
+
+    let app = Router::<()>::new().route(
+        "/",
+        get(|TypedHeader(authorization): TypedHeader<Authorization<Bearer>>| async move {
+            authorization.token().to_string()
+        }),
+    );
+
+    let request = axum::http::Request::get("/").header("Authorization", "Bearer abc.def.ghi").body(Body::empty()).unwrap();
+    let response = app.oneshot(request).await.unwrap();
+    let body = response.into_body().collect().await.unwrap().to_bytes();
+    assert_eq!(&body[..], b"abc.def.ghi");
+}
+
+pub static X_CLIENT_VERSION: HeaderName = HeaderName::from_static("x-client-version");
+
+///
+/// The version of the client app that sent a request, from its
+/// `X-Client-Version: 1.4.2` header. Versions compare by major, then minor,
+/// then patch.
+///
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ClientVersion {
+    pub major: u32,
+    pub minor: u32,
+    pub patch: u32,
+}
+
+impl ClientVersion {
+    pub const fn new(major: u32, minor: u32, patch: u32) -> Self {
+        ClientVersion { major, minor, patch }
+    }
+}
+
+impl fmt::Display for ClientVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}.{}", self.major, self.minor, self.patch)
+    }
+}
+
+impl FromStr for ClientVersion {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.trim().splitn(3, '.').map(|part| part.parse::<u32>().map_err(|_| ()));
+        match (parts.next(), parts.next(), parts.next()) {
+            (Some(major), Some(minor), Some(patch)) => Ok(ClientVersion::new(major?, minor?, patch?)),
+            _ => Err(()),
+        }
+    }
+}
+
+///
+/// What lets `TypedHeader<ClientVersion>` work: the header's name, and how to
+/// go from its values to a `ClientVersion` and back. A header may be sent
+/// more than once, so `decode` gets all of its values; this one must be sent
+/// exactly once.
+///
+impl Header for ClientVersion {
+    fn name() -> &'static HeaderName {
+        &X_CLIENT_VERSION
+    }
+
+    fn decode<'i, I>(values: &mut I) -> Result<Self, headers::Error>
+    where
+        I: Iterator<Item = &'i HeaderValue>,
+    {
+        let value = values.next().ok_or_else(headers::Error::invalid)?;
+        if values.next().is_some() {
+            return Err(headers::Error::invalid());
+        }
+        value
+            .to_str()
+            .ok()
+            .and_then(|value| value.parse().ok())
+            .ok_or_else(headers::Error::invalid)
+    }
+
+    fn encode<E: Extend<HeaderValue>>(&self, values: &mut E) {
+        values.extend(std::iter::once(HeaderValue::from_str(&self.to_string()).unwrap()));
+    }
+}
+
+///
+/// Rejects requests from clients older than the minimum version in state,
+/// with `426 Upgrade Required`, so that an app can tell its user to update
+/// rather than fail on a response it does not understand. Requests without
+/// the header, such as those from `curl`, are let through; those with a
+/// malformed one are not.
+///
+/// ```ignore
+/// router.layer(axum::middleware::from_fn_with_state(ClientVersion::new(1, 4, 0), require_client_version))
+/// ```
+///
+pub async fn require_client_version(
+    State(minimum): State<ClientVersion>,
+    version: Result<TypedHeader<ClientVersion>, TypedHeaderRejection>,
+    request: Request,
+    next: Next,
+) -> Response {
+    match version {
+        Ok(TypedHeader(version)) if version < minimum => (
+            StatusCode::UPGRADE_REQUIRED,
+            format!("Client version {} is no longer supported, please upgrade to {} or later", version, minimum),
+        )
+            .into_response(),
+        Ok(_) => next.run(request).await,
+        Err(rejection) if rejection.is_missing() => next.run(request).await,
+        Err(rejection) => rejection.into_response(),
+    }
+}
+
+///
+/// EXERCISE 3
+///
+/// `ClientVersion` implements `Header`, so it can be extracted like any other
+/// typed header, and `require_client_version` uses it to turn away old
+/// clients.
+///
+/// Add a handler that extracts `TypedHeader<ClientVersion>` and answers with
+/// the version, and check that `1.10.0` is newer than `1.9.3` (which it would
+/// not be if versions were compared as strings).
+///
+#[tokio::test]
+async fn old_clients_are_rejected() {
+    /// for ServiceExt::oneshot
+    use tower::util::ServiceExt;
+
+    let app = Router::new()
+        .route("/", get(|| async { "Hello" }))
+        .layer(axum::middleware::from_fn_with_state(ClientVersion::new(1, 4, 0), require_client_version));
+
+    let status = |version: Option<&'static str>| {
+        let app = app.clone();
+        async move {
+            let mut request = axum::http::Request::get("/");
+            if let Some(version) = version {
+                request = request.header(&X_CLIENT_VERSION, version);
+            }
+            app.oneshot(request.body(Body::empty()).unwrap()).await.unwrap().status()
+        }
+    };
+
+    assert_eq!(status(Some("1.4.0")).await, StatusCode::OK);
+    assert_eq!(status(Some("2.0.1")).await, StatusCode::OK);
+    assert_eq!(status(Some("1.3.9")).await, StatusCode::UPGRADE_REQUIRED);
+    assert_eq!(status(None).await, StatusCode::OK);
+    assert_eq!(status(Some("latest")).await, StatusCode::BAD_REQUEST);
+}
+
+#[test]
+fn client_versions_round_trip_through_headers() {
+    use axum_extra::headers::HeaderMapExt;
+
+    let mut headers = axum::http::HeaderMap::new();
+    headers.typed_insert(ClientVersion::new(1, 10, 0));
+
+    assert_eq!(headers.get(&X_CLIENT_VERSION).unwrap(), "1.10.0");
+    assert_eq!(headers.typed_get::<ClientVersion>(), Some(ClientVersion::new(1, 10, 0)));
+    assert!(ClientVersion::new(1, 10, 0) > ClientVersion::new(1, 9, 3));
+    assert_eq!("1.2".parse::<ClientVersion>(), Err(()));
+}
@@ -0,0 +1,159 @@
+#![allow(dead_code)]
+
+//!
+//! HTTP/2
+//! ------
+//!
+//! HTTP/1.1 sends one request at a time over a connection: a client that
+//! wants 32 requests in flight opens 32 connections. HTTP/2 multiplexes them
+//! instead, as streams over a single connection, with compressed headers.
+//!
+//! Browsers only speak HTTP/2 over TLS, where client and server agree on it
+//! during the handshake (ALPN, Application-Layer Protocol Negotiation). Over
+//! plain TCP, HTTP/2 is called h2c, and the client has to know in advance
+//! that the server speaks it ("prior knowledge"). Nothing on the public
+//! internet does that, but proxies and services talking to each other
+//! inside a data center often do.
+//!
+//! With Axum's `http2` feature enabled, `axum::serve` speaks both HTTP/1.1
+//! and h2c on the same listener, telling them apart by the first bytes that
+//! the client sends: every server of this workshop accepts h2c already.
+//! `serve_tls` serves HTTP/1.1 and HTTP/2 over TLS, offering both through
+//! ALPN.
+//!
+//! The load generator speaks h2c with `--http-version 2`. Compare it with
+//! HTTP/1.1 at the same concurrency:
+//!
+//! - Every worker shares a single connection, where HTTP/1.1 opened one per
+//!   worker: watch the server's file descriptors, or `ss -tn`, while it runs.
+//! - The server caps the number of streams it runs at once on a connection
+//!   (200, by default). Past that, requests wait in the client, so their
+//!   latency grows while the server looks idle.
+//! - Every stream shares one TCP connection, so a lost packet stalls all of
+//!   them until it is sent again, where with HTTP/1.1 it only stalled one
+//!   request. On a loopback interface, that never happens.
+//!
+
+use std::{io, sync::Arc};
+
+use axum::Router;
+use hyper_util::{
+    rt::{TokioExecutor, TokioIo},
+    server::conn::auto,
+    service::TowerToHyperService,
+};
+use tokio::net::TcpListener;
+use tokio_rustls::{
+    rustls::{
+        self,
+        pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer},
+        ServerConfig,
+    },
+    TlsAcceptor,
+};
+
+///
+/// A TLS configuration from a PEM certificate chain and private key, that
+/// offers HTTP/2 first, then HTTP/1.1, to clients that ask through ALPN.
+///
+pub fn tls_config(cert_pem: &[u8], key_pem: &[u8]) -> Result<ServerConfig, rustls::Error> {
+    let certs = CertificateDer::pem_slice_iter(cert_pem)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| rustls::Error::General(e.to_string()))?;
+    let key = PrivateKeyDer::from_pem_slice(key_pem).map_err(|e| rustls::Error::General(e.to_string()))?;
+
+    let mut config = ServerConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+        .with_safe_default_protocol_versions()?
+        .with_no_client_auth()
+        .with_single_cert(certs, key)?;
+    config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    Ok(config)
+}
+
+///
+/// Serves `router` over TLS, with HTTP/1.1 or HTTP/2, whichever each client
+/// picked. Connections whose handshake fails are dropped, without stopping
+/// the others.
+///
+pub async fn serve_tls(listener: TcpListener, router: Router, config: ServerConfig) -> io::Result<()> {
+    let acceptor = TlsAcceptor::from(Arc::new(config));
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let (acceptor, router) = (acceptor.clone(), router.clone());
+        tokio::spawn(async move {
+            let Ok(stream) = acceptor.accept(stream).await else {
+                return;
+            };
+            // The builder tells HTTP/2 from HTTP/1.1 by the client's first
+            // bytes, so it serves either, whatever ALPN agreed on.
+            let _ = auto::Builder::new(TokioExecutor::new())
+                .serve_connection_with_upgrades(TokioIo::new(stream), TowerToHyperService::new(router))
+                .await;
+        });
+    }
+}
+
+///
+/// EXERCISE 1
+///
+/// The same plaintext server answers an HTTP/1.1 client and an h2c client,
+/// which is forced to HTTP/2 with `http2_prior_knowledge`. The handler
+/// reports the version it was spoken to with.
+///
+/// Try turning off Axum's `http2` feature in `Cargo.toml`: what does the
+/// h2c client get? Then send 100 requests at once with each client, and
+/// count the connections the server accepts (wrap the listener, or watch
+/// `ss -tn`).
+///
+#[tokio::test]
+async fn h2c_on_the_plaintext_listener() {
+    use axum::{extract::Request, routing::get};
+
+    let app = Router::new().route("/", get(|request: Request| async move { format!("{:?}", request.version()) }));
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}/", listener.local_addr().unwrap());
+    tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+    let http1 = reqwest::Client::new();
+    let response = http1.get(&url).send().await.unwrap();
+    assert_eq!(response.version(), reqwest::Version::HTTP_11);
+    assert_eq!(response.text().await.unwrap(), "HTTP/1.1");
+
+    let h2c = reqwest::Client::builder().http2_prior_knowledge().build().unwrap();
+    let response = h2c.get(&url).send().await.unwrap();
+    assert_eq!(response.version(), reqwest::Version::HTTP_2);
+    assert_eq!(response.text().await.unwrap(), "HTTP/2.0");
+}
+
+///
+/// EXERCISE 2
+///
+/// HTTP/2 over TLS, with a self-signed certificate made up for the test. The
+/// client does not force HTTP/2 this time: it offers both versions during
+/// the handshake, and the server picks HTTP/2.
+///
+/// Try removing `b"h2"` from the ALPN protocols in `tls_config`. Which
+/// version do the two sides agree on then? And what happens to a client
+/// that forces HTTP/2 with `http2_prior_knowledge` anyway?
+///
+#[tokio::test]
+async fn http2_over_tls() {
+    use axum::{extract::Request, routing::get};
+
+    let rcgen::CertifiedKey { cert, key_pair } = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+    let config = tls_config(cert.pem().as_bytes(), key_pair.serialize_pem().as_bytes()).unwrap();
+
+    let app = Router::new().route("/", get(|request: Request| async move { format!("{:?}", request.version()) }));
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("https://localhost:{}/", listener.local_addr().unwrap().port());
+    tokio::spawn(serve_tls(listener, app, config));
+
+    let client = reqwest::Client::builder().danger_accept_invalid_certs(true).build().unwrap();
+    let response = client.get(&url).send().await.unwrap();
+    assert_eq!(response.version(), reqwest::Version::HTTP_2);
+    assert_eq!(response.text().await.unwrap(), "HTTP/2.0");
+
+    let http1 = reqwest::Client::builder().danger_accept_invalid_certs(true).http1_only().build().unwrap();
+    let response = http1.get(&url).send().await.unwrap();
+    assert_eq!(response.version(), reqwest::Version::HTTP_11);
+}
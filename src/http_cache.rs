@@ -0,0 +1,243 @@
+#![allow(dead_code)]
+
+//!
+//! HTTP CACHE
+//! ----------
+//!
+//! `CachingTodoRepo` caches the JSON of todo lists, below the handlers: a hit
+//! still goes through the router, the extractors, the handler and the
+//! serialization of everything that is not a list. `HttpCache` caches whole
+//! responses instead, in front of the handlers, so a hit costs a hash map
+//! lookup, and works the same for every `GET` route it wraps.
+//!
+//! Responses are keyed by their path and query, and by the request headers
+//! they can depend on (`Authorization`, `Cookie` and `Accept`), so that users
+//! never see each other's todos. Only `200 OK` responses are stored, and
+//! neither those that set a cookie, nor those that say not to
+//! (`Cache-Control: no-store` or `no-cache`).
+//!
+//! A hit carries an `Age` header, the number of seconds since it was stored.
+//! A client that wants a fresh response sends `Cache-Control: no-cache`: the
+//! request skips the cache, and its response replaces the stored one. With
+//! `no-store`, the response is not stored either.
+//!
+//! Every todo event empties the cache (see `invalidate_on_events`), since
+//! admins see everyone's todos, and any change can show up in someone else's
+//! list. Changes that publish no event, such as those of the recurrence
+//! scheduler, show up once the entries expire, after `max_age`. So does the
+//! expiry of an access token: a response stays cached under the token it was
+//! requested with, for `max_age` at most.
+//!
+
+use std::{
+    collections::HashMap,
+    future::Future,
+    sync::{Arc, RwLock},
+    time::{Duration, Instant},
+};
+
+use axum::{
+    body::{Body, Bytes},
+    extract::{Request, State},
+    http::{header, HeaderMap, HeaderValue, Method, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use tokio::sync::broadcast::error::RecvError;
+
+use crate::websocket::TodoEvents;
+
+///
+/// The request headers that a response may depend on, besides its URI.
+///
+const VARY: [header::HeaderName; 3] = [header::AUTHORIZATION, header::COOKIE, header::ACCEPT];
+
+///
+/// Once the cache holds this many responses, expired ones are dropped before
+/// storing another.
+///
+const MAX_ENTRIES: usize = 10_000;
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+struct CacheKey {
+    uri: String,
+    vary: Vec<Option<HeaderValue>>,
+}
+
+impl CacheKey {
+    fn of(request: &Request) -> Self {
+        CacheKey {
+            uri: request.uri().to_string(),
+            vary: VARY.iter().map(|name| request.headers().get(name).cloned()).collect(),
+        }
+    }
+}
+
+struct CachedResponse {
+    stored_at: Instant,
+    headers: HeaderMap,
+    body: Bytes,
+}
+
+///
+/// Whole responses to `GET` requests. Clones share the same entries.
+///
+#[derive(Clone)]
+pub struct HttpCache {
+    entries: Arc<RwLock<HashMap<CacheKey, CachedResponse>>>,
+    max_age: Duration,
+}
+
+impl HttpCache {
+    pub fn new(max_age: Duration) -> Self {
+        HttpCache { entries: Arc::default(), max_age }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.read().unwrap().len()
+    }
+
+    pub fn invalidate(&self) {
+        self.entries.write().unwrap().clear();
+    }
+
+    fn get(&self, key: &CacheKey) -> Option<Response> {
+        let entries = self.entries.read().unwrap();
+        let cached = entries.get(key).filter(|cached| cached.stored_at.elapsed() < self.max_age)?;
+
+        let mut response = Response::new(Body::from(cached.body.clone()));
+        *response.headers_mut() = cached.headers.clone();
+        response.headers_mut().insert(header::AGE, cached.stored_at.elapsed().as_secs().into());
+        Some(response)
+    }
+
+    fn insert(&self, key: CacheKey, headers: HeaderMap, body: Bytes) {
+        let mut entries = self.entries.write().unwrap();
+        if entries.len() >= MAX_ENTRIES {
+            entries.retain(|_, cached| cached.stored_at.elapsed() < self.max_age);
+        }
+        entries.insert(key, CachedResponse { stored_at: Instant::now(), headers, body });
+    }
+}
+
+fn has_directive(headers: &HeaderMap, directive: &str) -> bool {
+    headers
+        .get_all(header::CACHE_CONTROL)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|d| d.trim().eq_ignore_ascii_case(directive))
+}
+
+fn is_storable(response: &Response) -> bool {
+    response.status() == StatusCode::OK
+        && !response.headers().contains_key(header::SET_COOKIE)
+        && !has_directive(response.headers(), "no-store")
+        && !has_directive(response.headers(), "no-cache")
+}
+
+///
+/// Serves `GET` requests from the cache when it can, and stores the responses
+/// it could not serve.
+///
+/// ```ignore
+/// routes.layer(axum::middleware::from_fn_with_state(cache, cache_responses))
+/// ```
+///
+pub async fn cache_responses(State(cache): State<HttpCache>, request: Request, next: Next) -> Response {
+    if request.method() != Method::GET || has_directive(request.headers(), "no-store") {
+        return next.run(request).await;
+    }
+
+    let key = CacheKey::of(&request);
+    if !has_directive(request.headers(), "no-cache") {
+        if let Some(response) = cache.get(&key) {
+            return response;
+        }
+    }
+
+    let response = next.run(request).await;
+    if !is_storable(&response) {
+        return response;
+    }
+
+    let (parts, body) = response.into_parts();
+    let body = match axum::body::to_bytes(body, usize::MAX).await {
+        Ok(body) => body,
+        Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    };
+    cache.insert(key, parts.headers.clone(), body.clone());
+    Response::from_parts(parts, Body::from(body))
+}
+
+///
+/// Empties the cache whenever a todo is created, changed or deleted, and
+/// whenever events were missed. Returns once every publisher is gone.
+///
+/// Subscribes straight away, rather than when the future first runs, so that
+/// no event published in between goes unnoticed.
+///
+pub fn invalidate_on_events(cache: HttpCache, events: &TodoEvents) -> impl Future<Output = ()> {
+    let mut receiver = events.subscribe();
+    async move {
+        loop {
+            match receiver.recv().await {
+                Ok(_) | Err(RecvError::Lagged(_)) => cache.invalidate(),
+                Err(RecvError::Closed) => return,
+            }
+        }
+    }
+}
+
+#[tokio::test]
+async fn responses_are_cached_until_a_todo_changes() {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    // for Body::collect
+    use http_body_util::BodyExt;
+    /// for ServiceExt::oneshot
+    use tower::util::ServiceExt;
+    use axum::{routing::get, Router};
+
+    use crate::websocket::TodoEventKind;
+
+    let cache = HttpCache::new(Duration::from_secs(60));
+    let events = TodoEvents::default();
+    tokio::spawn(invalidate_on_events(cache.clone(), &events));
+
+    // Each response tells how many requests reached the handler.
+    let handled = Arc::new(AtomicUsize::new(0));
+    let app = Router::new()
+        .route(
+            "/todo",
+            get(move || async move { handled.fetch_add(1, Ordering::SeqCst).to_string() }),
+        )
+        .layer(axum::middleware::from_fn_with_state(cache.clone(), cache_responses));
+    let send = |token: &str, cache_control: Option<&str>| {
+        let mut request = Request::get("/todo?sort=id").header(header::AUTHORIZATION, token);
+        if let Some(cache_control) = cache_control {
+            request = request.header(header::CACHE_CONTROL, cache_control);
+        }
+        let response = app.clone().oneshot(request.body(Body::empty()).unwrap());
+        async move {
+            let response = response.await.unwrap();
+            let age = response.headers().get(header::AGE).cloned();
+            let body = response.into_body().collect().await.unwrap().to_bytes();
+            (String::from_utf8(body.to_vec()).unwrap(), age)
+        }
+    };
+
+    assert_eq!(send("alice", None).await, ("0".to_string(), None));
+    assert_eq!(send("alice", None).await, ("0".to_string(), Some(HeaderValue::from(0))));
+    // Someone else's request is never served someone else's response.
+    assert_eq!(send("bob", None).await, ("1".to_string(), None));
+
+    assert_eq!(send("alice", Some("no-cache")).await, ("2".to_string(), None));
+    assert_eq!(send("alice", None).await.0, "2");
+
+    events.publish(TodoEventKind::Updated, 1, 1);
+    while cache.len() > 0 {
+        tokio::task::yield_now().await;
+    }
+    assert_eq!(send("alice", None).await, ("3".to_string(), None));
+}
@@ -0,0 +1,167 @@
+#![allow(dead_code)]
+
+//!
+//! IDS
+//! ---
+//!
+//! Postgres hands out ids from sequences, but code that keeps its data in
+//! memory has to make its own, and hardcoding them (every user gets id `1`)
+//! only works until the second one. An `IdGenerator` makes the choice
+//! explicit, and replaceable in tests.
+//!
+//! - `SequenceIds` counts up from 1, like a `BIGSERIAL` column.
+//! - `UlidIds` makes ULIDs: 48 bits of milliseconds from a `Clock`, then 80
+//!   random bits. They sort by creation time, and can be created anywhere
+//!   without coordination.
+//! - `UuidV7Ids` makes UUIDv7s, which have the same layout as ULIDs (less the
+//!   6 bits of version and variant), but fit Postgres' native `UUID` type.
+//!
+//! All are deterministic when they need to be: a sequence always is, and the
+//! `seeded` ULIDs and UUIDs on a `FakeClock` are the same on every run.
+//!
+
+use std::sync::{
+    atomic::{AtomicI64, Ordering},
+    Arc, Mutex,
+};
+
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use ulid::Ulid;
+use uuid::Uuid;
+
+use crate::clock::SharedClock;
+
+pub trait IdGenerator: Send + Sync {
+    type Id;
+
+    fn next_id(&self) -> Self::Id;
+}
+
+///
+/// Ids 1, 2, 3, and so on. Clones share the same sequence.
+///
+#[derive(Clone, Debug)]
+pub struct SequenceIds {
+    next: Arc<AtomicI64>,
+}
+
+impl SequenceIds {
+    pub fn starting_at(first: i64) -> Self {
+        SequenceIds { next: Arc::new(AtomicI64::new(first)) }
+    }
+}
+
+impl Default for SequenceIds {
+    fn default() -> Self {
+        SequenceIds::starting_at(1)
+    }
+}
+
+impl IdGenerator for SequenceIds {
+    type Id = i64;
+
+    fn next_id(&self) -> i64 {
+        self.next.fetch_add(1, Ordering::Relaxed)
+    }
+}
+
+///
+/// ULIDs, timestamped by the clock. ULIDs made within the same millisecond
+/// are ordered by their random bits, not by when they were made.
+///
+#[derive(Clone)]
+pub struct UlidIds {
+    clock: SharedClock,
+    rng: Arc<Mutex<StdRng>>,
+}
+
+impl UlidIds {
+    pub fn new(clock: SharedClock) -> Self {
+        UlidIds { clock, rng: Arc::new(Mutex::new(StdRng::from_entropy())) }
+    }
+
+    ///
+    /// ULIDs whose random bits are the same on every run.
+    ///
+    pub fn seeded(clock: SharedClock, seed: u64) -> Self {
+        UlidIds { clock, rng: Arc::new(Mutex::new(StdRng::seed_from_u64(seed))) }
+    }
+}
+
+impl IdGenerator for UlidIds {
+    type Id = Ulid;
+
+    fn next_id(&self) -> Ulid {
+        let millis = (self.clock.now().unix_timestamp_nanos() / 1_000_000) as u64;
+        Ulid::from_parts(millis, self.rng.lock().unwrap().gen())
+    }
+}
+
+///
+/// UUIDv7s, timestamped by the clock. Like ULIDs, those made within the same
+/// millisecond are ordered by their random bits.
+///
+#[derive(Clone)]
+pub struct UuidV7Ids {
+    clock: SharedClock,
+    rng: Arc<Mutex<StdRng>>,
+}
+
+impl UuidV7Ids {
+    pub fn new(clock: SharedClock) -> Self {
+        UuidV7Ids { clock, rng: Arc::new(Mutex::new(StdRng::from_entropy())) }
+    }
+
+    ///
+    /// UUIDs whose random bits are the same on every run.
+    ///
+    pub fn seeded(clock: SharedClock, seed: u64) -> Self {
+        UuidV7Ids { clock, rng: Arc::new(Mutex::new(StdRng::seed_from_u64(seed))) }
+    }
+}
+
+impl IdGenerator for UuidV7Ids {
+    type Id = Uuid;
+
+    fn next_id(&self) -> Uuid {
+        let millis = (self.clock.now().unix_timestamp_nanos() / 1_000_000) as u64;
+        uuid::Builder::from_unix_timestamp_millis(millis, &self.rng.lock().unwrap().gen()).into_uuid()
+    }
+}
+
+#[test]
+fn sequences_are_shared_by_clones() {
+    let ids = SequenceIds::default();
+    let clone = ids.clone();
+
+    assert_eq!(ids.next_id(), 1);
+    assert_eq!(clone.next_id(), 2);
+    assert_eq!(ids.next_id(), 3);
+}
+
+#[test]
+fn seeded_ulids_are_reproducible_and_sort_by_time() {
+    use time::{macros::datetime, Duration};
+
+    use crate::clock::FakeClock;
+
+    let ulids = |seed| {
+        let clock = FakeClock::new(datetime!(2026-10-16 12:00 UTC));
+        let ids = UlidIds::seeded(Arc::new(clock.clone()), seed);
+        (0..3)
+            .map(|_| {
+                clock.advance(Duration::milliseconds(1));
+                ids.next_id()
+            })
+            .collect::<Vec<_>>()
+    };
+
+    let first = ulids(42);
+    assert_eq!(first, ulids(42));
+    assert_ne!(first, ulids(7));
+
+    let mut sorted = first.clone();
+    sorted.sort();
+    assert_eq!(sorted, first);
+    assert_eq!(first[0].timestamp_ms(), 1_792_152_000_001);
+}
@@ -0,0 +1,227 @@
+#![allow(dead_code)]
+
+//!
+//! IN-FLIGHT REQUESTS
+//! ------------------
+//!
+//! A count of the requests that are being handled right now, per route. The
+//! total is what a load test wants to plot against latency, and the breakdown
+//! is what tells which route the requests pile up on.
+//!
+//! The counts are kept as the `http_requests_in_flight` gauge, labelled with
+//! the method and the route (`/todo/:id`, not `/todo/5`, so that the number of
+//! series stays bounded), and served by the admin router at `GET /in-flight`.
+//!
+//! They also tell what graceful shutdown is waiting for. After Ctrl+C, the
+//! server stops accepting connections and lets the requests it has already
+//! accepted finish; `report_draining` prints how many are left while it does.
+//!
+//! A request counts until its handler (and the layers inside this one) has
+//! produced a response. Bodies that are still streaming after that, and
+//! websockets, which outlive the request that upgraded them, are not counted.
+//!
+
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use axum::{
+    extract::{FromRef, MatchedPath, Request, State},
+    middleware::Next,
+    response::Response,
+    Json,
+};
+
+use crate::app::Routes;
+
+///
+/// The path recorded for requests that matched no route.
+///
+pub const UNMATCHED: &str = "unmatched";
+
+///
+/// The number of requests in flight, per method and route. Clones share the
+/// same counts.
+///
+#[derive(Clone, Default)]
+pub struct InFlight {
+    requests: Arc<Mutex<BTreeMap<(String, String), usize>>>,
+}
+
+#[derive(Debug, PartialEq, serde::Deserialize, serde::Serialize)]
+pub struct InFlightRoute {
+    pub method: String,
+    pub path: String,
+    pub requests: usize,
+}
+
+#[derive(Debug, PartialEq, serde::Deserialize, serde::Serialize)]
+pub struct InFlightRequests {
+    pub total: usize,
+    pub routes: Vec<InFlightRoute>,
+}
+
+impl InFlight {
+    pub fn total(&self) -> usize {
+        self.requests.lock().unwrap().values().sum()
+    }
+
+    ///
+    /// The routes with requests in flight, in the order of their paths.
+    ///
+    pub fn snapshot(&self) -> InFlightRequests {
+        let requests = self.requests.lock().unwrap();
+        let mut routes: Vec<InFlightRoute> = requests
+            .iter()
+            .map(|((method, path), requests)| InFlightRoute {
+                method: method.clone(),
+                path: path.clone(),
+                requests: *requests,
+            })
+            .collect();
+        routes.sort_by(|a, b| a.path.cmp(&b.path).then_with(|| a.method.cmp(&b.method)));
+        InFlightRequests { total: requests.values().sum(), routes }
+    }
+
+    fn start(&self, method: String, path: String) -> InFlightGuard {
+        let key = (method, path);
+        let mut requests = self.requests.lock().unwrap();
+        let count = requests.entry(key.clone()).or_default();
+        *count += 1;
+        report(&key, *count);
+        InFlightGuard { in_flight: self.clone(), key }
+    }
+
+    fn finish(&self, key: &(String, String)) {
+        let mut requests = self.requests.lock().unwrap();
+        let count = requests.get_mut(key).expect("finished a request that never started");
+        *count -= 1;
+        report(key, *count);
+        if *count == 0 {
+            requests.remove(key);
+        }
+    }
+}
+
+fn report((method, path): &(String, String), count: usize) {
+    metrics::gauge!("http_requests_in_flight", count as f64, "method" => method.clone(), "route" => path.clone());
+}
+
+///
+/// Counts a request until it is dropped, which also happens when the request
+/// is cancelled, or its handler panics.
+///
+struct InFlightGuard {
+    in_flight: InFlight,
+    key: (String, String),
+}
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        self.in_flight.finish(&self.key);
+    }
+}
+
+///
+/// Counts every request while it is handled.
+///
+/// ```ignore
+/// router.layer(axum::middleware::from_fn_with_state(in_flight, track_in_flight))
+/// ```
+///
+pub async fn track_in_flight(State(in_flight): State<InFlight>, request: Request, next: Next) -> Response {
+    let path = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map_or(UNMATCHED, MatchedPath::as_str)
+        .to_string();
+    let _guard = in_flight.start(request.method().to_string(), path);
+    next.run(request).await
+}
+
+pub fn in_flight_routes<S>() -> Routes<S>
+where
+    InFlight: FromRef<S>,
+    S: Clone + Send + Sync + 'static,
+{
+    Routes::new().get("/in-flight", get_in_flight)
+}
+
+async fn get_in_flight(State(in_flight): State<InFlight>) -> Json<InFlightRequests> {
+    Json(in_flight.snapshot())
+}
+
+///
+/// Prints the requests still in flight every `period`, and returns once there
+/// are none left. Meant to be started when graceful shutdown begins.
+///
+pub async fn report_draining(in_flight: InFlight, period: Duration) {
+    let mut interval = tokio::time::interval(period);
+    loop {
+        interval.tick().await;
+        let InFlightRequests { total, routes } = in_flight.snapshot();
+        if total == 0 {
+            println!("Drained every in-flight request");
+            return;
+        }
+        let routes: Vec<String> = routes
+            .iter()
+            .map(|route| format!("{} {}: {}", route.method, route.path, route.requests))
+            .collect();
+        println!("Draining {} in-flight requests ({})", total, routes.join(", "));
+    }
+}
+
+#[tokio::test]
+async fn requests_are_counted_while_they_are_handled() {
+    // for Body::collect
+    use http_body_util::BodyExt;
+    /// for ServiceExt::oneshot
+    use tower::util::ServiceExt;
+    use axum::{body::Body, http::StatusCode, routing::get, Router};
+    use tokio::sync::Semaphore;
+
+    let in_flight = InFlight::default();
+    // Requests wait for a permit, and there are none until the test says so.
+    let gate = Arc::new(Semaphore::new(0));
+    let waiting = gate.clone();
+    let app = Router::new()
+        .route("/todo/:id", get(move || async move { drop(waiting.acquire().await.unwrap()) }))
+        .layer(axum::middleware::from_fn_with_state(in_flight.clone(), track_in_flight));
+    let admin = in_flight_routes().into_router().with_state(in_flight.clone());
+
+    let requests: Vec<_> = [1, 2]
+        .into_iter()
+        .map(|id| {
+            let request = Request::get(format!("/todo/{}", id)).body(Body::empty()).unwrap();
+            tokio::spawn(app.clone().oneshot(request))
+        })
+        .collect();
+    while in_flight.total() < 2 {
+        tokio::task::yield_now().await;
+    }
+
+    let response = admin.clone().oneshot(Request::get("/in-flight").body(Body::empty()).unwrap()).await.unwrap();
+    let body = response.into_body().collect().await.unwrap().to_bytes();
+    let snapshot: InFlightRequests = serde_json::from_slice(&body).unwrap();
+    assert_eq!(
+        snapshot,
+        InFlightRequests {
+            total: 2,
+            routes: vec![InFlightRoute { method: "GET".to_string(), path: "/todo/:id".to_string(), requests: 2 }],
+        }
+    );
+
+    let draining = tokio::spawn(report_draining(in_flight.clone(), Duration::from_millis(10)));
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    assert!(!draining.is_finished());
+
+    gate.add_permits(2);
+    for request in requests {
+        assert_eq!(request.await.unwrap().unwrap().status(), StatusCode::OK);
+    }
+    tokio::time::timeout(Duration::from_secs(1), draining).await.unwrap().unwrap();
+    assert_eq!(in_flight.snapshot(), InFlightRequests { total: 0, routes: vec![] });
+}
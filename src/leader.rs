@@ -0,0 +1,193 @@
+#![allow(dead_code)]
+
+//!
+//! LEADER ELECTION
+//! ---------------
+//!
+//! Some background tasks must run once per deployment, not once per
+//! instance: with three instances of the app, three recurrence schedulers
+//! would each create the next occurrence of a completed todo.
+//!
+//! Postgres advisory locks make a simple election. `pg_try_advisory_lock(key)`
+//! takes a lock that means whatever the application says it means, and that
+//! only one session can hold at a time. The instance that gets it runs the
+//! task, and the others try again every so often. The lock belongs to the
+//! session, so it is released when the connection closes: if the leader
+//! crashes, or loses its connection, another instance takes over on its next
+//! try.
+//!
+//! The lock lives on a connection of its own, taken out of the pool once and
+//! kept for every try, until it fails. A pooled connection would go back to
+//! the pool, still holding the lock, for some unrelated query to keep alive;
+//! and a new one for every try would have followers connect and disconnect
+//! every period, for as long as they follow.
+//!
+
+use std::{future::Future, time::Duration};
+
+use sha2::{Digest, Sha256};
+use sqlx::{pool::PoolConnection, PgConnection, Pool, Postgres};
+
+///
+/// The advisory lock key for a task. Every instance derives the same key from
+/// the same name.
+///
+pub fn lock_key(name: &str) -> i64 {
+    let digest = Sha256::digest(name.as_bytes());
+    i64::from_be_bytes(digest[..8].try_into().unwrap())
+}
+
+///
+/// Runs `task` on at most one instance at a time, across every instance that
+/// calls this with the same `name`. Instances that are not the leader try to
+/// become it every `period`, and the leader checks every `period` that it
+/// still holds the lock, stopping the task if it does not.
+///
+/// `task` is called again whenever this instance becomes the leader anew.
+///
+pub async fn run_as_leader<F, Fut>(pool: Pool<Postgres>, name: &str, period: Duration, task: F)
+where
+    F: Fn() -> Fut,
+    Fut: Future<Output = ()>,
+{
+    let key = lock_key(name);
+    let mut interval = tokio::time::interval(period);
+    let mut conn = None;
+
+    loop {
+        interval.tick().await;
+
+        let Some(lock) = try_lock(&pool, &mut conn, key).await else {
+            continue;
+        };
+        println!("Leading {}", name);
+
+        let finished = tokio::select! {
+            _ = task() => true,
+            _ = lost(lock, period) => false,
+        };
+        if finished {
+            let _ = sqlx::query!("SELECT pg_advisory_unlock($1)", key).fetch_one(&mut *lock).await;
+            return;
+        }
+        conn = None;
+        println!("No longer leading {}", name);
+    }
+}
+
+///
+/// The connection of this instance, now holding the lock, or `None` if
+/// another session holds it, or the database cannot be reached. `conn` is
+/// opened if there is none, and dropped if it fails, for the next try to open
+/// another.
+///
+async fn try_lock<'c>(
+    pool: &Pool<Postgres>,
+    conn: &'c mut Option<PgConnection>,
+    key: i64,
+) -> Option<&'c mut PgConnection> {
+    if conn.is_none() {
+        *conn = pool.acquire().await.ok().map(PoolConnection::detach);
+    }
+    let locked = sqlx::query_scalar!("SELECT pg_try_advisory_lock($1)", key)
+        .fetch_one(conn.as_mut()?)
+        .await;
+    match locked {
+        Ok(Some(true)) => conn.as_mut(),
+        Ok(_) => None,
+        Err(_) => {
+            *conn = None;
+            None
+        }
+    }
+}
+
+///
+/// Completes once the connection holding the lock stops answering, and with
+/// it, the lock is gone.
+///
+async fn lost(lock: &mut PgConnection, period: Duration) {
+    let mut interval = tokio::time::interval(period);
+    loop {
+        interval.tick().await;
+        if sqlx::query!("SELECT 1 AS alive").fetch_one(&mut *lock).await.is_err() {
+            return;
+        }
+    }
+}
+
+#[tokio::test]
+async fn only_one_instance_leads_and_another_takes_over() {
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+
+    use sqlx::postgres::PgPoolOptions;
+
+    let pool = PgPoolOptions::new()
+        .max_connections(4)
+        .connect(&crate::test_db::database_url().await)
+        .await
+        .unwrap();
+
+    // A name of its own, so that parallel test runs do not elect each other.
+    let name = format!("test-{}", uuid::Uuid::new_v4());
+    let instance = |leading: Arc<AtomicUsize>| {
+        let pool = pool.clone();
+        let name = name.clone();
+        tokio::spawn(async move {
+            run_as_leader(pool, &name, Duration::from_millis(20), || async {
+                leading.fetch_add(1, Ordering::SeqCst);
+                std::future::pending::<()>().await
+            })
+            .await
+        })
+    };
+
+    let (first, second) = (Arc::new(AtomicUsize::new(0)), Arc::new(AtomicUsize::new(0)));
+    let instances = [instance(first.clone()), instance(second.clone())];
+    tokio::time::sleep(Duration::from_millis(200)).await;
+
+    let leaders = [first.load(Ordering::SeqCst), second.load(Ordering::SeqCst)];
+    let leader = leaders.iter().position(|runs| *runs == 1).unwrap();
+    assert_eq!(leaders[1 - leader], 0);
+
+    // The leader dies, its connection with it, and the other one takes over.
+    instances[leader].abort();
+    tokio::time::sleep(Duration::from_millis(500)).await;
+
+    let follower = if leader == 0 { &second } else { &first };
+    assert_eq!(follower.load(Ordering::SeqCst), 1);
+}
+
+#[tokio::test]
+async fn followers_keep_their_connection_between_tries() {
+    use std::str::FromStr;
+
+    use sqlx::postgres::{PgConnectOptions, PgPoolOptions};
+
+    let url = crate::test_db::database_url().await;
+    let name = format!("test-{}", uuid::Uuid::new_v4());
+    let options = PgConnectOptions::from_str(&url).unwrap().application_name(&name);
+    let pool = PgPoolOptions::new().max_connections(4).connect_with(options).await.unwrap();
+    let instance = || {
+        let (pool, name) = (pool.clone(), name.clone());
+        tokio::spawn(async move {
+            run_as_leader(pool, &name, Duration::from_millis(20), std::future::pending::<()>).await
+        })
+    };
+    let sessions = || async {
+        let query =
+            "SELECT pid FROM pg_stat_activity WHERE application_name = $1 AND pid <> pg_backend_pid() ORDER BY pid";
+        sqlx::query_scalar::<_, i32>(query).bind(&name).fetch_all(&pool).await.unwrap()
+    };
+
+    let _instances = [instance(), instance()];
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    let before = sessions().await;
+    tokio::time::sleep(Duration::from_millis(200)).await;
+
+    assert_eq!(before.len(), 2);
+    assert_eq!(sessions().await, before);
+}
@@ -0,0 +1,536 @@
+#![allow(dead_code)]
+
+//!
+//! LISTS
+//! -----
+//!
+//! Todos belong to a single user, but some todos are shared: a shopping list
+//! for a household, or a checklist for a team. A list groups todos, and its
+//! members are granted one of three roles:
+//!
+//! - `viewer`: can see the todos of the list.
+//! - `editor`: can also add and change todos.
+//! - `owner`: can also invite and remove members.
+//!
+//! Authorization happens in the handlers: each one looks up the role of the
+//! current user in the list, and compares it to the role the action requires.
+//! Users who are not members at all get a 404, so they cannot even learn that
+//! the list exists.
+//!
+
+use std::str::FromStr;
+
+use axum::{
+    async_trait,
+    extract::{FromRef, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use axum_extra::routing::TypedPath;
+use sqlx::{Pool, Postgres};
+
+use crate::app::Routes;
+use crate::auth::{Claims, JwtKeys};
+use crate::persistence::TodoStatus;
+
+///
+/// Roles are ordered, so that `role >= ListRole::Editor` reads as "at least
+/// an editor".
+///
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ListRole {
+    Viewer,
+    Editor,
+    Owner,
+}
+
+impl ListRole {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ListRole::Viewer => "viewer",
+            ListRole::Editor => "editor",
+            ListRole::Owner => "owner",
+        }
+    }
+}
+
+impl FromStr for ListRole {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "viewer" => Ok(ListRole::Viewer),
+            "editor" => Ok(ListRole::Editor),
+            "owner" => Ok(ListRole::Owner),
+            _ => Err(format!("Unknown role: {}", s)),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, serde::Deserialize, serde::Serialize)]
+pub struct ListDTO {
+    pub id: i64,
+    pub name: String,
+    pub role: ListRole,
+}
+
+#[derive(Clone, Debug, PartialEq, serde::Deserialize, serde::Serialize)]
+pub struct ListTodoDTO {
+    pub id: i64,
+    pub title: String,
+    pub description: String,
+    pub status: TodoStatus,
+    pub owner_id: Option<i64>,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ListError {
+    NotFound,
+    Forbidden(ListRole),
+    InvalidMember(String),
+}
+
+impl IntoResponse for ListError {
+    fn into_response(self) -> Response {
+        match self {
+            ListError::NotFound => (StatusCode::NOT_FOUND, "List not found".to_string()),
+            ListError::Forbidden(required) => (
+                StatusCode::FORBIDDEN,
+                format!("This requires the {} role", required.as_str()),
+            ),
+            ListError::InvalidMember(message) => (StatusCode::UNPROCESSABLE_ENTITY, message),
+        }
+        .into_response()
+    }
+}
+
+#[async_trait]
+pub trait ListRepo: Send + Sync {
+    ///
+    /// Creates a list, with its creator as its owner.
+    ///
+    async fn create_list(&self, user_id: i64, name: &str) -> i64;
+    ///
+    /// The lists the user is a member of, with their role in each.
+    ///
+    async fn get_lists(&self, user_id: i64) -> Vec<ListDTO>;
+    async fn get_role(&self, list_id: i64, user_id: i64) -> Option<ListRole>;
+    async fn get_list_todos(&self, list_id: i64) -> Vec<ListTodoDTO>;
+    async fn create_list_todo(&self, list_id: i64, user_id: i64, title: &str, description: &str) -> i64;
+    async fn update_list_todo(
+        &self,
+        list_id: i64,
+        todo_id: i64,
+        title: Option<&str>,
+        description: Option<&str>,
+        status: Option<TodoStatus>,
+    ) -> Option<i64>;
+    ///
+    /// Adds a member, or changes their role if they already are one. Returns
+    /// `false` if there is no such user.
+    ///
+    async fn set_member(&self, list_id: i64, user_id: i64, role: ListRole) -> bool;
+    async fn remove_member(&self, list_id: i64, user_id: i64) -> bool;
+}
+
+#[derive(Clone)]
+pub struct ListRepoPostgres {
+    pool: Pool<Postgres>,
+}
+
+impl ListRepoPostgres {
+    pub fn new(pool: Pool<Postgres>) -> Self {
+        ListRepoPostgres { pool }
+    }
+}
+
+#[async_trait]
+impl ListRepo for ListRepoPostgres {
+    async fn create_list(&self, user_id: i64, name: &str) -> i64 {
+        let mut tx = self.pool.begin().await.unwrap();
+
+        let list_id = sqlx::query!(
+            "INSERT INTO lists (name, owner_id) VALUES ($1, $2) RETURNING id",
+            name,
+            user_id
+        )
+        .fetch_one(&mut *tx)
+        .await
+        .unwrap()
+        .id;
+
+        sqlx::query!(
+            "INSERT INTO list_members (list_id, user_id, role) VALUES ($1, $2, $3)",
+            list_id,
+            user_id,
+            ListRole::Owner.as_str()
+        )
+        .execute(&mut *tx)
+        .await
+        .unwrap();
+
+        tx.commit().await.unwrap();
+        list_id
+    }
+    async fn get_lists(&self, user_id: i64) -> Vec<ListDTO> {
+        let query = sqlx::query!(
+            "SELECT lists.id, lists.name, list_members.role
+             FROM lists JOIN list_members ON list_members.list_id = lists.id
+             WHERE list_members.user_id = $1 ORDER BY lists.id",
+            user_id
+        );
+        query
+            .fetch_all(&self.pool)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|row| ListDTO {
+                id: row.id,
+                name: row.name,
+                role: row.role.parse().unwrap(),
+            })
+            .collect()
+    }
+    async fn get_role(&self, list_id: i64, user_id: i64) -> Option<ListRole> {
+        let query = sqlx::query!(
+            "SELECT role FROM list_members WHERE list_id = $1 AND user_id = $2",
+            list_id,
+            user_id
+        );
+        query
+            .fetch_optional(&self.pool)
+            .await
+            .unwrap()
+            .map(|row| row.role.parse().unwrap())
+    }
+    async fn get_list_todos(&self, list_id: i64) -> Vec<ListTodoDTO> {
+        let query = sqlx::query_as!(
+            ListTodoDTO,
+            r#"SELECT id, title, description, status AS "status: TodoStatus", owner_id FROM todos WHERE list_id = $1 ORDER BY id"#,
+            list_id
+        );
+        query.fetch_all(&self.pool).await.unwrap()
+    }
+    async fn create_list_todo(&self, list_id: i64, user_id: i64, title: &str, description: &str) -> i64 {
+        let query = sqlx::query!(
+            "INSERT INTO todos (title, description, owner_id, list_id) VALUES ($1, $2, $3, $4) RETURNING id",
+            title,
+            description,
+            user_id,
+            list_id
+        );
+        query.fetch_one(&self.pool).await.unwrap().id
+    }
+    async fn update_list_todo(
+        &self,
+        list_id: i64,
+        todo_id: i64,
+        title: Option<&str>,
+        description: Option<&str>,
+        status: Option<TodoStatus>,
+    ) -> Option<i64> {
+        let query = sqlx::query!(
+            "UPDATE todos SET title = COALESCE($1, title), description = COALESCE($2, description), status = COALESCE($3, status)
+             WHERE id = $4 AND list_id = $5 RETURNING id",
+            title,
+            description,
+            status as Option<TodoStatus>,
+            todo_id,
+            list_id
+        );
+        query.fetch_optional(&self.pool).await.unwrap().map(|row| row.id)
+    }
+    async fn set_member(&self, list_id: i64, user_id: i64, role: ListRole) -> bool {
+        let query = sqlx::query!(
+            "INSERT INTO list_members (list_id, user_id, role) SELECT $1, id, $3 FROM users WHERE id = $2
+             ON CONFLICT (list_id, user_id) DO UPDATE SET role = EXCLUDED.role RETURNING user_id",
+            list_id,
+            user_id,
+            role.as_str()
+        );
+        query.fetch_optional(&self.pool).await.unwrap().is_some()
+    }
+    async fn remove_member(&self, list_id: i64, user_id: i64) -> bool {
+        let query = sqlx::query!(
+            "DELETE FROM list_members WHERE list_id = $1 AND user_id = $2",
+            list_id,
+            user_id
+        );
+        query.execute(&self.pool).await.unwrap().rows_affected() > 0
+    }
+}
+
+///
+/// Checks that the user has at least the `required` role in the list.
+///
+async fn authorize<R: ListRepo>(
+    repo: &R,
+    list_id: i64,
+    user_id: i64,
+    required: ListRole,
+) -> Result<ListRole, ListError> {
+    match repo.get_role(list_id, user_id).await {
+        None => Err(ListError::NotFound),
+        Some(role) if role < required => Err(ListError::Forbidden(required)),
+        Some(role) => Ok(role),
+    }
+}
+
+#[derive(Clone)]
+pub struct ListState<R: ListRepo> {
+    pub repo: R,
+}
+
+#[derive(Debug, TypedPath)]
+#[typed_path("/lists")]
+pub struct ListCollection;
+
+#[derive(Debug, TypedPath, serde::Deserialize)]
+#[typed_path("/lists/:list_id/todos")]
+pub struct ListTodos {
+    pub list_id: i64,
+}
+
+#[derive(Debug, TypedPath, serde::Deserialize)]
+#[typed_path("/lists/:list_id/todos/:todo_id")]
+pub struct ListTodo {
+    pub list_id: i64,
+    pub todo_id: i64,
+}
+
+#[derive(Debug, TypedPath, serde::Deserialize)]
+#[typed_path("/lists/:list_id/members")]
+pub struct ListMembers {
+    pub list_id: i64,
+}
+
+#[derive(Debug, TypedPath, serde::Deserialize)]
+#[typed_path("/lists/:list_id/members/:user_id")]
+pub struct ListMember {
+    pub list_id: i64,
+    pub user_id: i64,
+}
+
+pub fn list_routes<S, R>() -> Routes<S>
+where
+    R: ListRepo + Clone + 'static,
+    ListState<R>: FromRef<S>,
+    JwtKeys: FromRef<S>,
+    S: Clone + Send + Sync + 'static,
+{
+    Routes::new()
+        .get(ListCollection::PATH, get_lists::<R>)
+        .post(ListCollection::PATH, create_list::<R>)
+        .get(ListTodos::PATH, get_list_todos::<R>)
+        .post(ListTodos::PATH, create_list_todo::<R>)
+        .put(ListTodo::PATH, update_list_todo::<R>)
+        .post(ListMembers::PATH, set_member::<R>)
+        .delete(ListMember::PATH, remove_member::<R>)
+}
+
+async fn get_lists<R: ListRepo>(
+    Claims { sub: user_id, .. }: Claims,
+    State(ListState { repo }): State<ListState<R>>,
+) -> Json<Vec<ListDTO>> {
+    Json(repo.get_lists(user_id).await)
+}
+
+#[derive(Debug, serde::Deserialize, serde::Serialize)]
+struct CreateList {
+    name: String,
+}
+
+async fn create_list<R: ListRepo>(
+    Claims { sub: user_id, .. }: Claims,
+    State(ListState { repo }): State<ListState<R>>,
+    Json(CreateList { name }): Json<CreateList>,
+) -> Json<i64> {
+    Json(repo.create_list(user_id, &name).await)
+}
+
+async fn get_list_todos<R: ListRepo>(
+    Claims { sub: user_id, .. }: Claims,
+    ListTodos { list_id }: ListTodos,
+    State(ListState { repo }): State<ListState<R>>,
+) -> Result<Json<Vec<ListTodoDTO>>, ListError> {
+    authorize(&repo, list_id, user_id, ListRole::Viewer).await?;
+    Ok(Json(repo.get_list_todos(list_id).await))
+}
+
+#[derive(Debug, serde::Deserialize, serde::Serialize)]
+struct CreateListTodo {
+    title: String,
+    description: String,
+}
+
+async fn create_list_todo<R: ListRepo>(
+    Claims { sub: user_id, .. }: Claims,
+    ListTodos { list_id }: ListTodos,
+    State(ListState { repo }): State<ListState<R>>,
+    Json(CreateListTodo { title, description }): Json<CreateListTodo>,
+) -> Result<Json<i64>, ListError> {
+    authorize(&repo, list_id, user_id, ListRole::Editor).await?;
+    Ok(Json(repo.create_list_todo(list_id, user_id, &title, &description).await))
+}
+
+#[derive(Debug, serde::Deserialize, serde::Serialize)]
+struct UpdateListTodo {
+    title: Option<String>,
+    description: Option<String>,
+    status: Option<TodoStatus>,
+}
+
+async fn update_list_todo<R: ListRepo>(
+    Claims { sub: user_id, .. }: Claims,
+    ListTodo { list_id, todo_id }: ListTodo,
+    State(ListState { repo }): State<ListState<R>>,
+    Json(UpdateListTodo { title, description, status }): Json<UpdateListTodo>,
+) -> Result<Json<Option<i64>>, ListError> {
+    authorize(&repo, list_id, user_id, ListRole::Editor).await?;
+    let id = repo
+        .update_list_todo(list_id, todo_id, title.as_deref(), description.as_deref(), status)
+        .await;
+    Ok(Json(id))
+}
+
+#[derive(Debug, serde::Deserialize, serde::Serialize)]
+struct SetMember {
+    user_id: i64,
+    role: ListRole,
+}
+
+///
+/// Invites a user to the list, or changes the role of an existing member.
+/// Ownership cannot be handed out this way.
+///
+async fn set_member<R: ListRepo>(
+    Claims { sub: user_id, .. }: Claims,
+    ListMembers { list_id }: ListMembers,
+    State(ListState { repo }): State<ListState<R>>,
+    Json(member): Json<SetMember>,
+) -> Result<StatusCode, ListError> {
+    authorize(&repo, list_id, user_id, ListRole::Owner).await?;
+
+    if member.role == ListRole::Owner || member.user_id == user_id {
+        return Err(ListError::InvalidMember("A list has exactly one owner".to_string()));
+    }
+    if !repo.set_member(list_id, member.user_id, member.role).await {
+        return Err(ListError::InvalidMember(format!("User {} not found", member.user_id)));
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+///
+/// Removes a member from the list. Owners can remove anyone but themselves,
+/// and any member can leave a list they do not own.
+///
+async fn remove_member<R: ListRepo>(
+    Claims { sub: current_user_id, .. }: Claims,
+    ListMember { list_id, user_id }: ListMember,
+    State(ListState { repo }): State<ListState<R>>,
+) -> Result<StatusCode, ListError> {
+    let role = authorize(&repo, list_id, current_user_id, ListRole::Viewer).await?;
+
+    match (role, user_id == current_user_id) {
+        (ListRole::Owner, true) => {
+            return Err(ListError::InvalidMember("A list has exactly one owner".to_string()))
+        }
+        (ListRole::Owner, false) | (_, true) => {}
+        (_, false) => return Err(ListError::Forbidden(ListRole::Owner)),
+    }
+
+    if repo.remove_member(list_id, user_id).await {
+        Ok(StatusCode::NO_CONTENT)
+    } else {
+        Err(ListError::InvalidMember(format!("User {} is not a member", user_id)))
+    }
+}
+
+#[tokio::test]
+async fn viewers_cannot_mutate_lists() {
+    // for Body::collect
+    use http_body_util::BodyExt;
+    /// for ServiceExt::oneshot
+    use tower::util::ServiceExt;
+    use axum::{body::Body, http::{Method, Request}, Router};
+    use sqlx::postgres::PgPoolOptions;
+
+    use crate::auth::{AuthState, RefreshTokenRepoInMemory};
+    use crate::users::create_test_user;
+
+    #[derive(Clone, FromRef)]
+    struct TestState {
+        lists: ListState<ListRepoPostgres>,
+        keys: JwtKeys,
+    }
+
+    let pool = PgPoolOptions::new()
+        .max_connections(1)
+        .connect(&crate::test_db::database_url().await)
+        .await
+        .unwrap();
+
+    let keys = JwtKeys::from_secret(b"secret");
+    let auth = AuthState { repo: RefreshTokenRepoInMemory::default(), keys: keys.clone() };
+    let app: Router = list_routes::<_, ListRepoPostgres>()
+        .into_router()
+        .with_state(TestState { lists: ListState { repo: ListRepoPostgres::new(pool.clone()) }, keys });
+
+    let owner = create_test_user(&pool, false).await;
+    let editor = create_test_user(&pool, false).await;
+    let viewer = create_test_user(&pool, false).await;
+    let stranger = create_test_user(&pool, false).await;
+
+    let request = |method: Method, uri: String, user_id: i64, body: Option<serde_json::Value>| {
+        let auth = auth.clone();
+        let app = app.clone();
+        async move {
+            let token = auth.issue_tokens(user_id).await.access_token;
+            let request = Request::builder()
+                .method(method)
+                .uri(uri)
+                .header("Authorization", format!("Bearer {}", token))
+                .header("Content-Type", "application/json");
+            let body = body.map_or(Body::empty(), |body| Body::from(body.to_string()));
+            app.oneshot(request.body(body).unwrap()).await.unwrap()
+        }
+    };
+
+    let response = request(Method::POST, ListCollection.to_string(), owner, Some(serde_json::json!({ "name": "Groceries" }))).await;
+    let body = response.into_body().collect().await.unwrap().to_bytes();
+    let list_id: i64 = serde_json::from_slice(&body).unwrap();
+
+    let members = ListMembers { list_id }.to_string();
+    let response = request(Method::POST, members.clone(), owner, Some(serde_json::json!({ "user_id": editor, "role": "editor" }))).await;
+    assert_eq!(response.status(), StatusCode::NO_CONTENT);
+    let response = request(Method::POST, members.clone(), owner, Some(serde_json::json!({ "user_id": viewer, "role": "viewer" }))).await;
+    assert_eq!(response.status(), StatusCode::NO_CONTENT);
+    let response = request(Method::POST, members, editor, Some(serde_json::json!({ "user_id": stranger, "role": "viewer" }))).await;
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+    let todos = ListTodos { list_id }.to_string();
+    let todo = serde_json::json!({ "title": "Milk", "description": "Oat" });
+    let response = request(Method::POST, todos.clone(), viewer, Some(todo.clone())).await;
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    let response = request(Method::POST, todos.clone(), editor, Some(todo)).await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let response = request(Method::GET, todos.clone(), viewer, None).await;
+    let body = response.into_body().collect().await.unwrap().to_bytes();
+    let list_todos: Vec<ListTodoDTO> = serde_json::from_slice(&body).unwrap();
+    assert_eq!(list_todos.len(), 1);
+    assert_eq!(list_todos[0].owner_id, Some(editor));
+
+    let response = request(Method::GET, todos.clone(), stranger, None).await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+    let response = request(Method::DELETE, ListMember { list_id, user_id: editor }.to_string(), viewer, None).await;
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    let response = request(Method::DELETE, ListMember { list_id, user_id: editor }.to_string(), owner, None).await;
+    assert_eq!(response.status(), StatusCode::NO_CONTENT);
+
+    let response = request(Method::GET, todos, editor, None).await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+}
@@ -0,0 +1,128 @@
+#![allow(dead_code)]
+
+//!
+//! DATALOADER
+//! ----------
+//!
+//! Listing 50 todos together with their comments, one todo at a time, runs 1
+//! query for the todos and then 50 more, one per todo: the "N+1" problem.
+//! Writing the one query that loads all the comments at once is easy; keeping
+//! code that works on one todo at a time while still running that query is
+//! what a data loader is for.
+//!
+//! Each `load(key)` queues its key and then yields to the runtime once, so
+//! that the other loads of the same tick (the rest of a `join_all`, say) queue
+//! theirs too. The first of them to resume takes all the queued keys, loads
+//! them with a single call to the `Loader` (`WHERE todo_id = ANY($1)`), and
+//! hands every caller its value.
+//!
+//! A loader is meant to live for a single request: it holds whatever makes the
+//! query right for that request, such as the id of the user making it.
+//!
+
+use std::{collections::HashMap, hash::Hash, sync::Mutex};
+
+use axum::async_trait;
+use tokio::sync::oneshot;
+
+///
+/// Loads the values for many keys at once. Keys without a value are left out
+/// of the map.
+///
+#[async_trait]
+pub trait Loader: Send + Sync {
+    type Key: Clone + Eq + Hash + Send + Sync;
+    type Value: Clone + Send;
+
+    async fn load(&self, keys: &[Self::Key]) -> HashMap<Self::Key, Self::Value>;
+}
+
+type Waiter<L> = (<L as Loader>::Key, oneshot::Sender<Option<<L as Loader>::Value>>);
+
+pub struct DataLoader<L: Loader> {
+    loader: L,
+    queued: Mutex<Vec<Waiter<L>>>,
+}
+
+impl<L: Loader> DataLoader<L> {
+    pub fn new(loader: L) -> Self {
+        DataLoader { loader, queued: Mutex::new(Vec::new()) }
+    }
+
+    ///
+    /// The value for `key`, loaded in a batch with the keys of every other
+    /// load of the same tick.
+    ///
+    /// If the caller loading a batch is cancelled, the others load their own
+    /// keys instead, so a dropped future never leaves the rest hanging.
+    ///
+    pub async fn load(&self, key: L::Key) -> Option<L::Value> {
+        let (sender, receiver) = oneshot::channel();
+        self.queued.lock().unwrap().push((key.clone(), sender));
+
+        tokio::task::yield_now().await;
+
+        let batch = std::mem::take(&mut *self.queued.lock().unwrap());
+        if !batch.is_empty() {
+            self.dispatch(batch).await;
+        }
+
+        match receiver.await {
+            Ok(value) => value,
+            Err(_) => self.loader.load(std::slice::from_ref(&key)).await.remove(&key),
+        }
+    }
+
+    pub async fn load_many(&self, keys: impl IntoIterator<Item = L::Key>) -> Vec<Option<L::Value>> {
+        futures::future::join_all(keys.into_iter().map(|key| self.load(key))).await
+    }
+
+    async fn dispatch(&self, batch: Vec<Waiter<L>>) {
+        let mut keys: Vec<L::Key> = Vec::with_capacity(batch.len());
+        for (key, _) in &batch {
+            if !keys.contains(key) {
+                keys.push(key.clone());
+            }
+        }
+
+        let values = self.loader.load(&keys).await;
+        for (key, sender) in batch {
+            let _ = sender.send(values.get(&key).cloned());
+        }
+    }
+}
+
+#[tokio::test]
+async fn loads_of_the_same_tick_are_batched() {
+    use std::sync::Arc;
+
+    ///
+    /// Squares numbers, except 13, and remembers the batches it was asked for.
+    ///
+    #[derive(Clone, Default)]
+    struct Squares {
+        batches: Arc<Mutex<Vec<Vec<i64>>>>,
+    }
+
+    #[async_trait]
+    impl Loader for Squares {
+        type Key = i64;
+        type Value = i64;
+
+        async fn load(&self, keys: &[i64]) -> HashMap<i64, i64> {
+            self.batches.lock().unwrap().push(keys.to_vec());
+            keys.iter().filter(|key| **key != 13).map(|key| (*key, key * key)).collect()
+        }
+    }
+
+    let squares = Squares::default();
+    let loader = DataLoader::new(squares.clone());
+
+    let values = loader.load_many([3, 13, 4, 3]).await;
+    assert_eq!(values, vec![Some(9), None, Some(16), Some(9)]);
+    assert_eq!(*squares.batches.lock().unwrap(), vec![vec![3, 13, 4]]);
+
+    // Loads in separate ticks are separate batches.
+    assert_eq!(loader.load(5).await, Some(25));
+    assert_eq!(squares.batches.lock().unwrap().len(), 2);
+}
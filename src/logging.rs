@@ -0,0 +1,136 @@
+#![allow(dead_code)]
+
+//!
+//! LOGGING
+//! -------
+//!
+//! `tracing` events are filtered by directives such as
+//! `info,sqlx::query=debug`: everything at `info` and above, plus every SQL
+//! statement that sqlx runs (which it logs at `debug`, under the target
+//! `sqlx::query`). The directives are read from `RUST_LOG` at startup.
+//!
+//! During an incident, the statements are exactly what you want to see, and a
+//! restart is exactly what you do not want (it would also clear whatever state
+//! led to the problem). So the filter sits behind a reload layer, and the admin
+//! router's `PUT /log-level` swaps it for another one while the server runs:
+//!
+//! ```text
+//! curl -X PUT localhost:3001/log-level -H 'Content-Type: application/json' \
+//!      -d '{ "filter": "info,sqlx::query=debug" }'
+//! ```
+//!
+
+use axum::{
+    extract::{FromRef, State},
+    http::StatusCode,
+    Json,
+};
+use tracing_subscriber::{fmt, layer::SubscriberExt, reload, util::SubscriberInitExt, EnvFilter, Registry};
+
+use crate::app::Routes;
+
+///
+/// Changes the filter of the subscriber it was created with. Clones change the
+/// same filter.
+///
+#[derive(Clone)]
+pub struct LogLevel {
+    handle: reload::Handle<EnvFilter, Registry>,
+}
+
+#[derive(Debug, serde::Deserialize, serde::Serialize)]
+pub struct LogFilter {
+    pub filter: String,
+}
+
+impl LogLevel {
+    ///
+    /// A filter layer for a subscriber, and the `LogLevel` that changes it.
+    ///
+    pub fn layer(filter: &str) -> Result<(reload::Layer<EnvFilter, Registry>, LogLevel), String> {
+        let filter = EnvFilter::try_new(filter).map_err(|e| format!("Invalid log filter {:?}: {}", filter, e))?;
+        let (layer, handle) = reload::Layer::new(filter);
+        Ok((layer, LogLevel { handle }))
+    }
+
+    pub fn current(&self) -> String {
+        self.handle.with_current(ToString::to_string).unwrap_or_default()
+    }
+
+    pub fn set(&self, filter: &str) -> Result<(), String> {
+        let filter = EnvFilter::try_new(filter).map_err(|e| format!("Invalid log filter {:?}: {}", filter, e))?;
+        self.handle.reload(filter).map_err(|e| e.to_string())
+    }
+}
+
+///
+/// Installs the global subscriber, which prints events to stdout, filtered
+/// by `filter`.
+///
+pub fn init_logging(filter: &str) -> Result<LogLevel, String> {
+    let (layer, level) = LogLevel::layer(filter)?;
+    tracing_subscriber::registry()
+        .with(layer)
+        .with(fmt::layer())
+        .try_init()
+        .map_err(|e| e.to_string())?;
+    Ok(level)
+}
+
+pub fn log_level_routes<S>() -> Routes<S>
+where
+    LogLevel: FromRef<S>,
+    S: Clone + Send + Sync + 'static,
+{
+    Routes::new()
+        .get("/log-level", get_log_level)
+        .put("/log-level", set_log_level)
+}
+
+async fn get_log_level(State(level): State<LogLevel>) -> Json<LogFilter> {
+    Json(LogFilter { filter: level.current() })
+}
+
+async fn set_log_level(
+    State(level): State<LogLevel>,
+    Json(LogFilter { filter }): Json<LogFilter>,
+) -> Result<Json<LogFilter>, (StatusCode, String)> {
+    level.set(&filter).map_err(|e| (StatusCode::BAD_REQUEST, e))?;
+    Ok(Json(LogFilter { filter: level.current() }))
+}
+
+#[tokio::test]
+async fn the_log_level_changes_at_runtime() {
+    // for Body::collect
+    use http_body_util::BodyExt;
+    /// for ServiceExt::oneshot
+    use tower::util::ServiceExt;
+    use axum::{body::Body, http::{header, Request}};
+
+    // Installed for this test only, rather than globally.
+    let (layer, level) = LogLevel::layer("info").unwrap();
+    let _subscriber = tracing_subscriber::registry().with(layer).set_default();
+    let app = log_level_routes().into_router().with_state(level);
+
+    let put = |filter: &str| {
+        Request::put("/log-level")
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(serde_json::json!({ "filter": filter }).to_string()))
+            .unwrap()
+    };
+
+    assert!(!tracing::enabled!(target: "sqlx::query", tracing::Level::DEBUG));
+
+    let response = app.clone().oneshot(put("info,sqlx::query=debug")).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert!(tracing::enabled!(target: "sqlx::query", tracing::Level::DEBUG));
+    assert!(!tracing::enabled!(target: "hyper", tracing::Level::DEBUG));
+
+    let response = app.clone().oneshot(put("sqlx::query=loud")).await.unwrap();
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+    let response = app.oneshot(Request::get("/log-level").body(Body::empty()).unwrap()).await.unwrap();
+    let body = response.into_body().collect().await.unwrap().to_bytes();
+    let LogFilter { filter } = serde_json::from_slice(&body).unwrap();
+    assert_eq!(filter, "sqlx::query=debug,info");
+}
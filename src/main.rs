@@ -1,17 +1,89 @@
+mod admin;
+mod app;
 mod architecture;
+mod attachments;
+mod auth;
 mod basics;
 mod client;
+mod client_ip;
+mod clock;
+mod coalesce;
+mod config;
 mod context;
+mod contracts;
+mod cookies;
+mod cursor;
+mod degraded;
+#[cfg(feature = "embedded-postgres")]
+mod embedded_postgres;
+mod erasure;
+mod exposition;
+mod extract;
+mod faults;
+mod feed;
+mod geoip;
 mod handlers;
+mod headers;
+mod http2;
+mod http_cache;
+mod ids;
+mod inflight;
+mod leader;
+mod lists;
+mod loader;
+mod logging;
+mod maintenance;
 mod middleware;
+mod migrations;
+mod openapi;
+mod money;
+mod pat;
 mod persistence;
+mod pii;
 mod playground;
+mod problem;
+mod profiling;
+mod recurrence;
+mod remember_me;
+mod runtime_metrics;
+mod schema_drift;
+mod secrets;
+mod shadow;
+mod shedding;
+mod signed_urls;
+mod systemd;
+mod takeout;
+mod todo_repos;
+mod templates;
+#[cfg(test)]
+mod test_db;
+mod totp;
+mod unix_socket;
+mod upload;
+mod users;
+mod uuid_todos;
+mod webauthn;
+mod websocket;
 mod welcome;
 
 #[tokio::main]
 async fn main() {
-    // playground::example_postgres().await.unwrap();
-    basics::hello_world().await;
+    let args = std::env::args().collect::<Vec<_>>();
 
-    println!("Hello, world!");
+    match args.get(1).map(String::as_str) {
+        // cargo run -- routes [--json]
+        Some("routes") => persistence::print_todo_routes(args.iter().any(|arg| arg == "--json")),
+        // cargo run -- rotate-pii-keys
+        Some("rotate-pii-keys") => persistence::rotate_pii_keys().await,
+        // cargo run --bin rust-web -- check-migrations
+        Some("check-migrations") => migrations::check_migrations_command().await,
+        // cargo run --bin rust-web -- serve
+        Some("serve") => persistence::run_todo_app().await,
+        _ => {
+            // playground::example_postgres().await.unwrap();
+            basics::hello_world().await;
+
+            println!("Hello, world!");
+        }
+    }
 }
@@ -0,0 +1,311 @@
+#![allow(dead_code)]
+
+//!
+//! MAINTENANCE
+//! -----------
+//!
+//! Some changes cannot be made while users write: a migration that rewrites
+//! a large table, a database failover, a restore. For those, the app stays
+//! up, but its public routes answer `503 Service Unavailable`, with a page
+//! that tells browsers what is going on, and a problem (see `problem.rs`) for
+//! API clients. The admin router stays live throughout, so that health
+//! checks, metrics and the switch itself keep working.
+//!
+//! The switch is flipped from the admin router:
+//!
+//! ```text
+//! curl -X PUT localhost:3001/maintenance -H 'Content-Type: application/json' \
+//!      -d '{ "enabled": true, "message": "Moving to a bigger database" }'
+//! ```
+//!
+//! It lives in a `watch` channel, which the middleware reads on every
+//! request, and which anything else can subscribe to, to learn when it
+//! changes.
+//!
+//! Planned maintenance is scheduled in `MAINTENANCE_WINDOWS` instead, as a
+//! comma-separated list of RFC 3339 intervals:
+//! `2026-10-20T02:00:00Z/2026-10-20T04:00:00Z`. During a window, responses
+//! carry a `Retry-After` header with the time left.
+//!
+
+use std::{fmt, str::FromStr, sync::Arc};
+
+use askama::Template;
+use axum::{
+    extract::{FromRef, Request, State},
+    http::{header, HeaderMap, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Json,
+};
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+use tokio::sync::watch;
+
+use crate::app::Routes;
+use crate::clock::SharedClock;
+use crate::problem::Problem;
+use crate::templates::HtmlTemplate;
+
+const DEFAULT_MESSAGE: &str = "The service is down for maintenance.";
+
+///
+/// The switch, as the admin router reads and writes it.
+///
+#[derive(Clone, Debug, Default, PartialEq, serde::Deserialize, serde::Serialize)]
+pub struct MaintenanceMode {
+    pub enabled: bool,
+    /// Shown to users instead of the default message.
+    #[serde(default)]
+    pub message: Option<String>,
+}
+
+///
+/// A scheduled maintenance window, from `start` (inclusive) to `end`.
+///
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MaintenanceWindow {
+    pub start: OffsetDateTime,
+    pub end: OffsetDateTime,
+}
+
+///
+/// The windows of `MAINTENANCE_WINDOWS`, written as a comma-separated list of
+/// `start/end` intervals.
+///
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct MaintenanceWindows(pub Vec<MaintenanceWindow>);
+
+impl FromStr for MaintenanceWindows {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.split(',')
+            .map(str::trim)
+            .filter(|window| !window.is_empty())
+            .map(|window| {
+                let invalid = || format!("Invalid maintenance window: {}", window);
+                let (start, end) = window.split_once('/').ok_or_else(invalid)?;
+                let start = OffsetDateTime::parse(start, &Rfc3339).map_err(|_| invalid())?;
+                let end = OffsetDateTime::parse(end, &Rfc3339).map_err(|_| invalid())?;
+                if end <= start {
+                    return Err(invalid());
+                }
+                Ok(MaintenanceWindow { start, end })
+            })
+            .collect::<Result<Vec<_>, _>>()
+            .map(MaintenanceWindows)
+    }
+}
+
+impl fmt::Display for MaintenanceWindows {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, window) in self.0.iter().enumerate() {
+            let start = window.start.format(&Rfc3339).map_err(|_| fmt::Error)?;
+            let end = window.end.format(&Rfc3339).map_err(|_| fmt::Error)?;
+            write!(f, "{}{}/{}", if i == 0 { "" } else { "," }, start, end)?;
+        }
+        Ok(())
+    }
+}
+
+impl serde::Serialize for MaintenanceWindows {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+///
+/// Whether the app is down for maintenance, switched by hand or scheduled.
+/// Clones share the same switch.
+///
+#[derive(Clone)]
+pub struct Maintenance {
+    mode: Arc<watch::Sender<MaintenanceMode>>,
+    windows: MaintenanceWindows,
+    clock: SharedClock,
+}
+
+///
+/// Why, and until when, the app is down.
+///
+#[derive(Clone, Debug, PartialEq)]
+pub struct Downtime {
+    pub message: String,
+    pub until: Option<OffsetDateTime>,
+}
+
+impl Maintenance {
+    pub fn new(windows: MaintenanceWindows, clock: SharedClock) -> Self {
+        Maintenance { mode: Arc::new(watch::channel(MaintenanceMode::default()).0), windows, clock }
+    }
+
+    pub fn mode(&self) -> MaintenanceMode {
+        self.mode.borrow().clone()
+    }
+
+    pub fn set(&self, mode: MaintenanceMode) {
+        self.mode.send_replace(mode);
+    }
+
+    pub fn subscribe(&self) -> watch::Receiver<MaintenanceMode> {
+        self.mode.subscribe()
+    }
+
+    ///
+    /// The current downtime, if any. The switch wins over the windows, whose
+    /// end it does not know.
+    ///
+    pub fn downtime(&self) -> Option<Downtime> {
+        let mode = self.mode.borrow();
+        if mode.enabled {
+            let message = mode.message.clone().unwrap_or_else(|| DEFAULT_MESSAGE.to_string());
+            return Some(Downtime { message, until: None });
+        }
+
+        let now = self.clock.now();
+        self.windows
+            .0
+            .iter()
+            .find(|window| window.start <= now && now < window.end)
+            .map(|window| Downtime { message: DEFAULT_MESSAGE.to_string(), until: Some(window.end) })
+    }
+}
+
+#[derive(Template)]
+#[template(path = "maintenance.html")]
+struct MaintenancePage {
+    flash: Option<String>,
+    message: String,
+    until: Option<String>,
+}
+
+impl Downtime {
+    ///
+    /// A page for browsers, which ask for HTML, and a problem for everyone
+    /// else.
+    ///
+    fn response(self, headers: &HeaderMap, now: OffsetDateTime) -> Response {
+        let until = self.until.and_then(|until| until.format(&Rfc3339).ok());
+        let wants_html = headers
+            .get(header::ACCEPT)
+            .and_then(|accept| accept.to_str().ok())
+            .is_some_and(|accept| accept.contains("text/html"));
+
+        let mut response = if wants_html {
+            let page = MaintenancePage { flash: None, message: self.message, until };
+            (StatusCode::SERVICE_UNAVAILABLE, HtmlTemplate(page)).into_response()
+        } else {
+            let mut problem = Problem::new(StatusCode::SERVICE_UNAVAILABLE).detail(self.message);
+            if let Some(until) = until {
+                problem = problem.with("until", until);
+            }
+            problem.into_response()
+        };
+
+        if let Some(until) = self.until {
+            let seconds = (until - now).whole_seconds().max(0);
+            response.headers_mut().insert(header::RETRY_AFTER, seconds.into());
+        }
+        response
+    }
+}
+
+///
+/// Answers `503 Service Unavailable` instead of running the request while
+/// the app is down for maintenance.
+///
+pub async fn reject_during_maintenance(State(maintenance): State<Maintenance>, request: Request, next: Next) -> Response {
+    match maintenance.downtime() {
+        Some(downtime) => downtime.response(request.headers(), maintenance.clock.now()),
+        None => next.run(request).await,
+    }
+}
+
+pub fn maintenance_routes<S>() -> Routes<S>
+where
+    Maintenance: FromRef<S>,
+    S: Clone + Send + Sync + 'static,
+{
+    Routes::new()
+        .get("/maintenance", get_maintenance)
+        .put("/maintenance", set_maintenance)
+}
+
+async fn get_maintenance(State(maintenance): State<Maintenance>) -> Json<MaintenanceMode> {
+    Json(maintenance.mode())
+}
+
+async fn set_maintenance(
+    State(maintenance): State<Maintenance>,
+    Json(mode): Json<MaintenanceMode>,
+) -> Json<MaintenanceMode> {
+    maintenance.set(mode);
+    Json(maintenance.mode())
+}
+
+#[tokio::test]
+async fn maintenance_takes_public_routes_down() {
+    // for Body::collect
+    use http_body_util::BodyExt;
+    /// for ServiceExt::oneshot
+    use tower::util::ServiceExt;
+    use axum::{body::Body, routing::get, Router};
+    use time::{macros::datetime, Duration};
+
+    use crate::clock::FakeClock;
+
+    let clock = FakeClock::new(datetime!(2026-10-20 01:00 UTC));
+    let windows: MaintenanceWindows = "2026-10-20T02:00:00Z/2026-10-20T04:00:00Z".parse().unwrap();
+    assert_eq!(windows.to_string(), "2026-10-20T02:00:00Z/2026-10-20T04:00:00Z");
+    let maintenance = Maintenance::new(windows, Arc::new(clock.clone()));
+    let mut changes = maintenance.subscribe();
+
+    let public = Router::new()
+        .route("/todos", get(|| async { "Todos" }))
+        .layer(axum::middleware::from_fn_with_state(maintenance.clone(), reject_during_maintenance));
+    let admin = maintenance_routes().into_router().with_state(maintenance.clone());
+
+    let get = |app: &Router, uri: &str, accept: &str| {
+        let request = Request::get(uri).header(header::ACCEPT, accept).body(Body::empty()).unwrap();
+        app.clone().oneshot(request)
+    };
+
+    assert_eq!(get(&public, "/todos", "*/*").await.unwrap().status(), StatusCode::OK);
+
+    // Switched on by hand, from the admin router.
+    let request = Request::put("/maintenance")
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(Body::from(r#"{ "enabled": true, "message": "Moving to a bigger database" }"#))
+        .unwrap();
+    assert_eq!(admin.clone().oneshot(request).await.unwrap().status(), StatusCode::OK);
+    assert!(changes.has_changed().unwrap());
+    assert!(changes.borrow_and_update().enabled);
+
+    let response = get(&public, "/todos", "application/json").await.unwrap();
+    assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(response.headers()[header::CONTENT_TYPE], "application/problem+json");
+    let body = response.into_body().collect().await.unwrap().to_bytes();
+    let problem: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(problem["detail"], "Moving to a bigger database");
+
+    let response = get(&public, "/todos", "text/html,application/xhtml+xml").await.unwrap();
+    assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+    let body = response.into_body().collect().await.unwrap().to_bytes();
+    assert!(String::from_utf8(body.to_vec()).unwrap().contains("<p>Moving to a bigger database</p>"));
+
+    // The admin router is not behind the layer.
+    let response = get(&admin, "/maintenance", "application/json").await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    maintenance.set(MaintenanceMode::default());
+    assert_eq!(get(&public, "/todos", "*/*").await.unwrap().status(), StatusCode::OK);
+
+    // Scheduled: down from 02:00 to 04:00, as announced by `Retry-After`.
+    clock.set(datetime!(2026-10-20 03:30 UTC));
+    let response = get(&public, "/todos", "application/json").await.unwrap();
+    assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(response.headers()[header::RETRY_AFTER], "1800");
+
+    clock.advance(Duration::minutes(30));
+    assert_eq!(get(&public, "/todos", "*/*").await.unwrap().status(), StatusCode::OK);
+}
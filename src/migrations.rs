@@ -0,0 +1,374 @@
+#![allow(dead_code)]
+
+//!
+//! MIGRATIONS
+//! ----------
+//!
+//! Every migration in `migrations/` comes in two halves: `.up.sql`, which
+//! `sqlx migrate run` and the app apply, and `.down.sql`, which
+//! `sqlx migrate revert` applies to undo it. A down migration that does not
+//! quite undo its up migration is only found out when someone needs it, so
+//! the checks here find it first:
+//!
+//! ```sh
+//! cargo run --bin rust-web -- check-migrations
+//! ```
+//!
+//! It creates a scratch database next to the one in `DATABASE_URL`, and for
+//! each migration in turn applies it, reverts it, and applies it again,
+//! comparing the schema after each step with the one it should be. The
+//! `migrations_are_reversible_and_safe` test does the same.
+//!
+//! It also reads each up migration for statements that are fine on an empty
+//! development database, but fail or lock the table for a long time on one
+//! with data in it (see `Lint`). Migrations that did so before the checks
+//! existed are listed in `ACCEPTED`, since changing a migration that has
+//! been applied changes its checksum, and sqlx then refuses to run.
+//!
+
+use std::{collections::BTreeSet, fmt, str::FromStr};
+
+use sqlx::{
+    migrate::{Migrate, MigrateError, Migrator},
+    postgres::PgConnectOptions,
+    ConnectOptions, Connection, Executor, PgConnection,
+};
+
+///
+/// Statements that need more care on a table with rows than a migration run
+/// by the app at startup can give them.
+///
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Lint {
+    ///
+    /// `ADD COLUMN ... NOT NULL` without a default fails on any table that
+    /// has rows.
+    ///
+    NotNullWithoutDefault,
+    ///
+    /// `SET NOT NULL` fails if any row is NULL, and scans the whole table to
+    /// find out while holding an exclusive lock.
+    ///
+    SetNotNull,
+    ///
+    /// Changing the type of a column, adding one whose default has to be
+    /// computed for each row, or `VACUUM FULL` and `CLUSTER`, rewrite the
+    /// whole table while holding an exclusive lock.
+    ///
+    TableRewrite,
+    ///
+    /// `TYPE TIMESTAMPTZ` without `USING ... AT TIME ZONE` reads the existing
+    /// values in the session's time zone, so the instants they become depend
+    /// on the server's `TimeZone` setting.
+    ///
+    TimeZoneDependent,
+}
+
+impl fmt::Display for Lint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Lint::NotNullWithoutDefault => "adds a NOT NULL column without a default, which fails on a table with rows",
+            Lint::SetNotNull => "makes a column NOT NULL, which fails on NULLs and locks the table while it checks",
+            Lint::TableRewrite => "rewrites the table, which locks it until the rewrite is done",
+            Lint::TimeZoneDependent => "converts to TIMESTAMPTZ in the session's time zone instead of `AT TIME ZONE 'UTC'`",
+        })
+    }
+}
+
+///
+/// Up migrations that were applied with dangerous statements before these
+/// checks existed, by version.
+///
+const ACCEPTED: &[(i64, Lint)] = &[
+    // Todos were few when their timestamps became TIMESTAMPTZ.
+    (20261016220000, Lint::TableRewrite),
+];
+
+#[derive(Debug, PartialEq)]
+pub enum Issue {
+    Dangerous { lint: Lint, statement: String },
+    NoDownMigration,
+    ///
+    /// The schema after the down migration is not the one before the up
+    /// migration: what is missing (`-`) or left over (`+`).
+    ///
+    DownDiffers(Vec<String>),
+    ///
+    /// The schema after applying the migration again is not the one after
+    /// applying it the first time.
+    ///
+    UpDiffers(Vec<String>),
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Finding {
+    pub version: i64,
+    pub description: String,
+    pub issue: Issue,
+}
+
+impl fmt::Display for Finding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}: ", self.version, self.description)?;
+        match &self.issue {
+            Issue::Dangerous { lint, statement } => write!(f, "{}:\n    {}", lint, statement),
+            Issue::NoDownMigration => write!(f, "has no down migration"),
+            Issue::DownDiffers(diff) => write!(f, "the down migration does not undo it:\n    {}", diff.join("\n    ")),
+            Issue::UpDiffers(diff) => write!(f, "applying it again changes the schema:\n    {}", diff.join("\n    ")),
+        }
+    }
+}
+
+///
+/// Runs the checks for `cargo run --bin rust-web -- check-migrations`, and
+/// exits with an error if any failed.
+///
+pub async fn check_migrations_command() {
+    let url = std::env::var("DATABASE_URL").expect("DATABASE_URL must be set");
+    let findings = check_migrations(&url).await.unwrap();
+    for finding in &findings {
+        eprintln!("{}", finding);
+    }
+    if !findings.is_empty() {
+        std::process::exit(1);
+    }
+    let count = sqlx::migrate!().iter().filter(|migration| migration.migration_type.is_up_migration()).count();
+    println!("All {} migrations are reversible, and safe to run", count);
+}
+
+///
+/// Lints the migrations, and checks that they are reversible in a scratch
+/// database on the server of `url`, which is dropped afterwards.
+///
+pub async fn check_migrations(url: &str) -> Result<Vec<Finding>, sqlx::Error> {
+    let migrator = sqlx::migrate!();
+    let mut findings = lint(&migrator);
+
+    let options = PgConnectOptions::from_str(url)?;
+    let scratch = format!("migration_check_{}", uuid::Uuid::new_v4().simple());
+    let mut admin = options.connect().await?;
+    admin.execute(&*format!("CREATE DATABASE {}", scratch)).await?;
+
+    let checked = async {
+        let mut conn = options.clone().database(&scratch).connect().await?;
+        let checked = check_reversible(&mut conn, &migrator).await;
+        conn.close().await?;
+        Ok::<_, sqlx::Error>(checked?)
+    }
+    .await;
+    admin.execute(&*format!("DROP DATABASE IF EXISTS {} WITH (FORCE)", scratch)).await?;
+
+    findings.extend(checked?);
+    Ok(findings)
+}
+
+///
+/// The dangerous statements of the up migrations, except those `ACCEPTED`.
+///
+pub fn lint(migrator: &Migrator) -> Vec<Finding> {
+    migrator
+        .iter()
+        .filter(|migration| migration.migration_type.is_up_migration())
+        .flat_map(|migration| {
+            lint_sql(&migration.sql)
+                .into_iter()
+                .filter(|(lint, _)| !ACCEPTED.contains(&(migration.version, *lint)))
+                .map(|(lint, statement)| Finding {
+                    version: migration.version,
+                    description: migration.description.to_string(),
+                    issue: Issue::Dangerous { lint, statement },
+                })
+        })
+        .collect()
+}
+
+///
+/// Applies, reverts and applies again each migration in turn, on a
+/// connection to an empty database.
+///
+pub async fn check_reversible(conn: &mut PgConnection, migrator: &Migrator) -> Result<Vec<Finding>, MigrateError> {
+    conn.ensure_migrations_table().await?;
+
+    let mut findings = Vec::new();
+    for up in migrator.iter().filter(|migration| migration.migration_type.is_up_migration()) {
+        let finding = |issue| Finding { version: up.version, description: up.description.to_string(), issue };
+        let before = schema(conn).await?;
+        conn.apply(up).await?;
+        let after = schema(conn).await?;
+
+        let Some(down) = migrator.iter().find(|m| m.version == up.version && m.migration_type.is_down_migration()) else {
+            findings.push(finding(Issue::NoDownMigration));
+            continue;
+        };
+        conn.revert(down).await?;
+        let diff = schema_diff(&before, &schema(conn).await?);
+        if !diff.is_empty() {
+            findings.push(finding(Issue::DownDiffers(diff)));
+        }
+
+        // What the down migration left behind may stop the up migration.
+        if let Err(error) = conn.apply(up).await {
+            findings.push(finding(Issue::UpDiffers(vec![error.to_string()])));
+            break;
+        }
+        let diff = schema_diff(&after, &schema(conn).await?);
+        if !diff.is_empty() {
+            findings.push(finding(Issue::UpDiffers(diff)));
+        }
+    }
+    Ok(findings)
+}
+
+///
+/// Everything in the `public` schema that a migration may create, one line
+/// each, with its row-level security policies, and the privileges granted on
+/// it to roles other than its owner. Column order is left out, since a down
+/// migration can only put a dropped column back at the end.
+///
+async fn schema(conn: &mut PgConnection) -> Result<BTreeSet<String>, sqlx::Error> {
+    let lines: Vec<String> = sqlx::query_scalar(
+        r#"SELECT 'column ' || table_name || '.' || column_name || ' ' || udt_name
+                || CASE WHEN is_nullable = 'NO' THEN ' NOT NULL' ELSE '' END
+                || COALESCE(' DEFAULT ' || column_default, '')
+            FROM information_schema.columns WHERE table_schema = 'public'
+        UNION ALL SELECT indexdef FROM pg_indexes WHERE schemaname = 'public'
+        UNION ALL SELECT 'constraint ' || conname || ' ON ' || conrelid::regclass || ' ' || pg_get_constraintdef(oid)
+            FROM pg_constraint WHERE connamespace = 'public'::regnamespace
+        UNION ALL SELECT 'sequence ' || sequencename FROM pg_sequences WHERE schemaname = 'public'
+        UNION ALL SELECT 'function ' || oid::regprocedure || ' ' || md5(prosrc)
+            FROM pg_proc WHERE pronamespace = 'public'::regnamespace
+        UNION ALL SELECT pg_get_triggerdef(oid) FROM pg_trigger WHERE NOT tgisinternal
+        UNION ALL SELECT 'type ' || typname || ' AS ENUM (' || string_agg(enumlabel, ', ' ORDER BY enumsortorder) || ')'
+            FROM pg_type JOIN pg_enum ON enumtypid = pg_type.oid GROUP BY typname
+        UNION ALL SELECT 'row level security ON ' || oid::regclass
+            FROM pg_class WHERE relnamespace = 'public'::regnamespace AND relrowsecurity
+        UNION ALL SELECT 'policy ' || policyname || ' ON ' || tablename || ' ' || cmd || ' TO ' || array_to_string(roles, ', ')
+                || COALESCE(' USING ' || qual, '') || COALESCE(' WITH CHECK ' || with_check, '')
+            FROM pg_policies WHERE schemaname = 'public'
+        UNION ALL SELECT 'grant ' || privilege_type || ' ON ' || pg_class.oid::regclass || ' TO ' || grantee::regrole
+            FROM pg_class, aclexplode(relacl) WHERE relnamespace = 'public'::regnamespace AND grantee <> relowner
+        UNION ALL SELECT 'grant ' || privilege_type || ' ON ' || attrelid::regclass || '.' || attname || ' TO ' || grantee::regrole
+            FROM pg_attribute JOIN pg_class ON pg_class.oid = attrelid, aclexplode(attacl)
+            WHERE relnamespace = 'public'::regnamespace AND grantee <> relowner"#,
+    )
+    .fetch_all(conn)
+    .await?;
+    Ok(lines.into_iter().collect())
+}
+
+fn schema_diff(expected: &BTreeSet<String>, actual: &BTreeSet<String>) -> Vec<String> {
+    let missing = expected.difference(actual).map(|line| format!("- {}", line));
+    let extra = actual.difference(expected).map(|line| format!("+ {}", line));
+    missing.chain(extra).collect()
+}
+
+///
+/// The dangerous statements of `sql`, with their whitespace collapsed.
+///
+fn lint_sql(sql: &str) -> Vec<(Lint, String)> {
+    let mut found = Vec::new();
+    for statement in statements(sql) {
+        let upper = statement.to_uppercase();
+        let mut lint = |lint| found.push((lint, statement.clone()));
+
+        if upper.starts_with("VACUUM FULL") || upper.starts_with("CLUSTER") {
+            lint(Lint::TableRewrite);
+        }
+        if !upper.starts_with("ALTER TABLE") {
+            continue;
+        }
+        if upper.contains("SET NOT NULL") {
+            lint(Lint::SetNotNull);
+        }
+        if upper.contains("ALTER COLUMN") && upper.contains(" TYPE ") {
+            lint(Lint::TableRewrite);
+        }
+        for column in upper.split("ALTER COLUMN").skip(1) {
+            let to_timestamptz = ["TYPE TIMESTAMPTZ", "TYPE TIMESTAMP WITH TIME ZONE"];
+            if to_timestamptz.iter().any(|to| column.contains(to)) && !column.contains("AT TIME ZONE") {
+                lint(Lint::TimeZoneDependent);
+            }
+        }
+        for column in upper.split("ADD COLUMN").skip(1) {
+            if column.contains("NOT NULL") && !column.contains("DEFAULT") && !column.contains("SERIAL") {
+                lint(Lint::NotNullWithoutDefault);
+            }
+            let volatile = ["SERIAL", "RANDOM(", "GEN_RANDOM_UUID(", "CLOCK_TIMESTAMP(", "NEXTVAL("];
+            if volatile.iter().any(|function| column.contains(function)) {
+                lint(Lint::TableRewrite);
+            }
+        }
+    }
+    found
+}
+
+///
+/// The statements of `sql`, without comments. Semicolons inside `$$`
+/// quoted function bodies do not end a statement.
+///
+fn statements(sql: &str) -> Vec<String> {
+    let mut statements = Vec::new();
+    let mut current = String::new();
+    let mut quoted = false;
+    for line in sql.lines() {
+        let line = match line.find("--") {
+            Some(comment) if !quoted => &line[..comment],
+            _ => line,
+        };
+        for (i, part) in line.split("$$").enumerate() {
+            if i > 0 {
+                quoted = !quoted;
+                current.push_str("$$");
+            }
+            let mut rest = part;
+            while let (false, Some(end)) = (quoted, rest.find(';')) {
+                current.push_str(&rest[..end]);
+                statements.push(current.split_whitespace().collect::<Vec<_>>().join(" "));
+                current.clear();
+                rest = &rest[end + 1..];
+            }
+            current.push_str(rest);
+        }
+        current.push('\n');
+    }
+    statements.push(current.split_whitespace().collect::<Vec<_>>().join(" "));
+    statements.retain(|statement| !statement.is_empty());
+    statements
+}
+
+#[test]
+fn dangerous_statements_are_flagged() {
+    let sql = r#"
+        -- ALTER TABLE todos ALTER COLUMN title SET NOT NULL;
+        ALTER TABLE todos ADD COLUMN IF NOT EXISTS status todo_status NOT NULL DEFAULT 'open';
+        ALTER TABLE todos ADD COLUMN owner_id BIGINT NOT NULL;
+        ALTER TABLE todos ALTER COLUMN description SET NOT NULL;
+        ALTER TABLE todos ALTER COLUMN priority TYPE BIGINT, ADD COLUMN external_id UUID DEFAULT gen_random_uuid();
+        ALTER TABLE todos ALTER COLUMN created_at TYPE TIMESTAMPTZ USING created_at AT TIME ZONE 'UTC',
+            ALTER COLUMN completed_at TYPE timestamptz;
+        CREATE FUNCTION f() RETURNS TRIGGER AS $$ BEGIN ALTER TABLE x ALTER COLUMN y SET NOT NULL; END; $$ LANGUAGE plpgsql;
+        VACUUM FULL todos;
+    "#;
+
+    let lints: Vec<Lint> = lint_sql(sql).into_iter().map(|(lint, _)| lint).collect();
+    assert_eq!(
+        lints,
+        [
+            Lint::NotNullWithoutDefault,
+            Lint::SetNotNull,
+            Lint::TableRewrite,
+            Lint::TableRewrite,
+            Lint::TableRewrite,
+            Lint::TimeZoneDependent,
+            Lint::TableRewrite,
+        ]
+    );
+    assert_eq!(lint_sql(sql)[1].1, "ALTER TABLE todos ALTER COLUMN description SET NOT NULL");
+}
+
+#[tokio::test]
+async fn migrations_are_reversible_and_safe() {
+    let findings = check_migrations(&crate::test_db::database_url().await).await.unwrap();
+    let report: Vec<String> = findings.iter().map(Finding::to_string).collect();
+    assert!(findings.is_empty(), "{}", report.join("\n"));
+}
@@ -0,0 +1,200 @@
+#![allow(dead_code)]
+
+//!
+//! MONEY
+//! -----
+//!
+//! In `f64`, `0.1 + 0.2` is `0.30000000000000004`: binary floating point
+//! cannot represent most decimal fractions, so sums of prices drift, and an
+//! exchange rate applied to an amount produces digits that no one can pay.
+//!
+//! `Money` is an exact decimal instead (a `rust_decimal::Decimal`), always
+//! rounded to cents. Rounding goes half to even ("banker's rounding"), so that
+//! over many conversions the rounding errors do not all lean the same way.
+//!
+//! Exchange rates are plain `Decimal`s, with as many places as they need.
+//! Multiplying or dividing money by a rate rounds the result back to cents.
+//!
+//! In JSON, money is a string, such as `"12.30"`, since many JSON parsers
+//! read every number as a float. In Postgres, it is a `NUMERIC`, which is
+//! exact as well.
+//!
+
+use std::{
+    fmt,
+    iter::Sum,
+    ops::{Add, Div, Mul, Sub},
+    str::FromStr,
+};
+
+use rust_decimal::{Decimal, RoundingStrategy};
+
+#[derive(
+    Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash, serde::Deserialize, serde::Serialize, sqlx::Type,
+)]
+#[serde(from = "Decimal", into = "Decimal")]
+#[sqlx(transparent)]
+pub struct Money(Decimal);
+
+impl Money {
+    pub const ZERO: Money = Money(Decimal::ZERO);
+
+    ///
+    /// Rounds `amount` to cents, half to even.
+    ///
+    pub fn new(amount: Decimal) -> Self {
+        let mut amount = amount.round_dp_with_strategy(2, RoundingStrategy::MidpointNearestEven);
+        amount.rescale(2);
+        Money(amount)
+    }
+
+    pub fn amount(self) -> Decimal {
+        self.0
+    }
+}
+
+impl From<Decimal> for Money {
+    fn from(amount: Decimal) -> Self {
+        Money::new(amount)
+    }
+}
+
+impl From<Money> for Decimal {
+    fn from(money: Money) -> Self {
+        money.0
+    }
+}
+
+impl FromStr for Money {
+    type Err = rust_decimal::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Decimal::from_str(s.trim()).map(Money::new)
+    }
+}
+
+///
+/// Always with two decimal places: `130.00`, not `130`.
+///
+impl fmt::Display for Money {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(&self.0, f)
+    }
+}
+
+impl Add for Money {
+    type Output = Money;
+
+    fn add(self, other: Money) -> Money {
+        Money(self.0 + other.0)
+    }
+}
+
+impl Sub for Money {
+    type Output = Money;
+
+    fn sub(self, other: Money) -> Money {
+        Money(self.0 - other.0)
+    }
+}
+
+impl Mul<Decimal> for Money {
+    type Output = Money;
+
+    fn mul(self, rate: Decimal) -> Money {
+        Money::new(self.0 * rate)
+    }
+}
+
+impl Div<Decimal> for Money {
+    type Output = Money;
+
+    fn div(self, rate: Decimal) -> Money {
+        Money::new(self.0 / rate)
+    }
+}
+
+impl Sum for Money {
+    fn sum<I: Iterator<Item = Money>>(iter: I) -> Money {
+        iter.fold(Money::ZERO, Add::add)
+    }
+}
+
+#[test]
+fn money_rounds_to_cents_half_to_even() {
+    use rust_decimal_macros::dec;
+
+    let money = |s: &str| s.parse::<Money>().unwrap();
+
+    assert_eq!(money("100").to_string(), "100.00");
+    assert_eq!((money("100") * dec!(1.3)).to_string(), "130.00");
+    assert_eq!((money("10") / dec!(3)).to_string(), "3.33");
+    assert_eq!(money("0.125"), money("0.12"));
+    assert_eq!(money("0.135"), money("0.14"));
+
+    assert_eq!(serde_json::to_string(&money("12.3")).unwrap(), r#""12.30""#);
+    assert_eq!(serde_json::from_str::<Money>(r#""12.30""#).unwrap(), money("12.3"));
+}
+
+///
+/// EXERCISE 1
+///
+/// Ten prices of 10 cents should add up to a dollar. Run the test, and see
+/// what the `f64` total is instead.
+///
+/// The `prices` table stores amounts as `NUMERIC(12, 2)`, which sqlx maps to
+/// `Decimal`. `Money` derives `sqlx::Type` with `#[sqlx(transparent)]`, so it
+/// maps to whatever its `Decimal` maps to; queries still have to ask for it
+/// with a type override, such as `amount AS "amount: Money"`.
+///
+/// Postgres can add the prices up just as exactly: change the query to also
+/// select `SUM(amount)`, and compare it with the total computed in Rust.
+///
+#[tokio::test]
+async fn prices_add_up_exactly() {
+    use sqlx::postgres::PgPoolOptions;
+
+    #[derive(Debug)]
+    struct Price {
+        id: i64,
+        name: String,
+        amount: Money,
+        currency: String,
+    }
+
+    let pool = PgPoolOptions::new()
+        .max_connections(1)
+        .connect(&crate::test_db::database_url().await)
+        .await
+        .unwrap();
+
+    let float_total: f64 = (0..10).map(|_| 0.1).sum();
+    assert_ne!(float_total, 1.0);
+
+    // Rolled back at the end, so that the exercise leaves no prices behind.
+    let mut tx = pool.begin().await.unwrap();
+    let dime: Money = "0.10".parse().unwrap();
+    for i in 0..10 {
+        sqlx::query!(
+            "INSERT INTO prices (name, amount, currency) VALUES ($1, $2, 'USD')",
+            format!("Gumball {}", i),
+            dime as Money
+        )
+        .execute(&mut *tx)
+        .await
+        .unwrap();
+    }
+
+    let prices = sqlx::query_as!(
+        Price,
+        r#"SELECT id, name, amount AS "amount: Money", currency FROM prices WHERE name LIKE 'Gumball %' ORDER BY id"#
+    )
+    .fetch_all(&mut *tx)
+    .await
+    .unwrap();
+    tx.rollback().await.unwrap();
+
+    assert_eq!(prices.len(), 10);
+    let total: Money = prices.iter().map(|price| price.amount).sum();
+    assert_eq!(total.to_string(), "1.00");
+}
@@ -0,0 +1,470 @@
+#![allow(dead_code)]
+
+//!
+//! OPENAPI
+//! -------
+//!
+//! `openapi.json`, at the root of the repository, documents the JSON bodies
+//! that the todo routes accept. Documentation drifts: a field is renamed in a
+//! struct, or becomes required, and the spec goes on describing the old
+//! contract until a client trips over it.
+//!
+//! `validate_request_bodies` checks every body sent to a documented operation
+//! against the operation's schema, before the handler sees it. A body that
+//! does not match is answered with `422 Unprocessable Entity`, listing every
+//! violation rather than only the first:
+//!
+//! ```json
+//! {
+//!   "type": "about:blank", "title": "Unprocessable Entity", "status": 422,
+//!   "detail": "The body does not match the schema of POST /todo in openapi.json",
+//!   "violations": [{ "path": "priority", "message": "expected an integer, found \"high\"" }]
+//! }
+//! ```
+//!
+//! A body that matches, but that the handler still rejects with a 400 or a
+//! 422, means that the spec and the handler disagree, and is logged as a
+//! warning. Merge patches are the exception: their nulls remove members, so
+//! they are checked without them, and only the handler can tell whether the
+//! todo that is left is valid. The contract tests of `persistence.rs` send the examples of the
+//! spec to the handlers, and fail on either kind of mismatch.
+//!
+//! The validator understands the parts of OpenAPI 3.0 that the spec uses:
+//! `$ref`, `type`, `format` (`int32`, `int64` and `date-time`), `enum`,
+//! `nullable`, `required`, `properties`, `additionalProperties: false`,
+//! `items`, `allOf`, and `oneOf` with or without a `discriminator`.
+//!
+//! Like `FAULTS`, this is for development: release builds do not validate,
+//! and leave the handlers to reject what they cannot read.
+//!
+
+use std::sync::{Arc, OnceLock};
+
+use axum::{
+    body::Body,
+    extract::{Request, State},
+    http::{header, Method, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use serde_json::Value;
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+
+use crate::problem::Problem;
+
+/// The keys of a path item that are operations, rather than `parameters`
+/// or a `summary`.
+const METHODS: [&str; 8] = ["get", "put", "post", "delete", "options", "head", "patch", "trace"];
+
+/// The largest body that is buffered to be validated, as Axum's `Json`.
+const MAX_BODY_BYTES: usize = 2 * 1024 * 1024;
+
+///
+/// A parsed OpenAPI document. The default documents nothing, and so
+/// validates nothing.
+///
+#[derive(Clone, Debug, Default)]
+pub struct OpenApi(Arc<Value>);
+
+///
+/// Where a body breaks its schema, as a path like `operations[2].id`, and
+/// how. The path is omitted for the body as a whole.
+///
+#[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct Violation {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub path: Option<String>,
+    pub message: String,
+}
+
+impl OpenApi {
+    pub fn parse(json: &str) -> Result<OpenApi, serde_json::Error> {
+        serde_json::from_str(json).map(|document| OpenApi(Arc::new(document)))
+    }
+
+    ///
+    /// The spec of the todo API, `openapi.json`, parsed once.
+    ///
+    pub fn todos() -> OpenApi {
+        static SPEC: OnceLock<OpenApi> = OnceLock::new();
+        SPEC.get_or_init(|| OpenApi::parse(include_str!("../openapi.json")).unwrap()).clone()
+    }
+
+    ///
+    /// This spec in development, and an empty one in release builds.
+    ///
+    pub fn dev_only(self) -> OpenApi {
+        if cfg!(debug_assertions) {
+            self
+        } else {
+            OpenApi::default()
+        }
+    }
+
+    ///
+    /// The documented operations, as a method and an Axum path, such as
+    /// `("PUT", "/todo/:id")`.
+    ///
+    pub fn operations(&self) -> Vec<(String, String)> {
+        let Some(paths) = self.0.get("paths").and_then(Value::as_object) else {
+            return Vec::new();
+        };
+        paths
+            .iter()
+            .flat_map(|(path, item)| {
+                let axum_path = path
+                    .split('/')
+                    .map(|segment| match segment.strip_prefix('{').and_then(|s| s.strip_suffix('}')) {
+                        Some(name) => format!(":{}", name),
+                        None => segment.to_string(),
+                    })
+                    .collect::<Vec<_>>()
+                    .join("/");
+                item.as_object()
+                    .into_iter()
+                    .flatten()
+                    .filter(|(method, _)| METHODS.contains(&method.as_str()))
+                    .map(move |(method, _)| (method.to_uppercase(), axum_path.clone()))
+            })
+            .collect()
+    }
+
+    ///
+    /// The JSON schema of the body of the operation at `method` and `path`,
+    /// and its example, if it documents them. Templated segments such as
+    /// `{id}` match any segment.
+    ///
+    pub fn request_body(&self, method: &Method, path: &str) -> Option<(&Value, Option<&Value>)> {
+        let paths = self.0.get("paths")?.as_object()?;
+        let (_, item) = paths.iter().find(|(template, _)| matches_template(template, path))?;
+        let content = item.get(method.as_str().to_lowercase())?.pointer("/requestBody/content")?.as_object()?;
+        let (_, media) = content.iter().find(|(media_type, _)| is_json(media_type))?;
+        Some((media.get("schema")?, media.get("example")))
+    }
+
+    ///
+    /// Every way in which `value` breaks `schema`, none if it matches.
+    ///
+    pub fn validate(&self, schema: &Value, value: &Value) -> Vec<Violation> {
+        let mut violations = Vec::new();
+        self.check(schema, value, "", &mut violations);
+        violations
+    }
+
+    fn resolve<'a>(&'a self, mut schema: &'a Value) -> &'a Value {
+        while let Some(reference) = schema.get("$ref").and_then(Value::as_str) {
+            match reference.strip_prefix('#').and_then(|pointer| self.0.pointer(pointer)) {
+                Some(target) => schema = target,
+                None => panic!("Unresolved $ref {} in the OpenAPI spec", reference),
+            }
+        }
+        schema
+    }
+
+    fn check(&self, schema: &Value, value: &Value, path: &str, violations: &mut Vec<Violation>) {
+        let schema = self.resolve(schema);
+        let mut violation = |message: String| {
+            violations.push(Violation { path: (!path.is_empty()).then(|| path.to_string()), message })
+        };
+
+        if value.is_null() && schema.get("nullable").and_then(Value::as_bool).unwrap_or(false) {
+            return;
+        }
+
+        if let Some(expected) = schema.get("type").and_then(Value::as_str) {
+            if !has_type(value, expected) {
+                return violation(format!("expected {}, found {}", describe_type(expected), value));
+            }
+        }
+
+        match (schema.get("format").and_then(Value::as_str), value) {
+            (Some("int32"), Value::Number(n)) if n.as_i64().and_then(|n| i32::try_from(n).ok()).is_none() => {
+                violation(format!("{} does not fit in an int32", n))
+            }
+            (Some("int64"), Value::Number(n)) if n.as_i64().is_none() => {
+                violation(format!("{} does not fit in an int64", n))
+            }
+            (Some("date-time"), Value::String(s)) if OffsetDateTime::parse(s, &Rfc3339).is_err() => {
+                violation(format!("{:?} is not an RFC 3339 date-time", s))
+            }
+            _ => {}
+        }
+
+        if let Some(allowed) = schema.get("enum").and_then(Value::as_array) {
+            if !allowed.contains(value) {
+                let allowed = allowed.iter().map(Value::to_string).collect::<Vec<_>>().join(", ");
+                violation(format!("expected one of {}, found {}", allowed, value));
+            }
+        }
+
+        for part in schema.get("allOf").and_then(Value::as_array).into_iter().flatten() {
+            self.check(part, value, path, violations);
+        }
+
+        if let Some(variants) = schema.get("oneOf").and_then(Value::as_array) {
+            self.check_one_of(schema, variants, value, path, violations);
+        }
+
+        if let Value::Object(object) = value {
+            for field in schema.get("required").and_then(Value::as_array).into_iter().flatten() {
+                let field = field.as_str().unwrap_or_default();
+                if !object.contains_key(field) {
+                    violations.push(Violation { path: Some(join(path, field)), message: "missing field".to_string() });
+                }
+            }
+
+            let properties = schema.get("properties").and_then(Value::as_object);
+            for (field, value) in object {
+                match properties.and_then(|properties| properties.get(field)) {
+                    Some(property) => self.check(property, value, &join(path, field), violations),
+                    None if schema.get("additionalProperties") == Some(&Value::Bool(false)) => violations.push(Violation {
+                        path: Some(join(path, field)),
+                        message: "unknown field".to_string(),
+                    }),
+                    None => {}
+                }
+            }
+        }
+
+        if let (Value::Array(items), Some(item_schema)) = (value, schema.get("items")) {
+            for (index, item) in items.iter().enumerate() {
+                self.check(item_schema, item, &format!("{}[{}]", path, index), violations);
+            }
+        }
+    }
+
+    ///
+    /// With a discriminator, the variant is the one named by its property, so
+    /// its violations can be reported. Without one, exactly one variant must
+    /// match.
+    ///
+    fn check_one_of(&self, schema: &Value, variants: &[Value], value: &Value, path: &str, violations: &mut Vec<Violation>) {
+        let at = |field: Option<&str>| match field {
+            Some(field) => Some(join(path, field)),
+            None => (!path.is_empty()).then(|| path.to_string()),
+        };
+
+        if let Some(discriminator) = schema.get("discriminator") {
+            let property = discriminator.get("propertyName").and_then(Value::as_str).unwrap_or_default();
+            let mapping = discriminator.get("mapping").and_then(Value::as_object);
+            let names = mapping.map(|mapping| mapping.keys().cloned().collect::<Vec<_>>().join(", ")).unwrap_or_default();
+
+            let variant = match value.get(property) {
+                None => {
+                    let message = format!("missing field, one of {}", names);
+                    return violations.push(Violation { path: at(Some(property)), message });
+                }
+                Some(name) => name.as_str().and_then(|name| mapping?.get(name)),
+            };
+            match variant {
+                Some(reference) => self.check(&serde_json::json!({ "$ref": reference }), value, path, violations),
+                None => violations.push(Violation {
+                    path: at(Some(property)),
+                    message: format!("expected one of {}, found {}", names, value[property]),
+                }),
+            }
+            return;
+        }
+
+        let matching = variants.iter().filter(|variant| self.validate(variant, value).is_empty()).count();
+        if matching != 1 {
+            let message = format!("expected exactly one of {} schemas to match, {} did", variants.len(), matching);
+            violations.push(Violation { path: at(None), message });
+        }
+    }
+}
+
+///
+/// Checks the JSON bodies of the operations documented by the spec in the
+/// state, and answers those that break it with a `Problem` listing the
+/// violations. Other requests, and bodies that are not JSON at all, are left
+/// to the handlers.
+///
+pub async fn validate_request_bodies(State(spec): State<OpenApi>, request: Request, next: Next) -> Response {
+    let (method, path) = (request.method().clone(), request.uri().path().to_string());
+    let media_type = request
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .map(|content_type| content_type.split(';').next().unwrap_or_default().trim().to_string())
+        .unwrap_or_default();
+    let merge_patch = media_type == "application/merge-patch+json";
+    let schema = spec.request_body(&method, &path).map(|(schema, _)| schema.clone());
+    let Some(schema) = schema.filter(|_| is_json(&media_type)) else {
+        return next.run(request).await;
+    };
+
+    let (parts, body) = request.into_parts();
+    let Ok(bytes) = axum::body::to_bytes(body, MAX_BODY_BYTES).await else {
+        return Problem::new(StatusCode::PAYLOAD_TOO_LARGE).into_response();
+    };
+    let Ok(value) = serde_json::from_slice::<Value>(&bytes) else {
+        return next.run(Request::from_parts(parts, Body::from(bytes))).await;
+    };
+
+    // In a merge patch, null removes a member rather than setting it, so
+    // whether it may be removed is for the handler to say.
+    let value = if merge_patch { without_nulls(value) } else { value };
+    let violations = spec.validate(&schema, &value);
+    if !violations.is_empty() {
+        return Problem::new(StatusCode::UNPROCESSABLE_ENTITY)
+            .detail(format!("The body does not match the schema of {} {} in openapi.json", method, path))
+            .with("violations", violations)
+            .into_response();
+    }
+
+    let response = next.run(Request::from_parts(parts, Body::from(bytes))).await;
+    if !merge_patch && matches!(response.status(), StatusCode::BAD_REQUEST | StatusCode::UNPROCESSABLE_ENTITY) {
+        tracing::warn!(%method, %path, status = %response.status(), "The handler rejected a body that matches openapi.json");
+    }
+    response
+}
+
+fn matches_template(template: &str, path: &str) -> bool {
+    let (template, path) = (template.split('/').collect::<Vec<_>>(), path.split('/').collect::<Vec<_>>());
+    template.len() == path.len()
+        && template
+            .iter()
+            .zip(&path)
+            .all(|(expected, actual)| expected == actual || (expected.starts_with('{') && !actual.is_empty()))
+}
+
+fn is_json(media_type: &str) -> bool {
+    media_type == "application/json" || media_type.ends_with("+json")
+}
+
+fn without_nulls(value: Value) -> Value {
+    match value {
+        Value::Object(object) => Value::Object(
+            object
+                .into_iter()
+                .filter(|(_, value)| !value.is_null())
+                .map(|(key, value)| (key, without_nulls(value)))
+                .collect(),
+        ),
+        value => value,
+    }
+}
+
+fn has_type(value: &Value, expected: &str) -> bool {
+    match expected {
+        "object" => value.is_object(),
+        "array" => value.is_array(),
+        "string" => value.is_string(),
+        "boolean" => value.is_boolean(),
+        "number" => value.is_number(),
+        "integer" => value.is_i64() || value.is_u64(),
+        _ => true,
+    }
+}
+
+fn describe_type(expected: &str) -> String {
+    match expected {
+        "object" | "array" | "integer" => format!("an {}", expected),
+        _ => format!("a {}", expected),
+    }
+}
+
+fn join(path: &str, field: &str) -> String {
+    match path {
+        "" => field.to_string(),
+        path => format!("{}.{}", path, field),
+    }
+}
+
+#[test]
+fn bodies_are_checked_against_their_schemas() {
+    use serde_json::json;
+
+    let spec = OpenApi::todos();
+    let violations = |method: Method, path: &str, body: Value| {
+        let (schema, _) = spec.request_body(&method, path).unwrap();
+        spec.validate(schema, &body)
+            .into_iter()
+            .map(|violation| (violation.path.unwrap_or_default(), violation.message))
+            .collect::<Vec<_>>()
+    };
+    let at = |path: &str, message: &str| (path.to_string(), message.to_string());
+
+    assert_eq!(violations(Method::POST, "/todo", json!({ "title": "Write", "description": "" })), vec![]);
+    assert_eq!(
+        violations(Method::POST, "/todo", json!({ "title": "Write", "priority": "high" })),
+        vec![at("description", "missing field"), at("priority", r#"expected an integer, found "high""#)]
+    );
+    assert_eq!(
+        violations(Method::POST, "/todo", json!({ "title": "Write", "description": "", "due_at": "tomorrow" })),
+        vec![at("due_at", r#""tomorrow" is not an RFC 3339 date-time"#)]
+    );
+
+    // `nullable` allows null, and only there.
+    assert_eq!(violations(Method::PUT, "/todo/1", json!({ "status": null, "due_at": null })), vec![]);
+    assert_eq!(
+        violations(Method::PUT, "/todo/1", json!({ "status": "later" })),
+        vec![at("status", r#"expected one of "open", "in_progress", "done", "cancelled", found "later""#)]
+    );
+    assert_eq!(
+        violations(Method::PATCH, "/todo/1", json!({ "status": null, "id": 2 })),
+        vec![at("id", "unknown field"), at("status", "expected a string, found null")]
+    );
+
+    // The discriminator picks the variant whose violations are reported.
+    assert_eq!(
+        violations(
+            Method::POST,
+            "/todo/bulk",
+            json!({ "operations": [{ "op": "create", "title": "A", "description": "" }, { "op": "update" }, { "op": "move" }, {}] })
+        ),
+        vec![
+            at("operations[1].id", "missing field"),
+            at("operations[2].op", r#"expected one of create, delete, update, found "move""#),
+            at("operations[3].op", "missing field, one of create, delete, update"),
+        ]
+    );
+    assert_eq!(
+        violations(Method::PUT, "/todo/1/parent", json!({ "parent_id": 10_000_000_000_000_000_000u64 })),
+        vec![at("parent_id", "10000000000000000000 does not fit in an int64")]
+    );
+}
+
+#[tokio::test]
+async fn bodies_that_break_the_spec_never_reach_the_handler() {
+    // for Body::collect
+    use http_body_util::BodyExt;
+    /// for ServiceExt::oneshot
+    use tower::util::ServiceExt;
+    use axum::{routing::post, Router};
+
+    let app = Router::new()
+        .route("/todo/:id/comments", post(|| async { StatusCode::CREATED }))
+        .layer(axum::middleware::from_fn_with_state(OpenApi::todos(), validate_request_bodies));
+
+    let send = |content_type: &'static str, body: &'static str| {
+        let app = app.clone();
+        async move {
+            let request = axum::http::Request::builder()
+                .method(Method::POST)
+                .uri("/todo/1/comments")
+                .header("Content-Type", content_type)
+                .body(Body::from(body))
+                .unwrap();
+            let response = app.oneshot(request).await.unwrap();
+            let status = response.status();
+            (status, response.into_body().collect().await.unwrap().to_bytes())
+        }
+    };
+
+    assert_eq!(send("application/json", r#"{ "body": "Soon" }"#).await.0, StatusCode::CREATED);
+
+    let (status, body) = send("application/json", r#"{ "body": 42 }"#).await;
+    assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+    let problem: Value = serde_json::from_slice(&body).unwrap();
+    let violations: Vec<Violation> = serde_json::from_value(problem["violations"].clone()).unwrap();
+    assert_eq!(
+        violations,
+        vec![Violation { path: Some("body".to_string()), message: "expected a string, found 42".to_string() }]
+    );
+
+    // Neither malformed JSON nor other media types are the validator's to reject.
+    assert_eq!(send("application/json", r#"{ "body": "#).await.0, StatusCode::CREATED);
+    assert_eq!(send("text/plain", r#"{ "body": 42 }"#).await.0, StatusCode::CREATED);
+}
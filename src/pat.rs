@@ -0,0 +1,498 @@
+#![allow(dead_code)]
+
+//!
+//! PERSONAL ACCESS TOKENS
+//! ----------------------
+//!
+//! Scripts and integrations should not hold a user's password, nor a
+//! session that can do everything the user can. Personal access tokens
+//! (PATs) are for them: long-lived bearer tokens that a user creates at
+//! `POST /users/me/tokens`, with a name, the scopes they need, and an
+//! optional expiry, and that can be listed and revoked one by one.
+//!
+//! A PAT is `pat_` followed by a random part, and is only shown once, when
+//! created: like refresh tokens, it is stored hashed. The prefix tells it
+//! from an access token in `Authorization: Bearer`, and lets secret
+//! scanners find leaked ones.
+//!
+//! `authenticate_pats` looks PATs up, for the whole app, and rejects those
+//! that are unknown, revoked or expired. That does not let them in anywhere
+//! yet: a route accepts PATs only if it declares the scope they need, with
+//! the `require_scope` layer, which then hands the handler the same `Claims`
+//! as a session would. Routes that declare no scope, such as those that
+//! manage sessions, or tokens, keep answering PATs with `401`, and PATs
+//! without the scope get `403`. Session tokens are not limited by scopes.
+//!
+//! - `todo:read` lets a PAT read todos and their comments.
+//! - `todo:write` lets it create, change and delete them.
+//!
+
+use axum::{
+    async_trait,
+    extract::{FromRef, Path, Request, State},
+    http::{header, StatusCode},
+    middleware::{FromFnLayer, Next},
+    response::{IntoResponse, Response},
+    Json,
+};
+use futures::future::BoxFuture;
+use sqlx::{Pool, Postgres};
+use std::{fmt, str::FromStr};
+use time::{Duration, OffsetDateTime, PrimitiveDateTime};
+
+use crate::app::Routes;
+use crate::auth::{hash_token, random_token, AuthError, Claims, JwtKeys};
+use crate::clock::SharedClock;
+use crate::problem::Problem;
+
+const PAT_PREFIX: &str = "pat_";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Scope {
+    TodoRead,
+    TodoWrite,
+}
+
+impl FromStr for Scope {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "todo:read" => Ok(Scope::TodoRead),
+            "todo:write" => Ok(Scope::TodoWrite),
+            _ => Err(format!("Unknown scope: {}", s)),
+        }
+    }
+}
+
+impl fmt::Display for Scope {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Scope::TodoRead => "todo:read",
+            Scope::TodoWrite => "todo:write",
+        })
+    }
+}
+
+impl serde::Serialize for Scope {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for Scope {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct PersonalAccessToken {
+    pub id: i64,
+    pub user_id: i64,
+    pub name: String,
+    pub token_hash: String,
+    pub scopes: Vec<String>,
+    pub mfa: bool,
+    pub created_at: PrimitiveDateTime,
+    pub expires_at: Option<PrimitiveDateTime>,
+    pub last_used_at: Option<PrimitiveDateTime>,
+}
+
+impl PersonalAccessToken {
+    ///
+    /// The scopes of the token. Scopes that this version does not know, from
+    /// a newer one, grant nothing.
+    ///
+    pub fn scopes(&self) -> Vec<Scope> {
+        self.scopes.iter().filter_map(|scope| scope.parse().ok()).collect()
+    }
+
+    pub fn to_dto(&self) -> PersonalAccessTokenDTO {
+        PersonalAccessTokenDTO {
+            id: self.id,
+            name: self.name.clone(),
+            scopes: self.scopes(),
+            created_at: self.created_at.assume_utc(),
+            expires_at: self.expires_at.map(PrimitiveDateTime::assume_utc),
+            last_used_at: self.last_used_at.map(PrimitiveDateTime::assume_utc),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct PersonalAccessTokenDTO {
+    pub id: i64,
+    pub name: String,
+    pub scopes: Vec<Scope>,
+    #[serde(with = "time::serde::rfc3339")]
+    pub created_at: OffsetDateTime,
+    #[serde(with = "time::serde::rfc3339::option")]
+    pub expires_at: Option<OffsetDateTime>,
+    #[serde(with = "time::serde::rfc3339::option")]
+    pub last_used_at: Option<OffsetDateTime>,
+}
+
+#[async_trait]
+pub trait PatRepo: Send + Sync {
+    async fn insert(
+        &self,
+        user_id: i64,
+        name: &str,
+        token_hash: &str,
+        scopes: &[Scope],
+        mfa: bool,
+        expires_at: Option<PrimitiveDateTime>,
+    ) -> i64;
+    async fn find(&self, token_hash: &str) -> Option<PersonalAccessToken>;
+    async fn tokens(&self, user_id: i64) -> Vec<PersonalAccessToken>;
+    async fn record_use(&self, id: i64, now: PrimitiveDateTime);
+    async fn delete(&self, user_id: i64, id: i64) -> bool;
+}
+
+#[derive(Clone)]
+pub struct PatRepoPostgres {
+    pool: Pool<Postgres>,
+}
+
+impl PatRepoPostgres {
+    pub fn new(pool: Pool<Postgres>) -> Self {
+        PatRepoPostgres { pool }
+    }
+}
+
+#[async_trait]
+impl PatRepo for PatRepoPostgres {
+    async fn insert(
+        &self,
+        user_id: i64,
+        name: &str,
+        token_hash: &str,
+        scopes: &[Scope],
+        mfa: bool,
+        expires_at: Option<PrimitiveDateTime>,
+    ) -> i64 {
+        let scopes: Vec<String> = scopes.iter().map(Scope::to_string).collect();
+        let query = sqlx::query!(
+            "INSERT INTO personal_access_tokens (user_id, name, token_hash, scopes, mfa, expires_at)
+             VALUES ($1, $2, $3, $4, $5, $6) RETURNING id",
+            user_id,
+            name,
+            token_hash,
+            &scopes,
+            mfa,
+            expires_at
+        );
+        query.fetch_one(&self.pool).await.unwrap().id
+    }
+    async fn find(&self, token_hash: &str) -> Option<PersonalAccessToken> {
+        let query = sqlx::query_as!(
+            PersonalAccessToken,
+            "SELECT id, user_id, name, token_hash, scopes, mfa, created_at, expires_at, last_used_at
+             FROM personal_access_tokens WHERE token_hash = $1",
+            token_hash
+        );
+        query.fetch_optional(&self.pool).await.unwrap()
+    }
+    async fn tokens(&self, user_id: i64) -> Vec<PersonalAccessToken> {
+        let query = sqlx::query_as!(
+            PersonalAccessToken,
+            "SELECT id, user_id, name, token_hash, scopes, mfa, created_at, expires_at, last_used_at
+             FROM personal_access_tokens WHERE user_id = $1 ORDER BY id",
+            user_id
+        );
+        query.fetch_all(&self.pool).await.unwrap()
+    }
+    async fn record_use(&self, id: i64, now: PrimitiveDateTime) {
+        let query = sqlx::query!("UPDATE personal_access_tokens SET last_used_at = $2 WHERE id = $1", id, now);
+        query.execute(&self.pool).await.unwrap();
+    }
+    async fn delete(&self, user_id: i64, id: i64) -> bool {
+        let query = sqlx::query!("DELETE FROM personal_access_tokens WHERE user_id = $1 AND id = $2", user_id, id);
+        query.execute(&self.pool).await.unwrap().rows_affected() == 1
+    }
+}
+
+#[derive(Clone)]
+pub struct PatState<P: PatRepo> {
+    pub repo: P,
+    pub clock: SharedClock,
+}
+
+///
+/// What a valid PAT grants, for `require_scope` to check against the scope
+/// of the route.
+///
+#[derive(Clone, Debug, PartialEq)]
+pub struct PatGrant {
+    pub user_id: i64,
+    pub scopes: Vec<Scope>,
+    pub mfa: bool,
+    /// When the PAT was checked, by the clock of `PatState`.
+    pub authenticated_at: OffsetDateTime,
+}
+
+impl<P: PatRepo> PatState<P> {
+    ///
+    /// Creates a token, returning it along with the value that is shown
+    /// once.
+    ///
+    pub async fn create(
+        &self,
+        user_id: i64,
+        name: &str,
+        scopes: &[Scope],
+        mfa: bool,
+        expires_in: Option<Duration>,
+    ) -> (PersonalAccessToken, String) {
+        let token = format!("{}{}", PAT_PREFIX, random_token());
+        let expires_at = expires_in.map(|ttl| self.clock.now_utc_primitive() + ttl);
+        let token_hash = hash_token(&token);
+        self.repo.insert(user_id, name, &token_hash, scopes, mfa, expires_at).await;
+        (self.repo.find(&token_hash).await.unwrap(), token)
+    }
+
+    pub async fn authenticate(&self, token: &str) -> Result<PatGrant, AuthError> {
+        let pat = self.repo.find(&hash_token(token)).await.ok_or(AuthError::InvalidToken)?;
+        let authenticated_at = self.clock.now();
+        let now = self.clock.now_utc_primitive();
+        if pat.expires_at.is_some_and(|expires_at| expires_at <= now) {
+            return Err(AuthError::ExpiredToken);
+        }
+        self.repo.record_use(pat.id, now).await;
+        Ok(PatGrant { user_id: pat.user_id, scopes: pat.scopes(), mfa: pat.mfa, authenticated_at })
+    }
+}
+
+///
+/// Checks the PAT of requests that bear one, leaving a `PatGrant` for
+/// `require_scope`. Other requests go on untouched.
+///
+pub async fn authenticate_pats<P: PatRepo>(
+    State(pats): State<PatState<P>>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    let token = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .filter(|token| token.starts_with(PAT_PREFIX));
+    let Some(token) = token else {
+        return next.run(request).await;
+    };
+    match pats.authenticate(token).await {
+        Ok(grant) => {
+            request.extensions_mut().insert(grant);
+            next.run(request).await
+        }
+        Err(error) => error.into_response(),
+    }
+}
+
+type ScopeCheck = fn(State<Scope>, Request, Next) -> BoxFuture<'static, Response>;
+
+pub type RequireScopeLayer = FromFnLayer<ScopeCheck, Scope, (State<Scope>, Request)>;
+
+///
+/// Declares the scope that PATs need on the routes it is applied to, with
+/// `Routes::layer`. Requests with a session token are not affected.
+///
+pub fn require_scope(scope: Scope) -> RequireScopeLayer {
+    axum::middleware::from_fn_with_state(scope, check_scope as ScopeCheck)
+}
+
+fn check_scope(State(scope): State<Scope>, mut request: Request, next: Next) -> BoxFuture<'static, Response> {
+    Box::pin(async move {
+        let Some(grant) = request.extensions().get::<PatGrant>().cloned() else {
+            return next.run(request).await;
+        };
+        if !grant.scopes.contains(&scope) {
+            return Problem::new(StatusCode::FORBIDDEN)
+                .detail(format!("This token lacks the {} scope", scope))
+                .with("required_scope", scope)
+                .into_response();
+        }
+        // Valid for this request only, which is all that handlers look at.
+        let now = grant.authenticated_at.unix_timestamp();
+        let claims = Claims { sub: grant.user_id, exp: now, iat: now, mfa: grant.mfa };
+        request.extensions_mut().insert(claims);
+        next.run(request).await
+    })
+}
+
+///
+/// The routes that manage PATs. They only take session tokens, so that a
+/// leaked PAT cannot make itself others.
+///
+pub fn pat_routes<S, P>() -> Routes<S>
+where
+    P: PatRepo + Clone + 'static,
+    PatState<P>: FromRef<S>,
+    JwtKeys: FromRef<S>,
+    S: Clone + Send + Sync + 'static,
+{
+    Routes::new()
+        .post("/users/me/tokens", create_token::<P>)
+        .get("/users/me/tokens", list_tokens::<P>)
+        .delete("/users/me/tokens/:id", revoke_token::<P>)
+}
+
+#[derive(Debug, serde::Deserialize, serde::Serialize)]
+struct NewToken {
+    name: String,
+    scopes: Vec<Scope>,
+    /// Without it, the token lasts until it is revoked.
+    expires_in_days: Option<u32>,
+}
+
+#[derive(Debug, serde::Deserialize, serde::Serialize)]
+struct CreatedToken {
+    #[serde(flatten)]
+    details: PersonalAccessTokenDTO,
+    /// Shown once, and never again.
+    token: String,
+}
+
+async fn create_token<P: PatRepo>(
+    claims: Claims,
+    State(pats): State<PatState<P>>,
+    Json(NewToken { name, scopes, expires_in_days }): Json<NewToken>,
+) -> Result<(StatusCode, Json<CreatedToken>), (StatusCode, String)> {
+    if scopes.is_empty() {
+        return Err((StatusCode::UNPROCESSABLE_ENTITY, "A token needs at least one scope".to_string()));
+    }
+    let expires_in = expires_in_days.map(|days| Duration::days(days.into()));
+    let (pat, token) = pats.create(claims.sub, &name, &scopes, claims.mfa, expires_in).await;
+    tracing::info!(target: "audit", user_id = claims.sub, token_id = pat.id, ?scopes, "Personal access token created");
+    Ok((StatusCode::CREATED, Json(CreatedToken { details: pat.to_dto(), token })))
+}
+
+async fn list_tokens<P: PatRepo>(
+    claims: Claims,
+    State(pats): State<PatState<P>>,
+) -> Json<Vec<PersonalAccessTokenDTO>> {
+    Json(pats.repo.tokens(claims.sub).await.iter().map(PersonalAccessToken::to_dto).collect())
+}
+
+async fn revoke_token<P: PatRepo>(claims: Claims, State(pats): State<PatState<P>>, Path(id): Path<i64>) -> StatusCode {
+    if !pats.repo.delete(claims.sub, id).await {
+        return StatusCode::NOT_FOUND;
+    }
+    tracing::info!(target: "audit", user_id = claims.sub, token_id = id, "Personal access token revoked");
+    StatusCode::NO_CONTENT
+}
+
+#[tokio::test]
+async fn tokens_only_reach_the_routes_of_their_scopes() {
+    // for Body::collect
+    use http_body_util::BodyExt;
+    /// for ServiceExt::oneshot
+    use tower::util::ServiceExt;
+    use axum::{body::Body, http::Method};
+    use serde_json::{json, Value};
+    use sqlx::postgres::PgPoolOptions;
+    use std::sync::Arc;
+
+    use crate::clock::SystemClock;
+
+    #[derive(Clone, FromRef)]
+    struct TestState {
+        pats: PatState<PatRepoPostgres>,
+        keys: JwtKeys,
+    }
+
+    let pool = PgPoolOptions::new()
+        .max_connections(1)
+        .connect(&crate::test_db::database_url().await)
+        .await
+        .unwrap();
+    let user_id = crate::users::create_test_user(&pool, false).await;
+    let keys = JwtKeys::from_secret(b"secret");
+    let state = TestState { pats: PatState { repo: PatRepoPostgres::new(pool), clock: Arc::new(SystemClock) }, keys };
+    let session = crate::auth::AuthState { repo: crate::auth::RefreshTokenRepoInMemory::default(), keys: state.keys.clone() }
+        .issue_tokens(user_id)
+        .await
+        .access_token;
+
+    async fn whoami(Claims { sub, .. }: Claims) -> String {
+        sub.to_string()
+    }
+    let reads = Routes::new().get("/todo", whoami).layer(require_scope(Scope::TodoRead));
+    let writes = Routes::new().post("/todo", whoami).layer(require_scope(Scope::TodoWrite));
+    let app = Routes::new()
+        .merge(reads)
+        .merge(writes)
+        .get("/unscoped", whoami)
+        .merge(pat_routes::<_, PatRepoPostgres>())
+        .into_router()
+        .layer(axum::middleware::from_fn_with_state(state.clone(), authenticate_pats::<PatRepoPostgres>))
+        .with_state(state);
+    let send = |method: Method, uri: &str, token: &str, body: Value| {
+        let request = Request::builder()
+            .method(method)
+            .uri(uri)
+            .header("Authorization", format!("Bearer {}", token))
+            .header("Content-Type", "application/json")
+            .body(Body::from(body.to_string()))
+            .unwrap();
+        let app = app.clone();
+        async move {
+            let response = app.oneshot(request).await.unwrap();
+            let status = response.status();
+            let body = response.into_body().collect().await.unwrap().to_bytes();
+            (status, serde_json::from_slice::<Value>(&body).unwrap_or_default())
+        }
+    };
+
+    let new_token = json!({ "name": "Backup script", "scopes": ["todo:read"] });
+    let (status, created) = send(Method::POST, "/users/me/tokens", &session, new_token).await;
+    assert_eq!(status, StatusCode::CREATED);
+    assert_eq!(created["scopes"], json!(["todo:read"]));
+    let pat = created["token"].as_str().unwrap().to_string();
+    assert!(pat.starts_with(PAT_PREFIX));
+    let (status, _) = send(Method::POST, "/users/me/tokens", &session, json!({ "name": "x", "scopes": ["admin"] })).await;
+    assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+
+    assert_eq!(send(Method::GET, "/todo", &pat, Value::Null).await, (StatusCode::OK, json!(user_id)));
+    let (status, problem) = send(Method::POST, "/todo", &pat, Value::Null).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    assert_eq!(problem["required_scope"], "todo:write");
+    assert_eq!(send(Method::GET, "/unscoped", &pat, Value::Null).await.0, StatusCode::UNAUTHORIZED);
+    // Sessions are not limited by scopes, and PATs cannot make more PATs.
+    assert_eq!(send(Method::POST, "/todo", &session, Value::Null).await.0, StatusCode::OK);
+    let new_token = json!({ "name": "Escalation", "scopes": ["todo:write"] });
+    assert_eq!(send(Method::POST, "/users/me/tokens", &pat, new_token).await.0, StatusCode::UNAUTHORIZED);
+
+    let (_, tokens) = send(Method::GET, "/users/me/tokens", &session, Value::Null).await;
+    assert_eq!(tokens.as_array().unwrap().len(), 1);
+    assert!(tokens[0].get("token").is_none() && !tokens[0]["last_used_at"].is_null());
+    let uri = format!("/users/me/tokens/{}", created["id"]);
+    assert_eq!(send(Method::DELETE, &uri, &session, Value::Null).await.0, StatusCode::NO_CONTENT);
+    assert_eq!(send(Method::GET, "/todo", &pat, Value::Null).await.0, StatusCode::UNAUTHORIZED);
+}
+
+#[tokio::test]
+async fn scoped_claims_are_issued_at_the_time_of_the_grant() {
+    /// for ServiceExt::oneshot
+    use tower::util::ServiceExt;
+    use axum::body::Body;
+    use time::macros::datetime;
+
+    async fn issued_at(Claims { iat, .. }: Claims) -> String {
+        iat.to_string()
+    }
+    let authenticated_at = datetime!(2026-10-16 12:00 UTC);
+    let grant = PatGrant { user_id: 1, scopes: vec![Scope::TodoRead], mfa: false, authenticated_at };
+    let app = Routes::<JwtKeys>::new()
+        .get("/todo", issued_at)
+        .layer(require_scope(Scope::TodoRead))
+        .into_router()
+        .layer(axum::Extension(grant))
+        .with_state(JwtKeys::from_secret(b"secret"));
+
+    let response = app.oneshot(Request::get("/todo").body(Body::empty()).unwrap()).await.unwrap();
+    let body = http_body_util::BodyExt::collect(response.into_body()).await.unwrap().to_bytes();
+    assert_eq!(body, authenticated_at.unix_timestamp().to_string());
+}
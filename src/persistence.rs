@@ -30,12 +30,99 @@
 //!
 //! 3. Run `sqlx database create` to create the database.
 //!
-//! 4. Run `sqlx migrate run` to run the migrations in the `migrations` folder.
+//! 4. Run `sqlx migrate run` to run the migrations in the `migrations` folder
+//!    (the app also runs them when it starts).
+//!
+//! Without Postgres, build with `--no-default-features`, which leaves out
+//! the `postgres` feature, and with it every exercise that needs a database.
+//! Each exercise about todos has an in-memory variant right after it, which
+//! does the same through the `TodoRepo` trait, with a `TodoRepoInMemory`:
+//!
+//! ```sh
+//! cargo test --no-default-features in_memory
+//! ```
+//!
+//! Exercises 2, 9 and 10 are about Postgres itself (its SQL, its connection
+//! pool, and its round trips), and have no in-memory variant.
+//!
+//! Exercise 14 leaves relational databases behind, for DynamoDB, with the
+//! `dynamodb` feature.
 //!
 
-use axum::{async_trait, extract::{Path, State}, routing::{delete, get, post, put}, Json, Router};
+use std::{collections::{BTreeMap, HashMap, HashSet, VecDeque}, convert::Infallible, future::Future, sync::{Arc, Mutex}, time::{Duration, Instant}};
+
+use crate::admin::{admin_routes, AdminState};
+use crate::app::{AppBuilder, Routes};
+use crate::attachments::{
+    attachment_routes, AttachmentRepoPostgres, AttachmentState, ObjectStoreFs, ShareQuery, SharedBy, SharedObjectStore,
+};
+use crate::auth::{auth_routes, AuthState, Claims, JwtKeys, RefreshTokenRepoPostgres, SharedRefreshTokenRepo};
+#[cfg(feature = "redis")]
+use crate::auth::RefreshTokenRepoRedis;
+use crate::client_ip::TrustedProxies;
+use crate::clock::{SharedClock, SystemClock};
+use crate::coalesce::SingleFlight;
+use crate::config::{AppConfig, PoolConfig, SessionStoreKind, TodoRepoKind};
+use crate::cursor;
+use crate::degraded::{
+    catch_panics, database_unavailable, readiness_routes, reject_writes_when_down, watch_database, DbHealth,
+};
+use crate::erasure::{erasure_routes, ErasureRepoPostgres, ErasureState};
+use crate::exposition::RenderedMetrics;
+use crate::extract::{to_json, AppJson, ExtractError, QsQuery};
+use crate::faults::inject_faults;
+use crate::feed::{feed_routes, FeedRepoPostgres, FeedState};
+use crate::geoip::{enrich_with_geo, GeoIp};
+use crate::leader::run_as_leader;
+use crate::lists::{list_routes, ListRepoPostgres, ListState};
+use crate::maintenance::{maintenance_routes, reject_during_maintenance, Maintenance};
+use crate::openapi::{validate_request_bodies, OpenApi};
+use crate::recurrence::{recurrence_routes, run_recurrence_scheduler, RecurrenceRepoPostgres, RecurrenceState};
+use crate::remember_me::{remember_me_routes, restore_session, RememberMeRepoPostgres, RememberMeState};
+use crate::runtime_metrics::report_runtime_metrics;
+use crate::schema_drift::{table, SchemaDrift, Table, TableSchema};
+use crate::shadow::{shadow_requests, Shadow};
+use crate::shedding::{admission_control, shed_load, AdaptiveConfig, AdaptiveLimit};
+use crate::signed_urls::{SignedLink, SignedUrl, UrlSigner};
+use crate::takeout::{takeout_routes, TakeoutRepoPostgres, TakeoutState};
+use crate::templates::{set_flash, take_flash, HtmlTemplate, TodoFormPage};
+use crate::http_cache::{cache_responses, invalidate_on_events, HttpCache};
+use crate::ids::{IdGenerator, SequenceIds, UuidV7Ids};
+use crate::inflight::{in_flight_routes, track_in_flight, InFlight};
+use crate::loader::{DataLoader, Loader};
+use crate::logging::{init_logging, log_level_routes, LogLevel};
+use crate::pat::{authenticate_pats, pat_routes, require_scope, PatRepoPostgres, PatState, Scope};
+use crate::pii::{rotate_emails, PiiCipher, StaticKeys};
+use crate::profiling::profiling_routes;
+use crate::secrets::Secrets;
+use crate::todo_repos::conformance::{conformance_tests, run_todo_handler_suite, test_todo_app_as};
+#[cfg(feature = "any")]
+use crate::todo_repos::any::{AnyDialect, TodoRepoAny};
+#[cfg(feature = "dynamodb")]
+use crate::todo_repos::dynamo::TodoRepoDynamo;
+#[cfg(feature = "mongodb")]
+use crate::todo_repos::mongo::TodoRepoMongo;
+#[cfg(feature = "mysql")]
+use crate::todo_repos::mysql::TodoRepoMySql;
+use crate::todo_repos::rls::TodoRepoRls;
+#[cfg(feature = "sea-orm")]
+use crate::todo_repos::sea_orm::TodoRepoSeaOrm;
+#[cfg(feature = "sqlite")]
+use crate::todo_repos::sqlite::TodoRepoSqlite;
+use crate::totp::{require_two_factor_for_admins, TotpRepoPostgres, TwoFactorState};
+use crate::users::{user_routes, User, UserRepo, UserRepoPostgres, UserState};
+use crate::uuid_todos::{uuid_todo_routes, UuidTodoRepoPostgres, UuidTodoState};
+use crate::webauthn::{passkey_routes, PasskeyRepoPostgres, WebAuthnState};
+use crate::websocket::{socket_routes, SocketConfig, SocketState, TodoEventKind, TodoEvents};
+use axum::{async_trait, body::{Body, Bytes}, extract::{FromRef, Path, Query, State}, http::{header, StatusCode}, response::{IntoResponse, Redirect, Response}, routing::{delete, get, post, put}, Form, Json, Router};
+use axum_extra::{extract::cookie::CookieJar, routing::TypedPath};
+use futures::{stream::BoxStream, StreamExt, TryStreamExt};
+use http_body_util::BodyExt;
+use axum_prometheus::{metrics_exporter_prometheus::PrometheusHandle, PrometheusMetricLayer};
 use serde::de;
-use sqlx::{pool, postgres::PgPoolOptions, types::time::PrimitiveDateTime, Pool, Postgres};
+use sqlx::{pool, postgres::PgPoolOptions, types::time::{Date, OffsetDateTime, PrimitiveDateTime}, PgConnection, Pool, Postgres, QueryBuilder};
+use time::format_description::well_known::Iso8601;
+use tokio::sync::RwLock;
 
 ///
 /// EXERCISE 1
@@ -51,12 +138,32 @@ use sqlx::{pool, postgres::PgPoolOptions, types::time::PrimitiveDateTime, Pool,
 /// need to supply a database pool, which you can do so with the `fetch` family of
 /// methods.
 ///
+#[cfg(feature = "postgres")]
 async fn query_playground() {
     let _ = sqlx::query!("SELECT 1 + 1 AS sum");
 
     let _ = sqlx::query::<Postgres>("SELECT 1 + 1 AS sum");
 }
 
+///
+/// EXERCISE 1 (IN MEMORY)
+///
+/// Without Postgres, todos are kept by a `TodoRepoInMemory`, behind the same
+/// `TodoRepo` trait as the `TodoRepoPostgres` that the app uses. Experiment
+/// with its methods, such as `create_todo` and `get_todos`.
+///
+/// Like a query, calling a method does not do anything by itself: it returns
+/// a future, which only does the work when it is awaited. Is the todo below
+/// ever created?
+///
+async fn repo_playground() {
+    let repo = TodoRepoInMemory::default();
+
+    let _created = repo.create_todo(1, "Learn Axum", "", None, 0);
+
+    let _ = repo.get_todos(1, TodoSort::Id).await.unwrap();
+}
+
 ///
 /// EXERCISE 2
 ///
@@ -66,11 +173,12 @@ async fn query_playground() {
 /// Then modify the test to reference a row, which you can obtain by using the
 /// `fetch_one` method on the query result, and awaiting and unwrapping it.
 ///
+#[cfg(feature = "postgres")]
 #[tokio::test]
 async fn select_one_plus_one() {
     let pool = PgPoolOptions::new()
         .max_connections(1)
-        .connect(&std::env::var("DATABASE_URL").unwrap())
+        .connect(&crate::test_db::database_url().await)
         .await
         .unwrap();
 
@@ -86,29 +194,55 @@ async fn select_one_plus_one() {
 /// In this example, we are going to show the strength of sqlx by
 /// doing a select star query.
 ///
-/// Use the `sqlx::query!` macro to select all columns from the `todos` table.
-/// Use a `fetch_all`, and iterate over them, printing out each row.
+/// Use the `sqlx::query!` macro to select all columns from the `comments`
+/// table. Use a `fetch_all`, and iterate over them, printing out each row.
 ///
 /// What do you notice about the type of the row?
 ///
+/// (Why not the `todos` table? Try it, and read the error: its `status`
+/// column has a type that sqlx does not know. Exercise 7 shows the fix.)
+///
+#[cfg(feature = "postgres")]
 #[tokio::test]
 async fn select_star() {
     let pool = PgPoolOptions::new()
         .max_connections(1)
-        .connect(&std::env::var("DATABASE_URL").unwrap())
+        .connect(&crate::test_db::database_url().await)
         .await
         .unwrap();
 
-    let todos = sqlx::query!("SELECT * from todos")
+    let comments = sqlx::query!("SELECT * from comments")
         .fetch_all(&pool).await.unwrap();
 
-    for todo in todos {
-        println!("{:?}", todo);
+    for comment in comments {
+        println!("{:?}", comment);
     }
 
     assert!(true);
 }
 
+///
+/// EXERCISE 3 (IN MEMORY)
+///
+/// Use the `get_comments` method of the repo to get all the comments of the
+/// todo below, and iterate over them, printing out each one.
+///
+/// How does the type of a comment compare with the type of a row in the
+/// exercise above? Which would you rather have, and what does it cost?
+///
+#[tokio::test]
+async fn select_star_in_memory() {
+    let repo = TodoRepoInMemory::default();
+    let todo_id = repo.create_todo(1, "Learn SQLx", "", None, 0).await.unwrap();
+    repo.create_comment(1, todo_id, "Start with query!").await.unwrap().unwrap();
+
+    let comments = repo.get_comments(1, todo_id).await.unwrap();
+
+    for comment in comments {
+        println!("{:?}", comment);
+    }
+}
+
 ///
 /// EXERCISE 4
 ///
@@ -117,29 +251,33 @@ async fn select_star() {
 /// main query.
 ///
 /// Use the `query!` macro to insert a row into the `todo` table, keeping
-/// in mind every todo has a title, description, and a boolean indicating
-/// whether it is done.
+/// in mind every todo has a title, description, and a status.
+///
+/// The status is a Postgres enum, mapped to `TodoStatus`. The macro only
+/// knows the type of a parameter from the database, so tell it which Rust
+/// type to expect with `status as TodoStatus`.
 ///
 /// Using the `RETURNING` keyword, return the id of the inserted row,
 /// and assert it is greater than zero.
 ///
+#[cfg(feature = "postgres")]
 #[tokio::test]
 async fn insert_todo() {
     let _pool = PgPoolOptions::new()
         .max_connections(1)
-        .connect(&std::env::var("DATABASE_URL").unwrap())
+        .connect(&crate::test_db::database_url().await)
         .await
         .unwrap();
 
     let _title = "Learn SQLx";
     let _description = "I should really learn SQLx for my Axum web app";
-    let _done = false;
+    let _status = TodoStatus::Open;
 
     let query = sqlx::query!(
-        "INSERT INTO todos (title, description, done) VALUES ($1, $2, $3) RETURNING id",
+        "INSERT INTO todos (title, description, status) VALUES ($1, $2, $3) RETURNING id",
         _title,
         _description,
-        _done
+        _status as TodoStatus
     );
 
     let id = query.fetch_one(&_pool).await.unwrap().id;
@@ -147,6 +285,27 @@ async fn insert_todo() {
     assert!(id > 0);
 }
 
+///
+/// EXERCISE 4 (IN MEMORY)
+///
+/// Use the `create_todo` method of the repo to create a todo for the user
+/// with id 1, and assert that its id is greater than zero.
+///
+/// There is no status to pass: every new todo is open. Where does the
+/// Postgres repo get that from?
+///
+#[tokio::test]
+async fn insert_todo_in_memory() {
+    let repo = TodoRepoInMemory::default();
+
+    let _title = "Learn SQLx";
+    let _description = "I should really learn SQLx for my Axum web app";
+
+    let id = repo.create_todo(1, _title, _description, None, 0).await.unwrap();
+
+    assert!(id > 0);
+}
+
 ///
 /// EXERCISE 5
 ///
@@ -155,11 +314,12 @@ async fn insert_todo() {
 /// You may want to use `execute` to execute the query, rather than one
 /// of the fetch methods.
 ///
+#[cfg(feature = "postgres")]
 #[tokio::test]
 async fn update_todo_test() {
     let _pool = PgPoolOptions::new()
         .max_connections(1)
-        .connect(&std::env::var("DATABASE_URL").unwrap())
+        .connect(&crate::test_db::database_url().await)
         .await
         .unwrap();
 
@@ -169,6 +329,23 @@ async fn update_todo_test() {
     assert!(true);
 }
 
+///
+/// EXERCISE 5 (IN MEMORY)
+///
+/// Use the `update_todo` method of the repo to mark the todo below as done,
+/// leaving everything else about it as it is.
+///
+/// How does the method tell you that there was no such todo, or that it
+/// belongs to another user?
+///
+#[tokio::test]
+async fn update_todo_in_memory() {
+    let _repo = TodoRepoInMemory::default();
+
+    let _id = _repo.create_todo(1, "Learn SQLx", "", None, 0).await.unwrap();
+    let _status = TodoStatus::Done;
+}
+
 ///
 /// EXERCISE 6
 ///
@@ -177,11 +354,12 @@ async fn update_todo_test() {
 /// You may want to use `execute` to execute the query, rather than one
 /// of the fetch methods.
 ///
+#[cfg(feature = "postgres")]
 #[tokio::test]
 async fn delete_todo_test() {
     let _pool = PgPoolOptions::new()
         .max_connections(1)
-        .connect(&std::env::var("DATABASE_URL").unwrap())
+        .connect(&crate::test_db::database_url().await)
         .await
         .unwrap();
 
@@ -190,6 +368,19 @@ async fn delete_todo_test() {
     assert!(true);
 }
 
+///
+/// EXERCISE 6 (IN MEMORY)
+///
+/// Use the `delete_todo` method of the repo to delete the todo below, and
+/// then check that `get_todo` no longer finds it.
+///
+#[tokio::test]
+async fn delete_todo_in_memory() {
+    let _repo = TodoRepoInMemory::default();
+
+    let _id = _repo.create_todo(1, "Learn SQLx", "", None, 0).await.unwrap();
+}
+
 ///
 /// EXERCISE 7
 ///
@@ -201,17 +392,25 @@ async fn delete_todo_test() {
 /// table, and use the `sqlx::query_as!` macro to select all columns from the
 /// `todos` table.
 ///
+/// `SELECT *` does not compile here: `status` is a `todo_status`, a Postgres
+/// enum, and the macro cannot guess that it should become a `TodoStatus`.
+/// List the columns instead, and override the type of that one with
+/// `status AS "status: TodoStatus"`. The override does not convert anything:
+/// `TodoStatus` derives `sqlx::Type`, which decodes the enum's labels.
+///
+#[cfg(feature = "postgres")]
 #[tokio::test]
 async fn select_star_as() {
     let pool = PgPoolOptions::new()
         .max_connections(1)
-        .connect(&std::env::var("DATABASE_URL").unwrap())
+        .connect(&crate::test_db::database_url().await)
         .await
         .unwrap();
 
     let query = sqlx::query_as!(
         Todo,
-        "SELECT * from todos"
+        r#"SELECT id, title, description, status AS "status: TodoStatus", created_at, due_at, priority,
+        parent_id, owner_id, list_id, completed_at, metadata from todos"#
     );
 
     let todos = query.fetch_all(&pool).await.unwrap();
@@ -222,191 +421,6852 @@ async fn select_star_as() {
     assert!(true);
 }
 
-#[derive(Debug)]
-struct Todo {
-    id: i64,
-    title: String,
-    description: String,
-    done: bool,
-    created_at: PrimitiveDateTime,
-}
-impl Todo {
-    pub fn to_dto(&self) -> TodoDTO {
-        TodoDTO {
-            id: self.id,
-            title: self.title.clone(),
-            description: self.description.clone(),
-            done: self.done,
-            created_at: self.created_at.to_string(),
-        }
+///
+/// EXERCISE 7 (IN MEMORY)
+///
+/// The `Todo` struct that `query_as!` fills in is also what every method of
+/// `TodoRepo` returns, whichever repo is behind it. Use `get_todos` to get
+/// all the todos of the user with id 1, and print them.
+///
+/// What would change in the handlers if the app switched from one repo to
+/// the other?
+///
+#[tokio::test]
+async fn select_star_as_in_memory() {
+    let repo = TodoRepoInMemory::default();
+    repo.create_todo(1, "Learn SQLx", "", None, 0).await.unwrap();
+
+    let todos: Vec<Todo> = repo.get_todos(1, TodoSort::Id).await.unwrap();
+
+    for todo in todos {
+        println!("{:?}", todo);
     }
 }
 
-#[derive(Debug, serde::Deserialize, serde::Serialize)]
-struct TodoDTO {
-    id: i64,
-    title: String,
-    description: String,
-    done: bool,
-    created_at: String,
+///
+/// EXERCISE 8
+///
+/// `PATCH /todo/:id` accepts a JSON Merge Patch (RFC 7386): a document shaped
+/// like the resource, where each member replaces the one in the resource, and
+/// `null` removes it. It is simple, but it cannot set a member to `null`, and
+/// it cannot change a single element of an array.
+///
+/// JSON Patch (RFC 6902) is the alternative: a list of operations such as
+/// `{ "op": "replace", "path": "/status", "value": "done" }`, applied in order.
+///
+/// Fill in `json_patch` with the JSON Patch operations that have the same
+/// effect as `merge_patch` below. Which of the two would you rather accept
+/// from clients, and which would you rather implement?
+///
+#[tokio::test]
+async fn merge_patch_vs_json_patch() {
+    let mut todo = serde_json::json!({
+        "title": "Learn Axum",
+        "description": "Finish the persistence section",
+        "status": "open",
+        "tags": { "area": "web", "urgent": true }
+    });
+
+    let merge = serde_json::json!({
+        "status": "done",
+        "tags": { "urgent": null }
+    });
+
+    let _json_patch = serde_json::json!([]);
+
+    merge_patch(&mut todo, &merge);
+
+    assert_eq!(
+        todo,
+        serde_json::json!({
+            "title": "Learn Axum",
+            "description": "Finish the persistence section",
+            "status": "done",
+            "tags": { "area": "web" }
+        })
+    );
 }
 
 ///
-/// GRADUATION PROJECT
+/// EXERCISE 9
 ///
-/// In this project, you will build a simple CRUD API for a todo list,
-/// which uses sqlx for persistence.
+/// Every query borrows a connection from the pool, and gives it back when it
+/// is done. When all connections are in use, the next query waits, for at
+/// most `acquire_timeout`, and then fails with `sqlx::Error::PoolTimedOut`.
 ///
-pub async fn run_todo_app() {
-    let pool = PgPoolOptions::new()
+/// `PgPoolOptions` has a handful of knobs (see `pool_options`, and the
+/// `DATABASE_*` variables in `.env`):
+///
+/// - `max_connections`: how many queries can run at the same time. Postgres
+///   itself has a limit (`SHOW max_connections`), shared by every instance
+///   of the application.
+/// - `min_connections`: how many connections to keep open even when idle,
+///   so that a burst of traffic does not first have to wait for handshakes.
+/// - `acquire_timeout`: how long a query waits for a connection, before
+///   failing instead of queueing forever.
+/// - `idle_timeout`: how long an unused connection is kept open.
+/// - `test_before_acquire`: whether to ping a connection before handing it
+///   out, which costs a round trip but catches connections that Postgres or
+///   a proxy has silently closed.
+///
+/// The first pool below has a single connection, and ten slow queries to run
+/// at once, so most of them time out. The second pool runs the same queries
+/// without a single timeout. Which of its settings make the difference, and
+/// which could you remove without any test failing?
+///
+/// Why not simply set `max_connections` to 1000?
+///
+#[cfg(feature = "postgres")]
+#[tokio::test]
+async fn pool_acquire_timeouts() {
+    use std::time::Duration;
+
+    async fn run_slow_queries(pool: &Pool<Postgres>) -> Vec<Result<(), sqlx::Error>> {
+        let queries = (0..10).map(|_| async {
+            sqlx::query!("SELECT pg_sleep(0.2)").fetch_one(pool).await.map(|_| ())
+        });
+        futures::future::join_all(queries).await
+    }
+
+    let starved = PgPoolOptions::new()
         .max_connections(1)
-        .connect(&std::env::var("DATABASE_URL").unwrap())
+        .acquire_timeout(Duration::from_millis(300))
+        .connect(&crate::test_db::database_url().await)
         .await
         .unwrap();
 
-    let todo_state = TodoState { repo: TodoRepoPostgres { pool } };
+    let results = run_slow_queries(&starved).await;
+    let timeouts = results.iter().filter(|result| matches!(result, Err(sqlx::Error::PoolTimedOut))).count();
+    assert!(timeouts > 0);
+
+    let tuned = pool_options(&PoolConfig {
+        max_connections: 10,
+        min_connections: 10,
+        acquire_timeout_secs: 1,
+        ..PoolConfig::default()
+    })
+    .connect(&crate::test_db::database_url().await)
+    .await
+    .unwrap();
 
-    let todo_routes = Router::new()
-        .route("/", get(get_todos))
-        .route("/:id", get(get_todo))
-        .route("/", post(create_todo))
-        .route("/:id", put(update_todo))
-        .route("/:id", delete(delete_todo))
-        .with_state(todo_state);
+    let results = run_slow_queries(&tuned).await;
+    assert!(results.iter().all(|result| result.is_ok()));
+    assert_eq!(tuned.size(), 10);
+}
 
-    let app = Router::new()
-        .nest("/todo/", todo_routes);
+///
+/// EXERCISE 10
+///
+/// `sqlx::query!` prepares its statement the first time it runs on a
+/// connection, and caches it: later runs skip parsing and planning, and only
+/// send the parameters. `sqlx::query` does the same by default, unless
+/// `.persistent(false)` turns caching off.
+///
+/// Even a prepared statement costs a round trip per row, though, and round
+/// trips dominate once there are more than a handful of rows. Batching sends
+/// all the rows at once, either as a multi-row `VALUES` list (whose SQL, and
+/// so whose prepared statement, changes with the number of rows), or with
+/// `UNNEST`, which binds one array per column and is the same statement for
+/// any number of rows.
+///
+/// Run the test with `cargo test insert_strategies -- --nocapture` to
+/// see the timings, then try `cargo bench --bench inserts` for numbers you
+/// can trust, at several sizes. At what number of rows does batching start
+/// to pay off? What happens to the multi-row `VALUES` list at 100,000 rows?
+///
+#[cfg(feature = "postgres")]
+#[tokio::test]
+async fn insert_strategies() {
+    use std::time::Instant;
 
-    let listener = tokio::net::TcpListener::bind("127.0.0.1:3000")
+    let pool = PgPoolOptions::new()
+        .max_connections(1)
+        .connect(&crate::test_db::database_url().await)
         .await
         .unwrap();
 
-    println!("Listening on {}", listener.local_addr().unwrap());
+    let titles: Vec<String> = (0..200).map(|i| format!("Strategy {}", i)).collect();
 
-    axum::serve(listener, app).await.unwrap();
-}
+    // Each strategy runs in a transaction that is rolled back, so that the
+    // exercise does not leave hundreds of todos behind.
+    let start = Instant::now();
+    let mut tx = pool.begin().await.unwrap();
+    for title in &titles {
+        sqlx::query!("INSERT INTO todos (title, description) VALUES ($1, '')", title)
+            .execute(&mut *tx)
+            .await
+            .unwrap();
+    }
+    tx.rollback().await.unwrap();
+    let prepared = start.elapsed();
 
-#[derive(Clone)]
-struct TodoState<R: TodoRepo> {
-    repo: R
-}
+    let start = Instant::now();
+    let mut tx = pool.begin().await.unwrap();
+    for title in &titles {
+        sqlx::query("INSERT INTO todos (title, description) VALUES ($1, '')")
+            .bind(title)
+            .persistent(false)
+            .execute(&mut *tx)
+            .await
+            .unwrap();
+    }
+    tx.rollback().await.unwrap();
+    let unprepared = start.elapsed();
 
-#[async_trait]
-trait TodoRepo: Send + Sync {
-    async fn get_todos(&self) -> Vec<Todo>;
-    async fn get_todo(&self, id: i64) -> Option<Todo>;
-    async fn create_todo(&self, title: &str, description: &str) -> i64;
-    async fn update_todo(
-        &self,
-        id: i64,
-        title: Option<&str>,
-        description: Option<&str>,
-        done: Option<bool>,
-    ) -> Option<i64>;
-    async fn delete_todo(&self, id: i64) -> i64;
+    let start = Instant::now();
+    let mut tx = pool.begin().await.unwrap();
+    let inserted = sqlx::query!(
+        "INSERT INTO todos (title, description) SELECT title, '' FROM UNNEST($1::TEXT[]) AS t (title)",
+        &titles
+    )
+    .execute(&mut *tx)
+    .await
+    .unwrap()
+    .rows_affected();
+    tx.rollback().await.unwrap();
+    let batched = start.elapsed();
 
-}
+    println!("query! per row:   {:?}", prepared);
+    println!("query per row:    {:?}", unprepared);
+    println!("UNNEST, batched:  {:?}", batched);
 
-#[derive(Clone)]
-struct TodoRepoPostgres {
-    pool: Pool<Postgres>,
+    assert_eq!(inserted, titles.len() as u64);
 }
 
-#[async_trait]
-impl TodoRepo for TodoRepoPostgres {
-    async fn get_todos(&self) -> Vec<Todo> {
-        let query = sqlx::query_as!(Todo, "SELECT * from todos");
-        query.fetch_all(&self.pool).await.unwrap()
+///
+/// EXERCISE 11
+///
+/// Returning `Json(todos)` from a handler serializes the todos on every
+/// request, even when nothing has changed since the last one. `GET /todo/`
+/// instead keeps the serialized list around as `Bytes` (see
+/// `TodoListCache`), and hands the same bytes to every response with
+/// `Body::from`, which does not copy them.
+///
+/// Run this test with `cargo test cached_bytes_vs_serialization -- --nocapture`
+/// to compare the two. Then think about what the cache costs: memory for
+/// every cached list, and the work of invalidating it at every write. When
+/// would you not bother?
+///
+#[tokio::test]
+async fn cached_bytes_vs_serialization() {
+    use std::time::Instant;
+
+    use time::macros::datetime;
+
+    let todos: Vec<TodoDTO> = (0..1_000)
+        .map(|id| TodoDTO {
+            id,
+            title: format!("Todo {}", id),
+            description: "Serialize me, again and again".to_string(),
+            status: if id % 3 == 0 { TodoStatus::Done } else { TodoStatus::Open },
+            created_at: datetime!(2026-10-16 12:00 UTC),
+            due_at: None,
+            priority: 0,
+            parent_id: None,
+            owner_id: Some(1),
+            list_id: None,
+            completed_at: None,
+            metadata: serde_json::json!({}),
+            href: TodoById { id }.to_string(),
+        })
+        .collect();
+
+    let start = Instant::now();
+    for _ in 0..100 {
+        let _body = Body::from(serde_json::to_vec(&todos).unwrap());
     }
-    async fn get_todo(&self, id: i64) -> Option<Todo> {
-        let query = sqlx::query_as!(Todo, "SELECT * from todos where id = $1", id);
-        query.fetch_optional(&self.pool).await.unwrap()
+    let serialized = start.elapsed();
+
+    let cached = Bytes::from(serde_json::to_vec(&todos).unwrap());
+    let start = Instant::now();
+    for _ in 0..100 {
+        let _body = Body::from(cached.clone());
     }
-    async fn create_todo(&self, title: &str, description: &str) -> i64 {
-        let query = sqlx::query!(
-            "INSERT INTO todos (title, description, done) VALUES ($1, $2, $3) RETURNING id",
+    let reused = start.elapsed();
+
+    println!("100 responses, serialized each time: {:?}", serialized);
+    println!("100 responses, from cached bytes:    {:?}", reused);
+
+    assert!(reused < serialized);
+}
+
+///
+/// EXERCISE 12
+///
+/// Once a statement fails inside a Postgres transaction, the whole
+/// transaction is aborted: every statement after it fails with "current
+/// transaction is aborted, commands ignored until end of transaction block",
+/// and all that is left to do is to roll back. An import that should skip its
+/// bad rows, but keep the good ones, needs a way to undo a single statement.
+///
+/// That is what a savepoint is for. `SAVEPOINT name` marks a point in the
+/// transaction, `ROLLBACK TO SAVEPOINT name` undoes everything after it and
+/// leaves the transaction usable, and `RELEASE SAVEPOINT name` forgets the
+/// mark but keeps the work. Savepoints nest, and rolling back to one also
+/// undoes the savepoints made after it.
+///
+/// sqlx makes them for you: `begin()` on a transaction starts a nested
+/// transaction, which is a savepoint, and its `commit()` and `rollback()`
+/// release it and roll back to it. Dropped without either, it rolls back,
+/// just like a transaction does.
+///
+/// Below, an import of four todos, one of which has a parent that does not
+/// exist, keeps the other three. Then a named savepoint undoes two rows at
+/// once, savepoints nested in it included. Remove the nested transaction from `import_row` (insert with `tx`
+/// directly): which rows are left, and which error do you get?
+///
+#[cfg(feature = "postgres")]
+#[tokio::test]
+async fn savepoints() {
+    use sqlx::Connection;
+
+    let pool = PgPoolOptions::new()
+        .max_connections(1)
+        .connect(&crate::test_db::database_url().await)
+        .await
+        .unwrap();
+
+    // Every todo of this test has the same description, so that it can count
+    // and remove exactly its own rows.
+    let batch = uuid::Uuid::new_v4().to_string();
+
+    async fn import_row(tx: &mut PgConnection, title: &str, parent_id: Option<i64>, batch: &str) -> Result<(), sqlx::Error> {
+        let mut savepoint = tx.begin().await?;
+        let inserted = sqlx::query!(
+            "INSERT INTO todos (title, description, parent_id) VALUES ($1, $2, $3)",
             title,
-            description,
-            false
-        );
-        query.fetch_one(&self.pool).await.unwrap().id
+            batch,
+            parent_id
+        )
+        .execute(&mut *savepoint)
+        .await;
+
+        match inserted {
+            Ok(_) => savepoint.commit().await,
+            Err(e) => {
+                savepoint.rollback().await?;
+                Err(e)
+            }
+        }
     }
-    async fn update_todo(
-        &self,
-        id: i64,
-        title: Option<&str>,
-        description: Option<&str>,
-        done: Option<bool>,
-    ) -> Option<i64> {
-        let query = sqlx::query!(
-            "UPDATE todos SET title = COALESCE($1, title), description = COALESCE($2, description), done = COALESCE($3, done) where id = $4 RETURNING id",
-            title,
-            description,
-            done,
-            id
-        );
-    
-        query.fetch_optional(&self.pool).await.unwrap().map(|row| row.id)
+
+    let rows = [("First", None), ("Orphan", Some(-1)), ("Second", None), ("Third", None)];
+
+    let mut tx = pool.begin().await.unwrap();
+    let mut failed = Vec::new();
+    for (title, parent_id) in rows {
+        if import_row(&mut tx, title, parent_id, &batch).await.is_err() {
+            failed.push(title);
+        }
     }
-    async fn delete_todo(&self, id: i64) -> i64 {
-        let query = sqlx::query!(
-            "DELETE FROM todos where id = $1 RETURNING id",
-            id
-        );
-    
-        query.fetch_one(&self.pool).await.unwrap().id
+    tx.commit().await.unwrap();
+
+    async fn titles(pool: &Pool<Postgres>, batch: &str) -> Vec<String> {
+        let rows = sqlx::query!("SELECT title FROM todos WHERE description = $1 ORDER BY id", batch)
+            .fetch_all(pool)
+            .await
+            .unwrap();
+        rows.into_iter().map(|row| row.title).collect()
     }
-}
 
-async fn get_todos<R: TodoRepo>(
-    State(TodoState{ repo }): State<TodoState<R>>,
-) -> Json<Vec<TodoDTO>> {
-    let todos =  repo.get_todos().await;
-    Json(todos.into_iter().map(|todo| todo.to_dto()).collect())
-}
+    assert_eq!(failed, vec!["Orphan"]);
+    assert_eq!(titles(&pool, &batch).await, vec!["First", "Second", "Third"]);
 
-async fn get_todo<R: TodoRepo>(
-    Path(id): Path<i64>,
-    State(TodoState{ repo }): State<TodoState<R>>,
-) -> Json<Option<TodoDTO>> {
-    let maybe_todo = repo.get_todo(id).await;
-    Json(maybe_todo.map(|todo| todo.to_dto()))
+    // The same by hand, with a named savepoint around more than one row.
+    let mut tx = pool.begin().await.unwrap();
+    import_row(&mut tx, "Kept", None, &batch).await.unwrap();
+    sqlx::query("SAVEPOINT before_undone").execute(&mut *tx).await.unwrap();
+    import_row(&mut tx, "Undone", None, &batch).await.unwrap();
+    import_row(&mut tx, "Also undone", None, &batch).await.unwrap();
+    sqlx::query("ROLLBACK TO SAVEPOINT before_undone").execute(&mut *tx).await.unwrap();
+    tx.commit().await.unwrap();
+
+    assert_eq!(titles(&pool, &batch).await, vec!["First", "Second", "Third", "Kept"]);
+
+    sqlx::query!("DELETE FROM todos WHERE description = $1", batch).execute(&pool).await.unwrap();
 }
 
-#[derive(Debug, serde::Deserialize, serde::Serialize)]
-struct CreateTodo {
-    title: String,
-    description: String,
+///
+/// EXERCISE 12 (IN MEMORY)
+///
+/// Memory has no transactions to roll back, but `bulk` still promises one
+/// when `atomic` is true: either every operation takes effect, or none of
+/// them do. `TodoRepoInMemory` keeps the promise by applying the operations
+/// to a copy of its tables, and keeping the copy only if all of them succeed.
+///
+/// Below, four operations, one of which updates a todo that does not exist,
+/// run first independently, and then atomically. What would it take to
+/// undo a single operation, and keep the rest, as a savepoint does?
+///
+#[tokio::test]
+async fn savepoints_in_memory() {
+    let repo = TodoRepoInMemory::default();
+
+    let create = |title: &str| BulkOperation::Create {
+        title: title.to_string(),
+        description: String::new(),
+        due_at: None,
+        priority: 0,
+    };
+    let missing = BulkOperation::Update { id: -1, title: None, description: None, status: None, due_at: None, priority: None };
+    let operations = [create("First"), missing, create("Second"), create("Third")];
+
+    async fn titles(repo: &TodoRepoInMemory) -> Vec<String> {
+        repo.get_todos(1, TodoSort::Id).await.unwrap().into_iter().map(|todo| todo.title).collect()
+    }
+
+    let results = repo.bulk(1, &operations, false).await.unwrap();
+    assert_eq!(results[1], Err(BulkError::NotFound(-1)));
+    assert_eq!(titles(&repo).await, vec!["First", "Second", "Third"]);
+
+    let results = repo.bulk(1, &operations, true).await.unwrap();
+    assert_eq!(
+        results,
+        vec![Err(BulkError::RolledBack), Err(BulkError::NotFound(-1)), Err(BulkError::NotAttempted), Err(BulkError::NotAttempted)]
+    );
+    assert_eq!(titles(&repo).await, vec!["First", "Second", "Third"]);
 }
 
-async fn create_todo<R: TodoRepo>(
-    State(TodoState{ repo }): State<TodoState<R>>,
-    body: Json<CreateTodo>
-) -> Json<i64> {
-    let id = repo.create_todo(&body.title, &body.description).await;
-    Json(id)
+///
+/// EXERCISE 13
+///
+/// `POST /todo/claim` hands out todos like a queue hands out jobs: each
+/// claim assigns the oldest open, unassigned todo to the user claiming it.
+/// Two claims that run at the same time must never get the same todo.
+///
+/// Reading the next todo and then updating it, as two statements, is a race:
+/// both claims can read the same todo before either updates it. Even as a
+/// single `UPDATE ... where id = (SELECT ...)`, both subqueries may pick the
+/// same row. `SELECT ... FOR UPDATE` locks the row it picks until the end of
+/// the transaction, so the second claim waits for the first, and
+/// `SKIP LOCKED` makes it pass over the locked row to the next one instead of
+/// waiting (see `claim_next_todo`).
+///
+/// Remove `SKIP LOCKED` from the query, and run the test again. The claims
+/// still never share a todo, so why does the test fail? Then remove
+/// `FOR UPDATE` as well.
+///
+#[cfg(feature = "postgres")]
+#[tokio::test]
+async fn concurrent_claims_get_different_todos() {
+    use crate::users::create_test_user;
+
+    let pool = PgPoolOptions::new()
+        .max_connections(10)
+        .connect(&crate::test_db::database_url().await)
+        .await
+        .unwrap();
+
+    // A user who is not an admin only sees, and claims, their own todos.
+    let repo = TodoRepoPostgres { pool: pool.clone() };
+    let user_id = create_test_user(&pool, false).await;
+    let mut ids = Vec::new();
+    for i in 0..5 {
+        ids.push(repo.create_todo(user_id, &format!("Job {}", i), "", None, 0).await.unwrap());
+    }
+
+    let claims = futures::future::join_all((0..10).map(|_| repo.claim_next_todo(user_id))).await;
+    let claims: Vec<Option<Todo>> = claims.into_iter().map(Result::unwrap).collect();
+    let mut claimed: Vec<i64> = claims.iter().flatten().map(|todo| todo.id).collect();
+    claimed.sort();
+
+    assert_eq!(claimed, ids);
+    assert!(claims.iter().flatten().all(|todo| todo.status == TodoStatus::InProgress));
+    assert!(repo.claim_next_todo(user_id).await.unwrap().is_none());
 }
 
-#[derive(Debug, serde::Deserialize)]
-struct UpdateTodo {
-    title: Option<String>,
-    description: Option<String>,
-    done: Option<bool>,
+///
+/// EXERCISE 13 (IN MEMORY)
+///
+/// `TodoRepoInMemory` has no rows to lock, so a claim locks all of its
+/// tables, from finding the next todo until it is assigned. The claims below
+/// run on several threads at once, and still never share a todo.
+///
+/// Why is that simpler than `FOR UPDATE SKIP LOCKED`, and what would it cost
+/// a database to do the same, with `LOCK TABLE todos`?
+///
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn concurrent_claims_get_different_todos_in_memory() {
+    let repo = TodoRepoInMemory::default();
+    let user_id = 1;
+    let mut ids = Vec::new();
+    for i in 0..5 {
+        ids.push(repo.create_todo(user_id, &format!("Job {}", i), "", None, 0).await.unwrap());
+    }
+
+    let claims = (0..10).map(|_| {
+        let repo = repo.clone();
+        tokio::spawn(async move { repo.claim_next_todo(user_id).await.unwrap() })
+    });
+    let claims: Vec<Option<Todo>> = futures::future::join_all(claims).await.into_iter().map(Result::unwrap).collect();
+    let mut claimed: Vec<i64> = claims.iter().flatten().map(|todo| todo.id).collect();
+    claimed.sort();
+
+    assert_eq!(claimed, ids);
+    assert!(claims.iter().flatten().all(|todo| todo.status == TodoStatus::InProgress));
+    assert!(repo.claim_next_todo(user_id).await.unwrap().is_none());
 }
 
-async fn update_todo<R: TodoRepo>(
-    Path(id): Path<i64>,
-    State(TodoState{ repo }): State<TodoState<R>>,
-    Json(UpdateTodo{ title, description, done }): Json<UpdateTodo>
-) -> Json<Option<i64>> {
-    let id = repo.update_todo(id, title.as_deref(), description.as_deref(), done).await;
-    Json(id)
+///
+/// EXERCISE 14
+///
+/// DynamoDB has no joins, no `ORDER BY`, and no transactions that lock rows.
+/// Its tables are designed around the questions the app will ask, and a
+/// question without a key to answer it scans the whole table.
+/// `TodoRepoDynamo` keeps todos in a single table, with an index that finds
+/// the todos of a user in the order of their pages, and it never locks
+/// anything: each write checks that the todos it changes are at the version
+/// it read, and starts over if another write got there first.
+///
+/// This runs against DynamoDB Local, at `AWS_ENDPOINT_URL`:
+///
+/// ```bash
+/// docker run -p 8000:8000 amazon/dynamodb-local
+/// AWS_ENDPOINT_URL=http://localhost:8000 cargo test --features dynamodb dynamo
+/// ```
+///
+/// Remove the conditions from `put_todo`, and run the test again. Why do the
+/// claims now get the same todos? And why does `get_todos_page` need no
+/// pagination token other than the cursor that the app already has?
+///
+#[cfg(feature = "dynamodb")]
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn concurrent_claims_get_different_todos_in_dynamo() {
+    use aws_sdk_dynamodb::{config::{Credentials, Region}, Client as DynamoClient};
+    use crate::users::create_test_user;
+
+    let pool = PgPoolOptions::new()
+        .max_connections(1)
+        .connect(&crate::test_db::database_url().await)
+        .await
+        .unwrap();
+    let endpoint = std::env::var("AWS_ENDPOINT_URL").unwrap_or("http://localhost:8000".to_string());
+    let config = aws_config::defaults(aws_config::BehaviorVersion::latest())
+        .endpoint_url(endpoint)
+        .region(Region::new("local"))
+        .credentials_provider(Credentials::new("local", "local", None, None, "dynamodb-local"))
+        .load()
+        .await;
+    let client = DynamoClient::new(&config);
+    let table = format!("todos_{}", rand::random::<u32>());
+    TodoRepoDynamo::create_table(&client, &table).await;
+
+    let repo = TodoRepoDynamo::with_client(client, &table, UserRepoPostgres::new(pool.clone(), crate::pii::test_cipher()));
+    let user_id = create_test_user(&pool, false).await;
+    let mut ids = Vec::new();
+    for i in 0..5 {
+        ids.push(repo.create_todo(user_id, &format!("Job {}", i), "", None, 0).await.unwrap());
+    }
+
+    // Two at a time, each page starting after the last todo of the one before.
+    let mut paged = Vec::new();
+    let mut after = None;
+    loop {
+        let page = repo.get_todos_page(user_id, after, 2).await.unwrap();
+        let Some(last) = page.last() else { break };
+        after = Some(TodoCursor { created_at: last.created_at, id: last.id });
+        paged.extend(page.iter().map(|todo| todo.id));
+    }
+    assert_eq!(paged, ids);
+
+    let claims = (0..10).map(|_| {
+        let repo = repo.clone();
+        tokio::spawn(async move { repo.claim_next_todo(user_id).await.unwrap() })
+    });
+    let claims: Vec<Option<Todo>> = futures::future::join_all(claims).await.into_iter().map(Result::unwrap).collect();
+    let mut claimed: Vec<i64> = claims.iter().flatten().map(|todo| todo.id).collect();
+    claimed.sort();
+
+    assert_eq!(claimed, ids);
+    assert!(claims.iter().flatten().all(|todo| todo.status == TodoStatus::InProgress));
+    assert!(repo.claim_next_todo(user_id).await.unwrap().is_none());
+}
+
+///
+/// EXERCISE 15
+///
+/// `TodoRepoAny` runs the same queries on Postgres, MySQL or SQLite, through
+/// an `AnyPool`, which picks its driver from the scheme of the URL when it
+/// connects. That is only known when the app runs, so no `query!` can check
+/// those queries when it compiles, as it checks those of `TodoRepoPostgres`
+/// against Postgres: there is no one database to check them against.
+///
+/// Nor can a row of `Any` be read as a `Todo`. Try it, in the test below:
+///
+/// ```rust,ignore
+/// let todos: Vec<Todo> = sqlx::query_as("SELECT * FROM todos").fetch_all(&any).await.unwrap();
+/// ```
+///
+/// It does not compile, because the `Any` driver has no type for times, for
+/// `todo_status` or for JSON, so `OffsetDateTime` and the others cannot be
+/// decoded from it. `TodoRepoAny` reads an `AnyTodoRow` instead, and each of
+/// its queries converts the columns that it reads in the SQL of the database
+/// (see `AnyDialect`). Then see `nullable_any` and `AnyDialect::micros_text`
+/// for what reading even those few types took.
+///
+/// Then misspell a column, say `priority` as `priorty`, in the `get_todos` of
+/// `TodoRepoPostgres`, and in that of `TodoRepoAny`. When do you find out
+/// about each? What would it take for a test to find out about the second,
+/// on each of the three databases?
+///
+#[cfg(feature = "any")]
+#[tokio::test]
+async fn any_pool_picks_its_driver_from_the_url() {
+    use crate::users::create_test_user;
+
+    let pool = PgPoolOptions::new()
+        .max_connections(1)
+        .connect(&crate::test_db::database_url().await)
+        .await
+        .unwrap();
+    let user_id = create_test_user(&pool, false).await;
+    let other_user_id = create_test_user(&pool, false).await;
+
+    // SQLite only with the sqlite feature, as each driver is only built in
+    // with its own.
+    let sqlite = cfg!(feature = "sqlite").then(|| "sqlite::memory:".to_string());
+    for url in [Some(crate::test_db::database_url().await), sqlite].into_iter().flatten() {
+        let dialect = AnyDialect::of_url(&url).unwrap();
+        let any = TodoRepoAny::pool_options(&PoolConfig::default(), dialect).connect(&url).await.unwrap();
+        TodoRepoAny::migrator(dialect).run(&any).await.unwrap();
+
+        let (id,): (i64,) = sqlx::query_as(&dialect.sql("INSERT INTO todos (title, description, owner_id) VALUES (?, ?, ?) RETURNING id"))
+            .bind("Untyped")
+            .bind("")
+            .bind(user_id)
+            .fetch_one(&any)
+            .await
+            .unwrap();
+        let repo = TodoRepoAny { pool: any, dialect, users: UserRepoPostgres::new(pool.clone(), crate::pii::test_cipher()) };
+        let todo = repo.get_todo(user_id, id).await.unwrap().unwrap();
+        assert_eq!(todo.title, "Untyped");
+        assert_eq!(todo.status, TodoStatus::Open);
+        assert_eq!(todo.metadata, serde_json::json!({}));
+        assert_eq!(repo.delete_todo(user_id, id).await.unwrap(), Some(id));
+
+        conformance_tests(|| repo.clone(), user_id, other_user_id).await;
+    }
+}
+
+///
+/// EXERCISE 16
+///
+/// Each query of `TodoRepoPostgres` keeps to the todos of the user with
+/// `todo_visible_to`, and the one that forgets will show everyone's todos to
+/// anyone. `TodoRepoRls` leaves that to Postgres. The migration
+/// `todo_row_level_security` turns on row-level security for `todos` and
+/// `comments`, with policies that only let the `todo_rls` role see the rows
+/// visible to the user in the setting `app.user_id`, and `begin_as` switches
+/// to that role, and sets that user, for the length of a transaction.
+///
+/// The test below runs a query without a `WHERE`, as `begin_as` a user, and
+/// then on the pool itself, which connects as the owner of the table. Why
+/// does the second see every todo? Then, inside `begin_as`, try:
+///
+/// ```sql
+/// UPDATE todos SET owner_id = <other_user_id>
+/// ```
+///
+/// What happens, and which part of the policy did that? Then take the
+/// `set_config` out of `begin_as`, and run the tests again. Why is failing
+/// the better of what could happen there?
+///
+#[cfg(feature = "postgres")]
+#[tokio::test]
+async fn row_level_security_hides_what_a_query_forgets() {
+    use crate::users::create_test_user;
+
+    let pool = PgPoolOptions::new()
+        .max_connections(1)
+        .connect(&crate::test_db::database_url().await)
+        .await
+        .unwrap();
+    let user_id = create_test_user(&pool, false).await;
+    let other_user_id = create_test_user(&pool, false).await;
+    let admin_id = create_test_user(&pool, true).await;
+    let repo = TodoRepoRls { pool: pool.clone() };
+    let id = repo.create_todo(user_id, "Mine", "", None, 0).await.unwrap();
+    let other_id = repo.create_todo(other_user_id, "Theirs", "", None, 0).await.unwrap();
+
+    // No WHERE at all.
+    let forgetful = "SELECT id FROM todos";
+    let mut tx = repo.begin_as(user_id).await.unwrap();
+    let ids: Vec<i64> = sqlx::query_scalar(forgetful).fetch_all(&mut *tx).await.unwrap();
+    tx.commit().await.unwrap();
+    assert!(ids.contains(&id));
+    assert!(!ids.contains(&other_id));
+    assert_eq!(repo.get_todo(user_id, other_id).await.unwrap().map(|todo| todo.id), None);
+
+    let mut tx = repo.begin_as(admin_id).await.unwrap();
+    let ids: Vec<i64> = sqlx::query_scalar(forgetful).fetch_all(&mut *tx).await.unwrap();
+    tx.commit().await.unwrap();
+    assert!(ids.contains(&id) && ids.contains(&other_id));
+
+    let ids: Vec<i64> = sqlx::query_scalar(forgetful).fetch_all(&pool).await.unwrap();
+    assert!(ids.contains(&id) && ids.contains(&other_id));
+
+    // Nor can it change, delete or comment on what it cannot see.
+    let mut tx = repo.begin_as(user_id).await.unwrap();
+    let updated = sqlx::query("UPDATE todos SET title = 'Stolen'").execute(&mut *tx).await.unwrap();
+    let deleted = sqlx::query("DELETE FROM todos WHERE id = $1").bind(other_id).execute(&mut *tx).await.unwrap();
+    tx.rollback().await.unwrap();
+    assert_eq!(updated.rows_affected(), 1);
+    assert_eq!(deleted.rows_affected(), 0);
+    assert_eq!(repo.create_comment(user_id, other_id, "Sneaky").await.unwrap(), None);
+    assert_eq!(repo.get_todo(other_user_id, other_id).await.unwrap().unwrap().title, "Theirs");
+
+    // Outside a transaction of `begin_as`, the role and the user are gone.
+    let role: String = sqlx::query_scalar("SELECT current_user::TEXT").fetch_one(&pool).await.unwrap();
+    assert_ne!(role, "todo_rls");
+    let mut tx = pool.begin().await.unwrap();
+    sqlx::query("SET LOCAL ROLE todo_rls").execute(&mut *tx).await.unwrap();
+    assert!(sqlx::query_scalar::<_, i64>(forgetful).fetch_all(&mut *tx).await.is_err());
+}
+
+///
+/// Mapped to the `todo_status` enum type in Postgres. sqlx cannot tell what
+/// Rust type a custom Postgres type should become, so queries name it with a
+/// type override: `status AS "status: TodoStatus"`.
+///
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, serde::Deserialize, serde::Serialize, sqlx::Type)]
+#[sqlx(type_name = "todo_status", rename_all = "snake_case")]
+#[serde(rename_all = "snake_case")]
+pub enum TodoStatus {
+    #[default]
+    Open,
+    InProgress,
+    Done,
+    Cancelled,
+}
+
+///
+/// Lets a `&[TodoStatus]` be bound as a `todo_status[]`. Postgres names the
+/// array type of every type after it, with a leading underscore.
+///
+impl sqlx::postgres::PgHasArrayType for TodoStatus {
+    fn array_type_info() -> sqlx::postgres::PgTypeInfo {
+        sqlx::postgres::PgTypeInfo::with_name("_todo_status")
+    }
+}
+
+impl TodoStatus {
+    ///
+    /// The label of the status, the same in Postgres, MySQL, SQLite and JSON.
+    /// sqlx 0.7 cannot decode a MySQL `ENUM` into a type that derives
+    /// `sqlx::Type` (it expects a column type that MySQL never sends), so
+    /// `TodoRepoMySql` reads and binds statuses as labels, and `TodoRepoSqlite`
+    /// does too, as SQLite has no enums.
+    ///
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TodoStatus::Open => "open",
+            TodoStatus::InProgress => "in_progress",
+            TodoStatus::Done => "done",
+            TodoStatus::Cancelled => "cancelled",
+        }
+    }
+}
+
+impl std::str::FromStr for TodoStatus {
+    type Err = String;
+
+    fn from_str(label: &str) -> Result<Self, Self::Err> {
+        [TodoStatus::Open, TodoStatus::InProgress, TodoStatus::Done, TodoStatus::Cancelled]
+            .into_iter()
+            .find(|status| status.as_str() == label)
+            .ok_or_else(|| format!("Not a todo status: {}", label))
+    }
+}
+
+#[derive(Clone, Debug, sqlx::FromRow)]
+pub struct Todo {
+    pub id: i64,
+    pub title: String,
+    pub description: String,
+    pub status: TodoStatus,
+    pub created_at: OffsetDateTime,
+    pub due_at: Option<OffsetDateTime>,
+    pub priority: i32,
+    pub parent_id: Option<i64>,
+    pub owner_id: Option<i64>,
+    pub list_id: Option<i64>,
+    pub completed_at: Option<OffsetDateTime>,
+    pub metadata: serde_json::Value,
+}
+
+table!(Todo in "todos" {
+    id: i64,
+    title: String,
+    description: String,
+    status: TodoStatus,
+    created_at: OffsetDateTime,
+    due_at: Option<OffsetDateTime>,
+    priority: i32,
+    parent_id: Option<i64>,
+    owner_id: Option<i64>,
+    list_id: Option<i64>,
+    completed_at: Option<OffsetDateTime>,
+    metadata: serde_json::Value,
+});
+
+///
+/// The tables that structs are read from with `query_as` and `FromRow`,
+/// which the app checks for drift when it starts (see `schema_drift.rs`).
+///
+pub fn mirrored_tables() -> Vec<TableSchema> {
+    vec![Todo::schema(), User::schema()]
+}
+
+impl Todo {
+    pub fn to_dto(&self) -> TodoDTO {
+        TodoDTO {
+            id: self.id,
+            title: self.title.clone(),
+            description: self.description.clone(),
+            status: self.status,
+            created_at: self.created_at,
+            due_at: self.due_at,
+            priority: self.priority,
+            parent_id: self.parent_id,
+            owner_id: self.owner_id,
+            list_id: self.list_id,
+            completed_at: self.completed_at,
+            metadata: self.metadata.clone(),
+            href: TodoById { id: self.id }.to_string(),
+        }
+    }
+
+    ///
+    /// The same JSON as `to_dto`, borrowing the strings and metadata of the
+    /// todo rather than cloning them. Lists of todos are serialized once and
+    /// then dropped, so their DTOs never need to own anything.
+    ///
+    pub fn to_dto_ref(&self) -> TodoDTORef<'_> {
+        TodoDTORef {
+            id: self.id,
+            title: &self.title,
+            description: &self.description,
+            status: self.status,
+            created_at: self.created_at,
+            due_at: self.due_at,
+            priority: self.priority,
+            parent_id: self.parent_id,
+            owner_id: self.owner_id,
+            list_id: self.list_id,
+            completed_at: self.completed_at,
+            metadata: &self.metadata,
+            href: TodoById { id: self.id },
+        }
+    }
+}
+
+#[derive(Debug, serde::Deserialize, serde::Serialize)]
+pub struct TodoDTO {
+    pub id: i64,
+    title: String,
+    description: String,
+    pub status: TodoStatus,
+    #[serde(with = "time::serde::rfc3339")]
+    created_at: OffsetDateTime,
+    #[serde(with = "time::serde::rfc3339::option")]
+    due_at: Option<OffsetDateTime>,
+    priority: i32,
+    parent_id: Option<i64>,
+    owner_id: Option<i64>,
+    list_id: Option<i64>,
+    #[serde(with = "time::serde::rfc3339::option")]
+    pub completed_at: Option<OffsetDateTime>,
+    metadata: serde_json::Value,
+    href: String,
+}
+
+///
+/// A `TodoDTO` that borrows from the `Todo` it was made from. Even `href` is
+/// written straight into the output, rather than formatted into a `String`
+/// first.
+///
+#[derive(Debug, serde::Serialize)]
+pub struct TodoDTORef<'a> {
+    id: i64,
+    title: &'a str,
+    description: &'a str,
+    status: TodoStatus,
+    #[serde(with = "time::serde::rfc3339")]
+    created_at: OffsetDateTime,
+    #[serde(with = "time::serde::rfc3339::option")]
+    due_at: Option<OffsetDateTime>,
+    priority: i32,
+    parent_id: Option<i64>,
+    owner_id: Option<i64>,
+    list_id: Option<i64>,
+    #[serde(with = "time::serde::rfc3339::option")]
+    completed_at: Option<OffsetDateTime>,
+    metadata: &'a serde_json::Value,
+    #[serde(serialize_with = "serialize_display")]
+    href: TodoById,
+}
+
+fn serialize_display<T: std::fmt::Display, S: serde::Serializer>(value: &T, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.collect_str(value)
+}
+
+#[test]
+fn borrowed_dtos_serialize_like_owned_ones() {
+    use time::macros::datetime;
+
+    let todo = Todo {
+        id: 7,
+        title: "Water \"the\" plants".to_string(),
+        description: "Twice".to_string(),
+        status: TodoStatus::InProgress,
+        created_at: datetime!(2026-10-16 12:00 UTC),
+        due_at: Some(datetime!(2026-10-25 09:30 +02:00)),
+        priority: 2,
+        parent_id: Some(3),
+        owner_id: Some(1),
+        list_id: None,
+        completed_at: None,
+        metadata: serde_json::json!({ "tags": ["home"] }),
+    };
+
+    assert_eq!(serde_json::to_string(&todo.to_dto_ref()).unwrap(), serde_json::to_string(&todo.to_dto()).unwrap());
+}
+
+///
+/// A todo with its subtasks, and theirs, all the way down.
+///
+#[derive(Debug, serde::Deserialize, serde::Serialize)]
+pub struct TodoTreeDTO {
+    #[serde(flatten)]
+    pub todo: TodoDTO,
+    pub subtasks: Vec<TodoTreeDTO>,
+}
+
+impl TodoTreeDTO {
+    ///
+    /// Assembles the tree rooted at `root` from the flat list of todos in it.
+    ///
+    fn build(root: &Todo, todos: &[Todo]) -> TodoTreeDTO {
+        TodoTreeDTO {
+            todo: root.to_dto(),
+            subtasks: todos
+                .iter()
+                .filter(|todo| todo.parent_id == Some(root.id))
+                .map(|child| TodoTreeDTO::build(child, todos))
+                .collect(),
+        }
+    }
+}
+
+#[derive(Debug, serde::Deserialize, serde::Serialize)]
+pub struct StatusCount {
+    pub status: TodoStatus,
+    pub count: i64,
+}
+
+#[derive(Debug, serde::Deserialize, serde::Serialize)]
+pub struct DailyCount {
+    pub day: String,
+    pub count: i64,
+}
+
+///
+/// Aggregates over the todos visible to a user. `average_completion_seconds`
+/// is `None` until at least one todo has been completed.
+///
+#[derive(Debug, serde::Deserialize, serde::Serialize)]
+pub struct TodoStats {
+    pub by_status: Vec<StatusCount>,
+    pub created_per_day: Vec<DailyCount>,
+    pub average_completion_seconds: Option<f64>,
+}
+
+///
+/// What `TodoRepo::erase_user` deletes, or would delete: the todos of the
+/// user and their subtasks, the comments of them all, and the todos of others
+/// that are left without the user as their assignee.
+///
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct TodoErasure {
+    pub todos: i64,
+    pub comments: i64,
+    pub unassigned_todos: i64,
+}
+
+#[derive(Clone, Debug, sqlx::FromRow)]
+pub struct Comment {
+    pub id: i64,
+    pub todo_id: i64,
+    pub body: String,
+    pub created_at: OffsetDateTime,
+}
+impl Comment {
+    pub fn to_dto(&self) -> CommentDTO {
+        CommentDTO {
+            id: self.id,
+            body: self.body.clone(),
+            created_at: self.created_at,
+            href: TodoComment { id: self.todo_id, comment_id: self.id }.to_string(),
+        }
+    }
+}
+
+#[derive(Debug, serde::Deserialize, serde::Serialize)]
+pub struct CommentDTO {
+    pub id: i64,
+    body: String,
+    #[serde(with = "time::serde::rfc3339")]
+    created_at: OffsetDateTime,
+    href: String,
+}
+
+///
+/// A todo together with its comments, so that clients can render a todo's
+/// page with a single request.
+///
+#[derive(Debug, serde::Deserialize, serde::Serialize)]
+pub struct TodoWithCommentsDTO {
+    todo: TodoDTO,
+    pub comments: Vec<CommentDTO>,
+}
+
+///
+/// Typed paths name each route of the todo API once. The same struct is used
+/// to register the route, to extract its path parameters in the handler, and
+/// to generate links to it (`TodoById { id: 5 }.to_string() == "/todo/5"`).
+///
+#[derive(Debug, TypedPath)]
+#[typed_path("/todo")]
+pub struct TodoCollection;
+
+#[derive(Debug, TypedPath)]
+#[typed_path("/todo/bulk")]
+pub struct TodoBulk;
+
+#[derive(Debug, TypedPath)]
+#[typed_path("/todo/overdue")]
+struct TodoOverdue;
+
+#[derive(Debug, TypedPath)]
+#[typed_path("/todo/due")]
+pub struct TodoDue;
+
+#[derive(Debug, TypedPath)]
+#[typed_path("/todo/new")]
+struct TodoNew;
+
+#[derive(Debug, TypedPath)]
+#[typed_path("/todo/stats")]
+pub struct TodoStatsPath;
+
+#[derive(Debug, TypedPath)]
+#[typed_path("/todo/claim")]
+pub struct TodoClaim;
+
+#[derive(Debug, TypedPath)]
+#[typed_path("/todo/with-comments")]
+struct TodoCollectionWithComments;
+
+#[derive(Debug, TypedPath)]
+#[typed_path("/todo/import.ndjson")]
+struct TodoImport;
+
+#[derive(Debug, TypedPath, serde::Deserialize)]
+#[typed_path("/todo/:id")]
+pub struct TodoById {
+    pub id: i64,
+}
+
+#[derive(Debug, TypedPath, serde::Deserialize)]
+#[typed_path("/todo/:id/tree")]
+pub struct TodoTree {
+    pub id: i64,
+}
+
+#[derive(Debug, TypedPath, serde::Deserialize)]
+#[typed_path("/todo/:id/parent")]
+pub struct TodoParent {
+    pub id: i64,
+}
+
+#[derive(Debug, TypedPath, serde::Deserialize)]
+#[typed_path("/todo/:id/comments")]
+pub struct TodoComments {
+    pub id: i64,
+}
+
+#[derive(Debug, TypedPath, serde::Deserialize)]
+#[typed_path("/todo/:id/comments/:comment_id")]
+pub struct TodoComment {
+    pub id: i64,
+    pub comment_id: i64,
+}
+
+#[derive(Debug, TypedPath, serde::Deserialize)]
+#[typed_path("/todo/:id/share")]
+struct TodoShare {
+    id: i64,
+}
+
+#[derive(Debug, TypedPath, serde::Deserialize)]
+#[typed_path("/shared/todo/:id")]
+struct SharedTodo {
+    id: i64,
+}
+
+///
+/// GRADUATION PROJECT
+///
+/// In this project, you will build a simple CRUD API for a todo list,
+/// which uses sqlx for persistence.
+///
+pub async fn run_todo_app() {
+    #[cfg(feature = "embedded-postgres")]
+    let embedded = crate::embedded_postgres::EmbeddedPostgres::start_unless_configured().await.unwrap();
+
+    let secrets = Secrets::from_env().unwrap();
+    let config = AppConfig::load(&secrets).await.unwrap();
+    let log_level = init_logging(&config.log_filter).unwrap();
+
+    #[cfg(feature = "embedded-postgres")]
+    if let Some(embedded) = &embedded {
+        tracing::info!(port = embedded.port(), "Started an embedded Postgres, until the app stops");
+    }
+
+    let pool = pool_options(&config.pool)
+        .connect_with(config.connect_options().unwrap())
+        .await
+        .unwrap();
+
+    // Open connections stay authenticated, and new ones use the new password.
+    let rotated_pool = pool.clone();
+    let connect_options = config.connect_options().unwrap();
+    secrets.on_rotation("DATABASE_PASSWORD", move |password| {
+        rotated_pool.set_connect_options(connect_options.clone().password(password));
+    });
+
+    // Systemd is told that the app is ready once it serves, which it only
+    // does with its migrations run and a database that answers.
+    sqlx::migrate!().run(&pool).await.unwrap();
+    sqlx::query("SELECT 1").execute(&pool).await.unwrap();
+    if let Err(drift) = SchemaDrift::check(&pool, &mirrored_tables()).await {
+        panic!("{}", drift);
+    }
+    #[cfg(feature = "mysql")]
+    if config.todo_repo == TodoRepoKind::MySql {
+        TodoRepoMySql::migrate(&config).await;
+    }
+    #[cfg(feature = "sqlite")]
+    if config.todo_repo == TodoRepoKind::Sqlite {
+        TodoRepoSqlite::migrate(&config).await;
+    }
+    #[cfg(feature = "any")]
+    if config.todo_repo == TodoRepoKind::Any {
+        TodoRepoAny::migrate(&config).await;
+    }
+    #[cfg(feature = "dynamodb")]
+    if config.todo_repo == TodoRepoKind::DynamoDb {
+        TodoRepoDynamo::create_table(&TodoRepoDynamo::client_from_env().await, &config.dynamodb_table).await;
+    }
+    #[cfg(feature = "mongodb")]
+    if config.todo_repo == TodoRepoKind::MongoDb {
+        TodoRepoMongo::create_indexes(&TodoRepoMongo::connect(config.mongodb_url.as_ref().unwrap()).await).await;
+    }
+
+    let (prometheus_layer, metrics) = PrometheusMetricLayer::pair();
+
+    todo_app(&config, TodoAppState::new(&config, pool, metrics, log_level, secrets.clone()))
+        .background_task(secrets.watch())
+        .layer(prometheus_layer)
+        .trailing_slash(config.trailing_slash)
+        .serve(&config)
+        .await
+        .unwrap();
+
+    #[cfg(feature = "embedded-postgres")]
+    if let Some(embedded) = embedded {
+        embedded.stop().await.unwrap();
+    }
+}
+
+///
+/// Re-encrypts the emails of users with the current PII key (see `pii.rs`),
+/// after running the migrations, and reports how many it changed.
+///
+pub async fn rotate_pii_keys() {
+    let config = AppConfig::load(&Secrets::from_env().unwrap()).await.unwrap();
+    let pool = pool_options(&config.pool).connect_with(config.connect_options().unwrap()).await.unwrap();
+    sqlx::migrate!().run(&pool).await.unwrap();
+
+    let cipher = PiiCipher::new(Arc::new(StaticKeys { data: config.pii_keys, index: config.pii_index_key }));
+    let rotated = rotate_emails(&pool, &cipher, 500).await;
+    println!("Re-encrypted {} emails with key {}", rotated, cipher.current_key_id());
+}
+
+///
+/// Prints the routes of the todo app, as a table or as JSON. This does not
+/// need a running database, because the pool only connects when first used.
+///
+pub fn print_todo_routes(json: bool) {
+    use axum_prometheus::metrics_exporter_prometheus::PrometheusBuilder;
+
+    let config = AppConfig::from_env().unwrap();
+    let pool = PgPoolOptions::new().connect_lazy(&config.database_url).unwrap();
+    let metrics = PrometheusBuilder::new().build_recorder().handle();
+    let (_, log_level) = LogLevel::layer(&config.log_filter).unwrap();
+
+    let app = todo_app(&config, TodoAppState::new(&config, pool, metrics, log_level, Secrets::env()));
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(app.routes()).unwrap());
+    } else {
+        print!("{}", app.routes());
+    }
+}
+
+fn pool_options(config: &PoolConfig) -> PgPoolOptions {
+    PgPoolOptions::new()
+        .max_connections(config.max_connections)
+        .min_connections(config.min_connections)
+        .acquire_timeout(std::time::Duration::from_secs(config.acquire_timeout_secs))
+        .idle_timeout(std::time::Duration::from_secs(config.idle_timeout_secs))
+        .test_before_acquire(config.test_before_acquire)
+}
+
+///
+/// Publishes the size of the connection pool as gauges, which the admin
+/// router's `/metrics` endpoint exposes to Prometheus. A pool that is always
+/// fully in use (`db_pool_idle_connections` at 0, `db_pool_connections` at
+/// its maximum) is the first thing to look at when requests start to time
+/// out.
+///
+async fn report_pool_metrics(pool: Pool<Postgres>, period: std::time::Duration) {
+    let mut interval = tokio::time::interval(period);
+    loop {
+        interval.tick().await;
+        metrics::gauge!("db_pool_connections", pool.size() as f64);
+        metrics::gauge!("db_pool_idle_connections", pool.num_idle() as f64);
+        metrics::gauge!("db_pool_max_connections", pool.options().get_max_connections() as f64);
+    }
+}
+
+fn todo_app(config: &AppConfig, state: TodoAppState) -> AppBuilder<TodoAppState> {
+    let (repo, clock) = (state.recurrences.repo.clone(), state.clock.clone());
+    let scheduler = run_as_leader(state.pool.clone(), "recurrence-scheduler", Duration::from_secs(10), move || {
+        run_recurrence_scheduler(repo.clone(), clock.clone(), Duration::from_secs(60))
+    });
+    let pool_metrics = report_pool_metrics(state.pool.clone(), std::time::Duration::from_secs(5));
+    let db = state.db.clone();
+    let in_flight = state.in_flight.clone();
+    let admission = state.admission.clone();
+    let http_cache = state.http_cache.clone();
+    let invalidation = invalidate_on_events(http_cache.clone(), &state.todos.events);
+    let db_watcher = watch_database(state.pool.clone(), db.clone(), Duration::from_secs(1));
+    let geo = axum::middleware::from_fn_with_state(state.clone(), enrich_with_geo);
+    let maintenance = state.maintenance.clone();
+    let shadow = match &config.shadow_upstream {
+        Some(upstream) => Shadow::upstream(upstream, config.shadow_percent),
+        None => Shadow::disabled(),
+    };
+    let faults = config.faults.clone().dev_only();
+    let restore = axum::middleware::from_fn_with_state(
+        state.clone(),
+        restore_session::<RememberMeRepoPostgres, SharedRefreshTokenRepo>,
+    );
+    let two_factor = axum::middleware::from_fn_with_state(state.clone(), require_two_factor_for_admins::<UserRepoPostgres>);
+    let pats = axum::middleware::from_fn_with_state(state.clone(), authenticate_pats::<PatRepoPostgres>);
+
+    let app = AppBuilder::new(state)
+        .merge(
+            todo_routes::<_, AppTodoRepo>(config.expensive_route_concurrency)
+                .layer(axum::middleware::from_fn_with_state(http_cache, cache_responses))
+                .layer(two_factor),
+        )
+        .merge(shared_todo_routes::<_, AppTodoRepo>());
+    // These join the todos with tables of Postgres, and would find none of
+    // them if `TODO_REPO` keeps them elsewhere.
+    let app = if config.todo_repo.in_postgres() {
+        app.merge(attachment_routes::<_, AttachmentRepoPostgres>())
+            .merge(recurrence_routes::<_, RecurrenceRepoPostgres>())
+            .merge(list_routes::<_, ListRepoPostgres>())
+            .merge(feed_routes::<_, FeedRepoPostgres>())
+            .background_task(scheduler)
+    } else {
+        tracing::warn!(
+            todo_repo = ?config.todo_repo,
+            "Attachments, recurrences, lists and the feed are off, as the todos are not in Postgres"
+        );
+        app
+    };
+    app.merge(uuid_todo_routes::<_, UuidTodoRepoPostgres>())
+        .merge(user_routes::<_, UserRepoPostgres, SharedRefreshTokenRepo, RememberMeRepoPostgres, TotpRepoPostgres>())
+        .merge(passkey_routes::<_, UserRepoPostgres, SharedRefreshTokenRepo, PasskeyRepoPostgres>())
+        .merge(pat_routes::<_, PatRepoPostgres>())
+        .merge(erasure_routes::<_, UserRepoPostgres, ErasureRepoPostgres>())
+        .merge(takeout_routes::<_, UserRepoPostgres, TakeoutRepoPostgres>())
+        .merge(socket_routes())
+        .nest(
+            "/auth",
+            auth_routes::<_, SharedRefreshTokenRepo>()
+                .merge(remember_me_routes::<_, RememberMeRepoPostgres>())
+                .layer(restore),
+        )
+        .layer(pats)
+        .layer(axum::middleware::from_fn_with_state(shadow, shadow_requests))
+        .layer(axum::middleware::from_fn_with_state(faults, inject_faults))
+        .layer(axum::middleware::from_fn_with_state(db.clone(), reject_writes_when_down))
+        .layer(catch_panics(db))
+        .layer(axum::middleware::from_fn_with_state(admission, admission_control))
+        .layer(axum::middleware::from_fn_with_state(in_flight.clone(), track_in_flight))
+        .layer(geo)
+        .layer(axum::middleware::from_fn_with_state(maintenance, reject_during_maintenance))
+        .admin(admin_routes())
+        .admin(readiness_routes())
+        .admin(log_level_routes())
+        .admin(in_flight_routes())
+        .admin(profiling_routes())
+        .admin(maintenance_routes())
+        .report_draining(in_flight)
+        .background_task(pool_metrics)
+        .background_task(report_runtime_metrics(Duration::from_secs(5)))
+        .background_task(db_watcher)
+        .background_task(invalidation)
+}
+
+///
+/// The todo repo of the app: the one of `TODO_REPO`, timed, with concurrent
+/// reads of a todo coalesced, and todo lists cached.
+///
+type AppTodoRepo = CachingTodoRepo<CoalescingTodoRepo<InstrumentedTodoRepo<SharedTodoRepo>>>;
+
+///
+/// The state of the todo app as a whole. Each router extracts only the part it
+/// needs, thanks to the `FromRef` implementations generated by the derive.
+///
+#[derive(Clone, FromRef)]
+struct TodoAppState {
+    todos: TodoState<AppTodoRepo>,
+    attachments: AttachmentState<AttachmentRepoPostgres>,
+    recurrences: RecurrenceState<RecurrenceRepoPostgres>,
+    lists: ListState<ListRepoPostgres>,
+    feed: FeedState<FeedRepoPostgres>,
+    uuid_todos: UuidTodoState<UuidTodoRepoPostgres>,
+    users: UserState<UserRepoPostgres>,
+    auth: AuthState<SharedRefreshTokenRepo>,
+    remember_me: RememberMeState<RememberMeRepoPostgres>,
+    two_factor: TwoFactorState<TotpRepoPostgres>,
+    webauthn: WebAuthnState<PasskeyRepoPostgres>,
+    pats: PatState<PatRepoPostgres>,
+    erasure: ErasureState<ErasureRepoPostgres>,
+    takeout: TakeoutState<TakeoutRepoPostgres>,
+    signer: UrlSigner,
+    sockets: SocketState,
+    admin: AdminState,
+    log_level: LogLevel,
+    pool: Pool<Postgres>,
+    db: DbHealth,
+    in_flight: InFlight,
+    admission: AdaptiveLimit,
+    http_cache: HttpCache,
+    trusted_proxies: TrustedProxies,
+    geoip: GeoIp,
+    maintenance: Maintenance,
+    clock: SharedClock,
+}
+
+impl TodoAppState {
+    fn new(
+        config: &AppConfig,
+        pool: Pool<Postgres>,
+        metrics: PrometheusHandle,
+        log_level: LogLevel,
+        secrets: Secrets,
+    ) -> Self {
+        let clock: SharedClock = Arc::new(SystemClock);
+        let events = TodoEvents::default();
+        let db = DbHealth::default();
+
+        let store: SharedObjectStore = Arc::new(ObjectStoreFs::new(config.attachments_dir.clone()));
+        let pii = PiiCipher::new(Arc::new(StaticKeys {
+            data: config.pii_keys.clone(),
+            index: config.pii_index_key.clone(),
+        }));
+        let users = UserRepoPostgres::new(pool.clone(), pii);
+        let todo_repo: SharedTodoRepo = match config.todo_repo {
+            TodoRepoKind::Postgres => Arc::new(TodoRepoPostgres { pool: pool.clone() }),
+            TodoRepoKind::Rls => Arc::new(TodoRepoRls { pool: pool.clone() }),
+            #[cfg(feature = "mysql")]
+            TodoRepoKind::MySql => Arc::new(TodoRepoMySql::connect_lazy(config, users.clone())),
+            #[cfg(feature = "sqlite")]
+            TodoRepoKind::Sqlite => Arc::new(TodoRepoSqlite::connect_lazy(config, users.clone())),
+            #[cfg(feature = "sea-orm")]
+            TodoRepoKind::SeaOrm => Arc::new(TodoRepoSeaOrm::new(pool.clone())),
+            #[cfg(feature = "dynamodb")]
+            TodoRepoKind::DynamoDb => Arc::new(TodoRepoDynamo::connect_lazy(config, users.clone())),
+            #[cfg(feature = "mongodb")]
+            TodoRepoKind::MongoDb => Arc::new(TodoRepoMongo::connect_lazy(config, users.clone())),
+            #[cfg(feature = "any")]
+            TodoRepoKind::Any => Arc::new(TodoRepoAny::connect_lazy(config, users.clone())),
+        };
+        let sessions: SharedRefreshTokenRepo = match config.session_store {
+            SessionStoreKind::Postgres => Arc::new(RefreshTokenRepoPostgres::new(pool.clone())),
+            #[cfg(feature = "redis")]
+            SessionStoreKind::Redis => Arc::new(RefreshTokenRepoRedis::connect_lazy(
+                config.redis_url.as_deref().expect("REDIS_URL must be set when SESSION_STORE is redis"),
+            )),
+        };
+
+        let todo_repo = CachingTodoRepo::new(CoalescingTodoRepo::new(InstrumentedTodoRepo::new(
+            todo_repo,
+            Duration::from_millis(config.slow_query_threshold_ms),
+        )))
+        .with_health(db.clone());
+        // Erasing and exporting an account reach the todos in Postgres along
+        // with the rest of it, and need their repo only when they are not.
+        let todos_elsewhere: Option<SharedTodoRepo> =
+            (!config.todo_repo.in_postgres()).then(|| Arc::new(todo_repo.clone()) as SharedTodoRepo);
+
+        TodoAppState {
+            todos: TodoState {
+                repo: todo_repo,
+                clock: clock.clone(),
+                events: events.clone(),
+            },
+            attachments: AttachmentState {
+                repo: AttachmentRepoPostgres::new(pool.clone()),
+                store: store.clone(),
+            },
+            recurrences: RecurrenceState { repo: RecurrenceRepoPostgres::new(pool.clone()) },
+            lists: ListState { repo: ListRepoPostgres::new(pool.clone()) },
+            feed: FeedState { repo: FeedRepoPostgres::new(pool.clone()) },
+            uuid_todos: UuidTodoState {
+                repo: UuidTodoRepoPostgres::new(pool.clone(), UuidV7Ids::new(clock.clone())),
+            },
+            users: UserState { repo: users },
+            auth: AuthState {
+                repo: sessions,
+                keys: JwtKeys::from_secret(config.jwt_secret.as_bytes()).with_clock(clock.clone()),
+            },
+            remember_me: RememberMeState { repo: RememberMeRepoPostgres::new(pool.clone()), clock: clock.clone() },
+            two_factor: TwoFactorState { repo: TotpRepoPostgres::new(pool.clone()), clock: clock.clone() },
+            webauthn: WebAuthnState {
+                repo: PasskeyRepoPostgres::new(pool.clone()),
+                rp_id: config.webauthn_rp_id.clone(),
+                origin: config.webauthn_origin.clone(),
+                clock: clock.clone(),
+            },
+            pats: PatState { repo: PatRepoPostgres::new(pool.clone()), clock: clock.clone() },
+            erasure: ErasureState {
+                repo: ErasureRepoPostgres::new(pool.clone()),
+                store,
+                todos: todos_elsewhere.clone(),
+            },
+            takeout: TakeoutState {
+                repo: TakeoutRepoPostgres::new(pool.clone()),
+                clock: clock.clone(),
+                todos: todos_elsewhere,
+            },
+            signer: UrlSigner::from_secret(config.jwt_secret.as_bytes(), clock.clone()),
+            sockets: SocketState { events, config: SocketConfig::default() },
+            admin: AdminState {
+                config: Arc::new(RwLock::new(config.clone())),
+                metrics: RenderedMetrics::new(metrics),
+                secrets,
+            },
+            log_level,
+            pool,
+            db,
+            in_flight: InFlight::default(),
+            admission: AdaptiveLimit::new(AdaptiveConfig::new(
+                Duration::from_millis(config.admission_latency_target_ms),
+                config.admission_max_concurrency,
+            )),
+            http_cache: HttpCache::new(Duration::from_secs(config.http_cache_max_age_secs)),
+            trusted_proxies: config.trusted_proxies.clone(),
+            geoip: config.geoip_dir.as_deref().map(|dir| GeoIp::load_dir(dir).unwrap()).unwrap_or_default(),
+            maintenance: Maintenance::new(config.maintenance_windows.clone(), clock.clone()),
+            clock,
+        }
+    }
+}
+
+impl FromRef<TodoAppState> for JwtKeys {
+    fn from_ref(state: &TodoAppState) -> Self {
+        state.auth.keys.clone()
+    }
+}
+
+impl FromRef<TodoAppState> for axum_extra::extract::cookie::Key {
+    fn from_ref(state: &TodoAppState) -> Self {
+        state.auth.keys.cookie_key()
+    }
+}
+
+///
+/// The routes of the todos. Every one of them requires authentication, and
+/// acts on behalf of the user identified by the access token. Each of the
+/// expensive ones runs at most `expensive_concurrency` requests at a time
+/// (see the `shedding` module). Personal access tokens need `todo:read` to read them, and `todo:write`
+/// to change them (see the `pat` module). In debug builds, the bodies of the
+/// writes are checked against `openapi.json` (see the `openapi` module).
+///
+pub fn todo_routes<S, R>(expensive_concurrency: usize) -> Routes<S>
+where
+    R: TodoRepo + Clone + 'static,
+    TodoState<R>: FromRef<S>,
+    JwtKeys: FromRef<S>,
+    UrlSigner: FromRef<S>,
+    S: Clone + Send + Sync + 'static,
+{
+    let expensive_reads = Routes::new()
+        .get(TodoCollectionWithComments::PATH, get_todos_with_comments::<R>)
+        .layer(shed_load(expensive_concurrency))
+        .layer(require_scope(Scope::TodoRead));
+    let expensive_writes = Routes::new()
+        .post(TodoImport::PATH, import_todos::<R>)
+        .layer(shed_load(expensive_concurrency))
+        .layer(require_scope(Scope::TodoWrite));
+
+    let reads = Routes::new()
+        .get(TodoCollection::PATH, get_todos::<R>)
+        .get(TodoById::PATH, get_todo::<R>)
+        .get(TodoOverdue::PATH, get_overdue_todos::<R>)
+        .get(TodoDue::PATH, get_todos_due::<R>)
+        .get(TodoNew::PATH, get_todo_form)
+        .get(TodoStatsPath::PATH, get_todo_stats::<R>)
+        .get(TodoTree::PATH, get_todo_tree::<R>)
+        .get(TodoComments::PATH, get_comments::<R>)
+        .post(TodoShare::PATH, share_todo::<R>)
+        .layer(require_scope(Scope::TodoRead));
+
+    Routes::new()
+        .post(TodoNew::PATH, post_todo_form::<R>)
+        .post(TodoCollection::PATH, create_todo::<R>)
+        .put(TodoById::PATH, update_todo::<R>)
+        .patch(TodoById::PATH, patch_todo::<R>)
+        .delete(TodoById::PATH, delete_todo::<R>)
+        .post(TodoBulk::PATH, bulk_todos::<R>)
+        .post(TodoClaim::PATH, claim_todo::<R>)
+        .put(TodoParent::PATH, set_parent::<R>)
+        .post(TodoComments::PATH, create_comment::<R>)
+        .delete(TodoComment::PATH, delete_comment::<R>)
+        .layer(axum::middleware::from_fn_with_state(OpenApi::todos().dev_only(), validate_request_bodies))
+        .layer(require_scope(Scope::TodoWrite))
+        .merge(reads)
+        .merge(expensive_reads)
+        .merge(expensive_writes)
+}
+
+///
+/// The todos shared with signed links, which need no authentication, and so
+/// none of the layers of `todo_routes`.
+///
+pub fn shared_todo_routes<S, R>() -> Routes<S>
+where
+    R: TodoRepo + Clone + 'static,
+    TodoState<R>: FromRef<S>,
+    UrlSigner: FromRef<S>,
+    S: Clone + Send + Sync + 'static,
+{
+    Routes::new().get(SharedTodo::PATH, get_shared_todo::<R>)
+}
+
+#[derive(Clone)]
+pub struct TodoState<R: TodoRepo> {
+    pub repo: R,
+    pub clock: SharedClock,
+    pub events: TodoEvents,
+}
+
+///
+/// The failure of whatever a `TodoRepo` keeps its todos in, such as a query
+/// that Postgres rejected, or a pool that had no connection to hand out.
+///
+/// Handlers answer it with `503 Service Unavailable` and a `Retry-After`
+/// header, as when the database is down, and log it rather than show it to
+/// the client. Clones share the error, so that coalesced calls can all
+/// return it.
+///
+#[derive(Clone, Debug)]
+pub struct RepoError(Arc<dyn std::error::Error + Send + Sync>);
+
+impl<E: std::error::Error + Send + Sync + 'static> From<E> for RepoError {
+    fn from(error: E) -> Self {
+        RepoError(Arc::new(error))
+    }
+}
+
+impl std::fmt::Display for RepoError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl IntoResponse for RepoError {
+    fn into_response(self) -> Response {
+        tracing::error!(error = %self, "Todo repo call failed");
+        database_unavailable()
+    }
+}
+
+///
+/// For `?` in handlers that answer their other errors with a `Response`.
+///
+impl From<RepoError> for Response {
+    fn from(error: RepoError) -> Self {
+        error.into_response()
+    }
+}
+
+///
+/// Every method takes the id of the user making the request, and only sees
+/// the todos visible to that user: their own, or all of them for admins.
+/// Todos that are not visible behave exactly as if they did not exist.
+///
+/// Every method fails with a `RepoError` when the database does.
+///
+#[async_trait]
+pub trait TodoRepo: Send + Sync {
+    async fn get_todos(&self, user_id: i64, sort: TodoSort) -> Result<Vec<Todo>, RepoError>;
+    ///
+    /// The same todos as `get_todos`, already serialized as a JSON array of
+    /// `TodoDTO`s, ready to be sent as a response body.
+    ///
+    async fn get_todos_json(&self, user_id: i64, sort: TodoSort) -> Result<Bytes, RepoError> {
+        let todos = self.get_todos(user_id, sort).await?;
+        Ok(to_json(&todos.iter().map(Todo::to_dto_ref).collect::<Vec<_>>()).unwrap())
+    }
+    ///
+    /// Up to `limit` todos strictly after `after` in `(created_at, id)` order.
+    ///
+    async fn get_todos_page(&self, user_id: i64, after: Option<TodoCursor>, limit: i64) -> Result<Vec<Todo>, RepoError>;
+    async fn get_todo(&self, user_id: i64, id: i64) -> Result<Option<Todo>, RepoError>;
+    ///
+    /// Todos that match every part of the filter, in id order.
+    ///
+    async fn get_todos_filtered(&self, user_id: i64, filter: &TodoFilter) -> Result<Vec<Todo>, RepoError>;
+    ///
+    /// The todo with the given id, followed by all of its descendants.
+    ///
+    async fn get_todo_tree(&self, user_id: i64, id: i64) -> Result<Vec<Todo>, RepoError>;
+    ///
+    /// Moves a todo under a new parent (or to the top level, if `None`),
+    /// refusing to make a todo a descendant of itself.
+    ///
+    async fn set_parent(
+        &self,
+        user_id: i64,
+        id: i64,
+        parent_id: Option<i64>,
+    ) -> Result<Result<(), SubtaskError>, RepoError>;
+    ///
+    /// Todos that are still open or in progress, and whose due date is before
+    /// `now`, oldest due date first.
+    ///
+    async fn get_overdue_todos(&self, user_id: i64, now: OffsetDateTime) -> Result<Vec<Todo>, RepoError>;
+    ///
+    /// Todos due on `day` in the named time zone (such as `Europe/Copenhagen`),
+    /// from its midnight to the next, however many hours that is. `None` if
+    /// Postgres does not know the time zone.
+    ///
+    async fn get_todos_due_on(&self, user_id: i64, day: Date, time_zone: &str) -> Result<Option<Vec<Todo>>, RepoError>;
+    ///
+    /// Counts by status, todos created on each of the last 30 days in UTC
+    /// (including days without any), and the average time from creation to
+    /// completion.
+    ///
+    async fn get_stats(&self, user_id: i64) -> Result<TodoStats, RepoError>;
+    async fn create_todo(
+        &self,
+        user_id: i64,
+        title: &str,
+        description: &str,
+        due_at: Option<OffsetDateTime>,
+        priority: i32,
+    ) -> Result<i64, RepoError>;
+    #[allow(clippy::too_many_arguments)]
+    async fn update_todo(
+        &self,
+        user_id: i64,
+        id: i64,
+        title: Option<&str>,
+        description: Option<&str>,
+        status: Option<TodoStatus>,
+        due_at: Option<OffsetDateTime>,
+        priority: Option<i32>,
+    ) -> Result<Option<i64>, RepoError>;
+    ///
+    /// Overwrites every patchable field, including clearing the due date,
+    /// which `update_todo` cannot do.
+    ///
+    async fn replace_todo(&self, user_id: i64, id: i64, todo: &PatchableTodo) -> Result<Option<i64>, RepoError>;
+    ///
+    /// Replaces the todo with what `patch` makes of it, as `replace_todo`
+    /// does. Returns `None` if there is no such todo, and the error of
+    /// `patch`, having saved nothing, if it fails.
+    ///
+    /// Repos that can lock the todo while `patch` runs do, so that concurrent
+    /// patches of it apply one after the other. The others read it and then
+    /// replace it, and a change made to it in between is lost.
+    ///
+    async fn patch_todo(
+        &self,
+        user_id: i64,
+        id: i64,
+        patch: &TodoPatch<'_>,
+    ) -> Result<Option<Result<i64, ExtractError>>, RepoError> {
+        let Some(todo) = self.get_todo(user_id, id).await? else {
+            return Ok(None);
+        };
+        match patch(todo) {
+            Ok(patched) => Ok(self.replace_todo(user_id, id, &patched).await?.map(Ok)),
+            Err(error) => Ok(Some(Err(error))),
+        }
+    }
+    async fn delete_todo(&self, user_id: i64, id: i64) -> Result<Option<i64>, RepoError>;
+    ///
+    /// Creates all the todos at once, returning their ids in order. Either
+    /// all of them are created, or none are.
+    ///
+    async fn create_many(&self, user_id: i64, todos: &[CreateTodo]) -> Result<Vec<i64>, RepoError>;
+    ///
+    /// Applies every operation, returning one result per operation. When
+    /// `atomic` is true, the operations run in a single transaction, and if
+    /// any one of them fails, none of them take effect.
+    ///
+    async fn bulk(
+        &self,
+        user_id: i64,
+        operations: &[BulkOperation],
+        atomic: bool,
+    ) -> Result<Vec<Result<i64, BulkError>>, RepoError>;
+    ///
+    /// Assigns the oldest open, unassigned todo visible to the user to them,
+    /// and marks it in progress. Concurrent claims never get the same todo.
+    ///
+    async fn claim_next_todo(&self, user_id: i64) -> Result<Option<Todo>, RepoError>;
+    async fn get_comments(&self, user_id: i64, todo_id: i64) -> Result<Vec<Comment>, RepoError>;
+    ///
+    /// The comments of all the given todos, with a single query. Prefer
+    /// loading them through a `CommentLoader`, which calls this.
+    ///
+    async fn get_comments_of(&self, user_id: i64, todo_ids: &[i64]) -> Result<Vec<Comment>, RepoError>;
+    ///
+    /// Returns the id of the new comment, or `None` if there is no such todo.
+    ///
+    async fn create_comment(&self, user_id: i64, todo_id: i64, body: &str) -> Result<Option<i64>, RepoError>;
+    async fn delete_comment(&self, user_id: i64, todo_id: i64, comment_id: i64) -> Result<bool, RepoError>;
+    ///
+    /// Deletes the todos that the user owns, their subtasks (even those of
+    /// other users), and the comments of them all, and unassigns the user
+    /// from the todos that are left, as erasing their account does. With
+    /// `dry_run`, only counts what it would do.
+    ///
+    /// Unlike the other methods, this sees every todo, as the user is not the
+    /// one asking.
+    ///
+    async fn erase_user(&self, user_id: i64, dry_run: bool) -> Result<TodoErasure, RepoError>;
+    ///
+    /// The todos that the user owns, in `(created_at, id)` order, for a
+    /// takeout. They are read a page at a time, and for admins, who see
+    /// every todo, the pages include those of others, which are skipped.
+    ///
+    fn export_user(&self, user_id: i64) -> BoxStream<'_, Result<Todo, RepoError>> {
+        const PAGE_SIZE: i64 = 500;
+        futures::stream::try_unfold(Some(None), move |after| async move {
+            let Some(after) = after else {
+                return Ok::<_, RepoError>(None);
+            };
+            let page = self.get_todos_page(user_id, after, PAGE_SIZE).await?;
+            let next = match page.last() {
+                Some(last) if page.len() as i64 == PAGE_SIZE => {
+                    Some(Some(TodoCursor { created_at: last.created_at, id: last.id }))
+                }
+                _ => None,
+            };
+            let owned = page.into_iter().filter(move |todo| todo.owner_id == Some(user_id));
+            Ok(Some((futures::stream::iter(owned.map(Ok::<_, RepoError>)), next)))
+        })
+        .try_flatten()
+        .boxed()
+    }
+}
+
+#[derive(Clone)]
+struct TodoRepoPostgres {
+    pool: Pool<Postgres>,
+}
+
+#[async_trait]
+impl TodoRepo for TodoRepoPostgres {
+    async fn get_todos(&self, user_id: i64, sort: TodoSort) -> Result<Vec<Todo>, RepoError> {
+        let pool = &self.pool;
+        let todos = match sort {
+            TodoSort::Id => sqlx::query_as!(
+                Todo,
+                r#"SELECT id, title, description, status AS "status: TodoStatus", created_at, due_at, priority, parent_id, owner_id, list_id, completed_at, metadata
+                from todos where todo_visible_to(owner_id, $1) ORDER BY id"#,
+                user_id
+            )
+            .fetch_all(pool)
+            .await,
+            TodoSort::Priority => sqlx::query_as!(
+                Todo,
+                r#"SELECT id, title, description, status AS "status: TodoStatus", created_at, due_at, priority, parent_id, owner_id, list_id, completed_at, metadata
+                from todos where todo_visible_to(owner_id, $1) ORDER BY priority DESC, id"#,
+                user_id
+            )
+            .fetch_all(pool)
+            .await,
+            TodoSort::DueAt => sqlx::query_as!(
+                Todo,
+                r#"SELECT id, title, description, status AS "status: TodoStatus", created_at, due_at, priority, parent_id, owner_id, list_id, completed_at, metadata
+                from todos where todo_visible_to(owner_id, $1) ORDER BY due_at NULLS LAST, id"#,
+                user_id
+            )
+            .fetch_all(pool)
+            .await,
+        };
+        Ok(todos?)
+    }
+    async fn get_todos_page(
+        &self,
+        user_id: i64,
+        after: Option<TodoCursor>,
+        limit: i64,
+    ) -> Result<Vec<Todo>, RepoError> {
+        let (after_created_at, after_id) = match after {
+            Some(TodoCursor { created_at, id }) => (Some(created_at), Some(id)),
+            None => (None, None),
+        };
+        let query = sqlx::query_as!(
+            Todo,
+            r#"SELECT id, title, description, status AS "status: TodoStatus", created_at, due_at, priority, parent_id, owner_id, list_id, completed_at, metadata
+            from todos where todo_visible_to(owner_id, $1)
+            AND ($2::TIMESTAMPTZ IS NULL OR (created_at, id) > ($2, $3))
+            ORDER BY created_at, id LIMIT $4"#,
+            user_id,
+            after_created_at,
+            after_id,
+            limit
+        );
+        Ok(query.fetch_all(&self.pool).await?)
+    }
+    async fn get_todo(&self, user_id: i64, id: i64) -> Result<Option<Todo>, RepoError> {
+        let query = sqlx::query_as!(
+            Todo,
+            r#"SELECT id, title, description, status AS "status: TodoStatus", created_at, due_at, priority, parent_id, owner_id, list_id, completed_at, metadata
+            from todos where id = $1 AND todo_visible_to(owner_id, $2)"#,
+            id,
+            user_id
+        );
+        Ok(query.fetch_optional(&self.pool).await?)
+    }
+    async fn get_todos_filtered(&self, user_id: i64, filter: &TodoFilter) -> Result<Vec<Todo>, RepoError> {
+        let mut query = filtered_todos_query(QueryBuilder::new(""), user_id, filter);
+        Ok(query.build_query_as().fetch_all(&self.pool).await?)
+    }
+    async fn get_todo_tree(&self, user_id: i64, id: i64) -> Result<Vec<Todo>, RepoError> {
+        let query = sqlx::query_as!(
+            Todo,
+            r#"WITH RECURSIVE tree (id, depth) AS (
+                SELECT id, 0 FROM todos where id = $1 AND todo_visible_to(owner_id, $2)
+                UNION ALL
+                SELECT todos.id, tree.depth + 1 FROM todos JOIN tree ON todos.parent_id = tree.id
+                where todo_visible_to(todos.owner_id, $2)
+            )
+            SELECT todos.id, title, description, status AS "status: TodoStatus", created_at, due_at, priority, parent_id, owner_id, list_id, completed_at, metadata
+            FROM todos JOIN tree ON todos.id = tree.id ORDER BY tree.depth, todos.id"#,
+            id,
+            user_id
+        );
+        Ok(query.fetch_all(&self.pool).await?)
+    }
+    async fn set_parent(
+        &self,
+        user_id: i64,
+        id: i64,
+        parent_id: Option<i64>,
+    ) -> Result<Result<(), SubtaskError>, RepoError> {
+        if self.get_todo(user_id, id).await?.is_none() {
+            return Ok(Err(SubtaskError::NotFound(id)));
+        }
+        if let Some(parent_id) = parent_id {
+            if self.get_todo(user_id, parent_id).await?.is_none() {
+                return Ok(Err(SubtaskError::NotFound(parent_id)));
+            }
+        }
+
+        let query = sqlx::query!(
+            "UPDATE todos SET parent_id = $2 where id = $1 AND NOT EXISTS (
+                WITH RECURSIVE descendants (id) AS (
+                    SELECT id FROM todos where id = $1
+                    UNION ALL
+                    SELECT todos.id FROM todos JOIN descendants ON todos.parent_id = descendants.id
+                )
+                SELECT 1 FROM descendants where id = $2
+            )
+            RETURNING id",
+            id,
+            parent_id
+        );
+
+        match query.fetch_optional(&self.pool).await? {
+            Some(_) => Ok(Ok(())),
+            None => Ok(Err(SubtaskError::Cycle)),
+        }
+    }
+    async fn get_overdue_todos(&self, user_id: i64, now: OffsetDateTime) -> Result<Vec<Todo>, RepoError> {
+        let query = sqlx::query_as!(
+            Todo,
+            r#"SELECT id, title, description, status AS "status: TodoStatus", created_at, due_at, priority, parent_id, owner_id, list_id, completed_at, metadata
+            from todos where status IN ('open', 'in_progress') AND due_at < $1 AND todo_visible_to(owner_id, $2)
+            ORDER BY due_at, id"#,
+            now,
+            user_id
+        );
+        Ok(query.fetch_all(&self.pool).await?)
+    }
+    async fn get_todos_due_on(&self, user_id: i64, day: Date, time_zone: &str) -> Result<Option<Vec<Todo>>, RepoError> {
+        // A date cast to `TIMESTAMP` is its midnight, and `AT TIME ZONE` reads
+        // that midnight as a wall-clock time in the zone, giving the instant.
+        let query = sqlx::query_as!(
+            Todo,
+            r#"SELECT id, title, description, status AS "status: TodoStatus", created_at, due_at, priority, parent_id, owner_id, list_id, completed_at, metadata
+            from todos
+            where due_at >= $1::DATE::TIMESTAMP AT TIME ZONE $2
+                AND due_at < ($1::DATE + 1)::TIMESTAMP AT TIME ZONE $2
+                AND todo_visible_to(owner_id, $3)
+            ORDER BY due_at, id"#,
+            day,
+            time_zone,
+            user_id
+        );
+        match query.fetch_all(&self.pool).await {
+            Ok(todos) => Ok(Some(todos)),
+            // invalid_parameter_value: "time zone ... not recognized"
+            Err(sqlx::Error::Database(error)) if error.code().as_deref() == Some("22023") => Ok(None),
+            Err(error) => Err(error.into()),
+        }
+    }
+    async fn get_stats(&self, user_id: i64) -> Result<TodoStats, RepoError> {
+        let by_status = sqlx::query_as!(
+            StatusCount,
+            r#"SELECT status AS "status: TodoStatus", COUNT(*) AS "count!"
+            FROM todos where todo_visible_to(owner_id, $1) GROUP BY status ORDER BY status"#,
+            user_id
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        let created_per_day = sqlx::query_as!(
+            DailyCount,
+            r#"SELECT to_char(days.day, 'YYYY-MM-DD') AS "day!", COUNT(todos.id) AS "count!"
+            FROM generate_series((now() AT TIME ZONE 'UTC')::DATE - 29, (now() AT TIME ZONE 'UTC')::DATE, INTERVAL '1 day')
+                AS days (day)
+            LEFT JOIN todos ON (todos.created_at AT TIME ZONE 'UTC')::DATE = days.day AND todo_visible_to(todos.owner_id, $1)
+            GROUP BY days.day ORDER BY days.day"#,
+            user_id
+        )
+        .fetch_all(&self.pool)
+        .await
+        ?;
+
+        let average_completion_seconds = sqlx::query!(
+            "SELECT EXTRACT(EPOCH FROM AVG(completed_at - created_at))::FLOAT8 AS seconds
+            FROM todos where completed_at IS NOT NULL AND todo_visible_to(owner_id, $1)",
+            user_id
+        )
+        .fetch_one(&self.pool)
+        .await?
+        .seconds;
+
+        Ok(TodoStats { by_status, created_per_day, average_completion_seconds })
+    }
+    async fn create_todo(
+        &self,
+        user_id: i64,
+        title: &str,
+        description: &str,
+        due_at: Option<OffsetDateTime>,
+        priority: i32,
+    ) -> Result<i64, RepoError> {
+        let query = sqlx::query!(
+            "INSERT INTO todos (title, description, due_at, priority, owner_id) VALUES ($1, $2, $3, $4, $5) RETURNING id",
+            title,
+            description,
+            due_at,
+            priority,
+            user_id
+        );
+        Ok(query.fetch_one(&self.pool).await?.id)
+    }
+    async fn create_many(&self, user_id: i64, todos: &[CreateTodo]) -> Result<Vec<i64>, RepoError> {
+        let titles: Vec<&str> = todos.iter().map(|todo| todo.title.as_str()).collect();
+        let descriptions: Vec<&str> = todos.iter().map(|todo| todo.description.as_str()).collect();
+        let due_ats: Vec<Option<OffsetDateTime>> = todos.iter().map(|todo| todo.due_at).collect();
+        let priorities: Vec<i32> = todos.iter().map(|todo| todo.priority).collect();
+
+        // A single statement, whatever the number of todos: each column is
+        // bound as one array, and UNNEST zips the arrays back into rows. The
+        // rows are inserted in the order of the arrays, so the ids that the
+        // sequence hands out are increasing in that order too.
+        let query = sqlx::query!(
+            "INSERT INTO todos (title, description, due_at, priority, owner_id)
+            SELECT title, description, due_at, priority, $5
+            FROM UNNEST($1::TEXT[], $2::TEXT[], $3::TIMESTAMPTZ[], $4::INTEGER[]) WITH ORDINALITY
+                AS t (title, description, due_at, priority, position)
+            ORDER BY position
+            RETURNING id",
+            &titles as &[&str],
+            &descriptions as &[&str],
+            &due_ats as &[Option<OffsetDateTime>],
+            &priorities,
+            user_id
+        );
+
+        let mut ids: Vec<i64> = query.fetch_all(&self.pool).await?.into_iter().map(|row| row.id).collect();
+        ids.sort_unstable();
+        Ok(ids)
+    }
+    async fn update_todo(
+        &self,
+        user_id: i64,
+        id: i64,
+        title: Option<&str>,
+        description: Option<&str>,
+        status: Option<TodoStatus>,
+        due_at: Option<OffsetDateTime>,
+        priority: Option<i32>,
+    ) -> Result<Option<i64>, RepoError> {
+        let query = sqlx::query!(
+            "UPDATE todos SET title = COALESCE($1, title), description = COALESCE($2, description), status = COALESCE($3, status), due_at = COALESCE($4, due_at), priority = COALESCE($5, priority) where id = $6 AND todo_visible_to(owner_id, $7) RETURNING id",
+            title,
+            description,
+            status as Option<TodoStatus>,
+            due_at,
+            priority,
+            id,
+            user_id
+        );
+    
+        // The parents are completed in the same transaction, so they are never
+        // left behind the change of their subtask.
+        let mut tx = self.pool.begin().await?;
+        let Some(row) = query.fetch_optional(&mut *tx).await? else {
+            return Ok(None);
+        };
+        complete_parents(&mut tx, row.id).await?;
+        tx.commit().await?;
+        Ok(Some(row.id))
+    }
+    async fn replace_todo(&self, user_id: i64, id: i64, todo: &PatchableTodo) -> Result<Option<i64>, RepoError> {
+        let mut tx = self.pool.begin().await?;
+        let Some(id) = replace_todo_in(&mut tx, user_id, id, todo).await? else {
+            return Ok(None);
+        };
+        tx.commit().await?;
+        Ok(Some(id))
+    }
+    async fn patch_todo(
+        &self,
+        user_id: i64,
+        id: i64,
+        patch: &TodoPatch<'_>,
+    ) -> Result<Option<Result<i64, ExtractError>>, RepoError> {
+        // `FOR UPDATE` locks the todo until the transaction ends, so a
+        // concurrent patch or replace of it waits for this one, and then
+        // patches what this one saved.
+        let mut tx = self.pool.begin().await?;
+        let todo = sqlx::query_as!(
+            Todo,
+            r#"SELECT id, title, description, status AS "status: TodoStatus", created_at, due_at, priority, parent_id, owner_id, list_id, completed_at, metadata
+            from todos where id = $1 AND todo_visible_to(owner_id, $2) FOR UPDATE"#,
+            id,
+            user_id
+        )
+        .fetch_optional(&mut *tx)
+        .await?;
+        let Some(todo) = todo else {
+            return Ok(None);
+        };
+
+        let patched = match patch(todo) {
+            Ok(patched) => patched,
+            Err(error) => return Ok(Some(Err(error))),
+        };
+        let Some(id) = replace_todo_in(&mut tx, user_id, id, &patched).await? else {
+            return Ok(None);
+        };
+        tx.commit().await?;
+        Ok(Some(Ok(id)))
+    }
+    async fn delete_todo(&self, user_id: i64, id: i64) -> Result<Option<i64>, RepoError> {
+        let query = sqlx::query!(
+            "DELETE FROM todos where id = $1 AND todo_visible_to(owner_id, $2) RETURNING id",
+            id,
+            user_id
+        );
+
+        Ok(query.fetch_optional(&self.pool).await?.map(|row| row.id))
+    }
+    async fn bulk(
+        &self,
+        user_id: i64,
+        operations: &[BulkOperation],
+        atomic: bool,
+    ) -> Result<Vec<Result<i64, BulkError>>, RepoError> {
+        if !atomic {
+            let mut conn = self.pool.acquire().await?;
+            let mut results = Vec::with_capacity(operations.len());
+            for operation in operations {
+                results.push(apply_bulk_operation(&mut conn, user_id, operation).await);
+            }
+            return Ok(results);
+        }
+
+        let mut tx = self.pool.begin().await?;
+        let mut results = Vec::with_capacity(operations.len());
+        for operation in operations {
+            let result = apply_bulk_operation(&mut tx, user_id, operation).await;
+            let failed = result.is_err();
+            results.push(result);
+            if failed {
+                break;
+            }
+        }
+
+        if results.iter().all(Result::is_ok) {
+            tx.commit().await?;
+            return Ok(results);
+        }
+
+        tx.rollback().await?;
+        Ok(rolled_back(results, operations.len()))
+    }
+    async fn claim_next_todo(&self, user_id: i64) -> Result<Option<Todo>, RepoError> {
+        // `FOR UPDATE` locks the row that the subquery picks until the update
+        // commits, and `SKIP LOCKED` makes concurrent claims pass over rows
+        // that another claim has locked, rather than wait for them.
+        let query = sqlx::query_as!(
+            Todo,
+            r#"UPDATE todos SET assignee_id = $1, status = 'in_progress'
+            where id = (
+                SELECT id from todos where assignee_id IS NULL AND status = 'open' AND todo_visible_to(owner_id, $1)
+                ORDER BY id LIMIT 1 FOR UPDATE SKIP LOCKED
+            )
+            RETURNING id, title, description, status AS "status: TodoStatus", created_at, due_at, priority, parent_id, owner_id, list_id, completed_at, metadata"#,
+            user_id
+        );
+        Ok(query.fetch_optional(&self.pool).await?)
+    }
+    async fn get_comments(&self, user_id: i64, todo_id: i64) -> Result<Vec<Comment>, RepoError> {
+        let query = sqlx::query_as!(
+            Comment,
+            "SELECT comments.* from comments JOIN todos ON todos.id = comments.todo_id
+            where comments.todo_id = $1 AND todo_visible_to(todos.owner_id, $2) ORDER BY comments.id",
+            todo_id,
+            user_id
+        );
+        Ok(query.fetch_all(&self.pool).await?)
+    }
+    async fn get_comments_of(&self, user_id: i64, todo_ids: &[i64]) -> Result<Vec<Comment>, RepoError> {
+        let query = sqlx::query_as!(
+            Comment,
+            "SELECT comments.* from comments JOIN todos ON todos.id = comments.todo_id
+            where comments.todo_id = ANY($1) AND todo_visible_to(todos.owner_id, $2) ORDER BY comments.id",
+            todo_ids,
+            user_id
+        );
+        Ok(query.fetch_all(&self.pool).await?)
+    }
+    async fn create_comment(&self, user_id: i64, todo_id: i64, body: &str) -> Result<Option<i64>, RepoError> {
+        let query = sqlx::query!(
+            "INSERT INTO comments (todo_id, body) SELECT id, $2 FROM todos where id = $1 AND todo_visible_to(owner_id, $3) RETURNING id",
+            todo_id,
+            body,
+            user_id
+        );
+        Ok(query.fetch_optional(&self.pool).await?.map(|row| row.id))
+    }
+    async fn delete_comment(&self, user_id: i64, todo_id: i64, comment_id: i64) -> Result<bool, RepoError> {
+        let query = sqlx::query!(
+            "DELETE FROM comments USING todos where comments.todo_id = $1 AND comments.id = $2
+            AND todos.id = comments.todo_id AND todo_visible_to(todos.owner_id, $3)",
+            todo_id,
+            comment_id,
+            user_id
+        );
+        Ok(query.execute(&self.pool).await?.rows_affected() > 0)
+    }
+    async fn erase_user(&self, user_id: i64, dry_run: bool) -> Result<TodoErasure, RepoError> {
+        let mut tx = self.pool.begin().await?;
+        let erasure = erase_user_in(&mut tx, user_id, dry_run).await?;
+        tx.commit().await?;
+        Ok(erasure)
+    }
+}
+
+///
+/// An in-memory implementation of `TodoRepo`, for working through the
+/// persistence exercises without Postgres (see the `postgres` feature), and
+/// for tests of the handlers that do not care where todos are kept.
+///
+/// It does what the queries of `TodoRepoPostgres` and the triggers of the
+/// migrations do, down to `completed_at` and the completion of parents, with
+/// one exception: the only time zone it knows is `UTC`. There is no users
+/// table to look admins up in, so they are given up front.
+///
+#[derive(Clone, Default)]
+pub struct TodoRepoInMemory {
+    pub tables: Arc<Mutex<TodoTables>>,
+}
+
+impl TodoRepoInMemory {
+    fn with_admins(admins: impl IntoIterator<Item = i64>) -> Self {
+        let tables = TodoTables { admins: admins.into_iter().collect(), ..TodoTables::default() };
+        TodoRepoInMemory { tables: Arc::new(Mutex::new(tables)) }
+    }
+}
+
+///
+/// The rows of a `TodoRepoInMemory`. A transaction is a clone, which takes
+/// the place of the original when it commits. Clones share their sequences,
+/// so that ids are never handed out twice, as in Postgres.
+///
+#[derive(Clone, Default)]
+pub struct TodoTables {
+    pub todos: BTreeMap<i64, Todo>,
+    /// The user each claimed todo is assigned to, by todo.
+    pub assignees: HashMap<i64, i64>,
+    pub comments: BTreeMap<i64, Comment>,
+    pub admins: HashSet<i64>,
+    pub todo_ids: SequenceIds,
+    pub comment_ids: SequenceIds,
+}
+
+impl TodoTables {
+    fn visible_to(&self, todo: &Todo, user_id: i64) -> bool {
+        todo.owner_id == Some(user_id) || self.admins.contains(&user_id)
+    }
+
+    ///
+    /// The todos visible to the user, in id order.
+    ///
+    fn visible(&self, user_id: i64) -> impl Iterator<Item = &Todo> {
+        self.todos.values().filter(move |todo| self.visible_to(todo, user_id))
+    }
+
+    fn get(&self, user_id: i64, id: i64) -> Option<&Todo> {
+        self.todos.get(&id).filter(|todo| self.visible_to(todo, user_id))
+    }
+
+    pub fn insert(&mut self, user_id: i64, title: &str, description: &str, due_at: Option<OffsetDateTime>, priority: i32) -> i64 {
+        let id = self.todo_ids.next_id();
+        // To the microsecond, as `TIMESTAMPTZ` keeps it. Cursors keep no
+        // more, so the last todo of a page would otherwise come after its
+        // own cursor, and be repeated on the next page.
+        let now = OffsetDateTime::now_utc();
+        let todo = Todo {
+            id,
+            title: title.to_string(),
+            description: description.to_string(),
+            status: TodoStatus::Open,
+            created_at: now.replace_microsecond(now.microsecond()).unwrap(),
+            due_at,
+            priority,
+            parent_id: None,
+            owner_id: Some(user_id),
+            list_id: None,
+            completed_at: None,
+            metadata: serde_json::json!({}),
+        };
+        self.todos.insert(id, todo);
+        id
+    }
+
+    ///
+    /// Changes a visible todo, keeping `completed_at` in step with its status
+    /// like the `todos_completed_at` trigger, and then completes its parents.
+    ///
+    fn update(&mut self, user_id: i64, id: i64, change: impl FnOnce(&mut Todo)) -> Option<i64> {
+        self.get(user_id, id)?;
+        self.set(id, change);
+        self.complete_parents(id);
+        Some(id)
+    }
+
+    fn set(&mut self, id: i64, change: impl FnOnce(&mut Todo)) {
+        let todo = self.todos.get_mut(&id).unwrap();
+        let was_done = todo.status == TodoStatus::Done;
+        change(todo);
+        if todo.status != TodoStatus::Done {
+            todo.completed_at = None;
+        } else if !was_done {
+            todo.completed_at = Some(OffsetDateTime::now_utc());
+        }
+    }
+
+    ///
+    /// The same as the `complete_parents` of `TodoRepoPostgres`.
+    ///
+    fn complete_parents(&mut self, id: i64) {
+        let mut child_id = id;
+        while let Some(parent_id) = self.todos[&child_id].parent_id {
+            let still_to_do = |todo: &Todo| matches!(todo.status, TodoStatus::Open | TodoStatus::InProgress);
+            if !still_to_do(&self.todos[&parent_id])
+                || self.todos.values().any(|sibling| sibling.parent_id == Some(parent_id) && still_to_do(sibling))
+            {
+                return;
+            }
+            self.set(parent_id, |parent| parent.status = TodoStatus::Done);
+            child_id = parent_id;
+        }
+    }
+
+    ///
+    /// Deletes a visible todo, and, as the foreign keys cascade, its subtasks
+    /// and the comments of them all.
+    ///
+    fn delete(&mut self, user_id: i64, id: i64) -> Option<i64> {
+        self.get(user_id, id)?;
+        let mut deleted = vec![id];
+        let mut i = 0;
+        while let Some(&parent_id) = deleted.get(i) {
+            deleted.extend(self.todos.values().filter(|todo| todo.parent_id == Some(parent_id)).map(|todo| todo.id));
+            i += 1;
+        }
+        for id in &deleted {
+            self.todos.remove(id);
+            self.assignees.remove(id);
+        }
+        self.comments.retain(|_, comment| !deleted.contains(&comment.todo_id));
+        Some(id)
+    }
+
+    ///
+    /// Erases the todos of a user, as `erase_user` does.
+    ///
+    fn erase_user(&mut self, user_id: i64) -> TodoErasure {
+        let todos = self.todos.len();
+        let comments = self.comments.len();
+        let owned: Vec<i64> =
+            self.todos.values().filter(|todo| todo.owner_id == Some(user_id)).map(|todo| todo.id).collect();
+        for id in owned {
+            // An earlier one may have taken it along as a subtask.
+            if self.todos.contains_key(&id) {
+                self.delete(user_id, id);
+            }
+        }
+        let assigned = self.assignees.len();
+        self.assignees.retain(|_, assignee_id| *assignee_id != user_id);
+        TodoErasure {
+            todos: (todos - self.todos.len()) as i64,
+            comments: (comments - self.comments.len()) as i64,
+            unassigned_todos: (assigned - self.assignees.len()) as i64,
+        }
+    }
+
+    fn apply(&mut self, user_id: i64, operation: &BulkOperation) -> Result<i64, BulkError> {
+        match operation {
+            BulkOperation::Create { title, description, due_at, priority } => {
+                Ok(self.insert(user_id, title, description, *due_at, *priority))
+            }
+            BulkOperation::Update { id, title, description, status, due_at, priority } => self
+                .update(user_id, *id, |todo| {
+                    todo.title = title.clone().unwrap_or(todo.title.clone());
+                    todo.description = description.clone().unwrap_or(todo.description.clone());
+                    todo.status = status.unwrap_or(todo.status);
+                    todo.due_at = due_at.or(todo.due_at);
+                    todo.priority = priority.unwrap_or(todo.priority);
+                })
+                .ok_or(BulkError::NotFound(*id)),
+            BulkOperation::Delete { id } => self.delete(user_id, *id).ok_or(BulkError::NotFound(*id)),
+        }
+    }
+}
+
+///
+/// Whether `value` contains `contained`, as JSONB's `@>` decides it: objects
+/// contain objects with a subset of their members, each contained in turn,
+/// and arrays contain arrays whose every element is contained in one of theirs.
+///
+pub fn json_contains(value: &serde_json::Value, contained: &serde_json::Value) -> bool {
+    use serde_json::Value;
+
+    match (value, contained) {
+        (Value::Object(value), Value::Object(contained)) => contained
+            .iter()
+            .all(|(key, contained)| value.get(key).is_some_and(|value| json_contains(value, contained))),
+        (Value::Array(value), Value::Array(contained)) => {
+            contained.iter().all(|contained| value.iter().any(|value| json_contains(value, contained)))
+        }
+        (value, contained) => value == contained,
+    }
+}
+
+#[async_trait]
+impl TodoRepo for TodoRepoInMemory {
+    async fn get_todos(&self, user_id: i64, sort: TodoSort) -> Result<Vec<Todo>, RepoError> {
+        let mut todos: Vec<Todo> = self.tables.lock().unwrap().visible(user_id).cloned().collect();
+        match sort {
+            TodoSort::Id => {}
+            TodoSort::Priority => todos.sort_by_key(|todo| std::cmp::Reverse(todo.priority)),
+            TodoSort::DueAt => todos.sort_by_key(|todo| (todo.due_at.is_none(), todo.due_at)),
+        }
+        Ok(todos)
+    }
+    async fn get_todos_page(
+        &self,
+        user_id: i64,
+        after: Option<TodoCursor>,
+        limit: i64,
+    ) -> Result<Vec<Todo>, RepoError> {
+        let tables = self.tables.lock().unwrap();
+        let mut todos: Vec<Todo> = tables
+            .visible(user_id)
+            .filter(|todo| after.is_none_or(|after| (todo.created_at, todo.id) > (after.created_at, after.id)))
+            .cloned()
+            .collect();
+        todos.sort_by_key(|todo| (todo.created_at, todo.id));
+        todos.truncate(limit.max(0) as usize);
+        Ok(todos)
+    }
+    async fn get_todo(&self, user_id: i64, id: i64) -> Result<Option<Todo>, RepoError> {
+        Ok(self.tables.lock().unwrap().get(user_id, id).cloned())
+    }
+    async fn get_todos_filtered(&self, user_id: i64, filter: &TodoFilter) -> Result<Vec<Todo>, RepoError> {
+        let tables = self.tables.lock().unwrap();
+        let todos = tables.visible(user_id).filter(|todo| {
+            json_contains(&todo.metadata, &filter.metadata)
+                && (filter.statuses.is_empty() || filter.statuses.contains(&todo.status))
+                && filter.due_after.is_none_or(|after| todo.due_at.is_some_and(|due_at| due_at >= after))
+                && filter.due_before.is_none_or(|before| todo.due_at.is_some_and(|due_at| due_at < before))
+        });
+        Ok(todos.cloned().collect())
+    }
+    async fn get_todo_tree(&self, user_id: i64, id: i64) -> Result<Vec<Todo>, RepoError> {
+        let tables = self.tables.lock().unwrap();
+        let mut tree: Vec<Todo> = tables.get(user_id, id).cloned().into_iter().collect();
+        let mut depth = 0..tree.len();
+        while !depth.is_empty() {
+            let parent_ids: Vec<i64> = tree[depth.clone()].iter().map(|todo| todo.id).collect();
+            let children = tables.visible(user_id).filter(|todo| todo.parent_id.is_some_and(|id| parent_ids.contains(&id)));
+            let start = tree.len();
+            tree.extend(children.cloned());
+            depth = start..tree.len();
+        }
+        Ok(tree)
+    }
+    async fn set_parent(
+        &self,
+        user_id: i64,
+        id: i64,
+        parent_id: Option<i64>,
+    ) -> Result<Result<(), SubtaskError>, RepoError> {
+        let mut tables = self.tables.lock().unwrap();
+        if tables.get(user_id, id).is_none() {
+            return Ok(Err(SubtaskError::NotFound(id)));
+        }
+        if let Some(parent_id) = parent_id {
+            if tables.get(user_id, parent_id).is_none() {
+                return Ok(Err(SubtaskError::NotFound(parent_id)));
+            }
+        }
+
+        // The todo cannot go under any of its descendants, which is to say
+        // that it cannot be among the ancestors of its new parent.
+        let mut ancestor_id = parent_id;
+        while let Some(ancestor) = ancestor_id {
+            if ancestor == id {
+                return Ok(Err(SubtaskError::Cycle));
+            }
+            ancestor_id = tables.todos[&ancestor].parent_id;
+        }
+        tables.todos.get_mut(&id).unwrap().parent_id = parent_id;
+        Ok(Ok(()))
+    }
+    async fn get_overdue_todos(&self, user_id: i64, now: OffsetDateTime) -> Result<Vec<Todo>, RepoError> {
+        let tables = self.tables.lock().unwrap();
+        let mut todos: Vec<Todo> = tables
+            .visible(user_id)
+            .filter(|todo| matches!(todo.status, TodoStatus::Open | TodoStatus::InProgress))
+            .filter(|todo| todo.due_at.is_some_and(|due_at| due_at < now))
+            .cloned()
+            .collect();
+        todos.sort_by_key(|todo| (todo.due_at, todo.id));
+        Ok(todos)
+    }
+    async fn get_todos_due_on(&self, user_id: i64, day: Date, time_zone: &str) -> Result<Option<Vec<Todo>>, RepoError> {
+        if time_zone != "UTC" {
+            return Ok(None);
+        }
+        let tables = self.tables.lock().unwrap();
+        let mut todos: Vec<Todo> = tables
+            .visible(user_id)
+            .filter(|todo| todo.due_at.is_some_and(|due_at| due_at.to_offset(time::UtcOffset::UTC).date() == day))
+            .cloned()
+            .collect();
+        todos.sort_by_key(|todo| (todo.due_at, todo.id));
+        Ok(Some(todos))
+    }
+    async fn get_stats(&self, user_id: i64) -> Result<TodoStats, RepoError> {
+        let tables = self.tables.lock().unwrap();
+        let todos: Vec<&Todo> = tables.visible(user_id).collect();
+
+        let by_status = [TodoStatus::Open, TodoStatus::InProgress, TodoStatus::Done, TodoStatus::Cancelled]
+            .into_iter()
+            .map(|status| StatusCount { status, count: todos.iter().filter(|todo| todo.status == status).count() as i64 })
+            .filter(|count| count.count > 0)
+            .collect();
+
+        let today = OffsetDateTime::now_utc().date();
+        let created_per_day = (0..30)
+            .rev()
+            .map(|days_ago| today - time::Duration::days(days_ago))
+            .map(|day| DailyCount {
+                day: format!("{}-{:02}-{:02}", day.year(), u8::from(day.month()), day.day()),
+                count: todos.iter().filter(|todo| todo.created_at.to_offset(time::UtcOffset::UTC).date() == day).count() as i64,
+            })
+            .collect();
+
+        let completion_seconds: Vec<f64> = todos
+            .iter()
+            .filter_map(|todo| Some((todo.completed_at? - todo.created_at).as_seconds_f64()))
+            .collect();
+        let average_completion_seconds = (!completion_seconds.is_empty())
+            .then(|| completion_seconds.iter().sum::<f64>() / completion_seconds.len() as f64);
+
+        Ok(TodoStats { by_status, created_per_day, average_completion_seconds })
+    }
+    async fn create_todo(
+        &self,
+        user_id: i64,
+        title: &str,
+        description: &str,
+        due_at: Option<OffsetDateTime>,
+        priority: i32,
+    ) -> Result<i64, RepoError> {
+        Ok(self.tables.lock().unwrap().insert(user_id, title, description, due_at, priority))
+    }
+    async fn create_many(&self, user_id: i64, todos: &[CreateTodo]) -> Result<Vec<i64>, RepoError> {
+        let mut tables = self.tables.lock().unwrap();
+        Ok(todos
+            .iter()
+            .map(|todo| tables.insert(user_id, &todo.title, &todo.description, todo.due_at, todo.priority))
+            .collect())
+    }
+    async fn update_todo(
+        &self,
+        user_id: i64,
+        id: i64,
+        title: Option<&str>,
+        description: Option<&str>,
+        status: Option<TodoStatus>,
+        due_at: Option<OffsetDateTime>,
+        priority: Option<i32>,
+    ) -> Result<Option<i64>, RepoError> {
+        let operation = BulkOperation::Update {
+            id,
+            title: title.map(str::to_string),
+            description: description.map(str::to_string),
+            status,
+            due_at,
+            priority,
+        };
+        Ok(self.tables.lock().unwrap().apply(user_id, &operation).ok())
+    }
+    async fn replace_todo(&self, user_id: i64, id: i64, todo: &PatchableTodo) -> Result<Option<i64>, RepoError> {
+        Ok(self.tables.lock().unwrap().update(user_id, id, |replaced| {
+            replaced.title = todo.title.clone();
+            replaced.description = todo.description.clone();
+            replaced.status = todo.status;
+            replaced.due_at = todo.due_at;
+            replaced.priority = todo.priority;
+            replaced.metadata = serde_json::Value::Object(todo.metadata.clone());
+        }))
+    }
+    async fn patch_todo(
+        &self,
+        user_id: i64,
+        id: i64,
+        patch: &TodoPatch<'_>,
+    ) -> Result<Option<Result<i64, ExtractError>>, RepoError> {
+        let mut tables = self.tables.lock().unwrap();
+        let Some(todo) = tables.get(user_id, id).cloned() else {
+            return Ok(None);
+        };
+        let patched = match patch(todo) {
+            Ok(patched) => patched,
+            Err(error) => return Ok(Some(Err(error))),
+        };
+        let id = tables.update(user_id, id, |replaced| {
+            replaced.title = patched.title;
+            replaced.description = patched.description;
+            replaced.status = patched.status;
+            replaced.due_at = patched.due_at;
+            replaced.priority = patched.priority;
+            replaced.metadata = serde_json::Value::Object(patched.metadata);
+        });
+        Ok(id.map(Ok))
+    }
+    async fn delete_todo(&self, user_id: i64, id: i64) -> Result<Option<i64>, RepoError> {
+        Ok(self.tables.lock().unwrap().delete(user_id, id))
+    }
+    async fn bulk(
+        &self,
+        user_id: i64,
+        operations: &[BulkOperation],
+        atomic: bool,
+    ) -> Result<Vec<Result<i64, BulkError>>, RepoError> {
+        let mut tables = self.tables.lock().unwrap();
+        if !atomic {
+            return Ok(operations.iter().map(|operation| tables.apply(user_id, operation)).collect());
+        }
+
+        let mut tx = tables.clone();
+        let mut results = Vec::with_capacity(operations.len());
+        for operation in operations {
+            let result = tx.apply(user_id, operation);
+            let failed = result.is_err();
+            results.push(result);
+            if failed {
+                return Ok(rolled_back(results, operations.len()));
+            }
+        }
+        *tables = tx;
+        Ok(results)
+    }
+    async fn claim_next_todo(&self, user_id: i64) -> Result<Option<Todo>, RepoError> {
+        // The lock is held from finding the todo to assigning it, so no other
+        // claim can get in between, as `FOR UPDATE` ensures in Postgres.
+        let mut tables = self.tables.lock().unwrap();
+        let Some(todo) = tables
+            .visible(user_id)
+            .find(|todo| todo.status == TodoStatus::Open && !tables.assignees.contains_key(&todo.id))
+        else {
+            return Ok(None);
+        };
+        let id = todo.id;
+        tables.assignees.insert(id, user_id);
+        tables.set(id, |todo| todo.status = TodoStatus::InProgress);
+        Ok(tables.todos.get(&id).cloned())
+    }
+    async fn get_comments(&self, user_id: i64, todo_id: i64) -> Result<Vec<Comment>, RepoError> {
+        self.get_comments_of(user_id, &[todo_id]).await
+    }
+    async fn get_comments_of(&self, user_id: i64, todo_ids: &[i64]) -> Result<Vec<Comment>, RepoError> {
+        let tables = self.tables.lock().unwrap();
+        let comments = tables
+            .comments
+            .values()
+            .filter(|comment| todo_ids.contains(&comment.todo_id) && tables.get(user_id, comment.todo_id).is_some());
+        Ok(comments.cloned().collect())
+    }
+    async fn create_comment(&self, user_id: i64, todo_id: i64, body: &str) -> Result<Option<i64>, RepoError> {
+        let mut tables = self.tables.lock().unwrap();
+        if tables.get(user_id, todo_id).is_none() {
+            return Ok(None);
+        }
+        let id = tables.comment_ids.next_id();
+        let comment = Comment { id, todo_id, body: body.to_string(), created_at: OffsetDateTime::now_utc() };
+        tables.comments.insert(id, comment);
+        Ok(Some(id))
+    }
+    async fn delete_comment(&self, user_id: i64, todo_id: i64, comment_id: i64) -> Result<bool, RepoError> {
+        let mut tables = self.tables.lock().unwrap();
+        let found = tables.comments.get(&comment_id).is_some_and(|comment| comment.todo_id == todo_id);
+        if !found || tables.get(user_id, todo_id).is_none() {
+            return Ok(false);
+        }
+        tables.comments.remove(&comment_id);
+        Ok(true)
+    }
+    async fn erase_user(&self, user_id: i64, dry_run: bool) -> Result<TodoErasure, RepoError> {
+        let mut tables = self.tables.lock().unwrap();
+        if dry_run {
+            return Ok(tables.clone().erase_user(user_id));
+        }
+        Ok(tables.erase_user(user_id))
+    }
+}
+
+///
+/// The repo that `TODO_REPO` picks, when the app starts.
+///
+pub type SharedTodoRepo = Arc<dyn TodoRepo>;
+
+#[async_trait]
+impl TodoRepo for SharedTodoRepo {
+    async fn get_todos(&self, user_id: i64, sort: TodoSort) -> Result<Vec<Todo>, RepoError> {
+        self.as_ref().get_todos(user_id, sort).await
+    }
+    async fn get_todos_json(&self, user_id: i64, sort: TodoSort) -> Result<Bytes, RepoError> {
+        self.as_ref().get_todos_json(user_id, sort).await
+    }
+    async fn get_todos_page(
+        &self,
+        user_id: i64,
+        after: Option<TodoCursor>,
+        limit: i64,
+    ) -> Result<Vec<Todo>, RepoError> {
+        self.as_ref().get_todos_page(user_id, after, limit).await
+    }
+    async fn get_todo(&self, user_id: i64, id: i64) -> Result<Option<Todo>, RepoError> {
+        self.as_ref().get_todo(user_id, id).await
+    }
+    async fn get_todos_filtered(&self, user_id: i64, filter: &TodoFilter) -> Result<Vec<Todo>, RepoError> {
+        self.as_ref().get_todos_filtered(user_id, filter).await
+    }
+    async fn get_todo_tree(&self, user_id: i64, id: i64) -> Result<Vec<Todo>, RepoError> {
+        self.as_ref().get_todo_tree(user_id, id).await
+    }
+    async fn set_parent(
+        &self,
+        user_id: i64,
+        id: i64,
+        parent_id: Option<i64>,
+    ) -> Result<Result<(), SubtaskError>, RepoError> {
+        self.as_ref().set_parent(user_id, id, parent_id).await
+    }
+    async fn get_overdue_todos(&self, user_id: i64, now: OffsetDateTime) -> Result<Vec<Todo>, RepoError> {
+        self.as_ref().get_overdue_todos(user_id, now).await
+    }
+    async fn get_todos_due_on(&self, user_id: i64, day: Date, time_zone: &str) -> Result<Option<Vec<Todo>>, RepoError> {
+        self.as_ref().get_todos_due_on(user_id, day, time_zone).await
+    }
+    async fn get_stats(&self, user_id: i64) -> Result<TodoStats, RepoError> {
+        self.as_ref().get_stats(user_id).await
+    }
+    async fn create_todo(
+        &self,
+        user_id: i64,
+        title: &str,
+        description: &str,
+        due_at: Option<OffsetDateTime>,
+        priority: i32,
+    ) -> Result<i64, RepoError> {
+        self.as_ref().create_todo(user_id, title, description, due_at, priority).await
+    }
+    async fn update_todo(
+        &self,
+        user_id: i64,
+        id: i64,
+        title: Option<&str>,
+        description: Option<&str>,
+        status: Option<TodoStatus>,
+        due_at: Option<OffsetDateTime>,
+        priority: Option<i32>,
+    ) -> Result<Option<i64>, RepoError> {
+        self.as_ref().update_todo(user_id, id, title, description, status, due_at, priority).await
+    }
+    async fn replace_todo(&self, user_id: i64, id: i64, todo: &PatchableTodo) -> Result<Option<i64>, RepoError> {
+        self.as_ref().replace_todo(user_id, id, todo).await
+    }
+    async fn patch_todo(
+        &self,
+        user_id: i64,
+        id: i64,
+        patch: &TodoPatch<'_>,
+    ) -> Result<Option<Result<i64, ExtractError>>, RepoError> {
+        self.as_ref().patch_todo(user_id, id, patch).await
+    }
+    async fn delete_todo(&self, user_id: i64, id: i64) -> Result<Option<i64>, RepoError> {
+        self.as_ref().delete_todo(user_id, id).await
+    }
+    async fn create_many(&self, user_id: i64, todos: &[CreateTodo]) -> Result<Vec<i64>, RepoError> {
+        self.as_ref().create_many(user_id, todos).await
+    }
+    async fn bulk(
+        &self,
+        user_id: i64,
+        operations: &[BulkOperation],
+        atomic: bool,
+    ) -> Result<Vec<Result<i64, BulkError>>, RepoError> {
+        self.as_ref().bulk(user_id, operations, atomic).await
+    }
+    async fn claim_next_todo(&self, user_id: i64) -> Result<Option<Todo>, RepoError> {
+        self.as_ref().claim_next_todo(user_id).await
+    }
+    async fn get_comments(&self, user_id: i64, todo_id: i64) -> Result<Vec<Comment>, RepoError> {
+        self.as_ref().get_comments(user_id, todo_id).await
+    }
+    async fn get_comments_of(&self, user_id: i64, todo_ids: &[i64]) -> Result<Vec<Comment>, RepoError> {
+        self.as_ref().get_comments_of(user_id, todo_ids).await
+    }
+    async fn create_comment(&self, user_id: i64, todo_id: i64, body: &str) -> Result<Option<i64>, RepoError> {
+        self.as_ref().create_comment(user_id, todo_id, body).await
+    }
+    async fn delete_comment(&self, user_id: i64, todo_id: i64, comment_id: i64) -> Result<bool, RepoError> {
+        self.as_ref().delete_comment(user_id, todo_id, comment_id).await
+    }
+    async fn erase_user(&self, user_id: i64, dry_run: bool) -> Result<TodoErasure, RepoError> {
+        self.as_ref().erase_user(user_id, dry_run).await
+    }
+    fn export_user(&self, user_id: i64) -> BoxStream<'_, Result<Todo, RepoError>> {
+        self.as_ref().export_user(user_id)
+    }
+}
+
+///
+/// Wraps another repo, so that concurrent `get_todo` calls for the same todo
+/// and user share a single query. Every other method goes straight through.
+///
+#[derive(Clone)]
+struct CoalescingTodoRepo<R: TodoRepo> {
+    inner: R,
+    flights: SingleFlight<(i64, i64), Result<Option<Todo>, RepoError>>,
+}
+
+impl<R: TodoRepo> CoalescingTodoRepo<R> {
+    fn new(inner: R) -> Self {
+        CoalescingTodoRepo { inner, flights: SingleFlight::default() }
+    }
+}
+
+#[async_trait]
+impl<R: TodoRepo> TodoRepo for CoalescingTodoRepo<R> {
+    async fn get_todos(&self, user_id: i64, sort: TodoSort) -> Result<Vec<Todo>, RepoError> {
+        self.inner.get_todos(user_id, sort).await
+    }
+    async fn get_todos_json(&self, user_id: i64, sort: TodoSort) -> Result<Bytes, RepoError> {
+        self.inner.get_todos_json(user_id, sort).await
+    }
+    async fn get_todos_page(
+        &self,
+        user_id: i64,
+        after: Option<TodoCursor>,
+        limit: i64,
+    ) -> Result<Vec<Todo>, RepoError> {
+        self.inner.get_todos_page(user_id, after, limit).await
+    }
+    async fn get_todo(&self, user_id: i64, id: i64) -> Result<Option<Todo>, RepoError> {
+        // The user is part of the key: the same todo may be visible to one
+        // user and not to another.
+        self.flights.run((user_id, id), || self.inner.get_todo(user_id, id)).await
+    }
+    async fn get_todos_filtered(&self, user_id: i64, filter: &TodoFilter) -> Result<Vec<Todo>, RepoError> {
+        self.inner.get_todos_filtered(user_id, filter).await
+    }
+    async fn get_todo_tree(&self, user_id: i64, id: i64) -> Result<Vec<Todo>, RepoError> {
+        self.inner.get_todo_tree(user_id, id).await
+    }
+    async fn set_parent(
+        &self,
+        user_id: i64,
+        id: i64,
+        parent_id: Option<i64>,
+    ) -> Result<Result<(), SubtaskError>, RepoError> {
+        self.inner.set_parent(user_id, id, parent_id).await
+    }
+    async fn get_overdue_todos(&self, user_id: i64, now: OffsetDateTime) -> Result<Vec<Todo>, RepoError> {
+        self.inner.get_overdue_todos(user_id, now).await
+    }
+    async fn get_todos_due_on(&self, user_id: i64, day: Date, time_zone: &str) -> Result<Option<Vec<Todo>>, RepoError> {
+        self.inner.get_todos_due_on(user_id, day, time_zone).await
+    }
+    async fn get_stats(&self, user_id: i64) -> Result<TodoStats, RepoError> {
+        self.inner.get_stats(user_id).await
+    }
+    async fn create_todo(
+        &self,
+        user_id: i64,
+        title: &str,
+        description: &str,
+        due_at: Option<OffsetDateTime>,
+        priority: i32,
+    ) -> Result<i64, RepoError> {
+        self.inner.create_todo(user_id, title, description, due_at, priority).await
+    }
+    async fn update_todo(
+        &self,
+        user_id: i64,
+        id: i64,
+        title: Option<&str>,
+        description: Option<&str>,
+        status: Option<TodoStatus>,
+        due_at: Option<OffsetDateTime>,
+        priority: Option<i32>,
+    ) -> Result<Option<i64>, RepoError> {
+        self.inner.update_todo(user_id, id, title, description, status, due_at, priority).await
+    }
+    async fn replace_todo(&self, user_id: i64, id: i64, todo: &PatchableTodo) -> Result<Option<i64>, RepoError> {
+        self.inner.replace_todo(user_id, id, todo).await
+    }
+    async fn patch_todo(
+        &self,
+        user_id: i64,
+        id: i64,
+        patch: &TodoPatch<'_>,
+    ) -> Result<Option<Result<i64, ExtractError>>, RepoError> {
+        self.inner.patch_todo(user_id, id, patch).await
+    }
+    async fn delete_todo(&self, user_id: i64, id: i64) -> Result<Option<i64>, RepoError> {
+        self.inner.delete_todo(user_id, id).await
+    }
+    async fn create_many(&self, user_id: i64, todos: &[CreateTodo]) -> Result<Vec<i64>, RepoError> {
+        self.inner.create_many(user_id, todos).await
+    }
+    async fn bulk(
+        &self,
+        user_id: i64,
+        operations: &[BulkOperation],
+        atomic: bool,
+    ) -> Result<Vec<Result<i64, BulkError>>, RepoError> {
+        self.inner.bulk(user_id, operations, atomic).await
+    }
+    async fn claim_next_todo(&self, user_id: i64) -> Result<Option<Todo>, RepoError> {
+        self.inner.claim_next_todo(user_id).await
+    }
+    async fn get_comments(&self, user_id: i64, todo_id: i64) -> Result<Vec<Comment>, RepoError> {
+        self.inner.get_comments(user_id, todo_id).await
+    }
+    async fn get_comments_of(&self, user_id: i64, todo_ids: &[i64]) -> Result<Vec<Comment>, RepoError> {
+        self.inner.get_comments_of(user_id, todo_ids).await
+    }
+    async fn create_comment(&self, user_id: i64, todo_id: i64, body: &str) -> Result<Option<i64>, RepoError> {
+        self.inner.create_comment(user_id, todo_id, body).await
+    }
+    async fn delete_comment(&self, user_id: i64, todo_id: i64, comment_id: i64) -> Result<bool, RepoError> {
+        self.inner.delete_comment(user_id, todo_id, comment_id).await
+    }
+    async fn erase_user(&self, user_id: i64, dry_run: bool) -> Result<TodoErasure, RepoError> {
+        self.inner.erase_user(user_id, dry_run).await
+    }
+}
+
+///
+/// Serialized todo lists, as JSON, keyed by the user and order they were
+/// requested in.
+///
+/// `Bytes` is reference counted: handing a cached list to a response is a
+/// pointer copy, however large the list, where `Json(Vec<TodoDTO>)` would
+/// serialize it all over again for every request.
+///
+#[derive(Clone, Default)]
+struct TodoListCache {
+    entries: Arc<std::sync::RwLock<HashMap<(i64, TodoSort), CachedList>>>,
+}
+
+struct CachedList {
+    cached_at: Instant,
+    json: Bytes,
+}
+
+impl TodoListCache {
+    ///
+    /// Todos can also change without going through a `TodoRepo` (shared
+    /// lists, the recurrence scheduler), so entries expire after a while even
+    /// when nothing invalidates them.
+    ///
+    const MAX_AGE: Duration = Duration::from_secs(10);
+
+    fn get(&self, key: (i64, TodoSort)) -> Option<Bytes> {
+        let entries = self.entries.read().unwrap();
+        entries
+            .get(&key)
+            .filter(|list| list.cached_at.elapsed() < Self::MAX_AGE)
+            .map(|list| list.json.clone())
+    }
+
+    ///
+    /// The cached list, however old it is.
+    ///
+    fn get_stale(&self, key: (i64, TodoSort)) -> Option<Bytes> {
+        self.entries.read().unwrap().get(&key).map(|list| list.json.clone())
+    }
+
+    fn insert(&self, key: (i64, TodoSort), json: Bytes) {
+        self.entries.write().unwrap().insert(key, CachedList { cached_at: Instant::now(), json });
+    }
+
+    ///
+    /// Forgets every list, not just the current user's: admins see everyone's
+    /// todos, so any change can show up in someone else's list.
+    ///
+    fn invalidate(&self) {
+        self.entries.write().unwrap().clear();
+    }
+}
+
+///
+/// Wraps another repo, serving todo lists from a `TodoListCache`, and
+/// invalidating the cache whenever a todo is created, changed or deleted.
+/// While the database is down, or when a query fails, expired lists are
+/// served rather than none.
+///
+#[derive(Clone)]
+struct CachingTodoRepo<R: TodoRepo> {
+    inner: R,
+    cache: TodoListCache,
+    health: DbHealth,
+}
+
+impl<R: TodoRepo> CachingTodoRepo<R> {
+    fn new(inner: R) -> Self {
+        CachingTodoRepo { inner, cache: TodoListCache::default(), health: DbHealth::default() }
+    }
+
+    fn with_health(self, health: DbHealth) -> Self {
+        CachingTodoRepo { health, ..self }
+    }
+}
+
+#[async_trait]
+impl<R: TodoRepo> TodoRepo for CachingTodoRepo<R> {
+    async fn get_todos(&self, user_id: i64, sort: TodoSort) -> Result<Vec<Todo>, RepoError> {
+        self.inner.get_todos(user_id, sort).await
+    }
+    async fn get_todos_json(&self, user_id: i64, sort: TodoSort) -> Result<Bytes, RepoError> {
+        if let Some(json) = self.cache.get((user_id, sort)) {
+            return Ok(json);
+        }
+        if !self.health.is_up() {
+            if let Some(json) = self.cache.get_stale((user_id, sort)) {
+                return Ok(json);
+            }
+        }
+        // The health check may not have noticed yet that the database is
+        // down, so a failed query falls back on the stale list too.
+        match self.inner.get_todos_json(user_id, sort).await {
+            Ok(json) => {
+                self.cache.insert((user_id, sort), json.clone());
+                Ok(json)
+            }
+            Err(error) => self.cache.get_stale((user_id, sort)).ok_or(error),
+        }
+    }
+    async fn get_todos_page(
+        &self,
+        user_id: i64,
+        after: Option<TodoCursor>,
+        limit: i64,
+    ) -> Result<Vec<Todo>, RepoError> {
+        self.inner.get_todos_page(user_id, after, limit).await
+    }
+    async fn get_todo(&self, user_id: i64, id: i64) -> Result<Option<Todo>, RepoError> {
+        self.inner.get_todo(user_id, id).await
+    }
+    async fn get_todos_filtered(&self, user_id: i64, filter: &TodoFilter) -> Result<Vec<Todo>, RepoError> {
+        self.inner.get_todos_filtered(user_id, filter).await
+    }
+    async fn get_todo_tree(&self, user_id: i64, id: i64) -> Result<Vec<Todo>, RepoError> {
+        self.inner.get_todo_tree(user_id, id).await
+    }
+    async fn set_parent(
+        &self,
+        user_id: i64,
+        id: i64,
+        parent_id: Option<i64>,
+    ) -> Result<Result<(), SubtaskError>, RepoError> {
+        let result = self.inner.set_parent(user_id, id, parent_id).await;
+        self.cache.invalidate();
+        result
+    }
+    async fn get_overdue_todos(&self, user_id: i64, now: OffsetDateTime) -> Result<Vec<Todo>, RepoError> {
+        self.inner.get_overdue_todos(user_id, now).await
+    }
+    async fn get_todos_due_on(&self, user_id: i64, day: Date, time_zone: &str) -> Result<Option<Vec<Todo>>, RepoError> {
+        self.inner.get_todos_due_on(user_id, day, time_zone).await
+    }
+    async fn get_stats(&self, user_id: i64) -> Result<TodoStats, RepoError> {
+        self.inner.get_stats(user_id).await
+    }
+    async fn create_todo(
+        &self,
+        user_id: i64,
+        title: &str,
+        description: &str,
+        due_at: Option<OffsetDateTime>,
+        priority: i32,
+    ) -> Result<i64, RepoError> {
+        let id = self.inner.create_todo(user_id, title, description, due_at, priority).await;
+        self.cache.invalidate();
+        id
+    }
+    async fn update_todo(
+        &self,
+        user_id: i64,
+        id: i64,
+        title: Option<&str>,
+        description: Option<&str>,
+        status: Option<TodoStatus>,
+        due_at: Option<OffsetDateTime>,
+        priority: Option<i32>,
+    ) -> Result<Option<i64>, RepoError> {
+        let id = self.inner.update_todo(user_id, id, title, description, status, due_at, priority).await;
+        self.cache.invalidate();
+        id
+    }
+    async fn replace_todo(&self, user_id: i64, id: i64, todo: &PatchableTodo) -> Result<Option<i64>, RepoError> {
+        let id = self.inner.replace_todo(user_id, id, todo).await;
+        self.cache.invalidate();
+        id
+    }
+    async fn patch_todo(
+        &self,
+        user_id: i64,
+        id: i64,
+        patch: &TodoPatch<'_>,
+    ) -> Result<Option<Result<i64, ExtractError>>, RepoError> {
+        let id = self.inner.patch_todo(user_id, id, patch).await;
+        self.cache.invalidate();
+        id
+    }
+    async fn delete_todo(&self, user_id: i64, id: i64) -> Result<Option<i64>, RepoError> {
+        let id = self.inner.delete_todo(user_id, id).await;
+        self.cache.invalidate();
+        id
+    }
+    async fn create_many(&self, user_id: i64, todos: &[CreateTodo]) -> Result<Vec<i64>, RepoError> {
+        let ids = self.inner.create_many(user_id, todos).await;
+        self.cache.invalidate();
+        ids
+    }
+    async fn bulk(
+        &self,
+        user_id: i64,
+        operations: &[BulkOperation],
+        atomic: bool,
+    ) -> Result<Vec<Result<i64, BulkError>>, RepoError> {
+        let results = self.inner.bulk(user_id, operations, atomic).await;
+        self.cache.invalidate();
+        results
+    }
+    async fn claim_next_todo(&self, user_id: i64) -> Result<Option<Todo>, RepoError> {
+        let todo = self.inner.claim_next_todo(user_id).await;
+        self.cache.invalidate();
+        todo
+    }
+    async fn get_comments(&self, user_id: i64, todo_id: i64) -> Result<Vec<Comment>, RepoError> {
+        self.inner.get_comments(user_id, todo_id).await
+    }
+    async fn get_comments_of(&self, user_id: i64, todo_ids: &[i64]) -> Result<Vec<Comment>, RepoError> {
+        self.inner.get_comments_of(user_id, todo_ids).await
+    }
+    async fn create_comment(&self, user_id: i64, todo_id: i64, body: &str) -> Result<Option<i64>, RepoError> {
+        self.inner.create_comment(user_id, todo_id, body).await
+    }
+    async fn delete_comment(&self, user_id: i64, todo_id: i64, comment_id: i64) -> Result<bool, RepoError> {
+        self.inner.delete_comment(user_id, todo_id, comment_id).await
+    }
+    async fn erase_user(&self, user_id: i64, dry_run: bool) -> Result<TodoErasure, RepoError> {
+        let erasure = self.inner.erase_user(user_id, dry_run).await;
+        if !dry_run {
+            self.cache.invalidate();
+        }
+        erasure
+    }
+}
+
+///
+/// Wraps another repo, timing every call. The durations go into the
+/// `todo_repo_call_duration_seconds` histogram, labelled with the method, and
+/// calls slower than the threshold are logged as warnings, with their
+/// parameters. Parameters that users write themselves (titles, descriptions,
+/// comments, metadata) are redacted, since logs are read by more people, and
+/// kept for longer, than the database.
+///
+#[derive(Clone)]
+struct InstrumentedTodoRepo<R: TodoRepo> {
+    inner: R,
+    slow_threshold: Duration,
+}
+
+impl<R: TodoRepo> InstrumentedTodoRepo<R> {
+    fn new(inner: R, slow_threshold: Duration) -> Self {
+        InstrumentedTodoRepo { inner, slow_threshold }
+    }
+
+    async fn timed<T>(&self, method: &'static str, params: impl FnOnce() -> String, call: impl Future<Output = T>) -> T {
+        let start = Instant::now();
+        let result = call.await;
+        let elapsed = start.elapsed();
+
+        metrics::histogram!("todo_repo_call_duration_seconds", elapsed.as_secs_f64(), "method" => method);
+        if elapsed >= self.slow_threshold {
+            tracing::warn!(method, params = params(), elapsed_ms = elapsed.as_millis() as u64, "Slow todo repo call");
+        }
+        result
+    }
+}
+
+const REDACTED: &str = "<redacted>";
+
+#[async_trait]
+impl<R: TodoRepo> TodoRepo for InstrumentedTodoRepo<R> {
+    async fn get_todos(&self, user_id: i64, sort: TodoSort) -> Result<Vec<Todo>, RepoError> {
+        let params = || format!("user_id={} sort={:?}", user_id, sort);
+        self.timed("get_todos", params, self.inner.get_todos(user_id, sort)).await
+    }
+    async fn get_todos_json(&self, user_id: i64, sort: TodoSort) -> Result<Bytes, RepoError> {
+        let params = || format!("user_id={} sort={:?}", user_id, sort);
+        self.timed("get_todos_json", params, self.inner.get_todos_json(user_id, sort)).await
+    }
+    async fn get_todos_page(
+        &self,
+        user_id: i64,
+        after: Option<TodoCursor>,
+        limit: i64,
+    ) -> Result<Vec<Todo>, RepoError> {
+        let params = format!("user_id={} after={:?} limit={}", user_id, after, limit);
+        self.timed("get_todos_page", || params, self.inner.get_todos_page(user_id, after, limit)).await
+    }
+    async fn get_todo(&self, user_id: i64, id: i64) -> Result<Option<Todo>, RepoError> {
+        let params = || format!("user_id={} id={}", user_id, id);
+        self.timed("get_todo", params, self.inner.get_todo(user_id, id)).await
+    }
+    async fn get_todos_filtered(&self, user_id: i64, filter: &TodoFilter) -> Result<Vec<Todo>, RepoError> {
+        let params = || format!("user_id={} filter={}", user_id, REDACTED);
+        self.timed("get_todos_filtered", params, self.inner.get_todos_filtered(user_id, filter)).await
+    }
+    async fn get_todo_tree(&self, user_id: i64, id: i64) -> Result<Vec<Todo>, RepoError> {
+        let params = || format!("user_id={} id={}", user_id, id);
+        self.timed("get_todo_tree", params, self.inner.get_todo_tree(user_id, id)).await
+    }
+    async fn set_parent(
+        &self,
+        user_id: i64,
+        id: i64,
+        parent_id: Option<i64>,
+    ) -> Result<Result<(), SubtaskError>, RepoError> {
+        let params = || format!("user_id={} id={} parent_id={:?}", user_id, id, parent_id);
+        self.timed("set_parent", params, self.inner.set_parent(user_id, id, parent_id)).await
+    }
+    async fn get_overdue_todos(&self, user_id: i64, now: OffsetDateTime) -> Result<Vec<Todo>, RepoError> {
+        let params = || format!("user_id={} now={}", user_id, now);
+        self.timed("get_overdue_todos", params, self.inner.get_overdue_todos(user_id, now)).await
+    }
+    async fn get_todos_due_on(&self, user_id: i64, day: Date, time_zone: &str) -> Result<Option<Vec<Todo>>, RepoError> {
+        let params = || format!("user_id={} day={} time_zone={}", user_id, day, time_zone);
+        self.timed("get_todos_due_on", params, self.inner.get_todos_due_on(user_id, day, time_zone)).await
+    }
+    async fn get_stats(&self, user_id: i64) -> Result<TodoStats, RepoError> {
+        let params = || format!("user_id={}", user_id);
+        self.timed("get_stats", params, self.inner.get_stats(user_id)).await
+    }
+    async fn create_todo(
+        &self,
+        user_id: i64,
+        title: &str,
+        description: &str,
+        due_at: Option<OffsetDateTime>,
+        priority: i32,
+    ) -> Result<i64, RepoError> {
+        let params = || {
+            format!(
+                "user_id={} title={} description={} due_at={:?} priority={}",
+                user_id, REDACTED, REDACTED, due_at, priority
+            )
+        };
+        let call = self.inner.create_todo(user_id, title, description, due_at, priority);
+        self.timed("create_todo", params, call).await
+    }
+    async fn update_todo(
+        &self,
+        user_id: i64,
+        id: i64,
+        title: Option<&str>,
+        description: Option<&str>,
+        status: Option<TodoStatus>,
+        due_at: Option<OffsetDateTime>,
+        priority: Option<i32>,
+    ) -> Result<Option<i64>, RepoError> {
+        let params = || {
+            format!(
+                "user_id={} id={} title={} description={} status={:?} due_at={:?} priority={:?}",
+                user_id,
+                id,
+                title.map_or("None", |_| REDACTED),
+                description.map_or("None", |_| REDACTED),
+                status,
+                due_at,
+                priority
+            )
+        };
+        let call = self.inner.update_todo(user_id, id, title, description, status, due_at, priority);
+        self.timed("update_todo", params, call).await
+    }
+    async fn replace_todo(&self, user_id: i64, id: i64, todo: &PatchableTodo) -> Result<Option<i64>, RepoError> {
+        let params = || format!("user_id={} id={} todo={}", user_id, id, REDACTED);
+        self.timed("replace_todo", params, self.inner.replace_todo(user_id, id, todo)).await
+    }
+    async fn patch_todo(
+        &self,
+        user_id: i64,
+        id: i64,
+        patch: &TodoPatch<'_>,
+    ) -> Result<Option<Result<i64, ExtractError>>, RepoError> {
+        let params = || format!("user_id={} id={}", user_id, id);
+        self.timed("patch_todo", params, self.inner.patch_todo(user_id, id, patch)).await
+    }
+    async fn delete_todo(&self, user_id: i64, id: i64) -> Result<Option<i64>, RepoError> {
+        let params = || format!("user_id={} id={}", user_id, id);
+        self.timed("delete_todo", params, self.inner.delete_todo(user_id, id)).await
+    }
+    async fn create_many(&self, user_id: i64, todos: &[CreateTodo]) -> Result<Vec<i64>, RepoError> {
+        let params = || format!("user_id={} todos=<{} todos>", user_id, todos.len());
+        self.timed("create_many", params, self.inner.create_many(user_id, todos)).await
+    }
+    async fn bulk(
+        &self,
+        user_id: i64,
+        operations: &[BulkOperation],
+        atomic: bool,
+    ) -> Result<Vec<Result<i64, BulkError>>, RepoError> {
+        let params = || format!("user_id={} operations=<{} operations> atomic={}", user_id, operations.len(), atomic);
+        self.timed("bulk", params, self.inner.bulk(user_id, operations, atomic)).await
+    }
+    async fn claim_next_todo(&self, user_id: i64) -> Result<Option<Todo>, RepoError> {
+        let params = || format!("user_id={}", user_id);
+        self.timed("claim_next_todo", params, self.inner.claim_next_todo(user_id)).await
+    }
+    async fn get_comments(&self, user_id: i64, todo_id: i64) -> Result<Vec<Comment>, RepoError> {
+        let params = || format!("user_id={} todo_id={}", user_id, todo_id);
+        self.timed("get_comments", params, self.inner.get_comments(user_id, todo_id)).await
+    }
+    async fn get_comments_of(&self, user_id: i64, todo_ids: &[i64]) -> Result<Vec<Comment>, RepoError> {
+        let params = || format!("user_id={} todo_ids={:?}", user_id, todo_ids);
+        self.timed("get_comments_of", params, self.inner.get_comments_of(user_id, todo_ids)).await
+    }
+    async fn create_comment(&self, user_id: i64, todo_id: i64, body: &str) -> Result<Option<i64>, RepoError> {
+        let params = || format!("user_id={} todo_id={} body={}", user_id, todo_id, REDACTED);
+        self.timed("create_comment", params, self.inner.create_comment(user_id, todo_id, body)).await
+    }
+    async fn delete_comment(&self, user_id: i64, todo_id: i64, comment_id: i64) -> Result<bool, RepoError> {
+        let params = || format!("user_id={} todo_id={} comment_id={}", user_id, todo_id, comment_id);
+        self.timed("delete_comment", params, self.inner.delete_comment(user_id, todo_id, comment_id)).await
+    }
+    async fn erase_user(&self, user_id: i64, dry_run: bool) -> Result<TodoErasure, RepoError> {
+        let params = || format!("user_id={} dry_run={}", user_id, dry_run);
+        self.timed("erase_user", params, self.inner.erase_user(user_id, dry_run)).await
+    }
+}
+
+///
+/// Wraps two repos, the one in use and a candidate to replace it, such as a
+/// rewrite on another storage. Reads go to both at once, the caller gets the
+/// primary's answer, and the candidate's is compared with it: as JSON, like
+/// the responses of `shadow.rs`, so that the comparison sees what clients
+/// would. Disagreements are logged as warnings with the paths that differ,
+/// never their values, and every comparison is counted in
+/// `todo_repo_comparisons_total`, by method and result. Once the candidate
+/// has agreed long enough, it can be swapped in.
+///
+/// Writes go to the primary only: the candidate has to follow it by its own
+/// means, such as projecting the primary's changes, or it would give out ids
+/// of its own. A candidate slower than `candidate_timeout` is not waited for.
+///
+#[derive(Clone)]
+struct ComparingTodoRepo<A: TodoRepo, B: TodoRepo> {
+    primary: A,
+    candidate: B,
+    candidate_timeout: Duration,
+}
+
+impl<A: TodoRepo, B: TodoRepo> ComparingTodoRepo<A, B> {
+    fn new(primary: A, candidate: B, candidate_timeout: Duration) -> Self {
+        ComparingTodoRepo { primary, candidate, candidate_timeout }
+    }
+
+    async fn compared<T: Compared>(
+        &self,
+        method: &'static str,
+        params: impl FnOnce() -> String,
+        primary: impl Future<Output = T>,
+        candidate: impl Future<Output = T>,
+    ) -> T {
+        let (primary, candidate) = tokio::join!(primary, tokio::time::timeout(self.candidate_timeout, candidate));
+        let result = match candidate {
+            Ok(candidate) => {
+                let mut differences = Vec::new();
+                crate::shadow::diff("$".to_string(), &primary.to_json(), &candidate.to_json(), &mut differences);
+                if differences.is_empty() {
+                    "match"
+                } else {
+                    tracing::warn!(method, params = params(), ?differences, "Todo repos disagree");
+                    "mismatch"
+                }
+            }
+            Err(_) => "timeout",
+        };
+        metrics::increment_counter!("todo_repo_comparisons_total", "method" => method, "result" => result);
+        primary
+    }
+}
+
+///
+/// What `ComparingTodoRepo` compares of the results of reads.
+///
+trait Compared {
+    fn to_json(&self) -> serde_json::Value;
+}
+
+impl Compared for Todo {
+    fn to_json(&self) -> serde_json::Value {
+        serde_json::to_value(self.to_dto()).unwrap()
+    }
+}
+
+impl Compared for Comment {
+    fn to_json(&self) -> serde_json::Value {
+        serde_json::to_value(self.to_dto()).unwrap()
+    }
+}
+
+impl Compared for TodoStats {
+    fn to_json(&self) -> serde_json::Value {
+        serde_json::to_value(self).unwrap()
+    }
+}
+
+impl Compared for Bytes {
+    fn to_json(&self) -> serde_json::Value {
+        serde_json::from_slice(self).unwrap_or_else(|_| String::from_utf8_lossy(self).into())
+    }
+}
+
+impl<T: Compared> Compared for Vec<T> {
+    fn to_json(&self) -> serde_json::Value {
+        self.iter().map(T::to_json).collect()
+    }
+}
+
+impl<T: Compared> Compared for Option<T> {
+    fn to_json(&self) -> serde_json::Value {
+        self.as_ref().map_or(serde_json::Value::Null, T::to_json)
+    }
+}
+
+///
+/// A failed call agrees only with another failed call, whatever the error.
+///
+impl<T: Compared> Compared for Result<T, RepoError> {
+    fn to_json(&self) -> serde_json::Value {
+        match self {
+            Ok(value) => value.to_json(),
+            Err(_) => serde_json::json!({ "error": "database error" }),
+        }
+    }
+}
+
+#[async_trait]
+impl<A: TodoRepo, B: TodoRepo> TodoRepo for ComparingTodoRepo<A, B> {
+    async fn get_todos(&self, user_id: i64, sort: TodoSort) -> Result<Vec<Todo>, RepoError> {
+        let params = || format!("user_id={} sort={:?}", user_id, sort);
+        let (primary, candidate) = (self.primary.get_todos(user_id, sort), self.candidate.get_todos(user_id, sort));
+        self.compared("get_todos", params, primary, candidate).await
+    }
+    async fn get_todos_json(&self, user_id: i64, sort: TodoSort) -> Result<Bytes, RepoError> {
+        let params = || format!("user_id={} sort={:?}", user_id, sort);
+        let (primary, candidate) = (self.primary.get_todos_json(user_id, sort), self.candidate.get_todos_json(user_id, sort));
+        self.compared("get_todos_json", params, primary, candidate).await
+    }
+    async fn get_todos_page(
+        &self,
+        user_id: i64,
+        after: Option<TodoCursor>,
+        limit: i64,
+    ) -> Result<Vec<Todo>, RepoError> {
+        let params = || format!("user_id={} after={:?} limit={}", user_id, after, limit);
+        let primary = self.primary.get_todos_page(user_id, after, limit);
+        let candidate = self.candidate.get_todos_page(user_id, after, limit);
+        self.compared("get_todos_page", params, primary, candidate).await
+    }
+    async fn get_todo(&self, user_id: i64, id: i64) -> Result<Option<Todo>, RepoError> {
+        let params = || format!("user_id={} id={}", user_id, id);
+        let (primary, candidate) = (self.primary.get_todo(user_id, id), self.candidate.get_todo(user_id, id));
+        self.compared("get_todo", params, primary, candidate).await
+    }
+    async fn get_todos_filtered(&self, user_id: i64, filter: &TodoFilter) -> Result<Vec<Todo>, RepoError> {
+        let params = || format!("user_id={} filter={}", user_id, REDACTED);
+        let primary = self.primary.get_todos_filtered(user_id, filter);
+        let candidate = self.candidate.get_todos_filtered(user_id, filter);
+        self.compared("get_todos_filtered", params, primary, candidate).await
+    }
+    async fn get_todo_tree(&self, user_id: i64, id: i64) -> Result<Vec<Todo>, RepoError> {
+        let params = || format!("user_id={} id={}", user_id, id);
+        let (primary, candidate) = (self.primary.get_todo_tree(user_id, id), self.candidate.get_todo_tree(user_id, id));
+        self.compared("get_todo_tree", params, primary, candidate).await
+    }
+    async fn set_parent(
+        &self,
+        user_id: i64,
+        id: i64,
+        parent_id: Option<i64>,
+    ) -> Result<Result<(), SubtaskError>, RepoError> {
+        self.primary.set_parent(user_id, id, parent_id).await
+    }
+    async fn get_overdue_todos(&self, user_id: i64, now: OffsetDateTime) -> Result<Vec<Todo>, RepoError> {
+        let params = || format!("user_id={} now={}", user_id, now);
+        let primary = self.primary.get_overdue_todos(user_id, now);
+        let candidate = self.candidate.get_overdue_todos(user_id, now);
+        self.compared("get_overdue_todos", params, primary, candidate).await
+    }
+    async fn get_todos_due_on(&self, user_id: i64, day: Date, time_zone: &str) -> Result<Option<Vec<Todo>>, RepoError> {
+        let params = || format!("user_id={} day={} time_zone={}", user_id, day, time_zone);
+        let primary = self.primary.get_todos_due_on(user_id, day, time_zone);
+        let candidate = self.candidate.get_todos_due_on(user_id, day, time_zone);
+        self.compared("get_todos_due_on", params, primary, candidate).await
+    }
+    async fn get_stats(&self, user_id: i64) -> Result<TodoStats, RepoError> {
+        let params = || format!("user_id={}", user_id);
+        let (primary, candidate) = (self.primary.get_stats(user_id), self.candidate.get_stats(user_id));
+        self.compared("get_stats", params, primary, candidate).await
+    }
+    async fn create_todo(
+        &self,
+        user_id: i64,
+        title: &str,
+        description: &str,
+        due_at: Option<OffsetDateTime>,
+        priority: i32,
+    ) -> Result<i64, RepoError> {
+        self.primary.create_todo(user_id, title, description, due_at, priority).await
+    }
+    async fn update_todo(
+        &self,
+        user_id: i64,
+        id: i64,
+        title: Option<&str>,
+        description: Option<&str>,
+        status: Option<TodoStatus>,
+        due_at: Option<OffsetDateTime>,
+        priority: Option<i32>,
+    ) -> Result<Option<i64>, RepoError> {
+        self.primary.update_todo(user_id, id, title, description, status, due_at, priority).await
+    }
+    async fn replace_todo(&self, user_id: i64, id: i64, todo: &PatchableTodo) -> Result<Option<i64>, RepoError> {
+        self.primary.replace_todo(user_id, id, todo).await
+    }
+    async fn patch_todo(
+        &self,
+        user_id: i64,
+        id: i64,
+        patch: &TodoPatch<'_>,
+    ) -> Result<Option<Result<i64, ExtractError>>, RepoError> {
+        self.primary.patch_todo(user_id, id, patch).await
+    }
+    async fn delete_todo(&self, user_id: i64, id: i64) -> Result<Option<i64>, RepoError> {
+        self.primary.delete_todo(user_id, id).await
+    }
+    async fn create_many(&self, user_id: i64, todos: &[CreateTodo]) -> Result<Vec<i64>, RepoError> {
+        self.primary.create_many(user_id, todos).await
+    }
+    async fn bulk(
+        &self,
+        user_id: i64,
+        operations: &[BulkOperation],
+        atomic: bool,
+    ) -> Result<Vec<Result<i64, BulkError>>, RepoError> {
+        self.primary.bulk(user_id, operations, atomic).await
+    }
+    async fn claim_next_todo(&self, user_id: i64) -> Result<Option<Todo>, RepoError> {
+        // Claiming writes: only one repo can hand out the todo.
+        self.primary.claim_next_todo(user_id).await
+    }
+    async fn get_comments(&self, user_id: i64, todo_id: i64) -> Result<Vec<Comment>, RepoError> {
+        let params = || format!("user_id={} todo_id={}", user_id, todo_id);
+        let (primary, candidate) = (self.primary.get_comments(user_id, todo_id), self.candidate.get_comments(user_id, todo_id));
+        self.compared("get_comments", params, primary, candidate).await
+    }
+    async fn get_comments_of(&self, user_id: i64, todo_ids: &[i64]) -> Result<Vec<Comment>, RepoError> {
+        let params = || format!("user_id={} todo_ids={:?}", user_id, todo_ids);
+        let primary = self.primary.get_comments_of(user_id, todo_ids);
+        let candidate = self.candidate.get_comments_of(user_id, todo_ids);
+        self.compared("get_comments_of", params, primary, candidate).await
+    }
+    async fn create_comment(&self, user_id: i64, todo_id: i64, body: &str) -> Result<Option<i64>, RepoError> {
+        self.primary.create_comment(user_id, todo_id, body).await
+    }
+    async fn delete_comment(&self, user_id: i64, todo_id: i64, comment_id: i64) -> Result<bool, RepoError> {
+        self.primary.delete_comment(user_id, todo_id, comment_id).await
+    }
+    async fn erase_user(&self, user_id: i64, dry_run: bool) -> Result<TodoErasure, RepoError> {
+        self.primary.erase_user(user_id, dry_run).await
+    }
+}
+
+///
+/// A `TodoRepo` for unit tests of the handlers, which answers only the calls
+/// it was told to expect, and fails the test on any other:
+///
+/// ```ignore
+/// let repo = MockTodoRepo::default();
+/// repo.expect_get_todo().with((7, 5)).times(1).returning(|(_, id)| Ok(Some(todo(id))));
+/// ```
+///
+/// Unlike the fakes of the other tests, which behave like a repo, a mock
+/// checks how the handler uses it: which methods it calls, with what, and
+/// how often. Expectations are tried in the order they were set, and the
+/// first one that accepts the arguments answers. Without `times`, an
+/// expectation must be met at least once. Whether they all were is checked
+/// by `checkpoint`, and when the last clone of the mock is dropped.
+///
+#[cfg(test)]
+mod mock_todo_repo {
+    use std::{fmt::Debug, sync::Mutex};
+
+    use super::*;
+
+    ///
+    /// Turns the arguments of a `TodoRepo` method into values that an
+    /// expectation can keep: `&str` into `String`, slices into `Vec`s.
+    ///
+    trait ToArg<T> {
+        fn to_arg(self) -> T;
+    }
+
+    impl<T> ToArg<T> for T {
+        fn to_arg(self) -> T {
+            self
+        }
+    }
+
+    impl<T: Clone> ToArg<T> for &T {
+        fn to_arg(self) -> T {
+            self.clone()
+        }
+    }
+
+    impl ToArg<String> for &str {
+        fn to_arg(self) -> String {
+            self.to_string()
+        }
+    }
+
+    impl ToArg<Option<String>> for Option<&str> {
+        fn to_arg(self) -> Option<String> {
+            self.map(str::to_string)
+        }
+    }
+
+    impl<T: Clone> ToArg<Vec<T>> for &[T] {
+        fn to_arg(self) -> Vec<T> {
+            self.to_vec()
+        }
+    }
+
+    struct Expectation<A, R> {
+        matcher: Box<dyn Fn(&A) -> bool + Send>,
+        answer: Option<Box<dyn FnMut(A) -> R + Send>>,
+        /// `None` for at least once.
+        times: Option<usize>,
+        calls: usize,
+        delay: Duration,
+        description: String,
+    }
+
+    impl<A, R> Expectation<A, R> {
+        fn unmet(&self) -> Option<String> {
+            match self.times {
+                Some(times) if self.calls != times => {
+                    Some(format!("{} was expected {} times, and called {}", self.description, times, self.calls))
+                }
+                None if self.calls == 0 => Some(format!("{} was expected, and never called", self.description)),
+                _ => None,
+            }
+        }
+    }
+
+    /// Adds an expectation to those of its mock.
+    type Register<A, R> = Box<dyn FnOnce(Expectation<A, R>) + Send>;
+
+    ///
+    /// Sets up an expectation, which is registered when the builder is
+    /// dropped, usually at the end of the statement.
+    ///
+    pub struct ExpectationBuilder<A, R> {
+        expectation: Option<Expectation<A, R>>,
+        register: Option<Register<A, R>>,
+    }
+
+    impl<A: Debug + 'static, R: 'static> ExpectationBuilder<A, R> {
+        fn new(method: &str, register: impl FnOnce(Expectation<A, R>) + Send + 'static) -> Self {
+            ExpectationBuilder {
+                expectation: Some(Expectation {
+                    matcher: Box::new(|_| true),
+                    answer: None,
+                    times: None,
+                    calls: 0,
+                    delay: Duration::ZERO,
+                    description: method.to_string(),
+                }),
+                register: Some(Box::new(register)),
+            }
+        }
+
+        fn expectation(&mut self) -> &mut Expectation<A, R> {
+            self.expectation.as_mut().unwrap()
+        }
+
+        ///
+        /// Only matches calls with these arguments, as a tuple of all of
+        /// them after `&self`.
+        ///
+        pub fn with(mut self, args: A) -> Self
+        where
+            A: PartialEq + Send,
+        {
+            let expectation = self.expectation();
+            expectation.description = format!("{}{:?}", expectation.description, args);
+            expectation.matcher = Box::new(move |actual| *actual == args);
+            self
+        }
+
+        ///
+        /// Only matches calls whose arguments pass `predicate`.
+        ///
+        pub fn withf(mut self, predicate: impl Fn(&A) -> bool + Send + 'static) -> Self {
+            self.expectation().matcher = Box::new(predicate);
+            self
+        }
+
+        pub fn times(mut self, times: usize) -> Self {
+            self.expectation().times = Some(times);
+            self
+        }
+
+        ///
+        /// Fails the test if a matching call is made.
+        ///
+        pub fn never(self) -> Self {
+            self.times(0)
+        }
+
+        pub fn returning(mut self, answer: impl FnMut(A) -> R + Send + 'static) -> Self {
+            self.expectation().answer = Some(Box::new(answer));
+            self
+        }
+
+        pub fn return_const(self, value: R) -> Self
+        where
+            R: Clone + Send,
+        {
+            self.returning(move |_| value.clone())
+        }
+
+        ///
+        /// Answers only after `delay`, so that calls made at the same time
+        /// overlap, as they would with a database.
+        ///
+        pub fn delayed(mut self, delay: Duration) -> Self {
+            self.expectation().delay = delay;
+            self
+        }
+    }
+
+    impl<A, R> Drop for ExpectationBuilder<A, R> {
+        fn drop(&mut self) {
+            if let (Some(expectation), Some(register)) = (self.expectation.take(), self.register.take()) {
+                register(expectation);
+            }
+        }
+    }
+
+    ///
+    /// Answers a call with the first expectation that accepts it, and is not
+    /// used up, along with how long to wait before answering.
+    ///
+    fn answer<A: Debug, R>(method: &str, expectations: &mut [Expectation<A, R>], args: A) -> (R, Duration) {
+        let expectation = expectations
+            .iter_mut()
+            .find(|expectation| {
+                (expectation.matcher)(&args) && !matches!(expectation.times, Some(times) if expectation.calls >= times)
+            });
+        let Some(expectation) = expectation else {
+            panic!("Unexpected call: {}{:?}", method, args);
+        };
+        expectation.calls += 1;
+        match &mut expectation.answer {
+            Some(answer) => (answer(args), expectation.delay),
+            None => panic!("{} has no answer, set one with `returning`", expectation.description),
+        }
+    }
+
+    macro_rules! mock_todo_repo {
+        ($($method:ident / $expect:ident ($($arg:ident: $ty:ty => $owned:ty),*) -> $ret:ty;)*) => {
+            #[derive(Default)]
+            struct Expectations {
+                $($method: Vec<Expectation<($($owned,)*), Result<$ret, RepoError>>>,)*
+            }
+
+            impl Expectations {
+                fn unmet(&self) -> Vec<String> {
+                    let mut unmet = Vec::new();
+                    $(unmet.extend(self.$method.iter().filter_map(Expectation::unmet));)*
+                    unmet
+                }
+            }
+
+            #[derive(Clone, Default)]
+            pub struct MockTodoRepo(Arc<Shared>);
+
+            impl MockTodoRepo {
+                $(
+                    pub fn $expect(&self) -> ExpectationBuilder<($($owned,)*), Result<$ret, RepoError>> {
+                        let shared = self.0.clone();
+                        ExpectationBuilder::new(stringify!($method), move |expectation| {
+                            shared.0.lock().unwrap().$method.push(expectation)
+                        })
+                    }
+                )*
+            }
+
+            #[async_trait]
+            impl TodoRepo for MockTodoRepo {
+                $(
+                    async fn $method(&self, $($arg: $ty),*) -> Result<$ret, RepoError> {
+                        let args = ($(ToArg::<$owned>::to_arg($arg),)*);
+                        let (answer, delay) = answer(stringify!($method), &mut self.0 .0.lock().unwrap().$method, args);
+                        if !delay.is_zero() {
+                            tokio::time::sleep(delay).await;
+                        }
+                        answer
+                    }
+                )*
+            }
+        };
+    }
+
+    mock_todo_repo! {
+        get_todos / expect_get_todos(user_id: i64 => i64, sort: TodoSort => TodoSort) -> Vec<Todo>;
+        get_todos_page / expect_get_todos_page(
+            user_id: i64 => i64,
+            after: Option<TodoCursor> => Option<TodoCursor>,
+            limit: i64 => i64
+        ) -> Vec<Todo>;
+        get_todo / expect_get_todo(user_id: i64 => i64, id: i64 => i64) -> Option<Todo>;
+        get_todos_filtered / expect_get_todos_filtered(user_id: i64 => i64, filter: &TodoFilter => TodoFilter) -> Vec<Todo>;
+        get_todo_tree / expect_get_todo_tree(user_id: i64 => i64, id: i64 => i64) -> Vec<Todo>;
+        set_parent / expect_set_parent(
+            user_id: i64 => i64,
+            id: i64 => i64,
+            parent_id: Option<i64> => Option<i64>
+        ) -> Result<(), SubtaskError>;
+        get_overdue_todos / expect_get_overdue_todos(user_id: i64 => i64, now: OffsetDateTime => OffsetDateTime) -> Vec<Todo>;
+        get_todos_due_on / expect_get_todos_due_on(
+            user_id: i64 => i64,
+            day: Date => Date,
+            time_zone: &str => String
+        ) -> Option<Vec<Todo>>;
+        get_stats / expect_get_stats(user_id: i64 => i64) -> TodoStats;
+        create_todo / expect_create_todo(
+            user_id: i64 => i64,
+            title: &str => String,
+            description: &str => String,
+            due_at: Option<OffsetDateTime> => Option<OffsetDateTime>,
+            priority: i32 => i32
+        ) -> i64;
+        update_todo / expect_update_todo(
+            user_id: i64 => i64,
+            id: i64 => i64,
+            title: Option<&str> => Option<String>,
+            description: Option<&str> => Option<String>,
+            status: Option<TodoStatus> => Option<TodoStatus>,
+            due_at: Option<OffsetDateTime> => Option<OffsetDateTime>,
+            priority: Option<i32> => Option<i32>
+        ) -> Option<i64>;
+        replace_todo / expect_replace_todo(user_id: i64 => i64, id: i64 => i64, todo: &PatchableTodo => PatchableTodo) -> Option<i64>;
+        delete_todo / expect_delete_todo(user_id: i64 => i64, id: i64 => i64) -> Option<i64>;
+        create_many / expect_create_many(user_id: i64 => i64, todos: &[CreateTodo] => Vec<CreateTodo>) -> Vec<i64>;
+        bulk / expect_bulk(
+            user_id: i64 => i64,
+            operations: &[BulkOperation] => Vec<BulkOperation>,
+            atomic: bool => bool
+        ) -> Vec<Result<i64, BulkError>>;
+        claim_next_todo / expect_claim_next_todo(user_id: i64 => i64) -> Option<Todo>;
+        get_comments / expect_get_comments(user_id: i64 => i64, todo_id: i64 => i64) -> Vec<Comment>;
+        get_comments_of / expect_get_comments_of(user_id: i64 => i64, todo_ids: &[i64] => Vec<i64>) -> Vec<Comment>;
+        create_comment / expect_create_comment(user_id: i64 => i64, todo_id: i64 => i64, body: &str => String) -> Option<i64>;
+        delete_comment / expect_delete_comment(user_id: i64 => i64, todo_id: i64 => i64, comment_id: i64 => i64) -> bool;
+        erase_user / expect_erase_user(user_id: i64 => i64, dry_run: bool => bool) -> TodoErasure;
+    }
+
+    ///
+    /// The expectations of a mock and all its clones, checked when the last
+    /// of them is dropped.
+    ///
+    #[derive(Default)]
+    struct Shared(Mutex<Expectations>);
+
+    impl MockTodoRepo {
+        ///
+        /// Fails the test if an expectation set so far was not met, and
+        /// starts over with none.
+        ///
+        pub fn checkpoint(&self) {
+            let unmet = std::mem::take(&mut *self.0 .0.lock().unwrap()).unmet();
+            assert!(unmet.is_empty(), "Unmet expectations of the todo repo:\n{}", unmet.join("\n"));
+        }
+    }
+
+    impl Drop for Shared {
+        fn drop(&mut self) {
+            // A failing test has already said what went wrong.
+            if std::thread::panicking() {
+                return;
+            }
+            let unmet = self.0.get_mut().unwrap().unmet();
+            assert!(unmet.is_empty(), "Unmet expectations of the todo repo:\n{}", unmet.join("\n"));
+        }
+    }
+}
+
+///
+/// Marks the parent of a todo as done if none of its subtasks are still open
+/// or in progress, and then does the same for the grandparent, and so on up
+/// the tree. Cancelled subtasks do not hold their parent back, and cancelled
+/// parents stay cancelled.
+///
+pub async fn complete_parents(conn: &mut PgConnection, id: i64) -> Result<(), sqlx::Error> {
+    let mut child_id = id;
+    loop {
+        let completed = sqlx::query!(
+            "UPDATE todos parent SET status = 'done' FROM todos child
+            where child.id = $1 AND parent.id = child.parent_id AND parent.status IN ('open', 'in_progress')
+            AND NOT EXISTS (
+                SELECT 1 FROM todos sibling where sibling.parent_id = parent.id AND sibling.status IN ('open', 'in_progress')
+            )
+            RETURNING parent.id",
+            child_id
+        )
+        .fetch_optional(&mut *conn)
+        .await?;
+
+        match completed {
+            Some(parent) => child_id = parent.id,
+            None => return Ok(()),
+        }
+    }
+}
+
+///
+/// The `erase_user` of `TodoRepoPostgres`, on a connection that is already in
+/// a transaction. The foreign keys delete the subtasks and comments.
+///
+pub async fn erase_user_in(conn: &mut PgConnection, user_id: i64, dry_run: bool) -> Result<TodoErasure, sqlx::Error> {
+    let counts = sqlx::query!(
+        r#"WITH RECURSIVE erased_todos AS (
+            SELECT id FROM todos WHERE owner_id = $1
+            UNION
+            SELECT todos.id FROM todos JOIN erased_todos ON todos.parent_id = erased_todos.id
+        )
+        SELECT
+            (SELECT COUNT(*) FROM erased_todos) AS "todos!",
+            (SELECT COUNT(*) FROM comments WHERE todo_id IN (SELECT id FROM erased_todos)) AS "comments!",
+            (
+                SELECT COUNT(*) FROM todos
+                WHERE assignee_id = $1 AND id NOT IN (SELECT id FROM erased_todos)
+            ) AS "unassigned_todos!""#,
+        user_id
+    )
+    .fetch_one(&mut *conn)
+    .await?;
+    if !dry_run {
+        sqlx::query!("DELETE FROM todos WHERE owner_id = $1", user_id).execute(&mut *conn).await?;
+        sqlx::query!("UPDATE todos SET assignee_id = NULL WHERE assignee_id = $1", user_id).execute(&mut *conn).await?;
+    }
+    Ok(TodoErasure { todos: counts.todos, comments: counts.comments, unassigned_todos: counts.unassigned_todos })
+}
+
+///
+/// The `replace_todo` of `TodoRepoPostgres`, on a connection that is already
+/// in a transaction.
+///
+pub async fn replace_todo_in(
+    conn: &mut PgConnection,
+    user_id: i64,
+    id: i64,
+    todo: &PatchableTodo,
+) -> Result<Option<i64>, sqlx::Error> {
+    let replaced = sqlx::query!(
+        "UPDATE todos SET title = $1, description = $2, status = $3, due_at = $4, priority = $5, metadata = $6 where id = $7 AND todo_visible_to(owner_id, $8) RETURNING id",
+        todo.title,
+        todo.description,
+        todo.status as TodoStatus,
+        todo.due_at,
+        todo.priority,
+        serde_json::Value::Object(todo.metadata.clone()),
+        id,
+        user_id
+    )
+    .fetch_optional(&mut *conn)
+    .await?;
+
+    let Some(replaced) = replaced else {
+        return Ok(None);
+    };
+    complete_parents(conn, replaced.id).await?;
+    Ok(Some(replaced.id))
+}
+
+pub async fn apply_bulk_operation(
+    conn: &mut PgConnection,
+    user_id: i64,
+    operation: &BulkOperation,
+) -> Result<i64, BulkError> {
+    match operation {
+        BulkOperation::Create { title, description, due_at, priority } => sqlx::query!(
+            "INSERT INTO todos (title, description, due_at, priority, owner_id) VALUES ($1, $2, $3, $4, $5) RETURNING id",
+            title,
+            description,
+            *due_at,
+            priority,
+            user_id
+        )
+        .fetch_one(&mut *conn)
+        .await
+        .map(|row| row.id)
+        .map_err(BulkError::from),
+        BulkOperation::Update { id, title, description, status, due_at, priority } => {
+            let id = sqlx::query!(
+                "UPDATE todos SET title = COALESCE($1, title), description = COALESCE($2, description), status = COALESCE($3, status), due_at = COALESCE($4, due_at), priority = COALESCE($5, priority) where id = $6 AND todo_visible_to(owner_id, $7) RETURNING id",
+                title.as_deref(),
+                description.as_deref(),
+                *status as Option<TodoStatus>,
+                *due_at,
+                *priority,
+                id,
+                user_id
+            )
+            .fetch_optional(&mut *conn)
+            .await?
+            .map(|row| row.id)
+            .ok_or(BulkError::NotFound(*id))?;
+
+            complete_parents(conn, id).await?;
+            Ok(id)
+        }
+        BulkOperation::Delete { id } => sqlx::query!(
+            "DELETE FROM todos where id = $1 AND todo_visible_to(owner_id, $2) RETURNING id",
+            id,
+            user_id
+        )
+        .fetch_optional(&mut *conn)
+        .await?
+        .map(|row| row.id)
+        .ok_or(BulkError::NotFound(*id)),
+    }
+}
+
+///
+/// The results of an atomic bulk that was rolled back, after the operation
+/// that failed: the ones before it were undone, and the ones after it were
+/// never tried.
+///
+pub fn rolled_back(mut results: Vec<Result<i64, BulkError>>, operations: usize) -> Vec<Result<i64, BulkError>> {
+    results.resize_with(operations, || Err(BulkError::NotAttempted));
+    results
+        .into_iter()
+        .map(|result| match result {
+            Ok(_) => Err(BulkError::RolledBack),
+            Err(e) => Err(e),
+        })
+        .collect()
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TodoSort {
+    #[default]
+    Id,
+    /// Highest priority first.
+    Priority,
+    /// Earliest due date first, with todos that have no due date last.
+    DueAt,
+}
+
+#[derive(Debug, Default, serde::Deserialize)]
+struct TodoQuery {
+    #[serde(default)]
+    sort: TodoSort,
+    after: Option<String>,
+    limit: Option<i64>,
+    /// A JSON object that the metadata of every todo returned must contain.
+    metadata: Option<String>,
+    /// `?tag[]=home&tag[]=urgent`: todos whose metadata has both in `tags`.
+    #[serde(default)]
+    tag: Vec<String>,
+    /// `?status[]=open&status[]=in_progress`: todos with either status.
+    #[serde(default)]
+    status: Vec<TodoStatus>,
+    /// `?due[after]=...&due[before]=...`, in RFC 3339.
+    #[serde(default)]
+    due: DueRange,
+}
+
+#[derive(Debug, Default, serde::Deserialize)]
+struct DueRange {
+    #[serde(default, with = "time::serde::rfc3339::option")]
+    after: Option<OffsetDateTime>,
+    #[serde(default, with = "time::serde::rfc3339::option")]
+    before: Option<OffsetDateTime>,
+}
+
+///
+/// The filters of `GET /todo/`, as the repository applies them. Statuses are
+/// alternatives, everything else must hold at once.
+///
+#[derive(Clone, Debug, Default)]
+pub struct TodoFilter {
+    /// A JSON object, contained in (JSONB `@>`) the metadata of every todo.
+    pub metadata: serde_json::Value,
+    pub statuses: Vec<TodoStatus>,
+    /// Inclusive.
+    pub due_after: Option<OffsetDateTime>,
+    /// Exclusive.
+    pub due_before: Option<OffsetDateTime>,
+}
+
+///
+/// A value bound to a parameter of `filtered_todos_query`.
+///
+#[derive(Debug)]
+enum FilterBind<'a> {
+    Json(&'a serde_json::Value),
+    Statuses(&'a [TodoStatus]),
+    Time(OffsetDateTime),
+    Id(i64),
+}
+
+///
+/// The conditions of `get_todos_filtered`, as the SQL before and after the
+/// parameter of each. A filter that is not set has no condition, rather than
+/// one that is always true, so that the planner only sees conditions it can
+/// use an index for.
+///
+fn filter_conditions(user_id: i64, filter: &TodoFilter) -> Vec<(&'static str, FilterBind<'_>, &'static str)> {
+    let mut conditions = Vec::new();
+    // An empty object is contained in every metadata.
+    if filter.metadata != serde_json::json!({}) {
+        conditions.push(("metadata @> ", FilterBind::Json(&filter.metadata), ""));
+    }
+    if !filter.statuses.is_empty() {
+        conditions.push(("status = ANY(", FilterBind::Statuses(&filter.statuses), ")"));
+    }
+    if let Some(due_after) = filter.due_after {
+        conditions.push(("due_at >= ", FilterBind::Time(due_after), ""));
+    }
+    if let Some(due_before) = filter.due_before {
+        conditions.push(("due_at < ", FilterBind::Time(due_before), ""));
+    }
+    conditions.push(("todo_visible_to(owner_id, ", FilterBind::Id(user_id), ")"));
+    conditions
+}
+
+///
+/// Appends the query of `get_todos_filtered` to `query`, which is empty
+/// unless the caller wants to `EXPLAIN` it. Its SQL and binds are pinned down
+/// by the golden files of `filtered_todos_queries_match_golden_files`.
+///
+pub fn filtered_todos_query<'a>(
+    mut query: QueryBuilder<'a, Postgres>,
+    user_id: i64,
+    filter: &'a TodoFilter,
+) -> QueryBuilder<'a, Postgres> {
+    query.push(
+        "SELECT id, title, description, status, created_at, due_at, priority, parent_id, owner_id, list_id, completed_at, metadata \
+        FROM todos WHERE ",
+    );
+    for (i, (before, bind, after)) in filter_conditions(user_id, filter).into_iter().enumerate() {
+        if i > 0 {
+            query.push(" AND ");
+        }
+        query.push(before);
+        match bind {
+            FilterBind::Json(value) => query.push_bind(value),
+            FilterBind::Statuses(statuses) => query.push_bind(statuses),
+            FilterBind::Time(time) => query.push_bind(time),
+            FilterBind::Id(id) => query.push_bind(id),
+        };
+        query.push(after);
+    }
+    query.push(" ORDER BY id");
+    query
+}
+
+const DEFAULT_PAGE_SIZE: i64 = 50;
+const MAX_PAGE_SIZE: i64 = 500;
+
+///
+/// The position of a todo in the `(created_at, id)` order. The id breaks ties
+/// between todos created in the same transaction, so the order is total and
+/// a page boundary can never fall between two todos that compare equal.
+///
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TodoCursor {
+    pub created_at: OffsetDateTime,
+    pub id: i64,
+}
+
+impl TodoCursor {
+    fn encode(&self) -> String {
+        cursor::encode(self.created_at, &self.id.to_string())
+    }
+
+    fn decode(cursor: &str) -> Option<Self> {
+        let (created_at, id) = cursor::decode(cursor)?;
+        Some(TodoCursor {
+            created_at,
+            id: id.parse().ok()?,
+        })
+    }
+}
+
+///
+/// A page of todos, as `TodoDTO`s when read back in tests, and as
+/// `TodoDTORef`s when written.
+///
+#[derive(Debug, serde::Deserialize, serde::Serialize)]
+pub struct TodoPage<T = TodoDTO> {
+    pub todos: Vec<T>,
+    pub next_cursor: Option<String>,
+}
+
+///
+/// Without `after` or `limit`, `GET /todo/` returns every todo, as it always
+/// has, as a plain array. With either, it returns a page wrapped in an
+/// envelope that carries the cursor of the next page.
+///
+/// Keyset pagination: rather than skipping `OFFSET` rows, which Postgres has
+/// to read and discard, each page starts right after the last todo of the
+/// previous one, found through the `(created_at, id)` index.
+///
+/// With any filter, it returns only the matching todos, as an array:
+///
+/// - `metadata`, such as `?metadata={"label":"home"}` (URL encoded), keeps
+///   the todos whose metadata contains that object.
+/// - `tag[]` keeps those whose metadata `tags` array contains every tag.
+/// - `status[]` keeps those with any of the statuses.
+/// - `due[after]` and `due[before]` keep those due in that range.
+///
+/// Axum's `Query` cannot parse arrays or nested keys, so this takes a
+/// `QsQuery` instead.
+///
+async fn get_todos<R: TodoRepo>(
+    Claims { sub: user_id, .. }: Claims,
+    State(TodoState{ repo, .. }): State<TodoState<R>>,
+    QsQuery(TodoQuery { sort, after, limit, metadata, tag, status, due }): QsQuery<TodoQuery>,
+) -> Result<Response, Response> {
+    let bad_request = |message: &str| (StatusCode::BAD_REQUEST, message.to_string()).into_response();
+
+    if metadata.is_some() || !tag.is_empty() || !status.is_empty() || due.after.is_some() || due.before.is_some() {
+        if sort != TodoSort::Id || after.is_some() || limit.is_some() {
+            return Err(bad_request("Filters only support the default order, without pagination"));
+        }
+        let mut metadata = match metadata {
+            Some(metadata) => serde_json::from_str::<serde_json::Map<String, serde_json::Value>>(&metadata)
+                .map_err(|_| bad_request("The metadata filter must be a JSON object"))?,
+            None => serde_json::Map::new(),
+        };
+        if !tag.is_empty() {
+            if metadata.contains_key("tags") {
+                return Err(bad_request("Filter tags with either tag[] or metadata, not both"));
+            }
+            metadata.insert("tags".to_string(), serde_json::json!(tag));
+        }
+
+        let filter = TodoFilter {
+            metadata: serde_json::Value::Object(metadata),
+            statuses: status,
+            due_after: due.after,
+            due_before: due.before,
+        };
+        let todos = repo.get_todos_filtered(user_id, &filter).await?;
+        return Ok(AppJson(todos.iter().map(Todo::to_dto_ref).collect::<Vec<_>>()).into_response());
+    }
+
+    if after.is_none() && limit.is_none() {
+        let json = repo.get_todos_json(user_id, sort).await?;
+        return Ok(([(header::CONTENT_TYPE, "application/json")], Body::from(json)).into_response());
+    }
+
+    if sort != TodoSort::Id {
+        return Err(bad_request("Pagination only supports the default order"));
+    }
+    let after = match after {
+        Some(cursor) => Some(TodoCursor::decode(&cursor).ok_or_else(|| bad_request("Invalid cursor"))?),
+        None => None,
+    };
+    let limit = limit.unwrap_or(DEFAULT_PAGE_SIZE).clamp(1, MAX_PAGE_SIZE);
+
+    // One todo more than asked for tells whether there is a next page.
+    let mut todos = repo.get_todos_page(user_id, after, limit + 1).await?;
+    let next_cursor = if todos.len() as i64 > limit {
+        todos.truncate(limit as usize);
+        todos.last().map(|todo| TodoCursor { created_at: todo.created_at, id: todo.id }.encode())
+    } else {
+        None
+    };
+
+    Ok(AppJson(TodoPage {
+        todos: todos.iter().map(Todo::to_dto_ref).collect(),
+        next_cursor,
+    })
+    .into_response())
+}
+
+///
+/// Due dates are stored as `TIMESTAMPTZ`, so they are compared as instants,
+/// regardless of the offset a client used when setting them.
+///
+async fn get_overdue_todos<R: TodoRepo>(
+    Claims { sub: user_id, .. }: Claims,
+    State(TodoState { repo, clock, .. }): State<TodoState<R>>,
+) -> Result<Response, RepoError> {
+    let todos = repo.get_overdue_todos(user_id, clock.now()).await?;
+    Ok(AppJson(todos.iter().map(Todo::to_dto_ref).collect::<Vec<_>>()).into_response())
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct TodoDueQuery {
+    date: String,
+    tz: String,
+}
+
+///
+/// `GET /todo/due?date=2026-10-25&tz=Europe/Copenhagen` returns the todos due
+/// on that day in the client's time zone. Clients name the zone rather than
+/// send an offset, since a day that crosses a daylight saving transition has
+/// two offsets.
+///
+async fn get_todos_due<R: TodoRepo>(
+    Claims { sub: user_id, .. }: Claims,
+    State(TodoState{ repo, .. }): State<TodoState<R>>,
+    Query(TodoDueQuery { date, tz }): Query<TodoDueQuery>,
+) -> Result<Response, Response> {
+    let day = Date::parse(&date, &Iso8601::DATE)
+        .map_err(|_| (StatusCode::BAD_REQUEST, "The date must be formatted as YYYY-MM-DD").into_response())?;
+    let todos = repo
+        .get_todos_due_on(user_id, day, &tz)
+        .await?
+        .ok_or_else(|| (StatusCode::BAD_REQUEST, format!("Unknown time zone: {}", tz)).into_response())?;
+    Ok(AppJson(todos.iter().map(Todo::to_dto_ref).collect::<Vec<_>>()).into_response())
+}
+
+async fn get_todo_stats<R: TodoRepo>(
+    Claims { sub: user_id, .. }: Claims,
+    State(TodoState { repo, .. }): State<TodoState<R>>,
+) -> Result<Json<TodoStats>, RepoError> {
+    Ok(Json(repo.get_stats(user_id).await?))
+}
+
+async fn get_todo<R: TodoRepo>(
+    Claims { sub: user_id, .. }: Claims,
+    TodoById { id }: TodoById,
+    State(TodoState { repo, .. }): State<TodoState<R>>,
+) -> Result<Json<Option<TodoDTO>>, RepoError> {
+    let maybe_todo = repo.get_todo(user_id, id).await?;
+    Ok(Json(maybe_todo.map(|todo| todo.to_dto())))
+}
+
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
+pub struct CreateTodo {
+    pub title: String,
+    pub description: String,
+    #[serde(default, with = "time::serde::rfc3339::option")]
+    pub due_at: Option<OffsetDateTime>,
+    #[serde(default)]
+    pub priority: i32,
+}
+
+async fn create_todo<R: TodoRepo>(
+    Claims { sub: user_id, .. }: Claims,
+    State(TodoState { repo, events, .. }): State<TodoState<R>>,
+    AppJson(body): AppJson<CreateTodo>,
+) -> Result<Json<i64>, RepoError> {
+    let id = repo.create_todo(user_id, &body.title, &body.description, body.due_at, body.priority).await?;
+    events.publish(TodoEventKind::Created, id, user_id);
+    Ok(Json(id))
+}
+
+///
+/// The HTML form that creates a todo, showing the message left by the last
+/// post, if any.
+///
+async fn get_todo_form(_: Claims, jar: CookieJar) -> (CookieJar, HtmlTemplate<TodoFormPage>) {
+    let (jar, flash) = take_flash(jar);
+    let page = TodoFormPage { action: TodoNew.to_string(), flash, ..Default::default() };
+    (jar, HtmlTemplate(page))
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct TodoForm {
+    title: String,
+    #[serde(default)]
+    description: String,
+}
+
+///
+/// Creates a todo from the posted form, then redirects back to the form
+/// (Post/Redirect/Get) with a flash message. A form without a title is shown
+/// again, as it was filled in.
+///
+async fn post_todo_form<R: TodoRepo>(
+    Claims { sub: user_id, .. }: Claims,
+    State(TodoState{ repo, events, .. }): State<TodoState<R>>,
+    jar: CookieJar,
+    Form(TodoForm { title, description }): Form<TodoForm>,
+) -> Result<Response, RepoError> {
+    if title.trim().is_empty() {
+        let page = TodoFormPage {
+            action: TodoNew.to_string(),
+            error: Some("A todo needs a title".to_string()),
+            title,
+            description,
+            ..Default::default()
+        };
+        return Ok((StatusCode::UNPROCESSABLE_ENTITY, HtmlTemplate(page)).into_response());
+    }
+
+    let id = repo.create_todo(user_id, title.trim(), &description, None, 0).await?;
+    events.publish(TodoEventKind::Created, id, user_id);
+    let jar = set_flash(jar, format!("Created \"{}\"", title.trim()));
+    Ok((jar, Redirect::to(&TodoNew.to_string())).into_response())
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct UpdateTodo {
+    title: Option<String>,
+    description: Option<String>,
+    status: Option<TodoStatus>,
+    #[serde(default, with = "time::serde::rfc3339::option")]
+    due_at: Option<OffsetDateTime>,
+    priority: Option<i32>,
+}
+
+async fn update_todo<R: TodoRepo>(
+    Claims { sub: user_id, .. }: Claims,
+    TodoById { id }: TodoById,
+    State(TodoState { repo, events, .. }): State<TodoState<R>>,
+    AppJson(UpdateTodo { title, description, status, due_at, priority }): AppJson<UpdateTodo>,
+) -> Result<Json<Option<i64>>, RepoError> {
+    let id = repo.update_todo(user_id, id, title.as_deref(), description.as_deref(), status, due_at, priority).await?;
+    if let Some(id) = id {
+        events.publish(TodoEventKind::Updated, id, user_id);
+    }
+    Ok(Json(id))
+}
+
+async fn delete_todo<R: TodoRepo>(
+    Claims { sub: user_id, .. }: Claims,
+    TodoById { id }: TodoById,
+    State(TodoState { repo, events, .. }): State<TodoState<R>>,
+) -> Result<Json<Option<i64>>, RepoError> {
+    let deleted_id = repo.delete_todo(user_id, id).await?;
+    if let Some(id) = deleted_id {
+        events.publish(TodoEventKind::Deleted, id, user_id);
+    }
+    Ok(Json(deleted_id))
+}
+
+///
+/// The fields of a todo that a merge patch may change. Patches that touch any
+/// other field (such as `id`), or that remove a required field by setting it
+/// to `null`, fail to deserialize back into this struct.
+///
+/// The metadata is merged key by key like the rest of the document, so a
+/// patch such as `{"metadata": {"label": null}}` removes a single key, and
+/// `{"metadata": null}` removes them all.
+///
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct PatchableTodo {
+    pub title: String,
+    pub description: String,
+    pub status: TodoStatus,
+    #[serde(default, with = "time::serde::rfc3339::option")]
+    pub due_at: Option<OffsetDateTime>,
+    pub priority: i32,
+    #[serde(default)]
+    pub metadata: serde_json::Map<String, serde_json::Value>,
+}
+
+///
+/// What `TodoRepo::patch_todo` makes of a todo: the todo to replace it with,
+/// or the error to answer with instead.
+///
+pub type TodoPatch<'a> = dyn Fn(Todo) -> Result<PatchableTodo, ExtractError> + Send + Sync + 'a;
+
+///
+/// Applies a JSON Merge Patch (RFC 7386) to `target`. Objects are merged
+/// recursively, `null` removes a member, and any other value replaces the
+/// target wholesale.
+///
+fn merge_patch(target: &mut serde_json::Value, patch: &serde_json::Value) {
+    let serde_json::Value::Object(patch) = patch else {
+        *target = patch.clone();
+        return;
+    };
+
+    if !target.is_object() {
+        *target = serde_json::Value::Object(serde_json::Map::new());
+    }
+    let target = target.as_object_mut().unwrap();
+
+    for (key, value) in patch {
+        if value.is_null() {
+            target.remove(key);
+        } else {
+            merge_patch(target.entry(key.clone()).or_insert(serde_json::Value::Null), value);
+        }
+    }
+}
+
+///
+/// Unlike `update_todo`, which cannot tell a missing field from a `null` one,
+/// this handler applies the body as a merge patch to the todo's JSON form, so
+/// `null` explicitly clears a field. Send it as `application/merge-patch+json`.
+///
+async fn patch_todo<R: TodoRepo>(
+    Claims { sub: user_id, .. }: Claims,
+    TodoById { id }: TodoById,
+    State(TodoState{ repo, events, .. }): State<TodoState<R>>,
+    AppJson(patch): AppJson<serde_json::Value>
+) -> Result<Json<TodoDTO>, Response> {
+    let not_found = || (StatusCode::NOT_FOUND, format!("Todo {} not found", id)).into_response();
+
+    // The todo is read, patched and saved by the repo, in a transaction where
+    // it can have one, so that concurrent patches do not overwrite each other.
+    let apply = |todo: Todo| {
+        let mut document = serde_json::to_value(PatchableTodo {
+            title: todo.title,
+            description: todo.description,
+            status: todo.status,
+            due_at: todo.due_at,
+            priority: todo.priority,
+            metadata: match todo.metadata {
+                serde_json::Value::Object(metadata) => metadata,
+                _ => serde_json::Map::new(),
+            },
+        })
+        .unwrap();
+        merge_patch(&mut document, &patch);
+
+        // The patched document is checked like a request body would be, so the
+        // error names the field that the patch broke.
+        serde_path_to_error::deserialize(document)
+            .map_err(|e| ExtractError::from_path_error(StatusCode::UNPROCESSABLE_ENTITY, &e))
+    };
+    repo.patch_todo(user_id, id, &apply).await?.ok_or_else(not_found)?.map_err(IntoResponse::into_response)?;
+    events.publish(TodoEventKind::Updated, id, user_id);
+
+    let todo = repo.get_todo(user_id, id).await?.ok_or_else(not_found)?;
+    Ok(Json(todo.to_dto()))
+}
+
+#[derive(Clone, Debug, serde::Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum BulkOperation {
+    Create {
+        title: String,
+        description: String,
+        #[serde(default, with = "time::serde::rfc3339::option")]
+        due_at: Option<OffsetDateTime>,
+        #[serde(default)]
+        priority: i32,
+    },
+    Update {
+        id: i64,
+        title: Option<String>,
+        description: Option<String>,
+        status: Option<TodoStatus>,
+        #[serde(default, with = "time::serde::rfc3339::option")]
+        due_at: Option<OffsetDateTime>,
+        priority: Option<i32>,
+    },
+    Delete {
+        id: i64,
+    },
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum BulkError {
+    NotFound(i64),
+    /// The database failed; the error is logged, not sent to the client.
+    Database,
+    /// The operation succeeded, but was undone because another failed.
+    RolledBack,
+    /// The operation was skipped because an earlier one failed.
+    NotAttempted,
+}
+
+impl From<sqlx::Error> for BulkError {
+    fn from(error: sqlx::Error) -> Self {
+        tracing::error!(%error, "Bulk operation failed");
+        BulkError::Database
+    }
+}
+
+impl std::fmt::Display for BulkError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.message())
+    }
+}
+
+impl std::error::Error for BulkError {}
+
+impl BulkError {
+    fn status(&self) -> StatusCode {
+        match self {
+            BulkError::NotFound(_) => StatusCode::NOT_FOUND,
+            BulkError::Database => StatusCode::INTERNAL_SERVER_ERROR,
+            BulkError::RolledBack | BulkError::NotAttempted => StatusCode::FAILED_DEPENDENCY,
+        }
+    }
+
+    pub fn message(&self) -> String {
+        match self {
+            BulkError::NotFound(id) => format!("Todo {} not found", id),
+            BulkError::Database => "database error".to_string(),
+            BulkError::RolledBack => "Rolled back because another operation failed".to_string(),
+            BulkError::NotAttempted => "Not attempted because another operation failed".to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Default, serde::Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+enum BulkMode {
+    #[default]
+    Transaction,
+    Independent,
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct BulkRequest {
+    #[serde(default)]
+    mode: BulkMode,
+    operations: Vec<BulkOperation>,
+}
+
+#[derive(Debug, serde::Deserialize, serde::Serialize, PartialEq)]
+struct BulkResult {
+    status: u16,
+    id: Option<i64>,
+    error: Option<String>,
+}
+
+///
+/// Applies a batch of operations, and reports the outcome of each one. The
+/// response is `200 OK` if all of them succeeded, and `207 Multi-Status` if
+/// any of them failed.
+///
+async fn bulk_todos<R: TodoRepo>(
+    Claims { sub: user_id, .. }: Claims,
+    State(TodoState { repo, events, .. }): State<TodoState<R>>,
+    AppJson(BulkRequest { mode, operations }): AppJson<BulkRequest>,
+) -> Result<(StatusCode, Json<Vec<BulkResult>>), RepoError> {
+    let results = repo.bulk(user_id, &operations, mode == BulkMode::Transaction).await?;
+
+    // Only operations that succeeded were committed.
+    for (operation, result) in operations.iter().zip(&results) {
+        let kind = match operation {
+            BulkOperation::Create { .. } => TodoEventKind::Created,
+            BulkOperation::Update { .. } => TodoEventKind::Updated,
+            BulkOperation::Delete { .. } => TodoEventKind::Deleted,
+        };
+        if let Ok(id) = result {
+            events.publish(kind, *id, user_id);
+        }
+    }
+
+    let status = if results.iter().all(Result::is_ok) {
+        StatusCode::OK
+    } else {
+        StatusCode::MULTI_STATUS
+    };
+
+    let results = results
+        .into_iter()
+        .map(|result| match result {
+            Ok(id) => BulkResult { status: StatusCode::OK.as_u16(), id: Some(id), error: None },
+            Err(e) => BulkResult { status: e.status().as_u16(), id: None, error: Some(e.message()) },
+        })
+        .collect();
+
+    Ok((status, Json(results)))
+}
+
+///
+/// Claims the next todo waiting for someone to work on it. Answers
+/// `204 No Content` when there is none.
+///
+async fn claim_todo<R: TodoRepo>(
+    Claims { sub: user_id, .. }: Claims,
+    State(TodoState { repo, events, .. }): State<TodoState<R>>,
+) -> Result<Response, RepoError> {
+    match repo.claim_next_todo(user_id).await? {
+        Some(todo) => {
+            events.publish(TodoEventKind::Updated, todo.id, user_id);
+            Ok(Json(todo.to_dto()).into_response())
+        }
+        None => Ok(StatusCode::NO_CONTENT.into_response()),
+    }
+}
+
+const IMPORT_CHUNK_SIZE: usize = 100;
+
+///
+/// What `POST /todo/import.ndjson` reports back, one JSON object per line.
+///
+#[derive(Debug, PartialEq, serde::Deserialize, serde::Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+enum ImportEvent {
+    /// A chunk of todos was committed.
+    Progress { imported: usize, lines: usize },
+    /// A line could not be parsed, and was skipped.
+    Error { line: usize, error: String },
+    Done { imported: usize, lines: usize, errors: usize },
+}
+
+///
+/// The state of an import in progress: the request body still to be read,
+/// the bytes of an incomplete line, and the parsed todos not yet inserted.
+///
+struct Import<R: TodoRepo> {
+    repo: R,
+    user_id: i64,
+    body: Body,
+    todo_events: TodoEvents,
+    buffer: Vec<u8>,
+    pending: Vec<CreateTodo>,
+    events: VecDeque<ImportEvent>,
+    lines: usize,
+    imported: usize,
+    errors: usize,
+    finished: bool,
+}
+
+impl<R: TodoRepo> Import<R> {
+    ///
+    /// Reads the request body until there is something to report, and
+    /// returns `None` once the import is over.
+    ///
+    async fn next_event(&mut self) -> Option<ImportEvent> {
+        loop {
+            if let Some(event) = self.events.pop_front() {
+                return Some(event);
+            }
+            if self.finished {
+                return None;
+            }
+
+            match self.body.frame().await {
+                Some(Ok(frame)) => {
+                    if let Ok(data) = frame.into_data() {
+                        self.buffer.extend_from_slice(&data);
+                    }
+                    while let Some(end) = self.buffer.iter().position(|byte| *byte == b'\n') {
+                        let line: Vec<u8> = self.buffer.drain(..=end).collect();
+                        self.parse_line(&line);
+                        if self.pending.len() >= IMPORT_CHUNK_SIZE {
+                            if let Err(error) = self.flush().await {
+                                self.fail(error);
+                                break;
+                            }
+                        }
+                    }
+                }
+                Some(Err(error)) => {
+                    if let Err(error) = self.flush().await {
+                        self.fail(error);
+                        continue;
+                    }
+                    self.errors += 1;
+                    self.events.push_back(ImportEvent::Error { line: self.lines, error: error.to_string() });
+                    self.finish();
+                }
+                None => {
+                    // The last line does not need a trailing newline.
+                    let line = std::mem::take(&mut self.buffer);
+                    self.parse_line(&line);
+                    match self.flush().await {
+                        Ok(()) => self.finish(),
+                        Err(error) => self.fail(error),
+                    }
+                }
+            }
+        }
+    }
+
+    fn parse_line(&mut self, line: &[u8]) {
+        if line.iter().all(u8::is_ascii_whitespace) {
+            if !line.is_empty() {
+                self.lines += 1;
+            }
+            return;
+        }
+        self.lines += 1;
+
+        match serde_json::from_slice::<CreateTodo>(line) {
+            Ok(todo) => self.pending.push(todo),
+            Err(error) => {
+                self.errors += 1;
+                self.events.push_back(ImportEvent::Error { line: self.lines, error: error.to_string() });
+            }
+        }
+    }
+
+    async fn flush(&mut self) -> Result<(), RepoError> {
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+        let ids = self.repo.create_many(self.user_id, &self.pending).await?;
+        for id in &ids {
+            self.todo_events.publish(TodoEventKind::Created, *id, self.user_id);
+        }
+        self.imported += ids.len();
+        self.pending.clear();
+        self.events.push_back(ImportEvent::Progress { imported: self.imported, lines: self.lines });
+        Ok(())
+    }
+
+    fn finish(&mut self) {
+        self.finished = true;
+        self.events.push_back(ImportEvent::Done { imported: self.imported, lines: self.lines, errors: self.errors });
+    }
+
+    ///
+    /// Ends the import when the repo fails. The client already has its
+    /// `200 OK`, so the failure can only be reported in the stream, without
+    /// the details, which are logged.
+    ///
+    fn fail(&mut self, error: RepoError) {
+        tracing::error!(%error, "Import failed");
+        self.errors += 1;
+        self.events.push_back(ImportEvent::Error { line: self.lines, error: "database error".to_string() });
+        self.finish();
+    }
+}
+
+///
+/// Imports newline-delimited JSON todos, without ever holding the whole body
+/// in memory. The import is driven by the response: each time the client
+/// reads the next progress line, the handler reads the request body up to
+/// the next chunk and inserts it. A slow client therefore slows down the
+/// import, instead of letting progress events pile up on the server.
+///
+/// Every chunk is committed on its own, so an import that fails halfway
+/// keeps the chunks before the failure. Lines that are not valid todos are
+/// reported and skipped.
+///
+async fn import_todos<R: TodoRepo + Clone + 'static>(
+    Claims { sub: user_id, .. }: Claims,
+    State(TodoState{ repo, events, .. }): State<TodoState<R>>,
+    body: Body,
+) -> impl IntoResponse {
+    let import = Import {
+        repo,
+        user_id,
+        body,
+        todo_events: events,
+        buffer: Vec::new(),
+        pending: Vec::with_capacity(IMPORT_CHUNK_SIZE),
+        events: VecDeque::new(),
+        lines: 0,
+        imported: 0,
+        errors: 0,
+        finished: false,
+    };
+
+    let events = futures::stream::unfold(import, |mut import| async move {
+        let event = import.next_event().await?;
+        let line = format!("{}\n", serde_json::to_string(&event).unwrap());
+        Some((Ok::<_, Infallible>(line), import))
+    });
+
+    ([(header::CONTENT_TYPE, "application/x-ndjson")], Body::from_stream(events))
+}
+
+async fn get_todo_tree<R: TodoRepo>(
+    Claims { sub: user_id, .. }: Claims,
+    TodoTree { id }: TodoTree,
+    State(TodoState { repo, .. }): State<TodoState<R>>,
+) -> Result<Json<TodoTreeDTO>, Response> {
+    let todos = repo.get_todo_tree(user_id, id).await?;
+    let root = todos.first().ok_or_else(|| StatusCode::NOT_FOUND.into_response())?;
+    Ok(Json(TodoTreeDTO::build(root, &todos)))
+}
+
+#[derive(Debug, PartialEq)]
+pub enum SubtaskError {
+    NotFound(i64),
+    Cycle,
+}
+
+#[derive(Debug, serde::Deserialize, serde::Serialize)]
+struct SetParent {
+    parent_id: Option<i64>,
+}
+
+async fn set_parent<R: TodoRepo>(
+    Claims { sub: user_id, .. }: Claims,
+    TodoParent { id }: TodoParent,
+    State(TodoState { repo, events, .. }): State<TodoState<R>>,
+    AppJson(SetParent { parent_id }): AppJson<SetParent>,
+) -> Result<StatusCode, Response> {
+    match repo.set_parent(user_id, id, parent_id).await? {
+        Ok(()) => {
+            events.publish(TodoEventKind::Updated, id, user_id);
+            Ok(StatusCode::NO_CONTENT)
+        }
+        Err(SubtaskError::NotFound(id)) => {
+            Err((StatusCode::NOT_FOUND, format!("Todo {} not found", id)).into_response())
+        }
+        Err(SubtaskError::Cycle) => {
+            Err((StatusCode::UNPROCESSABLE_ENTITY, "A todo cannot be a subtask of itself or of its own subtasks")
+                .into_response())
+        }
+    }
+}
+
+async fn get_comments<R: TodoRepo>(
+    Claims { sub: user_id, .. }: Claims,
+    TodoComments { id }: TodoComments,
+    State(TodoState { repo, .. }): State<TodoState<R>>,
+) -> Result<Json<TodoWithCommentsDTO>, Response> {
+    let todo = repo.get_todo(user_id, id).await?.ok_or_else(|| StatusCode::NOT_FOUND.into_response())?;
+    let comments = repo.get_comments(user_id, id).await?;
+
+    Ok(Json(TodoWithCommentsDTO {
+        todo: todo.to_dto(),
+        comments: comments.iter().map(Comment::to_dto).collect(),
+    }))
+}
+
+///
+/// A signed link to a read-only view of the todo, which works without
+/// authentication until it expires (see the `signed_urls` module).
+///
+async fn share_todo<R: TodoRepo>(
+    Claims { sub: user_id, .. }: Claims,
+    TodoShare { id }: TodoShare,
+    State(TodoState{ repo, .. }): State<TodoState<R>>,
+    State(signer): State<UrlSigner>,
+    Query(query): Query<ShareQuery>,
+) -> Result<Json<SignedLink>, Response> {
+    repo.get_todo(user_id, id).await?.ok_or_else(|| StatusCode::NOT_FOUND.into_response())?;
+    let path = format!("{}?user={}", SharedTodo { id }, user_id);
+    tracing::info!(target: "audit", user_id, todo_id = id, "Todo shared");
+    Ok(Json(signer.sign(&path, query.ttl())))
+}
+
+async fn get_shared_todo<R: TodoRepo>(
+    _: SignedUrl,
+    SharedTodo { id }: SharedTodo,
+    Query(SharedBy { user }): Query<SharedBy>,
+    State(TodoState { repo, .. }): State<TodoState<R>>,
+) -> Result<Json<TodoDTO>, Response> {
+    let todo = repo.get_todo(user, id).await?.ok_or_else(|| StatusCode::NOT_FOUND.into_response())?;
+    Ok(Json(todo.to_dto()))
+}
+
+#[derive(Debug, serde::Deserialize, serde::Serialize)]
+struct CreateComment {
+    body: String,
+}
+
+///
+/// Loads the comments of todos visible to a user, grouped by todo. When the
+/// query fails, every todo of the batch gets the error.
+///
+struct CommentLoader<R: TodoRepo> {
+    repo: R,
+    user_id: i64,
+}
+
+#[async_trait]
+impl<R: TodoRepo> Loader for CommentLoader<R> {
+    type Key = i64;
+    type Value = Result<Vec<Comment>, RepoError>;
+
+    async fn load(&self, todo_ids: &[i64]) -> HashMap<i64, Result<Vec<Comment>, RepoError>> {
+        let loaded = match self.repo.get_comments_of(self.user_id, todo_ids).await {
+            Ok(loaded) => loaded,
+            Err(error) => return todo_ids.iter().map(|id| (*id, Err(error.clone()))).collect(),
+        };
+        let mut comments: HashMap<i64, Vec<Comment>> = HashMap::new();
+        for comment in loaded {
+            comments.entry(comment.todo_id).or_default().push(comment);
+        }
+        comments.into_iter().map(|(id, comments)| (id, Ok(comments))).collect()
+    }
+}
+
+///
+/// Every todo with its comments. Each todo loads its own comments, but
+/// through a `CommentLoader`, so that all of them are loaded with one query
+/// rather than one per todo.
+///
+async fn get_todos_with_comments<R: TodoRepo>(
+    Claims { sub: user_id, .. }: Claims,
+    State(TodoState{ repo, .. }): State<TodoState<R>>,
+) -> Result<AppJson<Vec<TodoWithCommentsDTO>>, RepoError> {
+    let todos = repo.get_todos(user_id, TodoSort::Id).await?;
+    let comments = DataLoader::new(CommentLoader { repo, user_id });
+
+    let todos = futures::future::join_all(todos.iter().map(|todo| async {
+        let comments = comments.load(todo.id).await.unwrap_or(Ok(Vec::new()))?;
+        Ok(TodoWithCommentsDTO {
+            todo: todo.to_dto(),
+            comments: comments.iter().map(Comment::to_dto).collect(),
+        })
+    }))
+    .await;
+
+    Ok(AppJson(todos.into_iter().collect::<Result<_, RepoError>>()?))
+}
+
+async fn create_comment<R: TodoRepo>(
+    Claims { sub: user_id, .. }: Claims,
+    TodoComments { id }: TodoComments,
+    State(TodoState { repo, events, .. }): State<TodoState<R>>,
+    AppJson(CreateComment { body }): AppJson<CreateComment>,
+) -> Result<Json<i64>, Response> {
+    let comment_id =
+        repo.create_comment(user_id, id, &body).await?.ok_or_else(|| StatusCode::NOT_FOUND.into_response())?;
+    events.publish(TodoEventKind::Updated, id, user_id);
+    Ok(Json(comment_id))
+}
+
+async fn delete_comment<R: TodoRepo>(
+    Claims { sub: user_id, .. }: Claims,
+    TodoComment { id, comment_id }: TodoComment,
+    State(TodoState { repo, events, .. }): State<TodoState<R>>,
+) -> Result<StatusCode, RepoError> {
+    if repo.delete_comment(user_id, id, comment_id).await? {
+        events.publish(TodoEventKind::Updated, id, user_id);
+        Ok(StatusCode::NO_CONTENT)
+    } else {
+        Ok(StatusCode::NOT_FOUND)
+    }
+}
+
+///
+/// The todo routes, with state applied, plus a fresh user and the value of an
+/// `Authorization` header for them, for tests of the todo handlers.
+///
+async fn test_todo_app(repo: TodoRepoPostgres) -> (Router, i64, String) {
+    test_todo_app_with_clock(repo, Arc::new(SystemClock)).await
+}
+
+///
+/// Like `test_todo_app`, but with the given clock in place of the system's.
+///
+async fn test_todo_app_with_clock(repo: TodoRepoPostgres, clock: SharedClock) -> (Router, i64, String) {
+    let user_id = crate::users::create_test_user(&repo.pool, false).await;
+    let (app, token) = test_todo_app_as(Arc::new(repo), user_id, clock).await;
+    (app, user_id, token)
+}
+
+#[tokio::test]
+async fn todo_handlers_work_on_postgres() {
+    let pool = PgPoolOptions::new()
+        .max_connections(1)
+        .connect(&crate::test_db::database_url().await)
+        .await
+        .unwrap();
+    let user_id = crate::users::create_test_user(&pool, false).await;
+    run_todo_handler_suite(Arc::new(TodoRepoPostgres { pool }), user_id).await;
+}
+
+#[tokio::test]
+async fn todo_handlers_work_in_memory() {
+    run_todo_handler_suite(Arc::new(TodoRepoInMemory::default()), 1).await;
+}
+
+#[tokio::test]
+async fn todo_repos_conform_in_memory() {
+    let repo = TodoRepoInMemory::default();
+    conformance_tests(|| repo.clone(), 1, 2).await;
+}
+
+#[tokio::test]
+async fn todo_repos_conform_on_postgres() {
+    let pool = PgPoolOptions::new()
+        .max_connections(4)
+        .connect(&crate::test_db::database_url().await)
+        .await
+        .unwrap();
+    let user_id = crate::users::create_test_user(&pool, false).await;
+    let other_user_id = crate::users::create_test_user(&pool, false).await;
+    conformance_tests(|| TodoRepoPostgres { pool: pool.clone() }, user_id, other_user_id).await;
+}
+
+#[tokio::test]
+async fn todo_links_use_typed_paths() {
+    // for Body::collect
+    use http_body_util::BodyExt;
+    /// for ServiceExt::oneshot
+    use tower::util::ServiceExt;
+    use axum::{body::Body, http::{Method, Request}};
+
+    let pool = PgPoolOptions::new()
+        .max_connections(1)
+        .connect(&crate::test_db::database_url().await)
+        .await
+        .unwrap();
+
+    let repo = TodoRepoPostgres { pool };
+    let (app, user_id, token) = test_todo_app(repo.clone()).await;
+    let id = repo.create_todo(user_id, "Typed paths", "Link to me", None, 0).await.unwrap();
+
+    let response = app
+        .oneshot(
+            Request::builder()
+                .method(Method::GET)
+                .uri(TodoById { id }.to_string())
+                .header("Authorization", token)
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+    let body = response.into_body().collect().await.unwrap().to_bytes();
+    let todo: TodoDTO = serde_json::from_slice(&body).unwrap();
+
+    assert_eq!(todo.href, format!("/todo/{}", id));
+}
+
+#[tokio::test]
+async fn bulk_transaction_rolls_back_on_failure() {
+    let pool = PgPoolOptions::new()
+        .max_connections(1)
+        .connect(&crate::test_db::database_url().await)
+        .await
+        .unwrap();
+
+    let repo = TodoRepoPostgres { pool: pool.clone() };
+    let user_id = crate::users::create_test_user(&pool, false).await;
+    let existing = repo.create_todo(user_id, "Bulk", "Existing todo", None, 0).await.unwrap();
+
+    let operations = vec![
+        BulkOperation::Update {
+            id: existing,
+            title: None,
+            description: None,
+            status: Some(TodoStatus::Done),
+            due_at: None,
+            priority: None,
+        },
+        BulkOperation::Delete { id: -1 },
+        BulkOperation::Create {
+            title: "Bulk".to_string(),
+            description: "Never created".to_string(),
+            due_at: None,
+            priority: 0,
+        },
+    ];
+
+    let results = repo.bulk(user_id, &operations, true).await.unwrap();
+
+    assert_eq!(results, vec![Err(BulkError::RolledBack), Err(BulkError::NotFound(-1)), Err(BulkError::NotAttempted)]);
+    assert_eq!(repo.get_todo(user_id, existing).await.unwrap().unwrap().status, TodoStatus::Open);
+}
+
+#[test]
+fn bulk_database_errors_are_not_shown_to_clients() {
+    let error = BulkError::from(sqlx::Error::Protocol("relation \"todos\" does not exist".to_string()));
+
+    assert_eq!(error, BulkError::Database);
+    assert_eq!(error.message(), "database error");
+}
+
+#[tokio::test]
+async fn bulk_independent_reports_partial_failure() {
+    // for Body::collect
+    use http_body_util::BodyExt;
+    /// for ServiceExt::oneshot
+    use tower::util::ServiceExt;
+    use axum::{body::Body, http::{Method, Request}};
+
+    let pool = PgPoolOptions::new()
+        .max_connections(1)
+        .connect(&crate::test_db::database_url().await)
+        .await
+        .unwrap();
+
+    let repo = TodoRepoPostgres { pool };
+    let (app, user_id, token) = test_todo_app(repo.clone()).await;
+
+    let response = app
+        .oneshot(
+            Request::builder()
+                .method(Method::POST)
+                .uri(TodoBulk.to_string())
+                .header("Authorization", token)
+                .header("Content-Type", "application/json")
+                .body(Body::from(
+                    r#"{
+                        "mode": "independent",
+                        "operations": [
+                            { "op": "create", "title": "Bulk", "description": "Created" },
+                            { "op": "delete", "id": -1 }
+                        ]
+                    }"#,
+                ))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), StatusCode::MULTI_STATUS);
+
+    let body = response.into_body().collect().await.unwrap().to_bytes();
+    let results: Vec<BulkResult> = serde_json::from_slice(&body).unwrap();
+
+    assert_eq!(results[0].status, 200);
+    assert!(repo.get_todo(user_id, results[0].id.unwrap()).await.unwrap().is_some());
+    assert_eq!(results[1], BulkResult { status: 404, id: None, error: Some("Todo -1 not found".to_string()) });
+}
+
+#[tokio::test]
+async fn patch_todo_merges_and_rejects_nulls() {
+    // for Body::collect
+    use http_body_util::BodyExt;
+    /// for ServiceExt::oneshot
+    use tower::util::ServiceExt;
+    use axum::{body::Body, http::{Method, Request}};
+
+    let pool = PgPoolOptions::new()
+        .max_connections(1)
+        .connect(&crate::test_db::database_url().await)
+        .await
+        .unwrap();
+
+    let repo = TodoRepoPostgres { pool };
+    let (app, user_id, token) = test_todo_app(repo.clone()).await;
+    let id = repo.create_todo(user_id, "Patch", "Before", None, 0).await.unwrap();
+
+    let patch = |body: &'static str| {
+        Request::builder()
+            .method(Method::PATCH)
+            .uri(TodoById { id }.to_string())
+            .header("Authorization", &token)
+            .header("Content-Type", "application/merge-patch+json")
+            .body(Body::from(body))
+            .unwrap()
+    };
+
+    let response = app.clone().oneshot(patch(r#"{ "status": "done" }"#)).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let body = response.into_body().collect().await.unwrap().to_bytes();
+    let todo: TodoDTO = serde_json::from_slice(&body).unwrap();
+    assert_eq!((todo.title.as_str(), todo.description.as_str(), todo.status), ("Patch", "Before", TodoStatus::Done));
+
+    let response = app.clone().oneshot(patch(r#"{ "title": null }"#)).await.unwrap();
+    assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    let body = response.into_body().collect().await.unwrap().to_bytes();
+    let error: crate::extract::ErrorBody = serde_json::from_slice(&body).unwrap();
+    assert_eq!(error.message, "missing field `title`");
+    assert_eq!(error.path.as_deref(), Some("title"));
+
+    let response = app.oneshot(patch(r#"{ "id": 0 }"#)).await.unwrap();
+    assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+}
+
+///
+/// Two patches of one todo at once, each of which takes long enough between
+/// reading the todo and saving it for the other to read it too, unless the
+/// repo locks it. Both of their keys must be in the metadata.
+///
+#[tokio::test(flavor = "multi_thread")]
+async fn concurrent_patches_do_not_overwrite_each_other() {
+    let pool = PgPoolOptions::new()
+        .max_connections(4)
+        .connect(&crate::test_db::database_url().await)
+        .await
+        .unwrap();
+    let user_id = crate::users::create_test_user(&pool, false).await;
+    let repos: [(SharedTodoRepo, i64); 3] = [
+        (Arc::new(TodoRepoPostgres { pool: pool.clone() }), user_id),
+        (Arc::new(TodoRepoRls { pool }), user_id),
+        (Arc::new(TodoRepoInMemory::default()), 1),
+    ];
+
+    for (repo, user_id) in repos {
+        let id = repo.create_todo(user_id, "Patched twice", "", None, 0).await.unwrap();
+        let patches = ["a", "b"].map(|key| {
+            let repo = repo.clone();
+            tokio::spawn(async move {
+                let apply = |todo: Todo| {
+                    std::thread::sleep(Duration::from_millis(200));
+                    let mut metadata = todo.metadata.as_object().cloned().unwrap_or_default();
+                    metadata.insert(key.to_string(), serde_json::json!(true));
+                    Ok(PatchableTodo {
+                        title: todo.title,
+                        description: todo.description,
+                        status: todo.status,
+                        due_at: todo.due_at,
+                        priority: todo.priority,
+                        metadata,
+                    })
+                };
+                repo.patch_todo(user_id, id, &apply).await.unwrap().unwrap().unwrap()
+            })
+        });
+        for patch in patches {
+            patch.await.unwrap();
+        }
+
+        let metadata = repo.get_todo(user_id, id).await.unwrap().unwrap().metadata;
+        assert_eq!(metadata, serde_json::json!({ "a": true, "b": true }));
+    }
+}
+
+///
+/// The contract of `openapi.json`: each example matches its schema, and is
+/// read by the handler's body type as the spec says. A field renamed on
+/// either side fails here.
+///
+#[test]
+fn openapi_examples_deserialize_into_the_request_types() {
+    use axum::http::Method;
+
+    let spec = OpenApi::todos();
+    let example = |method: Method, path: &str| {
+        let (schema, example) = spec.request_body(&method, path).unwrap();
+        let example = example.unwrap().clone();
+        assert_eq!(spec.validate(schema, &example), vec![], "example of {} {}", method, path);
+        example
+    };
+
+    serde_json::from_value::<CreateTodo>(example(Method::POST, "/todo")).unwrap();
+    serde_json::from_value::<BulkRequest>(example(Method::POST, "/todo/bulk")).unwrap();
+    serde_json::from_value::<UpdateTodo>(example(Method::PUT, "/todo/1")).unwrap();
+    serde_json::from_value::<SetParent>(example(Method::PUT, "/todo/1/parent")).unwrap();
+    serde_json::from_value::<CreateComment>(example(Method::POST, "/todo/1/comments")).unwrap();
+
+    // A merge patch is not a `PatchableTodo`, but a todo it is applied to
+    // must still be one.
+    let mut document = serde_json::json!({ "title": "Patch", "description": "", "status": "open", "priority": 0 });
+    merge_patch(&mut document, &example(Method::PATCH, "/todo/1"));
+    serde_json::from_value::<PatchableTodo>(document).unwrap();
+}
+
+///
+/// Sends the example of every documented operation to the todo routes,
+/// through the validation of `openapi.json`: none of them may be missing, or
+/// rejected by the handler.
+///
+#[tokio::test]
+async fn handlers_accept_the_openapi_examples() {
+    /// for ServiceExt::oneshot
+    use tower::util::ServiceExt;
+    use axum::{body::Body, http::{Method, Request}};
+
+    let pool = PgPoolOptions::new()
+        .max_connections(1)
+        .connect(&crate::test_db::database_url().await)
+        .await
+        .unwrap();
+
+    let repo = TodoRepoPostgres { pool };
+    let (app, user_id, token) = test_todo_app(repo.clone()).await;
+    let id = repo.create_todo(user_id, "Contract", "", None, 0).await.unwrap();
+
+    let spec = OpenApi::todos();
+    for (method, path) in spec.operations() {
+        let method = Method::from_bytes(method.as_bytes()).unwrap();
+        let uri = path.replace(":id", &id.to_string());
+        let (_, example) = spec.request_body(&method, &uri).unwrap();
+        let content_type = if method == Method::PATCH { "application/merge-patch+json" } else { "application/json" };
+
+        let request = Request::builder()
+            .method(method.clone())
+            .uri(&uri)
+            .header("Authorization", &token)
+            .header("Content-Type", content_type)
+            .body(Body::from(example.unwrap().to_string()))
+            .unwrap();
+        let status = app.clone().oneshot(request).await.unwrap().status();
+
+        assert!(status.is_success(), "{} {} answered {}", method, path, status);
+    }
+}
+
+///
+/// What the todo command-line client relies on, recorded into
+/// `contracts/todo-cli.json` (see the `contracts` module).
+///
+#[tokio::test]
+async fn record_the_todo_cli_contract() {
+    use crate::contracts::ContractRecorder;
+    use axum::{body::Body, http::{Method, Request}};
+
+    let pool = PgPoolOptions::new()
+        .max_connections(1)
+        .connect(&crate::test_db::database_url().await)
+        .await
+        .unwrap();
+
+    let repo = TodoRepoPostgres { pool };
+    let (app, user_id, token) = test_todo_app(repo.clone()).await;
+    let id = repo.create_todo(user_id, "Buy milk", "Semi-skimmed", None, 1).await.unwrap();
+
+    let request = |method: Method, uri: String, body: Option<&'static str>| {
+        let builder = Request::builder().method(method).uri(uri).header("Authorization", &token);
+        match body {
+            Some(body) => builder.header("Content-Type", "application/json").body(Body::from(body)),
+            None => builder.body(Body::empty()),
+        }
+        .unwrap()
+    };
+
+    let mut recorder = ContractRecorder::new("todo-cli", "todos");
+    let response = recorder
+        .send(&app, "create a todo", request(Method::POST, TodoCollection.to_string(), Some(r#"{ "title": "Call Bob", "description": "" }"#)))
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    recorder.given("a todo exists").param("id", id);
+    let response = recorder.send(&app, "fetch a todo", request(Method::GET, TodoById { id }.to_string(), None)).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let response = recorder
+        .send(&app, "complete a todo", request(Method::PUT, TodoById { id }.to_string(), Some(r#"{ "status": "done" }"#)))
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let response = recorder
+        .send(&app, "comment on a todo", request(Method::POST, TodoComments { id }.to_string(), Some(r#"{ "body": "Done" }"#)))
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let response = recorder.send(&app, "list the comments of a todo", request(Method::GET, TodoComments { id }.to_string(), None)).await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let contract = recorder.finish();
+    assert_eq!(contract.interactions.len(), 5);
+    assert_eq!(contract.interactions[1].request.path, "/todo/{id}");
+}
+
+///
+/// Replays every contract of `contracts/` against the todo routes, which
+/// fails when a change would break one of the consumers.
+///
+#[tokio::test]
+async fn todo_routes_honour_consumer_contracts() {
+    use crate::contracts::{Contract, ContractVerifier};
+
+    let pool = PgPoolOptions::new()
+        .max_connections(1)
+        .connect(&crate::test_db::database_url().await)
+        .await
+        .unwrap();
+
+    let repo = TodoRepoPostgres { pool };
+    let (app, user_id, token) = test_todo_app(repo.clone()).await;
+    let id = repo.create_todo(user_id, "Contract", "", None, 0).await.unwrap();
+
+    let verifier = ContractVerifier::new(app)
+        .header(header::AUTHORIZATION, &token)
+        .state("a todo exists", &[("id", &id)]);
+
+    let contracts = Contract::load_all();
+    assert!(!contracts.is_empty());
+    for contract in &contracts {
+        let mismatches = verifier.verify(contract).await;
+        let report = mismatches.iter().map(ToString::to_string).collect::<Vec<_>>().join("\n");
+        assert!(mismatches.is_empty(), "{}", report);
+    }
+}
+
+#[tokio::test]
+async fn metadata_is_patched_and_filtered_by_containment() {
+    // for Body::collect
+    use http_body_util::BodyExt;
+    /// for ServiceExt::oneshot
+    use tower::util::ServiceExt;
+    use axum::{body::Body, http::{Method, Request}};
+
+    let pool = PgPoolOptions::new()
+        .max_connections(1)
+        .connect(&crate::test_db::database_url().await)
+        .await
+        .unwrap();
+
+    let repo = TodoRepoPostgres { pool };
+    let (app, user_id, token) = test_todo_app(repo.clone()).await;
+    let home = repo.create_todo(user_id, "Home", "", None, 0).await.unwrap();
+    let work = repo.create_todo(user_id, "Work", "", None, 0).await.unwrap();
+
+    let patch = |id: i64, body: &'static str| {
+        Request::builder()
+            .method(Method::PATCH)
+            .uri(TodoById { id }.to_string())
+            .header("Authorization", &token)
+            .header("Content-Type", "application/merge-patch+json")
+            .body(Body::from(body))
+            .unwrap()
+    };
+    let filter = |metadata: &str| {
+        let url = reqwest::Url::parse_with_params("http://localhost/todo", [("metadata", metadata)]).unwrap();
+        Request::builder()
+            .uri(format!("{}?{}", TodoCollection::PATH, url.query().unwrap()))
+            .header("Authorization", &token)
+            .body(Body::empty())
+            .unwrap()
+    };
+
+    app.clone().oneshot(patch(home, r#"{ "metadata": { "label": "home", "source": { "app": "notes" } } }"#)).await.unwrap();
+    app.clone().oneshot(patch(work, r#"{ "metadata": { "label": "work", "source": { "app": "mail" } } }"#)).await.unwrap();
+
+    // Patching one key leaves the others alone.
+    let response = app.clone().oneshot(patch(home, r#"{ "metadata": { "source": null, "urgent": true } }"#)).await.unwrap();
+    let body = response.into_body().collect().await.unwrap().to_bytes();
+    let todo: TodoDTO = serde_json::from_slice(&body).unwrap();
+    assert_eq!(todo.metadata, serde_json::json!({ "label": "home", "urgent": true }));
+
+    let ids = |body: Bytes| {
+        let todos: Vec<TodoDTO> = serde_json::from_slice(&body).unwrap();
+        todos.into_iter().map(|todo| todo.id).collect::<Vec<_>>()
+    };
+
+    let response = app.clone().oneshot(filter(r#"{ "label": "home" }"#)).await.unwrap();
+    assert_eq!(ids(response.into_body().collect().await.unwrap().to_bytes()), vec![home]);
+
+    // Containment reaches into nested objects.
+    let response = app.clone().oneshot(filter(r#"{ "source": { "app": "mail" } }"#)).await.unwrap();
+    assert_eq!(ids(response.into_body().collect().await.unwrap().to_bytes()), vec![work]);
+
+    let response = app.clone().oneshot(filter(r#"{ "label": "home", "urgent": false }"#)).await.unwrap();
+    assert_eq!(ids(response.into_body().collect().await.unwrap().to_bytes()), Vec::<i64>::new());
+
+    let response = app.clone().oneshot(filter(r#"["home"]"#)).await.unwrap();
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+    let response = app.oneshot(patch(home, r#"{ "metadata": ["home"] }"#)).await.unwrap();
+    assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+}
+
+#[tokio::test]
+async fn todo_form_posts_redirect_with_a_flash_message() {
+    // for Body::collect
+    use http_body_util::BodyExt;
+    /// for ServiceExt::oneshot
+    use tower::util::ServiceExt;
+    use axum::{body::Body, http::{header, Method, Request}};
+
+    use crate::cookies::{cookie_pair, set_cookies};
+
+    let pool = PgPoolOptions::new()
+        .max_connections(1)
+        .connect(&crate::test_db::database_url().await)
+        .await
+        .unwrap();
+
+    let repo = TodoRepoPostgres { pool };
+    let (app, user_id, token) = test_todo_app(repo.clone()).await;
+
+    let get_form = |cookie: Option<String>| {
+        let mut request = Request::builder().uri(TodoNew.to_string()).header("Authorization", &token);
+        if let Some(cookie) = cookie {
+            request = request.header(header::COOKIE, cookie);
+        }
+        app.clone().oneshot(request.body(Body::empty()).unwrap())
+    };
+    let post_form = |body: &'static str| {
+        let request = Request::builder()
+            .method(Method::POST)
+            .uri(TodoNew.to_string())
+            .header("Authorization", &token)
+            .header(header::CONTENT_TYPE, "application/x-www-form-urlencoded")
+            .body(Body::from(body))
+            .unwrap();
+        app.clone().oneshot(request)
+    };
+
+    let response = get_form(None).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert!(response.headers()[header::CONTENT_TYPE].to_str().unwrap().starts_with("text/html"));
+    let html = response.into_body().collect().await.unwrap().to_bytes();
+    assert!(String::from_utf8_lossy(&html).contains(r#"<form method="post" action="/todo/new">"#));
+
+    let response = post_form("title=Buy+%3Cmilk%3E&description=2+liters").await.unwrap();
+    assert_eq!(response.status(), StatusCode::SEE_OTHER);
+    assert_eq!(response.headers()[header::LOCATION], TodoNew.to_string());
+    let flash = cookie_pair(&set_cookies(response.headers())[0]);
+
+    let todos = repo.get_todos(user_id, TodoSort::Id).await.unwrap();
+    assert_eq!((todos[0].title.as_str(), todos[0].description.as_str()), ("Buy <milk>", "2 liters"));
+
+    // The flash is shown once, escaped, and removed.
+    let response = get_form(Some(flash)).await.unwrap();
+    assert!(set_cookies(response.headers())[0].starts_with("flash=; "));
+    let html = response.into_body().collect().await.unwrap().to_bytes();
+    assert!(String::from_utf8_lossy(&html).contains("Created &quot;Buy &lt;milk&gt;&quot;"));
+
+    let response = post_form("title=+&description=Kept").await.unwrap();
+    assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    let html = response.into_body().collect().await.unwrap().to_bytes();
+    let html = String::from_utf8_lossy(&html);
+    assert!(html.contains("A todo needs a title") && html.contains(">Kept</textarea>"));
+}
+
+#[tokio::test]
+async fn todos_are_filtered_by_tags_statuses_and_due_dates() {
+    // for Body::collect
+    use http_body_util::BodyExt;
+    /// for ServiceExt::oneshot
+    use tower::util::ServiceExt;
+    use axum::{body::Body, http::Request};
+    use time::macros::datetime;
+
+    let pool = PgPoolOptions::new()
+        .max_connections(1)
+        .connect(&crate::test_db::database_url().await)
+        .await
+        .unwrap();
+
+    let repo = TodoRepoPostgres { pool };
+    let (app, user_id, token) = test_todo_app(repo.clone()).await;
+    let todo = |title: &'static str, due_at: OffsetDateTime, status: TodoStatus, tags: serde_json::Value| {
+        let repo = repo.clone();
+        async move {
+            let id = repo.create_todo(user_id, title, "", Some(due_at), 0).await.unwrap();
+            let patched = PatchableTodo {
+                title: title.to_string(),
+                description: String::new(),
+                status,
+                due_at: Some(due_at),
+                priority: 0,
+                metadata: serde_json::json!({ "tags": tags }).as_object().unwrap().clone(),
+            };
+            repo.replace_todo(user_id, id, &patched).await.unwrap().unwrap()
+        }
+    };
+
+    let groceries = todo("Groceries", datetime!(2026-10-17 12:00 UTC), TodoStatus::Open, serde_json::json!(["home", "urgent"])).await;
+    let laundry = todo("Laundry", datetime!(2026-10-18 12:00 UTC), TodoStatus::InProgress, serde_json::json!(["home"])).await;
+    let report = todo("Report", datetime!(2026-10-19 12:00 UTC), TodoStatus::Done, serde_json::json!(["work", "urgent"])).await;
+
+    let filter = |query: &'static str| {
+        let app = app.clone();
+        let request = Request::builder()
+            .uri(format!("{}?{}", TodoCollection::PATH, query))
+            .header("Authorization", &token)
+            .body(Body::empty())
+            .unwrap();
+        async move {
+            let response = app.oneshot(request).await.unwrap();
+            let status = response.status();
+            let body = response.into_body().collect().await.unwrap().to_bytes();
+            match status {
+                StatusCode::OK => Ok(serde_json::from_slice::<Vec<TodoDTO>>(&body).unwrap().iter().map(|todo| todo.id).collect::<Vec<_>>()),
+                status => Err(status),
+            }
+        }
+    };
+
+    assert_eq!(filter("tag[]=home").await, Ok(vec![groceries, laundry]));
+    assert_eq!(filter("tag[]=home&tag[]=urgent").await, Ok(vec![groceries]));
+    assert_eq!(filter("tag%5B%5D=urgent").await, Ok(vec![groceries, report]));
+    assert_eq!(filter("status[]=in_progress&status[]=done").await, Ok(vec![laundry, report]));
+    assert_eq!(filter("tag[]=urgent&status[]=open").await, Ok(vec![groceries]));
+    assert_eq!(filter("due[after]=2026-10-18T00:00:00Z").await, Ok(vec![laundry, report]));
+    assert_eq!(
+        filter("due[after]=2026-10-18T00:00:00Z&due[before]=2026-10-19T12:00:00Z").await,
+        Ok(vec![laundry])
+    );
+
+    assert_eq!(filter("status[]=someday").await, Err(StatusCode::BAD_REQUEST));
+    assert_eq!(filter("due[before]=tomorrow").await, Err(StatusCode::BAD_REQUEST));
+    assert_eq!(filter("tag[]=home&sort=priority").await, Err(StatusCode::BAD_REQUEST));
+    // metadata={"tags":[]}
+    assert_eq!(filter("tag[]=home&metadata=%7B%22tags%22%3A%5B%5D%7D").await, Err(StatusCode::BAD_REQUEST));
+}
+
+///
+/// Golden files of the SQL and binds of `filtered_todos_query`, one for each
+/// kind of filter and one for all of them at once. Review changes with
+/// `cargo insta review`.
+///
+#[test]
+fn filtered_todos_queries_match_golden_files() {
+    use time::macros::datetime;
+
+    let render = |filter: &TodoFilter| {
+        let sql = filtered_todos_query(QueryBuilder::new(""), 7, filter).into_sql();
+        let binds: Vec<String> = filter_conditions(7, filter)
+            .iter()
+            .enumerate()
+            .map(|(i, (_, bind, _))| format!("${} = {:?}", i + 1, bind))
+            .collect();
+        format!("{}\n\n{}", sql, binds.join("\n"))
+    };
+    let everything = TodoFilter {
+        metadata: serde_json::json!({ "tags": ["home"] }),
+        statuses: vec![TodoStatus::Open, TodoStatus::InProgress],
+        due_after: Some(datetime!(2026-10-18 0:00 UTC)),
+        due_before: Some(datetime!(2026-10-19 12:00 UTC)),
+    };
+
+    let none = TodoFilter { metadata: serde_json::json!({}), statuses: vec![], due_after: None, due_before: None };
+    insta::assert_snapshot!("filtered_todos_none", render(&none));
+    insta::assert_snapshot!("filtered_todos_metadata", render(&TodoFilter { metadata: everything.metadata.clone(), ..none.clone() }));
+    insta::assert_snapshot!("filtered_todos_statuses", render(&TodoFilter { statuses: everything.statuses.clone(), ..none.clone() }));
+    insta::assert_snapshot!("filtered_todos_due_after", render(&TodoFilter { due_after: everything.due_after, ..none.clone() }));
+    insta::assert_snapshot!("filtered_todos_due_range", render(&TodoFilter { due_after: everything.due_after, due_before: everything.due_before, ..none.clone() }));
+    insta::assert_snapshot!("filtered_todos_everything", render(&everything));
+}
+
+///
+/// The golden files say what SQL is sent, and this that Postgres can answer
+/// it from the indexes meant for it. Sequential scans are turned off, since
+/// on a table as small as the test's they would always win.
+///
+#[tokio::test]
+async fn filtered_todos_queries_use_their_indexes() {
+    use time::macros::datetime;
+
+    let pool = PgPoolOptions::new()
+        .max_connections(1)
+        .connect(&crate::test_db::database_url().await)
+        .await
+        .unwrap();
+
+    fn index_names(plan: &serde_json::Value, names: &mut Vec<String>) {
+        match plan {
+            serde_json::Value::Object(node) => {
+                if let Some(serde_json::Value::String(name)) = node.get("Index Name") {
+                    names.push(name.clone());
+                }
+                node.values().for_each(|value| index_names(value, names));
+            }
+            serde_json::Value::Array(nodes) => nodes.iter().for_each(|node| index_names(node, names)),
+            _ => {}
+        }
+    }
+    let indexes = |filter: TodoFilter| {
+        let pool = pool.clone();
+        async move {
+            let mut tx = pool.begin().await.unwrap();
+            sqlx::query("SET LOCAL enable_seqscan = off").execute(&mut *tx).await.unwrap();
+            let mut query = filtered_todos_query(QueryBuilder::new("EXPLAIN (FORMAT JSON) "), 7, &filter);
+            let plan: serde_json::Value = query.build_query_scalar().fetch_one(&mut *tx).await.unwrap();
+            let mut names = Vec::new();
+            index_names(&plan, &mut names);
+            names
+        }
+    };
+    let none = TodoFilter { metadata: serde_json::json!({}), statuses: vec![], due_after: None, due_before: None };
+
+    let metadata = TodoFilter { metadata: serde_json::json!({ "tags": ["home"] }), ..none.clone() };
+    assert!(indexes(metadata).await.contains(&"todos_metadata_idx".to_string()));
+    // The index of due dates only covers todos that are still to be done.
+    let due = TodoFilter {
+        statuses: vec![TodoStatus::Open, TodoStatus::InProgress],
+        due_after: Some(datetime!(2026-10-18 0:00 UTC)),
+        due_before: Some(datetime!(2026-10-19 12:00 UTC)),
+        ..none.clone()
+    };
+    assert!(indexes(due).await.contains(&"todos_due_at_idx".to_string()));
+    let done = TodoFilter { statuses: vec![TodoStatus::Done], due_after: Some(datetime!(2026-10-18 0:00 UTC)), ..none.clone() };
+    assert!(!indexes(done).await.contains(&"todos_due_at_idx".to_string()));
+}
+
+#[tokio::test]
+async fn overdue_todos_compare_instants() {
+    use time::{macros::datetime, Duration, UtcOffset};
+
+    let pool = PgPoolOptions::new()
+        .max_connections(1)
+        .connect(&crate::test_db::database_url().await)
+        .await
+        .unwrap();
+
+    let repo = TodoRepoPostgres { pool: pool.clone() };
+    let user_id = crate::users::create_test_user(&pool, false).await;
+    let now = datetime!(2026-10-16 12:00 UTC);
+
+    // Due an hour ago, but expressed at +05:00, so its wall-clock time (16:00)
+    // is later than `now`'s.
+    let overdue = now - Duration::hours(1);
+    let overdue = overdue.to_offset(UtcOffset::from_hms(5, 0, 0).unwrap());
+    let overdue = repo.create_todo(user_id, "Overdue", "Due an hour ago", Some(overdue), 0).await.unwrap();
+
+    // Due in an hour, but expressed at -05:00, so its wall-clock time (08:00)
+    // is earlier than `now`'s.
+    let upcoming = now + Duration::hours(1);
+    let upcoming = upcoming.to_offset(UtcOffset::from_hms(-5, 0, 0).unwrap());
+    let upcoming = repo.create_todo(user_id, "Upcoming", "Due in an hour", Some(upcoming), 0).await.unwrap();
+
+    let ids: Vec<i64> = repo.get_overdue_todos(user_id, now).await.unwrap().iter().map(|todo| todo.id).collect();
+
+    assert!(ids.contains(&overdue));
+    assert!(!ids.contains(&upcoming));
+}
+
+#[tokio::test]
+async fn overdue_todos_follow_the_clock() {
+    // for Body::collect
+    use http_body_util::BodyExt;
+    /// for ServiceExt::oneshot
+    use tower::util::ServiceExt;
+    use axum::http::{Method, Request};
+    use time::{macros::datetime, Duration};
+
+    use crate::clock::FakeClock;
+
+    let pool = PgPoolOptions::new()
+        .max_connections(1)
+        .connect(&crate::test_db::database_url().await)
+        .await
+        .unwrap();
+
+    let clock = FakeClock::new(datetime!(2026-10-16 11:55 UTC));
+    let repo = TodoRepoPostgres { pool };
+    let (app, user_id, token) = test_todo_app_with_clock(repo.clone(), Arc::new(clock.clone())).await;
+    let id = repo.create_todo(user_id, "Noon", "Due at noon", Some(datetime!(2026-10-16 12:00 UTC)), 0).await.unwrap();
+
+    let overdue = || async {
+        let request = Request::builder()
+            .method(Method::GET)
+            .uri(TodoOverdue.to_string())
+            .header("Authorization", &token)
+            .body(Body::empty())
+            .unwrap();
+        let response = app.clone().oneshot(request).await.unwrap();
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        serde_json::from_slice::<Vec<TodoDTO>>(&body).unwrap().iter().map(|todo| todo.id).collect::<Vec<_>>()
+    };
+
+    assert_eq!(overdue().await, Vec::<i64>::new());
+
+    clock.advance(Duration::minutes(10));
+    assert_eq!(overdue().await, vec![id]);
+}
+
+#[tokio::test]
+async fn due_dates_follow_the_client_time_zone_across_dst() {
+    // for Body::collect
+    use http_body_util::BodyExt;
+    /// for ServiceExt::oneshot
+    use tower::util::ServiceExt;
+    use axum::http::{Method, Request};
+    use time::macros::datetime;
+
+    let pool = PgPoolOptions::new()
+        .max_connections(1)
+        .connect(&crate::test_db::database_url().await)
+        .await
+        .unwrap();
+
+    let repo = TodoRepoPostgres { pool };
+    let (app, user_id, token) = test_todo_app(repo.clone()).await;
+    let due = |title: &'static str, at: OffsetDateTime| {
+        let repo = repo.clone();
+        async move { repo.create_todo(user_id, title, "", Some(at), 0).await.unwrap() }
+    };
+
+    // Copenhagen leaves summer time on 2026-10-25, which lasts 25 hours: from
+    // 22:00 UTC the day before (+02:00) to 23:00 UTC (+01:00).
+    let before_autumn = due("Before", datetime!(2026-10-24 21:30 UTC)).await;
+    let autumn_start = due("Start", datetime!(2026-10-24 22:30 UTC)).await;
+    let autumn_extra_hour = due("Extra hour", datetime!(2026-10-25 22:30 UTC)).await;
+    let after_autumn = due("After", datetime!(2026-10-25 23:30 UTC)).await;
+
+    // And it enters summer time on 2026-03-29, which lasts 23 hours: from
+    // 23:00 UTC the day before (+01:00) to 22:00 UTC (+02:00).
+    let spring_end = due("End", datetime!(2026-03-29 21:30 UTC)).await;
+    let after_spring = due("After", datetime!(2026-03-29 22:30 UTC)).await;
+
+    let due_on = |query: &'static str| {
+        let app = app.clone();
+        let token = token.clone();
+        async move {
+            let request = Request::builder()
+                .method(Method::GET)
+                .uri(format!("{}?{}", TodoDue, query))
+                .header("Authorization", &token)
+                .body(Body::empty())
+                .unwrap();
+            app.oneshot(request).await.unwrap()
+        }
+    };
+    let ids = |body: Bytes| {
+        let todos: Vec<TodoDTO> = serde_json::from_slice(&body).unwrap();
+        todos.into_iter().map(|todo| todo.id).collect::<Vec<_>>()
+    };
+
+    let response = due_on("date=2026-10-25&tz=Europe/Copenhagen").await;
+    let autumn = ids(response.into_body().collect().await.unwrap().to_bytes());
+    assert_eq!(autumn, vec![autumn_start, autumn_extra_hour]);
+    assert!(!autumn.contains(&before_autumn) && !autumn.contains(&after_autumn));
+
+    let response = due_on("date=2026-03-29&tz=Europe/Copenhagen").await;
+    assert_eq!(ids(response.into_body().collect().await.unwrap().to_bytes()), vec![spring_end]);
+
+    let response = due_on("date=2026-03-30&tz=Europe/Copenhagen").await;
+    assert_eq!(ids(response.into_body().collect().await.unwrap().to_bytes()), vec![after_spring]);
+
+    // The same instants fall on other days in UTC.
+    let response = due_on("date=2026-10-24&tz=UTC").await;
+    assert_eq!(ids(response.into_body().collect().await.unwrap().to_bytes()), vec![before_autumn, autumn_start]);
+
+    // Timestamps are RFC 3339, with their offset.
+    let response = due_on("date=2026-03-29&tz=Europe/Copenhagen").await;
+    let body: serde_json::Value = serde_json::from_slice(&response.into_body().collect().await.unwrap().to_bytes()).unwrap();
+    let created_at = body[0]["created_at"].as_str().unwrap();
+    assert!(OffsetDateTime::parse(created_at, &time::format_description::well_known::Rfc3339).is_ok());
+    assert_eq!(body[0]["due_at"], "2026-03-29T21:30:00Z");
+
+    assert_eq!(due_on("date=2026-10-25&tz=Europe/Atlantis").await.status(), StatusCode::BAD_REQUEST);
+    assert_eq!(due_on("date=25.10.2026&tz=Europe/Copenhagen").await.status(), StatusCode::BAD_REQUEST);
+}
+
+#[tokio::test]
+async fn stats_aggregate_visible_todos() {
+    let pool = PgPoolOptions::new()
+        .max_connections(1)
+        .connect(&crate::test_db::database_url().await)
+        .await
+        .unwrap();
+
+    let repo = TodoRepoPostgres { pool: pool.clone() };
+    let user_id = crate::users::create_test_user(&pool, false).await;
+
+    let stats = repo.get_stats(user_id).await.unwrap();
+    assert!(stats.by_status.is_empty());
+    assert_eq!(stats.created_per_day.len(), 30);
+    assert_eq!(stats.average_completion_seconds, None);
+
+    repo.create_todo(user_id, "Open", "", None, 0).await.unwrap();
+    let done = repo.create_todo(user_id, "Done", "", None, 0).await.unwrap();
+    repo.update_todo(user_id, done, None, None, Some(TodoStatus::Done), None, None).await.unwrap();
+    let cancelled = repo.create_todo(user_id, "Cancelled", "", None, 0).await.unwrap();
+    repo.update_todo(user_id, cancelled, None, None, Some(TodoStatus::Cancelled), None, None).await.unwrap();
+
+    let stats = repo.get_stats(user_id).await.unwrap();
+    let counts: Vec<(TodoStatus, i64)> = stats.by_status.iter().map(|status| (status.status, status.count)).collect();
+    assert_eq!(counts, vec![(TodoStatus::Open, 1), (TodoStatus::Done, 1), (TodoStatus::Cancelled, 1)]);
+    assert_eq!(stats.created_per_day.last().unwrap().count, 3);
+    assert!(stats.average_completion_seconds.unwrap() >= 0.0);
+    assert!(repo.get_todo(user_id, done).await.unwrap().unwrap().completed_at.is_some());
+    assert!(repo.get_todo(user_id, cancelled).await.unwrap().unwrap().completed_at.is_none());
+}
+
+#[tokio::test]
+async fn todos_sort_by_priority() {
+    // for Body::collect
+    use http_body_util::BodyExt;
+    /// for ServiceExt::oneshot
+    use tower::util::ServiceExt;
+    use axum::{body::Body, http::{Method, Request}};
+
+    let pool = PgPoolOptions::new()
+        .max_connections(1)
+        .connect(&crate::test_db::database_url().await)
+        .await
+        .unwrap();
+
+    let repo = TodoRepoPostgres { pool };
+    let (app, user_id, token) = test_todo_app(repo.clone()).await;
+    repo.create_todo(user_id, "Low", "Priority 1", None, 1).await.unwrap();
+    repo.create_todo(user_id, "High", "Priority 9", None, 9).await.unwrap();
+
+    let response = app
+        .oneshot(
+            Request::builder()
+                .method(Method::GET)
+                .uri(format!("{}?sort=priority", TodoCollection))
+                .header("Authorization", token)
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+    let body = response.into_body().collect().await.unwrap().to_bytes();
+    let todos: Vec<TodoDTO> = serde_json::from_slice(&body).unwrap();
+    let priorities: Vec<i32> = todos.iter().map(|todo| todo.priority).collect();
+
+    assert_eq!(priorities, vec![9, 1]);
+}
+
+#[tokio::test]
+async fn todos_page_with_a_cursor() {
+    // for Body::collect
+    use http_body_util::BodyExt;
+    /// for ServiceExt::oneshot
+    use tower::util::ServiceExt;
+    use axum::{body::Body, http::{Method, Request}};
+
+    let pool = PgPoolOptions::new()
+        .max_connections(1)
+        .connect(&crate::test_db::database_url().await)
+        .await
+        .unwrap();
+
+    let repo = TodoRepoPostgres { pool };
+    let (app, user_id, token) = test_todo_app(repo.clone()).await;
+    for title in ["First", "Second", "Third"] {
+        repo.create_todo(user_id, title, "Paged", None, 0).await.unwrap();
+    }
+
+    let get = |uri: String| {
+        Request::builder()
+            .method(Method::GET)
+            .uri(uri)
+            .header("Authorization", token.clone())
+            .body(Body::empty())
+            .unwrap()
+    };
+
+    let mut titles = vec![];
+    let mut uri = format!("{}?limit=2", TodoCollection);
+    loop {
+        let response = app.clone().oneshot(get(uri)).await.unwrap();
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let page: TodoPage = serde_json::from_slice(&body).unwrap();
+        titles.extend(page.todos.into_iter().map(|todo| todo.title));
+        match page.next_cursor {
+            Some(cursor) => uri = format!("{}?limit=2&after={}", TodoCollection, cursor),
+            None => break,
+        }
+    }
+    assert_eq!(titles, vec!["First", "Second", "Third"]);
+
+    let response = app.oneshot(get(format!("{}?after=nonsense", TodoCollection))).await.unwrap();
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+}
+
+#[tokio::test]
+async fn import_streams_ndjson_in_chunks() {
+    /// for ServiceExt::oneshot
+    use tower::util::ServiceExt;
+    use axum::http::{Method, Request};
+
+    let pool = PgPoolOptions::new()
+        .max_connections(1)
+        .connect(&crate::test_db::database_url().await)
+        .await
+        .unwrap();
+
+    let repo = TodoRepoPostgres { pool };
+    let (app, user_id, token) = test_todo_app(repo.clone()).await;
+
+    let mut ndjson = String::new();
+    for i in 0..250 {
+        ndjson.push_str(&format!("{{\"title\": \"Imported {}\", \"description\": \"\"}}\n", i));
+        if i == 10 {
+            ndjson.push_str("not json\n\n");
+        }
+    }
+
+    // Send the body in small pieces, so that lines are split across frames.
+    let pieces: Vec<Result<String, Infallible>> = ndjson
+        .as_bytes()
+        .chunks(7)
+        .map(|piece| Ok(String::from_utf8(piece.to_vec()).unwrap()))
+        .collect();
+
+    let response = app
+        .oneshot(
+            Request::builder()
+                .method(Method::POST)
+                .uri(TodoImport.to_string())
+                .header("Authorization", token)
+                .body(Body::from_stream(futures::stream::iter(pieces)))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+    let body = response.into_body().collect().await.unwrap().to_bytes();
+    let events: Vec<ImportEvent> = std::str::from_utf8(&body)
+        .unwrap()
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+
+    assert!(matches!(events[0], ImportEvent::Error { line: 12, .. }));
+    assert_eq!(events[1], ImportEvent::Progress { imported: 100, lines: 102 });
+    assert_eq!(events[3], ImportEvent::Progress { imported: 250, lines: 252 });
+    assert_eq!(events[4], ImportEvent::Done { imported: 250, lines: 252, errors: 1 });
+    assert_eq!(repo.get_todos(user_id, TodoSort::Id).await.unwrap().len(), 250);
+}
+
+#[tokio::test]
+async fn concurrent_reads_are_coalesced() {
+    use mock_todo_repo::MockTodoRepo;
+
+    let hot = |(user_id, id)| {
+        Ok(Some(Todo {
+            id,
+            title: "Hot".to_string(),
+            description: "Everyone wants to read me".to_string(),
+            status: TodoStatus::Open,
+            created_at: OffsetDateTime::UNIX_EPOCH,
+            due_at: None,
+            priority: 0,
+            parent_id: None,
+            owner_id: Some(user_id),
+            list_id: None,
+            completed_at: None,
+            metadata: serde_json::json!({}),
+        }))
+    };
+    // Slow enough that concurrent reads overlap.
+    let delay = Duration::from_millis(50);
+    let mock = MockTodoRepo::default();
+    let repo = CoalescingTodoRepo::new(mock.clone());
+
+    mock.expect_get_todo().with((1, 42)).times(1).delayed(delay).returning(hot);
+    let todos = futures::future::join_all((0..100).map(|_| repo.get_todo(1, 42))).await;
+    assert!(todos.iter().all(|todo| todo.as_ref().unwrap().as_ref().unwrap().id == 42));
+    mock.checkpoint();
+
+    // The flight is over: the next read goes to the database again.
+    mock.expect_get_todo().with((1, 42)).times(1).returning(hot);
+    repo.get_todo(1, 42).await.unwrap();
+    mock.checkpoint();
+
+    // Reads by another user never share a result.
+    mock.expect_get_todo().with((1, 42)).times(1).delayed(delay).returning(hot);
+    mock.expect_get_todo().with((2, 42)).times(1).delayed(delay).returning(hot);
+    let (first, second) = futures::future::join(repo.get_todo(1, 42), repo.get_todo(2, 42)).await;
+    first.unwrap();
+    second.unwrap();
+}
+
+#[tokio::test]
+async fn create_many_matches_per_row_inserts() {
+    use std::time::Instant;
+    use time::macros::datetime;
+
+    let pool = PgPoolOptions::new()
+        .max_connections(1)
+        .connect(&crate::test_db::database_url().await)
+        .await
+        .unwrap();
+
+    let repo = TodoRepoPostgres { pool: pool.clone() };
+    let user_id = crate::users::create_test_user(&pool, false).await;
+
+    let todos: Vec<CreateTodo> = (0..500)
+        .map(|i| CreateTodo {
+            title: format!("Batch {}", i),
+            description: "Created with UNNEST".to_string(),
+            due_at: (i % 2 == 0).then_some(datetime!(2026-10-16 12:00 UTC)),
+            priority: i,
+        })
+        .collect();
+
+    let start = Instant::now();
+    for todo in &todos {
+        repo.create_todo(user_id, &todo.title, &todo.description, todo.due_at, todo.priority).await.unwrap();
+    }
+    let per_row = start.elapsed();
+
+    let start = Instant::now();
+    let ids = repo.create_many(user_id, &todos).await.unwrap();
+    let batched = start.elapsed();
+
+    println!("500 per-row inserts: {:?}, create_many: {:?}", per_row, batched);
+
+    assert_eq!(ids.len(), todos.len());
+    for (id, todo) in ids.iter().zip(&todos) {
+        let created = repo.get_todo(user_id, *id).await.unwrap().unwrap();
+        assert_eq!(created.title, todo.title);
+        assert_eq!(created.due_at, todo.due_at);
+        assert_eq!(created.priority, todo.priority);
+        assert_eq!(created.owner_id, Some(user_id));
+    }
+}
+
+#[tokio::test]
+async fn todo_lists_are_cached_until_a_write() {
+    let pool = PgPoolOptions::new()
+        .max_connections(1)
+        .connect(&crate::test_db::database_url().await)
+        .await
+        .unwrap();
+
+    let inner = TodoRepoPostgres { pool: pool.clone() };
+    let repo = CachingTodoRepo::new(inner.clone());
+    let user_id = crate::users::create_test_user(&pool, false).await;
+
+    let count = |json: Bytes| serde_json::from_slice::<Vec<TodoDTO>>(&json).unwrap().len();
+
+    repo.create_todo(user_id, "Cached", "", None, 0).await.unwrap();
+    assert_eq!(count(repo.get_todos_json(user_id, TodoSort::Id).await.unwrap()), 1);
+
+    // Behind the cache's back: the cached list is served as it was.
+    inner.create_todo(user_id, "Unseen", "", None, 0).await.unwrap();
+    assert_eq!(count(repo.get_todos_json(user_id, TodoSort::Id).await.unwrap()), 1);
+
+    // Through the cache: the list is serialized again.
+    repo.create_todo(user_id, "Seen", "", None, 0).await.unwrap();
+    assert_eq!(count(repo.get_todos_json(user_id, TodoSort::Id).await.unwrap()), 3);
+}
+
+#[tokio::test]
+async fn expired_todo_lists_are_served_while_the_database_is_down() {
+    // Nothing listens on port 1, so every query fails.
+    let pool = PgPoolOptions::new().connect_lazy("postgres://postgres@127.0.0.1:1/postgres").unwrap();
+    let health = DbHealth::default();
+    let repo = CachingTodoRepo::new(TodoRepoPostgres { pool }).with_health(health.clone());
+
+    let expired = Instant::now() - TodoListCache::MAX_AGE * 2;
+    let json = Bytes::from_static(b"[]");
+    repo.cache.entries.write().unwrap().insert((1, TodoSort::Id), CachedList { cached_at: expired, json: json.clone() });
+
+    health.set_up(false);
+    assert_eq!(repo.get_todos_json(1, TodoSort::Id).await.unwrap(), json);
+}
+
+#[tokio::test]
+async fn slow_repo_calls_are_logged_without_user_content() {
+    use std::{io, sync::Mutex};
+
+    use tracing_subscriber::util::SubscriberInitExt;
+
+    ///
+    /// Where the test subscriber writes, so that the test can read it back.
+    ///
+    #[derive(Clone, Default)]
+    struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+    impl io::Write for SharedBuffer {
+        fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(bytes)
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    let pool = PgPoolOptions::new()
+        .max_connections(1)
+        .connect(&crate::test_db::database_url().await)
+        .await
+        .unwrap();
+    let user_id = crate::users::create_test_user(&pool, false).await;
+
+    // Installed for this test only, rather than globally.
+    let buffer = SharedBuffer::default();
+    let writer = buffer.clone();
+    let _subscriber = tracing_subscriber::fmt().with_writer(move || writer.clone()).with_ansi(false).set_default();
+
+    // Every call is slower than no time at all.
+    let repo = InstrumentedTodoRepo::new(TodoRepoPostgres { pool }, Duration::ZERO);
+    let id = repo.create_todo(user_id, "Buy a birthday present for Alice", "", None, 0).await.unwrap();
+    repo.get_todo(user_id, id).await.unwrap().unwrap();
+
+    let logs = String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap();
+    assert!(logs.contains("create_todo"));
+    assert!(logs.contains(&format!("user_id={} id={}", user_id, id)));
+    assert!(logs.contains("title=<redacted>"));
+    assert!(!logs.contains("Alice"));
+
+    // Calls faster than the threshold are not logged.
+    buffer.0.lock().unwrap().clear();
+    let repo = InstrumentedTodoRepo::new(repo.inner, Duration::from_secs(60));
+    repo.get_todo(user_id, id).await.unwrap().unwrap();
+    assert!(buffer.0.lock().unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn repos_are_compared_before_cutting_over() {
+    use std::{io, sync::Mutex};
+
+    use mock_todo_repo::MockTodoRepo;
+    use tracing_subscriber::util::SubscriberInitExt;
+
+    #[derive(Clone, Default)]
+    struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+    impl io::Write for SharedBuffer {
+        fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(bytes)
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    // Answers `get_todo` with the same todo every time, after `delay`.
+    let repo = |title: &'static str, delay| {
+        let repo = MockTodoRepo::default();
+        repo.expect_get_todo().delayed(delay).returning(move |(user_id, id)| {
+            Ok(Some(Todo {
+                id,
+                title: title.to_string(),
+                description: String::new(),
+                status: TodoStatus::Open,
+                created_at: OffsetDateTime::UNIX_EPOCH,
+                due_at: None,
+                priority: 0,
+                parent_id: None,
+                owner_id: Some(user_id),
+                list_id: None,
+                completed_at: None,
+                metadata: serde_json::json!({}),
+            }))
+        });
+        repo
+    };
+
+    let buffer = SharedBuffer::default();
+    let writer = buffer.clone();
+    let _subscriber = tracing_subscriber::fmt().with_writer(move || writer.clone()).with_ansi(false).set_default();
+
+    let primary = repo("Buy milk", Duration::ZERO);
+
+    let agreeing = ComparingTodoRepo::new(primary.clone(), repo("Buy milk", Duration::ZERO), Duration::from_secs(1));
+    assert_eq!(agreeing.get_todo(1, 42).await.unwrap().unwrap().title, "Buy milk");
+    assert!(buffer.0.lock().unwrap().is_empty());
+
+    // The primary's answer wins, and only the paths that differ are logged.
+    let diverging =
+        ComparingTodoRepo::new(primary.clone(), repo("Buy oat milk", Duration::ZERO), Duration::from_secs(1));
+    assert_eq!(diverging.get_todo(1, 42).await.unwrap().unwrap().title, "Buy milk");
+    let logs = String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap();
+    assert!(logs.contains("Todo repos disagree"));
+    assert!(logs.contains("$.title"));
+    assert!(!logs.contains("oat"));
+
+    // A slow candidate does not hold the primary's answer back for long.
+    let slow = ComparingTodoRepo::new(primary, repo("Buy milk", Duration::from_secs(60)), Duration::from_millis(50));
+    let todo = tokio::time::timeout(Duration::from_secs(1), slow.get_todo(1, 42)).await.unwrap();
+    assert_eq!(todo.unwrap().unwrap().title, "Buy milk");
+}
+
+///
+/// The todo routes over a `MockTodoRepo`, and the `Authorization` header of
+/// user 7, for tests that need no database.
+///
+#[cfg(test)]
+async fn mock_todo_app(repo: mock_todo_repo::MockTodoRepo) -> (Router, String) {
+    use crate::auth::RefreshTokenRepoInMemory;
+    use mock_todo_repo::MockTodoRepo;
+
+    #[derive(Clone, FromRef)]
+    struct TestState {
+        todos: TodoState<MockTodoRepo>,
+        keys: JwtKeys,
+        signer: UrlSigner,
+    }
+
+    let clock: SharedClock = Arc::new(SystemClock);
+    let keys = JwtKeys::from_secret(b"secret").with_clock(clock.clone());
+    let tokens = AuthState { repo: RefreshTokenRepoInMemory::default(), keys: keys.clone() }.issue_tokens(7).await;
+
+    let signer = UrlSigner::from_secret(b"secret", clock.clone());
+    let app = todo_routes::<_, MockTodoRepo>(8)
+        .into_router()
+        .with_state(TestState { todos: TodoState { repo, clock, events: TodoEvents::default() }, keys, signer });
+
+    (app, format!("Bearer {}", tokens.access_token))
+}
+
+#[tokio::test]
+async fn handlers_make_exactly_the_expected_repo_calls() {
+    // for Body::collect
+    use http_body_util::BodyExt;
+    /// for ServiceExt::oneshot
+    use tower::util::ServiceExt;
+    use axum::{body::Body, http::{Method, Request}};
+    use mock_todo_repo::MockTodoRepo;
+
+    let repo = MockTodoRepo::default();
+    let (app, token) = mock_todo_app(repo.clone()).await;
+    let send = |method: Method, uri: String| {
+        let request = Request::builder().method(method).uri(uri).header("Authorization", &token).body(Body::empty()).unwrap();
+        let app = app.clone();
+        async move { app.oneshot(request).await.unwrap().into_body().collect().await.unwrap().to_bytes() }
+    };
+
+    repo.expect_get_todo().with((7, 5)).times(1).returning(|(user_id, id)| {
+        Ok(Some(Todo {
+            id,
+            title: "Mocked".to_string(),
+            description: String::new(),
+            status: TodoStatus::Open,
+            created_at: OffsetDateTime::UNIX_EPOCH,
+            due_at: None,
+            priority: 0,
+            parent_id: None,
+            owner_id: Some(user_id),
+            list_id: None,
+            completed_at: None,
+            metadata: serde_json::json!({}),
+        }))
+    });
+    let todo: TodoDTO = serde_json::from_slice(&send(Method::GET, TodoById { id: 5 }.to_string()).await).unwrap();
+    assert_eq!((todo.id, todo.title.as_str()), (5, "Mocked"));
+    repo.checkpoint();
+
+    // Deleting a todo that is not there asks the repo once, and nothing else.
+    repo.expect_delete_todo().withf(|&(user_id, _)| user_id == 7).times(1).return_const(Ok(None));
+    assert_eq!(&send(Method::DELETE, TodoById { id: 6 }.to_string()).await[..], b"null");
+}
+
+#[tokio::test]
+#[should_panic(expected = "Unexpected call: get_todo(7, 5)")]
+async fn mocks_fail_on_unexpected_calls() {
+    /// for ServiceExt::oneshot
+    use tower::util::ServiceExt;
+    use axum::{body::Body, http::Request};
+
+    let repo = mock_todo_repo::MockTodoRepo::default();
+    repo.expect_get_todo().with((7, 6)).return_const(Ok(None));
+    let (app, token) = mock_todo_app(repo).await;
+
+    let request = Request::get(TodoById { id: 5 }.to_string()).header("Authorization", &token).body(Body::empty()).unwrap();
+    app.oneshot(request).await.unwrap();
+}
+
+#[test]
+#[should_panic(expected = "get_todo(7, 5) was expected 2 times, and called 1")]
+fn mocks_fail_on_unmet_expectations() {
+    let repo = mock_todo_repo::MockTodoRepo::default();
+    repo.expect_get_todo().with((7, 5)).times(2).return_const(Ok(None));
+
+    let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+    assert!(runtime.block_on(repo.get_todo(7, 5)).unwrap().is_none());
+    repo.checkpoint();
+}
+
+#[tokio::test]
+async fn repo_errors_are_answered_with_service_unavailable() {
+    /// for ServiceExt::oneshot
+    use tower::util::ServiceExt;
+    use axum::{body::Body, http::{header, Method, Request}};
+
+    let repo = mock_todo_repo::MockTodoRepo::default();
+    let down = || RepoError::from(std::io::Error::other("The database is down"));
+    repo.expect_get_todo().with((7, 5)).times(1).returning(move |_| Err(down()));
+    repo.expect_delete_todo().with((7, 5)).times(1).returning(move |_| Err(down()));
+    let (app, token) = mock_todo_app(repo.clone()).await;
+
+    for method in [Method::GET, Method::DELETE] {
+        let request = Request::builder()
+            .method(method)
+            .uri(TodoById { id: 5 }.to_string())
+            .header("Authorization", &token)
+            .body(Body::empty())
+            .unwrap();
+        let response = app.clone().oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert!(response.headers().contains_key(header::RETRY_AFTER));
+    }
+    repo.checkpoint();
+}
+
+#[tokio::test]
+async fn todos_are_listed_with_their_comments() {
+    // for Body::collect
+    use http_body_util::BodyExt;
+    /// for ServiceExt::oneshot
+    use tower::util::ServiceExt;
+    use axum::{body::Body, http::Request};
+
+    let pool = PgPoolOptions::new()
+        .max_connections(1)
+        .connect(&crate::test_db::database_url().await)
+        .await
+        .unwrap();
+
+    let repo = TodoRepoPostgres { pool };
+    let (app, user_id, token) = test_todo_app(repo.clone()).await;
+    let quiet = repo.create_todo(user_id, "Quiet", "", None, 0).await.unwrap();
+    let busy = repo.create_todo(user_id, "Busy", "", None, 0).await.unwrap();
+    repo.create_comment(user_id, busy, "First").await.unwrap().unwrap();
+    repo.create_comment(user_id, busy, "Second").await.unwrap().unwrap();
+
+    let request = Request::get(TodoCollectionWithComments.to_string())
+        .header("Authorization", &token)
+        .body(Body::empty())
+        .unwrap();
+    let body = app.oneshot(request).await.unwrap().into_body().collect().await.unwrap().to_bytes();
+    let todos: Vec<TodoWithCommentsDTO> = serde_json::from_slice(&body).unwrap();
+
+    let comments = |id: i64| -> Vec<String> {
+        let todo = todos.iter().find(|todo| todo.todo.id == id).unwrap();
+        todo.comments.iter().map(|comment| comment.body.clone()).collect()
+    };
+    assert_eq!(comments(quiet), Vec::<String>::new());
+    assert_eq!(comments(busy), vec!["First", "Second"]);
+}
+
+#[tokio::test]
+async fn comments_are_nested_under_todos() {
+    // for Body::collect
+    use http_body_util::BodyExt;
+    /// for ServiceExt::oneshot
+    use tower::util::ServiceExt;
+    use axum::{body::Body, http::{Method, Request}};
+
+    let pool = PgPoolOptions::new()
+        .max_connections(1)
+        .connect(&crate::test_db::database_url().await)
+        .await
+        .unwrap();
+
+    let repo = TodoRepoPostgres { pool: pool.clone() };
+    let (app, user_id, token) = test_todo_app(repo.clone()).await;
+    let id = repo.create_todo(user_id, "Comments", "Discuss me", None, 0).await.unwrap();
+
+    let response = app
+        .clone()
+        .oneshot(
+            Request::builder()
+                .method(Method::POST)
+                .uri(TodoComments { id }.to_string())
+                .header("Authorization", &token)
+                .header("Content-Type", "application/json")
+                .body(Body::from(r#"{ "body": "First!" }"#))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+    let body = response.into_body().collect().await.unwrap().to_bytes();
+    let comment_id: i64 = serde_json::from_slice(&body).unwrap();
+
+    let response = app
+        .clone()
+        .oneshot(
+            Request::builder()
+                .method(Method::GET)
+                .uri(TodoComments { id }.to_string())
+                .header("Authorization", &token)
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+    let body = response.into_body().collect().await.unwrap().to_bytes();
+    let TodoWithCommentsDTO { todo, comments } = serde_json::from_slice(&body).unwrap();
+
+    assert_eq!(todo.id, id);
+    assert_eq!(comments.len(), 1);
+    assert_eq!(comments[0].href, TodoComment { id, comment_id }.to_string());
+
+    let response = app
+        .oneshot(
+            Request::builder()
+                .method(Method::DELETE)
+                .uri(TodoComment { id: id + 1, comment_id }.to_string())
+                .header("Authorization", &token)
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+    repo.delete_todo(user_id, id).await.unwrap();
+
+    let remaining = sqlx::query!("SELECT COUNT(*) AS count FROM comments where id = $1", comment_id)
+        .fetch_one(&pool)
+        .await
+        .unwrap()
+        .count;
+
+    assert_eq!(remaining, Some(0));
+}
+
+#[tokio::test]
+async fn subtasks_form_a_tree() {
+    let pool = PgPoolOptions::new()
+        .max_connections(1)
+        .connect(&crate::test_db::database_url().await)
+        .await
+        .unwrap();
+
+    let repo = TodoRepoPostgres { pool: pool.clone() };
+    let user_id = crate::users::create_test_user(&pool, false).await;
+    let root = repo.create_todo(user_id, "Move house", "Root", None, 0).await.unwrap();
+    let pack = repo.create_todo(user_id, "Pack", "Child", None, 0).await.unwrap();
+    let books = repo.create_todo(user_id, "Pack books", "Grandchild", None, 0).await.unwrap();
+
+    repo.set_parent(user_id, pack, Some(root)).await.unwrap().unwrap();
+    repo.set_parent(user_id, books, Some(pack)).await.unwrap().unwrap();
+
+    assert_eq!(repo.set_parent(user_id, root, Some(books)).await.unwrap(), Err(SubtaskError::Cycle));
+    assert_eq!(repo.set_parent(user_id, root, Some(root)).await.unwrap(), Err(SubtaskError::Cycle));
+
+    let todos = repo.get_todo_tree(user_id, root).await.unwrap();
+    let tree = TodoTreeDTO::build(&todos[0], &todos);
+
+    assert_eq!(tree.todo.id, root);
+    assert_eq!(tree.subtasks[0].todo.id, pack);
+    assert_eq!(tree.subtasks[0].subtasks[0].todo.id, books);
+
+    // A cancelled sibling does not hold its parent back.
+    let tape = repo.create_todo(user_id, "Buy tape", "Cancelled grandchild", None, 0).await.unwrap();
+    repo.set_parent(user_id, tape, Some(pack)).await.unwrap().unwrap();
+    repo.update_todo(user_id, tape, None, None, Some(TodoStatus::Cancelled), None, None).await.unwrap();
+
+    repo.update_todo(user_id, books, None, None, Some(TodoStatus::InProgress), None, None).await.unwrap();
+    assert_eq!(repo.get_todo(user_id, pack).await.unwrap().unwrap().status, TodoStatus::Open);
+
+    repo.update_todo(user_id, books, None, None, Some(TodoStatus::Done), None, None).await.unwrap();
+
+    assert_eq!(repo.get_todo(user_id, pack).await.unwrap().unwrap().status, TodoStatus::Done);
+    assert_eq!(repo.get_todo(user_id, root).await.unwrap().unwrap().status, TodoStatus::Done);
+}
+
+#[tokio::test]
+async fn todos_are_scoped_to_their_owner() {
+    // for Body::collect
+    use http_body_util::BodyExt;
+    /// for ServiceExt::oneshot
+    use tower::util::ServiceExt;
+    use axum::{body::Body, http::{Method, Request}};
+
+    use crate::users::create_test_user;
+
+    let pool = PgPoolOptions::new()
+        .max_connections(1)
+        .connect(&crate::test_db::database_url().await)
+        .await
+        .unwrap();
+
+    let repo = TodoRepoPostgres { pool: pool.clone() };
+    let (app, _, token) = test_todo_app(repo.clone()).await;
+
+    let owner = create_test_user(&pool, false).await;
+    let admin = create_test_user(&pool, true).await;
+    let id = repo.create_todo(owner, "Private", "Only mine", None, 0).await.unwrap();
+
+    let response = app
+        .oneshot(
+            Request::builder()
+                .method(Method::GET)
+                .uri(TodoById { id }.to_string())
+                .header("Authorization", token)
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+    let body = response.into_body().collect().await.unwrap().to_bytes();
+    let todo: Option<TodoDTO> = serde_json::from_slice(&body).unwrap();
+    assert!(todo.is_none());
+
+    let stranger = create_test_user(&pool, false).await;
+    assert!(repo.get_todo(stranger, id).await.unwrap().is_none());
+    assert_eq!(repo.delete_todo(stranger, id).await.unwrap(), None);
+
+    assert_eq!(repo.get_todo(admin, id).await.unwrap().unwrap().owner_id, Some(owner));
+    assert_eq!(repo.delete_todo(admin, id).await.unwrap(), Some(id));
+}
+
+#[cfg(test)]
+#[derive(Clone, Debug)]
+enum TodoOp {
+    Create(String, i32),
+    Update(usize, Option<String>, Option<TodoStatus>),
+    Delete(usize),
+}
+
+#[cfg(test)]
+fn todo_status() -> impl proptest::strategy::Strategy<Value = TodoStatus> {
+    use proptest::prelude::*;
+
+    prop_oneof![
+        Just(TodoStatus::Open),
+        Just(TodoStatus::InProgress),
+        Just(TodoStatus::Done),
+        Just(TodoStatus::Cancelled),
+    ]
+}
+
+#[cfg(test)]
+fn todo_op() -> impl proptest::strategy::Strategy<Value = TodoOp> {
+    use proptest::prelude::*;
+
+    prop_oneof![
+        ("[a-z ]{1,12}", -5..5).prop_map(|(title, priority)| TodoOp::Create(title, priority)),
+        (any::<usize>(), proptest::option::of("[a-z ]{1,12}"), proptest::option::of(todo_status()))
+            .prop_map(|(pick, title, status)| TodoOp::Update(pick, title, status)),
+        any::<usize>().prop_map(TodoOp::Delete),
+    ]
+}
+
+#[cfg(test)]
+proptest::proptest! {
+    #![proptest_config(proptest::test_runner::Config::with_cases(16))]
+
+    ///
+    /// Applies random sequences of operations to the Postgres repo, and to a
+    /// model of it, and checks that the user sees exactly the todos of the
+    /// model after every step. Every case runs as a new user, so cases do not
+    /// see each other's todos.
+    ///
+    #[test]
+    fn todo_repo_matches_model(ops in proptest::collection::vec(todo_op(), 1..20)) {
+        use std::collections::BTreeMap;
+
+        let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+        runtime.block_on(async {
+            let pool = PgPoolOptions::new()
+                .max_connections(1)
+                .connect(&crate::test_db::database_url().await)
+                .await
+                .unwrap();
+            let repo = TodoRepoPostgres { pool: pool.clone() };
+            let user_id = crate::users::create_test_user(&pool, false).await;
+
+            // id -> (title, status, priority)
+            let mut model = BTreeMap::<i64, (String, TodoStatus, i32)>::new();
+            let pick = |model: &BTreeMap<i64, (String, TodoStatus, i32)>, pick: usize| {
+                model.keys().nth(pick % (model.len() + 1)).copied().unwrap_or(-1)
+            };
+
+            for op in ops {
+                match op {
+                    TodoOp::Create(title, priority) => {
+                        let id = repo.create_todo(user_id, &title, "", None, priority).await.unwrap();
+                        assert!(!model.contains_key(&id), "id {} was handed out twice", id);
+                        model.insert(id, (title, TodoStatus::Open, priority));
+                    }
+                    TodoOp::Update(pick_id, title, status) => {
+                        let id = pick(&model, pick_id);
+                        let updated = repo.update_todo(user_id, id, title.as_deref(), None, status, None, None).await.unwrap();
+                        let expected = model.get_mut(&id).map(|todo| {
+                            if let Some(title) = title {
+                                todo.0 = title;
+                            }
+                            if let Some(status) = status {
+                                todo.1 = status;
+                            }
+                            id
+                        });
+                        assert_eq!(updated, expected);
+                    }
+                    TodoOp::Delete(pick_id) => {
+                        let id = pick(&model, pick_id);
+                        let deleted = repo.delete_todo(user_id, id).await.unwrap();
+                        assert_eq!(deleted, model.remove(&id).map(|_| id));
+                    }
+                }
+
+                let todos: BTreeMap<i64, (String, TodoStatus, i32)> = repo
+                    .get_todos(user_id, TodoSort::Id)
+                    .await.unwrap()
+                    .into_iter()
+                    .map(|todo| (todo.id, (todo.title, todo.status, todo.priority)))
+                    .collect();
+                assert_eq!(todos, model);
+            }
+        });
+    }
+}
+
+///
+/// Pins down the wire format of every todo endpoint, so that a change to one
+/// of the DTOs shows up as a snapshot diff. Review changes with
+/// `cargo insta review`.
+///
+#[tokio::test]
+async fn todo_responses_match_snapshots() {
+    // for Body::collect
+    use http_body_util::BodyExt;
+    /// for ServiceExt::oneshot
+    use tower::util::ServiceExt;
+    use axum::{body::Body, http::{Method, Request}};
+    use insta::assert_json_snapshot;
+    use serde_json::{json, Value};
+
+    let pool = PgPoolOptions::new()
+        .max_connections(1)
+        .connect(&crate::test_db::database_url().await)
+        .await
+        .unwrap();
+
+    let (app, _, token) = test_todo_app(TodoRepoPostgres { pool }).await;
+    let _settings = crate::users::redacted_snapshots().bind_to_scope();
+
+    // Every snapshot records the status along with the body.
+    let send = |method: Method, uri: String, body: Option<Value>| {
+        let app = app.clone();
+        let request = Request::builder()
+            .method(method)
+            .uri(uri)
+            .header("Authorization", &token)
+            .header("Content-Type", "application/json")
+            .body(body.map_or(Body::empty(), |body| Body::from(body.to_string())))
+            .unwrap();
+        async move {
+            let response = app.oneshot(request).await.unwrap();
+            let status = response.status().as_u16();
+            let body = response.into_body().collect().await.unwrap().to_bytes();
+            let body: Value = if body.is_empty() { Value::Null } else { serde_json::from_slice(&body).unwrap() };
+            json!({ "status": status, "body": body })
+        }
+    };
+
+    let create = json!({ "title": "Snapshot", "description": "Parent", "due_at": "2020-01-01T00:00:00Z", "priority": 2 });
+    let response = send(Method::POST, TodoCollection.to_string(), Some(create)).await;
+    assert_json_snapshot!("create_todo", response, { ".body" => "[id]" });
+    let id = response["body"].as_i64().unwrap();
+
+    let child = json!({ "title": "Child", "description": "Subtask" });
+    let child = send(Method::POST, TodoCollection.to_string(), Some(child)).await["body"].as_i64().unwrap();
+    let response = send(Method::PUT, TodoParent { id: child }.to_string(), Some(json!({ "parent_id": id }))).await;
+    assert_json_snapshot!("set_parent", response);
+
+    assert_json_snapshot!("get_todo", send(Method::GET, TodoById { id }.to_string(), None).await);
+    assert_json_snapshot!("get_todos", send(Method::GET, TodoCollection.to_string(), None).await);
+    let page = format!("{}?limit=1", TodoCollection);
+    assert_json_snapshot!("get_todos_page", send(Method::GET, page, None).await);
+    assert_json_snapshot!("get_overdue_todos", send(Method::GET, TodoOverdue.to_string(), None).await);
+    assert_json_snapshot!("get_todo_tree", send(Method::GET, TodoTree { id }.to_string(), None).await);
+
+    let response = send(Method::PUT, TodoById { id: child }.to_string(), Some(json!({ "status": "done" }))).await;
+    assert_json_snapshot!("update_todo", response, { ".body" => "[id]" });
+    let response = send(Method::PATCH, TodoById { id }.to_string(), Some(json!({ "title": "Patched" }))).await;
+    assert_json_snapshot!("patch_todo", response);
+    assert_json_snapshot!("get_todo_stats", send(Method::GET, TodoStatsPath.to_string(), None).await);
+
+    let response = send(Method::POST, TodoComments { id }.to_string(), Some(json!({ "body": "Looks good" }))).await;
+    assert_json_snapshot!("create_comment", response, { ".body" => "[id]" });
+    let comment_id = response["body"].as_i64().unwrap();
+    assert_json_snapshot!("get_comments", send(Method::GET, TodoComments { id }.to_string(), None).await);
+    let response = send(Method::DELETE, TodoComment { id, comment_id }.to_string(), None).await;
+    assert_json_snapshot!("delete_comment", response);
+
+    let bulk = json!({
+        "mode": "independent",
+        "operations": [
+            { "op": "create", "title": "Bulk", "description": "Created in bulk" },
+            { "op": "delete", "id": -1 },
+        ],
+    });
+    assert_json_snapshot!("bulk_todos", send(Method::POST, TodoBulk.to_string(), Some(bulk)).await);
+
+    let import = app
+        .clone()
+        .oneshot(
+            Request::builder()
+                .method(Method::POST)
+                .uri(TodoImport.to_string())
+                .header("Authorization", &token)
+                .body(Body::from("{\"title\": \"Imported\", \"description\": \"\"}\nnot json\n"))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    // The import streams NDJSON, one event per line.
+    let body = import.into_body().collect().await.unwrap().to_bytes();
+    let events: Vec<Value> = body
+        .split(|byte| *byte == b'\n')
+        .filter(|line| !line.is_empty())
+        .map(|line| serde_json::from_slice(line).unwrap())
+        .collect();
+    assert_json_snapshot!("import_todos", events);
+
+    let response = send(Method::DELETE, TodoById { id }.to_string(), None).await;
+    assert_json_snapshot!("delete_todo", response, { ".body" => "[id]" });
 }
 
-async fn delete_todo<R: TodoRepo>(
-    Path(id): Path<i64>,
-    State(TodoState{ repo }): State<TodoState<R>>,
-) -> Json<i64> {
-    let deleted_id = repo.delete_todo(id).await;
-    Json(deleted_id)
-}
\ No newline at end of file
@@ -0,0 +1,348 @@
+#![allow(dead_code)]
+
+//!
+//! PII ENCRYPTION
+//! --------------
+//!
+//! A copy of the database, from a backup, a replica or a stolen laptop, should
+//! not be a list of everyone's email address. So the app encrypts emails
+//! itself before storing them, with AES-256-GCM, and Postgres only ever sees
+//! the ciphertext:
+//!
+//! ```text
+//! enc:v1:2:Z3c0...   (version 1, encrypted with data key 2)
+//! ```
+//!
+//! Each value has a random nonce, so the same email encrypts differently every
+//! time, and the name of its column is authenticated along with it, so a
+//! value copied into another column does not decrypt. That rules out
+//! `WHERE email = $1`: lookups go through a "blind index" instead, a keyed
+//! hash (HMAC-SHA256) of the email in `users.email_index`, which is the same
+//! for the same email, but useless without the index key.
+//!
+//! `UserRepoPostgres` does all of this, and the rest of the app only sees
+//! plaintext `User`s. Rows from before encryption, without an index, still
+//! hold their email in plaintext, and are read as they are.
+//!
+//! The keys come from a `KeyProvider`. `StaticKeys` takes them from the
+//! config: `PII_KEYS` lists the data keys by id, and the highest id is the one
+//! that encrypts, while `PII_INDEX_KEY` is the index key. A KMS would
+//! implement `KeyProvider` too, by having its master key unwrap the data keys
+//! once at startup, so that they are never stored in plaintext anywhere.
+//!
+//! To rotate the data key, add a new key with a higher id, deploy, and run
+//! `cargo run -- rotate-pii-keys`, which re-encrypts every email that is not
+//! encrypted with the current key, plaintext ones included, in batches. Once
+//! it is done, the old key can be removed. The index key cannot be rotated
+//! this way, since a lookup would miss every row not yet re-indexed.
+//!
+
+use std::{collections::BTreeMap, fmt, str::FromStr, sync::Arc};
+
+use base64::Engine as _;
+use hmac::{Hmac, Mac};
+use ring::{
+    aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, NONCE_LEN},
+    rand::{SecureRandom, SystemRandom},
+};
+use sha2::Sha256;
+use sqlx::{Pool, Postgres};
+
+const BASE64: base64::engine::GeneralPurpose = base64::engine::general_purpose::STANDARD;
+
+const PREFIX: &str = "enc:v1:";
+
+pub type Key = [u8; 32];
+
+///
+/// Where the keys come from: the config, or a KMS.
+///
+pub trait KeyProvider: Send + Sync {
+    ///
+    /// The id of the data key that encrypts new values.
+    ///
+    fn current_key_id(&self) -> u32;
+    fn data_key(&self, id: u32) -> Option<Key>;
+    fn index_key(&self) -> Key;
+}
+
+///
+/// Data keys by id, as in `1:<base64>,2:<base64>`, each of 32 bytes.
+///
+#[derive(Clone, Default, PartialEq)]
+pub struct DataKeys(BTreeMap<u32, Key>);
+
+impl FromStr for DataKeys {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let keys = s
+            .split(',')
+            .map(str::trim)
+            .filter(|key| !key.is_empty())
+            .map(|key| {
+                let (id, key) = key.split_once(':').ok_or_else(|| "Expected <id>:<base64 key>".to_string())?;
+                let id = id.parse().map_err(|_| format!("Invalid key id: {}", id))?;
+                Ok((id, decode_key(key)?))
+            })
+            .collect::<Result<BTreeMap<_, _>, String>>()?;
+        if keys.is_empty() {
+            return Err("At least one key is needed".to_string());
+        }
+        Ok(DataKeys(keys))
+    }
+}
+
+///
+/// Only the ids, which are all that is safe to show.
+///
+impl fmt::Debug for DataKeys {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("DataKeys").field(&self.0.keys().collect::<Vec<_>>()).finish()
+    }
+}
+
+#[derive(Clone, PartialEq)]
+pub struct IndexKey(Key);
+
+impl FromStr for IndexKey {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        decode_key(s.trim()).map(IndexKey)
+    }
+}
+
+impl fmt::Debug for IndexKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("IndexKey(<redacted>)")
+    }
+}
+
+fn decode_key(key: &str) -> Result<Key, String> {
+    BASE64
+        .decode(key)
+        .ok()
+        .and_then(|bytes| bytes.try_into().ok())
+        .ok_or_else(|| "A key must be 32 bytes, in base64".to_string())
+}
+
+pub struct StaticKeys {
+    pub data: DataKeys,
+    pub index: IndexKey,
+}
+
+impl KeyProvider for StaticKeys {
+    fn current_key_id(&self) -> u32 {
+        *self.data.0.keys().next_back().unwrap()
+    }
+    fn data_key(&self, id: u32) -> Option<Key> {
+        self.data.0.get(&id).copied()
+    }
+    fn index_key(&self) -> Key {
+        self.index.0
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum PiiError {
+    UnknownKey(u32),
+    Malformed,
+    /// The value was changed, or belongs to another column.
+    Forged,
+}
+
+impl fmt::Display for PiiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PiiError::UnknownKey(id) => write!(f, "encrypted with the unknown key {}", id),
+            PiiError::Malformed => f.write_str("malformed ciphertext"),
+            PiiError::Forged => f.write_str("ciphertext failed authentication"),
+        }
+    }
+}
+
+///
+/// Encrypts and decrypts the values of a column, named by `field`, such as
+/// `users.email`.
+///
+#[derive(Clone)]
+pub struct PiiCipher {
+    keys: Arc<dyn KeyProvider>,
+    random: SystemRandom,
+}
+
+impl PiiCipher {
+    pub fn new(keys: Arc<dyn KeyProvider>) -> Self {
+        PiiCipher { keys, random: SystemRandom::new() }
+    }
+
+    pub fn current_key_id(&self) -> u32 {
+        self.keys.current_key_id()
+    }
+
+    pub fn encrypt(&self, field: &str, plaintext: &str) -> String {
+        let id = self.keys.current_key_id();
+        let key = aead_key(&self.keys.data_key(id).expect("the current key is missing"));
+        let mut nonce = [0; NONCE_LEN];
+        self.random.fill(&mut nonce).unwrap();
+
+        let mut sealed = plaintext.as_bytes().to_vec();
+        key.seal_in_place_append_tag(Nonce::assume_unique_for_key(nonce), Aad::from(field), &mut sealed).unwrap();
+        format!("{}{}:{}", PREFIX, id, BASE64.encode([&nonce[..], &sealed].concat()))
+    }
+
+    ///
+    /// The plaintext of a value from `encrypt`, or the value itself if it was
+    /// never encrypted.
+    ///
+    pub fn decrypt(&self, field: &str, stored: &str) -> Result<String, PiiError> {
+        let Some((id, sealed)) = parse(stored)? else {
+            return Ok(stored.to_string());
+        };
+        let key = aead_key(&self.keys.data_key(id).ok_or(PiiError::UnknownKey(id))?);
+        let mut sealed = BASE64.decode(sealed).map_err(|_| PiiError::Malformed)?;
+        if sealed.len() < NONCE_LEN {
+            return Err(PiiError::Malformed);
+        }
+        let mut opened = sealed.split_off(NONCE_LEN);
+        let nonce = Nonce::try_assume_unique_for_key(&sealed).unwrap();
+        let plaintext = key.open_in_place(nonce, Aad::from(field), &mut opened).map_err(|_| PiiError::Forged)?;
+        String::from_utf8(plaintext.to_vec()).map_err(|_| PiiError::Malformed)
+    }
+
+    ///
+    /// The blind index of a value: equal for equal values of the same field.
+    ///
+    pub fn blind_index(&self, field: &str, plaintext: &str) -> String {
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.keys.index_key()).unwrap();
+        mac.update(field.as_bytes());
+        mac.update(&[0]);
+        mac.update(plaintext.as_bytes());
+        BASE64.encode(mac.finalize().into_bytes())
+    }
+}
+
+///
+/// The key id and sealed part of an encrypted value, or `None` for plaintext.
+///
+fn parse(stored: &str) -> Result<Option<(u32, &str)>, PiiError> {
+    let Some(rest) = stored.strip_prefix(PREFIX) else {
+        return Ok(None);
+    };
+    let (id, sealed) = rest.split_once(':').ok_or(PiiError::Malformed)?;
+    Ok(Some((id.parse().map_err(|_| PiiError::Malformed)?, sealed)))
+}
+
+fn aead_key(key: &Key) -> LessSafeKey {
+    LessSafeKey::new(UnboundKey::new(&AES_256_GCM, key).unwrap())
+}
+
+///
+/// Keys for tests, the same in every test so that they can share a database:
+/// key 1, which only encrypts in `rotation_encrypts_every_email_with_the_current_key`,
+/// and key 2, the current one.
+///
+#[cfg(test)]
+pub fn test_cipher() -> PiiCipher {
+    let data = DataKeys(BTreeMap::from([(1, [1; 32]), (2, [2; 32])]));
+    PiiCipher::new(Arc::new(StaticKeys { data, index: IndexKey([9; 32]) }))
+}
+
+///
+/// Re-encrypts, and indexes, every email that is not encrypted with the
+/// current key, `batch_size` users at a time, each batch in a transaction of
+/// its own. Returns how many were.
+///
+pub async fn rotate_emails(pool: &Pool<Postgres>, cipher: &PiiCipher, batch_size: i64) -> u64 {
+    let current = format!("{}{}:%", PREFIX, cipher.current_key_id());
+    let mut rotated = 0;
+    loop {
+        let mut tx = pool.begin().await.unwrap();
+        let users = sqlx::query!(
+            "SELECT id, email FROM users WHERE email_index IS NULL OR email NOT LIKE $1
+             ORDER BY id LIMIT $2 FOR UPDATE SKIP LOCKED",
+            current,
+            batch_size
+        )
+        .fetch_all(&mut *tx)
+        .await
+        .unwrap();
+        if users.is_empty() {
+            return rotated;
+        }
+        for user in users {
+            let email = cipher.decrypt("users.email", &user.email).unwrap();
+            sqlx::query!(
+                "UPDATE users SET email = $2, email_index = $3 WHERE id = $1",
+                user.id,
+                cipher.encrypt("users.email", &email),
+                cipher.blind_index("users.email", &email)
+            )
+            .execute(&mut *tx)
+            .await
+            .unwrap();
+            rotated += 1;
+        }
+        tx.commit().await.unwrap();
+    }
+}
+
+#[test]
+fn emails_are_encrypted_and_indexed() {
+    let cipher = test_cipher();
+    let sealed = cipher.encrypt("users.email", "ada@example.com");
+    assert!(sealed.starts_with("enc:v1:2:"));
+    assert_ne!(sealed, cipher.encrypt("users.email", "ada@example.com"));
+    assert_eq!(cipher.decrypt("users.email", &sealed).unwrap(), "ada@example.com");
+    assert_eq!(cipher.decrypt("users.email", "legacy@example.com").unwrap(), "legacy@example.com");
+
+    assert_eq!(cipher.decrypt("users.name", &sealed), Err(PiiError::Forged));
+    let tampered = sealed.replace("enc:v1:2:", "enc:v1:2:A");
+    assert!(cipher.decrypt("users.email", &tampered).is_err());
+    assert_eq!(cipher.decrypt("users.email", &sealed.replace(":2:", ":7:")), Err(PiiError::UnknownKey(7)));
+
+    let index = cipher.blind_index("users.email", "ada@example.com");
+    assert_eq!(index, cipher.blind_index("users.email", "ada@example.com"));
+    assert_ne!(index, cipher.blind_index("users.email", "bob@example.com"));
+
+    let keys: DataKeys = "1:AQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQE=, 2:AgICAgICAgICAgICAgICAgICAgICAgICAgICAgICAgI="
+        .parse()
+        .unwrap();
+    assert_eq!(format!("{:?}", keys), "DataKeys([1, 2])");
+    assert!("1:c2hvcnQ=".parse::<DataKeys>().is_err());
+}
+
+#[tokio::test]
+async fn rotation_encrypts_every_email_with_the_current_key() {
+    use sqlx::postgres::PgPoolOptions;
+
+    use crate::users::{UserRepo, UserRepoPostgres};
+
+    let pool = PgPoolOptions::new()
+        .max_connections(1)
+        .connect(&crate::test_db::database_url().await)
+        .await
+        .unwrap();
+    let old = DataKeys(BTreeMap::from([(1, [1; 32])]));
+    let old = PiiCipher::new(Arc::new(StaticKeys { data: old, index: IndexKey([9; 32]) }));
+    let users = UserRepoPostgres::new(pool.clone(), old);
+    let email = format!("{}@example.test", rand::random::<u64>());
+    let id = users.create_user("Ada", &email, "").await.unwrap();
+    let legacy = crate::users::create_test_user(&pool, false).await;
+    let stored = |id: i64| {
+        let pool = pool.clone();
+        async move { sqlx::query!("SELECT email FROM users WHERE id = $1", id).fetch_one(&pool).await.unwrap().email }
+    };
+
+    assert!(stored(id).await.starts_with("enc:v1:1:"));
+
+    // With a new key, 2, which is current.
+    let new = test_cipher();
+    assert!(rotate_emails(&pool, &new, 2).await >= 2);
+
+    assert!(stored(id).await.starts_with("enc:v1:2:"));
+    assert!(stored(legacy).await.starts_with("enc:v1:2:"));
+    let users = UserRepoPostgres::new(pool.clone(), new);
+    assert_eq!(users.get_user_by_email(&email).await.unwrap().id, id);
+}
@@ -0,0 +1,98 @@
+#![allow(dead_code)]
+
+//!
+//! PROBLEM DETAILS
+//! ---------------
+//!
+//! Every API invents its own error bodies, and every client has to learn
+//! them. RFC 9457 ("Problem Details for HTTP APIs") is a shared format for
+//! them, served as `application/problem+json`:
+//!
+//! ```json
+//! { "type": "about:blank", "title": "Not Found", "status": 404, "detail": "No route for GET /todos" }
+//! ```
+//!
+//! `title` is the same for every problem of a kind, and `detail` explains
+//! this occurrence. A problem may carry more members, such as the `hints` of
+//! the router's fallbacks.
+//!
+
+use axum::{
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+};
+use serde_json::{Map, Value};
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Problem {
+    pub status: StatusCode,
+    pub title: String,
+    pub detail: Option<String>,
+    pub extensions: Map<String, Value>,
+}
+
+impl Problem {
+    ///
+    /// A problem titled after its status code, as RFC 9457 recommends for
+    /// problems of type `about:blank`.
+    ///
+    pub fn new(status: StatusCode) -> Self {
+        Problem {
+            status,
+            title: status.canonical_reason().unwrap_or("Unknown").to_string(),
+            detail: None,
+            extensions: Map::new(),
+        }
+    }
+
+    pub fn detail(mut self, detail: impl Into<String>) -> Self {
+        self.detail = Some(detail.into());
+        self
+    }
+
+    pub fn with(mut self, name: &str, value: impl serde::Serialize) -> Self {
+        self.extensions.insert(name.to_string(), serde_json::to_value(value).unwrap());
+        self
+    }
+
+    pub fn to_json(&self) -> Value {
+        let mut body = Map::new();
+        body.insert("type".to_string(), Value::from("about:blank"));
+        body.insert("title".to_string(), Value::from(self.title.clone()));
+        body.insert("status".to_string(), Value::from(self.status.as_u16()));
+        if let Some(detail) = &self.detail {
+            body.insert("detail".to_string(), Value::from(detail.clone()));
+        }
+        for (name, value) in &self.extensions {
+            body.insert(name.clone(), value.clone());
+        }
+        Value::Object(body)
+    }
+}
+
+impl IntoResponse for Problem {
+    fn into_response(self) -> Response {
+        (
+            self.status,
+            [(header::CONTENT_TYPE, "application/problem+json")],
+            self.to_json().to_string(),
+        )
+            .into_response()
+    }
+}
+
+#[test]
+fn problems_serialize_with_their_extensions() {
+    let problem = Problem::new(StatusCode::NOT_FOUND).detail("No route for GET /todos").with("hints", ["/todo"]);
+
+    assert_eq!(
+        problem.to_json(),
+        serde_json::json!({
+            "type": "about:blank",
+            "title": "Not Found",
+            "status": 404,
+            "detail": "No route for GET /todos",
+            "hints": ["/todo"],
+        })
+    );
+}
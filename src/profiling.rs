@@ -0,0 +1,171 @@
+#![allow(dead_code)]
+
+//!
+//! PROFILING
+//! ---------
+//!
+//! Latency percentiles from the load generator say that a handler is slow, not
+//! why. A CPU profile does: `pprof` interrupts every thread of the process a
+//! hundred times a second, records the stack it was running, and reports how
+//! often each function showed up.
+//!
+//! The admin router's `GET /debug/pprof/profile` profiles the server for the
+//! given number of seconds, so start the load first, then ask for a profile
+//! while it runs:
+//!
+//! ```sh
+//! cargo run --release --bin loadgen -- --duration 30 ... &
+//! curl 'localhost:3001/debug/pprof/profile?seconds=10' > flamegraph.svg
+//! curl 'localhost:3001/debug/pprof/profile?seconds=10&format=pprof' > profile.pb
+//! go tool pprof -http :8080 profile.pb
+//! ```
+//!
+//! The flamegraph opens in a browser: the wider a frame, the more samples it
+//! was on the stack for. Profile a `--release` build, since a debug build
+//! spends its time in code that the optimizer would have removed.
+//!
+//! Only one profile can run at a time, and it slows the whole process down a
+//! little while it does.
+//!
+
+use std::time::Duration;
+
+use axum::{
+    extract::Query,
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+};
+use pprof::protos::Message;
+
+use crate::app::Routes;
+
+///
+/// Samples per second.
+///
+const FREQUENCY: i32 = 100;
+
+const MAX_SECONDS: u64 = 60;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ProfileFormat {
+    #[default]
+    Flamegraph,
+    Pprof,
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub struct ProfileParams {
+    #[serde(default = "default_seconds")]
+    pub seconds: u64,
+    #[serde(default)]
+    pub format: ProfileFormat,
+}
+
+fn default_seconds() -> u64 {
+    10
+}
+
+pub fn profiling_routes<S>() -> Routes<S>
+where
+    S: Clone + Send + Sync + 'static,
+{
+    Routes::new().get("/debug/pprof/profile", profile)
+}
+
+async fn profile(Query(params): Query<ProfileParams>) -> Result<Response, (StatusCode, String)> {
+    if !(1..=MAX_SECONDS).contains(&params.seconds) {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            format!("seconds must be between 1 and {}", MAX_SECONDS),
+        ));
+    }
+
+    // Resolving the symbols of the samples takes a while, and is all CPU, so
+    // the whole profile runs on the blocking pool.
+    let duration = Duration::from_secs(params.seconds);
+    let format = params.format;
+    tokio::task::spawn_blocking(move || capture(duration, format))
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+}
+
+fn capture(duration: Duration, format: ProfileFormat) -> Result<Response, (StatusCode, String)> {
+    let guard = pprof::ProfilerGuardBuilder::default()
+        .frequency(FREQUENCY)
+        .blocklist(&["libc", "libgcc", "pthread", "vdso"])
+        .build()
+        .map_err(|e| match e {
+            pprof::Error::Running => (StatusCode::CONFLICT, "A profile is already running".to_string()),
+            e => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()),
+        })?;
+    std::thread::sleep(duration);
+
+    let internal = |e: pprof::Error| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string());
+    let report = guard.report().build().map_err(internal)?;
+    drop(guard);
+
+    let mut body = Vec::new();
+    match format {
+        ProfileFormat::Flamegraph => {
+            report.flamegraph(&mut body).map_err(internal)?;
+            Ok(([(header::CONTENT_TYPE, "image/svg+xml")], body).into_response())
+        }
+        ProfileFormat::Pprof => {
+            report
+                .pprof()
+                .map_err(internal)?
+                .encode(&mut body)
+                .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+            Ok(([(header::CONTENT_TYPE, "application/octet-stream")], body).into_response())
+        }
+    }
+}
+
+#[tokio::test]
+async fn profiles_are_rendered_as_flamegraphs() {
+    // for Body::collect
+    use http_body_util::BodyExt;
+    /// for ServiceExt::oneshot
+    use tower::util::ServiceExt;
+    use std::sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    };
+
+    use axum::{body::Body, extract::Request};
+
+    let app = profiling_routes().into_router();
+
+    let response = app
+        .clone()
+        .oneshot(Request::get("/debug/pprof/profile?seconds=0").body(Body::empty()).unwrap())
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+    // Something to sample, which does not spend its time reading the clock:
+    // samples in the vDSO are left out.
+    let stop = Arc::new(AtomicBool::new(false));
+    let busy = std::thread::spawn({
+        let stop = stop.clone();
+        move || {
+            let mut n: u64 = 0;
+            while !stop.load(Ordering::Relaxed) {
+                n = std::hint::black_box(n.wrapping_mul(31).wrapping_add(7));
+            }
+        }
+    });
+
+    let response = app
+        .oneshot(Request::get("/debug/pprof/profile?seconds=1").body(Body::empty()).unwrap())
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.headers()[header::CONTENT_TYPE], "image/svg+xml");
+    let body = response.into_body().collect().await.unwrap().to_bytes();
+    assert!(String::from_utf8_lossy(&body).contains("<svg"));
+
+    stop.store(true, Ordering::Relaxed);
+    busy.join().unwrap();
+}
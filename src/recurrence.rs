@@ -0,0 +1,374 @@
+#![allow(dead_code)]
+
+//!
+//! RECURRENCE
+//! ----------
+//!
+//! Some todos come back: take out the trash every week, water the plants every
+//! three days, go to the gym on Mondays, Wednesdays, and Fridays.
+//!
+//! Rather than storing every future occurrence up front, each recurring todo
+//! carries a rule, and only one occurrence is open at a time. A scheduler runs
+//! in the background alongside the server, and whenever it finds an occurrence
+//! that has been completed, it creates the next one and moves the rule to it.
+//!
+
+use axum::{
+    async_trait,
+    extract::{FromRef, State},
+    http::StatusCode,
+};
+use axum_extra::routing::TypedPath;
+use sqlx::{Pool, Postgres};
+use time::{Duration, OffsetDateTime, Weekday};
+
+use crate::app::Routes;
+use crate::auth::{Claims, JwtKeys};
+use crate::clock::SharedClock;
+use crate::extract::AppJson;
+
+#[derive(Clone, Copy, Debug, PartialEq, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Day {
+    Mon,
+    Tue,
+    Wed,
+    Thu,
+    Fri,
+    Sat,
+    Sun,
+}
+
+impl From<Day> for Weekday {
+    fn from(day: Day) -> Self {
+        match day {
+            Day::Mon => Weekday::Monday,
+            Day::Tue => Weekday::Tuesday,
+            Day::Wed => Weekday::Wednesday,
+            Day::Thu => Weekday::Thursday,
+            Day::Fri => Weekday::Friday,
+            Day::Sat => Weekday::Saturday,
+            Day::Sun => Weekday::Sunday,
+        }
+    }
+}
+
+///
+/// When a todo comes back. Stored as JSON, for example
+/// `{ "freq": "daily", "interval": 3 }` or
+/// `{ "freq": "weekdays", "days": ["mon", "wed", "fri"] }`.
+///
+#[derive(Clone, Debug, PartialEq, serde::Deserialize, serde::Serialize)]
+#[serde(tag = "freq", rename_all = "snake_case")]
+pub enum RecurrenceRule {
+    Daily { interval: u32 },
+    Weekly { interval: u32 },
+    /// On the given days of the week, like the day-of-week field of cron.
+    Weekdays { days: Vec<Day> },
+}
+
+impl RecurrenceRule {
+    pub fn validate(&self) -> Result<(), String> {
+        match self {
+            RecurrenceRule::Daily { interval: 0 } | RecurrenceRule::Weekly { interval: 0 } => {
+                Err("interval must be at least 1".to_string())
+            }
+            RecurrenceRule::Weekdays { days } if days.is_empty() => {
+                Err("days must not be empty".to_string())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    ///
+    /// The first occurrence strictly after `after`, at the same time of day.
+    ///
+    pub fn next_occurrence(&self, after: OffsetDateTime) -> OffsetDateTime {
+        match self {
+            RecurrenceRule::Daily { interval } => after + Duration::days(*interval as i64),
+            RecurrenceRule::Weekly { interval } => after + Duration::weeks(*interval as i64),
+            RecurrenceRule::Weekdays { days } => (1..=7)
+                .map(|offset| after + Duration::days(offset))
+                .find(|next| days.iter().any(|day| Weekday::from(*day) == next.weekday()))
+                .unwrap_or(after + Duration::weeks(1)),
+        }
+    }
+}
+
+///
+/// The completed occurrence of a recurring todo, and the one created to
+/// replace it.
+///
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Materialized {
+    pub completed_id: i64,
+    pub next_id: i64,
+}
+
+#[async_trait]
+pub trait RecurrenceRepo: Send + Sync {
+    async fn get_recurrence(&self, user_id: i64, todo_id: i64) -> Option<RecurrenceRule>;
+    ///
+    /// Sets (or replaces) the rule of a todo. Returns `false` if there is no
+    /// such todo visible to the user.
+    ///
+    async fn set_recurrence(&self, user_id: i64, todo_id: i64, rule: &RecurrenceRule) -> bool;
+    async fn delete_recurrence(&self, user_id: i64, todo_id: i64) -> bool;
+    ///
+    /// Creates the next occurrence of every completed recurring todo, and
+    /// moves the rule over to it. Occurrences without a due date are
+    /// scheduled relative to `now`. This runs on behalf of the system, not
+    /// of any one user, so it is not scoped.
+    ///
+    async fn materialize_completed(&self, now: OffsetDateTime) -> Vec<Materialized>;
+}
+
+#[derive(Clone)]
+pub struct RecurrenceRepoPostgres {
+    pool: Pool<Postgres>,
+}
+
+impl RecurrenceRepoPostgres {
+    pub fn new(pool: Pool<Postgres>) -> Self {
+        RecurrenceRepoPostgres { pool }
+    }
+}
+
+#[async_trait]
+impl RecurrenceRepo for RecurrenceRepoPostgres {
+    async fn get_recurrence(&self, user_id: i64, todo_id: i64) -> Option<RecurrenceRule> {
+        let query = sqlx::query!(
+            "SELECT r.rule FROM todo_recurrences r JOIN todos t ON t.id = r.todo_id
+             WHERE r.todo_id = $1 AND todo_visible_to(t.owner_id, $2)",
+            todo_id,
+            user_id
+        );
+        query
+            .fetch_optional(&self.pool)
+            .await
+            .unwrap()
+            .map(|row| serde_json::from_str(&row.rule).unwrap())
+    }
+    async fn set_recurrence(&self, user_id: i64, todo_id: i64, rule: &RecurrenceRule) -> bool {
+        let query = sqlx::query!(
+            "INSERT INTO todo_recurrences (todo_id, rule)
+             SELECT id, $2 FROM todos WHERE id = $1 AND todo_visible_to(owner_id, $3)
+             ON CONFLICT (todo_id) DO UPDATE SET rule = EXCLUDED.rule RETURNING todo_id",
+            todo_id,
+            serde_json::to_string(rule).unwrap(),
+            user_id
+        );
+        query.fetch_optional(&self.pool).await.unwrap().is_some()
+    }
+    async fn delete_recurrence(&self, user_id: i64, todo_id: i64) -> bool {
+        let query = sqlx::query!(
+            "DELETE FROM todo_recurrences r USING todos t
+             WHERE r.todo_id = $1 AND t.id = r.todo_id AND todo_visible_to(t.owner_id, $2)",
+            todo_id,
+            user_id
+        );
+        query.execute(&self.pool).await.unwrap().rows_affected() > 0
+    }
+    async fn materialize_completed(&self, now: OffsetDateTime) -> Vec<Materialized> {
+        let mut tx = self.pool.begin().await.unwrap();
+
+        let completed = sqlx::query!(
+            "SELECT r.todo_id, r.rule, t.title, t.description, t.due_at, t.priority, t.owner_id
+             FROM todo_recurrences r JOIN todos t ON t.id = r.todo_id
+             WHERE t.status = 'done'
+             FOR UPDATE OF r"
+        )
+        .fetch_all(&mut *tx)
+        .await
+        .unwrap();
+
+        let mut materialized = Vec::with_capacity(completed.len());
+        for row in completed {
+            let rule: RecurrenceRule = serde_json::from_str(&row.rule).unwrap();
+            let due_at = rule.next_occurrence(row.due_at.unwrap_or(now));
+
+            let next_id = sqlx::query!(
+                "INSERT INTO todos (title, description, due_at, priority, owner_id) VALUES ($1, $2, $3, $4, $5) RETURNING id",
+                row.title,
+                row.description,
+                due_at,
+                row.priority,
+                row.owner_id
+            )
+            .fetch_one(&mut *tx)
+            .await
+            .unwrap()
+            .id;
+
+            sqlx::query!(
+                "UPDATE todo_recurrences SET todo_id = $1 WHERE todo_id = $2",
+                next_id,
+                row.todo_id
+            )
+            .execute(&mut *tx)
+            .await
+            .unwrap();
+
+            materialized.push(Materialized {
+                completed_id: row.todo_id,
+                next_id,
+            });
+        }
+
+        tx.commit().await.unwrap();
+        materialized
+    }
+}
+
+///
+/// Materializes completed occurrences every `period`, for as long as the
+/// server runs. Register it with `AppBuilder::background_task`.
+///
+pub async fn run_recurrence_scheduler<R: RecurrenceRepo>(repo: R, clock: SharedClock, period: std::time::Duration) {
+    let mut interval = tokio::time::interval(period);
+    loop {
+        interval.tick().await;
+        for Materialized { completed_id, next_id } in
+            repo.materialize_completed(clock.now()).await
+        {
+            println!("Todo {} recurs as todo {}", completed_id, next_id);
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct RecurrenceState<R: RecurrenceRepo> {
+    pub repo: R,
+}
+
+#[derive(Debug, TypedPath, serde::Deserialize)]
+#[typed_path("/todo/:id/recurrence")]
+pub struct TodoRecurrence {
+    pub id: i64,
+}
+
+pub fn recurrence_routes<S, R>() -> Routes<S>
+where
+    R: RecurrenceRepo + Clone + 'static,
+    RecurrenceState<R>: FromRef<S>,
+    JwtKeys: FromRef<S>,
+    S: Clone + Send + Sync + 'static,
+{
+    Routes::new()
+        .get(TodoRecurrence::PATH, get_recurrence::<R>)
+        .put(TodoRecurrence::PATH, set_recurrence::<R>)
+        .delete(TodoRecurrence::PATH, delete_recurrence::<R>)
+}
+
+async fn get_recurrence<R: RecurrenceRepo>(
+    Claims { sub: user_id, .. }: Claims,
+    TodoRecurrence { id }: TodoRecurrence,
+    State(RecurrenceState { repo }): State<RecurrenceState<R>>,
+) -> Result<AppJson<RecurrenceRule>, StatusCode> {
+    repo.get_recurrence(user_id, id).await.map(AppJson).ok_or(StatusCode::NOT_FOUND)
+}
+
+async fn set_recurrence<R: RecurrenceRepo>(
+    Claims { sub: user_id, .. }: Claims,
+    TodoRecurrence { id }: TodoRecurrence,
+    State(RecurrenceState { repo }): State<RecurrenceState<R>>,
+    AppJson(rule): AppJson<RecurrenceRule>,
+) -> Result<AppJson<RecurrenceRule>, (StatusCode, String)> {
+    rule.validate()
+        .map_err(|e| (StatusCode::UNPROCESSABLE_ENTITY, e))?;
+
+    if !repo.set_recurrence(user_id, id, &rule).await {
+        return Err((StatusCode::NOT_FOUND, format!("Todo {} not found", id)));
+    }
+    Ok(AppJson(rule))
+}
+
+async fn delete_recurrence<R: RecurrenceRepo>(
+    Claims { sub: user_id, .. }: Claims,
+    TodoRecurrence { id }: TodoRecurrence,
+    State(RecurrenceState { repo }): State<RecurrenceState<R>>,
+) -> StatusCode {
+    if repo.delete_recurrence(user_id, id).await {
+        StatusCode::NO_CONTENT
+    } else {
+        StatusCode::NOT_FOUND
+    }
+}
+
+#[tokio::test]
+async fn next_occurrence_follows_rule() {
+    use time::macros::datetime;
+
+    // A Friday.
+    let after = datetime!(2026-10-16 09:00 +02:00);
+
+    let daily = RecurrenceRule::Daily { interval: 3 };
+    assert_eq!(daily.next_occurrence(after), datetime!(2026-10-19 09:00 +02:00));
+
+    let weekly = RecurrenceRule::Weekly { interval: 2 };
+    assert_eq!(weekly.next_occurrence(after), datetime!(2026-10-30 09:00 +02:00));
+
+    let weekdays = RecurrenceRule::Weekdays { days: vec![Day::Mon, Day::Fri] };
+    assert_eq!(weekdays.next_occurrence(after), datetime!(2026-10-19 09:00 +02:00));
+
+    let rule: RecurrenceRule = serde_json::from_str(r#"{ "freq": "weekdays", "days": [] }"#).unwrap();
+    assert!(rule.validate().is_err());
+}
+
+#[tokio::test]
+async fn completed_occurrence_is_materialized() {
+    use sqlx::postgres::PgPoolOptions;
+    use time::macros::datetime;
+
+    use crate::persistence::TodoStatus;
+
+    let pool = PgPoolOptions::new()
+        .max_connections(1)
+        .connect(&crate::test_db::database_url().await)
+        .await
+        .unwrap();
+
+    let user_id = crate::users::create_test_user(&pool, false).await;
+    let due_at = datetime!(2026-10-16 09:00 UTC);
+    let todo_id = sqlx::query!(
+        "INSERT INTO todos (title, description, due_at, owner_id) VALUES ($1, $2, $3, $4) RETURNING id",
+        "Water the plants",
+        "Every three days",
+        due_at,
+        user_id
+    )
+    .fetch_one(&pool)
+    .await
+    .unwrap()
+    .id;
+
+    let repo = RecurrenceRepoPostgres::new(pool.clone());
+    let rule = RecurrenceRule::Daily { interval: 3 };
+    assert!(repo.set_recurrence(user_id, todo_id, &rule).await);
+
+    let materialized = repo.materialize_completed(OffsetDateTime::now_utc()).await;
+    assert!(materialized.iter().all(|m| m.completed_id != todo_id));
+
+    sqlx::query!("UPDATE todos SET status = 'done' WHERE id = $1", todo_id)
+        .execute(&pool)
+        .await
+        .unwrap();
+
+    let materialized = repo.materialize_completed(OffsetDateTime::now_utc()).await;
+    let next_id = materialized
+        .iter()
+        .find(|m| m.completed_id == todo_id)
+        .unwrap()
+        .next_id;
+
+    let next = sqlx::query!(r#"SELECT status AS "status: TodoStatus", due_at, owner_id FROM todos WHERE id = $1"#, next_id)
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+
+    assert_eq!(next.status, TodoStatus::Open);
+    assert_eq!(next.due_at, Some(datetime!(2026-10-19 09:00 UTC)));
+    assert_eq!(next.owner_id, Some(user_id));
+    assert_eq!(repo.get_recurrence(user_id, todo_id).await, None);
+    assert_eq!(repo.get_recurrence(user_id, next_id).await, Some(rule));
+}
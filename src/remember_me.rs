@@ -0,0 +1,407 @@
+#![allow(dead_code)]
+
+//!
+//! REMEMBER ME
+//! -----------
+//!
+//! A session (a refresh token family, see `auth.rs`) ends once it has gone a
+//! month unused, or when it is revoked. Ticking "remember me" at login also
+//! hands out a remember-me token, which outlives sessions: for a year, it
+//! starts a new one, without a password, whenever the browser has none.
+//!
+//! The token is split in two, `selector.validator`. The selector is an id,
+//! which finds the row without comparing secrets in SQL, and the validator is
+//! the secret, which is only stored hashed, so that a leaked table lets no
+//! one in. Every use replaces the validator, and keeps the selector, which
+//! names the whole series of tokens.
+//!
+//! That makes stolen tokens show. Once a thief has used a copy of the token,
+//! the user's own copy holds a validator that is no longer current, or the
+//! other way around: a known selector with the wrong validator means that
+//! two browsers hold the same token. Which one is the thief cannot be told,
+//! so the series is deleted, which logs both out, and the attempt is written
+//! to the audit log.
+//!
+//! `restore_session` sits in front of `/auth`. A request there without an
+//! access token or a refresh token cookie, but with a remember-me cookie,
+//! gets a new session, which `/auth/refresh` answers with. The rotated token
+//! goes back in the cookie.
+//!
+//! Remember-me tokens are separate from sessions: logging out everywhere
+//! ends the sessions, and `DELETE /auth/remember-me/all` the series that
+//! would start new ones.
+//!
+
+use axum::{
+    async_trait,
+    extract::{FromRef, Request, State},
+    http::{header, HeaderValue, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use axum_extra::extract::cookie::{Cookie, CookieJar, PrivateCookieJar, SameSite};
+use sqlx::{Pool, Postgres};
+use std::sync::Arc;
+use time::{Duration, PrimitiveDateTime};
+use tokio::sync::Mutex;
+
+use crate::app::Routes;
+use crate::auth::{hash_token, random_token, AuthState, Claims, JwtKeys, RefreshTokenRepo, TokenPair, REFRESH_TOKEN_COOKIE};
+use crate::clock::SharedClock;
+use crate::ids::{IdGenerator, SequenceIds};
+
+const REMEMBER_ME_TTL: Duration = Duration::days(365);
+
+pub const REMEMBER_ME_COOKIE: &str = "remember_me";
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct RememberMeToken {
+    pub id: i64,
+    pub user_id: i64,
+    pub selector: String,
+    pub validator_hash: String,
+    pub expires_at: PrimitiveDateTime,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum RememberMeError {
+    Invalid,
+    Expired,
+    /// The validator of a known series did not match: the token was copied.
+    Theft { user_id: i64 },
+}
+
+#[async_trait]
+pub trait RememberMeRepo: Send + Sync {
+    async fn insert(&self, user_id: i64, selector: &str, validator_hash: &str, expires_at: PrimitiveDateTime) -> i64;
+    async fn find(&self, selector: &str) -> Option<RememberMeToken>;
+    ///
+    /// Replaces the validator of the series, if it is still `old_hash`.
+    /// Returns `false` if another request replaced it first.
+    ///
+    async fn rotate(&self, selector: &str, old_hash: &str, new_hash: &str) -> bool;
+    async fn delete(&self, selector: &str) -> bool;
+    async fn delete_user(&self, user_id: i64) -> u64;
+}
+
+#[derive(Clone)]
+pub struct RememberMeRepoPostgres {
+    pool: Pool<Postgres>,
+}
+
+impl RememberMeRepoPostgres {
+    pub fn new(pool: Pool<Postgres>) -> Self {
+        RememberMeRepoPostgres { pool }
+    }
+}
+
+#[async_trait]
+impl RememberMeRepo for RememberMeRepoPostgres {
+    async fn insert(&self, user_id: i64, selector: &str, validator_hash: &str, expires_at: PrimitiveDateTime) -> i64 {
+        let query = sqlx::query!(
+            "INSERT INTO remember_tokens (user_id, selector, validator_hash, expires_at) VALUES ($1, $2, $3, $4) RETURNING id",
+            user_id,
+            selector,
+            validator_hash,
+            expires_at
+        );
+        query.fetch_one(&self.pool).await.unwrap().id
+    }
+    async fn find(&self, selector: &str) -> Option<RememberMeToken> {
+        let query = sqlx::query_as!(
+            RememberMeToken,
+            "SELECT id, user_id, selector, validator_hash, expires_at FROM remember_tokens WHERE selector = $1",
+            selector
+        );
+        query.fetch_optional(&self.pool).await.unwrap()
+    }
+    async fn rotate(&self, selector: &str, old_hash: &str, new_hash: &str) -> bool {
+        let query = sqlx::query!(
+            "UPDATE remember_tokens SET validator_hash = $3 WHERE selector = $1 AND validator_hash = $2",
+            selector,
+            old_hash,
+            new_hash
+        );
+        query.execute(&self.pool).await.unwrap().rows_affected() == 1
+    }
+    async fn delete(&self, selector: &str) -> bool {
+        let query = sqlx::query!("DELETE FROM remember_tokens WHERE selector = $1", selector);
+        query.execute(&self.pool).await.unwrap().rows_affected() == 1
+    }
+    async fn delete_user(&self, user_id: i64) -> u64 {
+        let query = sqlx::query!("DELETE FROM remember_tokens WHERE user_id = $1", user_id);
+        query.execute(&self.pool).await.unwrap().rows_affected()
+    }
+}
+
+///
+/// An in-memory implementation of `RememberMeRepo`, for tests.
+///
+#[derive(Clone, Default)]
+pub struct RememberMeRepoInMemory {
+    tokens: Arc<Mutex<Vec<RememberMeToken>>>,
+    ids: SequenceIds,
+}
+
+#[async_trait]
+impl RememberMeRepo for RememberMeRepoInMemory {
+    async fn insert(&self, user_id: i64, selector: &str, validator_hash: &str, expires_at: PrimitiveDateTime) -> i64 {
+        let id = self.ids.next_id();
+        self.tokens.lock().await.push(RememberMeToken {
+            id,
+            user_id,
+            selector: selector.to_string(),
+            validator_hash: validator_hash.to_string(),
+            expires_at,
+        });
+        id
+    }
+    async fn find(&self, selector: &str) -> Option<RememberMeToken> {
+        let tokens = self.tokens.lock().await;
+        tokens.iter().find(|token| token.selector == selector).cloned()
+    }
+    async fn rotate(&self, selector: &str, old_hash: &str, new_hash: &str) -> bool {
+        let mut tokens = self.tokens.lock().await;
+        match tokens.iter_mut().find(|token| token.selector == selector && token.validator_hash == old_hash) {
+            Some(token) => {
+                token.validator_hash = new_hash.to_string();
+                true
+            }
+            None => false,
+        }
+    }
+    async fn delete(&self, selector: &str) -> bool {
+        let mut tokens = self.tokens.lock().await;
+        let before = tokens.len();
+        tokens.retain(|token| token.selector != selector);
+        tokens.len() < before
+    }
+    async fn delete_user(&self, user_id: i64) -> u64 {
+        let mut tokens = self.tokens.lock().await;
+        let before = tokens.len();
+        tokens.retain(|token| token.user_id != user_id);
+        (before - tokens.len()) as u64
+    }
+}
+
+#[derive(Clone)]
+pub struct RememberMeState<M: RememberMeRepo> {
+    pub repo: M,
+    pub clock: SharedClock,
+}
+
+impl<M: RememberMeRepo> RememberMeState<M> {
+    ///
+    /// Starts a series for the user, returning the value of its cookie.
+    ///
+    pub async fn remember(&self, user_id: i64) -> String {
+        let (selector, validator) = (random_token(), random_token());
+        let expires_at = self.clock.now_utc_primitive() + REMEMBER_ME_TTL;
+        self.repo.insert(user_id, &selector, &hash_token(&validator), expires_at).await;
+        format!("{}.{}", selector, validator)
+    }
+
+    ///
+    /// Checks the value of a cookie, returning the user it remembers, and the
+    /// value that replaces it. A known selector with the wrong validator
+    /// deletes the series.
+    ///
+    pub async fn redeem(&self, value: &str) -> Result<(i64, String), RememberMeError> {
+        let (selector, validator) = value.split_once('.').ok_or(RememberMeError::Invalid)?;
+        let token = self.repo.find(selector).await.ok_or(RememberMeError::Invalid)?;
+        if token.expires_at <= self.clock.now_utc_primitive() {
+            self.repo.delete(selector).await;
+            return Err(RememberMeError::Expired);
+        }
+
+        let rotated = random_token();
+        if hash_token(validator) != token.validator_hash
+            || !self.repo.rotate(selector, &token.validator_hash, &hash_token(&rotated)).await
+        {
+            self.repo.delete(selector).await;
+            return Err(RememberMeError::Theft { user_id: token.user_id });
+        }
+        Ok((token.user_id, format!("{}.{}", selector, rotated)))
+    }
+
+    pub async fn forget(&self, value: &str) -> bool {
+        match value.split_once('.') {
+            Some((selector, _)) => self.repo.delete(selector).await,
+            None => false,
+        }
+    }
+}
+
+///
+/// Like the refresh token cookie, only sent back to `/auth`, and never to
+/// scripts, over plain HTTP, or with requests from other sites.
+///
+pub fn remember_me_cookie(jar: CookieJar, value: &str) -> CookieJar {
+    jar.add(
+        Cookie::build((REMEMBER_ME_COOKIE, value.to_string()))
+            .path("/auth")
+            .http_only(true)
+            .secure(true)
+            .same_site(SameSite::Strict)
+            .max_age(REMEMBER_ME_TTL),
+    )
+}
+
+pub fn forget_remember_me_cookie(jar: CookieJar) -> CookieJar {
+    jar.remove(Cookie::build(REMEMBER_ME_COOKIE).path("/auth"))
+}
+
+///
+/// The session that `restore_session` started for a request, for
+/// `/auth/refresh` to answer with.
+///
+#[derive(Clone, Debug)]
+pub struct RestoredSession(pub TokenPair);
+
+///
+/// Starts a new session from the remember-me cookie of requests that have
+/// none: no access token, and no refresh token cookie. The request then goes
+/// on with the new access token, and the new session as a `RestoredSession`.
+///
+pub async fn restore_session<M: RememberMeRepo, R: RefreshTokenRepo>(
+    State(remember_me): State<RememberMeState<M>>,
+    State(auth): State<AuthState<R>>,
+    jar: CookieJar,
+    private_jar: PrivateCookieJar,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    let has_session =
+        request.headers().contains_key(header::AUTHORIZATION) || private_jar.get(REFRESH_TOKEN_COOKIE).is_some();
+    let Some(cookie) = jar.get(REMEMBER_ME_COOKIE).filter(|_| !has_session) else {
+        return next.run(request).await;
+    };
+
+    match remember_me.redeem(cookie.value()).await {
+        Ok((user_id, rotated)) => {
+            tracing::info!(target: "audit", user_id, "Session restored from a remember-me token");
+            let tokens = auth.issue_tokens(user_id).await;
+            let bearer = HeaderValue::try_from(format!("Bearer {}", tokens.access_token)).unwrap();
+            request.headers_mut().insert(header::AUTHORIZATION, bearer);
+            request.extensions_mut().insert(RestoredSession(tokens));
+            (remember_me_cookie(jar, &rotated), next.run(request).await).into_response()
+        }
+        Err(RememberMeError::Theft { user_id }) => {
+            tracing::warn!(target: "audit", user_id, "Remember-me token reused, series deleted");
+            (forget_remember_me_cookie(jar), next.run(request).await).into_response()
+        }
+        Err(_) => (forget_remember_me_cookie(jar), next.run(request).await).into_response(),
+    }
+}
+
+///
+/// The routes that forget remembered logins, ready to be nested under
+/// `/auth`, next to `auth_routes`.
+///
+pub fn remember_me_routes<S, M>() -> Routes<S>
+where
+    M: RememberMeRepo + Clone + 'static,
+    RememberMeState<M>: FromRef<S>,
+    JwtKeys: FromRef<S>,
+    S: Clone + Send + Sync + 'static,
+{
+    Routes::new()
+        .delete("/remember-me", forget_browser::<M>)
+        .delete("/remember-me/all", forget_everywhere::<M>)
+}
+
+async fn forget_browser<M: RememberMeRepo>(
+    State(remember_me): State<RememberMeState<M>>,
+    jar: CookieJar,
+) -> (CookieJar, StatusCode) {
+    if let Some(cookie) = jar.get(REMEMBER_ME_COOKIE) {
+        remember_me.forget(cookie.value()).await;
+    }
+    (forget_remember_me_cookie(jar), StatusCode::NO_CONTENT)
+}
+
+async fn forget_everywhere<M: RememberMeRepo>(
+    claims: Claims,
+    State(remember_me): State<RememberMeState<M>>,
+    jar: CookieJar,
+) -> (CookieJar, StatusCode) {
+    remember_me.repo.delete_user(claims.sub).await;
+    (forget_remember_me_cookie(jar), StatusCode::NO_CONTENT)
+}
+
+#[tokio::test]
+async fn remembered_browsers_get_a_new_session() {
+    // for Body::collect
+    use http_body_util::BodyExt;
+    /// for ServiceExt::oneshot
+    use tower::util::ServiceExt;
+    use axum::{body::Body, http::Method};
+    use axum_extra::extract::cookie::Key;
+    use sqlx::postgres::PgPoolOptions;
+
+    use crate::auth::{auth_routes, RefreshTokenRepoInMemory};
+    use crate::clock::SystemClock;
+    use crate::cookies::{cookie_pair, set_cookies};
+
+    #[derive(Clone, FromRef)]
+    struct TestState {
+        auth: AuthState<RefreshTokenRepoInMemory>,
+        remember_me: RememberMeState<RememberMeRepoPostgres>,
+        keys: JwtKeys,
+        key: Key,
+    }
+
+    let pool = PgPoolOptions::new()
+        .max_connections(1)
+        .connect(&crate::test_db::database_url().await)
+        .await
+        .unwrap();
+    let user_id = crate::users::create_test_user(&pool, false).await;
+
+    let keys = JwtKeys::from_secret(b"secret");
+    let state = TestState {
+        auth: AuthState { repo: RefreshTokenRepoInMemory::default(), keys: keys.clone() },
+        remember_me: RememberMeState { repo: RememberMeRepoPostgres::new(pool), clock: Arc::new(SystemClock) },
+        key: keys.cookie_key(),
+        keys,
+    };
+    let app = auth_routes::<_, RefreshTokenRepoInMemory>()
+        .merge(remember_me_routes::<_, RememberMeRepoPostgres>())
+        .layer(axum::middleware::from_fn_with_state(
+            state.clone(),
+            restore_session::<RememberMeRepoPostgres, RefreshTokenRepoInMemory>,
+        ))
+        .into_router()
+        .with_state(state.clone());
+
+    let refresh = |cookie: String| {
+        let request = Request::builder()
+            .method(Method::POST)
+            .uri("/refresh")
+            .header(header::COOKIE, cookie)
+            .body(Body::empty())
+            .unwrap();
+        app.clone().oneshot(request)
+    };
+
+    let first = format!("{}={}", REMEMBER_ME_COOKIE, state.remember_me.remember(user_id).await);
+    let response = refresh(first.clone()).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let cookies = set_cookies(response.headers());
+    let rotated = cookies.iter().find(|cookie| cookie.starts_with("remember_me=")).unwrap();
+    assert!(rotated.contains("HttpOnly") && rotated.contains("Path=/auth"));
+    assert!(cookies.iter().any(|cookie| cookie.starts_with("refresh_token=")));
+    let body = response.into_body().collect().await.unwrap().to_bytes();
+    let tokens: TokenPair = serde_json::from_slice(&body).unwrap();
+    assert_eq!(crate::auth::decode_access_token(&state.keys, &tokens.access_token).unwrap().sub, user_id);
+
+    // The first cookie was copied: both copies stop working.
+    let response = refresh(first).await.unwrap();
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    let response = refresh(cookie_pair(rotated)).await.unwrap();
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    assert!(set_cookies(response.headers()).iter().any(|cookie| cookie.starts_with("remember_me=; ")));
+
+    let response = refresh(format!("{}=forged.token", REMEMBER_ME_COOKIE)).await.unwrap();
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+}
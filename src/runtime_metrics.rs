@@ -0,0 +1,242 @@
+#![allow(dead_code)]
+
+//!
+//! RUNTIME METRICS
+//! ---------------
+//!
+//! Tokio runs every task on a handful of worker threads, one per core by
+//! default. A task that blocks its thread (sleeping with `std::thread::sleep`,
+//! hashing a password, calling a synchronous client) takes that worker away
+//! from every other task for as long as it does, and the symptoms show up
+//! elsewhere: timers fire late, and requests queue up behind it.
+//!
+//! The runtime counts what its workers do, and `report_runtime_metrics`
+//! publishes it as gauges, next to the rest on the admin router's `/metrics`:
+//!
+//! - `tokio_workers`, and `tokio_alive_tasks`;
+//! - `tokio_worker_busy_ratio`, per worker: the share of the last period it
+//!   spent running tasks rather than waiting for one. Workers that stay near
+//!   1 while the app is not under load are blocked;
+//! - `tokio_global_queue_depth`: tasks waiting for any worker to pick them up.
+//!
+//! The runtime also counts the blocking pool, where `spawn_blocking` runs its
+//! closures, but only when built with `RUSTFLAGS="--cfg tokio_unstable"`:
+//!
+//! - `tokio_blocking_threads`, and `tokio_idle_blocking_threads`;
+//! - `tokio_blocking_queue_depth`: closures waiting for a blocking thread.
+//!
+
+use std::time::Duration;
+
+use tokio::runtime::{Handle, RuntimeMetrics};
+
+///
+/// Publishes the metrics of the current runtime every `period`.
+///
+pub async fn report_runtime_metrics(period: Duration) {
+    let metrics = Handle::current().metrics();
+    let mut busy = busy_durations(&metrics);
+    let mut interval = tokio::time::interval(period);
+    interval.tick().await;
+
+    loop {
+        interval.tick().await;
+        let previous = std::mem::replace(&mut busy, busy_durations(&metrics));
+
+        metrics::gauge!("tokio_workers", metrics.num_workers() as f64);
+        metrics::gauge!("tokio_alive_tasks", metrics.num_alive_tasks() as f64);
+        metrics::gauge!("tokio_global_queue_depth", metrics.global_queue_depth() as f64);
+        for (worker, (now, before)) in busy.iter().zip(&previous).enumerate() {
+            let ratio = (*now - *before).as_secs_f64() / period.as_secs_f64();
+            metrics::gauge!("tokio_worker_busy_ratio", ratio.min(1.0), "worker" => worker.to_string());
+        }
+
+        #[cfg(tokio_unstable)]
+        {
+            metrics::gauge!("tokio_blocking_threads", metrics.num_blocking_threads() as f64);
+            metrics::gauge!("tokio_idle_blocking_threads", metrics.num_idle_blocking_threads() as f64);
+            metrics::gauge!("tokio_blocking_queue_depth", metrics.blocking_queue_depth() as f64);
+        }
+    }
+}
+
+///
+/// How long each worker has spent running tasks since the runtime started.
+///
+pub fn busy_durations(metrics: &RuntimeMetrics) -> Vec<Duration> {
+    (0..metrics.num_workers()).map(|worker| metrics.worker_total_busy_duration(worker)).collect()
+}
+
+///
+/// EXERCISE 1
+///
+/// Two workers, and four tasks that each sleep for 200ms, while a fifth task
+/// wakes up every 10ms. With `std::thread::sleep`, the sleepers hold on to
+/// both workers: the workers are busy the whole time, and the ticker wakes up
+/// far later than it asked to. With `tokio::time::sleep`, the sleepers hand
+/// their worker back while they wait, and nobody notices them.
+///
+/// The requests of a server are the tasks here: a handler that blocks delays
+/// every request that lands on the same worker, not just its own.
+///
+/// Try wrapping the blocking sleep in `tokio::task::spawn_blocking` instead.
+/// What happens to the busy time of the workers, and to the ticker? And what
+/// if there are more blocking tasks than the blocking pool has threads
+/// (`max_blocking_threads`)?
+///
+#[test]
+fn blocking_the_runtime() {
+    use std::time::Instant;
+
+    ///
+    /// Runs four sleepers next to a ticker, and returns the time the workers
+    /// spent busy, and how late the ticker woke up, at worst.
+    ///
+    fn run(blocking: bool) -> (Duration, Duration) {
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(2)
+            .enable_all()
+            .build()
+            .unwrap();
+
+        runtime.block_on(async {
+            let metrics = Handle::current().metrics();
+            let before: Duration = busy_durations(&metrics).into_iter().sum();
+
+            let ticker = tokio::spawn(async {
+                let mut latest = Duration::ZERO;
+                for _ in 0..20 {
+                    let start = Instant::now();
+                    tokio::time::sleep(Duration::from_millis(10)).await;
+                    latest = latest.max(start.elapsed().saturating_sub(Duration::from_millis(10)));
+                }
+                latest
+            });
+            let sleepers: Vec<_> = (0..4)
+                .map(|_| {
+                    tokio::spawn(async move {
+                        if blocking {
+                            std::thread::sleep(Duration::from_millis(200));
+                        } else {
+                            tokio::time::sleep(Duration::from_millis(200)).await;
+                        }
+                    })
+                })
+                .collect();
+
+            for sleeper in sleepers {
+                sleeper.await.unwrap();
+            }
+            let lateness = ticker.await.unwrap();
+            let after: Duration = busy_durations(&metrics).into_iter().sum();
+            (after - before, lateness)
+        })
+    }
+
+    let (blocked_busy, blocked_lateness) = run(true);
+    let (async_busy, async_lateness) = run(false);
+
+    // Four sleeps of 200ms, on two workers.
+    assert!(blocked_busy >= Duration::from_millis(400), "{:?}", blocked_busy);
+    assert!(async_busy < Duration::from_millis(100), "{:?}", async_busy);
+    assert!(blocked_lateness >= Duration::from_millis(100), "{:?}", blocked_lateness);
+    assert!(async_lateness < blocked_lateness);
+}
+
+///
+/// EXERCISE 2
+///
+/// A handler that computes for a while without ever awaiting (hashing a
+/// password, parsing a large CSV upload, resizing an image) blocks its worker
+/// just like a sleep does, except that it cannot be made asynchronous: the
+/// work has to happen on some thread. Here, `stretch` stands for it: it
+/// hashes its input again and again for 100ms, like a key derivation
+/// function.
+///
+/// Four of them arrive at once on two workers, and then a request that
+/// should take no time at all: it waits for the workers to be done with the
+/// hashes first. There are two ways to keep the workers free:
+///
+/// - `tokio::task::spawn_blocking` runs the closure on the blocking pool, and
+///   the handler awaits it like any other future;
+/// - `tokio::task::block_in_place` keeps running the closure on the worker,
+///   but hands the worker's other tasks over to a new thread first. It saves
+///   moving the data to another thread, but panics on a `current_thread`
+///   runtime, which is what `#[tokio::test]` uses by default.
+///
+/// Try raising the number of hashes to 100: how long does the quick request
+/// wait with `spawn_blocking`? Then set `max_blocking_threads(2)` on the
+/// runtime. What is the blocking pool protecting the workers from, and what
+/// does it not protect the machine from?
+///
+#[test]
+fn cpu_bound_handlers() {
+    use std::time::Instant;
+
+    use axum::{body::Body, extract::Request, routing::get, Router};
+    use sha2::{Digest, Sha256};
+    /// for ServiceExt::oneshot
+    use tower::util::ServiceExt;
+
+    fn stretch(input: &[u8]) -> Vec<u8> {
+        let start = Instant::now();
+        let mut digest = Sha256::digest(input).to_vec();
+        while start.elapsed() < Duration::from_millis(100) {
+            digest = Sha256::digest(&digest).to_vec();
+        }
+        digest
+    }
+
+    let app = Router::new()
+        .route("/quick", get(|| async { "Done" }))
+        .route("/hash", get(|| async { stretch(b"hunter2") }))
+        .route(
+            "/hash/spawn-blocking",
+            get(|| async { tokio::task::spawn_blocking(|| stretch(b"hunter2")).await.unwrap() }),
+        )
+        .route(
+            "/hash/block-in-place",
+            get(|| async { tokio::task::block_in_place(|| stretch(b"hunter2")) }),
+        );
+
+    ///
+    /// How long the quick request takes, while four hashes are in flight.
+    ///
+    fn quick_latency(app: &Router, hash: &'static str) -> Duration {
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(2)
+            .enable_all()
+            .build()
+            .unwrap();
+
+        runtime.block_on(async {
+            let send = |uri: &'static str| {
+                let app = app.clone();
+                tokio::spawn(async move { app.oneshot(Request::get(uri).body(Body::empty()).unwrap()).await.unwrap() })
+            };
+
+            let hashes: Vec<_> = (0..4).map(|_| send(hash)).collect();
+            // Not `tokio::time::sleep`: with both workers blocked, its timer
+            // would not fire until they are done. This thread is not a worker,
+            // so blocking it holds up nothing.
+            std::thread::sleep(Duration::from_millis(10));
+
+            let start = Instant::now();
+            send("/quick").await.unwrap();
+            let latency = start.elapsed();
+
+            for hash in hashes {
+                hash.await.unwrap();
+            }
+            latency
+        })
+    }
+
+    let blocked = quick_latency(&app, "/hash");
+    let spawned = quick_latency(&app, "/hash/spawn-blocking");
+    let in_place = quick_latency(&app, "/hash/block-in-place");
+
+    assert!(blocked >= Duration::from_millis(50), "{:?}", blocked);
+    assert!(spawned < blocked / 2, "{:?} vs {:?}", spawned, blocked);
+    assert!(in_place < blocked / 2, "{:?} vs {:?}", in_place, blocked);
+}
@@ -0,0 +1,228 @@
+#![allow(dead_code)]
+
+//!
+//! SCHEMA DRIFT
+//! ------------
+//!
+//! `query_as!` checks a query against the database when it compiles, but a
+//! struct read with `query_as` or `FromRow` is only checked when the query
+//! runs. Give `Todo` a field without the migration that adds its column, or
+//! change the type of one, and the app starts fine, and fails on the first
+//! request that reads a todo.
+//!
+//! So the structs that mirror a table list their columns with `table!`, and
+//! the app compares them with `information_schema` when it starts, refusing
+//! to start if they differ:
+//!
+//! ```text
+//! The structs do not match the database; is a migration missing?
+//!     todos.due_at: the struct has timestamptz NOT NULL, the table has timestamptz NULL
+//!     todos.estimate: the struct has int4 NOT NULL, the table has no such column
+//! ```
+//!
+//! The `structs_match_the_database` test does the same. A table may have
+//! columns that its struct does not read.
+//!
+
+use std::fmt;
+
+use sqlx::{postgres::Postgres, Pool, TypeInfo};
+
+///
+/// A Rust type that a column is read into, `Option` for a nullable one.
+///
+pub trait Column {
+    const NULLABLE: bool;
+
+    fn type_name() -> String;
+}
+
+impl<T: Column> Column for Option<T> {
+    const NULLABLE: bool = true;
+
+    fn type_name() -> String {
+        T::type_name()
+    }
+}
+
+macro_rules! column {
+    ($($ty:ty),* $(,)?) => {
+        $(
+            impl Column for $ty {
+                const NULLABLE: bool = false;
+
+                fn type_name() -> String {
+                    <$ty as sqlx::Type<Postgres>>::type_info().name().to_lowercase()
+                }
+            }
+        )*
+    };
+}
+
+column!(
+    bool,
+    i32,
+    i64,
+    String,
+    Vec<u8>,
+    serde_json::Value,
+    time::OffsetDateTime,
+    time::PrimitiveDateTime,
+    uuid::Uuid,
+    crate::persistence::TodoStatus,
+);
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ColumnSchema {
+    pub name: &'static str,
+    pub type_name: String,
+    pub nullable: bool,
+}
+
+impl ColumnSchema {
+    pub fn of<T: Column>(name: &'static str) -> Self {
+        ColumnSchema { name, type_name: T::type_name(), nullable: T::NULLABLE }
+    }
+}
+
+impl fmt::Display for ColumnSchema {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", self.type_name, if self.nullable { "NULL" } else { "NOT NULL" })
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct TableSchema {
+    pub table: &'static str,
+    pub columns: Vec<ColumnSchema>,
+}
+
+///
+/// A struct that mirrors a table, which `table!` implements.
+///
+pub trait Table {
+    fn schema() -> TableSchema;
+}
+
+///
+/// Implements `Table` for a struct, from the types of its fields:
+///
+/// ```ignore
+/// table!(User in "users" { id: i64, name: String, created_at: OffsetDateTime });
+/// ```
+///
+/// Every field has to be listed, with its exact type, or it does not compile.
+///
+macro_rules! table {
+    ($struct:ident in $table:literal { $($field:ident: $ty:ty),* $(,)? }) => {
+        impl crate::schema_drift::Table for $struct {
+            fn schema() -> crate::schema_drift::TableSchema {
+                #[allow(unused)]
+                fn every_field_is_listed($struct { $($field),* }: $struct) {
+                    $(let _: $ty = $field;)*
+                }
+                crate::schema_drift::TableSchema {
+                    table: $table,
+                    columns: vec![$(crate::schema_drift::ColumnSchema::of::<$ty>(stringify!($field))),*],
+                }
+            }
+        }
+    };
+}
+
+pub(crate) use table;
+
+///
+/// How the structs differ from the database, a line for each column.
+///
+#[derive(Debug, PartialEq)]
+pub struct SchemaDrift(pub Vec<String>);
+
+impl fmt::Display for SchemaDrift {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "The structs do not match the database; is a migration missing?")?;
+        self.0.iter().try_for_each(|line| write!(f, "\n    {}", line))
+    }
+}
+
+impl std::error::Error for SchemaDrift {}
+
+impl SchemaDrift {
+    ///
+    /// Compares the columns of `tables` with those in the database.
+    ///
+    pub async fn check(pool: &Pool<Postgres>, tables: &[TableSchema]) -> Result<(), SchemaDrift> {
+        let mut drift = Vec::new();
+        for expected in tables {
+            let actual: Vec<(String, String, bool)> = sqlx::query_as(
+                "SELECT column_name::TEXT, udt_name::TEXT, is_nullable = 'YES' FROM information_schema.columns
+                WHERE table_schema = current_schema() AND table_name = $1",
+            )
+            .bind(expected.table)
+            .fetch_all(pool)
+            .await
+            .map_err(|error| SchemaDrift(vec![format!("{}: {}", expected.table, error)]))?;
+
+            for column in &expected.columns {
+                let found = actual.iter().find(|(name, _, _)| name == column.name);
+                let differs = match found {
+                    Some((_, type_name, nullable)) => {
+                        let found = ColumnSchema { name: column.name, type_name: type_name.clone(), nullable: *nullable };
+                        (&found != column).then(|| found.to_string())
+                    }
+                    None => Some("no such column".to_string()),
+                };
+                if let Some(found) = differs {
+                    drift.push(format!("{}.{}: the struct has {}, the table has {}", expected.table, column.name, column, found));
+                }
+            }
+        }
+        if drift.is_empty() {
+            Ok(())
+        } else {
+            Err(SchemaDrift(drift))
+        }
+    }
+}
+
+#[tokio::test]
+async fn structs_match_the_database() {
+    let pool = sqlx::postgres::PgPoolOptions::new()
+        .max_connections(1)
+        .connect(&crate::test_db::database_url().await)
+        .await
+        .unwrap();
+
+    if let Err(drift) = SchemaDrift::check(&pool, &crate::persistence::mirrored_tables()).await {
+        panic!("{}", drift);
+    }
+}
+
+#[tokio::test]
+async fn drift_is_reported_by_column() {
+    use time::{OffsetDateTime, PrimitiveDateTime};
+
+    struct Todo {
+        id: i64,
+        title: Option<String>,
+        created_at: PrimitiveDateTime,
+        due_at: Option<OffsetDateTime>,
+        estimate: i32,
+    }
+    table!(Todo in "todos" { id: i64, title: Option<String>, created_at: PrimitiveDateTime, due_at: Option<OffsetDateTime>, estimate: i32 });
+
+    let pool = sqlx::postgres::PgPoolOptions::new()
+        .max_connections(1)
+        .connect(&crate::test_db::database_url().await)
+        .await
+        .unwrap();
+
+    assert_eq!(
+        SchemaDrift::check(&pool, &[Todo::schema()]).await,
+        Err(SchemaDrift(vec![
+            "todos.title: the struct has text NULL, the table has text NOT NULL".to_string(),
+            "todos.created_at: the struct has timestamp NOT NULL, the table has timestamptz NOT NULL".to_string(),
+            "todos.estimate: the struct has int4 NOT NULL, the table has no such column".to_string(),
+        ]))
+    );
+}
@@ -0,0 +1,300 @@
+#![allow(dead_code)]
+
+//!
+//! SECRETS
+//! -------
+//!
+//! The app needs a few values that must not leak: the database password, the
+//! JWT secret, which also keys signed URLs, and the keys of `pii.rs`. Plain
+//! environment variables are the easy place for them, and a poor one: they
+//! are inherited by every child process, end up in crash reports and `ps e`,
+//! and only change when the app restarts.
+//!
+//! So secrets come from a `SecretsProvider`, chosen by `SECRETS_PROVIDER`:
+//!
+//! - `env` (the default), for development: the environment, as before.
+//! - `file`: one file per secret in `SECRETS_DIR` (`/run/secrets` by default),
+//!   as mounted by Docker and Kubernetes.
+//! - `vault`: a KV version 2 secret of HashiCorp Vault, or anything with the
+//!   same HTTP API, at `VAULT_ADDR`, read with `VAULT_TOKEN` from
+//!   `VAULT_SECRET_PATH` (such as `secret/data/todos`), with one field per
+//!   secret.
+//!
+//! Everything else in `AppConfig` stays in the environment.
+//!
+//! `Secrets` wraps the provider with a cache, so that a secret is fetched at
+//! most once every `SECRETS_TTL_SECS` (five minutes by default), and with
+//! rotation hooks: `watch` refetches the secrets that have hooks at that
+//! interval, and calls their hooks when they changed. The database password
+//! has one, which makes new connections use the new password. The others are
+//! copied into the app's state at startup, and a new JWT secret or PII key
+//! takes effect when the app restarts.
+//!
+
+use std::{
+    collections::HashMap,
+    fmt,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use axum::async_trait;
+use time::OffsetDateTime;
+
+use crate::clock::{SharedClock, SystemClock};
+
+#[derive(Debug)]
+pub struct SecretsError(String);
+
+impl fmt::Display for SecretsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+#[async_trait]
+pub trait SecretsProvider: Send + Sync {
+    ///
+    /// The current value of the secret, or `None` if there is no such secret.
+    ///
+    async fn fetch(&self, name: &str) -> Result<Option<String>, SecretsError>;
+}
+
+pub struct EnvSecrets;
+
+#[async_trait]
+impl SecretsProvider for EnvSecrets {
+    async fn fetch(&self, name: &str) -> Result<Option<String>, SecretsError> {
+        Ok(std::env::var(name).ok())
+    }
+}
+
+///
+/// Reads the secret `NAME` from the file `<dir>/NAME`, without the newline
+/// that editors and `echo` add at the end.
+///
+pub struct FileSecrets {
+    pub dir: PathBuf,
+}
+
+#[async_trait]
+impl SecretsProvider for FileSecrets {
+    async fn fetch(&self, name: &str) -> Result<Option<String>, SecretsError> {
+        match tokio::fs::read_to_string(self.dir.join(name)).await {
+            Ok(value) => Ok(Some(value.trim_end_matches(['\r', '\n']).to_string())),
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(error) => Err(SecretsError(format!("Cannot read the secret {}: {}", name, error))),
+        }
+    }
+}
+
+pub struct VaultSecrets {
+    pub addr: String,
+    pub token: String,
+    /// The path of a KV version 2 secret, with its `data/` part.
+    pub path: String,
+    pub client: reqwest::Client,
+}
+
+#[derive(serde::Deserialize)]
+struct VaultResponse {
+    data: VaultData,
+}
+
+#[derive(serde::Deserialize)]
+struct VaultData {
+    data: HashMap<String, String>,
+}
+
+#[async_trait]
+impl SecretsProvider for VaultSecrets {
+    async fn fetch(&self, name: &str) -> Result<Option<String>, SecretsError> {
+        let url = format!("{}/v1/{}", self.addr.trim_end_matches('/'), self.path.trim_start_matches('/'));
+        let error = |error: reqwest::Error| SecretsError(format!("Cannot read the secret {} from Vault: {}", name, error));
+        let response = self.client.get(url).header("X-Vault-Token", &self.token).send().await.map_err(error)?;
+        let VaultResponse { data: VaultData { mut data } } =
+            response.error_for_status().map_err(error)?.json().await.map_err(error)?;
+        Ok(data.remove(name))
+    }
+}
+
+type RotationHook = Arc<dyn Fn(&str) + Send + Sync>;
+
+struct Cached {
+    value: Option<String>,
+    fetched_at: OffsetDateTime,
+}
+
+///
+/// A `SecretsProvider` with a cache, and hooks for secrets that change.
+///
+#[derive(Clone)]
+pub struct Secrets {
+    provider: Arc<dyn SecretsProvider>,
+    ttl: Duration,
+    clock: SharedClock,
+    cache: Arc<Mutex<HashMap<String, Cached>>>,
+    hooks: Arc<Mutex<HashMap<String, Vec<RotationHook>>>>,
+}
+
+impl Secrets {
+    pub fn new(provider: Arc<dyn SecretsProvider>, ttl: Duration, clock: SharedClock) -> Self {
+        Secrets { provider, ttl, clock, cache: Default::default(), hooks: Default::default() }
+    }
+
+    ///
+    /// The environment, without a cache, since reading it is cheap.
+    ///
+    pub fn env() -> Self {
+        Secrets::new(Arc::new(EnvSecrets), Duration::ZERO, Arc::new(SystemClock))
+    }
+
+    ///
+    /// The same secrets, with the same cache and hooks, but that are always
+    /// fetched from the provider.
+    ///
+    pub fn uncached(&self) -> Self {
+        Secrets { ttl: Duration::ZERO, ..self.clone() }
+    }
+
+    ///
+    /// The provider chosen by the environment (see above).
+    ///
+    pub fn from_env() -> Result<Secrets, String> {
+        let var = |name: &str| std::env::var(name).ok().filter(|value| !value.is_empty());
+        let required = |name: &str| var(name).ok_or_else(|| format!("{} must be set", name));
+        let provider: Arc<dyn SecretsProvider> = match var("SECRETS_PROVIDER").as_deref().unwrap_or("env") {
+            "env" => Arc::new(EnvSecrets),
+            "file" => Arc::new(FileSecrets { dir: var("SECRETS_DIR").unwrap_or("/run/secrets".to_string()).into() }),
+            "vault" => Arc::new(VaultSecrets {
+                addr: required("VAULT_ADDR")?,
+                token: required("VAULT_TOKEN")?,
+                path: required("VAULT_SECRET_PATH")?,
+                client: reqwest::Client::new(),
+            }),
+            other => return Err(format!("SECRETS_PROVIDER has an invalid value: {}", other)),
+        };
+        let ttl = var("SECRETS_TTL_SECS").unwrap_or("300".to_string());
+        let ttl = ttl.parse().map_err(|_| format!("SECRETS_TTL_SECS has an invalid value: {}", ttl))?;
+        Ok(Secrets::new(provider, Duration::from_secs(ttl), Arc::new(SystemClock)))
+    }
+
+    ///
+    /// The secret, from the cache if it was fetched less than the TTL ago.
+    ///
+    pub async fn get(&self, name: &str) -> Result<Option<String>, SecretsError> {
+        let now = self.clock.now();
+        if let Some(cached) = self.cache.lock().unwrap().get(name) {
+            if now - cached.fetched_at < self.ttl {
+                return Ok(cached.value.clone());
+            }
+        }
+        self.fetch(name).await
+    }
+
+    pub async fn require(&self, name: &str) -> Result<String, String> {
+        match self.get(name).await {
+            Ok(Some(value)) => Ok(value),
+            Ok(None) => Err(format!("The secret {} must be set", name)),
+            Err(error) => Err(error.to_string()),
+        }
+    }
+
+    ///
+    /// Calls `hook` with the new value whenever the secret is found to have
+    /// changed, by `get` or by `watch`.
+    ///
+    pub fn on_rotation(&self, name: &str, hook: impl Fn(&str) + Send + Sync + 'static) {
+        self.hooks.lock().unwrap().entry(name.to_string()).or_default().push(Arc::new(hook));
+    }
+
+    ///
+    /// Refetches the secrets that have rotation hooks every TTL, forever. A
+    /// provider that fails keeps the previous values in use.
+    ///
+    pub async fn watch(self) {
+        let mut interval = tokio::time::interval(self.ttl.max(Duration::from_secs(1)));
+        interval.tick().await;
+        loop {
+            interval.tick().await;
+            let names = self.hooks.lock().unwrap().keys().cloned().collect::<Vec<_>>();
+            for name in names {
+                if let Err(error) = self.fetch(&name).await {
+                    tracing::warn!(secret = name, %error, "Failed to refresh a secret");
+                }
+            }
+        }
+    }
+
+    async fn fetch(&self, name: &str) -> Result<Option<String>, SecretsError> {
+        let value = self.provider.fetch(name).await?;
+        let previous = self
+            .cache
+            .lock()
+            .unwrap()
+            .insert(name.to_string(), Cached { value: value.clone(), fetched_at: self.clock.now() });
+
+        let rotated = previous.is_some_and(|previous| previous.value != value);
+        if let (true, Some(value)) = (rotated, &value) {
+            tracing::info!(secret = name, "Secret rotated");
+            let hooks = self.hooks.lock().unwrap().get(name).cloned().unwrap_or_default();
+            for hook in hooks {
+                hook(value);
+            }
+        }
+        Ok(value)
+    }
+}
+
+#[tokio::test]
+async fn secrets_are_cached_and_rotated() {
+    use axum::{extract::State, http::HeaderMap, routing::get, Json, Router};
+    use serde_json::{json, Value};
+    use tokio::net::TcpListener;
+
+    use crate::clock::FakeClock;
+
+    // A Vault that answers with whatever password it holds, and counts reads.
+    let vault = Arc::new(Mutex::new(("first".to_string(), 0)));
+    async fn read(State(vault): State<Arc<Mutex<(String, u32)>>>, headers: HeaderMap) -> Json<Value> {
+        assert_eq!(headers["X-Vault-Token"], "root");
+        let mut vault = vault.lock().unwrap();
+        vault.1 += 1;
+        Json(json!({ "data": { "data": { "DATABASE_PASSWORD": vault.0 }, "metadata": { "version": 1 } } }))
+    }
+    let router = Router::new().route("/v1/secret/data/todos", get(read)).with_state(vault.clone());
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move { axum::serve(listener, router).await.unwrap() });
+
+    let clock = Arc::new(FakeClock::new(OffsetDateTime::now_utc()));
+    let provider = VaultSecrets {
+        addr: format!("http://{}/", addr),
+        token: "root".to_string(),
+        path: "secret/data/todos".to_string(),
+        client: reqwest::Client::new(),
+    };
+    let secrets = Secrets::new(Arc::new(provider), Duration::from_secs(60), clock.clone());
+    let rotations = Arc::new(Mutex::new(Vec::new()));
+    let seen = rotations.clone();
+    secrets.on_rotation("DATABASE_PASSWORD", move |value| seen.lock().unwrap().push(value.to_string()));
+
+    assert_eq!(secrets.require("DATABASE_PASSWORD").await.unwrap(), "first");
+    assert!(secrets.require("JWT_SECRET").await.is_err());
+    vault.lock().unwrap().0 = "second".to_string();
+    assert_eq!(secrets.require("DATABASE_PASSWORD").await.unwrap(), "first");
+    assert_eq!(vault.lock().unwrap().1, 2);
+
+    clock.advance(time::Duration::seconds(61));
+    assert_eq!(secrets.require("DATABASE_PASSWORD").await.unwrap(), "second");
+    assert_eq!(*rotations.lock().unwrap(), ["second"]);
+
+    let dir = std::env::temp_dir().join(format!("secrets-{}", rand::random::<u64>()));
+    tokio::fs::create_dir_all(&dir).await.unwrap();
+    tokio::fs::write(dir.join("JWT_SECRET"), "from a file\n").await.unwrap();
+    let files = FileSecrets { dir: dir.clone() };
+    assert_eq!(files.fetch("JWT_SECRET").await.unwrap().as_deref(), Some("from a file"));
+    assert_eq!(files.fetch("PII_KEYS").await.unwrap(), None);
+    tokio::fs::remove_dir_all(dir).await.unwrap();
+}
@@ -0,0 +1,258 @@
+#![allow(dead_code)]
+
+//!
+//! SHADOW TRAFFIC
+//! --------------
+//!
+//! A rewrite that passes its tests can still answer real traffic differently:
+//! with data that the tests never had, or clients that send what nobody
+//! expected. Before cutting over to it, send it a copy of production traffic,
+//! and compare its answers with those of the current implementation, without
+//! the clients ever seeing them.
+//!
+//! `shadow_requests` does that for a sample of `GET` requests: the primary
+//! answers the client as usual, and a copy of the request goes to the shadow,
+//! in the background. When both have answered, their statuses and bodies are
+//! compared, JSON bodies member by member, so that the order of keys does not
+//! count as a difference. Mismatches are logged with the paths that differ,
+//! and every comparison is counted in `shadow_comparisons_total`, by result.
+//!
+//! Only `GET`s are mirrored, because sending a `POST` twice would do what it
+//! does twice. And only responses whose whole body is known up front are
+//! compared, which leaves out streams such as the feed's server-sent events.
+//!
+//! The shadow is another router in the same process, or another deployment
+//! altogether: set `SHADOW_UPSTREAM` to its base URL, and `SHADOW_PERCENT` to
+//! the percentage of requests to mirror to it.
+//!
+
+use std::time::Duration;
+
+use axum::{
+    body::{Body, Bytes, HttpBody},
+    extract::{Request, State},
+    http::{header, HeaderValue, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Router,
+};
+use serde_json::Value;
+use tower::ServiceExt;
+
+/// Larger bodies are not compared, to bound what is held in memory.
+const MAX_COMPARED_BODY: u64 = 1024 * 1024;
+
+/// How many differing paths a mismatch logs, at most.
+const MAX_LOGGED_DIFFERENCES: usize = 10;
+
+///
+/// Where requests are mirrored to, and how many of them.
+///
+#[derive(Clone)]
+pub struct Shadow {
+    target: Router,
+    percent: f64,
+}
+
+impl Shadow {
+    ///
+    /// Mirrors `percent` of the requests to `router`.
+    ///
+    pub fn new(router: Router, percent: f64) -> Self {
+        Shadow { target: router, percent }
+    }
+
+    ///
+    /// Mirrors `percent` of the requests to another deployment, at `base_url`,
+    /// through a router that forwards whatever it gets.
+    ///
+    pub fn upstream(base_url: &str, percent: f64) -> Self {
+        let client = reqwest::Client::builder().timeout(Duration::from_secs(10)).build().unwrap();
+        let base_url = base_url.trim_end_matches('/').to_string();
+        let target = Router::new().fallback(move |request: Request| async move {
+            forward(&client, &base_url, request).await.into_response()
+        });
+        Shadow { target, percent }
+    }
+
+    ///
+    /// Mirrors nothing.
+    ///
+    pub fn disabled() -> Self {
+        Shadow::new(Router::new(), 0.0)
+    }
+
+    fn sampled(&self) -> bool {
+        self.percent > 0.0 && rand::random::<f64>() * 100.0 < self.percent
+    }
+}
+
+async fn forward(client: &reqwest::Client, base_url: &str, request: Request) -> Result<Response, (StatusCode, String)> {
+    let path = request.uri().path_and_query().map_or("/", |path| path.as_str());
+    let mut upstream = client.get(format!("{}{}", base_url, path));
+    for (name, value) in request.headers().iter().filter(|(name, _)| *name != header::HOST) {
+        upstream = upstream.header(name.as_str(), value.as_bytes());
+    }
+    let upstream = upstream.send().await.map_err(|e| (StatusCode::BAD_GATEWAY, e.to_string()))?;
+
+    let mut response = Response::builder().status(upstream.status().as_u16());
+    if let Some(content_type) = upstream.headers().get(reqwest::header::CONTENT_TYPE) {
+        response = response.header(header::CONTENT_TYPE, content_type.as_bytes());
+    }
+    let body = upstream.bytes().await.map_err(|e| (StatusCode::BAD_GATEWAY, e.to_string()))?;
+    Ok(response.body(Body::from(body)).unwrap())
+}
+
+///
+/// Answers with the primary, and compares its answer with the shadow's in
+/// the background, for a sample of `GET` requests.
+///
+pub async fn shadow_requests(State(shadow): State<Shadow>, request: Request, next: Next) -> Response {
+    if request.method() != "GET" || !shadow.sampled() {
+        return next.run(request).await;
+    }
+
+    let mut copy = Request::new(Body::empty());
+    *copy.method_mut() = request.method().clone();
+    *copy.uri_mut() = request.uri().clone();
+    *copy.headers_mut() = request.headers().clone();
+    copy.headers_mut().insert("X-Shadow-Request", HeaderValue::from_static("1"));
+
+    let response = next.run(request).await;
+    let comparable = response.body().size_hint().exact().is_some_and(|size| size <= MAX_COMPARED_BODY);
+    if !comparable {
+        return response;
+    }
+
+    let (parts, body) = response.into_parts();
+    let Ok(body) = axum::body::to_bytes(body, MAX_COMPARED_BODY as usize).await else {
+        return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+    };
+    let primary = (parts.status, body.clone());
+
+    let uri = copy.uri().to_string();
+    tokio::spawn(async move {
+        let Ok(response) = shadow.target.oneshot(copy).await;
+        let (parts, body) = response.into_parts();
+        let result = match axum::body::to_bytes(body, MAX_COMPARED_BODY as usize).await {
+            Ok(body) => {
+                let differences = compare(&primary, &(parts.status, body));
+                if differences.is_empty() {
+                    "match"
+                } else {
+                    tracing::warn!(uri, ?differences, "Shadow response differs");
+                    "mismatch"
+                }
+            }
+            Err(_) => "error",
+        };
+        metrics::increment_counter!("shadow_comparisons_total", "result" => result);
+    });
+
+    Response::from_parts(parts, Body::from(body))
+}
+
+///
+/// What differs between two responses, as the paths of the JSON members
+/// that differ (`$.todos[3].title`), or `$` for bodies that are not JSON.
+///
+fn compare((status, body): &(StatusCode, Bytes), (shadow_status, shadow_body): &(StatusCode, Bytes)) -> Vec<String> {
+    let mut differences = Vec::new();
+    if status != shadow_status {
+        differences.push(format!("status: {} != {}", status, shadow_status));
+    }
+    match (serde_json::from_slice::<Value>(body), serde_json::from_slice::<Value>(shadow_body)) {
+        (Ok(body), Ok(shadow_body)) => diff("$".to_string(), &body, &shadow_body, &mut differences),
+        _ if body != shadow_body => differences.push("$".to_string()),
+        _ => {}
+    }
+    differences.truncate(MAX_LOGGED_DIFFERENCES);
+    differences
+}
+
+///
+/// Adds the paths, under `path`, of the members that differ between `a` and
+/// `b` to `differences`, up to `MAX_LOGGED_DIFFERENCES`.
+///
+pub(crate) fn diff(path: String, a: &Value, b: &Value, differences: &mut Vec<String>) {
+    if differences.len() >= MAX_LOGGED_DIFFERENCES {
+        return;
+    }
+    match (a, b) {
+        (Value::Object(a), Value::Object(b)) => {
+            for (key, value) in a {
+                match b.get(key) {
+                    Some(other) => diff(format!("{}.{}", path, key), value, other, differences),
+                    None => differences.push(format!("{}.{}", path, key)),
+                }
+            }
+            for key in b.keys().filter(|key| !a.contains_key(*key)) {
+                differences.push(format!("{}.{}", path, key));
+            }
+        }
+        (Value::Array(a), Value::Array(b)) if a.len() == b.len() => {
+            for (i, (a, b)) in a.iter().zip(b).enumerate() {
+                diff(format!("{}[{}]", path, i), a, b, differences);
+            }
+        }
+        (a, b) if a != b => differences.push(path),
+        _ => {}
+    }
+}
+
+#[tokio::test]
+async fn shadow_responses_are_compared_without_the_client_noticing() {
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+
+    // for Body::collect
+    use http_body_util::BodyExt;
+    use axum::{routing::get, Json};
+
+    let primary_json = serde_json::json!({ "id": 1, "title": "Buy milk", "tags": ["home", "urgent"] });
+    let shadow_json = serde_json::json!({ "tags": ["home", "later"], "title": "Buy milk", "id": 1, "href": "/todo/1" });
+    let differences = compare(
+        &(StatusCode::OK, Bytes::from(primary_json.to_string())),
+        &(StatusCode::OK, Bytes::from(shadow_json.to_string())),
+    );
+    assert_eq!(differences, vec!["$.tags[1]", "$.href"]);
+
+    let same_but_reordered = serde_json::json!({ "tags": ["home", "urgent"], "title": "Buy milk", "id": 1 });
+    let differences = compare(
+        &(StatusCode::OK, Bytes::from(primary_json.to_string())),
+        &(StatusCode::OK, Bytes::from(same_but_reordered.to_string())),
+    );
+    assert!(differences.is_empty());
+
+    let mirrored = Arc::new(AtomicUsize::new(0));
+    let counter = mirrored.clone();
+    let shadow = Router::new().route(
+        "/todo",
+        get(move || async move {
+            counter.fetch_add(1, Ordering::SeqCst);
+            // A slow shadow must not slow the client down.
+            tokio::time::sleep(Duration::from_secs(60)).await;
+            "Shadow"
+        }),
+    );
+    let app = Router::new()
+        .route("/todo", get(|| async { Json(serde_json::json!({ "id": 1 })) }).post(|| async { "Created" }))
+        .layer(axum::middleware::from_fn_with_state(Shadow::new(shadow, 100.0), shadow_requests));
+
+    let response = tokio::time::timeout(
+        Duration::from_secs(1),
+        app.clone().oneshot(Request::get("/todo").body(Body::empty()).unwrap()),
+    )
+    .await
+    .unwrap()
+    .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = response.into_body().collect().await.unwrap().to_bytes();
+    assert_eq!(body, r#"{"id":1}"#);
+
+    app.clone().oneshot(Request::post("/todo").body(Body::empty()).unwrap()).await.unwrap();
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert_eq!(mirrored.load(Ordering::SeqCst), 1);
+}
@@ -0,0 +1,331 @@
+#![allow(dead_code)]
+
+//!
+//! LOAD SHEDDING
+//! -------------
+//!
+//! Some requests cost far more than others: importing a file of todos holds a
+//! transaction for as long as the upload lasts, and listing todos with their
+//! comments loads every comment of every todo. A burst of them ties up every
+//! connection of the pool, and then even the cheap requests wait, and time
+//! out.
+//!
+//! Backpressure bounds the damage. Each expensive route only runs so many
+//! requests at a time (`ConcurrencyLimitLayer`), and rather than queueing the
+//! rest, where they would wait until their clients give up anyway, it turns
+//! them away at once (`LoadShedLayer`) with `503 Service Unavailable` and a
+//! `Retry-After` header. The client learns straight away that it should back
+//! off, and the server keeps its connections for the requests it accepted.
+//!
+//! The limits are per route, and per instance: 4 instances with a limit of 8
+//! can run 32 imports at once.
+//!
+//! A fixed limit has to be guessed, though, and the right guess changes with
+//! the size of the pool, the hardware, and what else Postgres is busy with.
+//! `AdaptiveLimit` finds it instead, the way TCP finds how fast it can send:
+//! it measures the 95th percentile of the latency of the requests it admits,
+//! and raises the limit by one while that stays under a target (additive
+//! increase), or cuts it by 10% when it does not (multiplicative decrease).
+//! Past the point where Postgres is saturated, more concurrency only means
+//! longer queues, so the limit settles around the concurrency that Postgres
+//! can actually serve within the target.
+//!
+//! To see it at work, give the server a small pool, and more clients than it
+//! can serve:
+//!
+//! ```sh
+//! DATABASE_MAX_CONNECTIONS=4 ADMISSION_LATENCY_TARGET_MS=50 cargo run --release --bin rust-web -- serve
+//! cargo run --release --bin loadgen -- --concurrency 128 --duration 30 ...
+//! curl -s localhost:3001/metrics | grep admission_
+//! ```
+//!
+//! With admission control, the latencies that loadgen reports stay around the
+//! target, and the rest of the requests fail fast with `503`. Set
+//! `ADMISSION_LATENCY_TARGET_MS` very high to switch it off, and watch the
+//! latencies grow with the queue instead, until requests time out waiting for
+//! a connection.
+//!
+
+use std::{
+    future::Ready,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use axum::{
+    error_handling::HandleErrorLayer,
+    extract::{Request, State},
+    http::{header, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    BoxError,
+};
+use tower::{
+    layer::util::{Identity, Stack},
+    limit::ConcurrencyLimitLayer,
+    load_shed::{error::Overloaded, LoadShedLayer},
+    ServiceBuilder,
+};
+
+use crate::problem::Problem;
+
+///
+/// How long clients are told to wait before retrying, in seconds.
+///
+pub const RETRY_AFTER_SECS: u64 = 1;
+
+type OverloadedHandler = fn(BoxError) -> Ready<Response>;
+
+pub type ShedLoadLayer =
+    ServiceBuilder<Stack<ConcurrencyLimitLayer, Stack<LoadShedLayer, Stack<HandleErrorLayer<OverloadedHandler, ()>, Identity>>>>;
+
+///
+/// Runs at most `max_concurrent` requests at a time on each route it is
+/// applied to, and answers any more with `503`.
+///
+/// ```ignore
+/// Routes::new().post("/todo/import.ndjson", import_todos).layer(shed_load(8))
+/// ```
+///
+pub fn shed_load(max_concurrent: usize) -> ShedLoadLayer {
+    ServiceBuilder::new()
+        .layer(HandleErrorLayer::new(overloaded as OverloadedHandler))
+        .layer(LoadShedLayer::new())
+        .layer(ConcurrencyLimitLayer::new(max_concurrent))
+}
+
+fn overloaded(error: BoxError) -> Ready<Response> {
+    let response = if error.is::<Overloaded>() {
+        too_busy()
+    } else {
+        Problem::new(StatusCode::INTERNAL_SERVER_ERROR).into_response()
+    };
+    std::future::ready(response)
+}
+
+fn too_busy() -> Response {
+    (
+        [(header::RETRY_AFTER, RETRY_AFTER_SECS.to_string())],
+        Problem::new(StatusCode::SERVICE_UNAVAILABLE).detail("The server is too busy, please try again later"),
+    )
+        .into_response()
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct AdaptiveConfig {
+    ///
+    /// The 95th percentile latency to stay under.
+    ///
+    pub target: Duration,
+    pub min_limit: usize,
+    pub max_limit: usize,
+    ///
+    /// How many latencies are measured before each adjustment of the limit.
+    ///
+    pub window: usize,
+}
+
+impl AdaptiveConfig {
+    pub fn new(target: Duration, max_limit: usize) -> Self {
+        AdaptiveConfig { target, min_limit: 1, max_limit, window: 50 }
+    }
+}
+
+///
+/// A concurrency limit that adjusts itself to keep the 95th percentile latency
+/// under a target. It starts at its maximum. Clones share the same limit.
+///
+#[derive(Clone)]
+pub struct AdaptiveLimit {
+    config: AdaptiveConfig,
+    state: Arc<Mutex<LimitState>>,
+}
+
+struct LimitState {
+    limit: f64,
+    in_flight: usize,
+    latencies: Vec<Duration>,
+}
+
+impl AdaptiveLimit {
+    pub fn new(config: AdaptiveConfig) -> Self {
+        let state = LimitState {
+            limit: config.max_limit as f64,
+            in_flight: 0,
+            latencies: Vec::with_capacity(config.window),
+        };
+        AdaptiveLimit { config, state: Arc::new(Mutex::new(state)) }
+    }
+
+    pub fn limit(&self) -> usize {
+        self.state.lock().unwrap().limit as usize
+    }
+
+    fn try_admit(&self) -> Option<Admitted> {
+        let mut state = self.state.lock().unwrap();
+        if state.in_flight >= state.limit as usize {
+            return None;
+        }
+        state.in_flight += 1;
+        Some(Admitted { limit: self.clone() })
+    }
+
+    fn record(&self, latency: Duration) {
+        let mut state = self.state.lock().unwrap();
+        state.latencies.push(latency);
+        if state.latencies.len() < self.config.window {
+            return;
+        }
+
+        let mut latencies = std::mem::replace(&mut state.latencies, Vec::with_capacity(self.config.window));
+        latencies.sort();
+        let p95 = latencies[(latencies.len() * 95).div_ceil(100) - 1];
+        state.limit = if p95 > self.config.target {
+            (state.limit * 0.9).max(self.config.min_limit as f64)
+        } else {
+            (state.limit + 1.0).min(self.config.max_limit as f64)
+        };
+
+        metrics::gauge!("admission_latency_p95_seconds", p95.as_secs_f64());
+        metrics::gauge!("admission_limit", state.limit.floor());
+    }
+}
+
+///
+/// A request counted against the limit, until it is dropped.
+///
+struct Admitted {
+    limit: AdaptiveLimit,
+}
+
+impl Drop for Admitted {
+    fn drop(&mut self) {
+        self.limit.state.lock().unwrap().in_flight -= 1;
+    }
+}
+
+///
+/// Turns away requests over the adaptive limit with `503`, and measures the
+/// latency of the others.
+///
+/// ```ignore
+/// router.layer(axum::middleware::from_fn_with_state(limit, admission_control))
+/// ```
+///
+pub async fn admission_control(State(limit): State<AdaptiveLimit>, request: Request, next: Next) -> Response {
+    let Some(_admitted) = limit.try_admit() else {
+        metrics::increment_counter!("admission_rejected_total");
+        return too_busy();
+    };
+    let start = tokio::time::Instant::now();
+    let response = next.run(request).await;
+    limit.record(start.elapsed());
+    response
+}
+
+#[tokio::test]
+async fn requests_over_the_limit_are_shed() {
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+
+    /// for ServiceExt::oneshot
+    use tower::util::ServiceExt;
+    use axum::body::Body;
+    use tokio::sync::Semaphore;
+
+    use crate::app::Routes;
+
+    // Slow requests wait for a permit, and there are none until the test says so.
+    let gate = Arc::new(Semaphore::new(0));
+    let started = Arc::new(AtomicUsize::new(0));
+    let (waiting, handling) = (gate.clone(), started.clone());
+    let app = Routes::<()>::new()
+        .get("/slow", move || async move {
+            handling.fetch_add(1, Ordering::SeqCst);
+            drop(waiting.acquire().await.unwrap())
+        })
+        .layer(shed_load(2))
+        .get("/cheap", || async {})
+        .into_router()
+        // Until the state is provided, handlers are turned into services, and
+        // layered, anew for every request, each with a limit of its own.
+        .with_state(());
+    let send = |uri: &'static str| {
+        let app = app.clone();
+        tokio::spawn(async move { app.oneshot(Request::get(uri).body(Body::empty()).unwrap()).await.unwrap() })
+    };
+
+    let slow = [send("/slow"), send("/slow")];
+    while started.load(Ordering::SeqCst) < 2 {
+        tokio::task::yield_now().await;
+    }
+
+    let response = send("/slow").await.unwrap();
+    assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(response.headers()[header::RETRY_AFTER], "1");
+    assert_eq!(send("/cheap").await.unwrap().status(), StatusCode::OK);
+
+    gate.add_permits(3);
+    for response in slow {
+        assert_eq!(response.await.unwrap().status(), StatusCode::OK);
+    }
+    assert_eq!(send("/slow").await.unwrap().status(), StatusCode::OK);
+}
+
+#[tokio::test(start_paused = true)]
+async fn the_adaptive_limit_keeps_latency_near_its_target() {
+    /// for ServiceExt::oneshot
+    use tower::util::ServiceExt;
+    use axum::{body::Body, routing::get, Router};
+    use tokio::{sync::Semaphore, time::Instant};
+
+    // Postgres, with a pool of 4 connections and queries of 20ms: 64 clients
+    // at once would wait 16 queries in line, 320ms.
+    let pool = Arc::new(Semaphore::new(4));
+    let limit = AdaptiveLimit::new(AdaptiveConfig::new(Duration::from_millis(100), 64));
+    let app = Router::new()
+        .route(
+            "/",
+            get(move || async move {
+                let _connection = pool.acquire().await.unwrap();
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }),
+        )
+        .layer(axum::middleware::from_fn_with_state(limit.clone(), admission_control));
+
+    // The clock is paused, so 10 simulated seconds pass in no time.
+    let start = Instant::now();
+    let clients = (0..64).map(|_| {
+        let app = app.clone();
+        tokio::spawn(async move {
+            let (mut latencies, mut shed) = (Vec::new(), 0);
+            while start.elapsed() < Duration::from_secs(10) {
+                let sent = Instant::now();
+                let response = app.clone().oneshot(Request::get("/").body(Body::empty()).unwrap()).await.unwrap();
+                if response.status() == StatusCode::SERVICE_UNAVAILABLE {
+                    shed += 1;
+                    tokio::time::sleep(Duration::from_millis(50)).await;
+                } else if start.elapsed() > Duration::from_secs(5) {
+                    // Once the limit has had time to settle.
+                    latencies.push(sent.elapsed());
+                }
+            }
+            (latencies, shed)
+        })
+    });
+
+    let (mut latencies, mut shed) = (Vec::new(), 0);
+    for client in futures::future::join_all(clients).await {
+        let (client_latencies, client_shed) = client.unwrap();
+        latencies.extend(client_latencies);
+        shed += client_shed;
+    }
+    latencies.sort();
+    let p95 = latencies[latencies.len() * 95 / 100];
+
+    assert!(shed > 0);
+    assert!(limit.limit() < 64, "{}", limit.limit());
+    assert!(p95 <= Duration::from_millis(150), "{:?}", p95);
+}
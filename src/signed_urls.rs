@@ -0,0 +1,202 @@
+#![allow(dead_code)]
+
+//!
+//! SIGNED URLS
+//! -----------
+//!
+//! Sometimes a user wants to hand something to someone without an account:
+//! a read-only view of a todo, or one of its attachments. A signed URL does
+//! that without storing anything: the server appends an expiry and an HMAC
+//! of the path and query to the URL, and later accepts the URL, without
+//! authentication, as long as neither was changed and it has not expired.
+//!
+//! ```text
+//! /shared/todo/42?user=7&expires=1767225600&signature=3q2-7w...
+//! ```
+//!
+//! Everything that decides what the URL gives access to, such as the user
+//! whose view of the todo it shows, has to be in the signed part. The
+//! `SignedUrl` extractor checks the signature of the request's URL, and
+//! handlers then read the rest of the query as usual.
+//!
+//! Links are signed with a key derived from the JWT secret, so that rotating
+//! the secret revokes them all. The servers that sign and verify them may not
+//! agree exactly on the time, so a link stays valid for `CLOCK_SKEW` after
+//! it expires.
+//!
+
+use axum::{
+    async_trait,
+    extract::{FromRef, FromRequestParts, OriginalUri},
+    http::{request::Parts, StatusCode},
+    response::{IntoResponse, Response},
+};
+use base64::Engine as _;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use time::{Duration, OffsetDateTime};
+
+use crate::clock::SharedClock;
+use crate::problem::Problem;
+
+const BASE64_URL: base64::engine::GeneralPurpose = base64::engine::general_purpose::URL_SAFE_NO_PAD;
+
+///
+/// How long after its expiry a link is still accepted, for servers whose
+/// clocks are behind the one that signed it.
+///
+pub const CLOCK_SKEW: Duration = Duration::seconds(30);
+
+///
+/// The longest that a link can be valid for. A link cannot be revoked on its
+/// own, so it should not outlive the reason it was shared.
+///
+pub const MAX_TTL: Duration = Duration::days(7);
+
+#[derive(Clone)]
+pub struct UrlSigner {
+    mac: Hmac<Sha256>,
+    clock: SharedClock,
+}
+
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub struct SignedLink {
+    pub url: String,
+    #[serde(with = "time::serde::rfc3339")]
+    pub expires_at: OffsetDateTime,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum SignedUrlError {
+    Missing,
+    Invalid,
+    Expired(OffsetDateTime),
+}
+
+impl IntoResponse for SignedUrlError {
+    fn into_response(self) -> Response {
+        match self {
+            SignedUrlError::Missing => {
+                Problem::new(StatusCode::FORBIDDEN).detail("The URL is not signed").into_response()
+            }
+            SignedUrlError::Invalid => {
+                Problem::new(StatusCode::FORBIDDEN).detail("The URL's signature is invalid").into_response()
+            }
+            SignedUrlError::Expired(expires_at) => Problem::new(StatusCode::GONE)
+                .detail("The link has expired")
+                .with("expired_at", expires_at.unix_timestamp())
+                .into_response(),
+        }
+    }
+}
+
+impl UrlSigner {
+    pub fn from_secret(secret: &[u8], clock: SharedClock) -> Self {
+        // A key of its own, so that a signature of a URL is never a valid
+        // signature of anything else made with the same secret.
+        let mut derive = Hmac::<Sha256>::new_from_slice(secret).unwrap();
+        derive.update(b"signed urls");
+        let key = derive.finalize().into_bytes();
+        UrlSigner { mac: Hmac::new_from_slice(&key).unwrap(), clock }
+    }
+
+    ///
+    /// Signs a path, with or without a query, for `ttl` from now, at most
+    /// `MAX_TTL`. The signature covers the path and the whole query.
+    ///
+    pub fn sign(&self, path: &str, ttl: Duration) -> SignedLink {
+        let expires_at = (self.clock.now() + ttl.min(MAX_TTL)).replace_nanosecond(0).unwrap();
+        let separator = if path.contains('?') { '&' } else { '?' };
+        let unsigned = format!("{}{}expires={}", path, separator, expires_at.unix_timestamp());
+        let signature = BASE64_URL.encode(self.signature(&unsigned));
+        SignedLink { url: format!("{}&signature={}", unsigned, signature), expires_at }
+    }
+
+    ///
+    /// Checks a path and query made by `sign`, and returns when it expires.
+    ///
+    pub fn verify(&self, path_and_query: &str) -> Result<OffsetDateTime, SignedUrlError> {
+        let (unsigned, signature) = path_and_query.rsplit_once("&signature=").ok_or(SignedUrlError::Missing)?;
+        let signature = BASE64_URL.decode(signature).map_err(|_| SignedUrlError::Invalid)?;
+        let mut mac = self.mac.clone();
+        mac.update(unsigned.as_bytes());
+        mac.verify_slice(&signature).map_err(|_| SignedUrlError::Invalid)?;
+
+        // Signed, so `expires` is the one that `sign` appended last.
+        let expires_at = unsigned
+            .rsplit_once("expires=")
+            .and_then(|(_, expires)| expires.parse().ok())
+            .and_then(|expires| OffsetDateTime::from_unix_timestamp(expires).ok())
+            .ok_or(SignedUrlError::Invalid)?;
+        if self.clock.now() > expires_at + CLOCK_SKEW {
+            return Err(SignedUrlError::Expired(expires_at));
+        }
+        Ok(expires_at)
+    }
+
+    fn signature(&self, unsigned: &str) -> Vec<u8> {
+        let mut mac = self.mac.clone();
+        mac.update(unsigned.as_bytes());
+        mac.finalize().into_bytes().to_vec()
+    }
+}
+
+///
+/// Rejects requests whose URL was not signed by `UrlSigner::sign`, or has
+/// expired. It checks the URL as the client sent it, even in nested routers.
+///
+#[derive(Debug)]
+pub struct SignedUrl {
+    pub expires_at: OffsetDateTime,
+}
+
+#[async_trait]
+impl<S> FromRequestParts<S> for SignedUrl
+where
+    UrlSigner: FromRef<S>,
+    S: Send + Sync,
+{
+    type Rejection = SignedUrlError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let uri = match parts.extensions.get::<OriginalUri>() {
+            Some(OriginalUri(uri)) => uri,
+            None => &parts.uri,
+        };
+        let path_and_query = uri.path_and_query().map(|p| p.as_str()).ok_or(SignedUrlError::Missing)?;
+        let expires_at = UrlSigner::from_ref(state).verify(path_and_query)?;
+        Ok(SignedUrl { expires_at })
+    }
+}
+
+#[test]
+fn signed_urls_expire_and_cannot_be_changed() {
+    use std::sync::Arc;
+    use time::macros::datetime;
+
+    use crate::clock::{Clock, FakeClock};
+
+    let clock = Arc::new(FakeClock::new(datetime!(2026-01-01 12:00:00.5 UTC)));
+    let signer = UrlSigner::from_secret(b"secret", clock.clone());
+
+    let link = signer.sign("/shared/todo/42?user=7", Duration::hours(1));
+    assert_eq!(link.expires_at, datetime!(2026-01-01 13:00:00 UTC));
+    assert!(link.url.starts_with("/shared/todo/42?user=7&expires=1767272400&signature="));
+    assert_eq!(signer.verify(&link.url), Ok(link.expires_at));
+
+    assert_eq!(signer.verify(&link.url.replace("user=7", "user=8")), Err(SignedUrlError::Invalid));
+    assert_eq!(signer.verify(&link.url.replace("/42", "/43")), Err(SignedUrlError::Invalid));
+    assert_eq!(signer.verify("/shared/todo/42?user=7"), Err(SignedUrlError::Missing));
+    let other = UrlSigner::from_secret(b"other secret", clock.clone());
+    assert_eq!(other.verify(&link.url), Err(SignedUrlError::Invalid));
+
+    // Still valid within the clock skew, and gone after it.
+    clock.advance(Duration::seconds(3600 + 29));
+    assert!(signer.verify(&link.url).is_ok());
+    clock.advance(Duration::seconds(2));
+    assert_eq!(signer.verify(&link.url), Err(SignedUrlError::Expired(link.expires_at)));
+
+    let link = signer.sign("/shared", Duration::days(30));
+    assert!(link.url.starts_with("/shared?expires="));
+    assert_eq!(link.expires_at, (clock.now() + MAX_TTL).replace_nanosecond(0).unwrap());
+}
@@ -0,0 +1,204 @@
+#![allow(dead_code)]
+
+//!
+//! SYSTEMD
+//! -------
+//!
+//! On most Linux servers, services are run by systemd, and two of its
+//! features change how a service starts.
+//!
+//! Socket activation: systemd binds the listening sockets itself, described
+//! by a `.socket` unit, and hands them over to the service when it starts.
+//! Connections that arrive while the service starts, or restarts, wait in
+//! the socket's backlog instead of being refused, and the service never
+//! needs the privileges to bind a low port.
+//!
+//! ```ini
+//! # /etc/systemd/system/rust-web.socket
+//! [Socket]
+//! ListenStream=0.0.0.0:80
+//! # The admin router, optionally
+//! ListenStream=127.0.0.1:3001
+//!
+//! [Install]
+//! WantedBy=sockets.target
+//! ```
+//!
+//! The sockets are passed as file descriptors 3, 4, and so on, in the order
+//! of the `.socket` unit, with their number in `LISTEN_FDS`, and the process
+//! they are meant for in `LISTEN_PID`. The first one serves the public
+//! router, in place of `BIND_ADDR` or `BIND_SOCKET`, and the second one, if
+//! any, the admin router, in place of `ADMIN_BIND_ADDR`.
+//!
+//! Readiness notification: with `Type=notify`, systemd considers the service
+//! started once it says so, by sending `READY=1` to the datagram socket in
+//! `NOTIFY_SOCKET`, and not as soon as the process is running. Units that
+//! come after it, and `systemctl start`, wait for that. The todo app sends
+//! it once its migrations have run, the database has answered a health
+//! check, and its listeners are bound, then `STOPPING=1` when it starts
+//! shutting down.
+//!
+//! ```ini
+//! # /etc/systemd/system/rust-web.service
+//! [Service]
+//! Type=notify
+//! ExecStart=/usr/local/bin/rust-web serve
+//! EnvironmentFile=/etc/rust-web.env
+//! ```
+//!
+//! Outside of systemd, none of these variables are set, and both features
+//! stay out of the way.
+//!
+
+use std::{
+    ffi::OsStr,
+    io,
+    os::{
+        fd::{FromRawFd, OwnedFd, RawFd},
+        unix::{ffi::OsStrExt, net::UnixDatagram},
+    },
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+/// The first file descriptor passed by systemd: 0, 1 and 2 are stdio.
+const LISTEN_FDS_START: RawFd = 3;
+
+/// Whether the passed file descriptors were taken already, by `listen_fds`.
+static TAKEN: AtomicBool = AtomicBool::new(false);
+
+///
+/// A listening socket passed by systemd.
+///
+#[derive(Debug)]
+pub enum ActivatedListener {
+    Tcp(std::net::TcpListener),
+    Unix(std::os::unix::net::UnixListener),
+}
+
+///
+/// The listening sockets passed by systemd, in the order of the `.socket`
+/// unit, or none if the process was not socket activated. They can only be
+/// taken once, so later calls return none either way.
+///
+pub fn listen_fds() -> io::Result<Vec<ActivatedListener>> {
+    if TAKEN.swap(true, Ordering::SeqCst) {
+        return Ok(Vec::new());
+    }
+
+    let count = passed_fds(
+        std::env::var("LISTEN_PID").ok().as_deref(),
+        std::env::var("LISTEN_FDS").ok().as_deref(),
+        std::process::id(),
+    );
+    // Child processes must not take the sockets for theirs.
+    for name in ["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"] {
+        std::env::remove_var(name);
+    }
+
+    (0..count as RawFd)
+        // SAFETY: systemd passed these to this process, and `TAKEN` makes
+        // sure that they are only owned once.
+        .map(|i| adopt(unsafe { OwnedFd::from_raw_fd(LISTEN_FDS_START + i) }))
+        .collect()
+}
+
+///
+/// The number of file descriptors passed, if they were passed to `own_pid`:
+/// the variables are inherited by child processes, which must ignore them.
+///
+fn passed_fds(listen_pid: Option<&str>, listen_fds: Option<&str>, own_pid: u32) -> usize {
+    match (listen_pid.map(str::parse::<u32>), listen_fds.map(str::parse::<usize>)) {
+        (Some(Ok(pid)), Some(Ok(fds))) if pid == own_pid => fds,
+        _ => 0,
+    }
+}
+
+///
+/// Tells a TCP socket from a Unix one: only the latter has an address that
+/// `UnixListener` can read.
+///
+fn adopt(fd: OwnedFd) -> io::Result<ActivatedListener> {
+    let unix = std::os::unix::net::UnixListener::from(fd);
+    if unix.local_addr().is_ok() {
+        unix.set_nonblocking(true)?;
+        return Ok(ActivatedListener::Unix(unix));
+    }
+
+    let tcp = std::net::TcpListener::from(OwnedFd::from(unix));
+    tcp.local_addr()?;
+    tcp.set_nonblocking(true)?;
+    Ok(ActivatedListener::Tcp(tcp))
+}
+
+///
+/// Sends `state` to systemd, such as `READY=1` or `STOPPING=1`, and returns
+/// whether there was a systemd to send it to.
+///
+pub fn notify(state: &str) -> io::Result<bool> {
+    match std::env::var_os("NOTIFY_SOCKET") {
+        Some(socket) => notify_socket(&socket, state).map(|()| true),
+        None => Ok(false),
+    }
+}
+
+///
+/// Like `notify`, but logs failures instead of returning them: a service
+/// that runs fine should not stop because systemd did not hear about it.
+///
+pub fn notify_or_log(state: &str) {
+    if let Err(e) = notify(state) {
+        eprintln!("Failed to notify systemd of {}: {}", state, e);
+    }
+}
+
+fn notify_socket(socket: &OsStr, state: &str) -> io::Result<()> {
+    let datagram = UnixDatagram::unbound()?;
+    match socket.as_bytes().strip_prefix(b"@") {
+        // A socket in the abstract namespace, which has no file.
+        Some(name) => {
+            use std::os::linux::net::SocketAddrExt;
+            let address = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+            datagram.send_to_addr(state.as_bytes(), &address)?
+        }
+        None => datagram.send_to(state.as_bytes(), socket)?,
+    };
+    Ok(())
+}
+
+#[test]
+fn listeners_passed_by_systemd_are_adopted() {
+    assert_eq!(passed_fds(Some("42"), Some("2"), 42), 2);
+    // Meant for the parent process.
+    assert_eq!(passed_fds(Some("41"), Some("2"), 42), 0);
+    assert_eq!(passed_fds(None, None, 42), 0);
+
+    let tcp = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = tcp.local_addr().unwrap();
+    match adopt(OwnedFd::from(tcp)).unwrap() {
+        ActivatedListener::Tcp(listener) => assert_eq!(listener.local_addr().unwrap(), address),
+        other => panic!("Expected a TCP listener, got {:?}", other),
+    }
+
+    let path = std::env::temp_dir().join(format!("rust-web-{}.sock", uuid::Uuid::new_v4()));
+    let unix = std::os::unix::net::UnixListener::bind(&path).unwrap();
+    match adopt(OwnedFd::from(unix)).unwrap() {
+        ActivatedListener::Unix(listener) => {
+            assert_eq!(listener.local_addr().unwrap().as_pathname(), Some(path.as_path()))
+        }
+        other => panic!("Expected a Unix listener, got {:?}", other),
+    }
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn readiness_is_sent_to_the_notify_socket() {
+    let path = std::env::temp_dir().join(format!("rust-web-{}.notify", uuid::Uuid::new_v4()));
+    let systemd = UnixDatagram::bind(&path).unwrap();
+
+    notify_socket(path.as_os_str(), "READY=1\nSTATUS=Serving todos").unwrap();
+
+    let mut buffer = [0; 64];
+    let received = systemd.recv(&mut buffer).unwrap();
+    assert_eq!(&buffer[..received], b"READY=1\nSTATUS=Serving todos");
+    std::fs::remove_file(&path).unwrap();
+}
@@ -0,0 +1,620 @@
+#![allow(dead_code)]
+
+//!
+//! TAKEOUT
+//! -------
+//!
+//! `GET /users/:id/export` hands users a copy of their data, as a ZIP archive
+//! that any operating system can open. Users can export their own data, and
+//! admins anyone's. The archive holds:
+//!
+//! - `user.json`, the account itself.
+//! - `todos.json` and `todos.csv`, the todos that the user owns, the second
+//!   for spreadsheets.
+//! - `comments.json`, the comments on those todos.
+//! - `attachments.json`, what is known about the files attached to them. The
+//!   files themselves can be downloaded one by one.
+//!
+//! An export can be large, and is never held in memory: the rows are streamed
+//! from Postgres, written into the archive as they arrive, and sent to the
+//! client in chunks of `CHUNK_SIZE`, from a task that waits whenever the
+//! client is slower than the database. That rules out knowing the size or the
+//! checksum of a file before writing it, which ZIP headers normally contain.
+//! ZIP allows putting them in a "data descriptor" after the data instead, and
+//! in the central directory at the end, which is what `ZipStream` does.
+//! Files are stored without compression, and an archive cannot reach 4 GB,
+//! since that would take ZIP64.
+//!
+//! When the todos are kept elsewhere (see `TODO_REPO`), they come from the
+//! `export_user` of their repo instead, a page at a time, in the order of
+//! their pages, and so do their comments, in id order within each page.
+//!
+//! The response has already started when the rows are read, so a database
+//! error can no longer change its status. The body fails instead, and the
+//! client gets a truncated download rather than an archive that looks whole.
+//!
+
+use std::io;
+
+use axum::{
+    async_trait,
+    body::{Body, Bytes},
+    extract::{FromRef, Path, State},
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+};
+use futures::{stream::BoxStream, StreamExt, TryStreamExt};
+use sqlx::{Pool, Postgres};
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+use tokio::sync::mpsc;
+
+use crate::app::Routes;
+use crate::auth::{Claims, JwtKeys};
+use crate::clock::SharedClock;
+use crate::persistence::{Comment, RepoError, SharedTodoRepo, Todo};
+use crate::users::{UserRepo, UserState};
+
+///
+/// How many bytes of the archive are sent at a time.
+///
+const CHUNK_SIZE: usize = 64 * 1024;
+
+#[derive(Debug, serde::Deserialize, serde::Serialize)]
+pub struct ExportTodo {
+    pub id: i64,
+    pub title: String,
+    pub description: String,
+    pub status: String,
+    pub priority: i32,
+    #[serde(with = "time::serde::rfc3339")]
+    pub created_at: OffsetDateTime,
+    #[serde(with = "time::serde::rfc3339::option")]
+    pub due_at: Option<OffsetDateTime>,
+    #[serde(with = "time::serde::rfc3339::option")]
+    pub completed_at: Option<OffsetDateTime>,
+    pub parent_id: Option<i64>,
+    pub list_id: Option<i64>,
+    pub metadata: serde_json::Value,
+}
+
+impl From<Todo> for ExportTodo {
+    fn from(todo: Todo) -> Self {
+        ExportTodo {
+            id: todo.id,
+            title: todo.title,
+            description: todo.description,
+            status: todo.status.as_str().to_string(),
+            priority: todo.priority,
+            created_at: todo.created_at,
+            due_at: todo.due_at,
+            completed_at: todo.completed_at,
+            parent_id: todo.parent_id,
+            list_id: todo.list_id,
+            metadata: todo.metadata,
+        }
+    }
+}
+
+#[derive(Debug, serde::Deserialize, serde::Serialize)]
+pub struct ExportComment {
+    pub id: i64,
+    pub todo_id: i64,
+    pub body: String,
+    #[serde(with = "time::serde::rfc3339")]
+    pub created_at: OffsetDateTime,
+}
+
+impl From<Comment> for ExportComment {
+    fn from(comment: Comment) -> Self {
+        ExportComment { id: comment.id, todo_id: comment.todo_id, body: comment.body, created_at: comment.created_at }
+    }
+}
+
+#[derive(Debug, serde::Deserialize, serde::Serialize)]
+pub struct ExportAttachment {
+    pub id: i64,
+    pub todo_id: i64,
+    pub filename: String,
+    pub content_type: String,
+    pub size: i64,
+    pub sha256: String,
+    #[serde(with = "time::serde::rfc3339")]
+    pub created_at: OffsetDateTime,
+}
+
+///
+/// Streams of the rows of a user's data, in id order.
+///
+#[async_trait]
+pub trait TakeoutRepo: Send + Sync {
+    fn todos(&self, user_id: i64) -> BoxStream<'_, Result<ExportTodo, sqlx::Error>>;
+    fn comments(&self, user_id: i64) -> BoxStream<'_, Result<ExportComment, sqlx::Error>>;
+    fn attachments(&self, user_id: i64) -> BoxStream<'_, Result<ExportAttachment, sqlx::Error>>;
+}
+
+#[derive(Clone)]
+pub struct TakeoutRepoPostgres {
+    pool: Pool<Postgres>,
+}
+
+impl TakeoutRepoPostgres {
+    pub fn new(pool: Pool<Postgres>) -> Self {
+        TakeoutRepoPostgres { pool }
+    }
+}
+
+#[async_trait]
+impl TakeoutRepo for TakeoutRepoPostgres {
+    fn todos(&self, user_id: i64) -> BoxStream<'_, Result<ExportTodo, sqlx::Error>> {
+        sqlx::query_as!(
+            ExportTodo,
+            r#"SELECT id, title, description, status::TEXT AS "status!", priority, created_at, due_at, completed_at,
+                      parent_id, list_id, metadata
+               FROM todos WHERE owner_id = $1 ORDER BY id"#,
+            user_id
+        )
+        .fetch(&self.pool)
+    }
+    fn comments(&self, user_id: i64) -> BoxStream<'_, Result<ExportComment, sqlx::Error>> {
+        sqlx::query_as!(
+            ExportComment,
+            "SELECT comments.id, comments.todo_id, comments.body, comments.created_at
+             FROM comments JOIN todos ON todos.id = comments.todo_id
+             WHERE todos.owner_id = $1 ORDER BY comments.id",
+            user_id
+        )
+        .fetch(&self.pool)
+    }
+    fn attachments(&self, user_id: i64) -> BoxStream<'_, Result<ExportAttachment, sqlx::Error>> {
+        sqlx::query_as!(
+            ExportAttachment,
+            "SELECT attachments.id, attachments.todo_id, attachments.filename, attachments.content_type,
+                    attachments.size, attachments.sha256, attachments.created_at
+             FROM attachments JOIN todos ON todos.id = attachments.todo_id
+             WHERE todos.owner_id = $1 ORDER BY attachments.id",
+            user_id
+        )
+        .fetch(&self.pool)
+    }
+}
+
+#[derive(Clone)]
+pub struct TakeoutState<T: TakeoutRepo> {
+    pub repo: T,
+    pub clock: SharedClock,
+    /// The repo of the todos, if they are kept elsewhere than in Postgres.
+    pub todos: Option<SharedTodoRepo>,
+}
+
+pub fn takeout_routes<S, U, T>() -> Routes<S>
+where
+    U: UserRepo + Clone + 'static,
+    T: TakeoutRepo + Clone + 'static,
+    UserState<U>: FromRef<S>,
+    TakeoutState<T>: FromRef<S>,
+    JwtKeys: FromRef<S>,
+    S: Clone + Send + Sync + 'static,
+{
+    Routes::new().get("/users/:id/export", export_user::<U, T>)
+}
+
+async fn export_user<U: UserRepo, T: TakeoutRepo + Clone + 'static>(
+    Claims { sub: caller, .. }: Claims,
+    Path(user_id): Path<i64>,
+    State(UserState { repo: users }): State<UserState<U>>,
+    State(TakeoutState { repo, clock, todos }): State<TakeoutState<T>>,
+) -> Result<Response, StatusCode> {
+    if caller != user_id && !users.get_user(caller).await.is_some_and(|user| user.is_admin) {
+        return Err(StatusCode::FORBIDDEN);
+    }
+    let user = users.get_user(user_id).await.ok_or(StatusCode::NOT_FOUND)?;
+    tracing::info!(target: "audit", user_id, exported_by = caller, "Account exported");
+
+    let now = clock.now();
+    let (out, chunks) = mpsc::channel(4);
+    tokio::spawn(async move {
+        let zip = ZipStream::new(out.clone(), now);
+        let profile = serde_json::json!({
+            "id": user.id,
+            "name": user.name,
+            "email": user.email,
+            "is_admin": user.is_admin,
+            "created_at": user.created_at.format(&Rfc3339).unwrap(),
+        });
+        if let Err(error) = write_export(zip, &repo, todos.as_ref(), user_id, &profile).await {
+            tracing::error!(user_id, %error, "Failed to export an account");
+            // The receiver is gone if the client hung up, and then there is
+            // no one left to tell.
+            let _ = out.send(Err(error)).await;
+        }
+    });
+
+    let body = futures::stream::unfold(chunks, |mut chunks| async move {
+        chunks.recv().await.map(|chunk| (chunk, chunks))
+    });
+    let filename = format!("takeout-{}-{}.zip", user_id, now.date());
+    Ok((
+        [
+            (header::CONTENT_TYPE, "application/zip".to_string()),
+            (header::CONTENT_DISPOSITION, format!("attachment; filename=\"{}\"", filename)),
+            (header::CACHE_CONTROL, "no-store".to_string()),
+        ],
+        Body::from_stream(body),
+    )
+        .into_response())
+}
+
+async fn write_export<T: TakeoutRepo>(
+    mut zip: ZipStream,
+    repo: &T,
+    todos: Option<&SharedTodoRepo>,
+    user_id: i64,
+    profile: &serde_json::Value,
+) -> io::Result<()> {
+    zip.start_file("user.json").await?;
+    zip.write(&serde_json::to_vec_pretty(profile).unwrap()).await?;
+    zip.finish_file().await?;
+
+    write_json_array(&mut zip, "todos.json", export_todos(repo, todos, user_id)).await?;
+
+    zip.start_file("todos.csv").await?;
+    zip.write(b"id,title,description,status,priority,created_at,due_at,completed_at,parent_id,list_id\r\n").await?;
+    let mut rows = export_todos(repo, todos, user_id);
+    while let Some(todo) = rows.next().await {
+        zip.write(todo_csv_row(&todo?).as_bytes()).await?;
+    }
+    zip.finish_file().await?;
+
+    write_json_array(&mut zip, "comments.json", export_comments(repo, todos, user_id)).await?;
+    write_json_array(&mut zip, "attachments.json", repo.attachments(user_id).map_err(io::Error::other).boxed()).await?;
+    zip.finish().await
+}
+
+///
+/// The todos of the user, from the repo of the todos if they are kept
+/// elsewhere than in Postgres.
+///
+fn export_todos<'a, T: TakeoutRepo>(
+    repo: &'a T,
+    todos: Option<&'a SharedTodoRepo>,
+    user_id: i64,
+) -> BoxStream<'a, io::Result<ExportTodo>> {
+    match todos {
+        Some(todos) => todos.export_user(user_id).map_ok(ExportTodo::from).map_err(repo_error).boxed(),
+        None => repo.todos(user_id).map_err(io::Error::other).boxed(),
+    }
+}
+
+///
+/// The comments on the todos of the user, from the repo of the todos if they
+/// are kept elsewhere than in Postgres, with one query per page of todos.
+///
+fn export_comments<'a, T: TakeoutRepo>(
+    repo: &'a T,
+    todos: Option<&'a SharedTodoRepo>,
+    user_id: i64,
+) -> BoxStream<'a, io::Result<ExportComment>> {
+    const PAGE_SIZE: usize = 500;
+    match todos {
+        Some(todos) => todos
+            .export_user(user_id)
+            .chunks(PAGE_SIZE)
+            .then(move |page| async move {
+                let ids = page.into_iter().map(|todo| todo.map(|todo| todo.id)).collect::<Result<Vec<_>, _>>()?;
+                todos.get_comments_of(user_id, &ids).await
+            })
+            .map_ok(|comments| futures::stream::iter(comments.into_iter().map(|comment| Ok(comment.into()))))
+            .try_flatten()
+            .map_err(repo_error)
+            .boxed(),
+        None => repo.comments(user_id).map_err(io::Error::other).boxed(),
+    }
+}
+
+///
+/// A `RepoError` as the error of the body, which can only be an `io::Error`.
+///
+fn repo_error(error: RepoError) -> io::Error {
+    io::Error::other(error.to_string())
+}
+
+async fn write_json_array<T: serde::Serialize>(
+    zip: &mut ZipStream,
+    name: &str,
+    mut rows: BoxStream<'_, io::Result<T>>,
+) -> io::Result<()> {
+    zip.start_file(name).await?;
+    zip.write(b"[").await?;
+    let mut first = true;
+    while let Some(row) = rows.next().await {
+        let row = serde_json::to_vec(&row?).unwrap();
+        zip.write(if first { b"\n" } else { b",\n" }).await?;
+        zip.write(&row).await?;
+        first = false;
+    }
+    zip.write(b"\n]\n").await?;
+    zip.finish_file().await
+}
+
+fn todo_csv_row(todo: &ExportTodo) -> String {
+    let time = |at: Option<OffsetDateTime>| at.map(|at| at.format(&Rfc3339).unwrap()).unwrap_or_default();
+    let id = |id: Option<i64>| id.map(|id| id.to_string()).unwrap_or_default();
+    let fields = [
+        todo.id.to_string(),
+        csv_field(&todo.title),
+        csv_field(&todo.description),
+        todo.status.clone(),
+        todo.priority.to_string(),
+        time(Some(todo.created_at)),
+        time(todo.due_at),
+        time(todo.completed_at),
+        id(todo.parent_id),
+        id(todo.list_id),
+    ];
+    format!("{}\r\n", fields.join(","))
+}
+
+///
+/// A CSV field, quoted if it needs to be (RFC 4180). Fields that a
+/// spreadsheet would take for a formula get a leading `'`, so that opening an
+/// export cannot run one that someone wrote into a title.
+///
+fn csv_field(value: &str) -> String {
+    let value = if value.starts_with(['=', '+', '-', '@']) { format!("'{}", value) } else { value.to_string() };
+    if value.contains([',', '"', '\r', '\n']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value
+    }
+}
+
+///
+/// Writes a ZIP archive to a channel as it is made, one stored file at a
+/// time: `start_file`, then any number of `write`s, then `finish_file`, and
+/// `finish` once all files are written.
+///
+pub struct ZipStream {
+    out: mpsc::Sender<io::Result<Bytes>>,
+    buffer: Vec<u8>,
+    /// The number of bytes written so far, sent or buffered.
+    offset: u64,
+    /// The DOS time and date of every file.
+    modified: (u16, u16),
+    entries: Vec<ZipEntry>,
+    current: Option<(ZipEntry, crc32fast::Hasher)>,
+}
+
+struct ZipEntry {
+    name: String,
+    offset: u32,
+    crc: u32,
+    size: u32,
+}
+
+const LOCAL_FILE_HEADER: u32 = 0x04034b50;
+const DATA_DESCRIPTOR: u32 = 0x08074b50;
+const CENTRAL_DIRECTORY_HEADER: u32 = 0x02014b50;
+const END_OF_CENTRAL_DIRECTORY: u32 = 0x06054b50;
+/// Version 2.0, the first with data descriptors.
+const ZIP_VERSION: u16 = 20;
+/// Sizes and checksum follow the data, and names are UTF-8.
+const ZIP_FLAGS: u16 = 0x0008 | 0x0800;
+
+impl ZipStream {
+    pub fn new(out: mpsc::Sender<io::Result<Bytes>>, modified: OffsetDateTime) -> Self {
+        let time = (modified.hour() as u16) << 11 | (modified.minute() as u16) << 5 | (modified.second() as u16 / 2);
+        let date = ((modified.year() - 1980).max(0) as u16) << 9 | (modified.month() as u16) << 5 | modified.day() as u16;
+        ZipStream { out, buffer: Vec::new(), offset: 0, modified: (time, date), entries: Vec::new(), current: None }
+    }
+
+    pub async fn start_file(&mut self, name: &str) -> io::Result<()> {
+        let entry = ZipEntry { name: name.to_string(), offset: self.offset_u32()?, crc: 0, size: 0 };
+        let mut header = Vec::with_capacity(30 + name.len());
+        header.extend_from_slice(&LOCAL_FILE_HEADER.to_le_bytes());
+        header.extend_from_slice(&ZIP_VERSION.to_le_bytes());
+        header.extend_from_slice(&ZIP_FLAGS.to_le_bytes());
+        // Stored, without compression.
+        header.extend_from_slice(&0u16.to_le_bytes());
+        header.extend_from_slice(&self.modified.0.to_le_bytes());
+        header.extend_from_slice(&self.modified.1.to_le_bytes());
+        // The checksum and sizes, in the data descriptor instead.
+        header.extend_from_slice(&[0; 12]);
+        header.extend_from_slice(&(name.len() as u16).to_le_bytes());
+        header.extend_from_slice(&0u16.to_le_bytes());
+        header.extend_from_slice(name.as_bytes());
+        self.emit(&header).await?;
+        self.current = Some((entry, crc32fast::Hasher::new()));
+        Ok(())
+    }
+
+    pub async fn write(&mut self, bytes: &[u8]) -> io::Result<()> {
+        let (entry, crc) = self.current.as_mut().expect("no file was started");
+        crc.update(bytes);
+        entry.size = u32::try_from(entry.size as u64 + bytes.len() as u64).map_err(|_| too_large())?;
+        self.emit(bytes).await
+    }
+
+    pub async fn finish_file(&mut self) -> io::Result<()> {
+        let (mut entry, crc) = self.current.take().expect("no file was started");
+        entry.crc = crc.finalize();
+        let mut descriptor = Vec::with_capacity(16);
+        descriptor.extend_from_slice(&DATA_DESCRIPTOR.to_le_bytes());
+        descriptor.extend_from_slice(&entry.crc.to_le_bytes());
+        descriptor.extend_from_slice(&entry.size.to_le_bytes());
+        descriptor.extend_from_slice(&entry.size.to_le_bytes());
+        self.emit(&descriptor).await?;
+        self.entries.push(entry);
+        Ok(())
+    }
+
+    pub async fn finish(mut self) -> io::Result<()> {
+        let start = self.offset_u32()?;
+        let mut directory = Vec::new();
+        for entry in &self.entries {
+            directory.extend_from_slice(&CENTRAL_DIRECTORY_HEADER.to_le_bytes());
+            // Made by and needed to extract.
+            directory.extend_from_slice(&ZIP_VERSION.to_le_bytes());
+            directory.extend_from_slice(&ZIP_VERSION.to_le_bytes());
+            directory.extend_from_slice(&ZIP_FLAGS.to_le_bytes());
+            directory.extend_from_slice(&0u16.to_le_bytes());
+            directory.extend_from_slice(&self.modified.0.to_le_bytes());
+            directory.extend_from_slice(&self.modified.1.to_le_bytes());
+            directory.extend_from_slice(&entry.crc.to_le_bytes());
+            directory.extend_from_slice(&entry.size.to_le_bytes());
+            directory.extend_from_slice(&entry.size.to_le_bytes());
+            directory.extend_from_slice(&(entry.name.len() as u16).to_le_bytes());
+            // No extra field, comment, disk number, or attributes.
+            directory.extend_from_slice(&[0; 12]);
+            directory.extend_from_slice(&entry.offset.to_le_bytes());
+            directory.extend_from_slice(entry.name.as_bytes());
+        }
+        let entries = u16::try_from(self.entries.len()).map_err(|_| too_large())?;
+        let mut end = Vec::with_capacity(22);
+        end.extend_from_slice(&END_OF_CENTRAL_DIRECTORY.to_le_bytes());
+        // This disk, and the one where the directory starts.
+        end.extend_from_slice(&[0; 4]);
+        end.extend_from_slice(&entries.to_le_bytes());
+        end.extend_from_slice(&entries.to_le_bytes());
+        end.extend_from_slice(&(directory.len() as u32).to_le_bytes());
+        end.extend_from_slice(&start.to_le_bytes());
+        end.extend_from_slice(&0u16.to_le_bytes());
+        self.emit(&directory).await?;
+        self.emit(&end).await?;
+        self.flush().await
+    }
+
+    async fn emit(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.buffer.extend_from_slice(bytes);
+        self.offset += bytes.len() as u64;
+        if self.buffer.len() >= CHUNK_SIZE {
+            self.flush().await?;
+        }
+        Ok(())
+    }
+
+    async fn flush(&mut self) -> io::Result<()> {
+        if self.buffer.is_empty() {
+            return Ok(());
+        }
+        let chunk = Bytes::from(std::mem::take(&mut self.buffer));
+        self.out.send(Ok(chunk)).await.map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))
+    }
+
+    fn offset_u32(&self) -> io::Result<u32> {
+        u32::try_from(self.offset).map_err(|_| too_large())
+    }
+}
+
+fn too_large() -> io::Error {
+    io::Error::other("the archive would need ZIP64")
+}
+
+#[tokio::test]
+async fn exports_are_zip_archives_of_the_users_data() {
+    // for Body::collect
+    use http_body_util::BodyExt;
+    /// for ServiceExt::oneshot
+    use tower::util::ServiceExt;
+    use axum::http::Request;
+    use sqlx::postgres::PgPoolOptions;
+    use std::{collections::HashMap, sync::Arc};
+
+    use crate::auth::{AuthState, RefreshTokenRepoInMemory};
+    use crate::clock::SystemClock;
+    use crate::users::{create_test_user, UserRepoPostgres};
+
+    #[derive(Clone, FromRef)]
+    struct TestState {
+        users: UserState<UserRepoPostgres>,
+        takeout: TakeoutState<TakeoutRepoPostgres>,
+        keys: JwtKeys,
+    }
+
+    ///
+    /// The files of an archive, read through its central directory.
+    ///
+    fn unzip(archive: &[u8]) -> HashMap<String, Vec<u8>> {
+        let u16_at = |at: usize| u16::from_le_bytes(archive[at..at + 2].try_into().unwrap()) as usize;
+        let u32_at = |at: usize| u32::from_le_bytes(archive[at..at + 4].try_into().unwrap()) as usize;
+        let end = archive.len() - 22;
+        assert_eq!(u32_at(end), END_OF_CENTRAL_DIRECTORY as usize);
+        let mut at = u32_at(end + 16);
+        let mut files = HashMap::new();
+        for _ in 0..u16_at(end + 10) {
+            assert_eq!(u32_at(at), CENTRAL_DIRECTORY_HEADER as usize);
+            let (crc, size, name_len, offset) = (u32_at(at + 16), u32_at(at + 24), u16_at(at + 28), u32_at(at + 42));
+            let name = String::from_utf8(archive[at + 46..at + 46 + name_len].to_vec()).unwrap();
+            assert_eq!(u32_at(offset), LOCAL_FILE_HEADER as usize);
+            let data = archive[offset + 30 + name_len..offset + 30 + name_len + size].to_vec();
+            assert_eq!(crc32fast::hash(&data) as usize, crc);
+            files.insert(name, data);
+            at += 46 + name_len;
+        }
+        files
+    }
+
+    let pool = PgPoolOptions::new()
+        .max_connections(2)
+        .connect(&crate::test_db::database_url().await)
+        .await
+        .unwrap();
+    let keys = JwtKeys::from_secret(b"secret");
+    let auth = AuthState { repo: RefreshTokenRepoInMemory::default(), keys: keys.clone() };
+    let app = takeout_routes::<_, UserRepoPostgres, TakeoutRepoPostgres>().into_router().with_state(TestState {
+        users: UserState { repo: UserRepoPostgres::new(pool.clone(), crate::pii::test_cipher()) },
+        takeout: TakeoutState {
+            repo: TakeoutRepoPostgres::new(pool.clone()),
+            clock: Arc::new(SystemClock),
+            todos: None,
+        },
+        keys,
+    });
+
+    let user = create_test_user(&pool, false).await;
+    let other = create_test_user(&pool, false).await;
+    let todo = sqlx::query_scalar!(
+        "INSERT INTO todos (title, description, owner_id) VALUES ('Taxes, \"2026\"', '=1+1', $1) RETURNING id",
+        user
+    )
+    .fetch_one(&pool)
+    .await
+    .unwrap();
+    sqlx::query!("INSERT INTO comments (todo_id, body) VALUES ($1, 'Before May')", todo).execute(&pool).await.unwrap();
+    sqlx::query!("INSERT INTO todos (title, description, owner_id) VALUES ('Not mine', '', $1)", other)
+        .execute(&pool)
+        .await
+        .unwrap();
+
+    let export = |user_id: i64, caller: i64| {
+        let app = app.clone();
+        let auth = auth.clone();
+        async move {
+            let token = auth.issue_tokens(caller).await.access_token;
+            let request = Request::get(format!("/users/{}/export", user_id))
+                .header("Authorization", format!("Bearer {}", token))
+                .body(Body::empty())
+                .unwrap();
+            app.oneshot(request).await.unwrap()
+        }
+    };
+
+    assert_eq!(export(user, other).await.status(), StatusCode::FORBIDDEN);
+    let response = export(user, user).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.headers()[header::CONTENT_TYPE], "application/zip");
+    let archive = response.into_body().collect().await.unwrap().to_bytes();
+    let files = unzip(&archive);
+
+    let mut names: Vec<_> = files.keys().cloned().collect();
+    names.sort();
+    assert_eq!(names, ["attachments.json", "comments.json", "todos.csv", "todos.json", "user.json"]);
+    let todos: Vec<ExportTodo> = serde_json::from_slice(&files["todos.json"]).unwrap();
+    assert_eq!(todos.len(), 1);
+    assert_eq!((todos[0].id, todos[0].status.as_str()), (todo, "open"));
+    let comments: Vec<ExportComment> = serde_json::from_slice(&files["comments.json"]).unwrap();
+    assert_eq!(comments[0].body, "Before May");
+    let attachments: Vec<ExportAttachment> = serde_json::from_slice(&files["attachments.json"]).unwrap();
+    assert!(attachments.is_empty());
+    let csv = String::from_utf8(files["todos.csv"].clone()).unwrap();
+    assert!(csv.contains(&format!("\r\n{},\"Taxes, \"\"2026\"\"\",'=1+1,open,0,", todo)));
+}
@@ -0,0 +1,172 @@
+#![allow(dead_code)]
+
+//!
+//! TEMPLATES
+//! ---------
+//!
+//! Not every client is a script that speaks JSON. A browser wants HTML, and
+//! sends what the user typed into a `<form>` as
+//! `application/x-www-form-urlencoded`: `title=Buy+milk&description=2%25`.
+//!
+//! HTML built with `format!` is one forgotten escape away from letting a user
+//! inject a `<script>` into everyone's page. Askama compiles the templates in
+//! `templates/` into Rust at build time instead, escapes every value unless
+//! told otherwise (`|safe`), and fails the build if a template uses a field
+//! that its struct does not have.
+//!
+//! After a form is posted, the server should not answer with a page: the
+//! browser would post the form again when the user reloads it. It answers
+//! with a `303 See Other` to a page that the browser then GETs instead
+//! ("Post/Redirect/Get"). Whatever the user should see about what happened,
+//! such as "Todo created", travels along in a short-lived "flash" cookie, which
+//! the next page shows and removes.
+//!
+
+use askama::Template;
+#[allow(unused_imports)]
+use axum::{
+    body::Body,
+    http::{header, Request, StatusCode},
+    response::{Html, IntoResponse, Redirect, Response},
+    routing::{get, post},
+    Form, Router,
+};
+use axum_extra::extract::cookie::{Cookie, CookieJar};
+
+///
+/// Any askama template, rendered as an HTML response.
+///
+pub struct HtmlTemplate<T>(pub T);
+
+impl<T: Template> IntoResponse for HtmlTemplate<T> {
+    fn into_response(self) -> Response {
+        match self.0.render() {
+            Ok(html) => Html(html).into_response(),
+            Err(error) => (StatusCode::INTERNAL_SERVER_ERROR, error.to_string()).into_response(),
+        }
+    }
+}
+
+///
+/// The form that creates a todo. When the form is rejected, it is rendered
+/// again with the error and what the user typed, so that nothing is lost.
+///
+#[derive(Debug, Default, Template)]
+#[template(path = "todo_form.html")]
+pub struct TodoFormPage {
+    pub action: String,
+    pub flash: Option<String>,
+    pub error: Option<String>,
+    pub title: String,
+    pub description: String,
+}
+
+const FLASH_COOKIE: &str = "flash";
+
+///
+/// Leaves a message for the next page. The cookie is plain: a client that
+/// changes it only changes what it shows itself, and it is escaped like any
+/// other value when rendered.
+///
+pub fn set_flash(jar: CookieJar, message: impl Into<String>) -> CookieJar {
+    jar.add(Cookie::build((FLASH_COOKIE, message.into())).path("/").http_only(true))
+}
+
+///
+/// Reads the message left by the previous request, and removes it so that it
+/// is shown only once.
+///
+pub fn take_flash(jar: CookieJar) -> (CookieJar, Option<String>) {
+    match jar.get(FLASH_COOKIE) {
+        Some(cookie) => {
+            let message = cookie.value().to_string();
+            (jar.remove(Cookie::build(FLASH_COOKIE).path("/")), Some(message))
+        }
+        None => (jar, None),
+    }
+}
+
+///
+/// EXERCISE 1
+///
+/// `Form<T>` is to form bodies what `Json<T>` is to JSON: it deserializes the
+/// `application/x-www-form-urlencoded` body into `T`, and rejects any other
+/// content type. Every value arrives as a string, and serde parses those that
+/// are not (`age=36` into a `u8`).
+///
+/// Send the same form as JSON, with `Content-Type: application/json`. What
+/// does the handler answer? Then make `age` optional, and leave it out.
+///
+#[tokio::test]
+async fn form_bodies() {
+    // for Body::collect
+    use http_body_util::BodyExt;
+    /// for ServiceExt::oneshot
+    use tower::util::ServiceExt;
+
+    #[derive(Debug, serde::Deserialize)]
+    struct Signup {
+        name: String,
+        age: u8,
+    }
+
+    let app = Router::new().route(
+        "/signup",
+        post(|Form(signup): Form<Signup>| async move { format!("{} is {}", signup.name, signup.age) }),
+    );
+
+    let request = Request::post("/signup")
+        .header(header::CONTENT_TYPE, "application/x-www-form-urlencoded")
+        .body(Body::from("name=Ada+Lovelace&age=36"))
+        .unwrap();
+    let response = app.clone().oneshot(request).await.unwrap();
+    let body = response.into_body().collect().await.unwrap().to_bytes();
+    assert_eq!(&body[..], b"Ada Lovelace is 36");
+
+    let request = Request::post("/signup")
+        .header(header::CONTENT_TYPE, "application/x-www-form-urlencoded")
+        .body(Body::from("name=Ada&age=old"))
+        .unwrap();
+    assert_eq!(app.oneshot(request).await.unwrap().status(), StatusCode::UNPROCESSABLE_ENTITY);
+}
+
+///
+/// EXERCISE 2
+///
+/// `Redirect::to` answers `303 See Other`, which tells the browser to GET
+/// the new location, whatever the method of the request was.
+/// `Redirect::temporary` (307) and `Redirect::permanent` (308) keep the
+/// method instead, so a browser redirected after a POST would POST again.
+///
+/// Change the handler to redirect with `Redirect::temporary`, and follow the
+/// redirect as a browser would. Which handler receives the second request?
+///
+#[tokio::test]
+async fn redirect_after_post() {
+    /// for ServiceExt::oneshot
+    use tower::util::ServiceExt;
+
+    let app = Router::new()
+        .route("/done", get(|| async { "Done" }))
+        .route("/form", post(|| async { Redirect::to("/done") }));
+
+    let response = app.oneshot(Request::post("/form").body(Body::empty()).unwrap()).await.unwrap();
+
+    assert_eq!(response.status(), StatusCode::SEE_OTHER);
+    assert_eq!(response.headers()[header::LOCATION], "/done");
+}
+
+#[test]
+fn templates_escape_their_values() {
+    let page = TodoFormPage {
+        action: "/todo/new".to_string(),
+        flash: Some("Created <b>".to_string()),
+        title: "<script>alert(1)</script>".to_string(),
+        ..Default::default()
+    };
+    let html = page.render().unwrap();
+
+    assert!(html.contains(r#"action="/todo/new""#));
+    assert!(html.contains("Created &lt;b&gt;"));
+    assert!(!html.contains("<script>"));
+}
@@ -0,0 +1,42 @@
+//!
+//! TODO REPOS
+//! ----------
+//!
+//! The `TodoRepo`s that `TODO_REPO` picks from, other than `TodoRepoPostgres`,
+//! which stays in `persistence.rs` with the trait, and with the in-memory
+//! repo of the exercises. Each has a module of its own, and those on other
+//! databases than Postgres are only built with their features. Every one of
+//! them runs the tests of `conformance`.
+//!
+
+#[cfg(any(feature = "sqlite", feature = "any"))]
+use sqlx::types::time::OffsetDateTime;
+
+#[cfg(feature = "any")]
+pub mod any;
+pub mod conformance;
+#[cfg(feature = "dynamodb")]
+pub mod dynamo;
+#[cfg(feature = "mongodb")]
+pub mod mongo;
+#[cfg(feature = "mysql")]
+pub mod mysql;
+pub mod rls;
+#[cfg(feature = "sea-orm")]
+pub mod sea_orm;
+#[cfg(feature = "sqlite")]
+pub mod sqlite;
+
+///
+/// A time as microseconds since the Unix epoch, as `TodoRepoSqlite` keeps
+/// times, and `TodoRepoAny` reads and binds them.
+///
+#[cfg(any(feature = "sqlite", feature = "any"))]
+pub fn unix_micros(at: OffsetDateTime) -> i64 {
+    (at.unix_timestamp_nanos() / 1000) as i64
+}
+
+#[cfg(any(feature = "sqlite", feature = "any"))]
+pub fn from_unix_micros(micros: i64) -> OffsetDateTime {
+    OffsetDateTime::from_unix_timestamp_nanos(micros as i128 * 1000).unwrap()
+}
@@ -0,0 +1,1079 @@
+//!
+//! ANY DATABASE
+//! ------------
+//!
+//! The todos in Postgres, MySQL or SQLite, through the `Any` driver of sqlx,
+//! with the `any` feature and `TODO_REPO=any` (see `TodoRepoAny`, and
+//! `EXERCISE 15` in `persistence.rs`).
+//!
+
+use std::{collections::HashMap, time::Duration};
+
+use crate::config::{AppConfig, PoolConfig};
+use crate::extract::ExtractError;
+use crate::persistence::{
+    json_contains, rolled_back, BulkError, BulkOperation, Comment, CreateTodo, DailyCount, PatchableTodo, RepoError,
+    StatusCount, SubtaskError, Todo, TodoCursor, TodoErasure, TodoFilter, TodoPatch, TodoRepo, TodoSort, TodoStats,
+    TodoStatus,
+};
+use crate::users::{UserRepo, UserRepoPostgres};
+use super::{from_unix_micros, unix_micros};
+use axum::async_trait;
+use sqlx::{any::{Any, AnyArguments, AnyPoolOptions, AnyRow}, AnyConnection, AnyPool, Arguments};
+use sqlx::types::time::{Date, OffsetDateTime};
+
+///
+/// A `TodoRepo` on whichever database `TODO_DATABASE_URL` names
+/// (`TODO_REPO=any`), through an `AnyPool`, whose driver is picked when it
+/// connects, by the scheme of the URL: Postgres, MySQL or SQLite, with the
+/// schema of `migrations`, `migrations_mysql` or `migrations_sqlite`. Its
+/// queries are those of `TodoRepoMySql`, written once for all three, and what
+/// they cannot share is written by `AnyDialect`:
+///
+/// - The `Any` driver only has the types that every database has: integers,
+///   floats, text, bytes and booleans. Times are bound as microseconds
+///   since the Unix epoch, and read as their text, and statuses and metadata
+///   are bound and read as text, converted to and from the types of the
+///   database in the SQL.
+/// - Parameters are `?`, and numbered for Postgres.
+/// - A `NULL` is bound as an `INT`, which Postgres will not take for text, so
+///   updates set only the columns they are given, rather than `COALESCE` a
+///   parameter with the column.
+/// - As for MySQL, whether the user is an admin is looked up in the Postgres
+///   of the users, and bound into each query.
+///
+/// See `EXERCISE 15` for what it costs to have no one database to check the
+/// queries against.
+///
+#[derive(Clone)]
+pub struct TodoRepoAny {
+    pub pool: AnyPool,
+    pub dialect: AnyDialect,
+    pub users: UserRepoPostgres,
+}
+
+///
+/// The SQL that differs between the databases of `TodoRepoAny`.
+///
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AnyDialect {
+    Postgres,
+    MySql,
+    Sqlite,
+}
+
+impl AnyDialect {
+    ///
+    /// The dialect of a URL, by its scheme, as the `Any` driver picks its
+    /// driver.
+    ///
+    pub fn of_url(url: &str) -> Option<Self> {
+        match url.split(':').next()? {
+            "postgres" | "postgresql" => Some(AnyDialect::Postgres),
+            "mysql" | "mariadb" => Some(AnyDialect::MySql),
+            "sqlite" => Some(AnyDialect::Sqlite),
+            _ => None,
+        }
+    }
+
+    ///
+    /// The SQL with its `?` numbered `$1`, `$2`... for Postgres. None of the
+    /// queries has a `?` in a string.
+    ///
+    pub fn sql(self, sql: &str) -> String {
+        if self != AnyDialect::Postgres {
+            return sql.to_string();
+        }
+        let mut numbered = String::with_capacity(sql.len());
+        let mut n = 0;
+        for c in sql.chars() {
+            if c == '?' {
+                n += 1;
+                numbered.push_str(&format!("${}", n));
+            } else {
+                numbered.push(c);
+            }
+        }
+        numbered
+    }
+
+    ///
+    /// A time column, as microseconds since the Unix epoch.
+    ///
+    fn micros(self, column: &str) -> String {
+        match self {
+            AnyDialect::Postgres => {
+                format!("CAST(EXTRACT(EPOCH FROM {}) * 1000000 AS BIGINT)", column)
+            }
+            AnyDialect::MySql => format!("TIMESTAMPDIFF(MICROSECOND, TIMESTAMP '1970-01-01 00:00:00', {})", column),
+            AnyDialect::Sqlite => column.to_string(),
+        }
+    }
+
+    ///
+    /// A time column, as the text of its microseconds since the Unix epoch,
+    /// for `micros_any` to read. sqlx 0.7.3 reads every integer of SQLite
+    /// through `Any` as an `i32`, which the microseconds of today overflow,
+    /// though ids stay well below it.
+    ///
+    fn micros_text(self, column: &str) -> String {
+        match self {
+            AnyDialect::Postgres | AnyDialect::Sqlite => {
+                format!("CAST({} AS TEXT)", self.micros(column))
+            }
+            AnyDialect::MySql => format!("CAST({} AS CHAR)", self.micros(column)),
+        }
+    }
+
+    ///
+    /// A parameter of microseconds since the Unix epoch, as a time.
+    ///
+    fn time(self) -> &'static str {
+        match self {
+            AnyDialect::Postgres => "(TIMESTAMPTZ 'epoch' + ? * INTERVAL '1 microsecond')",
+            AnyDialect::MySql => "TIMESTAMPADD(MICROSECOND, ?, TIMESTAMP '1970-01-01 00:00:00')",
+            AnyDialect::Sqlite => "?",
+        }
+    }
+
+    ///
+    /// A column of a status or of JSON, as text.
+    ///
+    fn text(self, column: &str) -> String {
+        match self {
+            AnyDialect::Postgres => format!("CAST({} AS TEXT)", column),
+            AnyDialect::MySql => format!("CAST({} AS CHAR)", column),
+            AnyDialect::Sqlite => column.to_string(),
+        }
+    }
+
+    ///
+    /// A parameter of the label of a status, as a status.
+    ///
+    fn status(self) -> &'static str {
+        match self {
+            AnyDialect::Postgres => "CAST(? AS todo_status)",
+            AnyDialect::MySql | AnyDialect::Sqlite => "?",
+        }
+    }
+
+    ///
+    /// A parameter of JSON text, as JSON.
+    ///
+    fn json(self) -> &'static str {
+        match self {
+            AnyDialect::Postgres => "CAST(? AS JSONB)",
+            AnyDialect::MySql | AnyDialect::Sqlite => "?",
+        }
+    }
+
+    ///
+    /// The condition that the metadata of a todo contains a parameter of
+    /// JSON text, if the database has one. SQLite does not, so its metadata
+    /// is matched by `json_contains` instead.
+    ///
+    fn metadata_contains(self) -> Option<&'static str> {
+        match self {
+            AnyDialect::Postgres => Some("todos.metadata @> CAST(? AS JSONB)"),
+            AnyDialect::MySql => Some("JSON_CONTAINS(todos.metadata, ?)"),
+            AnyDialect::Sqlite => None,
+        }
+    }
+
+    ///
+    /// The instant, in microseconds since the Unix epoch, at which a midnight
+    /// parameter is reached in a time zone parameter, or NULL where MySQL
+    /// does not know the zone. SQLite knows no time zones.
+    ///
+    fn midnight_in_zone(self) -> Option<String> {
+        match self {
+            AnyDialect::Postgres => Some(self.micros("CAST(? AS TIMESTAMP) AT TIME ZONE ?")),
+            AnyDialect::MySql => Some(self.micros("CONVERT_TZ(?, ?, '+00:00')")),
+            AnyDialect::Sqlite => None,
+        }
+    }
+
+    ///
+    /// The UTC day of a time column, as `YYYY-MM-DD`.
+    ///
+    fn day(self, column: &str) -> String {
+        match self {
+            AnyDialect::Postgres => format!("to_char({} AT TIME ZONE 'UTC', 'YYYY-MM-DD')", column),
+            AnyDialect::MySql => format!("DATE_FORMAT({}, '%Y-%m-%d')", column),
+            AnyDialect::Sqlite => {
+                format!("strftime('%Y-%m-%d', {} / 1000000, 'unixepoch')", column)
+            }
+        }
+    }
+
+    ///
+    /// A number as a float, where `AVG` gives a decimal that `Any` cannot
+    /// read. MySQL turns it into one when it is divided by a float.
+    ///
+    fn double(self, number: &str) -> String {
+        match self {
+            AnyDialect::Postgres => format!("CAST({} AS DOUBLE PRECISION)", number),
+            AnyDialect::MySql | AnyDialect::Sqlite => number.to_string(),
+        }
+    }
+
+    ///
+    /// What locks the row that a claim picks until it is claimed, and skips
+    /// those that other claims have locked. SQLite has a single writer, so
+    /// there is nothing to lock.
+    ///
+    fn for_update_skip_locked(self) -> &'static str {
+        match self {
+            AnyDialect::Postgres | AnyDialect::MySql => " FOR UPDATE SKIP LOCKED",
+            AnyDialect::Sqlite => "",
+        }
+    }
+
+    ///
+    /// What locks the row that a select reads until the transaction ends.
+    /// SQLite has a single writer, which the transaction already is.
+    ///
+    fn for_update(self) -> &'static str {
+        match self {
+            AnyDialect::Postgres | AnyDialect::MySql => " FOR UPDATE",
+            AnyDialect::Sqlite => "",
+        }
+    }
+
+    ///
+    /// What makes an `INSERT` return the id of its row. MySQL has no
+    /// `RETURNING`, and gives the id with the result instead.
+    ///
+    fn returning_id(self) -> &'static str {
+        match self {
+            AnyDialect::Postgres | AnyDialect::Sqlite => " RETURNING id",
+            AnyDialect::MySql => "",
+        }
+    }
+
+    ///
+    /// The columns of a `Todo`, as an `AnyTodoRow` reads them.
+    ///
+    fn todo_columns(self) -> String {
+        format!(
+            "todos.id, todos.title, todos.description, {} AS status, {} AS created_at, {} AS due_at, todos.priority,
+            todos.parent_id, todos.owner_id, todos.list_id, {} AS completed_at, {} AS metadata",
+            self.text("todos.status"),
+            self.micros_text("todos.created_at"),
+            self.micros_text("todos.due_at"),
+            self.micros_text("todos.completed_at"),
+            self.text("todos.metadata"),
+        )
+    }
+}
+
+///
+/// A `Todo` as the `Any` driver can read it, with no type that only some of
+/// its databases have.
+///
+struct AnyTodoRow {
+    id: i64,
+    title: String,
+    description: String,
+    status: String,
+    created_at: i64,
+    due_at: Option<i64>,
+    priority: i32,
+    parent_id: Option<i64>,
+    owner_id: Option<i64>,
+    list_id: Option<i64>,
+    completed_at: Option<i64>,
+    metadata: String,
+}
+
+///
+/// Written out rather than derived, as the nullable columns are read by
+/// `nullable_any`, and the times by `micros_any`.
+///
+impl sqlx::FromRow<'_, AnyRow> for AnyTodoRow {
+    fn from_row(row: &AnyRow) -> Result<Self, sqlx::Error> {
+        use sqlx::Row;
+
+        Ok(AnyTodoRow {
+            id: row.try_get("id")?,
+            title: row.try_get("title")?,
+            description: row.try_get("description")?,
+            status: row.try_get("status")?,
+            created_at: micros_any(row, "created_at")?.ok_or(sqlx::Error::Decode("created_at is NULL".into()))?,
+            due_at: micros_any(row, "due_at")?,
+            priority: row.try_get("priority")?,
+            parent_id: nullable_any(row, "parent_id")?,
+            owner_id: nullable_any(row, "owner_id")?,
+            list_id: nullable_any(row, "list_id")?,
+            completed_at: micros_any(row, "completed_at")?,
+            metadata: row.try_get("metadata")?,
+        })
+    }
+}
+
+///
+/// A column that may be NULL. sqlx 0.7.3 never decodes a NULL of `Any` as
+/// `None`, as every value and type of `Any` says that it is not NULL, so
+/// this looks at the name of the type instead, which is `NULL`.
+///
+fn nullable_any<'r, T, I>(row: &'r AnyRow, index: I) -> Result<Option<T>, sqlx::Error>
+where
+    T: sqlx::Decode<'r, Any> + sqlx::Type<Any>,
+    I: sqlx::ColumnIndex<AnyRow> + std::fmt::Debug,
+{
+    use sqlx::{Row, TypeInfo, ValueRef};
+
+    if row.try_get_raw(&index)?.type_info().name() == "NULL" {
+        return Ok(None);
+    }
+    row.try_get(index).map(Some)
+}
+
+///
+/// A time column of `AnyDialect::micros_text`.
+///
+fn micros_any(row: &AnyRow, column: &str) -> Result<Option<i64>, sqlx::Error> {
+    let micros: Option<String> = nullable_any(row, column)?;
+    micros
+        .map(|micros| micros.parse())
+        .transpose()
+        .map_err(|error| sqlx::Error::ColumnDecode { index: column.to_string(), source: Box::new(error) })
+}
+
+impl From<AnyTodoRow> for Todo {
+    fn from(row: AnyTodoRow) -> Self {
+        Todo {
+            id: row.id,
+            title: row.title,
+            description: row.description,
+            status: row.status.parse().unwrap(),
+            created_at: from_unix_micros(row.created_at),
+            due_at: row.due_at.map(from_unix_micros),
+            priority: row.priority,
+            parent_id: row.parent_id,
+            owner_id: row.owner_id,
+            list_id: row.list_id,
+            completed_at: row.completed_at.map(from_unix_micros),
+            metadata: serde_json::from_str(&row.metadata).unwrap(),
+        }
+    }
+}
+
+type AnyTodoQuery<'q> = sqlx::query::QueryAs<'q, Any, AnyTodoRow, AnyArguments<'q>>;
+
+impl TodoRepoAny {
+    ///
+    /// The repo of `config.todo_database_url`, which connects when first
+    /// used.
+    ///
+    pub fn connect_lazy(config: &AppConfig, users: UserRepoPostgres) -> Self {
+        let url = config.todo_database_url.as_deref().expect("TODO_DATABASE_URL must be set when TODO_REPO is any");
+        let dialect = Self::dialect_of(url);
+        TodoRepoAny { pool: Self::pool_options(&config.pool, dialect).connect_lazy(url).unwrap(), dialect, users }
+    }
+
+    fn dialect_of(url: &str) -> AnyDialect {
+        AnyDialect::of_url(url).expect("TODO_DATABASE_URL must be a postgres://, mysql:// or sqlite: URL")
+    }
+
+    ///
+    /// The options of `PoolConfig`, except for SQLite, which gets the single
+    /// connection of `TodoRepoSqlite::pool_options`. The drivers that were
+    /// built in are installed first, as the `Any` driver has none of its own.
+    ///
+    pub fn pool_options(config: &PoolConfig, dialect: AnyDialect) -> AnyPoolOptions {
+        sqlx::any::install_default_drivers();
+        let options = AnyPoolOptions::new().acquire_timeout(Duration::from_secs(config.acquire_timeout_secs));
+        match dialect {
+            AnyDialect::Sqlite => options.max_connections(1).idle_timeout(None).max_lifetime(None),
+            AnyDialect::Postgres | AnyDialect::MySql => options
+                .max_connections(config.max_connections)
+                .min_connections(config.min_connections)
+                .idle_timeout(Duration::from_secs(config.idle_timeout_secs))
+                .test_before_acquire(config.test_before_acquire),
+        }
+    }
+
+    pub fn migrator(dialect: AnyDialect) -> sqlx::migrate::Migrator {
+        match dialect {
+            AnyDialect::Postgres => sqlx::migrate!(),
+            AnyDialect::MySql => sqlx::migrate!("./migrations_mysql"),
+            AnyDialect::Sqlite => sqlx::migrate!("./migrations_sqlite"),
+        }
+    }
+
+    ///
+    /// Runs the migrations of the database of `config.todo_database_url`,
+    /// with a pool of its own, before the app starts.
+    ///
+    pub async fn migrate(config: &AppConfig) {
+        let url = config.todo_database_url.as_deref().unwrap();
+        let dialect = Self::dialect_of(url);
+        let pool = Self::pool_options(&config.pool, dialect).connect(url).await.unwrap();
+        Self::migrator(dialect).run(&pool).await.unwrap();
+        pool.close().await;
+    }
+
+    async fn is_admin(&self, user_id: i64) -> bool {
+        self.users.get_user(user_id).await.is_some_and(|user| user.is_admin)
+    }
+
+    ///
+    /// The SQL of a query of todos, with `rest` after the columns and the
+    /// table.
+    ///
+    fn select(&self, rest: &str) -> String {
+        self.dialect.sql(&format!("SELECT {} FROM todos {}", self.dialect.todo_columns(), rest))
+    }
+
+    async fn fetch_todos(&self, query: AnyTodoQuery<'_>) -> Result<Vec<Todo>, sqlx::Error> {
+        Ok(query.fetch_all(&self.pool).await?.into_iter().map(Todo::from).collect())
+    }
+}
+
+///
+/// The id of the row that an `INSERT` added, if it added one: from its
+/// `RETURNING id`, or from `LAST_INSERT_ID()` on MySQL.
+///
+async fn inserted_id_any<'q>(
+    conn: &mut AnyConnection,
+    dialect: AnyDialect,
+    query: sqlx::query::Query<'q, Any, AnyArguments<'q>>,
+) -> Result<Option<i64>, sqlx::Error> {
+    use sqlx::Row;
+
+    if dialect == AnyDialect::MySql {
+        let inserted = query.execute(conn).await?;
+        return Ok((inserted.rows_affected() > 0).then(|| inserted.last_insert_id()).flatten());
+    }
+    Ok(query.fetch_optional(conn).await?.map(|row| row.get(0)))
+}
+
+///
+/// The same as `complete_parents_mysql`.
+///
+async fn complete_parents_any(conn: &mut AnyConnection, dialect: AnyDialect, id: i64) -> Result<(), sqlx::Error> {
+    let parent_sql = dialect.sql(&format!(
+        "SELECT parent.id, {} FROM todos child JOIN todos parent ON parent.id = child.parent_id WHERE child.id = ?",
+        dialect.text("parent.status")
+    ));
+    let siblings_sql = dialect.sql("SELECT COUNT(*) FROM todos WHERE parent_id = ? AND status IN ('open', 'in_progress')");
+    let update_sql = dialect.sql("UPDATE todos SET status = 'done' WHERE id = ?");
+    let mut child_id = id;
+    loop {
+        let parent: Option<(i64, String)> =
+            sqlx::query_as(&parent_sql).bind(child_id).fetch_optional(&mut *conn).await?;
+        let Some((parent_id, status)) = parent else {
+            return Ok(());
+        };
+        let (open_siblings,): (i64,) = sqlx::query_as(&siblings_sql).bind(parent_id).fetch_one(&mut *conn).await?;
+        if !matches!(status.as_str(), "open" | "in_progress") || open_siblings > 0 {
+            return Ok(());
+        }
+        sqlx::query(&update_sql).bind(parent_id).execute(&mut *conn).await?;
+        child_id = parent_id;
+    }
+}
+
+///
+/// The `replace_todo` of `TodoRepoAny`, on a connection that is already in a
+/// transaction.
+///
+async fn replace_todo_any(
+    conn: &mut AnyConnection,
+    dialect: AnyDialect,
+    user_id: i64,
+    is_admin: bool,
+    id: i64,
+    todo: &PatchableTodo,
+) -> Result<Option<i64>, sqlx::Error> {
+    let sql = dialect.sql(&format!(
+        "UPDATE todos SET title = ?, description = ?, status = {}, due_at = {}, priority = ?, metadata = {}
+        WHERE id = ? AND (owner_id = ? OR ?)",
+        dialect.status(),
+        dialect.time(),
+        dialect.json()
+    ));
+    let query = sqlx::query(&sql)
+        .bind(&todo.title)
+        .bind(&todo.description)
+        .bind(todo.status.as_str())
+        .bind(todo.due_at.map(unix_micros))
+        .bind(todo.priority)
+        .bind(serde_json::to_string(&todo.metadata).unwrap())
+        .bind(id)
+        .bind(user_id)
+        .bind(is_admin);
+
+    if query.execute(&mut *conn).await?.rows_affected() == 0 {
+        return Ok(None);
+    }
+    complete_parents_any(conn, dialect, id).await?;
+    Ok(Some(id))
+}
+
+async fn insert_todo_any(
+    conn: &mut AnyConnection,
+    dialect: AnyDialect,
+    user_id: i64,
+    title: &str,
+    description: &str,
+    due_at: Option<OffsetDateTime>,
+    priority: i32,
+) -> Result<i64, sqlx::Error> {
+    let sql = dialect.sql(&format!(
+        "INSERT INTO todos (title, description, due_at, priority, owner_id) VALUES (?, ?, {}, ?, ?){}",
+        dialect.time(),
+        dialect.returning_id()
+    ));
+    let query = sqlx::query(&sql)
+        .bind(title)
+        .bind(description)
+        .bind(due_at.map(unix_micros))
+        .bind(priority)
+        .bind(user_id);
+    Ok(inserted_id_any(conn, dialect, query).await?.expect("an INSERT of VALUES adds a row"))
+}
+
+async fn apply_bulk_operation_any(
+    conn: &mut AnyConnection,
+    dialect: AnyDialect,
+    user_id: i64,
+    is_admin: bool,
+    operation: &BulkOperation,
+) -> Result<i64, BulkError> {
+    match operation {
+        BulkOperation::Create { title, description, due_at, priority } => {
+            Ok(insert_todo_any(conn, dialect, user_id, title, description, *due_at, *priority).await?)
+        }
+        BulkOperation::Update { id, title, description, status, due_at, priority } => {
+            let mut sets = Vec::new();
+            let mut arguments = AnyArguments::default();
+            if let Some(title) = title {
+                sets.push("title = ?".to_string());
+                arguments.add(title.as_str());
+            }
+            if let Some(description) = description {
+                sets.push("description = ?".to_string());
+                arguments.add(description.as_str());
+            }
+            if let Some(status) = status {
+                sets.push(format!("status = {}", dialect.status()));
+                arguments.add(status.as_str());
+            }
+            if let Some(due_at) = due_at {
+                sets.push(format!("due_at = {}", dialect.time()));
+                arguments.add(unix_micros(*due_at));
+            }
+            if let Some(priority) = priority {
+                sets.push("priority = ?".to_string());
+                arguments.add(*priority);
+            }
+            // With nothing to change, the update still tells whether the
+            // todo is there to be changed.
+            if sets.is_empty() {
+                sets.push("id = id".to_string());
+            }
+            arguments.add(*id);
+            arguments.add(user_id);
+            arguments.add(is_admin);
+
+            let sql = dialect.sql(&format!("UPDATE todos SET {} WHERE id = ? AND (owner_id = ? OR ?)", sets.join(", ")));
+            let updated = sqlx::query_with(&sql, arguments).execute(&mut *conn).await?;
+            if updated.rows_affected() == 0 {
+                return Err(BulkError::NotFound(*id));
+            }
+            complete_parents_any(conn, dialect, *id).await?;
+            Ok(*id)
+        }
+        BulkOperation::Delete { id } => {
+            let deleted = sqlx::query(&dialect.sql("DELETE FROM todos WHERE id = ? AND (owner_id = ? OR ?)"))
+                .bind(id)
+                .bind(user_id)
+                .bind(is_admin)
+                .execute(&mut *conn)
+                .await?;
+            if deleted.rows_affected() == 0 {
+                return Err(BulkError::NotFound(*id));
+            }
+            Ok(*id)
+        }
+    }
+}
+
+#[async_trait]
+impl TodoRepo for TodoRepoAny {
+    async fn get_todos(&self, user_id: i64, sort: TodoSort) -> Result<Vec<Todo>, RepoError> {
+        // Postgres sorts NULLs last, but MySQL and SQLite sort them first, so
+        // `due_at IS NULL` puts them last in all three.
+        let order = match sort {
+            TodoSort::Id => "todos.id",
+            TodoSort::Priority => "todos.priority DESC, todos.id",
+            TodoSort::DueAt => "todos.due_at IS NULL, todos.due_at, todos.id",
+        };
+        let sql = self.select(&format!("WHERE (owner_id = ? OR ?) ORDER BY {}", order));
+        let is_admin = self.is_admin(user_id).await;
+        Ok(self.fetch_todos(sqlx::query_as(&sql).bind(user_id).bind(is_admin)).await?)
+    }
+    async fn get_todos_page(
+        &self,
+        user_id: i64,
+        after: Option<TodoCursor>,
+        limit: i64,
+    ) -> Result<Vec<Todo>, RepoError> {
+        let is_admin = self.is_admin(user_id).await;
+        let Some(TodoCursor { created_at, id }) = after else {
+            let sql = self.select("WHERE (owner_id = ? OR ?) ORDER BY todos.created_at, todos.id LIMIT ?");
+            return Ok(self.fetch_todos(sqlx::query_as(&sql).bind(user_id).bind(is_admin).bind(limit)).await?);
+        };
+        let sql = self.select(&format!(
+            "WHERE (owner_id = ? OR ?) AND (todos.created_at, todos.id) > ({}, ?)
+            ORDER BY todos.created_at, todos.id LIMIT ?",
+            self.dialect.time()
+        ));
+        let query =
+            sqlx::query_as(&sql).bind(user_id).bind(is_admin).bind(unix_micros(created_at)).bind(id).bind(limit);
+        Ok(self.fetch_todos(query).await?)
+    }
+    async fn get_todo(&self, user_id: i64, id: i64) -> Result<Option<Todo>, RepoError> {
+        let sql = self.select("WHERE todos.id = ? AND (owner_id = ? OR ?)");
+        let is_admin = self.is_admin(user_id).await;
+        Ok(self.fetch_todos(sqlx::query_as(&sql).bind(id).bind(user_id).bind(is_admin)).await?.pop())
+    }
+    async fn get_todos_filtered(&self, user_id: i64, filter: &TodoFilter) -> Result<Vec<Todo>, RepoError> {
+        let mut sql = "WHERE (owner_id = ? OR ?)".to_string();
+        let mut arguments = AnyArguments::default();
+        arguments.add(user_id);
+        arguments.add(self.is_admin(user_id).await);
+        if !filter.statuses.is_empty() {
+            let statuses = vec![self.dialect.status(); filter.statuses.len()];
+            sql.push_str(&format!(" AND todos.status IN ({})", statuses.join(", ")));
+            for status in &filter.statuses {
+                arguments.add(status.as_str());
+            }
+        }
+        if let Some(due_after) = filter.due_after {
+            sql.push_str(&format!(" AND todos.due_at >= {}", self.dialect.time()));
+            arguments.add(unix_micros(due_after));
+        }
+        if let Some(due_before) = filter.due_before {
+            sql.push_str(&format!(" AND todos.due_at < {}", self.dialect.time()));
+            arguments.add(unix_micros(due_before));
+        }
+        let metadata_contains = self.dialect.metadata_contains();
+        if let Some(contains) = metadata_contains {
+            sql.push_str(&format!(" AND {}", contains));
+            arguments.add(filter.metadata.to_string());
+        }
+        sql.push_str(" ORDER BY todos.id");
+
+        let sql = self.select(&sql);
+        let todos = self.fetch_todos(sqlx::query_as_with(&sql, arguments)).await?;
+        if metadata_contains.is_some() {
+            return Ok(todos);
+        }
+        Ok(todos.into_iter().filter(|todo| json_contains(&todo.metadata, &filter.metadata)).collect())
+    }
+    async fn get_todo_tree(&self, user_id: i64, id: i64) -> Result<Vec<Todo>, RepoError> {
+        let sql = self.dialect.sql(&format!(
+            "WITH RECURSIVE tree (id, depth) AS (
+                SELECT id, 0 FROM todos WHERE id = ? AND (owner_id = ? OR ?)
+                UNION ALL
+                SELECT todos.id, tree.depth + 1 FROM todos JOIN tree ON todos.parent_id = tree.id
+                WHERE (todos.owner_id = ? OR ?)
+            )
+            SELECT {} FROM todos JOIN tree ON todos.id = tree.id ORDER BY tree.depth, todos.id",
+            self.dialect.todo_columns()
+        ));
+        let is_admin = self.is_admin(user_id).await;
+        Ok(self
+            .fetch_todos(sqlx::query_as(&sql).bind(id).bind(user_id).bind(is_admin).bind(user_id).bind(is_admin))
+            .await?)
+    }
+    async fn set_parent(
+        &self,
+        user_id: i64,
+        id: i64,
+        parent_id: Option<i64>,
+    ) -> Result<Result<(), SubtaskError>, RepoError> {
+        if self.get_todo(user_id, id).await?.is_none() {
+            return Ok(Err(SubtaskError::NotFound(id)));
+        }
+        if let Some(parent_id) = parent_id {
+            if self.get_todo(user_id, parent_id).await?.is_none() {
+                return Ok(Err(SubtaskError::NotFound(parent_id)));
+            }
+        }
+
+        let mut tx = self.pool.begin().await?;
+        let (cycles,): (i64,) = sqlx::query_as(&self.dialect.sql(
+            "WITH RECURSIVE descendants (id) AS (
+                SELECT id FROM todos WHERE id = ?
+                UNION ALL
+                SELECT todos.id FROM todos JOIN descendants ON todos.parent_id = descendants.id
+            )
+            SELECT COUNT(*) FROM descendants WHERE id = ?",
+        ))
+        .bind(id)
+        .bind(parent_id)
+        .fetch_one(&mut *tx)
+        .await?;
+        if cycles > 0 {
+            return Ok(Err(SubtaskError::Cycle));
+        }
+        sqlx::query(&self.dialect.sql("UPDATE todos SET parent_id = ? WHERE id = ?"))
+            .bind(parent_id)
+            .bind(id)
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+        Ok(Ok(()))
+    }
+    async fn get_overdue_todos(&self, user_id: i64, now: OffsetDateTime) -> Result<Vec<Todo>, RepoError> {
+        let sql = self.select(&format!(
+            "WHERE todos.status IN ('open', 'in_progress') AND todos.due_at < {} AND (owner_id = ? OR ?)
+            ORDER BY todos.due_at, todos.id",
+            self.dialect.time()
+        ));
+        let is_admin = self.is_admin(user_id).await;
+        Ok(self.fetch_todos(sqlx::query_as(&sql).bind(unix_micros(now)).bind(user_id).bind(is_admin)).await?)
+    }
+    async fn get_todos_due_on(&self, user_id: i64, day: Date, time_zone: &str) -> Result<Option<Vec<Todo>>, RepoError> {
+        let Some(next_day) = day.next_day() else {
+            return Ok(None);
+        };
+        let (start, end) = match self.dialect.midnight_in_zone() {
+            None if time_zone == "UTC" => {
+                (unix_micros(day.midnight().assume_utc()), unix_micros(next_day.midnight().assume_utc()))
+            }
+            None => return Ok(None),
+            Some(midnight) => {
+                let sql = self.dialect.sql(&format!("SELECT {}, {}", midnight, midnight));
+                let query = sqlx::query(&sql)
+                    .bind(day.to_string())
+                    .bind(time_zone)
+                    .bind(next_day.to_string())
+                    .bind(time_zone);
+                let row = match query.fetch_one(&self.pool).await {
+                    Ok(row) => row,
+                    // invalid_parameter_value, from Postgres: "time zone ... not recognized"
+                    Err(sqlx::Error::Database(error)) if error.code().as_deref() == Some("22023") => return Ok(None),
+                    Err(error) => return Err(error.into()),
+                };
+                // NULL where MySQL does not know the zone.
+                let (Some(start), Some(end)) = (nullable_any(&row, 0)?, nullable_any(&row, 1)?) else {
+                    return Ok(None);
+                };
+                (start, end)
+            }
+        };
+
+        let sql = self.select(&format!(
+            "WHERE todos.due_at >= {} AND todos.due_at < {} AND (owner_id = ? OR ?) ORDER BY todos.due_at, todos.id",
+            self.dialect.time(),
+            self.dialect.time()
+        ));
+        let is_admin = self.is_admin(user_id).await;
+        Ok(Some(self.fetch_todos(sqlx::query_as(&sql).bind(start).bind(end).bind(user_id).bind(is_admin)).await?))
+    }
+    async fn get_stats(&self, user_id: i64) -> Result<TodoStats, RepoError> {
+        let is_admin = self.is_admin(user_id).await;
+
+        // Statuses are read as text, so they are put in the order of the
+        // Postgres enum here.
+        let by_status: Vec<(String, i64)> = sqlx::query_as(&self.dialect.sql(&format!(
+            "SELECT {}, COUNT(*) FROM todos WHERE (owner_id = ? OR ?) GROUP BY todos.status",
+            self.dialect.text("todos.status")
+        )))
+        .bind(user_id)
+        .bind(is_admin)
+        .fetch_all(&self.pool)
+        .await?;
+        let mut by_status: Vec<StatusCount> = by_status
+            .into_iter()
+            .map(|(status, count)| StatusCount { status: status.parse().unwrap(), count })
+            .collect();
+        by_status.sort_by_key(|count| count.status as u8);
+
+        // Without `generate_series` in MySQL and SQLite, the days without
+        // todos are filled in here.
+        let today = OffsetDateTime::now_utc().date();
+        let first_day = today - time::Duration::days(29);
+        let counts: HashMap<String, i64> = sqlx::query_as(&self.dialect.sql(&format!(
+            "SELECT {} AS day, COUNT(*) FROM todos WHERE todos.created_at >= {} AND (owner_id = ? OR ?) GROUP BY day",
+            self.dialect.day("todos.created_at"),
+            self.dialect.time()
+        )))
+        .bind(unix_micros(first_day.midnight().assume_utc()))
+        .bind(user_id)
+        .bind(is_admin)
+        .fetch_all(&self.pool)
+        .await?
+        .into_iter()
+        .collect();
+        let created_per_day = (0..30)
+            .rev()
+            .map(|days_ago| today - time::Duration::days(days_ago))
+            .map(|day| format!("{}-{:02}-{:02}", day.year(), u8::from(day.month()), day.day()))
+            .map(|day| DailyCount { count: counts.get(&day).copied().unwrap_or(0), day })
+            .collect();
+
+        let average = format!(
+            "AVG({} - {})",
+            self.dialect.micros("todos.completed_at"),
+            self.dialect.micros("todos.created_at")
+        );
+        let row = sqlx::query(&self.dialect.sql(&format!(
+            "SELECT {} / 1e6 FROM todos WHERE todos.completed_at IS NOT NULL AND (owner_id = ? OR ?)",
+            self.dialect.double(&average)
+        )))
+        .bind(user_id)
+        .bind(is_admin)
+        .fetch_one(&self.pool)
+        .await?;
+        let average_completion_seconds = nullable_any(&row, 0)?;
+
+        Ok(TodoStats { by_status, created_per_day, average_completion_seconds })
+    }
+    async fn create_todo(
+        &self,
+        user_id: i64,
+        title: &str,
+        description: &str,
+        due_at: Option<OffsetDateTime>,
+        priority: i32,
+    ) -> Result<i64, RepoError> {
+        let mut conn = self.pool.acquire().await?;
+        Ok(insert_todo_any(&mut conn, self.dialect, user_id, title, description, due_at, priority).await?)
+    }
+    async fn create_many(&self, user_id: i64, todos: &[CreateTodo]) -> Result<Vec<i64>, RepoError> {
+        let mut tx = self.pool.begin().await?;
+        let mut ids = Vec::with_capacity(todos.len());
+        for todo in todos {
+            let id = insert_todo_any(&mut tx, self.dialect, user_id, &todo.title, &todo.description, todo.due_at, todo.priority);
+            ids.push(id.await?);
+        }
+        tx.commit().await?;
+        Ok(ids)
+    }
+    async fn update_todo(
+        &self,
+        user_id: i64,
+        id: i64,
+        title: Option<&str>,
+        description: Option<&str>,
+        status: Option<TodoStatus>,
+        due_at: Option<OffsetDateTime>,
+        priority: Option<i32>,
+    ) -> Result<Option<i64>, RepoError> {
+        let operation = BulkOperation::Update {
+            id,
+            title: title.map(str::to_string),
+            description: description.map(str::to_string),
+            status,
+            due_at,
+            priority,
+        };
+        let is_admin = self.is_admin(user_id).await;
+        let mut tx = self.pool.begin().await?;
+        match apply_bulk_operation_any(&mut tx, self.dialect, user_id, is_admin, &operation).await {
+            Ok(id) => {
+                tx.commit().await?;
+                Ok(Some(id))
+            }
+            Err(BulkError::NotFound(_)) => Ok(None),
+            Err(error) => Err(error.into()),
+        }
+    }
+    async fn replace_todo(&self, user_id: i64, id: i64, todo: &PatchableTodo) -> Result<Option<i64>, RepoError> {
+        let is_admin = self.is_admin(user_id).await;
+        let mut tx = self.pool.begin().await?;
+        let Some(id) = replace_todo_any(&mut tx, self.dialect, user_id, is_admin, id, todo).await? else {
+            return Ok(None);
+        };
+        tx.commit().await?;
+        Ok(Some(id))
+    }
+    async fn patch_todo(
+        &self,
+        user_id: i64,
+        id: i64,
+        patch: &TodoPatch<'_>,
+    ) -> Result<Option<Result<i64, ExtractError>>, RepoError> {
+        // As in `TodoRepoPostgres`, a concurrent patch of the todo waits for
+        // this one, and patches what this one saved.
+        let is_admin = self.is_admin(user_id).await;
+        let sql = self.select(&format!("WHERE todos.id = ? AND (owner_id = ? OR ?){}", self.dialect.for_update()));
+        let mut tx = self.pool.begin().await?;
+        let query: AnyTodoQuery = sqlx::query_as(&sql).bind(id).bind(user_id).bind(is_admin);
+        let Some(row) = query.fetch_optional(&mut *tx).await? else {
+            return Ok(None);
+        };
+
+        let patched = match patch(Todo::from(row)) {
+            Ok(patched) => patched,
+            Err(error) => return Ok(Some(Err(error))),
+        };
+        let Some(id) = replace_todo_any(&mut tx, self.dialect, user_id, is_admin, id, &patched).await? else {
+            return Ok(None);
+        };
+        tx.commit().await?;
+        Ok(Some(Ok(id)))
+    }
+    async fn delete_todo(&self, user_id: i64, id: i64) -> Result<Option<i64>, RepoError> {
+        let is_admin = self.is_admin(user_id).await;
+        let mut conn = self.pool.acquire().await?;
+        match apply_bulk_operation_any(&mut conn, self.dialect, user_id, is_admin, &BulkOperation::Delete { id }).await
+        {
+            Ok(id) => Ok(Some(id)),
+            Err(BulkError::NotFound(_)) => Ok(None),
+            Err(error) => Err(error.into()),
+        }
+    }
+    async fn bulk(
+        &self,
+        user_id: i64,
+        operations: &[BulkOperation],
+        atomic: bool,
+    ) -> Result<Vec<Result<i64, BulkError>>, RepoError> {
+        let is_admin = self.is_admin(user_id).await;
+        if !atomic {
+            let mut conn = self.pool.acquire().await?;
+            let mut results = Vec::with_capacity(operations.len());
+            for operation in operations {
+                results.push(apply_bulk_operation_any(&mut conn, self.dialect, user_id, is_admin, operation).await);
+            }
+            return Ok(results);
+        }
+
+        let mut tx = self.pool.begin().await?;
+        let mut results = Vec::with_capacity(operations.len());
+        for operation in operations {
+            let result = apply_bulk_operation_any(&mut tx, self.dialect, user_id, is_admin, operation).await;
+            let failed = result.is_err();
+            results.push(result);
+            if failed {
+                tx.rollback().await?;
+                return Ok(rolled_back(results, operations.len()));
+            }
+        }
+        tx.commit().await?;
+        Ok(results)
+    }
+    async fn claim_next_todo(&self, user_id: i64) -> Result<Option<Todo>, RepoError> {
+        // Locked first, and updated after, as MySQL cannot update the table
+        // its subquery reads.
+        let is_admin = self.is_admin(user_id).await;
+        let mut tx = self.pool.begin().await?;
+        let Some((id,)): Option<(i64,)> = sqlx::query_as(&self.dialect.sql(&format!(
+            "SELECT id FROM todos WHERE assignee_id IS NULL AND status = 'open' AND (owner_id = ? OR ?)
+            ORDER BY id LIMIT 1{}",
+            self.dialect.for_update_skip_locked()
+        )))
+        .bind(user_id)
+        .bind(is_admin)
+        .fetch_optional(&mut *tx)
+        .await?
+        else {
+            return Ok(None);
+        };
+        sqlx::query(&self.dialect.sql("UPDATE todos SET assignee_id = ?, status = 'in_progress' WHERE id = ?"))
+            .bind(user_id)
+            .bind(id)
+            .execute(&mut *tx)
+            .await?;
+        let todo: AnyTodoRow = sqlx::query_as(&self.select("WHERE todos.id = ?")).bind(id).fetch_one(&mut *tx).await?;
+        tx.commit().await?;
+        Ok(Some(todo.into()))
+    }
+    async fn get_comments(&self, user_id: i64, todo_id: i64) -> Result<Vec<Comment>, RepoError> {
+        self.get_comments_of(user_id, &[todo_id]).await
+    }
+    async fn get_comments_of(&self, user_id: i64, todo_ids: &[i64]) -> Result<Vec<Comment>, RepoError> {
+        if todo_ids.is_empty() {
+            return Ok(Vec::new());
+        }
+        let sql = self.dialect.sql(&format!(
+            "SELECT comments.id, comments.todo_id, comments.body, {} AS created_at
+            FROM comments JOIN todos ON todos.id = comments.todo_id
+            WHERE comments.todo_id IN ({}) AND (todos.owner_id = ? OR ?) ORDER BY comments.id",
+            self.dialect.micros_text("comments.created_at"),
+            vec!["?"; todo_ids.len()].join(", ")
+        ));
+        let mut arguments = AnyArguments::default();
+        for todo_id in todo_ids {
+            arguments.add(*todo_id);
+        }
+        arguments.add(user_id);
+        arguments.add(self.is_admin(user_id).await);
+        let rows = sqlx::query_with(&sql, arguments).fetch_all(&self.pool).await?;
+        rows.iter()
+            .map(|row| {
+                use sqlx::Row;
+
+                Ok(Comment {
+                    id: row.get("id"),
+                    todo_id: row.get("todo_id"),
+                    body: row.get("body"),
+                    created_at: from_unix_micros(micros_any(row, "created_at")?.unwrap()),
+                })
+            })
+            .collect()
+    }
+    async fn create_comment(&self, user_id: i64, todo_id: i64, body: &str) -> Result<Option<i64>, RepoError> {
+        let sql = self.dialect.sql(&format!(
+            "INSERT INTO comments (todo_id, body) SELECT id, ? FROM todos WHERE id = ? AND (owner_id = ? OR ?){}",
+            self.dialect.returning_id()
+        ));
+        let query = sqlx::query(&sql).bind(body).bind(todo_id).bind(user_id).bind(self.is_admin(user_id).await);
+        let mut conn = self.pool.acquire().await?;
+        Ok(inserted_id_any(&mut conn, self.dialect, query).await?)
+    }
+    async fn delete_comment(&self, user_id: i64, todo_id: i64, comment_id: i64) -> Result<bool, RepoError> {
+        let sql = self.dialect.sql(
+            "DELETE FROM comments WHERE todo_id = ? AND id = ?
+            AND todo_id IN (SELECT id FROM todos WHERE owner_id = ? OR ?)",
+        );
+        let query = sqlx::query(&sql).bind(todo_id).bind(comment_id).bind(user_id).bind(self.is_admin(user_id).await);
+        Ok(query.execute(&self.pool).await?.rows_affected() > 0)
+    }
+    async fn erase_user(&self, user_id: i64, dry_run: bool) -> Result<TodoErasure, RepoError> {
+        let sql = self.dialect.sql(
+            "WITH RECURSIVE erased_todos AS (
+                SELECT id FROM todos WHERE owner_id = ?
+                UNION
+                SELECT todos.id FROM todos JOIN erased_todos ON todos.parent_id = erased_todos.id
+            )
+            SELECT
+                (SELECT COUNT(*) FROM erased_todos),
+                (SELECT COUNT(*) FROM comments WHERE todo_id IN (SELECT id FROM erased_todos)),
+                (SELECT COUNT(*) FROM todos WHERE assignee_id = ? AND id NOT IN (SELECT id FROM erased_todos))",
+        );
+        let mut tx = self.pool.begin().await?;
+        let (todos, comments, unassigned_todos): (i64, i64, i64) =
+            sqlx::query_as(&sql).bind(user_id).bind(user_id).fetch_one(&mut *tx).await?;
+        if !dry_run {
+            // The foreign keys delete the subtasks and comments.
+            let delete = self.dialect.sql("DELETE FROM todos WHERE owner_id = ?");
+            sqlx::query(&delete).bind(user_id).execute(&mut *tx).await?;
+            let unassign = self.dialect.sql("UPDATE todos SET assignee_id = NULL WHERE assignee_id = ?");
+            sqlx::query(&unassign).bind(user_id).execute(&mut *tx).await?;
+        }
+        tx.commit().await?;
+        Ok(TodoErasure { todos, comments, unassigned_todos })
+    }
+}
+
+#[tokio::test]
+async fn todo_handlers_work_through_any_pool() {
+    use std::sync::Arc;
+    use sqlx::postgres::PgPoolOptions;
+    use super::conformance::run_todo_handler_suite;
+
+    let url = crate::test_db::database_url().await;
+    let pool = PgPoolOptions::new().max_connections(1).connect(&url).await.unwrap();
+    let user_id = crate::users::create_test_user(&pool, false).await;
+    let any = TodoRepoAny::pool_options(&PoolConfig::default(), AnyDialect::Postgres).connect(&url).await.unwrap();
+    let repo = TodoRepoAny { pool: any, dialect: AnyDialect::Postgres, users: UserRepoPostgres::new(pool, crate::pii::test_cipher()) };
+    run_todo_handler_suite(Arc::new(repo), user_id).await;
+}
@@ -0,0 +1,334 @@
+#![allow(dead_code)]
+
+//!
+//! CONFORMANCE
+//! -----------
+//!
+//! The tests that every `TodoRepo` runs, each with a test of its own, to
+//! check that it behaves as the others do: through the trait, and through
+//! the handlers.
+//!
+
+use std::sync::Arc;
+
+use crate::auth::{AuthState, JwtKeys};
+use crate::clock::{SharedClock, SystemClock};
+use crate::extract::ExtractError;
+use crate::persistence::{
+    shared_todo_routes, todo_routes, BulkError, BulkOperation, CreateTodo, PatchableTodo, SharedTodoRepo, SubtaskError,
+    Todo, TodoBulk, TodoById, TodoClaim, TodoCollection, TodoComment, TodoComments, TodoCursor, TodoDTO, TodoDue,
+    TodoErasure, TodoFilter, TodoPage, TodoParent, TodoRepo, TodoSort, TodoState, TodoStats, TodoStatsPath, TodoStatus,
+    TodoTree, TodoTreeDTO, TodoWithCommentsDTO,
+};
+use crate::signed_urls::UrlSigner;
+use crate::websocket::TodoEvents;
+use axum::{body::Bytes, extract::FromRef, http::StatusCode, Router};
+use futures::TryStreamExt;
+
+///
+/// The todo routes over any repo, as the app has them, and the value of an
+/// `Authorization` header for the given user.
+///
+pub async fn test_todo_app_as(repo: SharedTodoRepo, user_id: i64, clock: SharedClock) -> (Router, String) {
+    use crate::auth::RefreshTokenRepoInMemory;
+
+    #[derive(Clone, FromRef)]
+    struct TestState {
+        todos: TodoState<SharedTodoRepo>,
+        keys: JwtKeys,
+        signer: UrlSigner,
+    }
+
+    let keys = JwtKeys::from_secret(b"secret").with_clock(clock.clone());
+    let auth = AuthState { repo: RefreshTokenRepoInMemory::default(), keys: keys.clone() };
+    let tokens = auth.issue_tokens(user_id).await;
+
+    let signer = UrlSigner::from_secret(b"secret", clock.clone());
+    let app = todo_routes::<_, SharedTodoRepo>(8)
+        .merge(shared_todo_routes::<_, SharedTodoRepo>())
+        .into_router()
+        .with_state(TestState { todos: TodoState { repo, clock, events: TodoEvents::default() }, keys, signer });
+
+    (app, format!("Bearer {}", tokens.access_token))
+}
+
+///
+/// Works through the todo API as a client would, through every kind of
+/// handler, as the given user, who must have no todos yet. Every repo is
+/// expected to pass it, so each runs it with a test of its own.
+///
+pub async fn run_todo_handler_suite(repo: SharedTodoRepo, user_id: i64) {
+    // for Body::collect
+    use http_body_util::BodyExt;
+    /// for ServiceExt::oneshot
+    use tower::util::ServiceExt;
+    use axum::{body::Body, http::{Method, Request}};
+
+    let (app, token) = test_todo_app_as(repo, user_id, Arc::new(SystemClock)).await;
+    let send = |method: Method, uri: String, body: Option<&str>| {
+        let request = Request::builder().method(method.clone()).uri(uri).header("Authorization", &token);
+        let request = match body {
+            Some(body) => {
+                let content_type = if method == Method::PATCH { "application/merge-patch+json" } else { "application/json" };
+                request.header("Content-Type", content_type).body(Body::from(body.to_string()))
+            }
+            None => request.body(Body::empty()),
+        };
+        let response = app.clone().oneshot(request.unwrap());
+        async move {
+            let response = response.await.unwrap();
+            let status = response.status();
+            (status, response.into_body().collect().await.unwrap().to_bytes())
+        }
+    };
+    let json = |body: &Bytes| serde_json::from_slice::<serde_json::Value>(body).unwrap();
+    let ids = |body: &Bytes| json(body).as_array().unwrap().iter().map(|todo| todo["id"].as_i64().unwrap()).collect::<Vec<_>>();
+
+    let (_, body) = send(Method::POST, TodoCollection.to_string(), Some(r#"{"title": "Suite", "description": "Parent", "priority": 1}"#)).await;
+    let parent: i64 = serde_json::from_slice(&body).unwrap();
+    let child_body = r#"{"title": "Suite", "description": "Child", "due_at": "2026-10-25T10:00:00Z", "priority": 2}"#;
+    let (_, body) = send(Method::POST, TodoCollection.to_string(), Some(child_body)).await;
+    let child: i64 = serde_json::from_slice(&body).unwrap();
+
+    // Subtasks, and the tree of them.
+    let (status, _) = send(Method::PUT, TodoParent { id: child }.to_string(), Some(&format!(r#"{{"parent_id": {}}}"#, parent))).await;
+    assert_eq!(status, StatusCode::NO_CONTENT);
+    let (status, _) = send(Method::PUT, TodoParent { id: parent }.to_string(), Some(&format!(r#"{{"parent_id": {}}}"#, child))).await;
+    assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+    let (_, body) = send(Method::GET, TodoTree { id: parent }.to_string(), None).await;
+    let tree: TodoTreeDTO = serde_json::from_slice(&body).unwrap();
+    assert_eq!(tree.subtasks.iter().map(|subtask| subtask.todo.id).collect::<Vec<_>>(), vec![child]);
+
+    // Lists, sorted, paged, due on a day, and filtered.
+    let (_, body) = send(Method::GET, format!("{}?sort=priority", TodoCollection), None).await;
+    assert_eq!(ids(&body), vec![child, parent]);
+    let (_, body) = send(Method::GET, format!("{}?limit=1", TodoCollection), None).await;
+    let page: TodoPage = serde_json::from_slice(&body).unwrap();
+    assert_eq!(page.todos.iter().map(|todo| todo.id).collect::<Vec<_>>(), vec![parent]);
+    let (_, body) = send(Method::GET, format!("{}?limit=1&after={}", TodoCollection, page.next_cursor.unwrap()), None).await;
+    let page: TodoPage = serde_json::from_slice(&body).unwrap();
+    assert_eq!((page.todos.iter().map(|todo| todo.id).collect::<Vec<_>>(), page.next_cursor), (vec![child], None));
+    let (_, body) = send(Method::GET, format!("{}?date=2026-10-25&tz=UTC", TodoDue), None).await;
+    assert_eq!(ids(&body), vec![child]);
+    let (status, _) = send(Method::PATCH, TodoById { id: parent }.to_string(), Some(r#"{"metadata": {"tags": ["home", "urgent"]}}"#)).await;
+    assert_eq!(status, StatusCode::OK);
+    let (_, body) = send(Method::GET, format!("{}?tag[]=urgent", TodoCollection), None).await;
+    assert_eq!(ids(&body), vec![parent]);
+    let (_, body) = send(Method::GET, format!("{}?status[]=done", TodoCollection), None).await;
+    assert_eq!(ids(&body), Vec::<i64>::new());
+
+    // Comments.
+    let (_, body) = send(Method::POST, TodoComments { id: child }.to_string(), Some(r#"{"body": "A comment"}"#)).await;
+    let comment: i64 = serde_json::from_slice(&body).unwrap();
+    let (_, body) = send(Method::GET, TodoComments { id: child }.to_string(), None).await;
+    let with_comments: TodoWithCommentsDTO = serde_json::from_slice(&body).unwrap();
+    assert_eq!(with_comments.comments.iter().map(|comment| comment.id).collect::<Vec<_>>(), vec![comment]);
+    let (status, _) = send(Method::DELETE, TodoComment { id: child, comment_id: comment }.to_string(), None).await;
+    assert_eq!(status, StatusCode::NO_CONTENT);
+
+    // Completing the only subtask completes its parent.
+    let (_, body) = send(Method::PUT, TodoById { id: child }.to_string(), Some(r#"{"status": "done"}"#)).await;
+    assert_eq!(json(&body), serde_json::json!(child));
+    let (_, body) = send(Method::GET, TodoById { id: parent }.to_string(), None).await;
+    let todo: TodoDTO = serde_json::from_slice(&body).unwrap();
+    assert_eq!(todo.status, TodoStatus::Done);
+    assert!(todo.completed_at.is_some());
+
+    // Claims, of which there is one to make.
+    let (status, _) = send(Method::POST, TodoClaim.to_string(), None).await;
+    assert_eq!(status, StatusCode::NO_CONTENT);
+    let (_, body) = send(Method::POST, TodoCollection.to_string(), Some(r#"{"title": "Suite", "description": "Claimed"}"#)).await;
+    let claimed: i64 = serde_json::from_slice(&body).unwrap();
+    let (_, body) = send(Method::POST, TodoClaim.to_string(), None).await;
+    let todo: TodoDTO = serde_json::from_slice(&body).unwrap();
+    assert_eq!((todo.id, todo.status), (claimed, TodoStatus::InProgress));
+
+    let (_, body) = send(Method::GET, TodoStatsPath.to_string(), None).await;
+    let stats: TodoStats = serde_json::from_slice(&body).unwrap();
+    let by_status: Vec<(TodoStatus, i64)> = stats.by_status.iter().map(|count| (count.status, count.count)).collect();
+    assert_eq!(by_status, vec![(TodoStatus::InProgress, 1), (TodoStatus::Done, 2)]);
+    assert_eq!(stats.created_per_day.last().unwrap().count, 3);
+    assert!(stats.average_completion_seconds.is_some());
+
+    // Bulk operations, undone together.
+    let bulk = format!(r#"{{"operations": [{{"op": "update", "id": {}, "title": "Renamed"}}, {{"op": "delete", "id": -1}}]}}"#, claimed);
+    let (status, _) = send(Method::POST, TodoBulk.to_string(), Some(&bulk)).await;
+    assert_eq!(status, StatusCode::MULTI_STATUS);
+    let (_, body) = send(Method::GET, TodoById { id: claimed }.to_string(), None).await;
+    assert_eq!(json(&body)["title"], "Suite");
+
+    // Deleting a todo deletes its subtasks.
+    let (_, body) = send(Method::DELETE, TodoById { id: parent }.to_string(), None).await;
+    assert_eq!(json(&body), serde_json::json!(parent));
+    let (_, body) = send(Method::GET, TodoById { id: child }.to_string(), None).await;
+    assert_eq!(json(&body), serde_json::Value::Null);
+    let (_, body) = send(Method::GET, TodoCollection.to_string(), None).await;
+    assert_eq!(ids(&body), vec![claimed]);
+}
+
+///
+/// Checks, through `TodoRepo` alone, that a repo behaves as the others do:
+/// the same todos back from the same calls, the same answers for todos that
+/// do not exist or are not visible, the same pages, and nothing lost or
+/// handed out twice when calls run at once. `make` gives a repo on the same
+/// store each time it is called, as each instance of the app has one of its
+/// own, and each task of the concurrent calls makes one. Neither user may be
+/// an admin, or have any todos yet.
+///
+pub async fn conformance_tests<R: TodoRepo + 'static>(make: impl Fn() -> R, user_id: i64, other_user_id: i64) {
+    use time::macros::datetime;
+
+    let repo = make();
+    let due_at = datetime!(2026-10-25 10:00 UTC);
+
+    // Create, read, update, delete.
+    let id = repo.create_todo(user_id, "Conformance", "Created", Some(due_at), 2).await.unwrap();
+    let todo = repo.get_todo(user_id, id).await.unwrap().unwrap();
+    assert_eq!(
+        (todo.title.as_str(), todo.description.as_str(), todo.status),
+        ("Conformance", "Created", TodoStatus::Open)
+    );
+    assert_eq!((todo.due_at, todo.priority, todo.parent_id, todo.owner_id), (Some(due_at), 2, None, Some(user_id)));
+    assert_eq!((todo.completed_at, todo.metadata), (None, serde_json::json!({})));
+
+    assert_eq!(repo.update_todo(user_id, id, Some("Updated"), None, None, None, None).await.unwrap(), Some(id));
+    let todo = repo.get_todo(user_id, id).await.unwrap().unwrap();
+    assert_eq!((todo.title.as_str(), todo.description.as_str()), ("Updated", "Created"));
+    assert_eq!((todo.due_at, todo.priority), (Some(due_at), 2));
+    assert_eq!(repo.update_todo(user_id, id, None, None, Some(TodoStatus::Done), None, None).await.unwrap(), Some(id));
+    assert!(repo.get_todo(user_id, id).await.unwrap().unwrap().completed_at.is_some());
+
+    let replacement = PatchableTodo {
+        title: "Replaced".to_string(),
+        description: "Replaced".to_string(),
+        status: TodoStatus::Open,
+        due_at: None,
+        priority: 0,
+        metadata: serde_json::json!({ "tags": ["home", "urgent"] }).as_object().unwrap().clone(),
+    };
+    assert_eq!(repo.replace_todo(user_id, id, &replacement).await.unwrap(), Some(id));
+    let todo = repo.get_todo(user_id, id).await.unwrap().unwrap();
+    assert_eq!((todo.title.as_str(), todo.status, todo.due_at, todo.completed_at), ("Replaced", TodoStatus::Open, None, None));
+    let filter = TodoFilter { metadata: serde_json::json!({ "tags": ["urgent"] }), ..TodoFilter::default() };
+    assert_eq!(repo.get_todos_filtered(user_id, &filter).await.unwrap().iter().map(|todo| todo.id).collect::<Vec<_>>(), vec![id]);
+
+    let comment = repo.create_comment(user_id, id, "A comment").await.unwrap().unwrap();
+    let comments = repo.get_comments(user_id, id).await.unwrap();
+    assert_eq!(comments.iter().map(|comment| (comment.id, comment.body.as_str())).collect::<Vec<_>>(), vec![(comment, "A comment")]);
+    assert!(repo.delete_comment(user_id, id, comment).await.unwrap());
+    assert!(!repo.delete_comment(user_id, id, comment).await.unwrap());
+
+    assert_eq!(repo.delete_todo(user_id, id).await.unwrap(), Some(id));
+
+    // A todo that no longer exists, and one of another user, are not found
+    // by any method.
+    let other = repo.create_todo(other_user_id, "Conformance", "Someone else's", None, 0).await.unwrap();
+    for missing in [id, other] {
+        assert!(repo.get_todo(user_id, missing).await.unwrap().is_none());
+        assert_eq!(repo.update_todo(user_id, missing, Some("Found"), None, None, None, None).await.unwrap(), None);
+        assert_eq!(repo.replace_todo(user_id, missing, &replacement).await.unwrap(), None);
+        assert_eq!(repo.delete_todo(user_id, missing).await.unwrap(), None);
+        assert_eq!(repo.set_parent(user_id, missing, None).await.unwrap(), Err(SubtaskError::NotFound(missing)));
+        assert_eq!(repo.create_comment(user_id, missing, "Not found").await.unwrap(), None);
+        assert!(repo.get_comments(user_id, missing).await.unwrap().is_empty());
+        assert_eq!(repo.bulk(user_id, &[BulkOperation::Delete { id: missing }], false).await.unwrap(), vec![Err(BulkError::NotFound(missing))]);
+    }
+    assert!(repo.get_todos(user_id, TodoSort::Id).await.unwrap().is_empty());
+    assert_eq!(repo.get_todo(other_user_id, other).await.unwrap().unwrap().description, "Someone else's");
+    assert_eq!(repo.delete_todo(other_user_id, other).await.unwrap(), Some(other));
+
+    // Pages of todos created together, in one order however they are cut.
+    let todos: Vec<CreateTodo> = (0..5)
+        .map(|n| CreateTodo { title: "Conformance".to_string(), description: format!("Paged {}", n), due_at: None, priority: 0 })
+        .collect();
+    let ids = repo.create_many(user_id, &todos).await.unwrap();
+    let todos = repo.get_todos(user_id, TodoSort::Id).await.unwrap();
+    assert_eq!(todos.iter().map(|todo| todo.id).collect::<Vec<_>>(), ids);
+    let mut paged = Vec::new();
+    let mut after = None;
+    loop {
+        let page = repo.get_todos_page(user_id, after, 2).await.unwrap();
+        assert!(page.len() <= 2);
+        let Some(last) = page.last() else { break };
+        after = Some(TodoCursor { created_at: last.created_at, id: last.id });
+        paged.extend(page.iter().map(|todo| todo.id));
+    }
+    assert_eq!(paged, ids);
+    let deletes: Vec<BulkOperation> = ids.iter().map(|&id| BulkOperation::Delete { id }).collect();
+    assert!(repo.bulk(user_id, &deletes, true).await.unwrap().iter().all(Result::is_ok));
+
+    // Todos created at once all get ids of their own.
+    let created = futures::future::join_all((0..8).map(|n| {
+        let repo = make();
+        tokio::spawn(async move {
+            repo.create_todo(user_id, "Conformance", &format!("Concurrent {}", n), None, 0).await.unwrap()
+        })
+    }))
+    .await;
+    let mut created: Vec<i64> = created.into_iter().map(Result::unwrap).collect();
+    created.sort();
+    created.dedup();
+    assert_eq!(created.len(), 8);
+    assert_eq!(
+        repo.get_todos(user_id, TodoSort::Id).await.unwrap().iter().map(|todo| todo.id).collect::<Vec<_>>(),
+        created
+    );
+
+    // Updates of different fields at once both stick.
+    let (first, second) = (make(), make());
+    let id = created[0];
+    let (renamed, prioritized) = tokio::join!(
+        tokio::spawn(
+            async move { first.update_todo(user_id, id, Some("Renamed"), None, None, None, None).await.unwrap() }
+        ),
+        tokio::spawn(async move { second.update_todo(user_id, id, None, None, None, None, Some(5)).await.unwrap() }),
+    );
+    assert_eq!((renamed.unwrap(), prioritized.unwrap()), (Some(id), Some(id)));
+    let todo = repo.get_todo(user_id, id).await.unwrap().unwrap();
+    assert_eq!((todo.title.as_str(), todo.priority), ("Renamed", 5));
+
+    // Patches at once apply one after the other, each to what the one before
+    // saved, so that none is lost.
+    fn bump(todo: Todo) -> Result<PatchableTodo, ExtractError> {
+        let metadata = todo.metadata.as_object().cloned().unwrap_or_default();
+        let (title, description, status, due_at) = (todo.title, todo.description, todo.status, todo.due_at);
+        Ok(PatchableTodo { title, description, status, due_at, priority: todo.priority + 1, metadata })
+    }
+    let patches = futures::future::join_all((0..8).map(|_| {
+        let repo = make();
+        tokio::spawn(async move { repo.patch_todo(user_id, id, &bump).await.unwrap().unwrap().unwrap() })
+    }))
+    .await;
+    assert!(patches.into_iter().all(|patched| patched.unwrap() == id));
+    assert_eq!(repo.get_todo(user_id, id).await.unwrap().unwrap().priority, 5 + 8);
+
+    // Claims at once, more than there are todos, claim each todo once.
+    let claims = futures::future::join_all((0..16).map(|_| {
+        let repo = make();
+        tokio::spawn(async move { repo.claim_next_todo(user_id).await.unwrap() })
+    }))
+    .await;
+    let mut claimed: Vec<i64> = claims.into_iter().filter_map(Result::unwrap).map(|todo| todo.id).collect();
+    claimed.sort();
+    assert_eq!(claimed, created);
+    let statuses =
+        repo.get_todos(user_id, TodoSort::Id).await.unwrap().iter().map(|todo| todo.status).collect::<Vec<_>>();
+    assert_eq!(statuses, vec![TodoStatus::InProgress; 8]);
+
+    // The account of the user, exported and then erased, with the subtasks
+    // and comments of its todos, and not the todos of others.
+    repo.create_comment(user_id, created[1], "Erased").await.unwrap().unwrap();
+    repo.set_parent(user_id, created[2], Some(created[1])).await.unwrap().unwrap();
+    let other = repo.create_todo(other_user_id, "Conformance", "Kept", None, 0).await.unwrap();
+    let mut exported: Vec<i64> = repo.export_user(user_id).map_ok(|todo| todo.id).try_collect().await.unwrap();
+    exported.sort();
+    assert_eq!(exported, created);
+    let erased = TodoErasure { todos: 8, comments: 1, unassigned_todos: 0 };
+    assert_eq!(repo.erase_user(user_id, true).await.unwrap(), erased);
+    assert_eq!(repo.get_todos(user_id, TodoSort::Id).await.unwrap().len(), 8);
+    assert_eq!(repo.erase_user(user_id, false).await.unwrap(), erased);
+    assert!(repo.get_todos(user_id, TodoSort::Id).await.unwrap().is_empty());
+    assert_eq!(repo.erase_user(user_id, false).await.unwrap(), TodoErasure::default());
+    assert_eq!(repo.get_todo(other_user_id, other).await.unwrap().unwrap().description, "Kept");
+    assert_eq!(repo.delete_todo(other_user_id, other).await.unwrap(), Some(other));
+}
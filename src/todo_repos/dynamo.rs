@@ -0,0 +1,686 @@
+#![allow(dead_code)]
+
+//!
+//! DYNAMODB
+//! --------
+//!
+//! The todos in DynamoDB, with the `dynamodb` feature and
+//! `TODO_REPO=dynamodb` (see `TodoRepoDynamo`, and `EXERCISE 14` in
+//! `persistence.rs`).
+//!
+
+use std::{collections::{BTreeMap, HashMap}, future::Future, sync::{Arc, Mutex}, time::Duration};
+
+use crate::config::AppConfig;
+use crate::ids::SequenceIds;
+use crate::persistence::{
+    BulkError, BulkOperation, Comment, CreateTodo, PatchableTodo, RepoError, SubtaskError, Todo, TodoCursor,
+    TodoErasure, TodoFilter, TodoRepo, TodoRepoInMemory, TodoSort, TodoStats, TodoStatus, TodoTables,
+};
+use crate::users::{UserRepo, UserRepoPostgres};
+use axum::async_trait;
+use aws_sdk_dynamodb::{
+    client::Waiters,
+    error::{DisplayErrorContext, SdkError},
+    operation::{query::builders::QueryFluentBuilder, transact_write_items::TransactWriteItemsError},
+    types::{
+        AttributeDefinition, AttributeValue, BillingMode, ConditionCheck, Delete, GlobalSecondaryIndex, KeySchemaElement,
+        KeyType, Projection, ProjectionType, Put, ReturnValue, ScalarAttributeType, TransactWriteItem,
+    },
+    Client as DynamoClient,
+};
+use futures::FutureExt;
+use sqlx::types::time::{Date, OffsetDateTime};
+
+///
+/// A `TodoRepo` on DynamoDB (`TODO_REPO=dynamodb`), in a single table whose
+/// keys are designed around what the app asks of it:
+///
+/// | `pk`        | `sk`                 | The item                     |
+/// |-------------|----------------------|------------------------------|
+/// | `TODO#<id>` | `TODO`               | A todo.                      |
+/// | `TODO#<id>` | `COMMENT#<id>`       | A comment on the todo.       |
+/// | `COUNTER`   | `todos`, `comments`  | The last id handed out.      |
+///
+/// - A todo and its comments share a partition, so a single `Query` finds the
+///   comments of a todo, in order, as their ids are zero-padded.
+/// - The `by_owner` index finds the todos of a user, by `owner_id`, sorted by
+///   `created`: the zero-padded `created_at` and id, the order of
+///   `get_todos_page`. The key that a page of a query ends on, and that the
+///   next starts after, is made of nothing but those attributes and the keys
+///   of the todo, so it converts to and from the `TodoCursor` of the app.
+/// - DynamoDB has no sequences, so ids are counted with atomic `ADD`s.
+///
+/// The rest of what the app asks (filters, trees, stats) has no key to answer
+/// it, so those reads load the todos of the user through the index, and
+/// answer in memory, with a `TodoRepoInMemory`. So do writes, as there are no
+/// transactions that lock rows: a write changes an in-memory copy, and what
+/// it changed is written back with a single `TransactWriteItems`, each item
+/// on the condition that its `version` is still the one that was read. If
+/// another write got in first, the condition fails, and the write starts over
+/// from a fresh copy. That is optimistic locking. It also covers for the index
+/// being eventually consistent: a write that reads a todo as it was a moment
+/// ago fails its condition, like one that was overtaken.
+///
+/// Admins, who see the todos of every user, scan the whole table. A
+/// transaction writes at most 100 items, so that is the most that a write can
+/// change (only `create_many` writes more, in transactions of 100), and, as in
+/// memory, the only time zone of `get_todos_due_on` is `UTC`.
+///
+#[derive(Clone)]
+pub struct TodoRepoDynamo {
+    client: Arc<tokio::sync::OnceCell<DynamoClient>>,
+    table: String,
+    users: UserRepoPostgres,
+}
+
+type DynamoItem = HashMap<String, AttributeValue>;
+
+impl TodoRepoDynamo {
+    ///
+    /// The repo of the table `config.dynamodb_table`. Its client is
+    /// configured from the environment (`AWS_REGION`, `AWS_ENDPOINT_URL`, and
+    /// the rest) when it is first used, like a pool that connects lazily.
+    ///
+    pub fn connect_lazy(config: &AppConfig, users: UserRepoPostgres) -> Self {
+        TodoRepoDynamo { client: Arc::default(), table: config.dynamodb_table.clone(), users }
+    }
+
+    pub fn with_client(client: DynamoClient, table: &str, users: UserRepoPostgres) -> Self {
+        let client = Arc::new(tokio::sync::OnceCell::new_with(Some(client)));
+        TodoRepoDynamo { client, table: table.to_string(), users }
+    }
+
+    async fn client(&self) -> &DynamoClient {
+        self.client.get_or_init(Self::client_from_env).await
+    }
+
+    pub async fn client_from_env() -> DynamoClient {
+        DynamoClient::new(&aws_config::load_defaults(aws_config::BehaviorVersion::latest()).await)
+    }
+
+    ///
+    /// Creates the table and its index, unless they exist, as the migrations
+    /// do in Postgres. It is billed by request, so there is no capacity to
+    /// plan.
+    ///
+    pub async fn create_table(client: &DynamoClient, table: &str) {
+        let attribute = |name: &str, attribute_type: ScalarAttributeType| {
+            AttributeDefinition::builder().attribute_name(name).attribute_type(attribute_type).build().unwrap()
+        };
+        let key = |name: &str, key_type: KeyType| KeySchemaElement::builder().attribute_name(name).key_type(key_type).build().unwrap();
+        let by_owner = GlobalSecondaryIndex::builder()
+            .index_name("by_owner")
+            .key_schema(key("owner_id", KeyType::Hash))
+            .key_schema(key("created", KeyType::Range))
+            .projection(Projection::builder().projection_type(ProjectionType::All).build())
+            .build()
+            .unwrap();
+
+        let created = client
+            .create_table()
+            .table_name(table)
+            .attribute_definitions(attribute("pk", ScalarAttributeType::S))
+            .attribute_definitions(attribute("sk", ScalarAttributeType::S))
+            .attribute_definitions(attribute("owner_id", ScalarAttributeType::N))
+            .attribute_definitions(attribute("created", ScalarAttributeType::S))
+            .key_schema(key("pk", KeyType::Hash))
+            .key_schema(key("sk", KeyType::Range))
+            .global_secondary_indexes(by_owner)
+            .billing_mode(BillingMode::PayPerRequest)
+            .send()
+            .await;
+        match created {
+            Ok(_) => {}
+            Err(error) if error.as_service_error().is_some_and(|error| error.is_resource_in_use_exception()) => return,
+            Err(error) => panic!("{}", DisplayErrorContext(error)),
+        }
+        // A new table takes a while to become active, outside of DynamoDB Local.
+        client.wait_until_table_exists().table_name(table).wait(Duration::from_secs(300)).await.unwrap();
+    }
+
+    async fn is_admin(&self, user_id: i64) -> bool {
+        self.users.get_user(user_id).await.is_some_and(|user| user.is_admin)
+    }
+
+    ///
+    /// Hands out `count` ids at once, returning the first of them.
+    ///
+    async fn next_ids(&self, counter: &str, count: i64) -> Result<i64, RepoError> {
+        let updated = self
+            .client()
+            .await
+            .update_item()
+            .table_name(&self.table)
+            .key("pk", AttributeValue::S("COUNTER".to_string()))
+            .key("sk", AttributeValue::S(counter.to_string()))
+            .update_expression("ADD last_id :count")
+            .expression_attribute_values(":count", AttributeValue::N(count.to_string()))
+            .return_values(ReturnValue::UpdatedNew)
+            .send()
+            .await?;
+        let last_id: i64 = updated.attributes.unwrap()["last_id"].as_n().unwrap().parse().unwrap();
+        Ok(last_id - count + 1)
+    }
+
+    ///
+    /// Every item that the query finds, a page at a time. Each page ends
+    /// after 1 MB, or `limit` items, with the key to start the next one from.
+    ///
+    async fn query_all(&self, query: QueryFluentBuilder) -> Result<Vec<DynamoItem>, RepoError> {
+        let mut items = Vec::new();
+        let mut start = None;
+        loop {
+            let page = query.clone().set_exclusive_start_key(start).send().await?;
+            items.extend(page.items.unwrap_or_default());
+            start = page.last_evaluated_key;
+            if start.is_none() {
+                return Ok(items);
+            }
+        }
+    }
+
+    ///
+    /// The todos visible to the user, or every todo if `is_admin`, and the
+    /// version of each.
+    ///
+    async fn load(&self, user_id: i64, is_admin: bool) -> Result<(TodoTables, HashMap<i64, i64>), RepoError> {
+        let client = self.client().await;
+        let items = if is_admin {
+            let mut items = Vec::new();
+            let mut start = None;
+            loop {
+                let page = client
+                    .scan()
+                    .table_name(&self.table)
+                    .filter_expression("sk = :todo")
+                    .expression_attribute_values(":todo", AttributeValue::S("TODO".to_string()))
+                    .set_exclusive_start_key(start)
+                    .send()
+                    .await?;
+                items.extend(page.items.unwrap_or_default());
+                start = page.last_evaluated_key;
+                if start.is_none() {
+                    break items;
+                }
+            }
+        } else {
+            let query = client
+                .query()
+                .table_name(&self.table)
+                .index_name("by_owner")
+                .key_condition_expression("owner_id = :owner_id")
+                .expression_attribute_values(":owner_id", AttributeValue::N(user_id.to_string()));
+            self.query_all(query).await?
+        };
+
+        let mut tables = TodoTables::default();
+        if is_admin {
+            tables.admins.insert(user_id);
+        }
+        let mut versions = HashMap::new();
+        for item in &items {
+            let (todo, assignee_id) = todo_from_item(item);
+            versions.insert(todo.id, item["version"].as_n().unwrap().parse().unwrap());
+            if let Some(assignee_id) = assignee_id {
+                tables.assignees.insert(todo.id, assignee_id);
+            }
+            tables.todos.insert(todo.id, todo);
+        }
+        Ok((tables, versions))
+    }
+
+    async fn in_memory(&self, user_id: i64) -> Result<TodoRepoInMemory, RepoError> {
+        let (tables, _) = self.load(user_id, self.is_admin(user_id).await).await?;
+        Ok(TodoRepoInMemory { tables: Arc::new(Mutex::new(tables)) })
+    }
+
+    ///
+    /// Applies `change` to an in-memory copy of the todos of the user, and
+    /// writes back the todos that it created, changed and deleted, starting
+    /// over if any of them changed in the meantime. `new_ids` ids are set
+    /// aside for the todos that it creates.
+    ///
+    async fn write<T, F: Future<Output = Result<T, RepoError>>>(
+        &self,
+        user_id: i64,
+        new_ids: i64,
+        change: impl Fn(TodoRepoInMemory) -> F,
+    ) -> Result<T, RepoError> {
+        let is_admin = self.is_admin(user_id).await;
+        self.write_as(user_id, is_admin, new_ids, change).await
+    }
+
+    ///
+    /// The same as `write`, on every todo if `is_admin`, whether or not the
+    /// user is one.
+    ///
+    async fn write_as<T, F: Future<Output = Result<T, RepoError>>>(
+        &self,
+        user_id: i64,
+        is_admin: bool,
+        new_ids: i64,
+        change: impl Fn(TodoRepoInMemory) -> F,
+    ) -> Result<T, RepoError> {
+        const ATTEMPTS: usize = 10;
+
+        let client = self.client().await;
+        let first_id = if new_ids > 0 { self.next_ids("todos", new_ids).await? } else { 0 };
+        for attempt in 0..ATTEMPTS {
+            if attempt > 0 {
+                // A random pause, longer after each conflict, so that writes
+                // that keep colliding spread out.
+                tokio::time::sleep(Duration::from_millis(rand::random::<u64>() % (20 << attempt))).await;
+            }
+            let (mut tables, versions) = self.load(user_id, is_admin).await?;
+            tables.todo_ids = SequenceIds::starting_at(first_id);
+            let before = todo_items(&tables);
+            let repo = TodoRepoInMemory { tables: Arc::new(Mutex::new(tables)) };
+            // The in-memory repo never waits, so its futures are ready as soon
+            // as they are polled.
+            let result = change(repo.clone()).now_or_never().unwrap()?;
+            let after = todo_items(&repo.tables.lock().unwrap());
+
+            let mut writes = Vec::new();
+            for (id, item) in &after {
+                match before.get(id) {
+                    None => writes.push(self.put_todo(item.clone(), None)),
+                    Some(read) if read != item => writes.push(self.put_todo(item.clone(), Some(versions[id]))),
+                    Some(_) => {}
+                }
+            }
+            for id in before.keys().filter(|id| !after.contains_key(id)) {
+                let delete = Delete::builder()
+                    .table_name(&self.table)
+                    .set_key(Some(todo_key(*id)))
+                    .condition_expression("#version = :version")
+                    .expression_attribute_names("#version", "version")
+                    .expression_attribute_values(":version", AttributeValue::N(versions[id].to_string()));
+                writes.push(TransactWriteItem::builder().delete(delete.build().unwrap()).build());
+                for comment in self.comment_items(*id).await? {
+                    let key = HashMap::from([("pk".to_string(), comment["pk"].clone()), ("sk".to_string(), comment["sk"].clone())]);
+                    let delete = Delete::builder().table_name(&self.table).set_key(Some(key));
+                    writes.push(TransactWriteItem::builder().delete(delete.build().unwrap()).build());
+                }
+            }
+            if writes.is_empty() {
+                return Ok(result);
+            }
+
+            match client.transact_write_items().set_transact_items(Some(writes)).send().await {
+                Ok(_) => return Ok(result),
+                Err(error) if conflicted(&error) => continue,
+                Err(error) => return Err(error.into()),
+            }
+        }
+        panic!("Gave up on a write to the todos of user {} after {} conflicts", user_id, ATTEMPTS);
+    }
+
+    ///
+    /// Puts a todo on the condition that it is still at `version`, or, for a
+    /// new one, that there is none with its id.
+    ///
+    fn put_todo(&self, mut item: DynamoItem, version: Option<i64>) -> TransactWriteItem {
+        let put = Put::builder().table_name(&self.table).expression_attribute_names("#version", "version");
+        let put = match version {
+            Some(version) => put
+                .condition_expression("#version = :version")
+                .expression_attribute_values(":version", AttributeValue::N(version.to_string())),
+            None => put.condition_expression("attribute_not_exists(#version)"),
+        };
+        item.insert("version".to_string(), AttributeValue::N((version.unwrap_or(0) + 1).to_string()));
+        TransactWriteItem::builder().put(put.set_item(Some(item)).build().unwrap()).build()
+    }
+
+    async fn comment_items(&self, todo_id: i64) -> Result<Vec<DynamoItem>, RepoError> {
+        let query = self
+            .client()
+            .await
+            .query()
+            .table_name(&self.table)
+            .key_condition_expression("pk = :pk AND begins_with(sk, :comment)")
+            .expression_attribute_values(":pk", todo_key(todo_id)["pk"].clone())
+            .expression_attribute_values(":comment", AttributeValue::S("COMMENT#".to_string()));
+        self.query_all(query).await
+    }
+}
+
+fn todo_key(id: i64) -> DynamoItem {
+    HashMap::from([
+        ("pk".to_string(), AttributeValue::S(format!("TODO#{}", id))),
+        ("sk".to_string(), AttributeValue::S("TODO".to_string())),
+    ])
+}
+
+fn comment_key(todo_id: i64, id: i64) -> DynamoItem {
+    HashMap::from([
+        ("pk".to_string(), AttributeValue::S(format!("TODO#{}", todo_id))),
+        ("sk".to_string(), AttributeValue::S(format!("COMMENT#{:020}", id))),
+    ])
+}
+
+///
+/// The sort key of the `by_owner` index. Sort keys that are strings sort by
+/// their bytes, so the numbers are zero-padded to sort as numbers.
+///
+fn created_key(created_at: OffsetDateTime, id: i64) -> String {
+    format!("{:020}#{:020}", created_at.unix_timestamp_nanos(), id)
+}
+
+///
+/// The item of a todo, without its version. Absent values are left out, not
+/// stored as `NULL`, so that a todo without an owner stays out of the
+/// `by_owner` index. Timestamps are nanoseconds since the epoch.
+///
+fn todo_item(todo: &Todo, assignee_id: Option<i64>) -> DynamoItem {
+    let number = |value: i64| AttributeValue::N(value.to_string());
+    let timestamp = |at: OffsetDateTime| AttributeValue::N(at.unix_timestamp_nanos().to_string());
+    let attributes = [
+        ("id", Some(number(todo.id))),
+        ("title", Some(AttributeValue::S(todo.title.clone()))),
+        ("description", Some(AttributeValue::S(todo.description.clone()))),
+        ("status", Some(AttributeValue::S(todo.status.as_str().to_string()))),
+        ("created_at", Some(timestamp(todo.created_at))),
+        ("created", Some(AttributeValue::S(created_key(todo.created_at, todo.id)))),
+        ("due_at", todo.due_at.map(timestamp)),
+        ("priority", Some(number(todo.priority.into()))),
+        ("parent_id", todo.parent_id.map(number)),
+        ("owner_id", todo.owner_id.map(number)),
+        ("list_id", todo.list_id.map(number)),
+        ("assignee_id", assignee_id.map(number)),
+        ("completed_at", todo.completed_at.map(timestamp)),
+        ("metadata", Some(AttributeValue::S(todo.metadata.to_string()))),
+    ];
+    let attributes = attributes.into_iter().filter_map(|(name, value)| Some((name.to_string(), value?)));
+    todo_key(todo.id).into_iter().chain(attributes).collect()
+}
+
+fn todo_from_item(item: &DynamoItem) -> (Todo, Option<i64>) {
+    let number = |name: &str| item.get(name).map(|value| value.as_n().unwrap().parse::<i128>().unwrap());
+    let string = |name: &str| item[name].as_s().unwrap().clone();
+    let timestamp = |name: &str| number(name).map(|nanos| OffsetDateTime::from_unix_timestamp_nanos(nanos).unwrap());
+    let todo = Todo {
+        id: number("id").unwrap() as i64,
+        title: string("title"),
+        description: string("description"),
+        status: string("status").parse().unwrap(),
+        created_at: timestamp("created_at").unwrap(),
+        due_at: timestamp("due_at"),
+        priority: number("priority").unwrap() as i32,
+        parent_id: number("parent_id").map(|id| id as i64),
+        owner_id: number("owner_id").map(|id| id as i64),
+        list_id: number("list_id").map(|id| id as i64),
+        completed_at: timestamp("completed_at"),
+        metadata: serde_json::from_str(&string("metadata")).unwrap(),
+    };
+    (todo, number("assignee_id").map(|id| id as i64))
+}
+
+fn todo_items(tables: &TodoTables) -> BTreeMap<i64, DynamoItem> {
+    let items = tables.todos.values().map(|todo| (todo.id, todo_item(todo, tables.assignees.get(&todo.id).copied())));
+    items.collect()
+}
+
+fn comment_from_item(item: &DynamoItem) -> Comment {
+    let number = |name: &str| item[name].as_n().unwrap().parse::<i128>().unwrap();
+    Comment {
+        id: number("id") as i64,
+        todo_id: number("todo_id") as i64,
+        body: item["body"].as_s().unwrap().clone(),
+        created_at: OffsetDateTime::from_unix_timestamp_nanos(number("created_at")).unwrap(),
+    }
+}
+
+///
+/// Whether a transaction was cancelled because another write got to one of
+/// its items first, either before it, failing a condition, or at the same
+/// time.
+///
+fn conflicted(error: &SdkError<TransactWriteItemsError>) -> bool {
+    match error.as_service_error() {
+        Some(TransactWriteItemsError::TransactionCanceledException(cancelled)) => cancelled
+            .cancellation_reasons()
+            .iter()
+            .any(|reason| matches!(reason.code(), Some("ConditionalCheckFailed" | "TransactionConflict"))),
+        _ => false,
+    }
+}
+
+#[async_trait]
+impl TodoRepo for TodoRepoDynamo {
+    async fn get_todos(&self, user_id: i64, sort: TodoSort) -> Result<Vec<Todo>, RepoError> {
+        self.in_memory(user_id).await?.get_todos(user_id, sort).await
+    }
+    async fn get_todos_page(
+        &self,
+        user_id: i64,
+        after: Option<TodoCursor>,
+        limit: i64,
+    ) -> Result<Vec<Todo>, RepoError> {
+        if limit <= 0 {
+            return Ok(Vec::new());
+        }
+        if self.is_admin(user_id).await {
+            return self.in_memory(user_id).await?.get_todos_page(user_id, after, limit).await;
+        }
+
+        // The cursor is the key to start after. It is the key of a page of
+        // the index: those of the table, and those of the index.
+        let mut start = after.map(|after| {
+            let mut key = todo_key(after.id);
+            key.insert("owner_id".to_string(), AttributeValue::N(user_id.to_string()));
+            key.insert("created".to_string(), AttributeValue::S(created_key(after.created_at, after.id)));
+            key
+        });
+        let client = self.client().await;
+        let mut todos = Vec::new();
+        while (todos.len() as i64) < limit {
+            let page = client
+                .query()
+                .table_name(&self.table)
+                .index_name("by_owner")
+                .key_condition_expression("owner_id = :owner_id")
+                .expression_attribute_values(":owner_id", AttributeValue::N(user_id.to_string()))
+                .limit((limit - todos.len() as i64).min(i32::MAX as i64) as i32)
+                .set_exclusive_start_key(start)
+                .send()
+                .await?;
+            todos.extend(page.items.unwrap_or_default().iter().map(|item| todo_from_item(item).0));
+            start = page.last_evaluated_key;
+            if start.is_none() {
+                break;
+            }
+        }
+        Ok(todos)
+    }
+    async fn get_todo(&self, user_id: i64, id: i64) -> Result<Option<Todo>, RepoError> {
+        let item = self
+            .client()
+            .await
+            .get_item()
+            .table_name(&self.table)
+            .set_key(Some(todo_key(id)))
+            .consistent_read(true)
+            .send()
+            .await?
+            .item;
+        let Some(item) = item else {
+            return Ok(None);
+        };
+        let (todo, _) = todo_from_item(&item);
+        Ok((todo.owner_id == Some(user_id) || self.is_admin(user_id).await).then_some(todo))
+    }
+    async fn get_todos_filtered(&self, user_id: i64, filter: &TodoFilter) -> Result<Vec<Todo>, RepoError> {
+        self.in_memory(user_id).await?.get_todos_filtered(user_id, filter).await
+    }
+    async fn get_todo_tree(&self, user_id: i64, id: i64) -> Result<Vec<Todo>, RepoError> {
+        self.in_memory(user_id).await?.get_todo_tree(user_id, id).await
+    }
+    async fn set_parent(
+        &self,
+        user_id: i64,
+        id: i64,
+        parent_id: Option<i64>,
+    ) -> Result<Result<(), SubtaskError>, RepoError> {
+        self.write(user_id, 0, |repo| async move { repo.set_parent(user_id, id, parent_id).await }).await
+    }
+    async fn get_overdue_todos(&self, user_id: i64, now: OffsetDateTime) -> Result<Vec<Todo>, RepoError> {
+        self.in_memory(user_id).await?.get_overdue_todos(user_id, now).await
+    }
+    async fn get_todos_due_on(&self, user_id: i64, day: Date, time_zone: &str) -> Result<Option<Vec<Todo>>, RepoError> {
+        self.in_memory(user_id).await?.get_todos_due_on(user_id, day, time_zone).await
+    }
+    async fn get_stats(&self, user_id: i64) -> Result<TodoStats, RepoError> {
+        self.in_memory(user_id).await?.get_stats(user_id).await
+    }
+    async fn create_todo(
+        &self,
+        user_id: i64,
+        title: &str,
+        description: &str,
+        due_at: Option<OffsetDateTime>,
+        priority: i32,
+    ) -> Result<i64, RepoError> {
+        let todo = CreateTodo { title: title.to_string(), description: description.to_string(), due_at, priority };
+        Ok(self.create_many(user_id, &[todo]).await?[0])
+    }
+    async fn create_many(&self, user_id: i64, todos: &[CreateTodo]) -> Result<Vec<i64>, RepoError> {
+        if todos.is_empty() {
+            return Ok(Vec::new());
+        }
+        // New todos cannot conflict with anything, so there is nothing to
+        // load, and nothing to start over.
+        let first_id = self.next_ids("todos", todos.len() as i64).await?;
+        let mut tables = TodoTables { todo_ids: SequenceIds::starting_at(first_id), ..TodoTables::default() };
+        let ids: Vec<i64> = todos
+            .iter()
+            .map(|todo| tables.insert(user_id, &todo.title, &todo.description, todo.due_at, todo.priority))
+            .collect();
+
+        let items = todo_items(&tables);
+        let client = self.client().await;
+        for chunk in ids.chunks(100) {
+            let puts = chunk.iter().map(|id| self.put_todo(items[id].clone(), None)).collect();
+            client.transact_write_items().set_transact_items(Some(puts)).send().await?;
+        }
+        Ok(ids)
+    }
+    async fn update_todo(
+        &self,
+        user_id: i64,
+        id: i64,
+        title: Option<&str>,
+        description: Option<&str>,
+        status: Option<TodoStatus>,
+        due_at: Option<OffsetDateTime>,
+        priority: Option<i32>,
+    ) -> Result<Option<i64>, RepoError> {
+        self.write(user_id, 0, |repo| async move {
+            repo.update_todo(user_id, id, title, description, status, due_at, priority).await
+        })
+        .await
+    }
+    async fn replace_todo(&self, user_id: i64, id: i64, todo: &PatchableTodo) -> Result<Option<i64>, RepoError> {
+        self.write(user_id, 0, |repo| async move { repo.replace_todo(user_id, id, todo).await }).await
+    }
+    async fn delete_todo(&self, user_id: i64, id: i64) -> Result<Option<i64>, RepoError> {
+        self.write(user_id, 0, |repo| async move { repo.delete_todo(user_id, id).await }).await
+    }
+    async fn bulk(
+        &self,
+        user_id: i64,
+        operations: &[BulkOperation],
+        atomic: bool,
+    ) -> Result<Vec<Result<i64, BulkError>>, RepoError> {
+        let creates = operations.iter().filter(|operation| matches!(operation, BulkOperation::Create { .. })).count();
+        self.write(user_id, creates as i64, |repo| async move { repo.bulk(user_id, operations, atomic).await }).await
+    }
+    async fn claim_next_todo(&self, user_id: i64) -> Result<Option<Todo>, RepoError> {
+        // Concurrent claims can pick the same todo, but only the first to
+        // write it back finds it at the version it read. The others start
+        // over, and pick another.
+        self.write(user_id, 0, |repo| async move { repo.claim_next_todo(user_id).await }).await
+    }
+    async fn get_comments(&self, user_id: i64, todo_id: i64) -> Result<Vec<Comment>, RepoError> {
+        self.get_comments_of(user_id, &[todo_id]).await
+    }
+    async fn get_comments_of(&self, user_id: i64, todo_ids: &[i64]) -> Result<Vec<Comment>, RepoError> {
+        let mut comments = Vec::new();
+        for &todo_id in todo_ids {
+            if self.get_todo(user_id, todo_id).await?.is_some() {
+                comments.extend(self.comment_items(todo_id).await?.iter().map(comment_from_item));
+            }
+        }
+        comments.sort_by_key(|comment| comment.id);
+        Ok(comments)
+    }
+    async fn create_comment(&self, user_id: i64, todo_id: i64, body: &str) -> Result<Option<i64>, RepoError> {
+        if self.get_todo(user_id, todo_id).await?.is_none() {
+            return Ok(None);
+        }
+        let id = self.next_ids("comments", 1).await?;
+
+        // The todo may be deleted after it was read, so the comment is only
+        // put on the condition that the todo still exists.
+        let mut item = comment_key(todo_id, id);
+        item.insert("id".to_string(), AttributeValue::N(id.to_string()));
+        item.insert("todo_id".to_string(), AttributeValue::N(todo_id.to_string()));
+        item.insert("body".to_string(), AttributeValue::S(body.to_string()));
+        item.insert("created_at".to_string(), AttributeValue::N(OffsetDateTime::now_utc().unix_timestamp_nanos().to_string()));
+        let todo_exists = ConditionCheck::builder()
+            .table_name(&self.table)
+            .set_key(Some(todo_key(todo_id)))
+            .condition_expression("attribute_exists(pk)")
+            .build()
+            .unwrap();
+        let put = Put::builder().table_name(&self.table).set_item(Some(item)).build().unwrap();
+        let writes = vec![
+            TransactWriteItem::builder().condition_check(todo_exists).build(),
+            TransactWriteItem::builder().put(put).build(),
+        ];
+        match self.client().await.transact_write_items().set_transact_items(Some(writes)).send().await {
+            Ok(_) => Ok(Some(id)),
+            Err(error) if conflicted(&error) => Ok(None),
+            Err(error) => Err(error.into()),
+        }
+    }
+    async fn delete_comment(&self, user_id: i64, todo_id: i64, comment_id: i64) -> Result<bool, RepoError> {
+        if self.get_todo(user_id, todo_id).await?.is_none() {
+            return Ok(false);
+        }
+        let deleted = self
+            .client()
+            .await
+            .delete_item()
+            .table_name(&self.table)
+            .set_key(Some(comment_key(todo_id, comment_id)))
+            .condition_expression("attribute_exists(pk)")
+            .send()
+            .await;
+        match deleted {
+            Ok(_) => Ok(true),
+            Err(error) if error.as_service_error().is_some_and(|error| error.is_conditional_check_failed_exception()) => Ok(false),
+            Err(error) => Err(error.into()),
+        }
+    }
+    async fn erase_user(&self, user_id: i64, dry_run: bool) -> Result<TodoErasure, RepoError> {
+        // Every todo, as an admin sees them, for the subtasks of other users
+        // and the todos assigned to the user. The comments are not loaded
+        // along with the todos, so those of the erased todos are counted
+        // here, and deleted by `write_as`.
+        let (tables, _) = self.load(user_id, true).await?;
+        let erased = TodoRepoInMemory { tables: Arc::new(Mutex::new(tables.clone())) };
+        let mut erasure = erased.erase_user(user_id, false).await?;
+        let erased_ids: Vec<i64> = {
+            let left = erased.tables.lock().unwrap();
+            tables.todos.keys().filter(|id| !left.todos.contains_key(id)).copied().collect()
+        };
+        for id in erased_ids {
+            erasure.comments += self.comment_items(id).await?.len() as i64;
+        }
+        if !dry_run {
+            self.write_as(user_id, true, 0, |repo| async move { repo.erase_user(user_id, false).await }).await?;
+        }
+        Ok(erasure)
+    }
+}
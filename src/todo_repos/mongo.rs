@@ -0,0 +1,942 @@
+#![allow(dead_code)]
+
+//!
+//! MONGODB
+//! -------
+//!
+//! The todos in MongoDB, with the `mongodb` feature and `TODO_REPO=mongodb`
+//! (see `TodoRepoMongo`).
+//!
+
+use std::sync::Arc;
+
+use crate::config::AppConfig;
+use crate::persistence::{
+    rolled_back, BulkError, BulkOperation, Comment, CreateTodo, DailyCount, PatchableTodo, RepoError, StatusCount,
+    SubtaskError, Todo, TodoCursor, TodoErasure, TodoFilter, TodoRepo, TodoSort, TodoStats, TodoStatus,
+};
+use crate::users::{UserRepo, UserRepoPostgres};
+use axum::async_trait;
+use futures::{FutureExt, TryStreamExt};
+use mongodb::{
+    bson::{doc, Bson, DateTime as BsonDateTime, Document},
+    error::{ErrorKind as MongoErrorKind, Result as MongoResult},
+    options::ReturnDocument,
+    ClientSession, Collection, Database as MongoDatabase, IndexModel,
+};
+use sqlx::types::time::{Date, OffsetDateTime};
+
+///
+/// A `TodoRepo` on MongoDB (`TODO_REPO=mongodb`), with a document per todo
+/// in `todos`, a document per comment in `comments`, and the last id handed
+/// out of each in `counters`.
+///
+/// Ids stay `i64`s, as the API and every other repo has them, counted with
+/// atomic `$inc`s, rather than the `ObjectId`s that MongoDB generates: those
+/// are 12 bytes, which no `i64` holds, so they would have to be mapped to ids
+/// and back through another collection. ULIDs fit no better, and have an
+/// API of their own already (see the `uuid_todos` module).
+///
+/// MongoDB has an answer of its own to most of what the app asks:
+///
+/// - `$graphLookup` follows `parent_id` for trees, cycles, and the cascade of
+///   deletes that foreign keys do in Postgres.
+/// - Containment of metadata is a condition per member, on its dotted path.
+///   That is close to JSONB's `@>`, but not the same: a value also matches an
+///   array that contains it, and the elements of arrays in the filter must
+///   equal those of the todo, rather than be contained in them.
+/// - Time zones are those that MongoDB knows, from the same database that
+///   Postgres uses.
+/// - `find_one_and_update` claims a todo atomically.
+///
+/// Writes that touch more than one document run in multi-document
+/// transactions, which need a replica set (a single node started with
+/// `--replSet` will do), and start over when they conflict with another.
+/// What the triggers do in Postgres, `completed_at` and the completion of
+/// parents, is done in those transactions. Dates are BSON dates, which count
+/// milliseconds, so timestamps lose their microseconds.
+///
+#[derive(Clone)]
+pub struct TodoRepoMongo {
+    database: Arc<tokio::sync::OnceCell<MongoDatabase>>,
+    url: String,
+    users: UserRepoPostgres,
+}
+
+impl TodoRepoMongo {
+    ///
+    /// The repo of the database at `config.mongodb_url` (`todos`, unless the
+    /// URL names another), which it connects to when it is first used.
+    ///
+    pub fn connect_lazy(config: &AppConfig, users: UserRepoPostgres) -> Self {
+        let url = config.mongodb_url.clone().expect("MONGODB_URL must be set when TODO_REPO is mongodb");
+        TodoRepoMongo { database: Arc::default(), url, users }
+    }
+
+    fn with_database(database: MongoDatabase, users: UserRepoPostgres) -> Self {
+        let database = Arc::new(tokio::sync::OnceCell::new_with(Some(database)));
+        TodoRepoMongo { database, url: String::new(), users }
+    }
+
+    pub async fn connect(url: &str) -> MongoDatabase {
+        let client = mongodb::Client::with_uri_str(url).await.unwrap();
+        client.default_database().unwrap_or_else(|| client.database("todos"))
+    }
+
+    async fn database(&self) -> &MongoDatabase {
+        self.database.get_or_init(|| Self::connect(&self.url)).await
+    }
+
+    async fn todos(&self) -> Collection<Document> {
+        self.database().await.collection("todos")
+    }
+
+    async fn comments(&self) -> Collection<Document> {
+        self.database().await.collection("comments")
+    }
+
+    async fn session(&self) -> MongoResult<ClientSession> {
+        self.database().await.client().start_session().await
+    }
+
+    ///
+    /// Creates the indexes that the queries need, unless they exist, as the
+    /// migrations do in Postgres. The collections are created by their first
+    /// inserts.
+    ///
+    pub async fn create_indexes(database: &MongoDatabase) {
+        let index = |keys: Document| IndexModel::builder().keys(keys).build();
+        let todos = database.collection::<Document>("todos");
+        // The todos of a user, in the `(created_at, id)` order of pages.
+        todos.create_index(index(doc! { "owner_id": 1, "created_at": 1, "_id": 1 })).await.unwrap();
+        // The subtasks of a todo, for `$graphLookup`.
+        todos.create_index(index(doc! { "parent_id": 1 })).await.unwrap();
+        let comments = database.collection::<Document>("comments");
+        comments.create_index(index(doc! { "todo_id": 1, "_id": 1 })).await.unwrap();
+    }
+
+    async fn is_admin(&self, user_id: i64) -> bool {
+        self.users.get_user(user_id).await.is_some_and(|user| user.is_admin)
+    }
+
+    ///
+    /// `filter`, restricted to the todos visible to the user.
+    ///
+    async fn visible(&self, user_id: i64, filter: Document) -> Document {
+        visible_filter(self.is_admin(user_id).await, user_id, filter)
+    }
+
+    ///
+    /// Hands out `count` ids at once, returning the first of them. Like a
+    /// sequence in Postgres, the counter is outside of any transaction, so
+    /// that concurrent transactions do not conflict over it.
+    ///
+    async fn next_ids(&self, counter: &str, count: i64) -> MongoResult<i64> {
+        let counted = self
+            .database()
+            .await
+            .collection::<Document>("counters")
+            .find_one_and_update(doc! { "_id": counter }, doc! { "$inc": { "last_id": count } })
+            .upsert(true)
+            .return_document(ReturnDocument::After)
+            .await?
+            .unwrap();
+        Ok(counted.get_i64("last_id").unwrap() - count + 1)
+    }
+
+    async fn find_todos(&self, filter: Document, sort: Document, limit: Option<i64>) -> MongoResult<Vec<Todo>> {
+        let todos = self.todos().await;
+        let find = todos.find(filter).sort(sort);
+        let cursor = match limit {
+            Some(limit) => find.limit(limit).await,
+            None => find.await,
+        };
+        let documents: Vec<Document> = cursor?.try_collect().await?;
+        Ok(documents.iter().map(todo_from_document).collect())
+    }
+
+    async fn aggregate(&self, collection: Collection<Document>, pipeline: Vec<Document>) -> MongoResult<Vec<Document>> {
+        collection.aggregate(pipeline).await?.try_collect().await
+    }
+
+    async fn find_one_in(&self, session: &mut ClientSession, filter: Document) -> MongoResult<Option<Todo>> {
+        let found = self.todos().await.find_one(filter).session(&mut *session).await?;
+        Ok(found.as_ref().map(todo_from_document))
+    }
+
+    ///
+    /// The ids of the todo and all of its descendants, visible or not, as the
+    /// foreign keys of Postgres see them.
+    ///
+    async fn subtree_ids(&self, session: &mut ClientSession, id: i64) -> MongoResult<Vec<i64>> {
+        let pipeline = vec![doc! { "$match": { "_id": id } }, graph_lookup(doc! {})];
+        let mut cursor = self.todos().await.aggregate(pipeline).session(&mut *session).await?;
+        let documents: Vec<Document> = cursor.stream(session).try_collect().await?;
+        let Some(root) = documents.first() else {
+            return Ok(Vec::new());
+        };
+        let descendants = root.get_array("descendants").unwrap().iter();
+        let descendant_ids = descendants.map(|descendant| descendant.as_document().unwrap().get_i64("_id").unwrap());
+        Ok(std::iter::once(id).chain(descendant_ids).collect())
+    }
+
+    ///
+    /// Changes a visible todo, keeping `completed_at` in step with its
+    /// status, and then completes its parents, like the triggers.
+    ///
+    async fn update_in(
+        &self,
+        session: &mut ClientSession,
+        visible: Document,
+        id: i64,
+        change: &(dyn Fn(&mut Todo) + Send + Sync),
+    ) -> MongoResult<Option<i64>> {
+        let Some(mut todo) = self.find_one_in(session, doc! { "$and": [{ "_id": id }, visible] }).await? else {
+            return Ok(None);
+        };
+        let was_done = todo.status == TodoStatus::Done;
+        change(&mut todo);
+        if todo.status != TodoStatus::Done {
+            todo.completed_at = None;
+        } else if !was_done {
+            todo.completed_at = Some(OffsetDateTime::now_utc());
+        }
+
+        // `$set`, rather than a replacement, leaves `assignee_id` be.
+        let mut fields = todo_document(&todo);
+        fields.remove("_id");
+        self.todos().await.update_one(doc! { "_id": id }, doc! { "$set": fields }).session(&mut *session).await?;
+        self.complete_parents_in(session, todo).await?;
+        Ok(Some(id))
+    }
+
+    ///
+    /// The same as the `complete_parents` of `TodoRepoPostgres`, done a
+    /// parent at a time.
+    ///
+    async fn complete_parents_in(&self, session: &mut ClientSession, mut child: Todo) -> MongoResult<()> {
+        let still_to_do = [TodoStatus::Open.as_str(), TodoStatus::InProgress.as_str()];
+        let todos = self.todos().await;
+        while let Some(parent_id) = child.parent_id {
+            let Some(mut parent) = self.find_one_in(session, doc! { "_id": parent_id }).await? else {
+                return Ok(());
+            };
+            let siblings_to_do = doc! { "parent_id": parent_id, "status": { "$in": still_to_do.as_slice() } };
+            if !matches!(parent.status, TodoStatus::Open | TodoStatus::InProgress)
+                || todos.count_documents(siblings_to_do).session(&mut *session).await? > 0
+            {
+                return Ok(());
+            }
+            parent.status = TodoStatus::Done;
+            parent.completed_at = Some(OffsetDateTime::now_utc());
+            let update = doc! { "$set": { "status": parent.status.as_str(), "completed_at": parent.completed_at.map(bson_time) } };
+            todos.update_one(doc! { "_id": parent_id }, update).session(&mut *session).await?;
+            child = parent;
+        }
+        Ok(())
+    }
+
+    ///
+    /// Deletes a visible todo, and, as the foreign keys cascade in Postgres,
+    /// its subtasks and the comments of them all.
+    ///
+    async fn delete_in(&self, session: &mut ClientSession, visible: Document, id: i64) -> MongoResult<Option<i64>> {
+        if self.find_one_in(session, doc! { "$and": [{ "_id": id }, visible] }).await?.is_none() {
+            return Ok(None);
+        }
+        let ids = self.subtree_ids(session, id).await?;
+        self.todos().await.delete_many(doc! { "_id": { "$in": ids.clone() } }).session(&mut *session).await?;
+        self.comments().await.delete_many(doc! { "todo_id": { "$in": ids } }).session(&mut *session).await?;
+        Ok(Some(id))
+    }
+
+    ///
+    /// The `erase_user` of the repo, in the transaction of `session`.
+    ///
+    async fn erase_in(&self, session: &mut ClientSession, user_id: i64, dry_run: bool) -> MongoResult<TodoErasure> {
+        let pipeline = vec![doc! { "$match": { "owner_id": user_id } }, graph_lookup(doc! {})];
+        let mut cursor = self.todos().await.aggregate(pipeline).session(&mut *session).await?;
+        let roots: Vec<Document> = cursor.stream(session).try_collect().await?;
+        let mut ids = std::collections::BTreeSet::new();
+        for root in &roots {
+            ids.insert(root.get_i64("_id").unwrap());
+            let descendants = root.get_array("descendants").unwrap().iter();
+            ids.extend(descendants.map(|descendant| descendant.as_document().unwrap().get_i64("_id").unwrap()));
+        }
+        let ids: Vec<i64> = ids.into_iter().collect();
+
+        let (todos, comments) = (self.todos().await, self.comments().await);
+        let erased_comments = doc! { "todo_id": { "$in": ids.clone() } };
+        let assigned = doc! { "assignee_id": user_id, "_id": { "$nin": ids.clone() } };
+        let erasure = TodoErasure {
+            todos: ids.len() as i64,
+            comments: comments.count_documents(erased_comments.clone()).session(&mut *session).await? as i64,
+            unassigned_todos: todos.count_documents(assigned.clone()).session(&mut *session).await? as i64,
+        };
+        if !dry_run {
+            todos.delete_many(doc! { "_id": { "$in": ids } }).session(&mut *session).await?;
+            comments.delete_many(erased_comments).session(&mut *session).await?;
+            todos.update_many(assigned, doc! { "$set": { "assignee_id": null } }).session(&mut *session).await?;
+        }
+        Ok(erasure)
+    }
+
+    ///
+    /// Applies a bulk operation in the transaction of `session`. Creates take
+    /// their ids from `new_ids`, which were handed out beforehand.
+    ///
+    async fn apply_in(
+        &self,
+        session: &mut ClientSession,
+        user_id: i64,
+        visible: &Document,
+        operation: &BulkOperation,
+        new_ids: &mut std::ops::RangeFrom<i64>,
+    ) -> MongoResult<Result<i64, BulkError>> {
+        match operation {
+            BulkOperation::Create { title, description, due_at, priority } => {
+                let todo = new_todo(new_ids.next().unwrap(), user_id, title, description, *due_at, *priority);
+                self.todos().await.insert_one(todo_document(&todo)).session(session).await?;
+                Ok(Ok(todo.id))
+            }
+            BulkOperation::Update { id, title, description, status, due_at, priority } => {
+                let change = |todo: &mut Todo| {
+                    todo.title = title.clone().unwrap_or(todo.title.clone());
+                    todo.description = description.clone().unwrap_or(todo.description.clone());
+                    todo.status = status.unwrap_or(todo.status);
+                    todo.due_at = due_at.map(truncate_to_millis).or(todo.due_at);
+                    todo.priority = priority.unwrap_or(todo.priority);
+                };
+                let updated = self.update_in(session, visible.clone(), *id, &change).await?;
+                Ok(updated.ok_or(BulkError::NotFound(*id)))
+            }
+            BulkOperation::Delete { id } => {
+                let deleted = self.delete_in(session, visible.clone(), *id).await?;
+                Ok(deleted.ok_or(BulkError::NotFound(*id)))
+            }
+        }
+    }
+
+    ///
+    /// Changes a todo in a transaction of its own.
+    ///
+    async fn update(
+        &self,
+        user_id: i64,
+        id: i64,
+        change: &(dyn Fn(&mut Todo) + Send + Sync),
+    ) -> MongoResult<Option<i64>> {
+        let visible = self.visible(user_id, doc! {}).await;
+        let mut session = self.session().await?;
+        session
+            .start_transaction()
+            .and_run((self, &visible, change), |session, &mut (repo, visible, change)| {
+                async move { repo.update_in(session, visible.clone(), id, change).await }.boxed()
+            })
+            .await
+    }
+}
+
+///
+/// `filter`, and, for users who are not admins, the condition that they own
+/// the todo.
+///
+fn visible_filter(is_admin: bool, user_id: i64, mut filter: Document) -> Document {
+    if !is_admin {
+        filter.insert("owner_id", user_id);
+    }
+    filter
+}
+
+///
+/// Adds every descendant of a todo that matches `restrict` to it, as
+/// `descendants`, each with its `depth` below it, from 0. The search stops at
+/// todos that do not match, as the recursive queries of Postgres do.
+///
+fn graph_lookup(restrict: Document) -> Document {
+    doc! {
+        "$graphLookup": {
+            "from": "todos",
+            "startWith": "$_id",
+            "connectFromField": "_id",
+            "connectToField": "parent_id",
+            "as": "descendants",
+            "depthField": "depth",
+            "restrictSearchWithMatch": restrict,
+        }
+    }
+}
+
+///
+/// The conditions under which the metadata contains `contained`, added to
+/// `filter`, with `path` the path of `contained` in the document.
+///
+fn contains_filter(path: &str, contained: &serde_json::Value, filter: &mut Document) {
+    use serde_json::Value;
+
+    match contained {
+        Value::Object(members) if !members.is_empty() => {
+            for (key, member) in members {
+                contains_filter(&format!("{}.{}", path, key), member, filter);
+            }
+        }
+        Value::Object(_) => {
+            filter.insert(path, doc! { "$type": "object" });
+        }
+        // `$all` of nothing matches nothing, rather than any array.
+        Value::Array(elements) if elements.is_empty() => {
+            filter.insert(path, doc! { "$type": "array" });
+        }
+        Value::Array(elements) => {
+            filter.insert(path, doc! { "$all": mongodb::bson::to_bson(elements).unwrap() });
+        }
+        value => {
+            filter.insert(path, mongodb::bson::to_bson(value).unwrap());
+        }
+    }
+}
+
+fn truncate_to_millis(at: OffsetDateTime) -> OffsetDateTime {
+    from_bson_time(bson_time(at))
+}
+
+fn bson_time(at: OffsetDateTime) -> BsonDateTime {
+    BsonDateTime::from_millis(at.unix_timestamp_nanos().div_euclid(1_000_000) as i64)
+}
+
+fn from_bson_time(at: BsonDateTime) -> OffsetDateTime {
+    OffsetDateTime::from_unix_timestamp_nanos(at.timestamp_millis() as i128 * 1_000_000).unwrap()
+}
+
+fn new_todo(
+    id: i64,
+    user_id: i64,
+    title: &str,
+    description: &str,
+    due_at: Option<OffsetDateTime>,
+    priority: i32,
+) -> Todo {
+    Todo {
+        id,
+        title: title.to_string(),
+        description: description.to_string(),
+        status: TodoStatus::Open,
+        created_at: truncate_to_millis(OffsetDateTime::now_utc()),
+        due_at: due_at.map(truncate_to_millis),
+        priority,
+        parent_id: None,
+        owner_id: Some(user_id),
+        list_id: None,
+        completed_at: None,
+        metadata: serde_json::json!({}),
+    }
+}
+
+///
+/// The document of a todo. Absent values are stored as `null`, which
+/// conditions such as `{"parent_id": null}` match, and `assignee_id` is only
+/// there once the todo has been claimed.
+///
+fn todo_document(todo: &Todo) -> Document {
+    doc! {
+        "_id": todo.id,
+        "title": &todo.title,
+        "description": &todo.description,
+        "status": todo.status.as_str(),
+        "created_at": bson_time(todo.created_at),
+        "due_at": todo.due_at.map(bson_time),
+        "priority": todo.priority,
+        "parent_id": todo.parent_id,
+        "owner_id": todo.owner_id,
+        "list_id": todo.list_id,
+        "completed_at": todo.completed_at.map(bson_time),
+        "metadata": mongodb::bson::to_bson(&todo.metadata).unwrap(),
+    }
+}
+
+fn todo_from_document(document: &Document) -> Todo {
+    let time = |name: &str| document.get_datetime(name).ok().copied().map(from_bson_time);
+    let id = |name: &str| document.get_i64(name).ok();
+    let string = |name: &str| document.get_str(name).unwrap().to_string();
+    Todo {
+        id: id("_id").unwrap(),
+        title: string("title"),
+        description: string("description"),
+        status: string("status").parse().unwrap(),
+        created_at: time("created_at").unwrap(),
+        due_at: time("due_at"),
+        priority: document.get_i32("priority").unwrap(),
+        parent_id: id("parent_id"),
+        owner_id: id("owner_id"),
+        list_id: id("list_id"),
+        completed_at: time("completed_at"),
+        metadata: document.get("metadata").cloned().map_or(serde_json::json!({}), Bson::into_relaxed_extjson),
+    }
+}
+
+fn comment_from_document(document: &Document) -> Comment {
+    Comment {
+        id: document.get_i64("_id").unwrap(),
+        todo_id: document.get_i64("todo_id").unwrap(),
+        body: document.get_str("body").unwrap().to_string(),
+        created_at: from_bson_time(*document.get_datetime("created_at").unwrap()),
+    }
+}
+
+///
+/// The count of the group `key` of a `$group` that counts with `$sum`, which
+/// is an `Int32` until it no longer fits. Groups without any are left out.
+///
+fn count_of(groups: &[Document], key: &str) -> i64 {
+    let group = groups.iter().find(|group| group.get_str("_id").is_ok_and(|id| id == key));
+    match group.and_then(|group| group.get("count")) {
+        Some(Bson::Int32(count)) => (*count).into(),
+        Some(Bson::Int64(count)) => *count,
+        _ => 0,
+    }
+}
+
+///
+/// Whether MongoDB failed because it does not know a time zone.
+///
+fn unknown_time_zone(error: &mongodb::error::Error) -> bool {
+    matches!(&*error.kind, MongoErrorKind::Command(error) if error.code == 40485)
+}
+
+#[async_trait]
+impl TodoRepo for TodoRepoMongo {
+    async fn get_todos(&self, user_id: i64, sort: TodoSort) -> Result<Vec<Todo>, RepoError> {
+        let visible = self.visible(user_id, doc! {}).await;
+        match sort {
+            TodoSort::Id => Ok(self.find_todos(visible, doc! { "_id": 1 }, None).await?),
+            TodoSort::Priority => Ok(self.find_todos(visible, doc! { "priority": -1, "_id": 1 }, None).await?),
+            TodoSort::DueAt => {
+                // Nulls sort first, so todos without a due date are moved
+                // last by a field of their own.
+                let pipeline = vec![
+                    doc! { "$match": visible },
+                    doc! { "$set": { "no_due_at": { "$eq": [{ "$ifNull": ["$due_at", null] }, null] } } },
+                    doc! { "$sort": { "no_due_at": 1, "due_at": 1, "_id": 1 } },
+                ];
+                let documents = self.aggregate(self.todos().await, pipeline).await?;
+                Ok(documents.iter().map(todo_from_document).collect())
+            }
+        }
+    }
+    async fn get_todos_page(
+        &self,
+        user_id: i64,
+        after: Option<TodoCursor>,
+        limit: i64,
+    ) -> Result<Vec<Todo>, RepoError> {
+        if limit <= 0 {
+            return Ok(Vec::new());
+        }
+        let mut filter = doc! {};
+        if let Some(TodoCursor { created_at, id }) = after {
+            let created_at = bson_time(created_at);
+            filter.insert(
+                "$or",
+                vec![doc! { "created_at": { "$gt": created_at } }, doc! { "created_at": created_at, "_id": { "$gt": id } }],
+            );
+        }
+        let filter = self.visible(user_id, filter).await;
+        Ok(self.find_todos(filter, doc! { "created_at": 1, "_id": 1 }, Some(limit)).await?)
+    }
+    async fn get_todo(&self, user_id: i64, id: i64) -> Result<Option<Todo>, RepoError> {
+        let filter = self.visible(user_id, doc! { "_id": id }).await;
+        Ok(self.todos().await.find_one(filter).await?.as_ref().map(todo_from_document))
+    }
+    async fn get_todos_filtered(&self, user_id: i64, filter: &TodoFilter) -> Result<Vec<Todo>, RepoError> {
+        let mut conditions = doc! {};
+        contains_filter("metadata", &filter.metadata, &mut conditions);
+        if !filter.statuses.is_empty() {
+            let statuses: Vec<&str> = filter.statuses.iter().map(TodoStatus::as_str).collect();
+            conditions.insert("status", doc! { "$in": statuses });
+        }
+        let mut due_at = doc! {};
+        if let Some(after) = filter.due_after {
+            due_at.insert("$gte", bson_time(after));
+        }
+        if let Some(before) = filter.due_before {
+            due_at.insert("$lt", bson_time(before));
+        }
+        if !due_at.is_empty() {
+            conditions.insert("due_at", due_at);
+        }
+        let conditions = self.visible(user_id, conditions).await;
+        Ok(self.find_todos(conditions, doc! { "_id": 1 }, None).await?)
+    }
+    async fn get_todo_tree(&self, user_id: i64, id: i64) -> Result<Vec<Todo>, RepoError> {
+        let visible = self.visible(user_id, doc! {}).await;
+        let pipeline = vec![doc! { "$match": { "$and": [{ "_id": id }, visible.clone()] } }, graph_lookup(visible)];
+        let documents = self.aggregate(self.todos().await, pipeline).await?;
+        let Some(root) = documents.first() else {
+            return Ok(Vec::new());
+        };
+        let mut descendants: Vec<(i64, Todo)> = root
+            .get_array("descendants")
+            .unwrap()
+            .iter()
+            .map(|descendant| {
+                let descendant = descendant.as_document().unwrap();
+                (descendant.get_i64("depth").unwrap(), todo_from_document(descendant))
+            })
+            .collect();
+        descendants.sort_by_key(|(depth, todo)| (*depth, todo.id));
+        Ok(std::iter::once(todo_from_document(root)).chain(descendants.into_iter().map(|(_, todo)| todo)).collect())
+    }
+    async fn set_parent(
+        &self,
+        user_id: i64,
+        id: i64,
+        parent_id: Option<i64>,
+    ) -> Result<Result<(), SubtaskError>, RepoError> {
+        let visible = self.visible(user_id, doc! {}).await;
+        let mut session = self.session().await?;
+        let set = session
+            .start_transaction()
+            .and_run((self, &visible), |session, &mut (repo, visible)| {
+                async move {
+                    let visible_with_id = |id: i64| doc! { "$and": [{ "_id": id }, visible.clone()] };
+                    if repo.find_one_in(session, visible_with_id(id)).await?.is_none() {
+                        return Ok(Err(SubtaskError::NotFound(id)));
+                    }
+                    if let Some(parent_id) = parent_id {
+                        if repo.find_one_in(session, visible_with_id(parent_id)).await?.is_none() {
+                            return Ok(Err(SubtaskError::NotFound(parent_id)));
+                        }
+                        if repo.subtree_ids(session, id).await?.contains(&parent_id) {
+                            return Ok(Err(SubtaskError::Cycle));
+                        }
+                    }
+                    let update = doc! { "$set": { "parent_id": parent_id } };
+                    repo.todos().await.update_one(doc! { "_id": id }, update).session(&mut *session).await?;
+                    Ok(Ok(()))
+                }
+                .boxed()
+            })
+            .await;
+        Ok(set?)
+    }
+    async fn get_overdue_todos(&self, user_id: i64, now: OffsetDateTime) -> Result<Vec<Todo>, RepoError> {
+        let still_to_do = [TodoStatus::Open.as_str(), TodoStatus::InProgress.as_str()];
+        let filter = doc! { "status": { "$in": still_to_do.as_slice() }, "due_at": { "$lt": bson_time(now) } };
+        let filter = self.visible(user_id, filter).await;
+        Ok(self.find_todos(filter, doc! { "due_at": 1, "_id": 1 }, None).await?)
+    }
+    async fn get_todos_due_on(&self, user_id: i64, day: Date, time_zone: &str) -> Result<Option<Vec<Todo>>, RepoError> {
+        // The midnights that the day starts and ends with, worked out on their
+        // own, so that an unknown time zone fails whether or not any todo is
+        // due that day.
+        let midnight = |day: Date| {
+            let (year, month, day) = (day.year(), u8::from(day.month()) as i32, day.day() as i32);
+            doc! { "$dateFromParts": { "year": year, "month": month, "day": day, "timezone": time_zone } }
+        };
+        let pipeline = vec![
+            doc! { "$documents": [{}] },
+            doc! { "$project": { "start": midnight(day), "end": midnight(day.next_day().unwrap()) } },
+        ];
+        let database = self.database().await;
+        let bounds: MongoResult<Vec<Document>> = async { database.aggregate(pipeline).await?.try_collect().await }.await;
+        let bounds = match bounds {
+            Ok(bounds) => bounds,
+            Err(error) if unknown_time_zone(&error) => return Ok(None),
+            Err(error) => return Err(error.into()),
+        };
+        let (start, end) = (*bounds[0].get_datetime("start").unwrap(), *bounds[0].get_datetime("end").unwrap());
+
+        let filter = self.visible(user_id, doc! { "due_at": { "$gte": start, "$lt": end } }).await;
+        Ok(Some(self.find_todos(filter, doc! { "due_at": 1, "_id": 1 }, None).await?))
+    }
+    async fn get_stats(&self, user_id: i64) -> Result<TodoStats, RepoError> {
+        let today = OffsetDateTime::now_utc().date();
+        let first_day = today - time::Duration::days(29);
+        let pipeline = vec![
+            doc! { "$match": self.visible(user_id, doc! {}).await },
+            doc! {
+                "$facet": {
+                    "by_status": [{ "$group": { "_id": "$status", "count": { "$sum": 1 } } }],
+                    "created_per_day": [
+                        { "$match": { "created_at": { "$gte": bson_time(first_day.midnight().assume_utc()) } } },
+                        { "$group": { "_id": { "$dateToString": { "format": "%Y-%m-%d", "date": "$created_at" } }, "count": { "$sum": 1 } } },
+                    ],
+                    "completion": [
+                        { "$match": { "completed_at": { "$ne": null } } },
+                        { "$group": { "_id": null, "milliseconds": { "$avg": { "$subtract": ["$completed_at", "$created_at"] } } } },
+                    ],
+                }
+            },
+        ];
+        let facets = self.aggregate(self.todos().await, pipeline).await?.remove(0);
+        let facet = |name: &str| -> Vec<Document> {
+            facets.get_array(name).unwrap().iter().map(|document| document.as_document().unwrap().clone()).collect()
+        };
+
+        let by_status = facet("by_status");
+        let by_status = [TodoStatus::Open, TodoStatus::InProgress, TodoStatus::Done, TodoStatus::Cancelled]
+            .into_iter()
+            .map(|status| StatusCount { status, count: count_of(&by_status, status.as_str()) })
+            .filter(|count| count.count > 0)
+            .collect();
+
+        // Days without any todo have no group, so every day is filled in.
+        let per_day = facet("created_per_day");
+        let created_per_day = (0..30)
+            .rev()
+            .map(|days_ago| today - time::Duration::days(days_ago))
+            .map(|day| format!("{}-{:02}-{:02}", day.year(), u8::from(day.month()), day.day()))
+            .map(|day| DailyCount { count: count_of(&per_day, &day), day })
+            .collect();
+
+        let average_completion_seconds = facet("completion")
+            .first()
+            .and_then(|completion| completion.get_f64("milliseconds").ok())
+            .map(|milliseconds| milliseconds / 1000.0);
+
+        Ok(TodoStats { by_status, created_per_day, average_completion_seconds })
+    }
+    async fn create_todo(
+        &self,
+        user_id: i64,
+        title: &str,
+        description: &str,
+        due_at: Option<OffsetDateTime>,
+        priority: i32,
+    ) -> Result<i64, RepoError> {
+        let todo = new_todo(self.next_ids("todos", 1).await?, user_id, title, description, due_at, priority);
+        self.todos().await.insert_one(todo_document(&todo)).await?;
+        Ok(todo.id)
+    }
+    async fn create_many(&self, user_id: i64, todos: &[CreateTodo]) -> Result<Vec<i64>, RepoError> {
+        if todos.is_empty() {
+            return Ok(Vec::new());
+        }
+        let first_id = self.next_ids("todos", todos.len() as i64).await?;
+        let documents: Vec<Document> = todos
+            .iter()
+            .zip(first_id..)
+            .map(|(todo, id)| new_todo(id, user_id, &todo.title, &todo.description, todo.due_at, todo.priority))
+            .map(|todo| todo_document(&todo))
+            .collect();
+
+        let mut session = self.session().await?;
+        session
+            .start_transaction()
+            .and_run((self, &documents), |session, &mut (repo, documents)| {
+                async move { repo.todos().await.insert_many(documents).session(session).await }.boxed()
+            })
+            .await?;
+        Ok((first_id..first_id + todos.len() as i64).collect())
+    }
+    async fn update_todo(
+        &self,
+        user_id: i64,
+        id: i64,
+        title: Option<&str>,
+        description: Option<&str>,
+        status: Option<TodoStatus>,
+        due_at: Option<OffsetDateTime>,
+        priority: Option<i32>,
+    ) -> Result<Option<i64>, RepoError> {
+        let change = |todo: &mut Todo| {
+            todo.title = title.map_or(todo.title.clone(), str::to_string);
+            todo.description = description.map_or(todo.description.clone(), str::to_string);
+            todo.status = status.unwrap_or(todo.status);
+            todo.due_at = due_at.map(truncate_to_millis).or(todo.due_at);
+            todo.priority = priority.unwrap_or(todo.priority);
+        };
+        Ok(self.update(user_id, id, &change).await?)
+    }
+    async fn replace_todo(&self, user_id: i64, id: i64, todo: &PatchableTodo) -> Result<Option<i64>, RepoError> {
+        let change = |replaced: &mut Todo| {
+            replaced.title = todo.title.clone();
+            replaced.description = todo.description.clone();
+            replaced.status = todo.status;
+            replaced.due_at = todo.due_at.map(truncate_to_millis);
+            replaced.priority = todo.priority;
+            replaced.metadata = serde_json::Value::Object(todo.metadata.clone());
+        };
+        Ok(self.update(user_id, id, &change).await?)
+    }
+    async fn delete_todo(&self, user_id: i64, id: i64) -> Result<Option<i64>, RepoError> {
+        let visible = self.visible(user_id, doc! {}).await;
+        let mut session = self.session().await?;
+        let deleted = session
+            .start_transaction()
+            .and_run((self, &visible), |session, &mut (repo, visible)| {
+                async move { repo.delete_in(session, visible.clone(), id).await }.boxed()
+            })
+            .await;
+        Ok(deleted?)
+    }
+    async fn bulk(
+        &self,
+        user_id: i64,
+        operations: &[BulkOperation],
+        atomic: bool,
+    ) -> Result<Vec<Result<i64, BulkError>>, RepoError> {
+        let visible = self.visible(user_id, doc! {}).await;
+        let creates = operations.iter().filter(|operation| matches!(operation, BulkOperation::Create { .. })).count();
+        let first_id = if creates > 0 { self.next_ids("todos", creates as i64).await? } else { 0 };
+        let mut session = self.session().await?;
+
+        if !atomic {
+            let mut new_ids = first_id..;
+            let mut results = Vec::with_capacity(operations.len());
+            for operation in operations {
+                // Each operation commits on its own, so a create that is
+                // retried takes the same id again.
+                let first_new_id = new_ids.start;
+                let applied = session
+                    .start_transaction()
+                    .and_run((self, &visible, operation), |session, &mut (repo, visible, operation)| {
+                        async move { repo.apply_in(session, user_id, visible, operation, &mut (first_new_id..)).await }.boxed()
+                    })
+                    .await;
+                if matches!(operation, BulkOperation::Create { .. }) {
+                    new_ids.next();
+                }
+                results.push(applied?);
+            }
+            return Ok(results);
+        }
+
+        // A failed operation aborts the transaction with its results, which
+        // are all that is left of it.
+        let applied = session
+            .start_transaction()
+            .and_run((self, &visible, operations), |session, &mut (repo, visible, operations)| {
+                async move {
+                    let mut new_ids = first_id..;
+                    let mut results = Vec::with_capacity(operations.len());
+                    for operation in operations {
+                        let result = repo.apply_in(session, user_id, visible, operation, &mut new_ids).await?;
+                        let failed = result.is_err();
+                        results.push(result);
+                        if failed {
+                            return Err(mongodb::error::Error::custom(rolled_back(results, operations.len())));
+                        }
+                    }
+                    Ok(results)
+                }
+                .boxed()
+            })
+            .await;
+        match applied {
+            Ok(results) => Ok(results),
+            Err(error) => match error.get_custom::<Vec<Result<i64, BulkError>>>() {
+                Some(results) => Ok(results.clone()),
+                None => Err(error.into()),
+            },
+        }
+    }
+    async fn claim_next_todo(&self, user_id: i64) -> Result<Option<Todo>, RepoError> {
+        // A single document is updated atomically, so concurrent claims can
+        // never both find the same todo open and unassigned.
+        let filter = self.visible(user_id, doc! { "status": TodoStatus::Open.as_str(), "assignee_id": null }).await;
+        let claim = doc! { "$set": { "status": TodoStatus::InProgress.as_str(), "assignee_id": user_id } };
+        let claimed = self
+            .todos()
+            .await
+            .find_one_and_update(filter, claim)
+            .sort(doc! { "_id": 1 })
+            .return_document(ReturnDocument::After)
+            .await?;
+        Ok(claimed.as_ref().map(todo_from_document))
+    }
+    async fn get_comments(&self, user_id: i64, todo_id: i64) -> Result<Vec<Comment>, RepoError> {
+        self.get_comments_of(user_id, &[todo_id]).await
+    }
+    async fn get_comments_of(&self, user_id: i64, todo_ids: &[i64]) -> Result<Vec<Comment>, RepoError> {
+        let visible_ids: Vec<i64> = self
+            .find_todos(self.visible(user_id, doc! { "_id": { "$in": todo_ids } }).await, doc! { "_id": 1 }, None)
+            .await?
+            .iter()
+            .map(|todo| todo.id)
+            .collect();
+        let cursor = self.comments().await.find(doc! { "todo_id": { "$in": visible_ids } }).sort(doc! { "_id": 1 }).await;
+        let documents: Vec<Document> = cursor?.try_collect().await?;
+        Ok(documents.iter().map(comment_from_document).collect())
+    }
+    async fn create_comment(&self, user_id: i64, todo_id: i64, body: &str) -> Result<Option<i64>, RepoError> {
+        let id = self.next_ids("comments", 1).await?;
+        let comment = doc! {
+            "_id": id,
+            "todo_id": todo_id,
+            "body": body,
+            "created_at": bson_time(OffsetDateTime::now_utc()),
+        };
+        let filter = self.visible(user_id, doc! { "_id": todo_id }).await;
+
+        // Writing the todo as well, rather than only reading it, makes the
+        // transaction conflict with any that deletes it at the same time, so
+        // that no comment is left without its todo.
+        let mut session = self.session().await?;
+        let created = session
+            .start_transaction()
+            .and_run((self, &filter, &comment), |session, &mut (repo, filter, comment)| {
+                async move {
+                    let touch = doc! { "$set": { "commented_at": comment.get("created_at").unwrap().clone() } };
+                    let todo = repo.todos().await.update_one(filter.clone(), touch).session(&mut *session).await?;
+                    if todo.matched_count == 0 {
+                        return Ok(None);
+                    }
+                    repo.comments().await.insert_one(comment).session(&mut *session).await?;
+                    Ok(Some(id))
+                }
+                .boxed()
+            })
+            .await;
+        Ok(created?)
+    }
+    async fn delete_comment(&self, user_id: i64, todo_id: i64, comment_id: i64) -> Result<bool, RepoError> {
+        if self.get_todo(user_id, todo_id).await?.is_none() {
+            return Ok(false);
+        }
+        let deleted = self.comments().await.delete_one(doc! { "_id": comment_id, "todo_id": todo_id }).await?;
+        Ok(deleted.deleted_count > 0)
+    }
+    async fn erase_user(&self, user_id: i64, dry_run: bool) -> Result<TodoErasure, RepoError> {
+        let mut session = self.session().await?;
+        let erasure = session
+            .start_transaction()
+            .and_run(self, |session, repo| async move { repo.erase_in(session, user_id, dry_run).await }.boxed())
+            .await;
+        Ok(erasure?)
+    }
+}
+
+///
+/// Runs against the MongoDB of `MONGODB_URL`, which must be a replica set
+/// for transactions, with the users in the Postgres of the other tests:
+///
+/// ```bash
+/// docker run -d --name mongo -p 27017:27017 mongo:7 --replSet rs0
+/// docker exec mongo mongosh --eval 'rs.initiate()'
+/// MONGODB_URL='mongodb://localhost:27017/todos?directConnection=true' cargo test --features mongodb mongo
+/// ```
+///
+#[tokio::test]
+async fn todo_handlers_work_on_mongo() {
+    use std::sync::Arc;
+    use sqlx::postgres::PgPoolOptions;
+    use super::conformance::run_todo_handler_suite;
+
+    let pool = PgPoolOptions::new()
+        .max_connections(1)
+        .connect(&crate::test_db::database_url().await)
+        .await
+        .unwrap();
+    let url = std::env::var("MONGODB_URL").expect("MONGODB_URL must be set for the MongoDB tests");
+    let database = TodoRepoMongo::connect(&url).await;
+    TodoRepoMongo::create_indexes(&database).await;
+
+    // The database outlives that of the users, whose ids may come round
+    // again, with the todos of an earlier run.
+    let user_id = crate::users::create_test_user(&pool, false).await;
+    database.collection::<Document>("todos").delete_many(doc! { "owner_id": user_id }).await.unwrap();
+
+    let repo = TodoRepoMongo::with_database(database, UserRepoPostgres::new(pool.clone(), crate::pii::test_cipher()));
+    run_todo_handler_suite(Arc::new(repo), user_id).await;
+}
@@ -0,0 +1,726 @@
+//!
+//! SEAORM
+//! ------
+//!
+//! The todos of `TodoRepoPostgres` again, in the same tables, through SeaORM
+//! rather than sqlx (`TODO_REPO=sea_orm`, with the `sea-orm` feature), to
+//! compare the two. SeaORM runs on sqlx, and shares the app's pool.
+//!
+//! The tables are described twice: by the migrations, and by the entities
+//! below, which are not checked against them when compiling as `query!` is.
+//! Queries are built from the entities, so a misspelt column does not
+//! compile, but a column whose type differs from the database only fails
+//! when it is read. What SeaORM cannot build, such as the recursive queries
+//! of subtasks and the days of `generate_series`, is written out as SQL, as
+//! `Statement`s that nothing checks at all.
+//!
+
+use crate::extract::ExtractError;
+use crate::persistence::{
+    rolled_back, BulkError, BulkOperation, Comment, CreateTodo, DailyCount, PatchableTodo, RepoError, StatusCount,
+    SubtaskError, Todo, TodoCursor, TodoErasure, TodoFilter, TodoPatch, TodoRepo, TodoSort, TodoStats, TodoStatus,
+};
+use axum::async_trait;
+use sea_orm::{
+    sea_query::{Expr, LockBehavior, LockType, Query as SeaQuery, SimpleExpr},
+    ActiveModelTrait, ActiveValue::{NotSet, Set}, ColumnTrait, ConnectionTrait, DatabaseConnection, DbBackend, DbErr,
+    EntityTrait, QueryFilter, QueryOrder, QuerySelect, QueryTrait, RuntimeErr, SqlErr,
+    SqlxPostgresConnector, Statement, TransactionTrait,
+};
+use sqlx::{types::time::{Date, OffsetDateTime}, Pool, Postgres};
+
+mod todo {
+    use sea_orm::entity::prelude::*;
+
+    #[derive(Clone, Debug, PartialEq, DeriveEntityModel)]
+    #[sea_orm(table_name = "todos")]
+    pub struct Model {
+        #[sea_orm(primary_key)]
+        pub id: i64,
+        pub title: String,
+        pub description: String,
+        pub status: Status,
+        pub created_at: TimeDateTimeWithTimeZone,
+        pub due_at: Option<TimeDateTimeWithTimeZone>,
+        pub priority: i32,
+        pub parent_id: Option<i64>,
+        pub owner_id: Option<i64>,
+        pub list_id: Option<i64>,
+        pub assignee_id: Option<i64>,
+        pub completed_at: Option<TimeDateTimeWithTimeZone>,
+        pub metadata: Json,
+    }
+
+    #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+    pub enum Relation {
+        #[sea_orm(has_many = "super::comment::Entity")]
+        Comment,
+    }
+
+    impl Related<super::comment::Entity> for Entity {
+        fn to() -> RelationDef {
+            Relation::Comment.def()
+        }
+    }
+
+    impl ActiveModelBehavior for ActiveModel {}
+
+    ///
+    /// The `todo_status` enum of Postgres. `TodoStatus` is already an
+    /// `sqlx::Type`, and SeaORM needs a type of its own.
+    ///
+    #[derive(Clone, Copy, Debug, PartialEq, Eq, EnumIter, DeriveActiveEnum)]
+    #[sea_orm(rs_type = "String", db_type = "Enum", enum_name = "todo_status")]
+    pub enum Status {
+        #[sea_orm(string_value = "open")]
+        Open,
+        #[sea_orm(string_value = "in_progress")]
+        InProgress,
+        #[sea_orm(string_value = "done")]
+        Done,
+        #[sea_orm(string_value = "cancelled")]
+        Cancelled,
+    }
+}
+
+mod comment {
+    use sea_orm::entity::prelude::*;
+
+    #[derive(Clone, Debug, PartialEq, DeriveEntityModel)]
+    #[sea_orm(table_name = "comments")]
+    pub struct Model {
+        #[sea_orm(primary_key)]
+        pub id: i64,
+        pub todo_id: i64,
+        pub body: String,
+        pub created_at: TimeDateTimeWithTimeZone,
+    }
+
+    #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+    pub enum Relation {
+        #[sea_orm(belongs_to = "super::todo::Entity", from = "Column::TodoId", to = "super::todo::Column::Id")]
+        Todo,
+    }
+
+    impl Related<super::todo::Entity> for Entity {
+        fn to() -> RelationDef {
+            Relation::Todo.def()
+        }
+    }
+
+    impl ActiveModelBehavior for ActiveModel {}
+}
+
+impl From<TodoStatus> for todo::Status {
+    fn from(status: TodoStatus) -> Self {
+        match status {
+            TodoStatus::Open => todo::Status::Open,
+            TodoStatus::InProgress => todo::Status::InProgress,
+            TodoStatus::Done => todo::Status::Done,
+            TodoStatus::Cancelled => todo::Status::Cancelled,
+        }
+    }
+}
+
+impl From<todo::Status> for TodoStatus {
+    fn from(status: todo::Status) -> Self {
+        match status {
+            todo::Status::Open => TodoStatus::Open,
+            todo::Status::InProgress => TodoStatus::InProgress,
+            todo::Status::Done => TodoStatus::Done,
+            todo::Status::Cancelled => TodoStatus::Cancelled,
+        }
+    }
+}
+
+impl From<todo::Model> for Todo {
+    fn from(model: todo::Model) -> Self {
+        Todo {
+            id: model.id,
+            title: model.title,
+            description: model.description,
+            status: model.status.into(),
+            created_at: model.created_at,
+            due_at: model.due_at,
+            priority: model.priority,
+            parent_id: model.parent_id,
+            owner_id: model.owner_id,
+            list_id: model.list_id,
+            completed_at: model.completed_at,
+            metadata: model.metadata,
+        }
+    }
+}
+
+impl From<comment::Model> for Comment {
+    fn from(model: comment::Model) -> Self {
+        Comment { id: model.id, todo_id: model.todo_id, body: model.body, created_at: model.created_at }
+    }
+}
+
+impl From<DbErr> for BulkError {
+    fn from(error: DbErr) -> Self {
+        tracing::error!(%error, "Bulk operation failed");
+        BulkError::Database
+    }
+}
+
+///
+/// The columns of a todo, for the queries written out as SQL. A
+/// `todo_status` has to be read as text, as SeaORM reads it in the
+/// queries it builds.
+///
+const TODO_COLUMNS: &str = "todos.id, title, description, status::TEXT AS status, created_at, due_at, priority, \
+    parent_id, owner_id, list_id, assignee_id, completed_at, metadata";
+
+#[derive(Clone)]
+pub struct TodoRepoSeaOrm {
+    db: DatabaseConnection,
+}
+
+impl TodoRepoSeaOrm {
+    pub fn new(pool: Pool<Postgres>) -> Self {
+        TodoRepoSeaOrm { db: SqlxPostgresConnector::from_sqlx_postgres_pool(pool) }
+    }
+
+    async fn find_todos(&self, sql: String, values: Vec<sea_orm::Value>) -> Result<Vec<Todo>, DbErr> {
+        let statement = Statement::from_sql_and_values(DbBackend::Postgres, sql, values);
+        let models = todo::Entity::find().from_raw_sql(statement).all(&self.db).await?;
+        Ok(models.into_iter().map(Todo::from).collect())
+    }
+}
+
+///
+/// `todo_visible_to`, which SeaORM can only call as an expression of SQL.
+///
+fn visible_to(user_id: i64) -> SimpleExpr {
+    Expr::cust_with_values("todo_visible_to(todos.owner_id, $1)", [user_id])
+}
+
+///
+/// The same as `complete_parents`, on any connection of SeaORM, in a
+/// transaction or not.
+///
+pub async fn complete_parents(db: &impl ConnectionTrait, id: i64) -> Result<(), DbErr> {
+    let mut child_id = id;
+    loop {
+        let statement = Statement::from_sql_and_values(
+            DbBackend::Postgres,
+            "UPDATE todos parent SET status = 'done' FROM todos child
+            WHERE child.id = $1 AND parent.id = child.parent_id AND parent.status IN ('open', 'in_progress')
+            AND NOT EXISTS (
+                SELECT 1 FROM todos sibling WHERE sibling.parent_id = parent.id AND sibling.status IN ('open', 'in_progress')
+            )
+            RETURNING parent.id",
+            [child_id.into()],
+        );
+        match db.query_one(statement).await? {
+            Some(parent) => child_id = parent.try_get("", "id")?,
+            None => return Ok(()),
+        }
+    }
+}
+
+///
+/// Updates the fields of `changes` that are set, and completes parents,
+/// returning the id of the todo if it is visible to the user.
+///
+async fn update_visible(
+    db: &impl ConnectionTrait,
+    user_id: i64,
+    id: i64,
+    changes: todo::ActiveModel,
+) -> Result<Option<i64>, DbErr> {
+    // An `UPDATE` without anything to `SET` is not valid SQL.
+    if !changes.is_changed() {
+        let todo = todo::Entity::find_by_id(id).filter(visible_to(user_id)).one(db).await?;
+        return Ok(todo.map(|todo| todo.id));
+    }
+    let updated = todo::Entity::update_many()
+        .set(changes)
+        .filter(todo::Column::Id.eq(id))
+        .filter(visible_to(user_id))
+        .exec_with_returning(db)
+        .await?;
+    if updated.is_empty() {
+        return Ok(None);
+    }
+    complete_parents(db, id).await?;
+    Ok(Some(id))
+}
+
+///
+/// The changes that replace a todo with `todo`, for `update_visible`.
+///
+fn replacement(todo: &PatchableTodo) -> todo::ActiveModel {
+    todo::ActiveModel {
+        title: Set(todo.title.clone()),
+        description: Set(todo.description.clone()),
+        status: Set(todo.status.into()),
+        due_at: Set(todo.due_at),
+        priority: Set(todo.priority),
+        metadata: Set(serde_json::Value::Object(todo.metadata.clone())),
+        ..Default::default()
+    }
+}
+
+pub async fn apply_bulk_operation(
+    db: &impl ConnectionTrait,
+    user_id: i64,
+    operation: &BulkOperation,
+) -> Result<i64, BulkError> {
+    match operation {
+        BulkOperation::Create { title, description, due_at, priority } => {
+            let todo = todo::ActiveModel {
+                title: Set(title.clone()),
+                description: Set(description.clone()),
+                due_at: Set(*due_at),
+                priority: Set(*priority),
+                owner_id: Set(Some(user_id)),
+                ..Default::default()
+            };
+            Ok(todo::Entity::insert(todo).exec(db).await?.last_insert_id)
+        }
+        BulkOperation::Update { id, title, description, status, due_at, priority } => {
+            let changes = todo::ActiveModel {
+                title: title.clone().map_or(NotSet, Set),
+                description: description.clone().map_or(NotSet, Set),
+                status: status.map_or(NotSet, |status| Set(status.into())),
+                due_at: due_at.map_or(NotSet, |due_at| Set(Some(due_at))),
+                priority: priority.map_or(NotSet, Set),
+                ..Default::default()
+            };
+            update_visible(db, user_id, *id, changes).await?.ok_or(BulkError::NotFound(*id))
+        }
+        BulkOperation::Delete { id } => {
+            let deleted = todo::Entity::delete_many()
+                .filter(todo::Column::Id.eq(*id))
+                .filter(visible_to(user_id))
+                .exec(db)
+                .await?;
+            if deleted.rows_affected == 0 {
+                return Err(BulkError::NotFound(*id));
+            }
+            Ok(*id)
+        }
+    }
+}
+
+#[async_trait]
+impl TodoRepo for TodoRepoSeaOrm {
+    async fn get_todos(&self, user_id: i64, sort: TodoSort) -> Result<Vec<Todo>, RepoError> {
+        let select = todo::Entity::find().filter(visible_to(user_id));
+        let select = match sort {
+            TodoSort::Id => select,
+            TodoSort::Priority => select.order_by_desc(todo::Column::Priority),
+            // Postgres sorts NULLs last already, but SeaORM has no
+            // `NULLS LAST` to say so, and this reads the same anywhere.
+            TodoSort::DueAt => select.order_by_asc(todo::Column::DueAt.is_null()).order_by_asc(todo::Column::DueAt),
+        };
+        let models = select.order_by_asc(todo::Column::Id).all(&self.db).await?;
+        Ok(models.into_iter().map(Todo::from).collect())
+    }
+    async fn get_todos_page(
+        &self,
+        user_id: i64,
+        after: Option<TodoCursor>,
+        limit: i64,
+    ) -> Result<Vec<Todo>, RepoError> {
+        let select = todo::Entity::find().filter(visible_to(user_id)).apply_if(after, |select, after| {
+            let position = Expr::tuple([Expr::col(todo::Column::CreatedAt).into(), Expr::col(todo::Column::Id).into()]);
+            select.filter(position.gt(Expr::tuple([Expr::value(after.created_at), Expr::value(after.id)])))
+        });
+        let models = select
+            .order_by_asc(todo::Column::CreatedAt)
+            .order_by_asc(todo::Column::Id)
+            .limit(limit as u64)
+            .all(&self.db)
+            .await?;
+        Ok(models.into_iter().map(Todo::from).collect())
+    }
+    async fn get_todo(&self, user_id: i64, id: i64) -> Result<Option<Todo>, RepoError> {
+        let model = todo::Entity::find_by_id(id).filter(visible_to(user_id)).one(&self.db).await?;
+        Ok(model.map(Todo::from))
+    }
+    async fn get_todos_filtered(&self, user_id: i64, filter: &TodoFilter) -> Result<Vec<Todo>, RepoError> {
+        let select = todo::Entity::find()
+            .filter(visible_to(user_id))
+            .filter(Expr::cust_with_values("todos.metadata @> $1", [filter.metadata.clone()]))
+            .apply_if((!filter.statuses.is_empty()).then_some(&filter.statuses), |select, statuses| {
+                select.filter(todo::Column::Status.is_in(statuses.iter().map(|&status| todo::Status::from(status))))
+            })
+            .apply_if(filter.due_after, |select, due_after| select.filter(todo::Column::DueAt.gte(due_after)))
+            .apply_if(filter.due_before, |select, due_before| select.filter(todo::Column::DueAt.lt(due_before)));
+        let models = select.order_by_asc(todo::Column::Id).all(&self.db).await?;
+        Ok(models.into_iter().map(Todo::from).collect())
+    }
+    async fn get_todo_tree(&self, user_id: i64, id: i64) -> Result<Vec<Todo>, RepoError> {
+        let sql = format!(
+            "WITH RECURSIVE tree (id, depth) AS (
+                SELECT id, 0 FROM todos WHERE id = $1 AND todo_visible_to(owner_id, $2)
+                UNION ALL
+                SELECT todos.id, tree.depth + 1 FROM todos JOIN tree ON todos.parent_id = tree.id
+                WHERE todo_visible_to(todos.owner_id, $2)
+            )
+            SELECT {} FROM todos JOIN tree ON todos.id = tree.id ORDER BY tree.depth, todos.id",
+            TODO_COLUMNS
+        );
+        Ok(self.find_todos(sql, vec![id.into(), user_id.into()]).await?)
+    }
+    async fn set_parent(
+        &self,
+        user_id: i64,
+        id: i64,
+        parent_id: Option<i64>,
+    ) -> Result<Result<(), SubtaskError>, RepoError> {
+        if self.get_todo(user_id, id).await?.is_none() {
+            return Ok(Err(SubtaskError::NotFound(id)));
+        }
+        if let Some(parent_id) = parent_id {
+            if self.get_todo(user_id, parent_id).await?.is_none() {
+                return Ok(Err(SubtaskError::NotFound(parent_id)));
+            }
+        }
+
+        let statement = Statement::from_sql_and_values(
+            DbBackend::Postgres,
+            "UPDATE todos SET parent_id = $2 WHERE id = $1 AND NOT EXISTS (
+                WITH RECURSIVE descendants (id) AS (
+                    SELECT id FROM todos WHERE id = $1
+                    UNION ALL
+                    SELECT todos.id FROM todos JOIN descendants ON todos.parent_id = descendants.id
+                )
+                SELECT 1 FROM descendants WHERE id = $2
+            )",
+            [id.into(), parent_id.into()],
+        );
+        match self.db.execute(statement).await?.rows_affected() {
+            0 => Ok(Err(SubtaskError::Cycle)),
+            _ => Ok(Ok(())),
+        }
+    }
+    async fn get_overdue_todos(&self, user_id: i64, now: OffsetDateTime) -> Result<Vec<Todo>, RepoError> {
+        let models = todo::Entity::find()
+            .filter(todo::Column::Status.is_in([todo::Status::Open, todo::Status::InProgress]))
+            .filter(todo::Column::DueAt.lt(now))
+            .filter(visible_to(user_id))
+            .order_by_asc(todo::Column::DueAt)
+            .order_by_asc(todo::Column::Id)
+            .all(&self.db)
+            .await?;
+        Ok(models.into_iter().map(Todo::from).collect())
+    }
+    async fn get_todos_due_on(&self, user_id: i64, day: Date, time_zone: &str) -> Result<Option<Vec<Todo>>, RepoError> {
+        let due_on = Expr::cust_with_values(
+            "todos.due_at >= $1::DATE::TIMESTAMP AT TIME ZONE $2 AND todos.due_at < ($1::DATE + 1)::TIMESTAMP AT TIME ZONE $2",
+            [sea_orm::Value::from(day), time_zone.into()],
+        );
+        let select = todo::Entity::find()
+            .filter(due_on)
+            .filter(visible_to(user_id))
+            .order_by_asc(todo::Column::DueAt)
+            .order_by_asc(todo::Column::Id);
+        match select.all(&self.db).await {
+            Ok(models) => Ok(Some(models.into_iter().map(Todo::from).collect())),
+            // invalid_parameter_value: "time zone ... not recognized".
+            // SeaORM only names the errors of constraints, so this one is
+            // found in the error of sqlx that it wraps.
+            Err(DbErr::Query(RuntimeErr::SqlxError(sqlx::Error::Database(error))))
+                if error.code().as_deref() == Some("22023") =>
+            {
+                Ok(None)
+            }
+            Err(error) => Err(error.into()),
+        }
+    }
+    async fn get_stats(&self, user_id: i64) -> Result<TodoStats, RepoError> {
+        let by_status: Vec<(todo::Status, i64)> = todo::Entity::find()
+            .select_only()
+            .column(todo::Column::Status)
+            .column_as(Expr::col(todo::Column::Id).count(), "count")
+            .filter(visible_to(user_id))
+            .group_by(todo::Column::Status)
+            .order_by_asc(todo::Column::Status)
+            .into_tuple()
+            .all(&self.db)
+            .await?;
+        let by_status =
+            by_status.into_iter().map(|(status, count)| StatusCount { status: status.into(), count }).collect();
+
+        let days = self
+            .db
+            .query_all(Statement::from_sql_and_values(
+                DbBackend::Postgres,
+                "SELECT to_char(days.day, 'YYYY-MM-DD') AS day, COUNT(todos.id) AS count
+                FROM generate_series((now() AT TIME ZONE 'UTC')::DATE - 29, (now() AT TIME ZONE 'UTC')::DATE, INTERVAL '1 day')
+                    AS days (day)
+                LEFT JOIN todos ON (todos.created_at AT TIME ZONE 'UTC')::DATE = days.day AND todo_visible_to(todos.owner_id, $1)
+                GROUP BY days.day ORDER BY days.day",
+                [user_id.into()],
+            ))
+            .await?;
+        let created_per_day = days
+            .iter()
+            .map(|row| Ok(DailyCount { day: row.try_get("", "day")?, count: row.try_get("", "count")? }))
+            .collect::<Result<_, DbErr>>()?;
+
+        let average_completion_seconds: Option<f64> = todo::Entity::find()
+            .select_only()
+            .column_as(Expr::cust("EXTRACT(EPOCH FROM AVG(completed_at - created_at))::FLOAT8"), "seconds")
+            .filter(todo::Column::CompletedAt.is_not_null())
+            .filter(visible_to(user_id))
+            .into_tuple()
+            .one(&self.db)
+            .await?
+            .flatten();
+
+        Ok(TodoStats { by_status, created_per_day, average_completion_seconds })
+    }
+    async fn create_todo(
+        &self,
+        user_id: i64,
+        title: &str,
+        description: &str,
+        due_at: Option<OffsetDateTime>,
+        priority: i32,
+    ) -> Result<i64, RepoError> {
+        let operation = BulkOperation::Create {
+            title: title.to_string(),
+            description: description.to_string(),
+            due_at,
+            priority,
+        };
+        Ok(apply_bulk_operation(&self.db, user_id, &operation).await?)
+    }
+    async fn create_many(&self, user_id: i64, todos: &[CreateTodo]) -> Result<Vec<i64>, RepoError> {
+        // `insert_many` gives back the id of its last row alone, so each
+        // todo is inserted on its own, in a single transaction.
+        let tx = self.db.begin().await?;
+        let mut ids = Vec::with_capacity(todos.len());
+        for todo in todos {
+            let operation = BulkOperation::Create {
+                title: todo.title.clone(),
+                description: todo.description.clone(),
+                due_at: todo.due_at,
+                priority: todo.priority,
+            };
+            ids.push(apply_bulk_operation(&tx, user_id, &operation).await?);
+        }
+        tx.commit().await?;
+        Ok(ids)
+    }
+    async fn update_todo(
+        &self,
+        user_id: i64,
+        id: i64,
+        title: Option<&str>,
+        description: Option<&str>,
+        status: Option<TodoStatus>,
+        due_at: Option<OffsetDateTime>,
+        priority: Option<i32>,
+    ) -> Result<Option<i64>, RepoError> {
+        let operation = BulkOperation::Update {
+            id,
+            title: title.map(str::to_string),
+            description: description.map(str::to_string),
+            status,
+            due_at,
+            priority,
+        };
+        match apply_bulk_operation(&self.db, user_id, &operation).await {
+            Ok(id) => Ok(Some(id)),
+            Err(BulkError::NotFound(_)) => Ok(None),
+            Err(error) => Err(error.into()),
+        }
+    }
+    async fn replace_todo(&self, user_id: i64, id: i64, todo: &PatchableTodo) -> Result<Option<i64>, RepoError> {
+        Ok(update_visible(&self.db, user_id, id, replacement(todo)).await?)
+    }
+    async fn patch_todo(
+        &self,
+        user_id: i64,
+        id: i64,
+        patch: &TodoPatch<'_>,
+    ) -> Result<Option<Result<i64, ExtractError>>, RepoError> {
+        // `lock_exclusive` is the `FOR UPDATE` of `TodoRepoPostgres`, which
+        // has a concurrent patch of the todo wait for this one.
+        let tx = self.db.begin().await?;
+        let select = todo::Entity::find_by_id(id).filter(visible_to(user_id)).lock_exclusive();
+        let Some(model) = select.one(&tx).await? else {
+            return Ok(None);
+        };
+
+        let patched = match patch(Todo::from(model)) {
+            Ok(patched) => patched,
+            Err(error) => return Ok(Some(Err(error))),
+        };
+        let Some(id) = update_visible(&tx, user_id, id, replacement(&patched)).await? else {
+            return Ok(None);
+        };
+        tx.commit().await?;
+        Ok(Some(Ok(id)))
+    }
+    async fn delete_todo(&self, user_id: i64, id: i64) -> Result<Option<i64>, RepoError> {
+        match apply_bulk_operation(&self.db, user_id, &BulkOperation::Delete { id }).await {
+            Ok(id) => Ok(Some(id)),
+            Err(BulkError::NotFound(_)) => Ok(None),
+            Err(error) => Err(error.into()),
+        }
+    }
+    async fn bulk(
+        &self,
+        user_id: i64,
+        operations: &[BulkOperation],
+        atomic: bool,
+    ) -> Result<Vec<Result<i64, BulkError>>, RepoError> {
+        if !atomic {
+            let mut results = Vec::with_capacity(operations.len());
+            for operation in operations {
+                results.push(apply_bulk_operation(&self.db, user_id, operation).await);
+            }
+            return Ok(results);
+        }
+
+        let tx = self.db.begin().await?;
+        let mut results = Vec::with_capacity(operations.len());
+        for operation in operations {
+            let result = apply_bulk_operation(&tx, user_id, operation).await;
+            let failed = result.is_err();
+            results.push(result);
+            if failed {
+                tx.rollback().await?;
+                return Ok(rolled_back(results, operations.len()));
+            }
+        }
+        tx.commit().await?;
+        Ok(results)
+    }
+    async fn claim_next_todo(&self, user_id: i64) -> Result<Option<Todo>, RepoError> {
+        // The same `FOR UPDATE SKIP LOCKED` subquery as `TodoRepoPostgres`,
+        // built with sea-query, the query builder under SeaORM.
+        let next = SeaQuery::select()
+            .column(todo::Column::Id)
+            .from(todo::Entity)
+            .and_where(todo::Column::AssigneeId.is_null())
+            .and_where(todo::Column::Status.eq(todo::Status::Open))
+            .and_where(visible_to(user_id))
+            .order_by(todo::Column::Id, sea_orm::Order::Asc)
+            .limit(1)
+            .lock_with_behavior(LockType::Update, LockBehavior::SkipLocked)
+            .to_owned();
+        let changes = todo::ActiveModel {
+            assignee_id: Set(Some(user_id)),
+            status: Set(todo::Status::InProgress),
+            ..Default::default()
+        };
+        let claimed = todo::Entity::update_many()
+            .set(changes)
+            .filter(todo::Column::Id.in_subquery(next))
+            .exec_with_returning(&self.db)
+            .await?;
+        Ok(claimed.into_iter().next().map(Todo::from))
+    }
+    async fn get_comments(&self, user_id: i64, todo_id: i64) -> Result<Vec<Comment>, RepoError> {
+        self.get_comments_of(user_id, &[todo_id]).await
+    }
+    async fn get_comments_of(&self, user_id: i64, todo_ids: &[i64]) -> Result<Vec<Comment>, RepoError> {
+        let models = comment::Entity::find()
+            .inner_join(todo::Entity)
+            .filter(comment::Column::TodoId.is_in(todo_ids.iter().copied()))
+            .filter(visible_to(user_id))
+            .order_by_asc(comment::Column::Id)
+            .all(&self.db)
+            .await?;
+        Ok(models.into_iter().map(Comment::from).collect())
+    }
+    async fn create_comment(&self, user_id: i64, todo_id: i64, body: &str) -> Result<Option<i64>, RepoError> {
+        if self.get_todo(user_id, todo_id).await?.is_none() {
+            return Ok(None);
+        }
+        let comment = comment::ActiveModel { todo_id: Set(todo_id), body: Set(body.to_string()), ..Default::default() };
+        match comment.insert(&self.db).await {
+            Ok(comment) => Ok(Some(comment.id)),
+            // The todo was deleted since it was found.
+            Err(error) if matches!(error.sql_err(), Some(SqlErr::ForeignKeyConstraintViolation(_))) => Ok(None),
+            Err(error) => Err(error.into()),
+        }
+    }
+    async fn delete_comment(&self, user_id: i64, todo_id: i64, comment_id: i64) -> Result<bool, RepoError> {
+        let visible = todo::Entity::find().select_only().column(todo::Column::Id).filter(visible_to(user_id));
+        let deleted = comment::Entity::delete_many()
+            .filter(comment::Column::Id.eq(comment_id))
+            .filter(comment::Column::TodoId.eq(todo_id))
+            .filter(comment::Column::TodoId.in_subquery(visible.into_query()))
+            .exec(&self.db)
+            .await?;
+        Ok(deleted.rows_affected > 0)
+    }
+    async fn erase_user(&self, user_id: i64, dry_run: bool) -> Result<TodoErasure, RepoError> {
+        let tx = self.db.begin().await?;
+        let counts = tx
+            .query_one(Statement::from_sql_and_values(
+                DbBackend::Postgres,
+                "WITH RECURSIVE erased_todos AS (
+                    SELECT id FROM todos WHERE owner_id = $1
+                    UNION
+                    SELECT todos.id FROM todos JOIN erased_todos ON todos.parent_id = erased_todos.id
+                )
+                SELECT
+                    (SELECT COUNT(*) FROM erased_todos) AS todos,
+                    (SELECT COUNT(*) FROM comments WHERE todo_id IN (SELECT id FROM erased_todos)) AS comments,
+                    (
+                        SELECT COUNT(*) FROM todos
+                        WHERE assignee_id = $1 AND id NOT IN (SELECT id FROM erased_todos)
+                    ) AS unassigned_todos",
+                [user_id.into()],
+            ))
+            .await?
+            .unwrap();
+        let erasure = TodoErasure {
+            todos: counts.try_get("", "todos")?,
+            comments: counts.try_get("", "comments")?,
+            unassigned_todos: counts.try_get("", "unassigned_todos")?,
+        };
+        if !dry_run {
+            // The foreign keys delete the subtasks and comments.
+            todo::Entity::delete_many().filter(todo::Column::OwnerId.eq(user_id)).exec(&tx).await?;
+            todo::Entity::update_many()
+                .col_expr(todo::Column::AssigneeId, Expr::value(Option::<i64>::None))
+                .filter(todo::Column::AssigneeId.eq(user_id))
+                .exec(&tx)
+                .await?;
+        }
+        tx.commit().await?;
+        Ok(erasure)
+    }
+}
+
+#[tokio::test]
+async fn sea_orm_repo_conforms() {
+    use sqlx::postgres::PgPoolOptions;
+    use super::conformance::conformance_tests;
+
+    let pool = PgPoolOptions::new()
+        .max_connections(4)
+        .connect(&crate::test_db::database_url().await)
+        .await
+        .unwrap();
+    let user_id = crate::users::create_test_user(&pool, false).await;
+    let other_user_id = crate::users::create_test_user(&pool, false).await;
+    conformance_tests(|| TodoRepoSeaOrm::new(pool.clone()), user_id, other_user_id).await;
+}
+
+#[tokio::test]
+async fn todo_handlers_work_with_sea_orm() {
+    use std::sync::Arc;
+    use sqlx::postgres::PgPoolOptions;
+    use super::conformance::run_todo_handler_suite;
+
+    let pool = PgPoolOptions::new()
+        .max_connections(1)
+        .connect(&crate::test_db::database_url().await)
+        .await
+        .unwrap();
+    let user_id = crate::users::create_test_user(&pool, false).await;
+    run_todo_handler_suite(Arc::new(TodoRepoSeaOrm::new(pool)), user_id).await;
+}
@@ -0,0 +1,697 @@
+//!
+//! SQLITE
+//! ------
+//!
+//! The todos in SQLite, with the `sqlite` feature and `TODO_REPO=sqlite`
+//! (see `TodoRepoSqlite`).
+//!
+
+use std::{collections::HashMap, time::Duration};
+
+use crate::config::AppConfig;
+use crate::extract::ExtractError;
+use crate::persistence::{
+    json_contains, rolled_back, BulkError, BulkOperation, Comment, CreateTodo, DailyCount, PatchableTodo, RepoError,
+    StatusCount, SubtaskError, Todo, TodoCursor, TodoErasure, TodoFilter, TodoPatch, TodoRepo, TodoSort, TodoStats,
+    TodoStatus,
+};
+use crate::users::{UserRepo, UserRepoPostgres};
+use super::{from_unix_micros, unix_micros};
+use axum::async_trait;
+use sqlx::sqlite::{Sqlite, SqliteConnection, SqlitePoolOptions, SqliteRow};
+use sqlx::{types::time::{Date, OffsetDateTime}, Pool, QueryBuilder};
+
+///
+/// A `TodoRepo` on SQLite (`TODO_REPO=sqlite`), with the schema in
+/// `migrations_sqlite`. Its queries are those of `TodoRepoMySql`, with
+/// `RETURNING` where SQLite has it, and these differences:
+///
+/// - Times are kept as microseconds since the Unix epoch, converted by
+///   `unix_micros` and `from_unix_micros`, because SQLite has no type for
+///   them, and the text that sqlx would store does not sort as times do.
+/// - There is no `@>`, so the metadata of `get_todos_filtered` is matched
+///   here, by `json_contains`, after the rest of the filter has run.
+/// - SQLite knows no time zones, so todos are only due on a day in UTC, as in
+///   `TodoRepoInMemory`.
+/// - A database takes one writer at a time, and a transaction that reads
+///   first fails rather than waits when another writes in between, so the
+///   pool has a single connection, and queries take turns on it.
+///
+#[derive(Clone)]
+pub struct TodoRepoSqlite {
+    pool: Pool<Sqlite>,
+    users: UserRepoPostgres,
+}
+
+const TODO_COLUMNS_SQLITE: &str =
+    "todos.id, title, description, status, created_at, due_at, priority, parent_id, owner_id, list_id, completed_at, metadata";
+
+impl TodoRepoSqlite {
+    ///
+    /// The repo of `config.sqlite_url`, which connects when first used.
+    ///
+    pub fn connect_lazy(config: &AppConfig, users: UserRepoPostgres) -> Self {
+        let url = config.sqlite_url.as_deref().expect("SQLITE_URL must be set when TODO_REPO is sqlite");
+        TodoRepoSqlite { pool: Self::pool_options(config).connect_lazy(url).unwrap(), users }
+    }
+
+    ///
+    /// A pool of one connection, which it never closes, so that even a
+    /// `sqlite::memory:` database, which goes with its connection, lasts as
+    /// long as the pool.
+    ///
+    pub fn pool_options(config: &AppConfig) -> SqlitePoolOptions {
+        SqlitePoolOptions::new()
+            .max_connections(1)
+            .acquire_timeout(Duration::from_secs(config.pool.acquire_timeout_secs))
+            .idle_timeout(None)
+            .max_lifetime(None)
+    }
+
+    ///
+    /// Runs the migrations of `migrations_sqlite`, with a pool of its own,
+    /// before the app starts.
+    ///
+    pub async fn migrate(config: &AppConfig) {
+        let pool = Self::pool_options(config).connect(config.sqlite_url.as_deref().unwrap()).await.unwrap();
+        sqlx::migrate!("./migrations_sqlite").run(&pool).await.unwrap();
+        pool.close().await;
+    }
+
+    async fn is_admin(&self, user_id: i64) -> bool {
+        self.users.get_user(user_id).await.is_some_and(|user| user.is_admin)
+    }
+
+    async fn fetch_todos(&self, query: SqliteQuery<'_>) -> Result<Vec<Todo>, sqlx::Error> {
+        let rows = query.fetch_all(&self.pool).await?;
+        Ok(rows.iter().map(todo_from_sqlite).collect())
+    }
+}
+
+type SqliteQuery<'q> = sqlx::query::Query<'q, Sqlite, sqlx::sqlite::SqliteArguments<'q>>;
+
+fn todo_from_sqlite(row: &SqliteRow) -> Todo {
+    use sqlx::Row;
+
+    Todo {
+        id: row.get("id"),
+        title: row.get("title"),
+        description: row.get("description"),
+        status: row.get::<String, _>("status").parse().unwrap(),
+        created_at: from_unix_micros(row.get("created_at")),
+        due_at: row.get::<Option<i64>, _>("due_at").map(from_unix_micros),
+        priority: row.get("priority"),
+        parent_id: row.get("parent_id"),
+        owner_id: row.get("owner_id"),
+        list_id: row.get("list_id"),
+        completed_at: row.get::<Option<i64>, _>("completed_at").map(from_unix_micros),
+        metadata: row.get::<sqlx::types::Json<serde_json::Value>, _>("metadata").0,
+    }
+}
+
+fn comment_from_sqlite(row: &SqliteRow) -> Comment {
+    use sqlx::Row;
+
+    Comment {
+        id: row.get("id"),
+        todo_id: row.get("todo_id"),
+        body: row.get("body"),
+        created_at: from_unix_micros(row.get("created_at")),
+    }
+}
+
+///
+/// The same as `complete_parents_mysql`.
+///
+async fn complete_parents_sqlite(conn: &mut SqliteConnection, id: i64) -> Result<(), sqlx::Error> {
+    let mut child_id = id;
+    loop {
+        let parent: Option<(i64, String)> = sqlx::query_as(
+            "SELECT parent.id, parent.status FROM todos child JOIN todos parent ON parent.id = child.parent_id
+            WHERE child.id = ?",
+        )
+        .bind(child_id)
+        .fetch_optional(&mut *conn)
+        .await?;
+        let Some((parent_id, status)) = parent else {
+            return Ok(());
+        };
+        let (open_siblings,): (i64,) =
+            sqlx::query_as("SELECT COUNT(*) FROM todos WHERE parent_id = ? AND status IN ('open', 'in_progress')")
+                .bind(parent_id)
+                .fetch_one(&mut *conn)
+                .await?;
+        if !matches!(status.as_str(), "open" | "in_progress") || open_siblings > 0 {
+            return Ok(());
+        }
+        sqlx::query("UPDATE todos SET status = 'done' WHERE id = ?").bind(parent_id).execute(&mut *conn).await?;
+        child_id = parent_id;
+    }
+}
+
+///
+/// The `replace_todo` of `TodoRepoSqlite`, on a connection that is already in
+/// a transaction.
+///
+async fn replace_todo_sqlite(
+    conn: &mut SqliteConnection,
+    user_id: i64,
+    is_admin: bool,
+    id: i64,
+    todo: &PatchableTodo,
+) -> Result<Option<i64>, sqlx::Error> {
+    let query = sqlx::query(
+        "UPDATE todos SET title = ?, description = ?, status = ?, due_at = ?, priority = ?, metadata = ?
+        WHERE id = ? AND (owner_id = ? OR ?)",
+    )
+    .bind(&todo.title)
+    .bind(&todo.description)
+    .bind(todo.status.as_str())
+    .bind(todo.due_at.map(unix_micros))
+    .bind(todo.priority)
+    .bind(sqlx::types::Json(&todo.metadata))
+    .bind(id)
+    .bind(user_id)
+    .bind(is_admin);
+
+    if query.execute(&mut *conn).await?.rows_affected() == 0 {
+        return Ok(None);
+    }
+    complete_parents_sqlite(conn, id).await?;
+    Ok(Some(id))
+}
+
+async fn apply_bulk_operation_sqlite(
+    conn: &mut SqliteConnection,
+    user_id: i64,
+    is_admin: bool,
+    operation: &BulkOperation,
+) -> Result<i64, BulkError> {
+    match operation {
+        BulkOperation::Create { title, description, due_at, priority } => Ok(sqlx::query(
+            "INSERT INTO todos (title, description, due_at, priority, owner_id) VALUES (?, ?, ?, ?, ?)",
+        )
+        .bind(title)
+        .bind(description)
+        .bind(due_at.map(unix_micros))
+        .bind(priority)
+        .bind(user_id)
+        .execute(&mut *conn)
+        .await?
+        .last_insert_rowid()),
+        BulkOperation::Update { id, title, description, status, due_at, priority } => {
+            let updated = sqlx::query(
+                "UPDATE todos SET title = COALESCE(?, title), description = COALESCE(?, description),
+                status = COALESCE(?, status), due_at = COALESCE(?, due_at), priority = COALESCE(?, priority)
+                WHERE id = ? AND (owner_id = ? OR ?)",
+            )
+            .bind(title)
+            .bind(description)
+            .bind(status.map(|status| status.as_str()))
+            .bind(due_at.map(unix_micros))
+            .bind(priority)
+            .bind(id)
+            .bind(user_id)
+            .bind(is_admin)
+            .execute(&mut *conn)
+            .await?;
+            if updated.rows_affected() == 0 {
+                return Err(BulkError::NotFound(*id));
+            }
+            complete_parents_sqlite(conn, *id).await?;
+            Ok(*id)
+        }
+        BulkOperation::Delete { id } => {
+            let deleted = sqlx::query("DELETE FROM todos WHERE id = ? AND (owner_id = ? OR ?)")
+                .bind(id)
+                .bind(user_id)
+                .bind(is_admin)
+                .execute(&mut *conn)
+                .await?;
+            if deleted.rows_affected() == 0 {
+                return Err(BulkError::NotFound(*id));
+            }
+            Ok(*id)
+        }
+    }
+}
+
+#[async_trait]
+impl TodoRepo for TodoRepoSqlite {
+    async fn get_todos(&self, user_id: i64, sort: TodoSort) -> Result<Vec<Todo>, RepoError> {
+        // SQLite sorts NULLs first, so `due_at IS NULL` puts them last.
+        let order = match sort {
+            TodoSort::Id => "id",
+            TodoSort::Priority => "priority DESC, id",
+            TodoSort::DueAt => "due_at IS NULL, due_at, id",
+        };
+        let sql = format!("SELECT {} FROM todos WHERE (owner_id = ? OR ?) ORDER BY {}", TODO_COLUMNS_SQLITE, order);
+        let is_admin = self.is_admin(user_id).await;
+        Ok(self.fetch_todos(sqlx::query(&sql).bind(user_id).bind(is_admin)).await?)
+    }
+    async fn get_todos_page(
+        &self,
+        user_id: i64,
+        after: Option<TodoCursor>,
+        limit: i64,
+    ) -> Result<Vec<Todo>, RepoError> {
+        let is_admin = self.is_admin(user_id).await;
+        let Some(TodoCursor { created_at, id }) = after else {
+            let sql = format!(
+                "SELECT {} FROM todos WHERE (owner_id = ? OR ?) ORDER BY created_at, id LIMIT ?",
+                TODO_COLUMNS_SQLITE
+            );
+            return Ok(self.fetch_todos(sqlx::query(&sql).bind(user_id).bind(is_admin).bind(limit)).await?);
+        };
+        let sql = format!(
+            "SELECT {} FROM todos WHERE (owner_id = ? OR ?) AND (created_at, id) > (?, ?) ORDER BY created_at, id LIMIT ?",
+            TODO_COLUMNS_SQLITE
+        );
+        let query = sqlx::query(&sql).bind(user_id).bind(is_admin).bind(unix_micros(created_at)).bind(id).bind(limit);
+        Ok(self.fetch_todos(query).await?)
+    }
+    async fn get_todo(&self, user_id: i64, id: i64) -> Result<Option<Todo>, RepoError> {
+        let sql = format!("SELECT {} FROM todos WHERE id = ? AND (owner_id = ? OR ?)", TODO_COLUMNS_SQLITE);
+        let is_admin = self.is_admin(user_id).await;
+        Ok(self.fetch_todos(sqlx::query(&sql).bind(id).bind(user_id).bind(is_admin)).await?.pop())
+    }
+    async fn get_todos_filtered(&self, user_id: i64, filter: &TodoFilter) -> Result<Vec<Todo>, RepoError> {
+        let is_admin = self.is_admin(user_id).await;
+        let mut query = QueryBuilder::<Sqlite>::new(format!("SELECT {} FROM todos WHERE (owner_id = ", TODO_COLUMNS_SQLITE));
+        query.push_bind(user_id).push(" OR ").push_bind(is_admin).push(")");
+        if !filter.statuses.is_empty() {
+            query.push(" AND status IN (");
+            let mut statuses = query.separated(", ");
+            for status in &filter.statuses {
+                statuses.push_bind(status.as_str());
+            }
+            query.push(")");
+        }
+        if let Some(due_after) = filter.due_after {
+            query.push(" AND due_at >= ").push_bind(unix_micros(due_after));
+        }
+        if let Some(due_before) = filter.due_before {
+            query.push(" AND due_at < ").push_bind(unix_micros(due_before));
+        }
+        query.push(" ORDER BY id");
+        let rows = query.build().fetch_all(&self.pool).await?;
+        Ok(rows.iter().map(todo_from_sqlite).filter(|todo| json_contains(&todo.metadata, &filter.metadata)).collect())
+    }
+    async fn get_todo_tree(&self, user_id: i64, id: i64) -> Result<Vec<Todo>, RepoError> {
+        let sql = format!(
+            "WITH RECURSIVE tree (id, depth) AS (
+                SELECT id, 0 FROM todos WHERE id = ? AND (owner_id = ? OR ?)
+                UNION ALL
+                SELECT todos.id, tree.depth + 1 FROM todos JOIN tree ON todos.parent_id = tree.id
+                WHERE (todos.owner_id = ? OR ?)
+            )
+            SELECT {} FROM todos JOIN tree ON todos.id = tree.id ORDER BY tree.depth, todos.id",
+            TODO_COLUMNS_SQLITE
+        );
+        let is_admin = self.is_admin(user_id).await;
+        Ok(self
+            .fetch_todos(sqlx::query(&sql).bind(id).bind(user_id).bind(is_admin).bind(user_id).bind(is_admin))
+            .await?)
+    }
+    async fn set_parent(
+        &self,
+        user_id: i64,
+        id: i64,
+        parent_id: Option<i64>,
+    ) -> Result<Result<(), SubtaskError>, RepoError> {
+        if self.get_todo(user_id, id).await?.is_none() {
+            return Ok(Err(SubtaskError::NotFound(id)));
+        }
+        if let Some(parent_id) = parent_id {
+            if self.get_todo(user_id, parent_id).await?.is_none() {
+                return Ok(Err(SubtaskError::NotFound(parent_id)));
+            }
+        }
+
+        let mut tx = self.pool.begin().await?;
+        let (cycles,): (i64,) = sqlx::query_as(
+            "WITH RECURSIVE descendants (id) AS (
+                SELECT id FROM todos WHERE id = ?
+                UNION ALL
+                SELECT todos.id FROM todos JOIN descendants ON todos.parent_id = descendants.id
+            )
+            SELECT COUNT(*) FROM descendants WHERE id = ?",
+        )
+        .bind(id)
+        .bind(parent_id)
+        .fetch_one(&mut *tx)
+        .await?;
+        if cycles > 0 {
+            return Ok(Err(SubtaskError::Cycle));
+        }
+        sqlx::query("UPDATE todos SET parent_id = ? WHERE id = ?").bind(parent_id).bind(id).execute(&mut *tx).await?;
+        tx.commit().await?;
+        Ok(Ok(()))
+    }
+    async fn get_overdue_todos(&self, user_id: i64, now: OffsetDateTime) -> Result<Vec<Todo>, RepoError> {
+        let sql = format!(
+            "SELECT {} FROM todos WHERE status IN ('open', 'in_progress') AND due_at < ? AND (owner_id = ? OR ?)
+            ORDER BY due_at, id",
+            TODO_COLUMNS_SQLITE
+        );
+        let is_admin = self.is_admin(user_id).await;
+        Ok(self.fetch_todos(sqlx::query(&sql).bind(unix_micros(now)).bind(user_id).bind(is_admin)).await?)
+    }
+    async fn get_todos_due_on(&self, user_id: i64, day: Date, time_zone: &str) -> Result<Option<Vec<Todo>>, RepoError> {
+        let Some(next_day) = day.next_day().filter(|_| time_zone == "UTC") else {
+            return Ok(None);
+        };
+        let start = day.midnight().assume_utc();
+        let end = next_day.midnight().assume_utc();
+
+        let sql = format!(
+            "SELECT {} FROM todos WHERE due_at >= ? AND due_at < ? AND (owner_id = ? OR ?) ORDER BY due_at, id",
+            TODO_COLUMNS_SQLITE
+        );
+        let is_admin = self.is_admin(user_id).await;
+        let query = sqlx::query(&sql).bind(unix_micros(start)).bind(unix_micros(end)).bind(user_id).bind(is_admin);
+        Ok(Some(self.fetch_todos(query).await?))
+    }
+    async fn get_stats(&self, user_id: i64) -> Result<TodoStats, RepoError> {
+        let is_admin = self.is_admin(user_id).await;
+
+        // Statuses are text, so they are put in the order of the Postgres enum.
+        let by_status: Vec<(String, i64)> = sqlx::query_as(
+            "SELECT status, COUNT(*) FROM todos WHERE (owner_id = ? OR ?) GROUP BY status
+            ORDER BY CASE status WHEN 'open' THEN 0 WHEN 'in_progress' THEN 1 WHEN 'done' THEN 2 ELSE 3 END",
+        )
+        .bind(user_id)
+        .bind(is_admin)
+        .fetch_all(&self.pool)
+        .await?;
+        let by_status = by_status
+            .into_iter()
+            .map(|(status, count)| StatusCount { status: status.parse().unwrap(), count })
+            .collect();
+
+        // Without `generate_series`, the days without todos are filled in here.
+        let today = OffsetDateTime::now_utc().date();
+        let first_day = today - time::Duration::days(29);
+        let counts: HashMap<String, i64> = sqlx::query_as(
+            "SELECT strftime('%Y-%m-%d', created_at / 1000000, 'unixepoch') AS day, COUNT(*) FROM todos
+            WHERE created_at >= ? AND (owner_id = ? OR ?) GROUP BY day",
+        )
+        .bind(unix_micros(first_day.midnight().assume_utc()))
+        .bind(user_id)
+        .bind(is_admin)
+        .fetch_all(&self.pool)
+        .await?
+        .into_iter()
+        .collect();
+        let created_per_day = (0..30)
+            .rev()
+            .map(|days_ago| today - time::Duration::days(days_ago))
+            .map(|day| format!("{}-{:02}-{:02}", day.year(), u8::from(day.month()), day.day()))
+            .map(|day| DailyCount { count: counts.get(&day).copied().unwrap_or(0), day })
+            .collect();
+
+        let (average_completion_seconds,): (Option<f64>,) = sqlx::query_as(
+            "SELECT AVG(completed_at - created_at) / 1e6 FROM todos WHERE completed_at IS NOT NULL AND (owner_id = ? OR ?)",
+        )
+        .bind(user_id)
+        .bind(is_admin)
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(TodoStats { by_status, created_per_day, average_completion_seconds })
+    }
+    async fn create_todo(
+        &self,
+        user_id: i64,
+        title: &str,
+        description: &str,
+        due_at: Option<OffsetDateTime>,
+        priority: i32,
+    ) -> Result<i64, RepoError> {
+        let query = sqlx::query("INSERT INTO todos (title, description, due_at, priority, owner_id) VALUES (?, ?, ?, ?, ?)")
+            .bind(title)
+            .bind(description)
+            .bind(due_at.map(unix_micros))
+            .bind(priority)
+            .bind(user_id);
+        Ok(query.execute(&self.pool).await?.last_insert_rowid())
+    }
+    async fn create_many(&self, user_id: i64, todos: &[CreateTodo]) -> Result<Vec<i64>, RepoError> {
+        // The rows of `RETURNING` come back in no particular order, so each
+        // todo is inserted on its own, in a single transaction.
+        let mut tx = self.pool.begin().await?;
+        let mut ids = Vec::with_capacity(todos.len());
+        for todo in todos {
+            let operation = BulkOperation::Create {
+                title: todo.title.clone(),
+                description: todo.description.clone(),
+                due_at: todo.due_at,
+                priority: todo.priority,
+            };
+            ids.push(apply_bulk_operation_sqlite(&mut tx, user_id, false, &operation).await?);
+        }
+        tx.commit().await?;
+        Ok(ids)
+    }
+    async fn update_todo(
+        &self,
+        user_id: i64,
+        id: i64,
+        title: Option<&str>,
+        description: Option<&str>,
+        status: Option<TodoStatus>,
+        due_at: Option<OffsetDateTime>,
+        priority: Option<i32>,
+    ) -> Result<Option<i64>, RepoError> {
+        let operation = BulkOperation::Update {
+            id,
+            title: title.map(str::to_string),
+            description: description.map(str::to_string),
+            status,
+            due_at,
+            priority,
+        };
+        let is_admin = self.is_admin(user_id).await;
+        let mut tx = self.pool.begin().await?;
+        match apply_bulk_operation_sqlite(&mut tx, user_id, is_admin, &operation).await {
+            Ok(id) => {
+                tx.commit().await?;
+                Ok(Some(id))
+            }
+            Err(BulkError::NotFound(_)) => Ok(None),
+            Err(error) => Err(error.into()),
+        }
+    }
+    async fn replace_todo(&self, user_id: i64, id: i64, todo: &PatchableTodo) -> Result<Option<i64>, RepoError> {
+        let is_admin = self.is_admin(user_id).await;
+        let mut tx = self.pool.begin().await?;
+        let Some(id) = replace_todo_sqlite(&mut tx, user_id, is_admin, id, todo).await? else {
+            return Ok(None);
+        };
+        tx.commit().await?;
+        Ok(Some(id))
+    }
+    async fn patch_todo(
+        &self,
+        user_id: i64,
+        id: i64,
+        patch: &TodoPatch<'_>,
+    ) -> Result<Option<Result<i64, ExtractError>>, RepoError> {
+        // The transaction holds the only connection of the pool, so a
+        // concurrent patch of the todo waits for this one to be saved, with
+        // nothing to lock.
+        let is_admin = self.is_admin(user_id).await;
+        let sql = format!("SELECT {} FROM todos WHERE id = ? AND (owner_id = ? OR ?)", TODO_COLUMNS_SQLITE);
+        let mut tx = self.pool.begin().await?;
+        let Some(row) = sqlx::query(&sql).bind(id).bind(user_id).bind(is_admin).fetch_optional(&mut *tx).await? else {
+            return Ok(None);
+        };
+
+        let patched = match patch(todo_from_sqlite(&row)) {
+            Ok(patched) => patched,
+            Err(error) => return Ok(Some(Err(error))),
+        };
+        let Some(id) = replace_todo_sqlite(&mut tx, user_id, is_admin, id, &patched).await? else {
+            return Ok(None);
+        };
+        tx.commit().await?;
+        Ok(Some(Ok(id)))
+    }
+    async fn delete_todo(&self, user_id: i64, id: i64) -> Result<Option<i64>, RepoError> {
+        let is_admin = self.is_admin(user_id).await;
+        let mut conn = self.pool.acquire().await?;
+        match apply_bulk_operation_sqlite(&mut conn, user_id, is_admin, &BulkOperation::Delete { id }).await {
+            Ok(id) => Ok(Some(id)),
+            Err(BulkError::NotFound(_)) => Ok(None),
+            Err(error) => Err(error.into()),
+        }
+    }
+    async fn bulk(
+        &self,
+        user_id: i64,
+        operations: &[BulkOperation],
+        atomic: bool,
+    ) -> Result<Vec<Result<i64, BulkError>>, RepoError> {
+        let is_admin = self.is_admin(user_id).await;
+        if !atomic {
+            let mut conn = self.pool.acquire().await?;
+            let mut results = Vec::with_capacity(operations.len());
+            for operation in operations {
+                results.push(apply_bulk_operation_sqlite(&mut conn, user_id, is_admin, operation).await);
+            }
+            return Ok(results);
+        }
+
+        let mut tx = self.pool.begin().await?;
+        let mut results = Vec::with_capacity(operations.len());
+        for operation in operations {
+            let result = apply_bulk_operation_sqlite(&mut tx, user_id, is_admin, operation).await;
+            let failed = result.is_err();
+            results.push(result);
+            if failed {
+                tx.rollback().await?;
+                return Ok(rolled_back(results, operations.len()));
+            }
+        }
+        tx.commit().await?;
+        Ok(results)
+    }
+    async fn claim_next_todo(&self, user_id: i64) -> Result<Option<Todo>, RepoError> {
+        // With a single writer, the todo that the subquery picks cannot be
+        // claimed by anyone else before the update, so there is nothing to
+        // lock, or to skip.
+        let sql = format!(
+            "UPDATE todos SET assignee_id = ?, status = 'in_progress'
+            WHERE id = (
+                SELECT id FROM todos WHERE assignee_id IS NULL AND status = 'open' AND (owner_id = ? OR ?)
+                ORDER BY id LIMIT 1
+            )
+            RETURNING {}",
+            TODO_COLUMNS_SQLITE
+        );
+        let is_admin = self.is_admin(user_id).await;
+        Ok(self.fetch_todos(sqlx::query(&sql).bind(user_id).bind(user_id).bind(is_admin)).await?.pop())
+    }
+    async fn get_comments(&self, user_id: i64, todo_id: i64) -> Result<Vec<Comment>, RepoError> {
+        self.get_comments_of(user_id, &[todo_id]).await
+    }
+    async fn get_comments_of(&self, user_id: i64, todo_ids: &[i64]) -> Result<Vec<Comment>, RepoError> {
+        if todo_ids.is_empty() {
+            return Ok(Vec::new());
+        }
+        let mut query = QueryBuilder::<Sqlite>::new(
+            "SELECT comments.id, comments.todo_id, comments.body, comments.created_at
+            FROM comments JOIN todos ON todos.id = comments.todo_id WHERE comments.todo_id IN (",
+        );
+        let mut ids = query.separated(", ");
+        for todo_id in todo_ids {
+            ids.push_bind(todo_id);
+        }
+        query.push(") AND (todos.owner_id = ").push_bind(user_id);
+        query.push(" OR ").push_bind(self.is_admin(user_id).await).push(") ORDER BY comments.id");
+        let rows = query.build().fetch_all(&self.pool).await?;
+        Ok(rows.iter().map(comment_from_sqlite).collect())
+    }
+    async fn create_comment(&self, user_id: i64, todo_id: i64, body: &str) -> Result<Option<i64>, RepoError> {
+        let query = sqlx::query_as("INSERT INTO comments (todo_id, body) SELECT id, ? FROM todos WHERE id = ? AND (owner_id = ? OR ?) RETURNING id")
+            .bind(body)
+            .bind(todo_id)
+            .bind(user_id)
+            .bind(self.is_admin(user_id).await);
+        Ok(query.fetch_optional(&self.pool).await?.map(|(id,)| id))
+    }
+    async fn delete_comment(&self, user_id: i64, todo_id: i64, comment_id: i64) -> Result<bool, RepoError> {
+        let query = sqlx::query(
+            "DELETE FROM comments WHERE todo_id = ? AND id = ?
+            AND todo_id IN (SELECT id FROM todos WHERE owner_id = ? OR ?)",
+        )
+        .bind(todo_id)
+        .bind(comment_id)
+        .bind(user_id)
+        .bind(self.is_admin(user_id).await);
+        Ok(query.execute(&self.pool).await?.rows_affected() > 0)
+    }
+    async fn erase_user(&self, user_id: i64, dry_run: bool) -> Result<TodoErasure, RepoError> {
+        let mut tx = self.pool.begin().await?;
+        let (todos, comments, unassigned_todos): (i64, i64, i64) = sqlx::query_as(
+            "WITH RECURSIVE erased_todos AS (
+                SELECT id FROM todos WHERE owner_id = ?
+                UNION
+                SELECT todos.id FROM todos JOIN erased_todos ON todos.parent_id = erased_todos.id
+            )
+            SELECT
+                (SELECT COUNT(*) FROM erased_todos),
+                (SELECT COUNT(*) FROM comments WHERE todo_id IN (SELECT id FROM erased_todos)),
+                (SELECT COUNT(*) FROM todos WHERE assignee_id = ? AND id NOT IN (SELECT id FROM erased_todos))",
+        )
+        .bind(user_id)
+        .bind(user_id)
+        .fetch_one(&mut *tx)
+        .await?;
+        if !dry_run {
+            // The foreign keys delete the subtasks and comments.
+            sqlx::query("DELETE FROM todos WHERE owner_id = ?").bind(user_id).execute(&mut *tx).await?;
+            let unassign = sqlx::query("UPDATE todos SET assignee_id = NULL WHERE assignee_id = ?").bind(user_id);
+            unassign.execute(&mut *tx).await?;
+        }
+        tx.commit().await?;
+        Ok(TodoErasure { todos, comments, unassigned_todos })
+    }
+}
+
+///
+/// A repo on a fresh SQLite database in memory, migrated, which lasts as long
+/// as the repo, with the users of `pool`.
+///
+#[cfg(test)]
+async fn test_sqlite_repo(pool: &sqlx::Pool<sqlx::Postgres>) -> TodoRepoSqlite {
+    let sqlite = SqlitePoolOptions::new()
+        .max_connections(1)
+        .idle_timeout(None)
+        .max_lifetime(None)
+        .connect("sqlite::memory:")
+        .await
+        .unwrap();
+    sqlx::migrate!("./migrations_sqlite").run(&sqlite).await.unwrap();
+    TodoRepoSqlite { pool: sqlite, users: UserRepoPostgres::new(pool.clone(), crate::pii::test_cipher()) }
+}
+
+///
+/// Runs against a SQLite database in memory, with the users in the Postgres
+/// of the other tests:
+///
+/// ```bash
+/// cargo test --features sqlite sqlite
+/// ```
+///
+#[tokio::test]
+async fn todo_repos_conform_on_sqlite() {
+    use sqlx::postgres::PgPoolOptions;
+    use super::conformance::conformance_tests;
+
+    let pool = PgPoolOptions::new()
+        .max_connections(1)
+        .connect(&crate::test_db::database_url().await)
+        .await
+        .unwrap();
+    let user_id = crate::users::create_test_user(&pool, false).await;
+    let other_user_id = crate::users::create_test_user(&pool, false).await;
+    let repo = test_sqlite_repo(&pool).await;
+    conformance_tests(|| repo.clone(), user_id, other_user_id).await;
+}
+
+#[tokio::test]
+async fn todo_handlers_work_on_sqlite() {
+    use std::sync::Arc;
+    use sqlx::postgres::PgPoolOptions;
+    use super::conformance::run_todo_handler_suite;
+
+    let pool = PgPoolOptions::new()
+        .max_connections(1)
+        .connect(&crate::test_db::database_url().await)
+        .await
+        .unwrap();
+    let user_id = crate::users::create_test_user(&pool, false).await;
+    let repo = test_sqlite_repo(&pool).await;
+    run_todo_handler_suite(Arc::new(repo), user_id).await;
+}
@@ -0,0 +1,554 @@
+#![allow(dead_code)]
+
+//!
+//! TWO-FACTOR AUTHENTICATION
+//! -------------------------
+//!
+//! A password can leak. A second factor, the time-based one-time passwords
+//! (TOTP, RFC 6238) of an authenticator app, means that a leaked password
+//! alone no longer logs anyone in.
+//!
+//! The app and the server share a secret. Every 30 seconds, both compute a
+//! 6-digit code from the secret and the current time step, with HMAC-SHA1,
+//! and the login succeeds if they agree. Clocks drift, so the codes of the
+//! steps just before and after are accepted too; a code that was accepted
+//! once is not accepted again, so that one seen over a shoulder is useless.
+//!
+//! Enrolling takes two steps. `POST /users/me/two-factor` draws a secret and
+//! answers with its `otpauth://` URI, the payload of the QR code that apps
+//! scan. It stays pending until `POST /users/me/two-factor/confirm` receives
+//! a first code, proving that the app has it, which answers with ten
+//! recovery codes: single-use codes for a lost phone, shown once and only
+//! stored hashed.
+//!
+//! From then on, `POST /users/login` answers a right password with a short
+//! lived `two_factor_token`, instead of tokens, which
+//! `POST /users/login/two-factor` exchanges, along with a code or a recovery
+//! code, for tokens whose `mfa` claim is set.
+//!
+//! Admins can see and change every todo, so `require_two_factor_for_admins`
+//! turns them away from the todo routes unless their session started with a
+//! second factor. Sessions restored from a remember-me token did not, which
+//! sends admins back to the login.
+//!
+
+use axum::{
+    async_trait,
+    extract::{Request, State},
+    http::{header, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use sha1::Sha1;
+use sqlx::{Pool, Postgres};
+use std::{collections::HashMap, sync::Arc};
+use time::{Duration, PrimitiveDateTime};
+use tokio::sync::Mutex;
+
+use crate::auth::{decode_access_token, hash_token, sign, verify, JwtKeys};
+use crate::clock::SharedClock;
+use crate::pat::PatGrant;
+use crate::problem::Problem;
+use crate::users::{UserRepo, UserState};
+
+const STEP_SECONDS: i64 = 30;
+const DIGITS: u32 = 6;
+const RECOVERY_CODES: usize = 10;
+const CHALLENGE_TTL: Duration = Duration::minutes(5);
+///
+/// The name that authenticator apps show next to the account.
+///
+const ISSUER: &str = "Todos";
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct TotpSecret {
+    pub user_id: i64,
+    /// Base32, as apps take it.
+    pub secret: String,
+    /// `None` while the enrollment is pending.
+    pub enabled_at: Option<PrimitiveDateTime>,
+    pub last_step: Option<i64>,
+}
+
+#[async_trait]
+pub trait TotpRepo: Send + Sync {
+    async fn get(&self, user_id: i64) -> Option<TotpSecret>;
+    ///
+    /// Starts an enrollment with a new pending secret, replacing any pending
+    /// one. Returns `false` if the user already has two-factor enabled.
+    ///
+    async fn set_pending(&self, user_id: i64, secret: &str) -> bool;
+    ///
+    /// Enables the pending secret, with the step of the code that confirmed
+    /// it, and replaces the recovery codes. Returns `false` if nothing was
+    /// pending.
+    ///
+    async fn enable(&self, user_id: i64, step: i64, now: PrimitiveDateTime, recovery_code_hashes: &[String]) -> bool;
+    ///
+    /// Records that the code of `step` was used, unless it, or a later one,
+    /// already was. Returns `false` for a replayed code.
+    ///
+    async fn use_step(&self, user_id: i64, step: i64) -> bool;
+    ///
+    /// Marks an unused recovery code of the user as used, returning `false`
+    /// if there was none with this hash.
+    ///
+    async fn use_recovery_code(&self, user_id: i64, code_hash: &str, now: PrimitiveDateTime) -> bool;
+    ///
+    /// Removes the secret and the recovery codes of the user.
+    ///
+    async fn disable(&self, user_id: i64) -> bool;
+}
+
+#[derive(Clone)]
+pub struct TotpRepoPostgres {
+    pool: Pool<Postgres>,
+}
+
+impl TotpRepoPostgres {
+    pub fn new(pool: Pool<Postgres>) -> Self {
+        TotpRepoPostgres { pool }
+    }
+}
+
+#[async_trait]
+impl TotpRepo for TotpRepoPostgres {
+    async fn get(&self, user_id: i64) -> Option<TotpSecret> {
+        let query = sqlx::query_as!(
+            TotpSecret,
+            "SELECT user_id, secret, enabled_at, last_step FROM totp_secrets WHERE user_id = $1",
+            user_id
+        );
+        query.fetch_optional(&self.pool).await.unwrap()
+    }
+    async fn set_pending(&self, user_id: i64, secret: &str) -> bool {
+        let query = sqlx::query!(
+            "INSERT INTO totp_secrets (user_id, secret) VALUES ($1, $2)
+             ON CONFLICT (user_id) DO UPDATE SET secret = $2 WHERE totp_secrets.enabled_at IS NULL",
+            user_id,
+            secret
+        );
+        query.execute(&self.pool).await.unwrap().rows_affected() == 1
+    }
+    async fn enable(&self, user_id: i64, step: i64, now: PrimitiveDateTime, recovery_code_hashes: &[String]) -> bool {
+        let mut tx = self.pool.begin().await.unwrap();
+        let query = sqlx::query!(
+            "UPDATE totp_secrets SET enabled_at = $2, last_step = $3 WHERE user_id = $1 AND enabled_at IS NULL",
+            user_id,
+            now,
+            step
+        );
+        if query.execute(&mut *tx).await.unwrap().rows_affected() == 0 {
+            return false;
+        }
+        let query = sqlx::query!("DELETE FROM recovery_codes WHERE user_id = $1", user_id);
+        query.execute(&mut *tx).await.unwrap();
+        let query = sqlx::query!(
+            "INSERT INTO recovery_codes (user_id, code_hash) SELECT $1, * FROM UNNEST($2::TEXT[])",
+            user_id,
+            recovery_code_hashes
+        );
+        query.execute(&mut *tx).await.unwrap();
+        tx.commit().await.unwrap();
+        true
+    }
+    async fn use_step(&self, user_id: i64, step: i64) -> bool {
+        let query = sqlx::query!(
+            "UPDATE totp_secrets SET last_step = $2
+             WHERE user_id = $1 AND enabled_at IS NOT NULL AND (last_step IS NULL OR last_step < $2)",
+            user_id,
+            step
+        );
+        query.execute(&self.pool).await.unwrap().rows_affected() == 1
+    }
+    async fn use_recovery_code(&self, user_id: i64, code_hash: &str, now: PrimitiveDateTime) -> bool {
+        let query = sqlx::query!(
+            "UPDATE recovery_codes SET used_at = $3 WHERE user_id = $1 AND code_hash = $2 AND used_at IS NULL",
+            user_id,
+            code_hash,
+            now
+        );
+        query.execute(&self.pool).await.unwrap().rows_affected() > 0
+    }
+    async fn disable(&self, user_id: i64) -> bool {
+        let mut tx = self.pool.begin().await.unwrap();
+        let query = sqlx::query!("DELETE FROM recovery_codes WHERE user_id = $1", user_id);
+        query.execute(&mut *tx).await.unwrap();
+        let query = sqlx::query!("DELETE FROM totp_secrets WHERE user_id = $1", user_id);
+        let deleted = query.execute(&mut *tx).await.unwrap().rows_affected() == 1;
+        tx.commit().await.unwrap();
+        deleted
+    }
+}
+
+///
+/// An in-memory implementation of `TotpRepo`, for tests.
+///
+#[derive(Clone, Default)]
+pub struct TotpRepoInMemory {
+    secrets: Arc<Mutex<HashMap<i64, TotpSecret>>>,
+    /// The hashes of the unused recovery codes, by user.
+    recovery_codes: Arc<Mutex<HashMap<i64, Vec<String>>>>,
+}
+
+#[async_trait]
+impl TotpRepo for TotpRepoInMemory {
+    async fn get(&self, user_id: i64) -> Option<TotpSecret> {
+        self.secrets.lock().await.get(&user_id).cloned()
+    }
+    async fn set_pending(&self, user_id: i64, secret: &str) -> bool {
+        let mut secrets = self.secrets.lock().await;
+        if secrets.get(&user_id).is_some_and(|secret| secret.enabled_at.is_some()) {
+            return false;
+        }
+        let pending = TotpSecret { user_id, secret: secret.to_string(), enabled_at: None, last_step: None };
+        secrets.insert(user_id, pending);
+        true
+    }
+    async fn enable(&self, user_id: i64, step: i64, now: PrimitiveDateTime, recovery_code_hashes: &[String]) -> bool {
+        let mut secrets = self.secrets.lock().await;
+        match secrets.get_mut(&user_id).filter(|secret| secret.enabled_at.is_none()) {
+            Some(secret) => {
+                secret.enabled_at = Some(now);
+                secret.last_step = Some(step);
+                self.recovery_codes.lock().await.insert(user_id, recovery_code_hashes.to_vec());
+                true
+            }
+            None => false,
+        }
+    }
+    async fn use_step(&self, user_id: i64, step: i64) -> bool {
+        let mut secrets = self.secrets.lock().await;
+        match secrets.get_mut(&user_id).filter(|secret| secret.enabled_at.is_some()) {
+            Some(secret) if secret.last_step.is_none_or(|last| last < step) => {
+                secret.last_step = Some(step);
+                true
+            }
+            _ => false,
+        }
+    }
+    async fn use_recovery_code(&self, user_id: i64, code_hash: &str, _now: PrimitiveDateTime) -> bool {
+        let mut recovery_codes = self.recovery_codes.lock().await;
+        let Some(hashes) = recovery_codes.get_mut(&user_id) else {
+            return false;
+        };
+        let before = hashes.len();
+        hashes.retain(|hash| hash != code_hash);
+        hashes.len() < before
+    }
+    async fn disable(&self, user_id: i64) -> bool {
+        self.recovery_codes.lock().await.remove(&user_id);
+        self.secrets.lock().await.remove(&user_id).is_some()
+    }
+}
+
+///
+/// What an authenticator app needs: the secret, to type in, or the URI, to
+/// scan as a QR code.
+///
+#[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct Enrollment {
+    pub secret: String,
+    pub otpauth_uri: String,
+}
+
+#[derive(Clone)]
+pub struct TwoFactorState<T: TotpRepo> {
+    pub repo: T,
+    pub clock: SharedClock,
+}
+
+impl<T: TotpRepo> TwoFactorState<T> {
+    pub async fn is_enabled(&self, user_id: i64) -> bool {
+        self.repo.get(user_id).await.is_some_and(|secret| secret.enabled_at.is_some())
+    }
+
+    ///
+    /// Draws a pending secret for the user, named `account` in their app.
+    /// Returns `None` if two-factor is already enabled.
+    ///
+    pub async fn enroll(&self, user_id: i64, account: &str) -> Option<Enrollment> {
+        let mut bytes = [0u8; 20];
+        rand::thread_rng().fill_bytes(&mut bytes);
+        let secret = base32_encode(&bytes);
+        if !self.repo.set_pending(user_id, &secret).await {
+            return None;
+        }
+        let otpauth_uri = format!(
+            "otpauth://totp/{issuer}:{account}?secret={secret}&issuer={issuer}&algorithm=SHA1&digits={DIGITS}&period={STEP_SECONDS}",
+            issuer = percent_encode(ISSUER),
+            account = percent_encode(account),
+        );
+        Some(Enrollment { secret, otpauth_uri })
+    }
+
+    ///
+    /// Enables the pending secret if `code` is one of its current codes,
+    /// returning the recovery codes, which are not kept.
+    ///
+    pub async fn confirm(&self, user_id: i64, code: &str) -> Option<Vec<String>> {
+        let secret = self.repo.get(user_id).await.filter(|secret| secret.enabled_at.is_none())?;
+        let step = matching_step(&secret.secret, code, self.clock.now().unix_timestamp())?;
+
+        let codes: Vec<String> = (0..RECOVERY_CODES).map(|_| recovery_code()).collect();
+        let hashes: Vec<String> = codes.iter().map(|code| hash_token(&normalize(code))).collect();
+        let now = self.clock.now_utc_primitive();
+        self.repo.enable(user_id, step, now, &hashes).await.then_some(codes)
+    }
+
+    ///
+    /// Checks the second factor of an enabled user: a current code that was
+    /// not used yet, or an unused recovery code, which is then used up.
+    ///
+    pub async fn verify(&self, user_id: i64, code: &str) -> bool {
+        let Some(secret) = self.repo.get(user_id).await.filter(|secret| secret.enabled_at.is_some()) else {
+            return false;
+        };
+        match matching_step(&secret.secret, code, self.clock.now().unix_timestamp()) {
+            Some(step) => self.repo.use_step(user_id, step).await,
+            None => {
+                let now = self.clock.now_utc_primitive();
+                self.repo.use_recovery_code(user_id, &hash_token(&normalize(code)), now).await
+            }
+        }
+    }
+
+    ///
+    /// Turns two-factor off, which takes a code, so that a stolen access
+    /// token cannot.
+    ///
+    pub async fn disable(&self, user_id: i64, code: &str) -> bool {
+        self.verify(user_id, code).await && self.repo.disable(user_id).await
+    }
+}
+
+///
+/// The claims of a login waiting for its second factor. They have no `sub`,
+/// so the token is no access token.
+///
+#[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct TwoFactorChallenge {
+    pub uid: i64,
+    pub exp: i64,
+    pub remember_me: bool,
+}
+
+pub fn issue_challenge(keys: &JwtKeys, user_id: i64, remember_me: bool) -> String {
+    let exp = (keys.now() + CHALLENGE_TTL).unix_timestamp();
+    sign(keys, &TwoFactorChallenge { uid: user_id, exp, remember_me })
+}
+
+pub fn verify_challenge(keys: &JwtKeys, token: &str) -> Option<TwoFactorChallenge> {
+    verify::<TwoFactorChallenge>(keys, token).filter(|challenge| challenge.exp > keys.now().unix_timestamp())
+}
+
+///
+/// Turns admins whose session did not start with a second factor away, with
+/// `403 Forbidden`, as well as their personal access tokens that such a
+/// session created. Other requests, including those without a valid access
+/// token, go on, for the routes to authenticate as usual.
+///
+/// Tokens only say whether a session used a second factor, not whether its
+/// user is an admin, which can change, so this looks the user up.
+///
+pub async fn require_two_factor_for_admins<U: UserRepo>(
+    State(UserState { repo }): State<UserState<U>>,
+    State(keys): State<JwtKeys>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let session = match request.extensions().get::<PatGrant>() {
+        Some(grant) => Some((grant.user_id, grant.mfa)),
+        None => request
+            .headers()
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .and_then(|token| decode_access_token(&keys, token).ok())
+            .map(|claims| (claims.sub, claims.mfa)),
+    };
+
+    if let Some((user_id, _)) = session.filter(|(_, mfa)| !mfa) {
+        if repo.get_user(user_id).await.is_some_and(|user| user.is_admin) {
+            return Problem::new(StatusCode::FORBIDDEN)
+                .detail("Admins must log in with two-factor authentication")
+                .into_response();
+        }
+    }
+    next.run(request).await
+}
+
+///
+/// The code of a base32 secret at a Unix time, as an app would show it.
+///
+pub fn code_at(secret: &str, unix_time: i64) -> Option<String> {
+    let key = base32_decode(secret)?;
+    let code = hotp(&key, (unix_time / STEP_SECONDS) as u64, DIGITS);
+    Some(format!("{:0width$}", code, width = DIGITS as usize))
+}
+
+/// The step of the code, among the current step and its neighbours.
+fn matching_step(secret: &str, code: &str, unix_time: i64) -> Option<i64> {
+    let code = code.trim();
+    let step = unix_time / STEP_SECONDS;
+    (step - 1..=step + 1).find(|&step| code_at(secret, step * STEP_SECONDS).as_deref() == Some(code))
+}
+
+///
+/// The HMAC-based one-time password of RFC 4226, of which TOTP is the one
+/// whose counter is the time step.
+///
+fn hotp(key: &[u8], counter: u64, digits: u32) -> u32 {
+    let mut mac = Hmac::<Sha1>::new_from_slice(key).unwrap();
+    mac.update(&counter.to_be_bytes());
+    let hash = mac.finalize().into_bytes();
+    let offset = (hash[hash.len() - 1] & 0x0f) as usize;
+    let code = u32::from_be_bytes([hash[offset] & 0x7f, hash[offset + 1], hash[offset + 2], hash[offset + 3]]);
+    code % 10u32.pow(digits)
+}
+
+/// `xxxxx-xxxxx`, in lowercase base32.
+fn recovery_code() -> String {
+    let mut bytes = [0u8; 7];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    let code = base32_encode(&bytes).to_lowercase();
+    format!("{}-{}", &code[..5], &code[5..10])
+}
+
+/// Recovery codes as typed: any case, with or without the dash.
+fn normalize(code: &str) -> String {
+    code.chars().filter(char::is_ascii_alphanumeric).collect::<String>().to_lowercase()
+}
+
+const BASE32: &[u8; 32] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+
+/// RFC 4648 base32, without padding.
+fn base32_encode(bytes: &[u8]) -> String {
+    let mut out = String::new();
+    let (mut buffer, mut bits) = (0u32, 0);
+    for &byte in bytes {
+        buffer = (buffer << 8) | byte as u32;
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            out.push(BASE32[(buffer >> bits) as usize & 31] as char);
+        }
+    }
+    if bits > 0 {
+        out.push(BASE32[(buffer << (5 - bits)) as usize & 31] as char);
+    }
+    out
+}
+
+fn base32_decode(s: &str) -> Option<Vec<u8>> {
+    let mut out = Vec::new();
+    let (mut buffer, mut bits) = (0u32, 0);
+    for c in s.trim_end_matches('=').bytes() {
+        let value = BASE32.iter().position(|&b| b == c.to_ascii_uppercase())? as u32;
+        buffer = (buffer << 5) | value;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((buffer >> bits) as u8);
+        }
+    }
+    Some(out)
+}
+
+/// Escapes all but the unreserved characters of RFC 3986.
+fn percent_encode(s: &str) -> String {
+    s.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => (b as char).to_string(),
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+#[tokio::test]
+async fn codes_are_checked_once_within_the_window() {
+    use time::macros::datetime;
+
+    use crate::clock::{Clock, FakeClock};
+
+    // The SHA-1 test vectors of RFC 6238, in 8 digits.
+    let key = b"12345678901234567890";
+    assert_eq!(hotp(key, 59 / 30, 8), 94287082);
+    assert_eq!(hotp(key, 1111111109 / 30, 8), 7081804);
+    assert_eq!(hotp(key, 20000000000 / 30, 8), 65353130);
+    assert_eq!(base32_decode(&base32_encode(key)).unwrap(), key);
+    assert_eq!(base32_encode(key), "GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ");
+
+    let clock = FakeClock::new(datetime!(2026-10-16 12:00 UTC));
+    let state = TwoFactorState { repo: TotpRepoInMemory::default(), clock: Arc::new(clock.clone()) };
+    let code_at = |secret: &str, offset: i64| code_at(secret, clock.now().unix_timestamp() + offset).unwrap();
+
+    let enrollment = state.enroll(42, "ada@example.test").await.unwrap();
+    assert!(enrollment.otpauth_uri.starts_with("otpauth://totp/Todos:ada%40example.test?secret="));
+    assert!(!state.is_enabled(42).await);
+    assert!(!state.verify(42, &code_at(&enrollment.secret, 0)).await);
+
+    let recovery_codes = state.confirm(42, &code_at(&enrollment.secret, 0)).await.unwrap();
+    assert_eq!(recovery_codes.len(), RECOVERY_CODES);
+    assert!(state.is_enabled(42).await);
+    assert!(state.enroll(42, "ada@example.test").await.is_none());
+
+    // The code that confirmed is used, the next one is accepted early, once.
+    assert!(!state.verify(42, &code_at(&enrollment.secret, 0)).await);
+    assert!(state.verify(42, &code_at(&enrollment.secret, STEP_SECONDS)).await);
+    assert!(!state.verify(42, &code_at(&enrollment.secret, STEP_SECONDS)).await);
+    clock.advance(Duration::minutes(5));
+    assert!(!state.verify(42, &code_at(&enrollment.secret, -3 * STEP_SECONDS)).await);
+    assert!(state.verify(42, &code_at(&enrollment.secret, -STEP_SECONDS)).await);
+
+    // Recovery codes work once, however they are typed.
+    assert!(state.verify(42, &recovery_codes[0].to_uppercase().replace('-', "")).await);
+    assert!(!state.verify(42, &recovery_codes[0]).await);
+    assert!(state.disable(42, &recovery_codes[1]).await);
+    assert!(!state.is_enabled(42).await);
+}
+
+#[tokio::test]
+async fn admins_need_a_second_factor() {
+    /// for ServiceExt::oneshot
+    use tower::util::ServiceExt;
+    use axum::{body::Body, routing::get, Router};
+    use sqlx::postgres::PgPoolOptions;
+
+    use crate::auth::{AuthState, RefreshTokenRepoInMemory};
+    use crate::users::{create_test_user, UserRepoPostgres};
+
+    let pool = PgPoolOptions::new()
+        .max_connections(1)
+        .connect(&crate::test_db::database_url().await)
+        .await
+        .unwrap();
+    let admin = create_test_user(&pool, true).await;
+    let user = create_test_user(&pool, false).await;
+
+    #[derive(Clone, axum::extract::FromRef)]
+    struct TestState {
+        users: UserState<UserRepoPostgres>,
+        keys: JwtKeys,
+    }
+
+    let auth = AuthState { repo: RefreshTokenRepoInMemory::default(), keys: JwtKeys::from_secret(b"secret") };
+    let users = UserState { repo: UserRepoPostgres::new(pool, crate::pii::test_cipher()) };
+    let state = TestState { users, keys: auth.keys.clone() };
+    let app = Router::new()
+        .route("/todos", get(|| async { "Todos" }))
+        .layer(axum::middleware::from_fn_with_state(state, require_two_factor_for_admins::<UserRepoPostgres>));
+    let get = |token: String| {
+        let request = Request::get("/todos").header(header::AUTHORIZATION, format!("Bearer {}", token));
+        app.clone().oneshot(request.body(Body::empty()).unwrap())
+    };
+
+    let response = get(auth.issue_tokens(admin).await.access_token).await.unwrap();
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    let response = get(auth.issue_two_factor_tokens(admin).await.access_token).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let response = get(auth.issue_tokens(user).await.access_token).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+}
@@ -0,0 +1,272 @@
+#![allow(dead_code)]
+
+//!
+//! UNIX SOCKETS
+//! ------------
+//!
+//! Behind a reverse proxy on the same machine, such as nginx, the app does not
+//! need a TCP port at all: it can listen on a Unix domain socket, a file that
+//! the proxy connects to instead of an address.
+//!
+//! ```nginx
+//! upstream rust_web {
+//!     server unix:/run/rust-web/rust-web.sock;
+//! }
+//! ```
+//!
+//! There is no port to collide with, no loopback traffic to firewall, and who
+//! may connect is decided by the permissions of the file: with the default
+//! mode, `660`, only the user the app runs as and its group, which the proxy
+//! is then added to.
+//!
+//! Set `BIND_SOCKET` to the path of the socket, and `BIND_SOCKET_MODE` to its
+//! permissions, in octal. `BIND_ADDR` is ignored then; the admin router stays
+//! on TCP.
+//!
+//! The socket file outlives the process that bound it, unless that process
+//! removes it, so `UnixSocket` removes it when dropped. A server that was
+//! killed leaves a stale socket behind, which `UnixSocket::bind` removes,
+//! unless another server is still accepting connections on it.
+//!
+
+use std::{
+    convert::Infallible,
+    fmt,
+    fs::Permissions,
+    future::Future,
+    io,
+    os::unix::fs::{FileTypeExt, PermissionsExt},
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::Duration,
+};
+
+use axum::{http::Request, response::Response, Router};
+use hyper::body::Incoming;
+use hyper_util::{
+    rt::{TokioExecutor, TokioIo},
+    server::conn::auto,
+    service::TowerToHyperService,
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::UnixListener,
+    sync::watch,
+};
+use tower::Service;
+
+///
+/// The permissions of a socket file, read and written in octal, like
+/// `chmod`'s: `660`.
+///
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SocketMode(pub u32);
+
+impl Default for SocketMode {
+    fn default() -> Self {
+        SocketMode(0o660)
+    }
+}
+
+impl FromStr for SocketMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match u32::from_str_radix(s, 8) {
+            Ok(mode) if mode <= 0o777 => Ok(SocketMode(mode)),
+            _ => Err(format!("Invalid socket mode: {}", s)),
+        }
+    }
+}
+
+impl fmt::Display for SocketMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:03o}", self.0)
+    }
+}
+
+impl serde::Serialize for SocketMode {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+///
+/// A listener bound to a socket file, which it removes when dropped, unless
+/// it was bound by systemd (see `systemd.rs`).
+///
+pub struct UnixSocket {
+    listener: UnixListener,
+    path: PathBuf,
+    remove_on_drop: bool,
+}
+
+impl UnixSocket {
+    ///
+    /// Binds a socket at `path`, with the permissions `mode`. A socket that is
+    /// already there is replaced if nobody accepts connections on it anymore.
+    /// Anything else at `path` is left alone, and is an error.
+    ///
+    pub fn bind(path: &Path, mode: SocketMode) -> io::Result<UnixSocket> {
+        match std::fs::symlink_metadata(path) {
+            Ok(metadata) if metadata.file_type().is_socket() => {
+                if std::os::unix::net::UnixStream::connect(path).is_ok() {
+                    return Err(io::Error::new(
+                        io::ErrorKind::AddrInUse,
+                        format!("Another server is listening on {}", path.display()),
+                    ));
+                }
+                std::fs::remove_file(path)?;
+            }
+            Ok(_) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::AlreadyExists,
+                    format!("{} exists, and is not a socket", path.display()),
+                ))
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+
+        let listener = UnixListener::bind(path)?;
+        let socket = UnixSocket { listener, path: path.to_path_buf(), remove_on_drop: true };
+        std::fs::set_permissions(path, Permissions::from_mode(mode.0))?;
+        Ok(socket)
+    }
+
+    ///
+    /// A socket that was bound already, by systemd: its file is systemd's to
+    /// remove, and keeps accepting connections while the app restarts.
+    ///
+    pub fn from_std(listener: std::os::unix::net::UnixListener) -> io::Result<UnixSocket> {
+        let path = listener.local_addr()?.as_pathname().map(Path::to_path_buf).unwrap_or_default();
+        listener.set_nonblocking(true)?;
+        Ok(UnixSocket { listener: UnixListener::from_std(listener)?, path, remove_on_drop: false })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for UnixSocket {
+    fn drop(&mut self) {
+        if self.remove_on_drop {
+            let _ = std::fs::remove_file(&self.path);
+        }
+    }
+}
+
+///
+/// Serves `router` on a Unix socket until `shutdown` completes, then lets the
+/// requests in flight finish, as `axum::serve` does for TCP (which it only
+/// supports in this version of Axum). The socket file is removed as soon as
+/// the server stops accepting connections.
+///
+pub async fn serve_unix(
+    (socket, router): (UnixSocket, Router),
+    shutdown: impl Future<Output = ()> + Send + 'static,
+) -> io::Result<()> {
+    println!("Listening on {}", socket.path().display());
+
+    // Connections wait for `stop` to change, and hold on to `done` until
+    // they are closed, so that `done_tx.closed()` waits for all of them.
+    let (stop_tx, stop_rx) = watch::channel(());
+    let (done_tx, done_rx) = watch::channel(());
+    tokio::pin!(shutdown);
+
+    loop {
+        let stream = tokio::select! {
+            accepted = socket.listener.accept() => match accepted {
+                Ok((stream, _)) => stream,
+                Err(e) => {
+                    // Out of file descriptors, usually: wait for some to close.
+                    eprintln!("Failed to accept a connection: {}", e);
+                    tokio::time::sleep(Duration::from_secs(1)).await;
+                    continue;
+                }
+            },
+            _ = &mut shutdown => break,
+        };
+
+        let (router, stop, done) = (router.clone(), stop_rx.clone(), done_rx.clone());
+        tokio::spawn(async move {
+            serve_connection(stream, router, stop).await;
+            drop(done);
+        });
+    }
+
+    drop(socket);
+    drop(stop_tx);
+    drop(done_rx);
+    done_tx.closed().await;
+    Ok(())
+}
+
+///
+/// Serves `service` on a single connection, with HTTP/1.1 or HTTP/2, until
+/// the client closes it or `stop` changes. Then the requests in flight are
+/// answered, and the connection is closed.
+///
+pub async fn serve_connection<I, S>(io: I, service: S, mut stop: watch::Receiver<()>)
+where
+    I: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    S: Service<Request<Incoming>, Response = Response, Error = Infallible> + Clone + Send + 'static,
+    S::Future: Send + 'static,
+{
+    let builder = auto::Builder::new(TokioExecutor::new());
+    let connection = builder.serve_connection_with_upgrades(TokioIo::new(io), TowerToHyperService::new(service));
+    tokio::pin!(connection);
+    tokio::select! {
+        _ = connection.as_mut() => {}
+        _ = stop.changed() => {
+            connection.as_mut().graceful_shutdown();
+            let _ = connection.as_mut().await;
+        }
+    }
+}
+
+#[tokio::test]
+async fn requests_are_served_over_a_unix_socket() {
+    use axum::{body::Body, http::StatusCode, routing::get};
+    // for Body::collect
+    use http_body_util::BodyExt;
+    use tokio::{net::UnixStream, sync::oneshot};
+
+    let dir = std::env::temp_dir().join(format!("rust-web-{}", uuid::Uuid::new_v4()));
+    std::fs::create_dir(&dir).unwrap();
+    let path = dir.join("app.sock");
+
+    // A server that was killed leaves its socket behind.
+    drop(std::os::unix::net::UnixListener::bind(&path).unwrap());
+    assert!(path.exists());
+
+    let socket = UnixSocket::bind(&path, SocketMode(0o600)).unwrap();
+    assert_eq!(std::fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+
+    let app = Router::new().route("/", get(|| async { "Hello over a socket" }));
+    let (stop, stopped) = oneshot::channel::<()>();
+    let server = tokio::spawn(serve_unix((socket, app), async move {
+        let _ = stopped.await;
+    }));
+
+    // The socket is in use, so a second server must not take it over.
+    let error = UnixSocket::bind(&path, SocketMode::default()).err().unwrap();
+    assert_eq!(error.kind(), io::ErrorKind::AddrInUse);
+
+    let stream = UnixStream::connect(&path).await.unwrap();
+    let (mut sender, connection) = hyper::client::conn::http1::handshake(TokioIo::new(stream)).await.unwrap();
+    tokio::spawn(connection);
+    let request = Request::get("http://localhost/").body(Body::empty()).unwrap();
+    let response = sender.send_request(request).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = response.into_body().collect().await.unwrap().to_bytes();
+    assert_eq!(body, "Hello over a socket");
+
+    stop.send(()).unwrap();
+    drop(sender);
+    server.await.unwrap().unwrap();
+    assert!(!path.exists());
+
+    let _ = std::fs::remove_dir(&dir);
+}
@@ -0,0 +1,147 @@
+#![allow(dead_code)]
+
+//!
+//! UPLOADS
+//! -------
+//!
+//! Reading a file out of a `multipart/form-data` body, and deciding how to
+//! serve it back. The body comes from the client as is, so everything here
+//! has to cope with whatever it sends: missing parts, parts without a name,
+//! names with paths or quotes in them, and bytes that are not what the client
+//! says they are.
+//!
+//! It is kept apart from the attachment routes, without anything from the
+//! rest of the crate, so that the fuzz target in `fuzz/fuzz_targets/multipart.rs`
+//! can run it over arbitrary bodies.
+//!
+
+use axum::{
+    body::Bytes,
+    extract::{multipart::MultipartError, Multipart},
+};
+
+///
+/// Known file signatures, checked against the first bytes of an upload.
+///
+const SIGNATURES: &[(&[u8], &str)] = &[
+    (b"\x89PNG\r\n\x1a\n", "image/png"),
+    (b"\xff\xd8\xff", "image/jpeg"),
+    (b"GIF87a", "image/gif"),
+    (b"GIF89a", "image/gif"),
+    (b"%PDF-", "application/pdf"),
+    (b"PK\x03\x04", "application/zip"),
+];
+
+#[derive(Debug)]
+pub struct Upload {
+    ///
+    /// The name of the file, without any path the client sent with it.
+    /// Never empty.
+    ///
+    pub filename: String,
+    pub content_type: String,
+    pub bytes: Bytes,
+}
+
+///
+/// Why a body was not an upload, in words for the client.
+///
+#[derive(Debug, PartialEq)]
+pub struct InvalidUpload(pub String);
+
+impl From<MultipartError> for InvalidUpload {
+    fn from(error: MultipartError) -> Self {
+        InvalidUpload(error.body_text())
+    }
+}
+
+///
+/// Reads the first part named `file`, skipping any others before it.
+///
+pub async fn read_upload(multipart: &mut Multipart) -> Result<Upload, InvalidUpload> {
+    while let Some(field) = multipart.next_field().await? {
+        if field.name() != Some("file") {
+            continue;
+        }
+        let filename = match field.file_name().map(base_name) {
+            Some(filename) if !filename.is_empty() => filename.to_string(),
+            _ => return Err(InvalidUpload("The file has no name".to_string())),
+        };
+        let declared = field.content_type().map(str::to_string);
+        let bytes = field.bytes().await?;
+        let content_type = sniff_content_type(&bytes, declared.as_deref());
+        return Ok(Upload { filename, content_type, bytes });
+    }
+    Err(InvalidUpload("Missing a part named `file`".to_string()))
+}
+
+///
+/// The content type to serve a file with. A known signature wins over
+/// whatever the client declared, and a client cannot pass off bytes that are
+/// not text as text. Text keeps its declared type if it has one.
+///
+pub fn sniff_content_type(bytes: &[u8], declared: Option<&str>) -> String {
+    if let Some((_, content_type)) = SIGNATURES.iter().find(|(signature, _)| bytes.starts_with(signature)) {
+        return content_type.to_string();
+    }
+    let is_text = !bytes.contains(&0) && std::str::from_utf8(bytes).is_ok();
+    match declared {
+        Some(declared) if is_text && (declared.starts_with("text/") || declared == "application/json") => {
+            declared.to_string()
+        }
+        _ if is_text => "text/plain; charset=utf-8".to_string(),
+        _ => "application/octet-stream".to_string(),
+    }
+}
+
+///
+/// `attachment; filename="..."` with a plain ASCII fallback for old clients,
+/// and the exact name, percent-encoded as UTF-8, in `filename*` (RFC 6266).
+///
+pub fn content_disposition(filename: &str) -> String {
+    let fallback: String = filename
+        .chars()
+        .map(|c| if (c.is_ascii_graphic() || c == ' ') && c != '"' && c != '\\' { c } else { '_' })
+        .collect();
+    let encoded: String = filename
+        .bytes()
+        .map(|b| {
+            if b.is_ascii_alphanumeric() || b"!#$&+-.^_`|~".contains(&b) {
+                (b as char).to_string()
+            } else {
+                format!("%{:02X}", b)
+            }
+        })
+        .collect();
+    format!("attachment; filename=\"{}\"; filename*=UTF-8''{}", fallback, encoded)
+}
+
+///
+/// Browsers send only the name of the file, but other clients may send a
+/// whole path, from either kind of system.
+///
+fn base_name(filename: &str) -> &str {
+    filename.rsplit(['/', '\\']).next().unwrap_or(filename)
+}
+
+#[test]
+fn content_types_are_sniffed_from_the_bytes() {
+    assert_eq!(sniff_content_type(b"\x89PNG\r\n\x1a\n....", Some("text/plain")), "image/png");
+    assert_eq!(sniff_content_type(b"%PDF-1.7", None), "application/pdf");
+    assert_eq!(sniff_content_type(b"a,b\n1,2\n", Some("text/csv")), "text/csv");
+    assert_eq!(sniff_content_type(b"hello", Some("image/png")), "text/plain; charset=utf-8");
+    assert_eq!(sniff_content_type(b"\x00\x01\x02", Some("text/plain")), "application/octet-stream");
+}
+
+#[test]
+fn content_disposition_keeps_the_exact_name() {
+    assert_eq!(
+        content_disposition("report.pdf"),
+        r#"attachment; filename="report.pdf"; filename*=UTF-8''report.pdf"#
+    );
+    assert_eq!(
+        content_disposition("kvittering \"æ\".pdf"),
+        r#"attachment; filename="kvittering ___.pdf"; filename*=UTF-8''kvittering%20%22%C3%A6%22.pdf"#
+    );
+    assert_eq!(base_name(r"C:\Users\ada\notes.txt"), "notes.txt");
+}
@@ -0,0 +1,726 @@
+#![allow(dead_code)]
+
+//!
+//! USERS
+//! -----
+//!
+//! The users graduation project in `context.rs` keeps its users in memory, and
+//! anyone can read or change anyone else's data. This module is its grown-up
+//! counterpart for the todo app: users are stored in Postgres, with a hashed
+//! password, and log in to obtain the token pair issued by the `auth` module.
+//!
+//! Every todo belongs to the user who created it, and users only ever see
+//! their own todos. Admins (`users.is_admin`) are the exception: they can see
+//! and change every todo, which is decided in SQL by `todo_visible_to`.
+//!
+//! Users who enabled two-factor authentication finish logging in with a
+//! code from their authenticator app (see `totp.rs`).
+//!
+
+use argon2::{
+    password_hash::{rand_core::OsRng, PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+    Argon2,
+};
+use axum::{
+    async_trait,
+    extract::{FromRef, Path, State},
+    http::StatusCode,
+    Extension, Json,
+};
+use axum_extra::extract::cookie::{CookieJar, Key, PrivateCookieJar};
+use sqlx::{types::time::OffsetDateTime, Pool, Postgres};
+
+use crate::app::Routes;
+use crate::auth::{remember_refresh_token, AuthError, AuthState, Claims, JwtKeys, RefreshTokenRepo, Session, TokenPair};
+use crate::client_ip::{ClientIp, TrustedProxies};
+use crate::geoip::Geo;
+use crate::pii::PiiCipher;
+use crate::remember_me::{remember_me_cookie, RememberMeRepo, RememberMeState};
+use crate::schema_drift::table;
+use crate::totp::{issue_challenge, verify_challenge, Enrollment, TotpRepo, TwoFactorState};
+
+#[derive(Clone, Debug)]
+pub struct User {
+    pub id: i64,
+    pub name: String,
+    pub email: String,
+    pub password_hash: String,
+    pub is_admin: bool,
+    pub created_at: OffsetDateTime,
+}
+
+table!(User in "users" {
+    id: i64,
+    name: String,
+    email: String,
+    password_hash: String,
+    is_admin: bool,
+    created_at: OffsetDateTime,
+});
+
+impl User {
+    pub fn to_dto(&self) -> UserDTO {
+        UserDTO {
+            id: self.id,
+            name: self.name.clone(),
+            email: self.email.clone(),
+            is_admin: self.is_admin,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, serde::Deserialize, serde::Serialize)]
+pub struct UserDTO {
+    pub id: i64,
+    pub name: String,
+    pub email: String,
+    pub is_admin: bool,
+}
+
+#[async_trait]
+pub trait UserRepo: Send + Sync {
+    async fn get_user(&self, id: i64) -> Option<User>;
+    async fn get_user_by_email(&self, email: &str) -> Option<User>;
+    ///
+    /// Returns the id of the new user, or `None` if the email is taken.
+    ///
+    async fn create_user(&self, name: &str, email: &str, password_hash: &str) -> Option<i64>;
+}
+
+///
+/// Stores emails encrypted, and finds them by their blind index (see
+/// `pii.rs`).
+///
+#[derive(Clone)]
+pub struct UserRepoPostgres {
+    pool: Pool<Postgres>,
+    cipher: PiiCipher,
+}
+
+impl UserRepoPostgres {
+    pub fn new(pool: Pool<Postgres>, cipher: PiiCipher) -> Self {
+        UserRepoPostgres { pool, cipher }
+    }
+
+    fn decrypted(&self, mut user: User) -> User {
+        user.email = self
+            .cipher
+            .decrypt("users.email", &user.email)
+            .unwrap_or_else(|error| panic!("Cannot decrypt the email of user {}: {}", user.id, error));
+        user
+    }
+}
+
+#[async_trait]
+impl UserRepo for UserRepoPostgres {
+    async fn get_user(&self, id: i64) -> Option<User> {
+        let query = sqlx::query_as!(
+            User,
+            "SELECT id, name, email, password_hash, is_admin, created_at FROM users WHERE id = $1",
+            id
+        );
+        query.fetch_optional(&self.pool).await.unwrap().map(|user| self.decrypted(user))
+    }
+    async fn get_user_by_email(&self, email: &str) -> Option<User> {
+        let query = sqlx::query_as!(
+            User,
+            "SELECT id, name, email, password_hash, is_admin, created_at FROM users
+             WHERE email_index = $1 OR (email_index IS NULL AND email = $2)",
+            self.cipher.blind_index("users.email", email),
+            email
+        );
+        query.fetch_optional(&self.pool).await.unwrap().map(|user| self.decrypted(user))
+    }
+    async fn create_user(&self, name: &str, email: &str, password_hash: &str) -> Option<i64> {
+        // Rows from before encryption keep their email in plaintext, which the
+        // unique index on `email_index` cannot see.
+        let query = sqlx::query!(
+            "INSERT INTO users (name, email, email_index, password_hash)
+             SELECT $1, $2, $3, $4
+             WHERE NOT EXISTS (SELECT 1 FROM users WHERE email_index IS NULL AND email = $5)
+             ON CONFLICT (email_index) DO NOTHING RETURNING id",
+            name,
+            self.cipher.encrypt("users.email", email),
+            self.cipher.blind_index("users.email", email),
+            password_hash,
+            email
+        );
+        query.fetch_optional(&self.pool).await.unwrap().map(|row| row.id)
+    }
+}
+
+pub fn hash_password(password: &str) -> String {
+    let salt = SaltString::generate(&mut OsRng);
+    Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .unwrap()
+        .to_string()
+}
+
+pub fn verify_password(password: &str, password_hash: &str) -> bool {
+    PasswordHash::new(password_hash)
+        .map(|hash| Argon2::default().verify_password(password.as_bytes(), &hash).is_ok())
+        .unwrap_or(false)
+}
+
+#[derive(Clone)]
+pub struct UserState<R: UserRepo> {
+    pub repo: R,
+}
+
+pub fn user_routes<S, U, R, M, T>() -> Routes<S>
+where
+    U: UserRepo + Clone + 'static,
+    R: RefreshTokenRepo + Clone + 'static,
+    M: RememberMeRepo + Clone + 'static,
+    T: TotpRepo + Clone + 'static,
+    UserState<U>: FromRef<S>,
+    AuthState<R>: FromRef<S>,
+    RememberMeState<M>: FromRef<S>,
+    TwoFactorState<T>: FromRef<S>,
+    JwtKeys: FromRef<S>,
+    Key: FromRef<S>,
+    TrustedProxies: FromRef<S>,
+    S: Clone + Send + Sync + 'static,
+{
+    Routes::new()
+        .post("/users", register::<U, R>)
+        .post("/users/login", login::<U, R, M, T>)
+        .post("/users/login/two-factor", login_two_factor::<R, M, T>)
+        .get("/users/me", me::<U>)
+        .get("/users/me/sessions", sessions::<R>)
+        .delete("/users/me/sessions/:id", revoke_session::<R>)
+        .post("/users/me/two-factor", enroll_two_factor::<U, T>)
+        .post("/users/me/two-factor/confirm", confirm_two_factor::<T>)
+        .delete("/users/me/two-factor", disable_two_factor::<T>)
+}
+
+#[derive(Debug, serde::Deserialize, serde::Serialize)]
+struct Register {
+    name: String,
+    email: String,
+    password: String,
+}
+
+#[derive(Debug, serde::Deserialize, serde::Serialize)]
+struct Registered {
+    user: UserDTO,
+    tokens: TokenPair,
+}
+
+async fn register<U: UserRepo, R: RefreshTokenRepo>(
+    State(UserState { repo }): State<UserState<U>>,
+    State(auth): State<AuthState<R>>,
+    Json(Register { name, email, password }): Json<Register>,
+) -> Result<(StatusCode, Json<Registered>), (StatusCode, String)> {
+    // Argon2 is slow on purpose, so it runs on the blocking pool rather than
+    // holding up the other requests of this worker (see `runtime_metrics`).
+    let password_hash = tokio::task::spawn_blocking(move || hash_password(&password)).await.unwrap();
+    let id = repo
+        .create_user(&name, &email, &password_hash)
+        .await
+        .ok_or((StatusCode::CONFLICT, format!("{} is already registered", email)))?;
+
+    let user = repo.get_user(id).await.unwrap();
+    let tokens = auth.issue_tokens(id).await;
+
+    Ok((StatusCode::CREATED, Json(Registered { user: user.to_dto(), tokens })))
+}
+
+#[derive(Debug, serde::Deserialize, serde::Serialize)]
+struct Login {
+    email: String,
+    password: String,
+    /// Also keep the refresh token in a cookie, for browsers, and a
+    /// remember-me token, which starts new sessions (see `remember_me.rs`).
+    #[serde(default)]
+    remember_me: bool,
+}
+
+///
+/// The tokens, or, for users with two-factor enabled, the token to send
+/// along with their code to `/users/login/two-factor`.
+///
+#[derive(Debug, serde::Deserialize, serde::Serialize)]
+#[serde(untagged)]
+enum LoginResponse {
+    Tokens(TokenPair),
+    TwoFactorRequired { two_factor_token: String },
+}
+
+///
+/// Logins, and failed attempts, are logged under the `audit` target, with the
+/// address of the client past any trusted proxies (see `client_ip.rs`), and
+/// its country and ASN, if known (see `geoip.rs`).
+///
+#[allow(clippy::too_many_arguments)]
+async fn login<U: UserRepo, R: RefreshTokenRepo, M: RememberMeRepo, T: TotpRepo>(
+    State(UserState { repo }): State<UserState<U>>,
+    State(auth): State<AuthState<R>>,
+    State(remember): State<RememberMeState<M>>,
+    State(two_factor): State<TwoFactorState<T>>,
+    client_ip: Option<ClientIp>,
+    geo: Option<Extension<Geo>>,
+    jar: PrivateCookieJar,
+    plain_jar: CookieJar,
+    Json(Login { email, password, remember_me }): Json<Login>,
+) -> Result<(PrivateCookieJar, CookieJar, Json<LoginResponse>), AuthError> {
+    let client_ip = client_ip.map_or_else(|| "unknown".to_string(), |ip| ip.to_string());
+    let Extension(geo) = geo.unwrap_or_default();
+    let (country, asn) = (geo.country.as_deref(), geo.asn);
+    let Some(user) = repo.get_user_by_email(&email).await else {
+        tracing::warn!(target: "audit", email, client_ip, country, asn, "Login failed: unknown email");
+        return Err(AuthError::InvalidCredentials);
+    };
+    let password_hash = user.password_hash.clone();
+    let valid = tokio::task::spawn_blocking(move || verify_password(&password, &password_hash)).await.unwrap();
+    if !valid {
+        tracing::warn!(target: "audit", user_id = user.id, client_ip, country, asn, "Login failed: wrong password");
+        return Err(AuthError::InvalidCredentials);
+    }
+    if two_factor.is_enabled(user.id).await {
+        tracing::info!(target: "audit", user_id = user.id, client_ip, country, asn, "Login awaiting second factor");
+        let two_factor_token = issue_challenge(&auth.keys, user.id, remember_me);
+        return Ok((jar, plain_jar, Json(LoginResponse::TwoFactorRequired { two_factor_token })));
+    }
+    tracing::info!(target: "audit", user_id = user.id, client_ip, country, asn, "Login");
+
+    let tokens = auth.issue_tokens(user.id).await;
+    let (jar, plain_jar) = remember_login(&remember, jar, plain_jar, user.id, &tokens, remember_me).await;
+    Ok((jar, plain_jar, Json(LoginResponse::Tokens(tokens))))
+}
+
+async fn remember_login<M: RememberMeRepo>(
+    remember: &RememberMeState<M>,
+    jar: PrivateCookieJar,
+    plain_jar: CookieJar,
+    user_id: i64,
+    tokens: &TokenPair,
+    remember_me: bool,
+) -> (PrivateCookieJar, CookieJar) {
+    if !remember_me {
+        return (jar, plain_jar);
+    }
+    let jar = remember_refresh_token(jar, &tokens.refresh_token);
+    let plain_jar = remember_me_cookie(plain_jar, &remember.remember(user_id).await);
+    (jar, plain_jar)
+}
+
+#[derive(Debug, serde::Deserialize, serde::Serialize)]
+struct TwoFactorLogin {
+    two_factor_token: String,
+    /// A code of the authenticator app, or a recovery code.
+    code: String,
+}
+
+///
+/// The second step of the login of users with two-factor enabled, which
+/// issues tokens whose `mfa` claim is set.
+///
+#[allow(clippy::too_many_arguments)]
+async fn login_two_factor<R: RefreshTokenRepo, M: RememberMeRepo, T: TotpRepo>(
+    State(auth): State<AuthState<R>>,
+    State(remember): State<RememberMeState<M>>,
+    State(two_factor): State<TwoFactorState<T>>,
+    client_ip: Option<ClientIp>,
+    jar: PrivateCookieJar,
+    plain_jar: CookieJar,
+    Json(TwoFactorLogin { two_factor_token, code }): Json<TwoFactorLogin>,
+) -> Result<(PrivateCookieJar, CookieJar, Json<TokenPair>), AuthError> {
+    let client_ip = client_ip.map_or_else(|| "unknown".to_string(), |ip| ip.to_string());
+    let challenge = verify_challenge(&auth.keys, &two_factor_token).ok_or(AuthError::InvalidToken)?;
+    if !two_factor.verify(challenge.uid, &code).await {
+        tracing::warn!(target: "audit", user_id = challenge.uid, client_ip, "Login failed: wrong second factor");
+        return Err(AuthError::InvalidCode);
+    }
+    tracing::info!(target: "audit", user_id = challenge.uid, client_ip, "Login");
+
+    let tokens = auth.issue_two_factor_tokens(challenge.uid).await;
+    let (jar, plain_jar) = remember_login(&remember, jar, plain_jar, challenge.uid, &tokens, challenge.remember_me).await;
+    Ok((jar, plain_jar, Json(tokens)))
+}
+
+async fn me<U: UserRepo>(
+    claims: Claims,
+    State(UserState { repo }): State<UserState<U>>,
+) -> Result<Json<UserDTO>, StatusCode> {
+    let user = repo.get_user(claims.sub).await.ok_or(StatusCode::NOT_FOUND)?;
+    Ok(Json(user.to_dto()))
+}
+
+async fn sessions<R: RefreshTokenRepo>(claims: Claims, State(auth): State<AuthState<R>>) -> Json<Vec<Session>> {
+    Json(auth.sessions(claims.sub).await)
+}
+
+///
+/// Ends a session, such as the one of a lost phone: its refresh token stops
+/// working, and its access token expires soon after.
+///
+async fn revoke_session<R: RefreshTokenRepo>(
+    claims: Claims,
+    State(auth): State<AuthState<R>>,
+    Path(id): Path<String>,
+) -> StatusCode {
+    match auth.revoke_session(claims.sub, &id).await {
+        true => StatusCode::NO_CONTENT,
+        false => StatusCode::NOT_FOUND,
+    }
+}
+
+///
+/// Starts enrolling the user in two-factor authentication, answering with
+/// the secret and the URI of its QR code.
+///
+async fn enroll_two_factor<U: UserRepo, T: TotpRepo>(
+    claims: Claims,
+    State(UserState { repo }): State<UserState<U>>,
+    State(two_factor): State<TwoFactorState<T>>,
+) -> Result<Json<Enrollment>, (StatusCode, String)> {
+    let user = repo.get_user(claims.sub).await.ok_or((StatusCode::NOT_FOUND, "No such user".to_string()))?;
+    let enrollment = two_factor
+        .enroll(user.id, &user.email)
+        .await
+        .ok_or((StatusCode::CONFLICT, "Two-factor authentication is already enabled".to_string()))?;
+    Ok(Json(enrollment))
+}
+
+#[derive(Debug, serde::Deserialize, serde::Serialize)]
+struct TwoFactorCode {
+    code: String,
+}
+
+#[derive(Debug, serde::Deserialize, serde::Serialize)]
+struct RecoveryCodes {
+    recovery_codes: Vec<String>,
+}
+
+///
+/// Enables two-factor authentication once a first code shows that the app
+/// has the secret. The recovery codes are only ever shown here.
+///
+async fn confirm_two_factor<T: TotpRepo>(
+    claims: Claims,
+    State(two_factor): State<TwoFactorState<T>>,
+    Json(TwoFactorCode { code }): Json<TwoFactorCode>,
+) -> Result<Json<RecoveryCodes>, (StatusCode, String)> {
+    let recovery_codes = two_factor
+        .confirm(claims.sub, &code)
+        .await
+        .ok_or((StatusCode::UNPROCESSABLE_ENTITY, "Invalid two-factor code".to_string()))?;
+    tracing::info!(target: "audit", user_id = claims.sub, "Two-factor authentication enabled");
+    Ok(Json(RecoveryCodes { recovery_codes }))
+}
+
+async fn disable_two_factor<T: TotpRepo>(
+    claims: Claims,
+    State(two_factor): State<TwoFactorState<T>>,
+    Json(TwoFactorCode { code }): Json<TwoFactorCode>,
+) -> StatusCode {
+    if !two_factor.disable(claims.sub, &code).await {
+        return StatusCode::UNPROCESSABLE_ENTITY;
+    }
+    tracing::info!(target: "audit", user_id = claims.sub, "Two-factor authentication disabled");
+    StatusCode::NO_CONTENT
+}
+
+///
+/// Creates a user with a unique email and no usable password, for tests that
+/// need a todo owner.
+///
+pub async fn create_test_user(pool: &Pool<Postgres>, is_admin: bool) -> i64 {
+    let query = sqlx::query!(
+        "INSERT INTO users (name, email, password_hash, is_admin) VALUES ($1, $2, $3, $4) RETURNING id",
+        "Test",
+        format!("{}@example.test", rand::random::<u64>()),
+        "",
+        is_admin
+    );
+    query.fetch_one(pool).await.unwrap().id
+}
+
+///
+/// Snapshot settings that redact whatever differs from one run to the next:
+/// ids, timestamps, tokens and the random emails of test users. Redacting
+/// only values that are set keeps the difference between a `null` and a
+/// present value in the snapshot.
+///
+#[cfg(test)]
+pub fn redacted_snapshots() -> insta::Settings {
+    use insta::internals::Content;
+
+    fn redact(placeholder: &'static str) -> impl Fn(Content, insta::internals::ContentPath<'_>) -> Content {
+        move |value, _| match value.resolve_inner() {
+            Content::None | Content::Unit => value,
+            _ => Content::from(placeholder),
+        }
+    }
+
+    let mut settings = insta::Settings::clone_current();
+    for key in ["id", "owner_id", "parent_id", "list_id", "todo_id"] {
+        settings.add_dynamic_redaction(&format!(".**.{}", key), redact("[id]"));
+    }
+    for key in ["created_at", "completed_at", "at"] {
+        settings.add_dynamic_redaction(&format!(".**.{}", key), redact("[timestamp]"));
+    }
+    for key in ["access_token", "refresh_token", "next_cursor"] {
+        settings.add_dynamic_redaction(&format!(".**.{}", key), redact("[token]"));
+    }
+    settings.add_dynamic_redaction(".**.email", redact("[email]"));
+    settings.add_dynamic_redaction(".**.day", redact("[date]"));
+    settings.add_dynamic_redaction(".**.average_completion_seconds", redact("[seconds]"));
+    // Links embed ids, so only their numeric segments are redacted.
+    settings.add_dynamic_redaction(".**.href", |value, _| match value.as_str() {
+        Some(href) => Content::from(
+            href.split('/')
+                .map(|segment| if segment.parse::<i64>().is_ok() { "[id]" } else { segment })
+                .collect::<Vec<_>>()
+                .join("/"),
+        ),
+        None => value,
+    });
+    settings
+}
+
+#[cfg(test)]
+async fn test_user_app() -> axum::Router {
+    use std::sync::Arc;
+
+    use sqlx::postgres::PgPoolOptions;
+
+    use crate::auth::RefreshTokenRepoInMemory;
+    use crate::clock::SystemClock;
+    use crate::remember_me::RememberMeRepoInMemory;
+    use crate::totp::TotpRepoInMemory;
+
+    #[derive(Clone, FromRef)]
+    struct TestState {
+        users: UserState<UserRepoPostgres>,
+        auth: AuthState<RefreshTokenRepoInMemory>,
+        remember_me: RememberMeState<RememberMeRepoInMemory>,
+        two_factor: TwoFactorState<TotpRepoInMemory>,
+        trusted_proxies: TrustedProxies,
+    }
+
+    impl FromRef<TestState> for JwtKeys {
+        fn from_ref(state: &TestState) -> Self {
+            state.auth.keys.clone()
+        }
+    }
+
+    impl FromRef<TestState> for Key {
+        fn from_ref(state: &TestState) -> Self {
+            state.auth.keys.cookie_key()
+        }
+    }
+
+    let pool = PgPoolOptions::new()
+        .max_connections(1)
+        .connect(&crate::test_db::database_url().await)
+        .await
+        .unwrap();
+
+    user_routes::<_, UserRepoPostgres, RefreshTokenRepoInMemory, RememberMeRepoInMemory, TotpRepoInMemory>()
+        .into_router()
+        .with_state(TestState {
+            users: UserState { repo: UserRepoPostgres::new(pool, crate::pii::test_cipher()) },
+            auth: AuthState {
+                repo: RefreshTokenRepoInMemory::default(),
+                keys: JwtKeys::from_secret(b"secret"),
+            },
+            remember_me: RememberMeState { repo: RememberMeRepoInMemory::default(), clock: Arc::new(SystemClock) },
+            two_factor: TwoFactorState { repo: TotpRepoInMemory::default(), clock: Arc::new(SystemClock) },
+            trusted_proxies: TrustedProxies::default(),
+        })
+}
+
+#[tokio::test]
+async fn register_then_login() {
+    // for Body::collect
+    use http_body_util::BodyExt;
+    /// for ServiceExt::oneshot
+    use tower::util::ServiceExt;
+    use axum::{body::Body, http::{Method, Request}};
+
+    let app = test_user_app().await;
+
+    let post = |uri: &str, body: String| {
+        Request::builder()
+            .method(Method::POST)
+            .uri(uri)
+            .header("Content-Type", "application/json")
+            .body(Body::from(body))
+            .unwrap()
+    };
+
+    let email = format!("{}@example.test", rand::random::<u64>());
+    let register = serde_json::json!({ "name": "Ada", "email": email, "password": "hunter2" });
+
+    let response = app.clone().oneshot(post("/users", register.to_string())).await.unwrap();
+    assert_eq!(response.status(), StatusCode::CREATED);
+
+    let response = app.clone().oneshot(post("/users", register.to_string())).await.unwrap();
+    assert_eq!(response.status(), StatusCode::CONFLICT);
+
+    let wrong = serde_json::json!({ "email": email, "password": "hunter3" });
+    let response = app.clone().oneshot(post("/users/login", wrong.to_string())).await.unwrap();
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+    let right = serde_json::json!({ "email": email, "password": "hunter2" });
+    let response = app.clone().oneshot(post("/users/login", right.to_string())).await.unwrap();
+    assert!(response.headers().get("Set-Cookie").is_none());
+    let body = response.into_body().collect().await.unwrap().to_bytes();
+    let tokens: TokenPair = serde_json::from_slice(&body).unwrap();
+
+    let remembered = serde_json::json!({ "email": email, "password": "hunter2", "remember_me": true });
+    let response = app.clone().oneshot(post("/users/login", remembered.to_string())).await.unwrap();
+    let cookies = crate::cookies::set_cookies(response.headers());
+    assert!(cookies[0].starts_with("refresh_token=") && cookies[0].contains("HttpOnly"));
+    assert!(cookies[1].starts_with("remember_me=") && cookies[1].contains("HttpOnly"));
+
+    let response = app
+        .oneshot(
+            Request::builder()
+                .method(Method::GET)
+                .uri("/users/me")
+                .header("Authorization", format!("Bearer {}", tokens.access_token))
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+    let body = response.into_body().collect().await.unwrap().to_bytes();
+    let user: UserDTO = serde_json::from_slice(&body).unwrap();
+
+    assert_eq!(user.email, email);
+    assert!(!user.is_admin);
+}
+
+#[tokio::test]
+async fn two_factor_login() {
+    // for Body::collect
+    use http_body_util::BodyExt;
+    /// for ServiceExt::oneshot
+    use tower::util::ServiceExt;
+    use axum::{body::Body, http::{Method, Request}};
+    use serde_json::{json, Value};
+
+    use crate::totp::code_at;
+
+    let app = test_user_app().await;
+    let send = |method: Method, uri: &str, token: Option<&str>, body: Value| {
+        let mut request = Request::builder()
+            .method(method)
+            .uri(uri)
+            .header("Content-Type", "application/json");
+        if let Some(token) = token {
+            request = request.header("Authorization", format!("Bearer {}", token));
+        }
+        let request = request.body(Body::from(body.to_string())).unwrap();
+        let app = app.clone();
+        async move {
+            let response = app.oneshot(request).await.unwrap();
+            let status = response.status();
+            let body = response.into_body().collect().await.unwrap().to_bytes();
+            (status, serde_json::from_slice::<Value>(&body).unwrap_or_default())
+        }
+    };
+    let now = || time::OffsetDateTime::now_utc().unix_timestamp();
+
+    let email = format!("{}@example.test", rand::random::<u64>());
+    let register = json!({ "name": "Ada", "email": email, "password": "hunter2" });
+    let (_, registered) = send(Method::POST, "/users", None, register).await;
+    let token = registered["tokens"]["access_token"].as_str().unwrap();
+
+    let (status, enrollment) = send(Method::POST, "/users/me/two-factor", Some(token), json!({})).await;
+    assert_eq!(status, StatusCode::OK);
+    assert!(enrollment["otpauth_uri"].as_str().unwrap().starts_with("otpauth://totp/"));
+    let secret = enrollment["secret"].as_str().unwrap();
+
+    let wrong = json!({ "code": "000000" });
+    let (status, _) = send(Method::POST, "/users/me/two-factor/confirm", Some(token), wrong).await;
+    assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+    let right = json!({ "code": code_at(secret, now()).unwrap() });
+    let (status, confirmed) = send(Method::POST, "/users/me/two-factor/confirm", Some(token), right).await;
+    assert_eq!(status, StatusCode::OK);
+    let recovery_code = confirmed["recovery_codes"][0].as_str().unwrap();
+
+    // The password alone only gets a challenge, which is no access token.
+    let login = json!({ "email": email, "password": "hunter2", "remember_me": true });
+    let (status, challenge) = send(Method::POST, "/users/login", None, login.clone()).await;
+    assert_eq!(status, StatusCode::OK);
+    assert!(challenge.get("access_token").is_none());
+    let two_factor_token = challenge["two_factor_token"].as_str().unwrap();
+    let (status, _) = send(Method::GET, "/users/me", Some(two_factor_token), Value::Null).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+    let wrong = json!({ "two_factor_token": two_factor_token, "code": "000000" });
+    let (status, _) = send(Method::POST, "/users/login/two-factor", None, wrong).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+    // The code that confirmed was used, the next one was not.
+    let code = code_at(secret, now() + 30).unwrap();
+    let right = json!({ "two_factor_token": two_factor_token, "code": code });
+    let (status, tokens) = send(Method::POST, "/users/login/two-factor", None, right.clone()).await;
+    assert_eq!(status, StatusCode::OK);
+    let keys = JwtKeys::from_secret(b"secret");
+    let claims = crate::auth::decode_access_token(&keys, tokens["access_token"].as_str().unwrap()).unwrap();
+    assert!(claims.mfa);
+    let (status, _) = send(Method::POST, "/users/login/two-factor", None, right).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+    let (_, challenge) = send(Method::POST, "/users/login", None, login).await;
+    let recovery = json!({ "two_factor_token": challenge["two_factor_token"], "code": recovery_code });
+    let (status, _) = send(Method::POST, "/users/login/two-factor", None, recovery.clone()).await;
+    assert_eq!(status, StatusCode::OK);
+    let (status, _) = send(Method::POST, "/users/login/two-factor", None, recovery).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+}
+
+///
+/// Pins down the wire format of every user endpoint. Review changes with
+/// `cargo insta review`.
+///
+#[tokio::test]
+async fn user_responses_match_snapshots() {
+    // for Body::collect
+    use http_body_util::BodyExt;
+    /// for ServiceExt::oneshot
+    use tower::util::ServiceExt;
+    use axum::{body::Body, http::{Method, Request}};
+    use insta::assert_json_snapshot;
+    use serde_json::{json, Value};
+
+    let app = test_user_app().await;
+    let _settings = redacted_snapshots().bind_to_scope();
+
+    let send = |method: Method, uri: &str, token: Option<&str>, body: Option<Value>| {
+        let app = app.clone();
+        let mut request = Request::builder()
+            .method(method)
+            .uri(uri)
+            .header("Content-Type", "application/json");
+        if let Some(token) = token {
+            request = request.header("Authorization", format!("Bearer {}", token));
+        }
+        let request = request.body(body.map_or(Body::empty(), |body| Body::from(body.to_string()))).unwrap();
+        async move {
+            let response = app.oneshot(request).await.unwrap();
+            let status = response.status().as_u16();
+            let body = response.into_body().collect().await.unwrap().to_bytes();
+            let body: Value = serde_json::from_slice(&body).unwrap();
+            json!({ "status": status, "body": body })
+        }
+    };
+
+    let email = format!("{}@example.test", rand::random::<u64>());
+    let register = json!({ "name": "Ada", "email": email, "password": "hunter2" });
+    assert_json_snapshot!("register", send(Method::POST, "/users", None, Some(register)).await);
+
+    let login = json!({ "email": email, "password": "hunter2" });
+    let response = send(Method::POST, "/users/login", None, Some(login)).await;
+    assert_json_snapshot!("login", response);
+
+    let token = response["body"]["access_token"].as_str().unwrap();
+    assert_json_snapshot!("me", send(Method::GET, "/users/me", Some(token), None).await);
+}
@@ -0,0 +1,370 @@
+#![allow(dead_code)]
+
+//!
+//! UUID KEYS
+//! ---------
+//!
+//! The todos table is keyed by a `BIGSERIAL`: the database hands out ids, one
+//! after the other. That is compact and fast, but ids can only be known after
+//! an insert, and they reveal how many todos there are.
+//!
+//! `/uuid-todo/` serves the same kind of todos from `uuid_todos`, which is
+//! keyed by UUIDs made by the application. Those are UUIDv7s (see `ids`), not
+//! random UUIDv4s, because a UUIDv7 starts with its creation time: new keys
+//! land at the end of the primary key index, as with a sequence, rather than
+//! anywhere in it. EXERCISE 1 shows the difference.
+//!
+//! In handlers, a `Uuid` is extracted from the path like any other value, and
+//! a path that is not a UUID is rejected with `400 Bad Request`.
+//!
+
+use axum::{
+    async_trait,
+    extract::{FromRef, State},
+    http::StatusCode,
+    Json,
+};
+use axum_extra::routing::TypedPath;
+use sqlx::{types::time::OffsetDateTime, types::Uuid, Pool, Postgres};
+
+use crate::app::Routes;
+use crate::auth::{Claims, JwtKeys};
+use crate::ids::{IdGenerator, UuidV7Ids};
+
+#[derive(Debug)]
+pub struct UuidTodo {
+    pub id: Uuid,
+    pub title: String,
+    pub description: String,
+    pub done: bool,
+    pub owner_id: i64,
+    pub created_at: OffsetDateTime,
+}
+
+impl UuidTodo {
+    fn to_dto(&self) -> UuidTodoDTO {
+        UuidTodoDTO {
+            id: self.id,
+            title: self.title.clone(),
+            description: self.description.clone(),
+            done: self.done,
+            owner_id: self.owner_id,
+            created_at: self.created_at,
+            href: UuidTodoById { id: self.id }.to_string(),
+        }
+    }
+}
+
+#[derive(Debug, serde::Deserialize, serde::Serialize)]
+pub struct UuidTodoDTO {
+    pub id: Uuid,
+    pub title: String,
+    pub description: String,
+    pub done: bool,
+    pub owner_id: i64,
+    #[serde(with = "time::serde::rfc3339")]
+    pub created_at: OffsetDateTime,
+    pub href: String,
+}
+
+#[derive(Debug, TypedPath)]
+#[typed_path("/uuid-todo")]
+pub struct UuidTodoCollection;
+
+#[derive(Debug, TypedPath, serde::Deserialize)]
+#[typed_path("/uuid-todo/:id")]
+pub struct UuidTodoById {
+    pub id: Uuid,
+}
+
+///
+/// Like `TodoRepo`, every method only sees the todos visible to the user.
+///
+#[async_trait]
+pub trait UuidTodoRepo: Send + Sync {
+    ///
+    /// The user's todos, oldest first, which for UUIDv7 keys is id order.
+    ///
+    async fn get_todos(&self, user_id: i64) -> Vec<UuidTodo>;
+    async fn get_todo(&self, user_id: i64, id: Uuid) -> Option<UuidTodo>;
+    async fn create_todo(&self, user_id: i64, title: &str, description: &str) -> Uuid;
+    async fn delete_todo(&self, user_id: i64, id: Uuid) -> Option<Uuid>;
+}
+
+#[derive(Clone)]
+pub struct UuidTodoRepoPostgres {
+    pool: Pool<Postgres>,
+    ids: UuidV7Ids,
+}
+
+impl UuidTodoRepoPostgres {
+    pub fn new(pool: Pool<Postgres>, ids: UuidV7Ids) -> Self {
+        UuidTodoRepoPostgres { pool, ids }
+    }
+}
+
+#[async_trait]
+impl UuidTodoRepo for UuidTodoRepoPostgres {
+    async fn get_todos(&self, user_id: i64) -> Vec<UuidTodo> {
+        let query = sqlx::query_as!(
+            UuidTodo,
+            "SELECT * FROM uuid_todos WHERE todo_visible_to(owner_id, $1) ORDER BY id",
+            user_id
+        );
+        query.fetch_all(&self.pool).await.unwrap()
+    }
+    async fn get_todo(&self, user_id: i64, id: Uuid) -> Option<UuidTodo> {
+        let query = sqlx::query_as!(
+            UuidTodo,
+            "SELECT * FROM uuid_todos WHERE id = $1 AND todo_visible_to(owner_id, $2)",
+            id,
+            user_id
+        );
+        query.fetch_optional(&self.pool).await.unwrap()
+    }
+    async fn create_todo(&self, user_id: i64, title: &str, description: &str) -> Uuid {
+        let query = sqlx::query!(
+            "INSERT INTO uuid_todos (id, title, description, owner_id) VALUES ($1, $2, $3, $4) RETURNING id",
+            self.ids.next_id(),
+            title,
+            description,
+            user_id
+        );
+        query.fetch_one(&self.pool).await.unwrap().id
+    }
+    async fn delete_todo(&self, user_id: i64, id: Uuid) -> Option<Uuid> {
+        let query = sqlx::query!(
+            "DELETE FROM uuid_todos WHERE id = $1 AND todo_visible_to(owner_id, $2) RETURNING id",
+            id,
+            user_id
+        );
+        query.fetch_optional(&self.pool).await.unwrap().map(|row| row.id)
+    }
+}
+
+#[derive(Clone)]
+pub struct UuidTodoState<R: UuidTodoRepo> {
+    pub repo: R,
+}
+
+pub fn uuid_todo_routes<S, R>() -> Routes<S>
+where
+    R: UuidTodoRepo + Clone + 'static,
+    UuidTodoState<R>: FromRef<S>,
+    JwtKeys: FromRef<S>,
+    S: Clone + Send + Sync + 'static,
+{
+    Routes::new()
+        .get(UuidTodoCollection::PATH, get_todos::<R>)
+        .post(UuidTodoCollection::PATH, create_todo::<R>)
+        .get(UuidTodoById::PATH, get_todo::<R>)
+        .delete(UuidTodoById::PATH, delete_todo::<R>)
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct CreateUuidTodo {
+    title: String,
+    description: String,
+}
+
+async fn get_todos<R: UuidTodoRepo>(
+    Claims { sub: user_id, .. }: Claims,
+    State(UuidTodoState { repo }): State<UuidTodoState<R>>,
+) -> Json<Vec<UuidTodoDTO>> {
+    Json(repo.get_todos(user_id).await.iter().map(UuidTodo::to_dto).collect())
+}
+
+async fn get_todo<R: UuidTodoRepo>(
+    Claims { sub: user_id, .. }: Claims,
+    UuidTodoById { id }: UuidTodoById,
+    State(UuidTodoState { repo }): State<UuidTodoState<R>>,
+) -> Result<Json<UuidTodoDTO>, StatusCode> {
+    let todo = repo.get_todo(user_id, id).await.ok_or(StatusCode::NOT_FOUND)?;
+    Ok(Json(todo.to_dto()))
+}
+
+async fn create_todo<R: UuidTodoRepo>(
+    Claims { sub: user_id, .. }: Claims,
+    State(UuidTodoState { repo }): State<UuidTodoState<R>>,
+    Json(CreateUuidTodo { title, description }): Json<CreateUuidTodo>,
+) -> (StatusCode, Json<Uuid>) {
+    (StatusCode::CREATED, Json(repo.create_todo(user_id, &title, &description).await))
+}
+
+async fn delete_todo<R: UuidTodoRepo>(
+    Claims { sub: user_id, .. }: Claims,
+    UuidTodoById { id }: UuidTodoById,
+    State(UuidTodoState { repo }): State<UuidTodoState<R>>,
+) -> StatusCode {
+    match repo.delete_todo(user_id, id).await {
+        Some(_) => StatusCode::NO_CONTENT,
+        None => StatusCode::NOT_FOUND,
+    }
+}
+
+///
+/// EXERCISE 1
+///
+/// This test inserts the same number of rows into two tables that only differ
+/// in how their UUID keys are made: randomly (v4), or starting with a
+/// timestamp (v7). It then compares the two.
+///
+/// Run it with `--nocapture`, and before reading the assertions, predict:
+///
+/// 1. Which table returns its rows in insertion order under `ORDER BY id`?
+///    What does that mean for cursor pagination over the id alone?
+/// 2. Which primary key index ends up larger, and why? (Hint: where in the
+///    B-tree does each new key go, and what happens to a full page there?)
+/// 3. How many index pages does each insert touch, and how many of them are
+///    likely to be in memory once the table no longer fits in it?
+///
+/// Then double the number of rows. Does the gap in index size grow? And what
+/// happens if the v7 keys are all made in the same millisecond, by taking
+/// them from a `SystemClock` instead of the fake one?
+///
+#[tokio::test]
+async fn uuid_key_locality() {
+    use sqlx::postgres::PgPoolOptions;
+    use time::{macros::datetime, Duration};
+
+    use crate::clock::FakeClock;
+
+    const ROWS: usize = 20_000;
+
+    let pool = PgPoolOptions::new()
+        .max_connections(1)
+        .connect(&crate::test_db::database_url().await)
+        .await
+        .unwrap();
+
+    // Keys made a millisecond apart, as for todos created one per request.
+    let clock = FakeClock::new(datetime!(2026-10-16 12:00 UTC));
+    let v7 = UuidV7Ids::new(std::sync::Arc::new(clock.clone()));
+    let keys = [
+        ("v4", (0..ROWS).map(|_| Uuid::new_v4()).collect::<Vec<_>>()),
+        (
+            "v7",
+            (0..ROWS)
+                .map(|_| {
+                    clock.advance(Duration::milliseconds(1));
+                    v7.next_id()
+                })
+                .collect::<Vec<_>>(),
+        ),
+    ];
+
+    let mut index_sizes = vec![];
+    for (version, keys) in &keys {
+        let table = format!("uuid_{}_keys", version);
+        sqlx::query(&format!("CREATE TEMPORARY TABLE {} (id UUID PRIMARY KEY, position INTEGER NOT NULL)", table))
+            .execute(&pool)
+            .await
+            .unwrap();
+
+        // One row per statement, as an application would insert them.
+        let mut tx = pool.begin().await.unwrap();
+        for (position, key) in keys.iter().enumerate() {
+            sqlx::query(&format!("INSERT INTO {} (id, position) VALUES ($1, $2)", table))
+                .bind(key)
+                .bind(position as i32)
+                .execute(&mut *tx)
+                .await
+                .unwrap();
+        }
+        tx.commit().await.unwrap();
+
+        let positions: Vec<i32> = sqlx::query_scalar(&format!("SELECT position FROM {} ORDER BY id", table))
+            .fetch_all(&pool)
+            .await
+            .unwrap();
+        let in_order = positions.windows(2).filter(|pair| pair[0] < pair[1]).count();
+
+        let index_size: i64 = sqlx::query_scalar(&format!("SELECT pg_relation_size('{}_pkey')", table))
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+
+        println!(
+            "{}: {} of {} neighbours in insertion order, primary key index of {} kB",
+            version,
+            in_order,
+            ROWS - 1,
+            index_size / 1024
+        );
+        index_sizes.push((in_order, index_size));
+    }
+
+    let [(v4_in_order, v4_size), (v7_in_order, v7_size)] = index_sizes[..] else { unreachable!() };
+    assert_eq!(v7_in_order, ROWS - 1);
+    assert!(v4_in_order < ROWS - 1);
+    assert!(v7_size < v4_size);
+}
+
+#[tokio::test]
+async fn uuid_todos_are_listed_in_creation_order() {
+    // for Body::collect
+    use http_body_util::BodyExt;
+    /// for ServiceExt::oneshot
+    use tower::util::ServiceExt;
+    use axum::{body::Body, http::{Method, Request}};
+    use sqlx::postgres::PgPoolOptions;
+    use time::{macros::datetime, Duration};
+
+    use crate::auth::{AuthState, RefreshTokenRepoInMemory};
+    use crate::clock::FakeClock;
+    use crate::users::create_test_user;
+
+    #[derive(Clone, FromRef)]
+    struct TestState {
+        todos: UuidTodoState<UuidTodoRepoPostgres>,
+        keys: JwtKeys,
+    }
+
+    let pool = PgPoolOptions::new()
+        .max_connections(1)
+        .connect(&crate::test_db::database_url().await)
+        .await
+        .unwrap();
+
+    // The clock only decides the ids here, so it can be far from the real
+    // time that the access token is checked against. The random bits are not
+    // seeded, so that the ids differ from those of earlier runs.
+    let clock = FakeClock::new(datetime!(2026-10-16 12:00 UTC));
+    let repo = UuidTodoRepoPostgres::new(pool.clone(), UuidV7Ids::new(std::sync::Arc::new(clock.clone())));
+
+    let user_id = create_test_user(&pool, false).await;
+    let keys = JwtKeys::from_secret(b"secret");
+    let auth = AuthState { repo: RefreshTokenRepoInMemory::default(), keys: keys.clone() };
+    let token = format!("Bearer {}", auth.issue_tokens(user_id).await.access_token);
+
+    let app = uuid_todo_routes::<_, UuidTodoRepoPostgres>()
+        .into_router()
+        .with_state(TestState { todos: UuidTodoState { repo: repo.clone() }, keys });
+
+    let mut created = vec![];
+    for title in ["First", "Second", "Third"] {
+        created.push(repo.create_todo(user_id, title, "").await);
+        clock.advance(Duration::milliseconds(1));
+    }
+
+    let request = |uri: String| {
+        Request::builder()
+            .method(Method::GET)
+            .uri(uri)
+            .header("Authorization", &token)
+            .body(Body::empty())
+            .unwrap()
+    };
+
+    let response = app.clone().oneshot(request(UuidTodoCollection.to_string())).await.unwrap();
+    let body = response.into_body().collect().await.unwrap().to_bytes();
+    let todos: Vec<UuidTodoDTO> = serde_json::from_slice(&body).unwrap();
+    assert_eq!(todos.iter().map(|todo| todo.id).collect::<Vec<_>>(), created);
+    assert_eq!(todos[0].href, format!("/uuid-todo/{}", created[0]));
+
+    let response = app.clone().oneshot(request(todos[1].href.clone())).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let response = app.oneshot(request("/uuid-todo/42".to_string())).await.unwrap();
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+}
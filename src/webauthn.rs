@@ -0,0 +1,830 @@
+#![allow(dead_code)]
+
+//!
+//! PASSKEYS
+//! --------
+//!
+//! Passkeys (WebAuthn) replace the password with a key pair that the user's
+//! device keeps: the server only ever stores the public key, and a login is
+//! a signature over a challenge, which no phishing site can ask for, since
+//! the browser binds it to the origin. Devices unlock the key with a
+//! fingerprint or a PIN, which makes a passkey two factors at once: passkey
+//! logins start sessions whose `mfa` claim is set (see `totp.rs`).
+//!
+//! Both ceremonies take two requests. The first answers with the options of
+//! `navigator.credentials.create()` or `.get()`, in their JSON form, with a
+//! random challenge; the second receives what the browser returned, in the
+//! JSON form of `PublicKeyCredential`, and checks it against the challenge,
+//! which can be answered only once, and for five minutes.
+//!
+//! - `POST /users/me/passkeys/challenge`, then `POST /users/me/passkeys`,
+//!   registers a passkey for the logged in user. `GET /users/me/passkeys`
+//!   lists them, and `DELETE /users/me/passkeys/:id` removes one.
+//! - `POST /users/login/passkey/challenge`, then `POST /users/login/passkey`,
+//!   logs in, with any of the passkeys that the device holds for the site:
+//!   the user does not even type an email.
+//!
+//! Only what passkeys use in practice is supported: ES256 (ECDSA on P-256)
+//! keys, and the `none` attestation, which does not prove the make of the
+//! authenticator. Authenticators that count their signatures must count
+//! up; a count that goes back means a cloned authenticator, and the login
+//! is refused.
+//!
+//! `WEBAUTHN_RP_ID` is the domain that passkeys are bound to, and
+//! `WEBAUTHN_ORIGIN` the origin of the pages that use them.
+//!
+
+use axum::{
+    async_trait,
+    extract::{FromRef, Path, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use base64::Engine as _;
+use ring::signature::{UnparsedPublicKey, ECDSA_P256_SHA256_ASN1};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use sqlx::{Pool, Postgres};
+use time::{Duration, OffsetDateTime, PrimitiveDateTime};
+
+use crate::app::Routes;
+use crate::auth::{random_token, AuthState, Claims, JwtKeys, RefreshTokenRepo, TokenPair};
+use crate::clock::SharedClock;
+use crate::client_ip::{ClientIp, TrustedProxies};
+use crate::problem::Problem;
+use crate::users::{UserRepo, UserState};
+
+const CHALLENGE_TTL: Duration = Duration::minutes(5);
+const RP_NAME: &str = "Todos";
+/// The COSE identifier of ECDSA with SHA-256.
+const ES256: i64 = -7;
+
+const USER_PRESENT: u8 = 0x01;
+const USER_VERIFIED: u8 = 0x04;
+const ATTESTED_CREDENTIAL: u8 = 0x40;
+
+const BASE64_URL: base64::engine::GeneralPurpose = base64::engine::general_purpose::URL_SAFE_NO_PAD;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Passkey {
+    pub id: i64,
+    pub user_id: i64,
+    /// Base64url, as browsers send it.
+    pub credential_id: String,
+    /// An uncompressed P-256 point.
+    pub public_key: Vec<u8>,
+    pub sign_count: i64,
+    pub name: String,
+    pub created_at: PrimitiveDateTime,
+    pub last_used_at: Option<PrimitiveDateTime>,
+}
+
+impl Passkey {
+    pub fn to_dto(&self) -> PasskeyDTO {
+        PasskeyDTO {
+            id: self.id,
+            name: self.name.clone(),
+            created_at: self.created_at.assume_utc(),
+            last_used_at: self.last_used_at.map(PrimitiveDateTime::assume_utc),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct PasskeyDTO {
+    pub id: i64,
+    pub name: String,
+    #[serde(with = "time::serde::rfc3339")]
+    pub created_at: OffsetDateTime,
+    #[serde(with = "time::serde::rfc3339::option")]
+    pub last_used_at: Option<OffsetDateTime>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Ceremony {
+    Registration,
+    Authentication,
+}
+
+impl Ceremony {
+    fn as_str(self) -> &'static str {
+        match self {
+            Ceremony::Registration => "registration",
+            Ceremony::Authentication => "authentication",
+        }
+    }
+
+    /// The `type` of the client data of the ceremony.
+    fn client_data_type(self) -> &'static str {
+        match self {
+            Ceremony::Registration => "webauthn.create",
+            Ceremony::Authentication => "webauthn.get",
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Challenge {
+    pub user_id: Option<i64>,
+    pub expires_at: PrimitiveDateTime,
+}
+
+#[async_trait]
+pub trait PasskeyRepo: Send + Sync {
+    async fn insert_challenge(
+        &self,
+        challenge: &str,
+        user_id: Option<i64>,
+        ceremony: Ceremony,
+        expires_at: PrimitiveDateTime,
+    );
+    ///
+    /// Removes the challenge, returning it, so that it is answered at most
+    /// once. Returns `None` if there was no such challenge for the ceremony.
+    ///
+    async fn take_challenge(&self, challenge: &str, ceremony: Ceremony) -> Option<Challenge>;
+    ///
+    /// Returns the id of the new passkey, or `None` if the credential is
+    /// already registered.
+    ///
+    async fn insert(&self, user_id: i64, credential_id: &str, public_key: &[u8], sign_count: i64, name: &str)
+        -> Option<i64>;
+    async fn find(&self, credential_id: &str) -> Option<Passkey>;
+    async fn passkeys(&self, user_id: i64) -> Vec<Passkey>;
+    async fn record_use(&self, id: i64, sign_count: i64, now: PrimitiveDateTime);
+    async fn delete(&self, user_id: i64, id: i64) -> bool;
+}
+
+#[derive(Clone)]
+pub struct PasskeyRepoPostgres {
+    pool: Pool<Postgres>,
+}
+
+impl PasskeyRepoPostgres {
+    pub fn new(pool: Pool<Postgres>) -> Self {
+        PasskeyRepoPostgres { pool }
+    }
+}
+
+#[async_trait]
+impl PasskeyRepo for PasskeyRepoPostgres {
+    async fn insert_challenge(
+        &self,
+        challenge: &str,
+        user_id: Option<i64>,
+        ceremony: Ceremony,
+        expires_at: PrimitiveDateTime,
+    ) {
+        // Challenges that were never answered would pile up otherwise.
+        let query = sqlx::query!("DELETE FROM webauthn_challenges WHERE expires_at < $1", expires_at - CHALLENGE_TTL);
+        query.execute(&self.pool).await.unwrap();
+        let query = sqlx::query!(
+            "INSERT INTO webauthn_challenges (challenge, user_id, kind, expires_at) VALUES ($1, $2, $3, $4)",
+            challenge,
+            user_id,
+            ceremony.as_str(),
+            expires_at
+        );
+        query.execute(&self.pool).await.unwrap();
+    }
+    async fn take_challenge(&self, challenge: &str, ceremony: Ceremony) -> Option<Challenge> {
+        let query = sqlx::query_as!(
+            Challenge,
+            "DELETE FROM webauthn_challenges WHERE challenge = $1 AND kind = $2 RETURNING user_id, expires_at",
+            challenge,
+            ceremony.as_str()
+        );
+        query.fetch_optional(&self.pool).await.unwrap()
+    }
+    async fn insert(
+        &self,
+        user_id: i64,
+        credential_id: &str,
+        public_key: &[u8],
+        sign_count: i64,
+        name: &str,
+    ) -> Option<i64> {
+        let query = sqlx::query!(
+            "INSERT INTO webauthn_credentials (user_id, credential_id, public_key, sign_count, name)
+             VALUES ($1, $2, $3, $4, $5) ON CONFLICT (credential_id) DO NOTHING RETURNING id",
+            user_id,
+            credential_id,
+            public_key,
+            sign_count,
+            name
+        );
+        query.fetch_optional(&self.pool).await.unwrap().map(|row| row.id)
+    }
+    async fn find(&self, credential_id: &str) -> Option<Passkey> {
+        let query = sqlx::query_as!(
+            Passkey,
+            "SELECT id, user_id, credential_id, public_key, sign_count, name, created_at, last_used_at
+             FROM webauthn_credentials WHERE credential_id = $1",
+            credential_id
+        );
+        query.fetch_optional(&self.pool).await.unwrap()
+    }
+    async fn passkeys(&self, user_id: i64) -> Vec<Passkey> {
+        let query = sqlx::query_as!(
+            Passkey,
+            "SELECT id, user_id, credential_id, public_key, sign_count, name, created_at, last_used_at
+             FROM webauthn_credentials WHERE user_id = $1 ORDER BY id",
+            user_id
+        );
+        query.fetch_all(&self.pool).await.unwrap()
+    }
+    async fn record_use(&self, id: i64, sign_count: i64, now: PrimitiveDateTime) {
+        let query = sqlx::query!(
+            "UPDATE webauthn_credentials SET sign_count = $2, last_used_at = $3 WHERE id = $1",
+            id,
+            sign_count,
+            now
+        );
+        query.execute(&self.pool).await.unwrap();
+    }
+    async fn delete(&self, user_id: i64, id: i64) -> bool {
+        let query = sqlx::query!("DELETE FROM webauthn_credentials WHERE user_id = $1 AND id = $2", user_id, id);
+        query.execute(&self.pool).await.unwrap().rows_affected() == 1
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum WebAuthnError {
+    Malformed(&'static str),
+    InvalidChallenge,
+    WrongOrigin,
+    WrongRelyingParty,
+    UserNotVerified,
+    UnsupportedAttestation,
+    UnsupportedKey,
+    AlreadyRegistered,
+    UnknownCredential,
+    InvalidSignature,
+    ClonedAuthenticator,
+}
+
+impl IntoResponse for WebAuthnError {
+    fn into_response(self) -> Response {
+        let (status, detail) = match self {
+            WebAuthnError::Malformed(what) => (StatusCode::BAD_REQUEST, format!("Malformed {}", what)),
+            WebAuthnError::InvalidChallenge => (StatusCode::BAD_REQUEST, "Unknown or expired challenge".to_string()),
+            WebAuthnError::WrongOrigin => (StatusCode::BAD_REQUEST, "Wrong origin".to_string()),
+            WebAuthnError::WrongRelyingParty => (StatusCode::BAD_REQUEST, "Wrong relying party".to_string()),
+            WebAuthnError::UserNotVerified => (StatusCode::BAD_REQUEST, "The user was not verified".to_string()),
+            WebAuthnError::UnsupportedAttestation => {
+                (StatusCode::BAD_REQUEST, "Only the none attestation is supported".to_string())
+            }
+            WebAuthnError::UnsupportedKey => (StatusCode::BAD_REQUEST, "Only ES256 keys are supported".to_string()),
+            WebAuthnError::AlreadyRegistered => (StatusCode::CONFLICT, "The passkey is already registered".to_string()),
+            WebAuthnError::UnknownCredential => (StatusCode::UNAUTHORIZED, "Unknown passkey".to_string()),
+            WebAuthnError::InvalidSignature => (StatusCode::UNAUTHORIZED, "Invalid signature".to_string()),
+            WebAuthnError::ClonedAuthenticator => {
+                (StatusCode::UNAUTHORIZED, "The signature counter went back".to_string())
+            }
+        };
+        Problem::new(status).detail(detail).into_response()
+    }
+}
+
+#[derive(Clone)]
+pub struct WebAuthnState<P: PasskeyRepo> {
+    pub repo: P,
+    /// The domain that passkeys are bound to, such as `todos.example.com`.
+    pub rp_id: String,
+    /// The origin of the pages that use them, such as `https://todos.example.com`.
+    pub origin: String,
+    pub clock: SharedClock,
+}
+
+///
+/// The parts of the client data that are checked. The challenge is the one
+/// the browser received, in base64url.
+///
+#[derive(Debug, serde::Deserialize)]
+struct ClientData {
+    #[serde(rename = "type")]
+    kind: String,
+    challenge: String,
+    origin: String,
+}
+
+///
+/// The authenticator data, which the authenticator signs. Its credential is
+/// only there when a passkey is registered.
+///
+#[derive(Debug, PartialEq)]
+struct AuthenticatorData {
+    rp_id_hash: [u8; 32],
+    flags: u8,
+    sign_count: u32,
+    /// The id and the public key of a new credential.
+    credential: Option<(Vec<u8>, Vec<u8>)>,
+}
+
+impl<P: PasskeyRepo> WebAuthnState<P> {
+    async fn new_challenge(&self, user_id: Option<i64>, ceremony: Ceremony) -> String {
+        let challenge = random_token();
+        let expires_at = self.clock.now_utc_primitive() + CHALLENGE_TTL;
+        self.repo.insert_challenge(&challenge, user_id, ceremony, expires_at).await;
+        challenge
+    }
+
+    ///
+    /// The options of `navigator.credentials.create()`. A passkey is
+    /// discoverable, so that logins need no email, and the device must
+    /// verify the user.
+    ///
+    pub async fn registration_options(&self, user_id: i64, name: &str, display_name: &str) -> Value {
+        let challenge = self.new_challenge(Some(user_id), Ceremony::Registration).await;
+        let exclude: Vec<Value> = self
+            .repo
+            .passkeys(user_id)
+            .await
+            .iter()
+            .map(|passkey| json!({ "type": "public-key", "id": passkey.credential_id }))
+            .collect();
+        json!({
+            "rp": { "id": self.rp_id, "name": RP_NAME },
+            "user": { "id": BASE64_URL.encode(user_id.to_be_bytes()), "name": name, "displayName": display_name },
+            "challenge": challenge,
+            "pubKeyCredParams": [{ "type": "public-key", "alg": ES256 }],
+            "timeout": CHALLENGE_TTL.whole_milliseconds() as i64,
+            "excludeCredentials": exclude,
+            "authenticatorSelection": { "residentKey": "required", "userVerification": "required" },
+            "attestation": "none",
+        })
+    }
+
+    ///
+    /// The options of `navigator.credentials.get()`, with no credentials to
+    /// pick from: the device offers those it holds for the site.
+    ///
+    pub async fn authentication_options(&self) -> Value {
+        let challenge = self.new_challenge(None, Ceremony::Authentication).await;
+        json!({
+            "challenge": challenge,
+            "rpId": self.rp_id,
+            "timeout": CHALLENGE_TTL.whole_milliseconds() as i64,
+            "userVerification": "required",
+        })
+    }
+
+    ///
+    /// Checks the client data of a ceremony, and takes its challenge,
+    /// returning it.
+    ///
+    async fn check_client_data(&self, client_data_json: &[u8], ceremony: Ceremony) -> Result<Challenge, WebAuthnError> {
+        let client_data: ClientData =
+            serde_json::from_slice(client_data_json).map_err(|_| WebAuthnError::Malformed("client data"))?;
+        if client_data.kind != ceremony.client_data_type() {
+            return Err(WebAuthnError::Malformed("client data"));
+        }
+        if client_data.origin != self.origin {
+            return Err(WebAuthnError::WrongOrigin);
+        }
+        let challenge = self
+            .repo
+            .take_challenge(&client_data.challenge, ceremony)
+            .await
+            .ok_or(WebAuthnError::InvalidChallenge)?;
+        if challenge.expires_at <= self.clock.now_utc_primitive() {
+            return Err(WebAuthnError::InvalidChallenge);
+        }
+        Ok(challenge)
+    }
+
+    fn check_authenticator_data(&self, data: &AuthenticatorData) -> Result<(), WebAuthnError> {
+        if data.rp_id_hash[..] != Sha256::digest(self.rp_id.as_bytes())[..] {
+            return Err(WebAuthnError::WrongRelyingParty);
+        }
+        if data.flags & (USER_PRESENT | USER_VERIFIED) != USER_PRESENT | USER_VERIFIED {
+            return Err(WebAuthnError::UserNotVerified);
+        }
+        Ok(())
+    }
+
+    ///
+    /// Registers the passkey that the browser created for the user.
+    ///
+    pub async fn register(
+        &self,
+        user_id: i64,
+        credential: RegistrationCredential,
+        name: &str,
+    ) -> Result<Passkey, WebAuthnError> {
+        let client_data_json = decode(&credential.response.client_data_json, "client data")?;
+        let attestation_object = decode(&credential.response.attestation_object, "attestation object")?;
+
+        let challenge = self.check_client_data(&client_data_json, Ceremony::Registration).await?;
+        if challenge.user_id != Some(user_id) {
+            return Err(WebAuthnError::InvalidChallenge);
+        }
+
+        let attestation: ciborium::Value = ciborium::from_reader(&attestation_object[..])
+            .map_err(|_| WebAuthnError::Malformed("attestation object"))?;
+        let field = |name: &str| {
+            attestation
+                .as_map()
+                .and_then(|map| map.iter().find(|(key, _)| key.as_text() == Some(name)))
+                .map(|(_, value)| value)
+        };
+        if field("fmt").and_then(ciborium::Value::as_text) != Some("none") {
+            return Err(WebAuthnError::UnsupportedAttestation);
+        }
+        let auth_data = field("authData")
+            .and_then(ciborium::Value::as_bytes)
+            .ok_or(WebAuthnError::Malformed("attestation object"))?;
+        let auth_data = parse_authenticator_data(auth_data)?;
+        self.check_authenticator_data(&auth_data)?;
+
+        let (credential_id, public_key) = auth_data.credential.ok_or(WebAuthnError::Malformed("authenticator data"))?;
+        let credential_id = BASE64_URL.encode(credential_id);
+        if credential_id != credential.id {
+            return Err(WebAuthnError::Malformed("credential id"));
+        }
+        self.repo
+            .insert(user_id, &credential_id, &public_key, auth_data.sign_count.into(), name)
+            .await
+            .ok_or(WebAuthnError::AlreadyRegistered)?;
+        Ok(self.repo.find(&credential_id).await.unwrap())
+    }
+
+    ///
+    /// Checks the signature of a login, returning the id of the user whose
+    /// passkey signed it.
+    ///
+    pub async fn authenticate(&self, credential: AuthenticationCredential) -> Result<i64, WebAuthnError> {
+        let client_data_json = decode(&credential.response.client_data_json, "client data")?;
+        let authenticator_data = decode(&credential.response.authenticator_data, "authenticator data")?;
+        let signature = decode(&credential.response.signature, "signature")?;
+
+        self.check_client_data(&client_data_json, Ceremony::Authentication).await?;
+        let passkey = self.repo.find(&credential.id).await.ok_or(WebAuthnError::UnknownCredential)?;
+        let auth_data = parse_authenticator_data(&authenticator_data)?;
+        self.check_authenticator_data(&auth_data)?;
+
+        let signed = [&authenticator_data[..], &Sha256::digest(&client_data_json)[..]].concat();
+        UnparsedPublicKey::new(&ECDSA_P256_SHA256_ASN1, &passkey.public_key)
+            .verify(&signed, &signature)
+            .map_err(|_| WebAuthnError::InvalidSignature)?;
+
+        if let Some(user_handle) = &credential.response.user_handle {
+            if decode(user_handle, "user handle")? != passkey.user_id.to_be_bytes() {
+                return Err(WebAuthnError::UnknownCredential);
+            }
+        }
+        // Authenticators that do not count always say 0.
+        let sign_count = i64::from(auth_data.sign_count);
+        if (sign_count != 0 || passkey.sign_count != 0) && sign_count <= passkey.sign_count {
+            tracing::warn!(target: "audit", user_id = passkey.user_id, passkey_id = passkey.id, "Passkey counter went back");
+            return Err(WebAuthnError::ClonedAuthenticator);
+        }
+        self.repo.record_use(passkey.id, sign_count, self.clock.now_utc_primitive()).await;
+        Ok(passkey.user_id)
+    }
+}
+
+fn decode(value: &str, what: &'static str) -> Result<Vec<u8>, WebAuthnError> {
+    BASE64_URL.decode(value.trim_end_matches('=')).map_err(|_| WebAuthnError::Malformed(what))
+}
+
+///
+/// The authenticator data: the SHA-256 of the RP id, the flags, the
+/// signature counter, and, when registering, the credential.
+///
+fn parse_authenticator_data(data: &[u8]) -> Result<AuthenticatorData, WebAuthnError> {
+    if data.len() < 37 {
+        return Err(WebAuthnError::Malformed("authenticator data"));
+    }
+    let flags = data[32];
+    let sign_count = u32::from_be_bytes(data[33..37].try_into().unwrap());
+
+    let credential = if flags & ATTESTED_CREDENTIAL != 0 {
+        // The AAGUID of the authenticator's model, which is not checked,
+        // then the length of the credential id, the id, and the public key.
+        let rest = data.get(37 + 16..).ok_or(WebAuthnError::Malformed("authenticator data"))?;
+        let (length, rest) = rest.split_at_checked(2).ok_or(WebAuthnError::Malformed("authenticator data"))?;
+        let (credential_id, rest) = rest
+            .split_at_checked(u16::from_be_bytes([length[0], length[1]]) as usize)
+            .ok_or(WebAuthnError::Malformed("authenticator data"))?;
+        let public_key: ciborium::Value = ciborium::from_reader(rest).map_err(|_| WebAuthnError::Malformed("authenticator data"))?;
+        Some((credential_id.to_vec(), p256_public_key(&public_key)?))
+    } else {
+        None
+    };
+
+    Ok(AuthenticatorData { rp_id_hash: data[..32].try_into().unwrap(), flags, sign_count, credential })
+}
+
+///
+/// The uncompressed point of an ES256 key in COSE form: a map whose key
+/// type (1) is EC2 (2), algorithm (3) ES256, curve (-1) P-256 (1), and
+/// coordinates (-2 and -3) 32 bytes each.
+///
+fn p256_public_key(key: &ciborium::Value) -> Result<Vec<u8>, WebAuthnError> {
+    let map = key.as_map().ok_or(WebAuthnError::UnsupportedKey)?;
+    let get = |label: i64| {
+        map.iter()
+            .find(|(key, _)| key.as_integer() == Some(label.into()))
+            .map(|(_, value)| value)
+    };
+    let integer = |label: i64| get(label).and_then(ciborium::Value::as_integer).map(i128::from);
+    if integer(1) != Some(2) || integer(3) != Some(ES256.into()) || integer(-1) != Some(1) {
+        return Err(WebAuthnError::UnsupportedKey);
+    }
+    let coordinate = |label: i64| get(label).and_then(ciborium::Value::as_bytes).filter(|bytes| bytes.len() == 32);
+    match (coordinate(-2), coordinate(-3)) {
+        (Some(x), Some(y)) => Ok([&[0x04][..], x, y].concat()),
+        _ => Err(WebAuthnError::UnsupportedKey),
+    }
+}
+
+///
+/// A `PublicKeyCredential` from `navigator.credentials.create()`, as its
+/// `toJSON()` has it.
+///
+#[derive(Debug, serde::Deserialize, serde::Serialize)]
+pub struct RegistrationCredential {
+    pub id: String,
+    pub response: AttestationResponse,
+}
+
+#[derive(Debug, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AttestationResponse {
+    #[serde(rename = "clientDataJSON")]
+    pub client_data_json: String,
+    pub attestation_object: String,
+}
+
+///
+/// A `PublicKeyCredential` from `navigator.credentials.get()`, as its
+/// `toJSON()` has it.
+///
+#[derive(Debug, serde::Deserialize, serde::Serialize)]
+pub struct AuthenticationCredential {
+    pub id: String,
+    pub response: AssertionResponse,
+}
+
+#[derive(Debug, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AssertionResponse {
+    #[serde(rename = "clientDataJSON")]
+    pub client_data_json: String,
+    pub authenticator_data: String,
+    pub signature: String,
+    pub user_handle: Option<String>,
+}
+
+pub fn passkey_routes<S, U, R, P>() -> Routes<S>
+where
+    U: UserRepo + Clone + 'static,
+    R: RefreshTokenRepo + Clone + 'static,
+    P: PasskeyRepo + Clone + 'static,
+    UserState<U>: FromRef<S>,
+    AuthState<R>: FromRef<S>,
+    WebAuthnState<P>: FromRef<S>,
+    JwtKeys: FromRef<S>,
+    TrustedProxies: FromRef<S>,
+    S: Clone + Send + Sync + 'static,
+{
+    Routes::new()
+        .post("/users/me/passkeys/challenge", registration_options::<U, P>)
+        .post("/users/me/passkeys", register::<P>)
+        .get("/users/me/passkeys", passkeys::<P>)
+        .delete("/users/me/passkeys/:id", delete_passkey::<P>)
+        .post("/users/login/passkey/challenge", authentication_options::<P>)
+        .post("/users/login/passkey", login::<R, P>)
+}
+
+async fn registration_options<U: UserRepo, P: PasskeyRepo>(
+    claims: Claims,
+    State(UserState { repo }): State<UserState<U>>,
+    State(webauthn): State<WebAuthnState<P>>,
+) -> Result<Json<Value>, StatusCode> {
+    let user = repo.get_user(claims.sub).await.ok_or(StatusCode::NOT_FOUND)?;
+    Ok(Json(webauthn.registration_options(user.id, &user.email, &user.name).await))
+}
+
+#[derive(Debug, serde::Deserialize, serde::Serialize)]
+struct Registration {
+    #[serde(flatten)]
+    credential: RegistrationCredential,
+    /// What the user calls the device, to tell their passkeys apart.
+    #[serde(default = "default_passkey_name")]
+    name: String,
+}
+
+fn default_passkey_name() -> String {
+    "Passkey".to_string()
+}
+
+async fn register<P: PasskeyRepo>(
+    claims: Claims,
+    State(webauthn): State<WebAuthnState<P>>,
+    Json(Registration { credential, name }): Json<Registration>,
+) -> Result<(StatusCode, Json<PasskeyDTO>), WebAuthnError> {
+    let passkey = webauthn.register(claims.sub, credential, &name).await?;
+    tracing::info!(target: "audit", user_id = claims.sub, passkey_id = passkey.id, "Passkey registered");
+    Ok((StatusCode::CREATED, Json(passkey.to_dto())))
+}
+
+async fn passkeys<P: PasskeyRepo>(claims: Claims, State(webauthn): State<WebAuthnState<P>>) -> Json<Vec<PasskeyDTO>> {
+    Json(webauthn.repo.passkeys(claims.sub).await.iter().map(Passkey::to_dto).collect())
+}
+
+async fn delete_passkey<P: PasskeyRepo>(
+    claims: Claims,
+    State(webauthn): State<WebAuthnState<P>>,
+    Path(id): Path<i64>,
+) -> StatusCode {
+    match webauthn.repo.delete(claims.sub, id).await {
+        true => StatusCode::NO_CONTENT,
+        false => StatusCode::NOT_FOUND,
+    }
+}
+
+async fn authentication_options<P: PasskeyRepo>(State(webauthn): State<WebAuthnState<P>>) -> Json<Value> {
+    Json(webauthn.authentication_options().await)
+}
+
+///
+/// Logs in with a passkey, which verified the user on the device, so the
+/// session counts as a two-factor one.
+///
+async fn login<R: RefreshTokenRepo, P: PasskeyRepo>(
+    State(auth): State<AuthState<R>>,
+    State(webauthn): State<WebAuthnState<P>>,
+    client_ip: Option<ClientIp>,
+    Json(credential): Json<AuthenticationCredential>,
+) -> Result<Json<TokenPair>, WebAuthnError> {
+    let client_ip = client_ip.map_or_else(|| "unknown".to_string(), |ip| ip.to_string());
+    match webauthn.authenticate(credential).await {
+        Ok(user_id) => {
+            tracing::info!(target: "audit", user_id, client_ip, "Login with a passkey");
+            Ok(Json(auth.issue_two_factor_tokens(user_id).await))
+        }
+        Err(error) => {
+            tracing::warn!(target: "audit", client_ip, ?error, "Login failed: passkey rejected");
+            Err(error)
+        }
+    }
+}
+
+#[tokio::test]
+async fn passkeys_register_and_log_in() {
+    // for Body::collect
+    use http_body_util::BodyExt;
+    /// for ServiceExt::oneshot
+    use tower::util::ServiceExt;
+    use axum::{body::Body, http::{Method, Request}};
+    use ring::rand::SystemRandom;
+    use ring::signature::{EcdsaKeyPair, KeyPair, ECDSA_P256_SHA256_ASN1_SIGNING};
+    use sqlx::postgres::PgPoolOptions;
+    use std::sync::Arc;
+
+    use crate::auth::{decode_access_token, RefreshTokenRepoInMemory};
+    use crate::clock::SystemClock;
+    use crate::users::{create_test_user, UserRepoPostgres};
+
+    #[derive(Clone, FromRef)]
+    struct TestState {
+        users: UserState<UserRepoPostgres>,
+        auth: AuthState<RefreshTokenRepoInMemory>,
+        webauthn: WebAuthnState<PasskeyRepoPostgres>,
+        keys: JwtKeys,
+        trusted_proxies: TrustedProxies,
+    }
+
+    let pool = PgPoolOptions::new()
+        .max_connections(1)
+        .connect(&crate::test_db::database_url().await)
+        .await
+        .unwrap();
+    let user_id = create_test_user(&pool, false).await;
+    let keys = JwtKeys::from_secret(b"secret");
+    let state = TestState {
+        users: UserState { repo: UserRepoPostgres::new(pool.clone(), crate::pii::test_cipher()) },
+        auth: AuthState { repo: RefreshTokenRepoInMemory::default(), keys: keys.clone() },
+        webauthn: WebAuthnState {
+            repo: PasskeyRepoPostgres::new(pool),
+            rp_id: "todos.example.test".to_string(),
+            origin: "https://todos.example.test".to_string(),
+            clock: Arc::new(SystemClock),
+        },
+        keys,
+        trusted_proxies: TrustedProxies::default(),
+    };
+    let access_token = state.auth.issue_tokens(user_id).await.access_token;
+    let app = passkey_routes::<_, UserRepoPostgres, RefreshTokenRepoInMemory, PasskeyRepoPostgres>()
+        .into_router()
+        .with_state(state.clone());
+    let send = |method: Method, uri: &str, authenticated: bool, body: Value| {
+        let mut request = Request::builder().method(method).uri(uri).header("Content-Type", "application/json");
+        if authenticated {
+            request = request.header("Authorization", format!("Bearer {}", access_token));
+        }
+        let request = request.body(Body::from(body.to_string())).unwrap();
+        let app = app.clone();
+        async move {
+            let response = app.oneshot(request).await.unwrap();
+            let status = response.status();
+            let body = response.into_body().collect().await.unwrap().to_bytes();
+            (status, serde_json::from_slice::<Value>(&body).unwrap_or_default())
+        }
+    };
+
+    // The authenticator: a P-256 key pair, and what it signs.
+    let rng = SystemRandom::new();
+    let pkcs8 = EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_ASN1_SIGNING, &rng).unwrap();
+    let key_pair = EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_ASN1_SIGNING, pkcs8.as_ref(), &rng).unwrap();
+    let credential_id: [u8; 16] = rand::random();
+    let rp_id_hash = Sha256::digest(b"todos.example.test");
+    let client_data = |kind: &str, options: &Value| {
+        let challenge = options["challenge"].as_str().unwrap();
+        json!({ "type": kind, "challenge": challenge, "origin": "https://todos.example.test" }).to_string()
+    };
+
+    let (status, options) = send(Method::POST, "/users/me/passkeys/challenge", true, Value::Null).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(options["rp"]["id"], "todos.example.test");
+    assert_eq!(options["user"]["id"], BASE64_URL.encode(user_id.to_be_bytes()));
+
+    let point = key_pair.public_key().as_ref();
+    let cose_key = ciborium::Value::Map(vec![
+        (1.into(), 2.into()),
+        (3.into(), ES256.into()),
+        ((-1).into(), 1.into()),
+        ((-2).into(), ciborium::Value::Bytes(point[1..33].to_vec())),
+        ((-3).into(), ciborium::Value::Bytes(point[33..].to_vec())),
+    ]);
+    let mut auth_data = [&rp_id_hash[..], &[USER_PRESENT | USER_VERIFIED | ATTESTED_CREDENTIAL][..], &[0; 4][..], &[0; 16][..]].concat();
+    auth_data.extend((credential_id.len() as u16).to_be_bytes());
+    auth_data.extend(credential_id);
+    ciborium::into_writer(&cose_key, &mut auth_data).unwrap();
+    let attestation = ciborium::Value::Map(vec![
+        ("fmt".into(), "none".into()),
+        ("attStmt".into(), ciborium::Value::Map(vec![])),
+        ("authData".into(), ciborium::Value::Bytes(auth_data)),
+    ]);
+    let mut attestation_object = Vec::new();
+    ciborium::into_writer(&attestation, &mut attestation_object).unwrap();
+    let registration = json!({
+        "id": BASE64_URL.encode(credential_id),
+        "type": "public-key",
+        "response": {
+            "clientDataJSON": BASE64_URL.encode(client_data("webauthn.create", &options)),
+            "attestationObject": BASE64_URL.encode(&attestation_object),
+        },
+        "name": "Laptop",
+    });
+    let (status, passkey) = send(Method::POST, "/users/me/passkeys", true, registration.clone()).await;
+    assert_eq!(status, StatusCode::CREATED);
+    assert_eq!(passkey["name"], "Laptop");
+    // Challenges are answered once.
+    let (status, _) = send(Method::POST, "/users/me/passkeys", true, registration).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+
+    let assertion = |options: &Value, sign_count: u32, tamper: bool| {
+        let client_data = client_data("webauthn.get", options);
+        let auth_data = [&rp_id_hash[..], &[USER_PRESENT | USER_VERIFIED][..], &sign_count.to_be_bytes()[..]].concat();
+        let mut signed = [&auth_data[..], &Sha256::digest(client_data.as_bytes())[..]].concat();
+        if tamper {
+            signed[0] ^= 1;
+        }
+        let signature = key_pair.sign(&rng, &signed).unwrap();
+        json!({
+            "id": BASE64_URL.encode(credential_id),
+            "type": "public-key",
+            "response": {
+                "clientDataJSON": BASE64_URL.encode(client_data),
+                "authenticatorData": BASE64_URL.encode(auth_data),
+                "signature": BASE64_URL.encode(signature.as_ref()),
+                "userHandle": BASE64_URL.encode(user_id.to_be_bytes()),
+            },
+        })
+    };
+
+    let (_, options) = send(Method::POST, "/users/login/passkey/challenge", false, Value::Null).await;
+    let (status, tokens) = send(Method::POST, "/users/login/passkey", false, assertion(&options, 1, false)).await;
+    assert_eq!(status, StatusCode::OK);
+    let claims = decode_access_token(&state.keys, tokens["access_token"].as_str().unwrap()).unwrap();
+    assert_eq!((claims.sub, claims.mfa), (user_id, true));
+
+    let (_, options) = send(Method::POST, "/users/login/passkey/challenge", false, Value::Null).await;
+    let (status, _) = send(Method::POST, "/users/login/passkey", false, assertion(&options, 2, true)).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    // A counter that goes back means a cloned authenticator.
+    let (_, options) = send(Method::POST, "/users/login/passkey/challenge", false, Value::Null).await;
+    let (status, _) = send(Method::POST, "/users/login/passkey", false, assertion(&options, 1, false)).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+    let (_, passkeys) = send(Method::GET, "/users/me/passkeys", true, Value::Null).await;
+    assert_eq!(passkeys.as_array().unwrap().len(), 1);
+    assert!(!passkeys[0]["last_used_at"].is_null());
+    let uri = format!("/users/me/passkeys/{}", passkeys[0]["id"]);
+    let (status, _) = send(Method::DELETE, &uri, true, Value::Null).await;
+    assert_eq!(status, StatusCode::NO_CONTENT);
+}
@@ -0,0 +1,351 @@
+#![allow(dead_code)]
+
+//!
+//! WEBSOCKETS
+//! ----------
+//!
+//! HTTP only answers; it cannot tell a client that something changed. A
+//! WebSocket starts out as an HTTP request that asks to be "upgraded", and
+//! then stays open, with either side sending messages whenever it likes.
+//!
+//! `GET /ws/todos` upgrades to a socket on which the server pushes a
+//! `TodoEvent` whenever its user creates, changes or deletes a todo, from any
+//! device or tab, so that all of them can stay in sync.
+//!
+//! Three things make a socket harder to get right than a request:
+//!
+//! - Authentication. Browsers cannot set an `Authorization` header on the
+//!   upgrade request, so the access token may also be sent as the
+//!   `?access_token=` query parameter (which ends up in access logs), or as a
+//!   subprotocol: `Sec-WebSocket-Protocol: bearer, <access token>`, to which
+//!   the server answers that it speaks `bearer`.
+//! - Heartbeats. A client that vanishes without closing the connection (a
+//!   laptop lid closed, a phone out of coverage) leaves a socket that looks
+//!   open forever. The server pings every `ping_interval`, and closes sockets
+//!   that have not sent anything, pongs included, for `idle_timeout`.
+//! - Backpressure. Events can be produced faster than a slow client reads
+//!   them. Each socket has a bounded queue of `send_buffer` messages; a client
+//!   that lets it fill up is disconnected, rather than the server buffering
+//!   without limit on its behalf.
+//!
+
+use std::time::Duration;
+
+use axum::{
+    async_trait,
+    extract::{
+        ws::{close_code, CloseFrame, Message, WebSocket, WebSocketUpgrade},
+        FromRef, FromRequestParts, Query, State,
+    },
+    http::{header, request::Parts, HeaderMap},
+    response::Response,
+};
+use axum_extra::routing::TypedPath;
+use futures::{SinkExt, StreamExt};
+use tokio::{
+    sync::{broadcast, mpsc},
+    time::Instant,
+};
+
+use crate::app::Routes;
+use crate::auth::{decode_access_token, AuthError, Claims, JwtKeys};
+
+pub const BEARER_PROTOCOL: &str = "bearer";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TodoEventKind {
+    Created,
+    Updated,
+    Deleted,
+}
+
+///
+/// Something that happened to a todo. `user_id` is the user who did it.
+///
+#[derive(Clone, Debug, PartialEq, serde::Deserialize, serde::Serialize)]
+pub struct TodoEvent {
+    pub kind: TodoEventKind,
+    pub todo_id: i64,
+    pub user_id: i64,
+}
+
+///
+/// Where todo events are published, and where sockets subscribe to them.
+/// Clones publish to, and subscribe from, the same channel.
+///
+#[derive(Clone)]
+pub struct TodoEvents {
+    sender: broadcast::Sender<TodoEvent>,
+}
+
+impl Default for TodoEvents {
+    fn default() -> Self {
+        TodoEvents { sender: broadcast::channel(256).0 }
+    }
+}
+
+impl TodoEvents {
+    ///
+    /// Publishes an event to every current subscriber. Having none is fine.
+    ///
+    pub fn publish(&self, kind: TodoEventKind, todo_id: i64, user_id: i64) {
+        let _ = self.sender.send(TodoEvent { kind, todo_id, user_id });
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<TodoEvent> {
+        self.sender.subscribe()
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SocketConfig {
+    pub ping_interval: Duration,
+    pub idle_timeout: Duration,
+    pub send_buffer: usize,
+}
+
+impl Default for SocketConfig {
+    fn default() -> Self {
+        SocketConfig {
+            ping_interval: Duration::from_secs(15),
+            idle_timeout: Duration::from_secs(45),
+            send_buffer: 32,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct SocketState {
+    pub events: TodoEvents,
+    pub config: SocketConfig,
+}
+
+///
+/// The claims of an upgrade request, from its `Authorization` header if it
+/// has one, or else from its query string or subprotocols.
+///
+#[derive(Clone, Debug)]
+pub struct SocketClaims(pub Claims);
+
+#[derive(Debug, serde::Deserialize)]
+struct TokenQuery {
+    access_token: String,
+}
+
+#[async_trait]
+impl<S> FromRequestParts<S> for SocketClaims
+where
+    JwtKeys: FromRef<S>,
+    S: Send + Sync,
+{
+    type Rejection = AuthError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        if parts.headers.contains_key(header::AUTHORIZATION) {
+            return Claims::from_request_parts(parts, state).await.map(SocketClaims);
+        }
+
+        let token = Query::<TokenQuery>::try_from_uri(&parts.uri)
+            .map(|Query(query)| query.access_token)
+            .ok()
+            .or_else(|| protocol_token(&parts.headers))
+            .ok_or(AuthError::MissingToken)?;
+
+        decode_access_token(&JwtKeys::from_ref(state), &token).map(SocketClaims)
+    }
+}
+
+///
+/// The token in `Sec-WebSocket-Protocol: bearer, <token>`.
+///
+fn protocol_token(headers: &HeaderMap) -> Option<String> {
+    let protocols = headers.get(header::SEC_WEBSOCKET_PROTOCOL)?.to_str().ok()?;
+    let mut protocols = protocols.split(',').map(str::trim);
+    protocols.find(|protocol| *protocol == BEARER_PROTOCOL)?;
+    protocols.next().map(str::to_string)
+}
+
+#[derive(Debug, TypedPath)]
+#[typed_path("/ws/todos")]
+pub struct TodoEventsSocket;
+
+pub fn socket_routes<S>() -> Routes<S>
+where
+    SocketState: FromRef<S>,
+    JwtKeys: FromRef<S>,
+    S: Clone + Send + Sync + 'static,
+{
+    Routes::new().get(TodoEventsSocket::PATH, todo_events_socket)
+}
+
+///
+/// The claims are checked before the upgrade, so that a client without a
+/// valid token gets a plain 401 rather than a socket that closes at once.
+///
+async fn todo_events_socket(
+    SocketClaims(claims): SocketClaims,
+    State(state): State<SocketState>,
+    upgrade: WebSocketUpgrade,
+) -> Response {
+    upgrade
+        .protocols([BEARER_PROTOCOL])
+        .on_upgrade(move |socket| stream_todo_events(socket, claims.sub, state))
+}
+
+async fn stream_todo_events(socket: WebSocket, user_id: i64, SocketState { events, config }: SocketState) {
+    let (mut sink, mut stream) = socket.split();
+
+    // Only the writer waits for the client. Everything else queues messages
+    // without waiting, and finds out that the client is too slow when the
+    // queue is full.
+    let (outbox, mut queued) = mpsc::channel::<Message>(config.send_buffer);
+    let writer = tokio::spawn(async move {
+        while let Some(message) = queued.recv().await {
+            if sink.send(message).await.is_err() {
+                break;
+            }
+        }
+    });
+
+    let too_slow = || close_frame(close_code::AGAIN, "Too slow to keep up with events");
+    let mut events = events.subscribe();
+    let mut heartbeat = tokio::time::interval_at(Instant::now() + config.ping_interval, config.ping_interval);
+    let mut last_seen = Instant::now();
+
+    let close = loop {
+        tokio::select! {
+            message = stream.next() => match message {
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break None,
+                Some(Ok(_)) => last_seen = Instant::now(),
+            },
+            event = events.recv() => match event {
+                Ok(event) if event.user_id == user_id => {
+                    let message = Message::Text(serde_json::to_string(&event).unwrap());
+                    if outbox.try_send(message).is_err() {
+                        break Some(too_slow());
+                    }
+                }
+                Ok(_) => {}
+                Err(broadcast::error::RecvError::Lagged(_)) => break Some(too_slow()),
+                Err(broadcast::error::RecvError::Closed) => break Some(close_frame(close_code::AWAY, "Shutting down")),
+            },
+            _ = heartbeat.tick() => {
+                if last_seen.elapsed() >= config.idle_timeout {
+                    break Some(close_frame(close_code::AWAY, "Idle timeout"));
+                }
+                if outbox.try_send(Message::Ping(Vec::new())).is_err() {
+                    break Some(too_slow());
+                }
+            },
+        }
+    };
+
+    if let Some(frame) = close {
+        let _ = outbox.try_send(Message::Close(Some(frame)));
+    }
+    drop(outbox);
+
+    // Give the writer a chance to flush what is queued, but not forever.
+    let abort = writer.abort_handle();
+    if tokio::time::timeout(config.idle_timeout, writer).await.is_err() {
+        abort.abort();
+    }
+}
+
+fn close_frame(code: u16, reason: &'static str) -> CloseFrame<'static> {
+    CloseFrame { code, reason: reason.into() }
+}
+
+#[cfg(test)]
+async fn serve_sockets(config: SocketConfig) -> (std::net::SocketAddr, TodoEvents, JwtKeys) {
+    #[derive(Clone, FromRef)]
+    struct TestState {
+        sockets: SocketState,
+        keys: JwtKeys,
+    }
+
+    let events = TodoEvents::default();
+    let keys = JwtKeys::from_secret(b"secret");
+    let app = socket_routes()
+        .into_router()
+        .with_state(TestState { sockets: SocketState { events: events.clone(), config }, keys: keys.clone() });
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+    (addr, events, keys)
+}
+
+#[tokio::test]
+async fn sockets_authenticate_and_stream_their_users_events() {
+    use tokio_tungstenite::{connect_async, tungstenite::{self, client::IntoClientRequest}};
+
+    use crate::auth::{AuthState, RefreshTokenRepoInMemory};
+
+    let (addr, events, keys) = serve_sockets(SocketConfig::default()).await;
+    let auth = AuthState { repo: RefreshTokenRepoInMemory::default(), keys };
+    let token = auth.issue_tokens(7).await.access_token;
+    let url = format!("ws://{}{}", addr, TodoEventsSocket);
+
+    match connect_async(url.as_str()).await {
+        Err(tungstenite::Error::Http(response)) => assert_eq!(response.status(), 401),
+        other => panic!("expected a 401, got {:?}", other.map(|(_, response)| response)),
+    }
+
+    let mut request = url.as_str().into_client_request().unwrap();
+    request
+        .headers_mut()
+        .insert(header::SEC_WEBSOCKET_PROTOCOL, format!("{}, {}", BEARER_PROTOCOL, token).parse().unwrap());
+    let (mut socket, response) = connect_async(request).await.unwrap();
+    assert_eq!(response.headers()[header::SEC_WEBSOCKET_PROTOCOL], BEARER_PROTOCOL);
+
+    let (mut by_query, _) = connect_async(format!("{}?access_token={}", url, token)).await.unwrap();
+
+    // Give the sockets a moment to subscribe before publishing.
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    events.publish(TodoEventKind::Created, 1, 8);
+    events.publish(TodoEventKind::Updated, 2, 7);
+
+    let expected = TodoEvent { kind: TodoEventKind::Updated, todo_id: 2, user_id: 7 };
+    for socket in [&mut socket, &mut by_query] {
+        let message = socket.next().await.unwrap().unwrap();
+        assert_eq!(serde_json::from_str::<TodoEvent>(message.to_text().unwrap()).unwrap(), expected);
+    }
+}
+
+#[tokio::test]
+async fn idle_sockets_are_closed() {
+    use tokio_tungstenite::connect_async;
+
+    use crate::auth::{AuthState, RefreshTokenRepoInMemory};
+
+    let config = SocketConfig {
+        ping_interval: Duration::from_millis(20),
+        idle_timeout: Duration::from_millis(100),
+        send_buffer: 8,
+    };
+    let (addr, _, keys) = serve_sockets(config).await;
+    let auth = AuthState { repo: RefreshTokenRepoInMemory::default(), keys };
+    let url = format!("ws://{}{}?access_token={}", addr, TodoEventsSocket, auth.issue_tokens(7).await.access_token);
+
+    // Reading answers the server's pings with pongs, which keeps the socket
+    // open well past the idle timeout.
+    let (mut live, _) = connect_async(url.as_str()).await.unwrap();
+    let read_for_a_while = tokio::time::timeout(Duration::from_millis(300), async {
+        while let Some(message) = live.next().await {
+            assert!(message.unwrap().is_ping());
+        }
+    });
+    assert!(read_for_a_while.await.is_err());
+
+    // A client that does not read sends no pongs, and is disconnected. It
+    // finds out when it reads again, by then too late to answer the pings.
+    let (mut idle, _) = connect_async(url.as_str()).await.unwrap();
+    tokio::time::sleep(Duration::from_millis(300)).await;
+    let disconnected = tokio::time::timeout(Duration::from_secs(1), async {
+        while let Some(Ok(_)) = idle.next().await {}
+    });
+    assert!(disconnected.await.is_ok());
+}
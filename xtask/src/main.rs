@@ -0,0 +1,102 @@
+//!
+//! XTASK
+//! -----
+//!
+//! Chores that take more than one cargo command, run with `cargo xtask`:
+//!
+//! - `cargo xtask prepare` writes the query data of every `query!` to
+//!   `.sqlx`, checking each query against the database in `DATABASE_URL`.
+//!   Run it after changing a query, and commit `.sqlx` along with it.
+//! - `cargo xtask prepare --check` fails if `.sqlx` is not what `prepare`
+//!   would write, for CI.
+//!
+//! This is what `cargo sqlx prepare` does, without installing sqlx-cli: the
+//! macros write the data themselves when built with `SQLX_OFFLINE_DIR` set,
+//! so the task rebuilds the app with it set.
+//!
+
+use std::{
+    collections::BTreeMap,
+    env, fs,
+    path::{Path, PathBuf},
+    process::{exit, Command},
+};
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    match args.iter().map(String::as_str).collect::<Vec<_>>().as_slice() {
+        ["prepare"] => prepare(false),
+        ["prepare", "--check"] => prepare(true),
+        _ => {
+            eprintln!("Usage: cargo xtask prepare [--check]");
+            exit(2);
+        }
+    }
+}
+
+fn prepare(check: bool) {
+    let root = Path::new(env!("CARGO_MANIFEST_DIR")).parent().unwrap().to_path_buf();
+    let sqlx = root.join(".sqlx");
+    let out = if check { root.join("target").join("sqlx-check") } else { sqlx.clone() };
+
+    // Starts empty, so that the data of queries that are gone goes with them.
+    if out.exists() {
+        fs::remove_dir_all(&out).unwrap();
+    }
+    fs::create_dir_all(&out).unwrap();
+
+    // The macros only run when the app is compiled, so it is compiled again,
+    // online, with every query, including those of tests and features.
+    cargo(&root, &["clean", "--package", "rust-web"], &[]);
+    cargo(
+        &root,
+        &["check", "--all-targets", "--all-features"],
+        &[("SQLX_OFFLINE", "false"), ("SQLX_OFFLINE_DIR", out.to_str().unwrap())],
+    );
+
+    if !check {
+        println!("Wrote the data of {} queries to .sqlx", queries(&sqlx).len());
+        return;
+    }
+    let (expected, actual) = (queries(&out), queries(&sqlx));
+    let mut stale = false;
+    for (name, data) in &expected {
+        match actual.get(name) {
+            None => eprintln!("Missing from .sqlx: {}", name),
+            Some(found) if found != data => eprintln!("Out of date in .sqlx: {}", name),
+            Some(_) => continue,
+        }
+        stale = true;
+    }
+    for name in actual.keys().filter(|name| !expected.contains_key(*name)) {
+        eprintln!("No longer used, in .sqlx: {}", name);
+        stale = true;
+    }
+    if stale {
+        eprintln!("Run `cargo xtask prepare`, and commit .sqlx");
+        exit(1);
+    }
+    println!("The query data in .sqlx is up to date");
+}
+
+fn cargo(root: &PathBuf, args: &[&str], envs: &[(&str, &str)]) {
+    let cargo = env::var("CARGO").unwrap_or_else(|_| "cargo".to_string());
+    let status = Command::new(cargo).args(args).envs(envs.iter().copied()).current_dir(root).status().unwrap();
+    if !status.success() {
+        exit(status.code().unwrap_or(1));
+    }
+}
+
+///
+/// The query data files in `dir`, by name.
+///
+fn queries(dir: &Path) -> BTreeMap<String, String> {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return BTreeMap::new();
+    };
+    entries
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|extension| extension == "json"))
+        .map(|path| (path.file_name().unwrap().to_string_lossy().into_owned(), fs::read_to_string(&path).unwrap()))
+        .collect()
+}